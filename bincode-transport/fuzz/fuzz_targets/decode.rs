@@ -0,0 +1,28 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Feeds arbitrary bytes to the decoder, as a server would when reading from a malicious client.
+//!
+//! Run with `cargo fuzz run decode` from the bincode-transport directory.
+
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use rpc::{ClientMessage, ServerMessage};
+use tarpc_bincode_transport::Codec;
+use tokio_codec::Decoder;
+
+fuzz_target!(|data: &[u8]| {
+    // A small max frame length so that the fuzzer can easily hit the limit.
+    let mut server = Codec::<ClientMessage<String>, ServerMessage<String>>::new(1024);
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(_)) = server.decode(&mut buf) {}
+
+    let mut client = Codec::<ServerMessage<Vec<u8>>, ClientMessage<Vec<u8>>>::new(1024);
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(_)) = client.decode(&mut buf) {}
+});
@@ -0,0 +1,877 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A length-delimited bincode codec with strict resource bounds.
+//!
+//! Each frame is a 4-byte big-endian length followed by that many bytes of bincode. The decoder
+//! never panics on malformed input, and never buffers or allocates more than the max frame length
+//! for a single frame: a length prefix over the limit is rejected before any of the frame is
+//! buffered, and bincode is limited to the bytes in the frame, so an inner length prefix can't
+//! make it allocate more. The framing itself lives in [`frame`](crate::frame), for use without
+//! tokio.
+//!
+//! Frames can hold another serde data format instead, like JSON or MessagePack, by implementing
+//! [`Format`] for it. The framing and its bounds stay the same, but whether the format allocates
+//! more than the bytes in a frame is up to the format.
+//!
+//! A codec that knows it is decoding [requests or responses](Decodes) recovers the request ID of
+//! a frame it can't decode, e.g. one calling a method the server doesn't know about, and returns
+//! an [`UndecodableRequest`] or [`UndecodableResponse`] error. The server or client can then fail
+//! just the one request, instead of closing the connection.
+//!
+//! A codec decoding requests can also be given per-method [`PayloadLimits`]. It reads which
+//! method a request calls the same way it recovers the request ID, and rejects a frame too long
+//! for that method with an [`UndecodableRequest`] error with code
+//! [`PayloadTooLarge`](ErrorCode::PayloadTooLarge), before deserializing the request.
+//!
+//! A codec given [`Metrics`] reports the bytes of every frame it reads and writes, length prefix
+//! included. A codec given [payload metrics](Codec::with_payload_metrics) also reports the size
+//! of every request and response it reads or writes, labeled by the method the request calls.
+
+pub use crate::frame::{Bincode, Format, FrameTooLong, DEFAULT_MAX_FRAME_LEN};
+
+use crate::frame::{self, LEN_PREFIX};
+use bytes::BytesMut;
+use rpc::{
+    metrics::Metrics, server::limits::PayloadLimits, ErrorCode, UndecodableRequest,
+    UndecodableResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io, marker::PhantomData};
+use tokio_codec::{Decoder, Encoder};
+
+/// The kind of rpc message a [`Codec`] decodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decodes {
+    /// [`ClientMessage`](rpc::ClientMessage)s, as read by servers.
+    Requests,
+    /// [`ServerMessage`](rpc::ServerMessage)s, as read by clients.
+    Responses,
+    /// Anything else. Frames that can't be decoded are plain errors.
+    Other,
+}
+
+/// Encodes `SinkItem`s and decodes `Item`s as length-delimited frames, in bincode unless another
+/// [`Format`] is given.
+#[derive(Debug)]
+pub struct Codec<Item, SinkItem, F = Bincode> {
+    max_frame_len: usize,
+    decodes: Decodes,
+    format: F,
+    metrics: Option<Metrics>,
+    payload_limits: Option<MethodLimits>,
+    payload_metrics: Option<PayloadMetrics>,
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+/// Payload limits, along with the names of the methods they apply to, in the order of the
+/// variants of the request type.
+#[derive(Clone, Debug)]
+pub(crate) struct MethodLimits {
+    limits: PayloadLimits,
+    methods: &'static [&'static str],
+}
+
+impl MethodLimits {
+    pub(crate) fn new(limits: PayloadLimits, methods: &'static [&'static str]) -> Self {
+        MethodLimits { limits, methods }
+    }
+
+    fn max_bytes(&self, method: u32) -> (&'static str, u64) {
+        match self.methods.get(method as usize) {
+            Some(name) => (name, self.limits.max_bytes(name)),
+            None => ("unknown", self.limits.default_max_bytes()),
+        }
+    }
+}
+
+/// Reports the sizes of request and response payloads, labeled by the names of the methods, in
+/// the order of the variants of the request type.
+#[derive(Clone, Debug)]
+pub(crate) struct PayloadMetrics {
+    metrics: Metrics,
+    methods: &'static [&'static str],
+    /// The method each request in flight calls, by request ID, to label its response with.
+    in_flight: HashMap<u64, &'static str>,
+}
+
+impl PayloadMetrics {
+    pub(crate) fn new(metrics: Metrics, methods: &'static [&'static str]) -> Self {
+        PayloadMetrics {
+            metrics,
+            methods,
+            in_flight: HashMap::new(),
+        }
+    }
+
+    /// Reports the request in `frame`, read or written on `side`, if it holds one.
+    fn request<F: Format>(&mut self, format: &F, side: &'static str, frame: &[u8]) {
+        let header = match format.deserialize::<RequestHeader>(frame) {
+            Ok(header) => header,
+            Err(_) => return,
+        };
+        if header.kind == CANCEL_KIND {
+            self.in_flight.remove(&header.request_id);
+            return;
+        }
+        if !holds_request_body(header.kind) {
+            return;
+        }
+        let method = request_method(format, frame, self.methods.len())
+            .and_then(|method| self.methods.get(method as usize).cloned())
+            .unwrap_or("unknown");
+        self.metrics
+            .request_payload(side, method, frame.len() as u64);
+        if header.kind == REQUEST_KIND || header.kind == STREAMING_REQUEST_KIND {
+            self.in_flight.insert(header.request_id, method);
+        }
+    }
+
+    /// Reports the response in `frame`, read or written on `side`, if it holds one.
+    fn response<F: Format>(&mut self, format: &F, side: &'static str, frame: &[u8]) {
+        let header = match format.deserialize::<ResponseHeader>(frame) {
+            Ok(header) => header,
+            Err(_) => return,
+        };
+        let method = match header.kind {
+            RESPONSE_KIND => self.in_flight.remove(&header.request_id),
+            STREAM_ITEM_KIND => self.in_flight.get(&header.request_id).cloned(),
+            _ => return,
+        };
+        let method = method.unwrap_or("unknown");
+        self.metrics
+            .response_payload(side, method, frame.len() as u64);
+    }
+}
+
+impl<Item, SinkItem> Codec<Item, SinkItem> {
+    /// Returns a bincode codec that rejects frames longer than `max_frame_len` bytes, not counting
+    /// the length prefix.
+    pub fn new(max_frame_len: usize) -> Self {
+        Codec::with_format(max_frame_len, Bincode)
+    }
+}
+
+impl<Item, SinkItem, F> Codec<Item, SinkItem, F> {
+    /// Returns a codec that serializes frames in `format`, and rejects frames longer than
+    /// `max_frame_len` bytes, not counting the length prefix.
+    pub fn with_format(max_frame_len: usize, format: F) -> Self {
+        Codec {
+            max_frame_len: max_frame_len.min(u32::max_value() as usize),
+            decodes: Decodes::Other,
+            format,
+            metrics: None,
+            payload_limits: None,
+            payload_metrics: None,
+            ghost: PhantomData,
+        }
+    }
+
+    /// Sets the kind of rpc message the codec decodes.
+    pub fn decoding(mut self, decodes: Decodes) -> Self {
+        self.decodes = decodes;
+        self
+    }
+
+    /// Reports the bytes the codec reads and writes to `metrics`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Rejects requests whose frames are longer than `limits` allows for the method they call,
+    /// with a [`PayloadTooLarge`](ErrorCode::PayloadTooLarge) error, without deserializing them.
+    /// `methods` names the methods in the order of the variants of the request type, e.g. the
+    /// `Request::METHODS` of a service defined with `tarpc::service!`.
+    ///
+    /// Only applies to codecs [decoding requests](Decodes::Requests). A frame's length, not
+    /// counting the length prefix, includes the request's trace context, deadline, and metadata
+    /// along with its arguments. Each item of a streamed request body is held to the limit of the
+    /// method whose body it's in.
+    pub fn with_payload_limits(
+        mut self,
+        limits: PayloadLimits,
+        methods: &'static [&'static str],
+    ) -> Self {
+        self.payload_limits = Some(MethodLimits::new(limits, methods));
+        self
+    }
+
+    pub(crate) fn with_method_limits(mut self, limits: Option<MethodLimits>) -> Self {
+        self.payload_limits = limits;
+        self
+    }
+
+    /// Reports the size of every request and response the codec reads or writes to `metrics`,
+    /// as [payload histograms](Metrics::request_payload) labeled by the method the request
+    /// calls. `methods` names the methods in the order of the variants of the request type, e.g.
+    /// the `Request::METHODS` of a service defined with `tarpc::service!`, which are the names
+    /// its `Request::name` returns.
+    ///
+    /// Only applies to codecs [decoding](Decodes) requests or responses. Sizes are of frames, not
+    /// counting the length prefix.
+    pub fn with_payload_metrics(
+        mut self,
+        metrics: Metrics,
+        methods: &'static [&'static str],
+    ) -> Self {
+        self.payload_metrics = Some(PayloadMetrics::new(metrics, methods));
+        self
+    }
+
+    pub(crate) fn with_method_metrics(mut self, metrics: Option<PayloadMetrics>) -> Self {
+        self.payload_metrics = metrics;
+        self
+    }
+
+    /// Returns the maximum length of a frame, not counting the length prefix.
+    pub fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+}
+
+impl<Item, SinkItem, F: Default> Default for Codec<Item, SinkItem, F> {
+    fn default() -> Self {
+        Codec::with_format(DEFAULT_MAX_FRAME_LEN, F::default())
+    }
+}
+
+impl<Item, SinkItem, F> Decoder for Codec<Item, SinkItem, F>
+where
+    Item: for<'de> Deserialize<'de>,
+    F: Format,
+{
+    type Item = Item;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Item>> {
+        let frame = match frame::decode(src, self.max_frame_len)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.read((LEN_PREFIX + frame.len()) as u64);
+        }
+        if let Some(payloads) = &mut self.payload_metrics {
+            match self.decodes {
+                Decodes::Requests => payloads.request(&self.format, "server", &frame),
+                Decodes::Responses => payloads.response(&self.format, "client", &frame),
+                Decodes::Other => {}
+            }
+        }
+        self.check_payload_limit(&frame)?;
+        self.format
+            .deserialize(&frame)
+            .map(Some)
+            .map_err(|e| self.decode_error(&frame, e))
+    }
+}
+
+impl<Item, SinkItem, F: Format> Codec<Item, SinkItem, F> {
+    fn check_payload_limit(&self, frame: &[u8]) -> io::Result<()> {
+        let limits = match (&self.payload_limits, self.decodes) {
+            (Some(limits), Decodes::Requests) => limits,
+            _ => return Ok(()),
+        };
+        let header = match self.format.deserialize::<RequestHeader>(frame) {
+            Ok(header) => header,
+            // Left for decoding to fail on.
+            Err(_) => return Ok(()),
+        };
+        // Cancellations call no method.
+        if !holds_request_body(header.kind) {
+            return Ok(());
+        }
+        let method = match request_method(&self.format, frame, limits.methods.len()) {
+            Some(method) => method,
+            None => return Ok(()),
+        };
+        let (method, max_bytes) = limits.max_bytes(method);
+        if frame.len() as u64 <= max_bytes {
+            return Ok(());
+        }
+        let detail = format!(
+            "Payload too large: {} bytes exceeds the {}-byte limit of method {}.",
+            frame.len(),
+            max_bytes,
+            method
+        );
+        Err(
+            UndecodableRequest::new(header.trace_context, header.request_id, detail)
+                .with_code(ErrorCode::PayloadTooLarge)
+                .with_notification(header.kind == NOTIFICATION_KIND)
+                .with_stream_item(header.kind == REQUEST_ITEM_KIND)
+                .into(),
+        )
+    }
+
+    fn decode_error(&self, frame: &[u8], e: io::Error) -> io::Error {
+        match self.decodes {
+            Decodes::Requests => match self.format.deserialize::<RequestHeader>(frame) {
+                Ok(ref header) if holds_request_body(header.kind) => {
+                    let (trace_context, request_id) = (header.trace_context, header.request_id);
+                    return UndecodableRequest::new(trace_context, request_id, e.to_string())
+                        .with_notification(header.kind == NOTIFICATION_KIND)
+                        .with_stream_item(header.kind == REQUEST_ITEM_KIND)
+                        .into();
+                }
+                _ => {}
+            },
+            Decodes::Responses => match self.format.deserialize::<ResponseHeader>(frame) {
+                Ok(ref header)
+                    if header.kind == RESPONSE_KIND || header.kind == STREAM_ITEM_KIND =>
+                {
+                    return UndecodableResponse::new(header.request_id, e.to_string()).into();
+                }
+                _ => {}
+            },
+            Decodes::Other => {}
+        }
+        e
+    }
+}
+
+/// Returns the variant index of the method the request in `frame` calls, out of `methods`, or of
+/// the method whose streamed body the item in `frame` is in.
+fn request_method<F: Format>(format: &F, frame: &[u8], methods: usize) -> Option<u32> {
+    let MethodHeader { header, method } = format.deserialize::<MethodHeader>(frame).ok()?;
+    match header.kind {
+        REQUEST_ITEM_KIND if method as usize == methods => format
+            .deserialize::<ItemMethodHeader>(frame)
+            .ok()
+            .map(|item| item.method),
+        _ => Some(method),
+    }
+}
+
+/// The variant index of [`ClientMessageKind::Request`](rpc::ClientMessageKind::Request).
+const REQUEST_KIND: u32 = 0;
+
+/// The variant index of [`ClientMessageKind::Cancel`](rpc::ClientMessageKind::Cancel), which
+/// starts with a request ID too.
+const CANCEL_KIND: u32 = 1;
+
+/// The variant index of
+/// [`ClientMessageKind::Notification`](rpc::ClientMessageKind::Notification), which holds a
+/// request too.
+const NOTIFICATION_KIND: u32 = 2;
+
+/// The variant index of
+/// [`ClientMessageKind::StreamingRequest`](rpc::ClientMessageKind::StreamingRequest), which holds
+/// a request too.
+const STREAMING_REQUEST_KIND: u32 = 4;
+
+/// The variant index of [`ClientMessageKind::StreamItem`](rpc::ClientMessageKind::StreamItem),
+/// which starts with a request ID, followed by an item encoded like a request body.
+const REQUEST_ITEM_KIND: u32 = 5;
+
+/// Whether messages of `kind` hold a request body, whose method the payload limits apply to.
+fn holds_request_body(kind: u32) -> bool {
+    [
+        REQUEST_KIND,
+        NOTIFICATION_KIND,
+        STREAMING_REQUEST_KIND,
+        REQUEST_ITEM_KIND,
+    ]
+    .contains(&kind)
+}
+
+/// The variant index of [`ServerMessage::Response`](rpc::ServerMessage::Response).
+const RESPONSE_KIND: u32 = 0;
+
+/// The variant index of [`ServerMessage::StreamItem`](rpc::ServerMessage::StreamItem), which
+/// starts with a request ID too.
+const STREAM_ITEM_KIND: u32 = 2;
+
+/// The bincode encoding of a [`ClientMessage`](rpc::ClientMessage) holding a request starts with
+/// these fields, followed by the request body. They can be decoded even when the body can't. In
+/// formats that encode messages differently, the fields just fail to decode too.
+#[derive(Deserialize)]
+struct RequestHeader {
+    trace_context: trace::Context,
+    kind: u32,
+    request_id: u64,
+}
+
+/// The bincode encoding of a [`ServerMessage`](rpc::ServerMessage) holding a response likewise
+/// starts with these fields.
+#[derive(Deserialize)]
+struct ResponseHeader {
+    kind: u32,
+    request_id: u64,
+}
+
+/// The body of a request starts with the variant index of the method it calls, in bincode.
+#[derive(Deserialize)]
+struct MethodHeader {
+    header: RequestHeader,
+    method: u32,
+}
+
+/// The items of the streamed bodies of requests to services defined with `tarpc::service!` are
+/// sent in the request variant after the last method's, which starts with the variant index of
+/// the method whose body they're in, so they're held to that method's limit.
+#[derive(Deserialize)]
+struct ItemMethodHeader {
+    header: MethodHeader,
+    method: u32,
+}
+
+impl<Item, SinkItem, F> Encoder for Codec<Item, SinkItem, F>
+where
+    SinkItem: Serialize,
+    F: Format,
+{
+    type Item = SinkItem;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: SinkItem, dst: &mut BytesMut) -> io::Result<()> {
+        let start = dst.len();
+        let len = frame::encode(&self.format, self.max_frame_len, &item, dst)?;
+        if let Some(metrics) = &self.metrics {
+            metrics.written(len);
+        }
+        if let Some(payloads) = &mut self.payload_metrics {
+            let frame = &dst[start + LEN_PREFIX..];
+            match self.decodes {
+                Decodes::Requests => payloads.response(&self.format, "server", frame),
+                Decodes::Responses => payloads.request(&self.format, "client", frame),
+                Decodes::Other => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Codec, Decodes, Format, FrameTooLong};
+    use bytes::{BufMut, BytesMut};
+    use rpc::{
+        metrics::{Labels, Metrics, MetricsSink},
+        server::limits::PayloadLimits,
+        ClientMessage, ErrorCode, ServerMessage, UndecodableRequest, UndecodableResponse,
+    };
+    use serde::{Deserialize, Serialize};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+    use tokio_codec::{Decoder, Encoder};
+
+    #[test]
+    fn round_trip() {
+        let mut codec = Codec::<String, String>::default();
+        let mut buf = BytesMut::new();
+        codec.encode("hello".into(), &mut buf).unwrap();
+        codec.encode("world".into(), &mut buf).unwrap();
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("hello".into()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("world".into()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn waits_for_full_frame() {
+        let mut codec = Codec::<String, String>::default();
+        let mut buf = BytesMut::new();
+        codec.encode("hello".into(), &mut buf).unwrap();
+        let mut partial = buf.split_to(buf.len() - 1);
+
+        assert_eq!(codec.decode(&mut partial).unwrap(), None);
+        partial.extend_from_slice(&buf);
+        assert_eq!(codec.decode(&mut partial).unwrap(), Some("hello".into()));
+    }
+
+    #[test]
+    fn rejects_oversized_length_prefix() {
+        let mut codec = Codec::<String, String>::new(16);
+        let mut buf = BytesMut::with_capacity(4);
+        buf.put_u32_be(u32::max_value());
+
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = e.get_ref().unwrap().downcast_ref::<FrameTooLong>().unwrap();
+        assert_eq!(e.len, u64::from(u32::max_value()));
+        assert_eq!(e.max_frame_len, 16);
+        // Space for the claimed frame was never reserved.
+        assert!(buf.capacity() < 1024);
+    }
+
+    #[test]
+    fn rejects_oversized_item() {
+        let mut codec = Codec::<String, String>::new(16);
+        let mut buf = BytesMut::new();
+        assert!(codec.encode("a".repeat(17), &mut buf).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn undecodable_request_keeps_request_id() {
+        #[derive(Serialize)]
+        enum NewRequest {
+            #[allow(dead_code)]
+            Old(String),
+            New(String),
+        }
+        #[derive(Deserialize)]
+        enum OldRequest {
+            #[allow(dead_code)]
+            Old(String),
+        }
+
+        // Encoded the same as a ClientMessage holding a request with ID 7.
+        type NewClientMessage = (trace::Context, u32, u64, NewRequest, u64);
+
+        let mut codec = Codec::<ClientMessage<OldRequest>, NewClientMessage>::default()
+            .decoding(Decodes::Requests);
+        let trace_context = trace::Context::new_root();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(
+                (trace_context, 0, 7, NewRequest::New("hi".into()), 0),
+                &mut buf,
+            )
+            .unwrap();
+
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<UndecodableRequest>()
+            .unwrap();
+        assert_eq!(e.request_id, 7);
+        assert_eq!(e.trace_context, trace_context);
+        assert!(!e.notification);
+
+        // Notifications hold requests too.
+        codec
+            .encode(
+                (trace_context, 2, 8, NewRequest::New("hi".into()), 0),
+                &mut buf,
+            )
+            .unwrap();
+        let e = codec.decode(&mut buf).unwrap_err();
+        let e = e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<UndecodableRequest>()
+            .unwrap();
+        assert_eq!(e.request_id, 8);
+        assert!(e.notification);
+
+        // So do the items of streamed request bodies, which start with the request's ID.
+        type NewStreamItem = (trace::Context, u32, u64, NewRequest);
+        let mut codec = Codec::<ClientMessage<OldRequest>, NewStreamItem>::default()
+            .decoding(Decodes::Requests);
+        codec
+            .encode(
+                (trace_context, 5, 9, NewRequest::New("hi".into())),
+                &mut buf,
+            )
+            .unwrap();
+        let e = codec.decode(&mut buf).unwrap_err();
+        let e = e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<UndecodableRequest>()
+            .unwrap();
+        assert_eq!(e.request_id, 9);
+        assert!(e.stream_item);
+        assert!(!e.notification);
+    }
+
+    #[test]
+    fn rejects_requests_over_their_method_limit() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        #[allow(non_camel_case_types)]
+        enum Request {
+            hello(String),
+            upload_chunk(Vec<u8>),
+            __Item(RequestItem),
+        }
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        #[allow(non_camel_case_types)]
+        enum RequestItem {
+            hello(()),
+            upload_chunk(Vec<u8>),
+        }
+        const METHODS: &[&str] = &["hello", "upload_chunk"];
+
+        // Encoded the same as the start of a ClientMessage holding a request.
+        type Message = (trace::Context, u32, u64, Request);
+
+        let limits = PayloadLimits::new(128).with_method("upload_chunk", 4096);
+        let mut codec = Codec::<Message, Message>::default()
+            .decoding(Decodes::Requests)
+            .with_payload_limits(limits, METHODS);
+        let trace_context = trace::Context::new_root();
+        let mut buf = BytesMut::new();
+        let chunk = (trace_context, 0, 7, Request::upload_chunk(vec![0; 1024]));
+        codec.encode(chunk, &mut buf).unwrap();
+        let hello = (trace_context, 2, 8, Request::hello("a".repeat(1024)));
+        codec.encode(hello, &mut buf).unwrap();
+        let small = (trace_context, 0, 9, Request::hello("hi".into()));
+        codec.encode(small, &mut buf).unwrap();
+
+        let (_, _, request_id, _) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(request_id, 7);
+
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<UndecodableRequest>()
+            .unwrap();
+        assert_eq!(e.request_id, 8);
+        assert_eq!(e.code, ErrorCode::PayloadTooLarge);
+        assert!(e.notification);
+
+        // The rejected frame was consumed, so the connection can carry on.
+        let (_, _, request_id, request) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(request_id, 9);
+        assert_eq!(request, Request::hello("hi".into()));
+
+        // Items of streamed bodies are held to the limit of the method whose body they're in.
+        let item = |request_id, len| {
+            let item = RequestItem::upload_chunk(vec![0; len]);
+            (trace_context, 5, request_id, Request::__Item(item))
+        };
+        codec.encode(item(10, 1024), &mut buf).unwrap();
+        codec.encode(item(10, 8192), &mut buf).unwrap();
+
+        let (_, _, request_id, _) = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(request_id, 10);
+
+        let e = codec.decode(&mut buf).unwrap_err();
+        let e = e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<UndecodableRequest>()
+            .unwrap();
+        assert_eq!(e.request_id, 10);
+        assert_eq!(e.code, ErrorCode::PayloadTooLarge);
+        assert!(e.stream_item);
+    }
+
+    #[test]
+    fn undecodable_response_keeps_request_id() {
+        #[derive(Serialize)]
+        enum NewResponse {
+            #[allow(dead_code)]
+            Old(String),
+            New(u64),
+        }
+        #[derive(Deserialize)]
+        enum OldResponse {
+            #[allow(dead_code)]
+            Old(String),
+        }
+
+        // Encoded the same as a message holding a successful Response to the request with ID 7.
+        type NewResponseMessage = (u32, u64, Result<NewResponse, ()>);
+
+        let mut codec = Codec::<ServerMessage<OldResponse>, NewResponseMessage>::default()
+            .decoding(Decodes::Responses);
+        let mut buf = BytesMut::new();
+        codec
+            .encode((0, 7, Ok(NewResponse::New(1))), &mut buf)
+            .unwrap();
+
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<UndecodableResponse>()
+            .unwrap();
+        assert_eq!(e.request_id, 7);
+    }
+
+    #[test]
+    fn undecodable_stream_item_keeps_request_id() {
+        // Encoded the same as a message holding an item streamed to the request with ID 7.
+        type NewStreamItemMessage = (u32, u64, u64);
+
+        let mut codec = Codec::<ServerMessage<String>, NewStreamItemMessage>::default()
+            .decoding(Decodes::Responses);
+        let mut buf = BytesMut::new();
+        codec.encode((2, 7, u64::max_value()), &mut buf).unwrap();
+
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let e = e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<UndecodableResponse>()
+            .unwrap();
+        assert_eq!(e.request_id, 7);
+    }
+
+    #[test]
+    fn undecodable_frame_without_kind_is_plain_error() {
+        let mut codec = Codec::<(u64, bool), (u64, u8)>::default();
+        let mut buf = BytesMut::new();
+        codec.encode((7, 2), &mut buf).unwrap();
+
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<UndecodableResponse>()
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_inner_length_beyond_frame() {
+        let mut codec = Codec::<Vec<u8>, Vec<u8>>::default();
+        let mut buf = BytesMut::with_capacity(12);
+        // A frame of 8 bytes claiming to hold a Vec of u64::MAX bytes.
+        buf.put_u32_be(8);
+        buf.put_u64_le(u64::max_value());
+
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn other_format() {
+        /// Writes strings as their bytes, for a format that isn't bincode.
+        struct Raw;
+
+        impl Format for Raw {
+            fn serialize_into<W: io::Write, T: Serialize>(
+                &self,
+                mut writer: W,
+                item: &T,
+            ) -> io::Result<()> {
+                let bytes = bincode::serialize(item).unwrap();
+                // Skip the length bincode writes before the string's bytes.
+                writer.write_all(&bytes[8..])
+            }
+
+            fn deserialize<T: for<'de> Deserialize<'de>>(&self, frame: &[u8]) -> io::Result<T> {
+                let mut bytes = bincode::serialize(&(frame.len() as u64)).unwrap();
+                bytes.extend_from_slice(frame);
+                bincode::deserialize(&bytes)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+
+        let mut codec = Codec::<String, String, _>::with_format(16, Raw);
+        let mut buf = BytesMut::new();
+        codec.encode("hello".into(), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"\0\0\0\x05hello");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some("hello".into()));
+
+        assert!(codec.encode("a".repeat(17), &mut buf).is_err());
+    }
+    #[test]
+    fn reports_bytes_on_the_wire() {
+        #[derive(Clone, Default)]
+        struct Bytes(Arc<Mutex<Vec<(&'static str, u64)>>>);
+
+        impl MetricsSink for Bytes {
+            fn counter(&self, name: &'static str, _: Labels, delta: u64) {
+                self.0.lock().unwrap().push((name, delta));
+            }
+
+            fn gauge(&self, _: &'static str, _: Labels, _: f64) {}
+
+            fn histogram(&self, _: &'static str, _: Labels, _: f64) {}
+        }
+
+        let bytes = Bytes::default();
+        let mut codec =
+            Codec::<String, String>::default().with_metrics(Metrics::new(bytes.clone()));
+        let mut buf = BytesMut::new();
+        codec.encode("hello".into(), &mut buf).unwrap();
+        codec.decode(&mut buf).unwrap();
+
+        // The length prefix, bincode's length of the string, and the string.
+        assert_eq!(
+            *bytes.0.lock().unwrap(),
+            vec![
+                ("tarpc_bytes_written_total", 17),
+                ("tarpc_bytes_read_total", 17)
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_payload_sizes_per_method() {
+        #[derive(Clone, Default)]
+        struct Payloads(Arc<Mutex<Vec<(&'static str, String, f64)>>>);
+
+        impl MetricsSink for Payloads {
+            fn counter(&self, _: &'static str, _: Labels, _: u64) {}
+
+            fn gauge(&self, _: &'static str, _: Labels, _: f64) {}
+
+            fn histogram(&self, name: &'static str, labels: Labels, value: f64) {
+                let labels: Vec<_> = labels.iter().map(|(_, v)| *v).collect();
+                self.0.lock().unwrap().push((name, labels.join(","), value));
+            }
+        }
+
+        #[derive(Serialize, Deserialize)]
+        #[allow(non_camel_case_types)]
+        enum Request {
+            hello(String),
+            upload_chunk(Vec<u8>),
+        }
+        const METHODS: &[&str] = &["hello", "upload_chunk"];
+
+        // Encoded the same as the start of a ClientMessage holding a request, and a ServerMessage
+        // holding a response.
+        type RequestMessage = (trace::Context, u32, u64, Request);
+        type ResponseMessage = (u32, u64, Result<String, ()>);
+
+        let payloads = Payloads::default();
+        let metrics = Metrics::new(payloads.clone());
+        let mut client = Codec::<ResponseMessage, RequestMessage>::default()
+            .decoding(Decodes::Responses)
+            .with_payload_metrics(metrics.clone(), METHODS);
+        let mut server = Codec::<RequestMessage, ResponseMessage>::default()
+            .decoding(Decodes::Requests)
+            .with_payload_metrics(metrics, METHODS);
+        let mut buf = BytesMut::new();
+        let chunk = (
+            trace::Context::new_root(),
+            0,
+            7,
+            Request::upload_chunk(vec![0; 1024]),
+        );
+        client.encode(chunk, &mut buf).unwrap();
+        server.decode(&mut buf).unwrap().unwrap();
+        server.encode((0, 7, Ok("done".into())), &mut buf).unwrap();
+        client.decode(&mut buf).unwrap().unwrap();
+
+        let payloads = payloads.0.lock().unwrap();
+        let labels: Vec<_> = payloads
+            .iter()
+            .map(|(name, labels, _)| format!("{}{{{}}}", name, labels))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                "tarpc_request_payload_bytes{client,upload_chunk}",
+                "tarpc_request_payload_bytes{server,upload_chunk}",
+                "tarpc_response_payload_bytes{server,upload_chunk}",
+                "tarpc_response_payload_bytes{client,upload_chunk}",
+            ]
+        );
+        // Both sides see the same frames, without their length prefixes.
+        assert!(payloads[0].2 > 1024.0);
+        assert_eq!(payloads[0].2, payloads[1].2);
+        // The response's kind and request ID, and bincode's Ok, length, and string.
+        assert_eq!(payloads[2].2, 28.0);
+        assert_eq!(payloads[2].2, payloads[3].2);
+    }
+}
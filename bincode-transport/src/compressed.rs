@@ -0,0 +1,1076 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A bincode transport that compresses frames with pluggable compression algorithms.
+//!
+//! Algorithms implement [`Algorithm`] and are registered in a [`Registry`] under a one-byte ID.
+//! Each frame carries the ID of the algorithm that compressed it, and a frame is decompressed with
+//! whichever algorithm the receiving registry has under that ID, so the two ends of a connection
+//! needn't compress with the same algorithm.
+//!
+//! When a connection is established by [`connect`] or [`listen`], each end tells the other which
+//! algorithms it has, and then only compresses with algorithms the other end has too, sending
+//! frames uncompressed otherwise. So a new algorithm can be rolled out by compressing with it
+//! everywhere at once; connections to peers that don't have it yet stay uncompressed. Transports
+//! made with [`new`] skip this negotiation, and compress with any configured algorithm.
+//!
+//! Over plain TCP, an attacker in the middle could strip algorithms from an offer, silently
+//! leaving the connection uncompressed. Ends that share a [`signed::Config`] can rule this out by
+//! [confirming](connect_confirmed) the negotiation: each end then signs what it saw of the
+//! negotiation, and the connection fails if the other end saw anything different.
+//!
+//! Gzip, Snappy, and LZ4 are built in, behind the `gzip`, `snappy`, and `lz4` features, but aren't
+//! registered by default.
+//!
+//! A transport can also be given a function that [hints](Hint) at what each message holds, e.g.
+//! text, or an image that is already compressed. The registry picks the algorithm to compress a
+//! message with by its hint, and by default doesn't recompress already-compressed payloads.
+
+use crate::{
+    signed::{self, Role},
+    Codec, Connector,
+};
+use futures::{compat::*, prelude::*, ready, stream::FuturesUnordered};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use rpc::server::IpFilter;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, io,
+    marker::PhantomData,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio_codec::Framed;
+use tokio_io::{
+    io::{read_exact, write_all},
+    AsyncRead, AsyncWrite,
+};
+use tokio_tcp::{TcpListener, TcpStream};
+use tokio_timer::Timeout;
+
+/// The ID of the built-in algorithm that leaves frames uncompressed. It is always registered.
+pub const UNCOMPRESSED: u8 = 0;
+/// The conventional ID of the [`Gzip`] algorithm.
+pub const GZIP: u8 = 1;
+/// The conventional ID of the [`Snappy`] algorithm.
+pub const SNAPPY: u8 = 2;
+/// The conventional ID of the [`Lz4`] algorithm.
+pub const LZ4: u8 = 3;
+
+/// What a message mostly holds, as far as compressing it goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Hint {
+    /// Nothing is known about the message.
+    Unknown,
+    /// Data that is already compressed, like JPEG images or video, which won't compress further.
+    Compressed,
+    /// Text, which usually compresses well.
+    Text,
+    /// Binary data that isn't already compressed.
+    Binary,
+}
+
+/// A compression algorithm.
+pub trait Algorithm: Send + Sync + 'static {
+    /// Returns `data`, compressed.
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Returns `data`, decompressed. Must fail, rather than allocate more, if the decompressed
+    /// data is longer than `max_len` bytes.
+    fn decompress(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>>;
+}
+
+#[derive(Debug)]
+struct Uncompressed;
+
+impl Algorithm for Uncompressed {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+
+    fn decompress(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        if data.len() > max_len {
+            return Err(CompressionError::TooLong { max_len }.into());
+        }
+        Ok(data.to_vec())
+    }
+}
+
+/// The compression algorithms known to one end of a compressed transport.
+#[derive(Clone)]
+pub struct Registry {
+    /// The ID of the algorithm used to compress outbound frames.
+    compress_with: u8,
+    /// The IDs of algorithms used instead for outbound frames with certain hints.
+    compress_hinted_with: HashMap<Hint, u8>,
+    /// All algorithms that inbound frames may be compressed with.
+    algorithms: Arc<HashMap<u8, Arc<dyn Algorithm>>>,
+    /// Payloads shorter than this are sent uncompressed.
+    min_len: usize,
+}
+
+impl Registry {
+    /// Returns a registry holding only the [uncompressed](UNCOMPRESSED) algorithm, which it also
+    /// compresses with.
+    pub fn new() -> Self {
+        let mut algorithms: HashMap<u8, Arc<dyn Algorithm>> = HashMap::new();
+        algorithms.insert(UNCOMPRESSED, Arc::new(Uncompressed));
+        let mut compress_hinted_with = HashMap::new();
+        compress_hinted_with.insert(Hint::Compressed, UNCOMPRESSED);
+        Registry {
+            compress_with: UNCOMPRESSED,
+            compress_hinted_with,
+            algorithms: Arc::new(algorithms),
+            min_len: 128,
+        }
+    }
+
+    /// Registers `algorithm` under `id`, replacing any algorithm previously registered under it.
+    ///
+    /// # Panics
+    ///
+    /// If `id` is [`UNCOMPRESSED`].
+    pub fn with_algorithm(mut self, id: u8, algorithm: impl Algorithm) -> Self {
+        assert_ne!(
+            id, UNCOMPRESSED,
+            "The uncompressed algorithm can't be replaced."
+        );
+        Arc::make_mut(&mut self.algorithms).insert(id, Arc::new(algorithm));
+        self
+    }
+
+    /// Compresses outbound frames with the algorithm registered under `id`, unless their
+    /// [hint](Hint) says otherwise.
+    ///
+    /// # Panics
+    ///
+    /// If no algorithm is registered under `id`.
+    pub fn compressing_with(mut self, id: u8) -> Self {
+        self.assert_registered(id);
+        self.compress_with = id;
+        self
+    }
+
+    /// Compresses outbound frames hinted `hint` with the algorithm registered under `id`, e.g. to
+    /// compress text with a slower algorithm that compresses it much better. Frames hinted
+    /// [`Compressed`](Hint::Compressed) are sent [uncompressed](UNCOMPRESSED) unless configured
+    /// otherwise.
+    ///
+    /// # Panics
+    ///
+    /// If no algorithm is registered under `id`.
+    pub fn compressing_hinted_with(mut self, hint: Hint, id: u8) -> Self {
+        self.assert_registered(id);
+        self.compress_hinted_with.insert(hint, id);
+        self
+    }
+
+    fn assert_registered(&self, id: u8) {
+        assert!(
+            self.algorithms.contains_key(&id),
+            "No compression algorithm is registered under ID {}.",
+            id
+        );
+    }
+
+    /// Sends payloads shorter than `min_len` bytes uncompressed, because compressing them costs
+    /// more than it saves. Defaults to 128 bytes.
+    pub fn with_min_len(mut self, min_len: usize) -> Self {
+        self.min_len = min_len;
+        self
+    }
+
+    /// Returns the IDs of the registered algorithms, other than [`UNCOMPRESSED`].
+    fn ids(&self) -> Vec<u8> {
+        let mut ids: Vec<_> = self
+            .algorithms
+            .keys()
+            .cloned()
+            .filter(|&id| id != UNCOMPRESSED)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// Compresses outbound frames only with algorithms that are also in `peer_ids`, sending frames
+    /// that would be compressed with other algorithms uncompressed instead.
+    fn shared_with(mut self, peer_ids: &[u8]) -> Self {
+        let shared = |id: u8| id == UNCOMPRESSED || peer_ids.contains(&id);
+        if !shared(self.compress_with) {
+            self.compress_with = UNCOMPRESSED;
+        }
+        for id in self.compress_hinted_with.values_mut() {
+            if !shared(*id) {
+                *id = UNCOMPRESSED;
+            }
+        }
+        self
+    }
+
+    fn compress(&self, payload: Vec<u8>, hint: Hint) -> io::Result<CompressedFrame> {
+        let algorithm = self
+            .compress_hinted_with
+            .get(&hint)
+            .cloned()
+            .unwrap_or(self.compress_with);
+        if algorithm == UNCOMPRESSED || payload.len() < self.min_len {
+            return Ok(CompressedFrame {
+                algorithm: UNCOMPRESSED,
+                payload,
+            });
+        }
+        Ok(CompressedFrame {
+            algorithm,
+            payload: self.algorithms[&algorithm].compress(&payload)?,
+        })
+    }
+
+    fn decompress(&self, frame: CompressedFrame, max_len: usize) -> io::Result<Vec<u8>> {
+        if frame.algorithm == UNCOMPRESSED {
+            return Ok(frame.payload);
+        }
+        self.algorithms
+            .get(&frame.algorithm)
+            .ok_or(CompressionError::UnknownAlgorithm(frame.algorithm))?
+            .decompress(&frame.payload, max_len)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut ids: Vec<_> = self.algorithms.keys().collect();
+        ids.sort();
+        f.debug_struct("Registry")
+            .field("compress_with", &self.compress_with)
+            .field("compress_hinted_with", &self.compress_hinted_with)
+            .field("algorithm_ids", &ids)
+            .field("min_len", &self.min_len)
+            .finish()
+    }
+}
+
+/// Gzip, which compresses well, but slowly. Requires the `gzip` feature.
+#[cfg(feature = "gzip")]
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct Gzip {
+    /// The compression level, from 0, for no compression, to 9, for the best. Defaults to 6.
+    pub level: u32,
+}
+
+#[cfg(feature = "gzip")]
+impl Default for Gzip {
+    fn default() -> Self {
+        Gzip { level: 6 }
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl Algorithm for Gzip {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        use std::io::Write;
+
+        let level = flate2::Compression::new(self.level);
+        let mut encoder = flate2::write::GzEncoder::new(vec![], level);
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    fn decompress(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        use std::io::Read;
+
+        let mut decompressed = vec![];
+        flate2::read::GzDecoder::new(data)
+            .take(max_len as u64 + 1)
+            .read_to_end(&mut decompressed)?;
+        if decompressed.len() > max_len {
+            return Err(CompressionError::TooLong { max_len }.into());
+        }
+        Ok(decompressed)
+    }
+}
+
+/// Snappy, which is fast, but compresses less. Requires the `snappy` feature.
+#[cfg(feature = "snappy")]
+#[derive(Clone, Debug, Default)]
+pub struct Snappy;
+
+#[cfg(feature = "snappy")]
+impl Algorithm for Snappy {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(snap::Encoder::new().compress_vec(data)?)
+    }
+
+    fn decompress(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        if snap::decompress_len(data)? > max_len {
+            return Err(CompressionError::TooLong { max_len }.into());
+        }
+        Ok(snap::Decoder::new().decompress_vec(data)?)
+    }
+}
+
+/// LZ4, which is fast, but compresses less. Requires the `lz4` feature.
+#[cfg(feature = "lz4")]
+#[derive(Clone, Debug, Default)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Algorithm for Lz4 {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        // Prefixed with the decompressed length, which is checked before decompressing.
+        liblz4::block::compress(data, None, true)
+    }
+
+    fn decompress(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+        if data.len() < 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "LZ4 frame is missing its decompressed length.",
+            ));
+        }
+        let mut len = [0; 4];
+        len.copy_from_slice(&data[..4]);
+        if i32::from_le_bytes(len) as usize > max_len {
+            return Err(CompressionError::TooLong { max_len }.into());
+        }
+        liblz4::block::decompress(data, None)
+    }
+}
+
+/// The reason a compressed frame was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CompressionError {
+    /// The frame was compressed with an algorithm that isn't registered.
+    UnknownAlgorithm(u8),
+    /// The frame decompressed to more than the max frame length.
+    TooLong {
+        /// The max frame length, in bytes.
+        max_len: usize,
+    },
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompressionError::UnknownAlgorithm(id) => {
+                write!(f, "Frame compressed with unknown algorithm {}.", id)
+            }
+            CompressionError::TooLong { max_len } => write!(
+                f,
+                "Frame decompresses to more than the max frame length of {} bytes.",
+                max_len
+            ),
+        }
+    }
+}
+
+impl Error for CompressionError {}
+
+impl From<CompressionError> for io::Error {
+    fn from(e: CompressionError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// The frame written to the wire.
+#[derive(Debug, Serialize, Deserialize)]
+struct CompressedFrame {
+    algorithm: u8,
+    payload: Vec<u8>,
+}
+
+/// Hints at what outbound messages hold.
+struct Hints<SinkItem>(Arc<dyn Fn(&SinkItem) -> Hint + Send + Sync>);
+
+impl<SinkItem> fmt::Debug for Hints<SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Hints")
+    }
+}
+
+/// A transport that compresses frames written to, and decompresses frames read from, a
+/// [`TcpStream`].
+#[derive(Debug)]
+pub struct Transport<S, Item, SinkItem> {
+    inner: Compat01As03Sink<Framed<S, Codec<CompressedFrame, CompressedFrame>>, CompressedFrame>,
+    registry: Registry,
+    hints: Option<Hints<SinkItem>>,
+    max_frame_len: usize,
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+impl<S, Item, SinkItem> Transport<S, Item, SinkItem> {
+    /// Picks the algorithm to compress each outbound message with using the [hint](Hint) that
+    /// `hints` returns for the message. Without hints, all messages are hinted
+    /// [`Unknown`](Hint::Unknown).
+    pub fn with_hints(mut self, hints: impl Fn(&SinkItem) -> Hint + Send + Sync + 'static) -> Self {
+        self.hints = Some(Hints(Arc::new(hints)));
+        self
+    }
+
+    unsafe_pinned!(
+        inner: Compat01As03Sink<Framed<S, Codec<CompressedFrame, CompressedFrame>>, CompressedFrame>
+    );
+    unsafe_unpinned!(registry: Registry);
+}
+
+impl<S, Item, SinkItem> Stream for Transport<S, Item, SinkItem>
+where
+    S: AsyncRead,
+    Item: for<'a> Deserialize<'a>,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
+        let frame = match ready!(self.as_mut().inner().poll_next(cx)?) {
+            Some(frame) => frame,
+            None => return Poll::Ready(None),
+        };
+        let max_frame_len = self.max_frame_len;
+        let payload = self.as_mut().registry().decompress(frame, max_frame_len)?;
+        Poll::Ready(Some(
+            bincode::config()
+                .limit(payload.len() as u64)
+                .deserialize(&payload)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        ))
+    }
+}
+
+impl<S, Item, SinkItem> Sink<SinkItem> for Transport<S, Item, SinkItem>
+where
+    S: AsyncWrite,
+    SinkItem: Serialize,
+{
+    type SinkError = io::Error;
+
+    fn start_send(mut self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        let hint = self
+            .hints
+            .as_ref()
+            .map(|hints| (hints.0)(&item))
+            .unwrap_or(Hint::Unknown);
+        let payload = bincode::serialize(&item)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let frame = self.as_mut().registry().compress(payload, hint)?;
+        self.inner().start_send(frame)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_ready(cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_close(cx)
+    }
+}
+
+impl<Item, SinkItem> rpc::Transport for Transport<TcpStream, Item, SinkItem>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    type Item = Item;
+    type SinkItem = SinkItem;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.get_ref().get_ref().peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.get_ref().get_ref().local_addr()
+    }
+}
+
+/// Returns a new compressed transport that reads from and writes to `io`.
+pub fn new<S, Item, SinkItem>(io: S, registry: Registry) -> Transport<S, Item, SinkItem>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    framed(io, Codec::default(), registry)
+}
+
+fn framed<S, Item, SinkItem>(
+    io: S,
+    codec: Codec<CompressedFrame, CompressedFrame>,
+    registry: Registry,
+) -> Transport<S, Item, SinkItem>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    Transport {
+        max_frame_len: codec.max_frame_len(),
+        inner: Compat01As03Sink::new(Framed::new(io, codec)),
+        registry,
+        hints: None,
+        ghost: PhantomData,
+    }
+}
+
+/// Connects to `addr`, and negotiates the algorithms to compress with with the server, before
+/// wrapping the connection in a compressed transport.
+pub async fn connect<Item, SinkItem>(
+    addr: &SocketAddr,
+    registry: Registry,
+) -> io::Result<Transport<TcpStream, Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    await!(Connector::new().compressed(addr, registry))
+}
+
+/// Like [`connect`], but confirms the negotiation under `config`'s signing key, failing with
+/// [`InvalidData`](io::ErrorKind::InvalidData) if it was tampered with. The server must
+/// [confirm](Incoming::with_confirmation) negotiations with the same keys.
+pub async fn connect_confirmed<Item, SinkItem>(
+    addr: &SocketAddr,
+    registry: Registry,
+    config: signed::Config,
+) -> io::Result<Transport<TcpStream, Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    await!(Connector::new().compressed_confirmed(addr, registry, config))
+}
+
+impl Connector {
+    /// Like [`connect`](self::connect), but connects with the options of this connector. The
+    /// transport isn't timed, but otherwise takes the connector's max frame length, which also
+    /// bounds decompressed payloads.
+    pub fn compressed<Item, SinkItem>(
+        &self,
+        addr: &SocketAddr,
+        registry: Registry,
+    ) -> impl Future<Output = io::Result<Transport<TcpStream, Item, SinkItem>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        self.negotiated(addr, registry, None)
+    }
+
+    /// Like [`connect_confirmed`], but connects with the options of this connector.
+    pub fn compressed_confirmed<Item, SinkItem>(
+        &self,
+        addr: &SocketAddr,
+        registry: Registry,
+        config: signed::Config,
+    ) -> impl Future<Output = io::Result<Transport<TcpStream, Item, SinkItem>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        self.negotiated(addr, registry, Some(config))
+    }
+
+    fn negotiated<Item, SinkItem>(
+        &self,
+        addr: &SocketAddr,
+        registry: Registry,
+        confirmation: Option<signed::Config>,
+    ) -> impl Future<Output = io::Result<Transport<TcpStream, Item, SinkItem>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let connector = self.clone();
+        let addr = *addr;
+        self.within(addr, async move {
+            let conn = await!(connector.tcp(addr))?;
+            let negotiation = negotiate(conn, registry, Role::Client, confirmation);
+            let (conn, registry) = await!(connector.handshaking(negotiation))?;
+            let codec = Codec::new(connector.max_frame_len());
+            Ok(framed(conn, codec, registry))
+        })
+    }
+}
+
+/// Tells the peer of `conn` the algorithms in `registry`, and returns `registry` restricted to the
+/// algorithms the peer has too.
+///
+/// Each end sends the number of algorithms it has, other than [`UNCOMPRESSED`], as one byte,
+/// followed by their IDs. With a `confirmation` config, the ends first exchange nonces, as in a
+/// [`signed::handshake`], and afterwards [confirm](signed::confirm) that they saw the same offers,
+/// the client's first.
+async fn negotiate<S>(
+    conn: S,
+    registry: Registry,
+    role: Role,
+    confirmation: Option<signed::Config>,
+) -> io::Result<(S, Registry)>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (conn, session) = match confirmation {
+        Some(_) => {
+            let (conn, session) = await!(signed::handshake(conn, role))?;
+            (conn, Some(session))
+        }
+        None => (conn, None),
+    };
+    let ids = registry.ids();
+    let mut offer = vec![ids.len() as u8];
+    offer.extend(ids);
+    let (conn, _) = await!(write_all(conn, offer.clone()).compat())?;
+    let (conn, len) = await!(read_exact(conn, [0; 1]).compat())?;
+    let (conn, peer_ids) = await!(read_exact(conn, vec![0; len[0] as usize]).compat())?;
+    let conn = match (confirmation, session) {
+        (Some(config), Some(session)) => {
+            let mut peer_offer = len.to_vec();
+            peer_offer.extend_from_slice(&peer_ids);
+            let transcript = match role {
+                Role::Client => [offer, peer_offer].concat(),
+                Role::Server => [peer_offer, offer].concat(),
+            };
+            await!(signed::confirm(conn, config, session, transcript))?
+        }
+        _ => conn,
+    };
+    Ok((conn, registry.shared_with(&peer_ids)))
+}
+
+/// Listens on `addr`, negotiating the algorithms to compress with with each client before wrapping
+/// its connection in a compressed transport.
+pub fn listen<Item, SinkItem>(
+    addr: &SocketAddr,
+    registry: Registry,
+) -> io::Result<Incoming<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let incoming = listener.incoming().compat();
+    Ok(Incoming {
+        incoming,
+        local_addr,
+        ip_filter: IpFilter::default(),
+        registry,
+        confirmation: None,
+        negotiations: FuturesUnordered::new(),
+        max_negotiations: 64,
+        negotiation_timeout: Duration::from_secs(10),
+        ghost: PhantomData,
+    })
+}
+
+type Negotiation = Pin<Box<dyn Future<Output = io::Result<(TcpStream, Registry)>> + Send>>;
+
+/// A [`TcpListener`] that negotiates the algorithms to compress with with each client before
+/// wrapping its connection in a compressed transport.
+///
+/// Negotiations run concurrently, up to a [limit](Incoming::with_max_negotiations), beyond which
+/// no more connections are accepted until a negotiation completes. A failed negotiation yields an
+/// error for that connection only.
+pub struct Incoming<Item, SinkItem> {
+    incoming: Compat01As03<tokio_tcp::Incoming>,
+    local_addr: SocketAddr,
+    ip_filter: IpFilter,
+    registry: Registry,
+    confirmation: Option<signed::Config>,
+    negotiations: FuturesUnordered<Negotiation>,
+    max_negotiations: usize,
+    negotiation_timeout: Duration,
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+impl<Item, SinkItem> fmt::Debug for Incoming<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Incoming")
+            .field("local_addr", &self.local_addr)
+            .field("ip_filter", &self.ip_filter)
+            .field("registry", &self.registry)
+            .field("confirmation", &self.confirmation)
+            .field("negotiations", &self.negotiations.len())
+            .field("max_negotiations", &self.max_negotiations)
+            .field("negotiation_timeout", &self.negotiation_timeout)
+            .finish()
+    }
+}
+
+impl<Item, SinkItem> Incoming<Item, SinkItem> {
+    unsafe_pinned!(incoming: Compat01As03<tokio_tcp::Incoming>);
+    unsafe_unpinned!(negotiations: FuturesUnordered<Negotiation>);
+
+    /// Returns the address being listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Closes connections from IPs that `ip_filter` doesn't allow as soon as they're accepted,
+    /// before the negotiation. Allows every IP by default.
+    pub fn with_ip_filter(mut self, ip_filter: IpFilter) -> Self {
+        self.ip_filter = ip_filter;
+        self
+    }
+
+    /// Sets the maximum number of negotiations in progress at once. Defaults to 64.
+    pub fn with_max_negotiations(mut self, max_negotiations: usize) -> Self {
+        self.max_negotiations = max_negotiations;
+        self
+    }
+
+    /// Sets how long a client has to complete the negotiation before its connection is closed.
+    /// Defaults to 10 seconds.
+    pub fn with_negotiation_timeout(mut self, negotiation_timeout: Duration) -> Self {
+        self.negotiation_timeout = negotiation_timeout;
+        self
+    }
+
+    /// Confirms each negotiation under `config`'s signing key, failing the connection if it was
+    /// tampered with. Clients must then connect with [`connect_confirmed`], and the same keys.
+    pub fn with_confirmation(mut self, config: signed::Config) -> Self {
+        self.confirmation = Some(config);
+        self
+    }
+}
+
+impl<Item, SinkItem> Stream for Incoming<Item, SinkItem>
+where
+    Item: for<'a> Deserialize<'a>,
+    SinkItem: Serialize,
+{
+    type Item = io::Result<Transport<TcpStream, Item, SinkItem>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while self.negotiations.len() < self.max_negotiations {
+            match self.as_mut().incoming().poll_next(cx) {
+                Poll::Ready(Some(Ok(conn))) => {
+                    if !crate::is_peer_allowed(&self.ip_filter, &conn) {
+                        continue;
+                    }
+                    let negotiation = Timeout::new(
+                        negotiate(
+                            conn,
+                            self.registry.clone(),
+                            Role::Server,
+                            self.confirmation.clone(),
+                        )
+                        .boxed()
+                        .compat(),
+                        self.negotiation_timeout,
+                    )
+                    .compat()
+                    .map_err(|e| {
+                        if e.is_elapsed() {
+                            io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "Client didn't complete the compression negotiation in time.",
+                            )
+                        } else if e.is_inner() {
+                            e.into_inner().expect("Checked by is_inner.")
+                        } else {
+                            io::Error::new(
+                                io::ErrorKind::Other,
+                                format!("Could not set the negotiation timeout: {}", e),
+                            )
+                        }
+                    });
+                    self.as_mut().negotiations().push(negotiation.boxed());
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) if self.negotiations.is_empty() => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+
+        match ready!(self.as_mut().negotiations().poll_next_unpin(cx)) {
+            Some(negotiation) => {
+                let (conn, registry) = negotiation?;
+                Poll::Ready(Some(Ok(new(conn, registry))))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate, Algorithm, CompressionError, Hint, Registry, UNCOMPRESSED};
+    use crate::signed::{self, Role};
+    use futures::{compat::*, prelude::*};
+    use std::io::{self, Read, Write};
+    use tokio_io::{AsyncRead, AsyncWrite};
+    use tokio_tcp::{TcpListener, TcpStream};
+
+    /// Run-length encodes bytes as (count, byte) pairs.
+    struct RunLength;
+
+    impl Algorithm for RunLength {
+        fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+            let mut compressed = vec![];
+            for &byte in data {
+                match compressed.len() {
+                    len if len >= 2 && compressed[len - 1] == byte && compressed[len - 2] < 255 => {
+                        compressed[len - 2] += 1
+                    }
+                    _ => compressed.extend_from_slice(&[1, byte]),
+                }
+            }
+            Ok(compressed)
+        }
+
+        fn decompress(&self, data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+            let mut decompressed = vec![];
+            for run in data.chunks(2) {
+                if decompressed.len() + run[0] as usize > max_len {
+                    return Err(CompressionError::TooLong { max_len }.into());
+                }
+                decompressed.extend(std::iter::repeat(run[1]).take(run[0] as usize));
+            }
+            Ok(decompressed)
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let registry = Registry::new()
+            .with_algorithm(1, RunLength)
+            .compressing_with(1);
+        let payload = vec![7; 1000];
+
+        let frame = registry.compress(payload.clone(), Hint::Unknown).unwrap();
+        assert_eq!(frame.algorithm, 1);
+        assert!(frame.payload.len() < 20);
+        assert_eq!(registry.decompress(frame, 1000).unwrap(), payload);
+    }
+
+    #[test]
+    fn short_payloads_are_uncompressed() {
+        let registry = Registry::new()
+            .with_algorithm(1, RunLength)
+            .compressing_with(1)
+            .with_min_len(16);
+
+        let frame = registry.compress(vec![7; 15], Hint::Unknown).unwrap();
+        assert_eq!(frame.algorithm, UNCOMPRESSED);
+        assert_eq!(frame.payload, vec![7; 15]);
+    }
+
+    #[test]
+    fn compresses_by_hint() {
+        let registry = Registry::new()
+            .with_algorithm(1, RunLength)
+            .with_algorithm(2, RunLength)
+            .compressing_with(1)
+            .compressing_hinted_with(Hint::Text, 2);
+
+        let frame = registry.compress(vec![7; 1000], Hint::Text).unwrap();
+        assert_eq!(frame.algorithm, 2);
+        let frame = registry.compress(vec![7; 1000], Hint::Binary).unwrap();
+        assert_eq!(frame.algorithm, 1);
+        let frame = registry.compress(vec![7; 1000], Hint::Compressed).unwrap();
+        assert_eq!(frame.algorithm, UNCOMPRESSED);
+    }
+
+    #[test]
+    fn decompresses_with_any_registered_algorithm() {
+        let sender = Registry::new()
+            .with_algorithm(1, RunLength)
+            .compressing_with(1);
+        // The receiver knows the algorithm, but doesn't compress with it.
+        let receiver = Registry::new().with_algorithm(1, RunLength);
+
+        let frame = sender.compress(vec![7; 1000], Hint::Unknown).unwrap();
+        assert_eq!(receiver.decompress(frame, 1000).unwrap(), vec![7; 1000]);
+    }
+
+    #[test]
+    fn rejects_unknown_algorithm() {
+        let sender = Registry::new()
+            .with_algorithm(2, RunLength)
+            .compressing_with(2);
+        let receiver = Registry::new().with_algorithm(1, RunLength);
+
+        let frame = sender.compress(vec![7; 1000], Hint::Unknown).unwrap();
+        let e = receiver.decompress(frame, 1000).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.get_ref().unwrap().downcast_ref::<CompressionError>(),
+            Some(&CompressionError::UnknownAlgorithm(2))
+        );
+    }
+
+    #[test]
+    fn compresses_only_with_algorithms_the_peer_has() {
+        let registry = Registry::new()
+            .with_algorithm(1, RunLength)
+            .with_algorithm(2, RunLength)
+            .compressing_with(1)
+            .compressing_hinted_with(Hint::Text, 2);
+        assert_eq!(registry.ids(), vec![1, 2]);
+
+        let registry = registry.shared_with(&[2]);
+        let frame = registry.compress(vec![7; 1000], Hint::Unknown).unwrap();
+        assert_eq!(frame.algorithm, UNCOMPRESSED);
+        let frame = registry.compress(vec![7; 1000], Hint::Text).unwrap();
+        assert_eq!(frame.algorithm, 2);
+    }
+
+    #[test]
+    fn rejects_frame_decompressing_past_max_len() {
+        let registry = Registry::new()
+            .with_algorithm(1, RunLength)
+            .compressing_with(1);
+
+        let frame = registry.compress(vec![7; 1000], Hint::Unknown).unwrap();
+        let e = registry.decompress(frame, 999).unwrap_err();
+        assert_eq!(
+            e.get_ref().unwrap().downcast_ref::<CompressionError>(),
+            Some(&CompressionError::TooLong { max_len: 999 })
+        );
+    }
+
+    /// Replaces the offer written after the nonce with an empty one, like an attacker in the
+    /// middle would, if `strip` is set.
+    struct StripOffer {
+        conn: TcpStream,
+        strip: bool,
+        written: usize,
+    }
+
+    impl Read for StripOffer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.conn.read(buf)
+        }
+    }
+
+    impl Write for StripOffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.strip && self.written == 16 {
+                self.conn.write(&[0])?;
+                self.strip = false;
+                return Ok(buf.len());
+            }
+            let written = self.conn.write(buf)?;
+            self.written += written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.conn.flush()
+        }
+    }
+
+    impl AsyncRead for StripOffer {}
+
+    impl AsyncWrite for StripOffer {
+        fn shutdown(&mut self) -> futures_legacy::Poll<(), io::Error> {
+            self.conn.shutdown()
+        }
+    }
+
+    /// Runs a confirmed negotiation, and returns the algorithms the client and server then
+    /// compress with, or why the negotiation failed.
+    fn negotiate_confirmed(strip: bool) -> (io::Result<u8>, io::Result<u8>) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let negotiations = async move {
+            let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let mut incoming = listener.incoming().compat();
+            let config = signed::Config::new(1, "key");
+            let registry = Registry::new()
+                .with_algorithm(1, RunLength)
+                .compressing_with(1);
+            let compresses_with = |registry: Registry| -> io::Result<u8> {
+                Ok(registry.compress(vec![7; 1000], Hint::Unknown)?.algorithm)
+            };
+
+            let client = {
+                let (config, registry) = (config.clone(), registry.clone());
+                async move {
+                    let conn = await!(TcpStream::connect(&addr).compat())?;
+                    let conn = StripOffer {
+                        conn,
+                        strip,
+                        written: 0,
+                    };
+                    let negotiation = negotiate(conn, registry, Role::Client, Some(config));
+                    compresses_with(await!(negotiation)?.1)
+                }
+            };
+            let server = async move {
+                let conn = await!(incoming.next()).unwrap()?;
+                let negotiation = negotiate(conn, registry, Role::Server, Some(config));
+                compresses_with(await!(negotiation)?.1)
+            };
+            tx.send(await!(future::join(client, server))).unwrap();
+        };
+
+        tokio::run(negotiations.unit_error().boxed().compat());
+        rx.recv().unwrap()
+    }
+
+    #[test]
+    fn confirms_negotiations() {
+        let (client, server) = negotiate_confirmed(false);
+        assert_eq!(client.unwrap(), 1);
+        assert_eq!(server.unwrap(), 1);
+    }
+
+    #[test]
+    fn rejects_stripped_offers() {
+        let (client, server) = negotiate_confirmed(true);
+        assert_eq!(client.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(server.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Checks that `algorithm` round trips, and rejects frames that decompress past the max length.
+    #[cfg(any(feature = "gzip", feature = "snappy", feature = "lz4"))]
+    fn check_builtin(algorithm: impl Algorithm) {
+        let registry = Registry::new()
+            .with_algorithm(1, algorithm)
+            .compressing_with(1);
+        let payload: Vec<u8> = (0..1000).map(|i| (i % 10) as u8).collect();
+
+        let frame = registry.compress(payload.clone(), Hint::Unknown).unwrap();
+        assert!(frame.payload.len() < payload.len());
+        assert_eq!(registry.decompress(frame, 1000).unwrap(), payload);
+
+        let frame = registry.compress(payload, Hint::Unknown).unwrap();
+        let e = registry.decompress(frame, 999).unwrap_err();
+        assert_eq!(
+            e.get_ref().unwrap().downcast_ref::<CompressionError>(),
+            Some(&CompressionError::TooLong { max_len: 999 })
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip() {
+        check_builtin(super::Gzip::default());
+    }
+
+    #[cfg(feature = "snappy")]
+    #[test]
+    fn snappy() {
+        check_builtin(super::Snappy);
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4() {
+        check_builtin(super::Lz4);
+    }
+}
@@ -0,0 +1,478 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A builder of client connections, over any of the transports of this crate.
+//!
+//! A [`Connector`] holds the options that connecting takes: the reactor to register with, socket
+//! options, the max frame length, and timeouts. It makes plain bincode transports with
+//! [`connect`](Connector::connect), and each kind of transport in the other modules with a method
+//! of its own, e.g. [`handshake`](Connector::handshake) or [`signed`](Connector::signed). The free
+//! `connect` functions are shorthand for a `Connector` with the default options.
+//!
+//! Each of those connects to an address. [`connect_to`](Connector::connect_to) connects any of
+//! them to a name instead, resolved with the connector's [`Resolver`], by default a [`Dns`]
+//! resolver that caches addresses for as long as their TTL.
+
+use crate::{
+    codec::{PayloadMetrics, DEFAULT_MAX_FRAME_LEN},
+    connect_error, Codec, Decodes, Transport,
+};
+use futures::{compat::*, prelude::*};
+use net2::TcpBuilder;
+use rpc::{
+    client::discovery::{Dns, Resolver},
+    metrics::Metrics,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_reactor::Handle;
+use tokio_tcp::TcpStream;
+use tokio_timer::Timeout;
+
+/// Connects to servers, with the options it was built with.
+#[derive(Clone, Debug)]
+pub struct Connector {
+    handle: Option<Handle>,
+    connect_timeout: Option<Duration>,
+    handshake_timeout: Duration,
+    deadline: Option<Instant>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    nodelay: Option<bool>,
+    keepalive: Option<Duration>,
+    max_frame_len: usize,
+    payload_metrics: Option<PayloadMetrics>,
+    resolver: SharedResolver,
+}
+
+/// The resolver of a connector, shared by its clones.
+#[derive(Clone)]
+struct SharedResolver(Arc<dyn Resolver>);
+
+impl fmt::Debug for SharedResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Resolver")
+    }
+}
+
+impl Default for Connector {
+    fn default() -> Self {
+        Connector {
+            handle: None,
+            connect_timeout: None,
+            handshake_timeout: Duration::from_secs(10),
+            deadline: None,
+            read_timeout: None,
+            write_timeout: None,
+            nodelay: None,
+            keepalive: None,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            payload_metrics: None,
+            resolver: SharedResolver(Arc::new(Dns::default())),
+        }
+    }
+}
+
+impl Connector {
+    /// Returns a connector with the default options: the default reactor, the OS's socket
+    /// options and connect timeout, a handshake timeout of 10 seconds, the
+    /// [default](crate::codec::DEFAULT_MAX_FRAME_LEN) max frame length, no deadline or read
+    /// and write timeouts, and a [`Dns`] resolver of its own.
+    pub fn new() -> Self {
+        Connector::default()
+    }
+
+    /// Registers connections with the reactor of `handle`, instead of the default reactor, for
+    /// applications that run a reactor of their own.
+    pub fn with_handle(mut self, handle: Handle) -> Self {
+        self.handle = Some(handle);
+        self
+    }
+
+    /// Fails with a [`ConnectTimeout`](crate::ConnectTimeout) if the TCP connection isn't
+    /// established within `connect_timeout`, instead of waiting as long as the OS does, which can
+    /// be minutes.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Fails with a [`TimedOut`](io::ErrorKind::TimedOut) error if the server doesn't complete
+    /// the handshake of a transport within `handshake_timeout` of the TCP connection being
+    /// established, so that a server that accepts connections but never answers doesn't hang the
+    /// client. The handshake is the exchange of the [`handshake`](Connector::handshake),
+    /// [`signed`](Connector::signed), [`compressed`](Connector::compressed), `tls`, and
+    /// `tls_resuming` transports before their first frame; plain transports have none. Defaults to 10 seconds.
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    /// Fails with a [`ConnectTimeout`](crate::ConnectTimeout) if the transport isn't ready by
+    /// `deadline`, including any handshake after the TCP connection is established.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Sets the [read timeout](Transport::with_read_timeout) of the transports made.
+    ///
+    /// Only transports of the crate's root [`Transport`] type are timed, i.e. those of
+    /// [`connect`](Connector::connect), [`handshake`](Connector::handshake), `tls`, and `tls_resuming`.
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = Some(read_timeout);
+        self
+    }
+
+    /// Sets the [write timeout](Transport::with_write_timeout) of the transports made.
+    ///
+    /// Like the read timeout, only applies to transports of the crate's root [`Transport`] type.
+    pub fn with_write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.write_timeout = Some(write_timeout);
+        self
+    }
+
+    /// Sets `TCP_NODELAY` on connections, which sends small writes right away, rather than
+    /// waiting to coalesce them. Left as the OS defaults it, usually off, if not set.
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = Some(nodelay);
+        self
+    }
+
+    /// Enables TCP keepalive on connections, probing the server after `keepalive` without any
+    /// traffic, so that a server that vanished is noticed. Left as the OS defaults it, usually
+    /// off, if not set.
+    pub fn with_keepalive(mut self, keepalive: Duration) -> Self {
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Makes transports that reject frames longer than `max_frame_len` bytes, in either
+    /// direction, with a [`FrameTooLong`](crate::FrameTooLong) error.
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    /// Resolves the names given to [`connect_to`](Connector::connect_to) with `resolver`, e.g.
+    /// a [`Dns`] resolver that looks names up through a DNS client library, or one shared with
+    /// other connectors, so that they share its cache.
+    pub fn with_resolver<R: Resolver>(mut self, resolver: R) -> Self {
+        self.resolver = SharedResolver(Arc::new(resolver));
+        self
+    }
+
+    /// Reports the size of every request and response over the transports made to `metrics`, per
+    /// method, as with [`Codec::with_payload_metrics`].
+    ///
+    /// Like the read timeout, only applies to transports of the crate's root [`Transport`] type.
+    pub fn with_payload_metrics(
+        mut self,
+        metrics: Metrics,
+        methods: &'static [&'static str],
+    ) -> Self {
+        self.payload_metrics = Some(PayloadMetrics::new(metrics, methods));
+        self
+    }
+
+    /// Returns the max frame length of the transports made.
+    pub(crate) fn max_frame_len(&self) -> usize {
+        self.max_frame_len
+    }
+
+    /// Connects to `addr`, wrapping the connection in a bincode transport that decodes
+    /// [responses](Decodes::Responses).
+    pub fn connect<Item, SinkItem>(
+        &self,
+        addr: &SocketAddr,
+    ) -> impl Future<Output = io::Result<Transport<TcpStream, Item, SinkItem>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let connector = self.clone();
+        let addr = *addr;
+        self.within(addr, async move {
+            let conn = await!(connector.tcp(addr))?;
+            Ok(connector.transport(conn))
+        })
+    }
+
+    /// Resolves `name` with this connector's resolver, and connects to each address it resolves
+    /// to in turn with `connect`, until a connection succeeds. Fails with the error of the last
+    /// attempt if none succeed.
+    ///
+    /// `connect` is called with this connector and the address, and can make any of its
+    /// transports, e.g. `|connector, addr| connector.handshake(&addr, credentials.clone())`.
+    pub fn connect_to<F, Fut, T>(
+        &self,
+        name: &str,
+        connect: F,
+    ) -> impl Future<Output = io::Result<T>>
+    where
+        F: Fn(&Connector, SocketAddr) -> Fut,
+        Fut: Future<Output = io::Result<T>>,
+    {
+        let connector = self.clone();
+        let addrs = self.resolver.0.resolve_once(name);
+        let name = name.to_string();
+        async move {
+            let mut last_error = None;
+            for addr in await!(addrs)? {
+                match await!(connect(&connector, addr)) {
+                    Ok(transport) => return Ok(transport),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            Err(last_error.unwrap_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} resolved to no addresses.", name),
+                )
+            }))
+        }
+    }
+
+    /// Establishes a TCP connection to `addr`, with the socket options and connect timeout of
+    /// this connector.
+    pub(crate) fn tcp(&self, addr: SocketAddr) -> impl Future<Output = io::Result<TcpStream>> {
+        let connector = self.clone();
+        async move {
+            let conn = match &connector.handle {
+                Some(handle) => {
+                    let builder = if addr.is_ipv4() {
+                        TcpBuilder::new_v4()?
+                    } else {
+                        TcpBuilder::new_v6()?
+                    };
+                    TcpStream::connect_std(builder.to_tcp_stream()?, &addr, handle)
+                }
+                None => TcpStream::connect(&addr),
+            };
+            let conn = match connector.connect_timeout {
+                Some(timeout) => await!(Timeout::new(conn, timeout).compat())
+                    .map_err(|e| connect_error(e, addr, timeout))?,
+                None => await!(conn.compat())?,
+            };
+            if let Some(nodelay) = connector.nodelay {
+                conn.set_nodelay(nodelay)?;
+            }
+            if let Some(keepalive) = connector.keepalive {
+                conn.set_keepalive(Some(keepalive))?;
+            }
+            Ok(conn)
+        }
+    }
+
+    /// Fails `handshake` with a [`TimedOut`](io::ErrorKind::TimedOut) error if it doesn't finish
+    /// within the handshake timeout of this connector.
+    pub(crate) fn handshaking<T>(
+        &self,
+        handshake: impl Future<Output = io::Result<T>>,
+    ) -> impl Future<Output = io::Result<T>> {
+        let handshake_timeout = self.handshake_timeout;
+        Timeout::new(Box::pin(handshake).compat(), handshake_timeout)
+            .compat()
+            .map_err(move |e| {
+                if e.is_elapsed() {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!(
+                            "Server didn't complete the handshake within {:?}.",
+                            handshake_timeout
+                        ),
+                    )
+                } else if e.is_inner() {
+                    e.into_inner().expect("Checked by is_inner.")
+                } else {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Could not set the handshake timeout: {}", e),
+                    )
+                }
+            })
+    }
+
+    /// Fails `connecting` to `addr` with a [`ConnectTimeout`](crate::ConnectTimeout) if it
+    /// hasn't finished by the deadline of this connector.
+    pub(crate) fn within<T>(
+        &self,
+        addr: SocketAddr,
+        connecting: impl Future<Output = io::Result<T>>,
+    ) -> impl Future<Output = io::Result<T>> {
+        let deadline = self.deadline;
+        async move {
+            let deadline = match deadline {
+                Some(deadline) => deadline,
+                None => return await!(connecting),
+            };
+            let now = Instant::now();
+            let timeout = if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            };
+            await!(Timeout::new_at(Box::pin(connecting).compat(), deadline).compat())
+                .map_err(|e| connect_error(e, addr, timeout))
+        }
+    }
+
+    /// Wraps `io` in a transport that decodes responses, with the max frame length, timeouts, and
+    /// payload metrics of this connector.
+    pub(crate) fn transport<S, Item, SinkItem>(&self, io: S) -> Transport<S, Item, SinkItem>
+    where
+        S: AsyncRead + AsyncWrite,
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let codec = Codec::new(self.max_frame_len)
+            .decoding(Decodes::Responses)
+            .with_method_metrics(self.payload_metrics.clone());
+        let mut transport = Transport::with_codec(io, codec);
+        transport.read_timeout = self.read_timeout;
+        transport.write_timeout = self.write_timeout;
+        transport
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Connector;
+    use crate::handshake::Credentials;
+    use futures::{compat::*, prelude::*, stream::BoxStream};
+    use rpc::client::discovery::Resolver;
+    use std::{
+        io,
+        net::{SocketAddr, TcpListener},
+        time::{Duration, Instant},
+    };
+    use tokio_tcp::TcpStream;
+
+    #[test]
+    fn past_deadlines_time_out() {
+        let addr = "127.0.0.1:80".parse().unwrap();
+        let connect = async move {
+            let connector = Connector::new().with_deadline(Instant::now());
+            let e =
+                await!(connector.within(addr, future::pending::<io::Result<()>>())).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+            assert!(e
+                .get_ref()
+                .unwrap()
+                .downcast_ref::<crate::ConnectTimeout>()
+                .is_some());
+        };
+
+        tokio::run(connect.unit_error().boxed().compat());
+    }
+
+    #[test]
+    fn deadlines_cover_handshakes() {
+        struct Empty;
+
+        impl Credentials for Empty {
+            fn respond(&self, _: &[u8]) -> Vec<u8> {
+                vec![]
+            }
+        }
+
+        // Connections are established in the listener's backlog, but never sent a challenge.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = async move {
+            let connector =
+                Connector::new().with_deadline(Instant::now() + Duration::from_millis(50));
+            let e = await!(connector.handshake::<String, String, _>(&addr, Empty)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        };
+
+        tokio::run(connect.unit_error().boxed().compat());
+        drop(listener);
+    }
+
+    #[test]
+    fn handshakes_time_out() {
+        struct Empty;
+
+        impl Credentials for Empty {
+            fn respond(&self, _: &[u8]) -> Vec<u8> {
+                vec![]
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = async move {
+            let connector = Connector::new()
+                .with_connect_timeout(Duration::from_secs(10))
+                .with_handshake_timeout(Duration::from_millis(50))
+                .with_max_frame_len(1024);
+            let e = await!(connector.handshake::<String, String, _>(&addr, Empty)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+            // Told apart from connections that weren't established in time.
+            assert!(e
+                .get_ref()
+                .unwrap()
+                .downcast_ref::<crate::ConnectTimeout>()
+                .is_none());
+        };
+
+        tokio::run(connect.unit_error().boxed().compat());
+        drop(listener);
+    }
+
+    #[test]
+    fn socket_options_are_set() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = async move {
+            let connector = Connector::new()
+                .with_nodelay(true)
+                .with_keepalive(Duration::from_secs(30))
+                .with_deadline(Instant::now() + Duration::from_secs(10));
+            let conn: TcpStream = await!(connector.within(addr, connector.tcp(addr))).unwrap();
+            assert!(conn.nodelay().unwrap());
+            assert_eq!(conn.keepalive().unwrap(), Some(Duration::from_secs(30)));
+        };
+
+        tokio::run(connect.unit_error().boxed().compat());
+    }
+
+    #[test]
+    fn names_connect_to_the_first_address_that_accepts() {
+        /// Resolves every name to the same addresses.
+        struct Fixed(Vec<SocketAddr>);
+
+        impl Resolver for Fixed {
+            fn resolve(&self, _name: &str) -> BoxStream<'static, io::Result<Vec<SocketAddr>>> {
+                stream::once(future::ready(Ok(self.0.clone()))).boxed()
+            }
+        }
+
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = async move {
+            let connector = Connector::new().with_resolver(Fixed(vec![closed, addr]));
+            let conn: TcpStream =
+                await!(connector.connect_to("service", |connector, addr| connector.tcp(addr)))
+                    .unwrap();
+            assert_eq!(conn.peer_addr().unwrap(), addr);
+        };
+
+        tokio::run(connect.unit_error().boxed().compat());
+        drop(listener);
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! The framing of the [`Codec`](crate::Codec), and its size limits, without the codec's tokio and
+//! rpc runtime dependencies.
+//!
+//! Each frame is a 4-byte big-endian length followed by that many bytes in a serde [`Format`].
+//! This module is built even without the default `runtime` feature, so tools that only read or
+//! write frames, e.g. to persist or replay request logs, share the transport's exact encoding.
+
+use bytes::{BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fmt, io};
+
+/// The size of the length prefix at the start of every frame.
+pub const LEN_PREFIX: usize = 4;
+
+/// The max frame length used by [`Codec::default`](crate::Codec): 16 MiB.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// A serde data format that frames are serialized in.
+pub trait Format {
+    /// Serializes `item` into `writer`.
+    fn serialize_into<W: io::Write, T: Serialize>(&self, writer: W, item: &T) -> io::Result<()>;
+
+    /// Deserializes a `T` from `frame`, which holds exactly one serialized item.
+    fn deserialize<T: for<'de> Deserialize<'de>>(&self, frame: &[u8]) -> io::Result<T>;
+
+    /// Returns the number of bytes `item` serializes to. By default, serializes `item` and
+    /// counts the bytes.
+    fn serialized_size<T: Serialize>(&self, item: &T) -> io::Result<u64> {
+        struct Count(u64);
+
+        impl io::Write for Count {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0 += buf.len() as u64;
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut count = Count(0);
+        self.serialize_into(&mut count, item)?;
+        Ok(count.0)
+    }
+}
+
+/// The [bincode](https://docs.rs/bincode) format.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+impl Format for Bincode {
+    fn serialize_into<W: io::Write, T: Serialize>(&self, writer: W, item: &T) -> io::Result<()> {
+        bincode::serialize_into(writer, item)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn deserialize<T: for<'de> Deserialize<'de>>(&self, frame: &[u8]) -> io::Result<T> {
+        bincode::config()
+            .limit(frame.len() as u64)
+            .deserialize(frame)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn serialized_size<T: Serialize>(&self, item: &T) -> io::Result<u64> {
+        bincode::serialized_size(item).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
+
+/// A frame longer than the codec's max frame length, whether read or about to be written.
+///
+/// Returned as the inner error of an [`InvalidData`](io::ErrorKind::InvalidData) error, so that
+/// it can be told apart from frames that couldn't be decoded, by downcasting the error with
+/// `io::Error::get_ref`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameTooLong {
+    /// The length of the frame, as claimed by its length prefix when reading.
+    pub len: u64,
+    /// The max frame length of the codec.
+    pub max_frame_len: usize,
+}
+
+impl fmt::Display for FrameTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Frame of {} bytes exceeds the max frame length of {} bytes.",
+            self.len, self.max_frame_len
+        )
+    }
+}
+
+impl Error for FrameTooLong {}
+
+impl From<FrameTooLong> for io::Error {
+    fn from(e: FrameTooLong) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Appends `item` to `dst` as a frame serialized in `format`, unless it's longer than
+/// `max_frame_len`. Returns the length of the frame, length prefix included.
+pub fn encode<F: Format, T: Serialize>(
+    format: &F,
+    max_frame_len: usize,
+    item: &T,
+    dst: &mut BytesMut,
+) -> io::Result<u64> {
+    let len = format.serialized_size(item)?;
+    if len > max_frame_len.min(u32::max_value() as usize) as u64 {
+        return Err(FrameTooLong { len, max_frame_len }.into());
+    }
+
+    dst.reserve(LEN_PREFIX + len as usize);
+    dst.put_u32_be(len as u32);
+    format.serialize_into(dst.writer(), item)?;
+    Ok(LEN_PREFIX as u64 + len)
+}
+
+/// Splits the next frame off the front of `src`, without its length prefix, once all of it has
+/// been read. A length prefix over `max_frame_len` is rejected before any of the frame is
+/// buffered.
+pub fn decode(src: &mut BytesMut, max_frame_len: usize) -> io::Result<Option<BytesMut>> {
+    if src.len() < LEN_PREFIX {
+        return Ok(None);
+    }
+
+    let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+    if len > max_frame_len {
+        return Err(FrameTooLong {
+            len: len as u64,
+            max_frame_len,
+        }
+        .into());
+    }
+
+    let frame_len = LEN_PREFIX + len;
+    if src.len() < frame_len {
+        src.reserve(frame_len - src.len());
+        return Ok(None);
+    }
+
+    let mut frame = src.split_to(frame_len);
+    frame.advance(LEN_PREFIX);
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, Bincode, Format, FrameTooLong};
+    use bytes::BytesMut;
+
+    #[test]
+    fn frames_round_trip_without_a_codec() {
+        let mut buf = BytesMut::new();
+        let len = encode(&Bincode, 64, &"hello".to_string(), &mut buf).unwrap();
+        assert_eq!(len, buf.len() as u64);
+
+        let frame = decode(&mut buf, 64).unwrap().unwrap();
+        let item: String = Bincode.deserialize(&frame).unwrap();
+        assert_eq!(item, "hello");
+        assert_eq!(decode(&mut buf, 64).unwrap(), None);
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_rejected() {
+        let mut buf = BytesMut::new();
+        let e = encode(&Bincode, 4, &"hello".to_string(), &mut buf).unwrap_err();
+        assert!(e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<FrameTooLong>()
+            .is_some());
+        assert!(buf.is_empty());
+
+        encode(&Bincode, 64, &"hello".to_string(), &mut buf).unwrap();
+        let e = decode(&mut buf, 4).unwrap_err();
+        assert_eq!(
+            *e.get_ref().unwrap().downcast_ref::<FrameTooLong>().unwrap(),
+            FrameTooLong {
+                len: 13,
+                max_frame_len: 4
+            }
+        );
+    }
+}
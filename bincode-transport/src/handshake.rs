@@ -0,0 +1,482 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Bincode transports that authenticate the client when the connection is established, before any
+//! requests are sent.
+//!
+//! Right after accepting a connection, the server sends a challenge from its [`Authenticator`].
+//! The client answers with credentials from its [`Credentials`], e.g. a token, or an HMAC of the
+//! challenge, and the server either accepts the connection, making the identity it authenticated
+//! as [current](rpc::server::identity::current) in the handlers of the client's requests, or
+//! rejects it with the reason, and closes it.
+//!
+//! A server can also issue each client it accepts a session, from its [`Sessions`]. The server
+//! sends the session's token with its verdict, and a client that reconnects presents the token
+//! along with its credentials, so that its new connection rejoins the session, e.g. when
+//! [reconnecting](rpc::client::reconnect::resuming). Both ends must then use sessions, as clients
+//! [connect](connect_resuming) differently.
+//!
+//! Handshake messages are length-prefixed byte strings of at most [`MAX_HANDSHAKE_FRAME_LEN`]
+//! bytes.
+
+use crate::{Codec, Connector, Decodes, FrameTooLong, Transport};
+use futures::{compat::*, prelude::*, ready, stream::FuturesUnordered};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use rpc::server::IpFilter;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    marker::PhantomData,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio_io::{
+    io::{read_exact, write_all},
+    AsyncRead, AsyncWrite,
+};
+use tokio_tcp::{TcpListener, TcpStream};
+use tokio_timer::Timeout;
+
+/// The longest challenge, credentials, or rejection reason that can be sent in a handshake.
+pub const MAX_HANDSHAKE_FRAME_LEN: usize = 64 * 1024;
+
+/// Sent by the server to accept the client's credentials.
+const ACCEPTED: u8 = 0;
+/// Sent by the server, followed by the reason, to reject the client's credentials.
+const REJECTED: u8 = 1;
+
+/// Decides which clients a server accepts connections from.
+pub trait Authenticator: Send + Sync + 'static {
+    /// Returns the challenge sent to a newly connected client, e.g. a random nonce for the client
+    /// to sign. Can be empty, e.g. when clients present a token.
+    fn challenge(&self) -> Vec<u8>;
+
+    /// Returns the identity the client authenticated as, if `credentials` answer `challenge`.
+    /// Otherwise, returns the reason the client is rejected, which is sent to the client.
+    fn verify(&self, challenge: &[u8], credentials: &[u8]) -> io::Result<String>;
+}
+
+/// Answers a server's challenge.
+pub trait Credentials {
+    /// Returns the credentials that answer `challenge`.
+    fn respond(&self, challenge: &[u8]) -> Vec<u8>;
+}
+
+/// The sessions a server issued to the clients it accepted, so that a client that reconnects,
+/// e.g. after a network blip, rejoins its session rather than starting a new one. Clones share
+/// their sessions.
+#[derive(Clone)]
+pub struct Sessions {
+    ttl: Duration,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+}
+
+struct Session {
+    /// The identity the session was issued to, the only one that can rejoin it.
+    identity: String,
+    /// When a connection last joined the session.
+    joined_at: Instant,
+}
+
+impl fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sessions")
+            .field("ttl", &self.ttl)
+            .field("sessions", &self.sessions.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Sessions {
+    /// Returns an empty set of sessions, each of which expires once no connection has joined it
+    /// for `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Sessions {
+            ttl,
+            sessions: Arc::default(),
+        }
+    }
+
+    /// Ends the session of `token`, so that a client presenting it starts a new session. Returns
+    /// false if there was no such session.
+    pub fn end(&self, token: &str) -> bool {
+        self.sessions.lock().unwrap().remove(token).is_some()
+    }
+
+    /// Returns the token of the session a client authenticated as `identity` joins by presenting
+    /// `token`: the session of `token`, if it was issued to `identity` and hasn't expired, or else
+    /// a new session.
+    fn join(&self, identity: &str, token: &str) -> String {
+        let now = Instant::now();
+        let ttl = self.ttl;
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| now.duration_since(session.joined_at) < ttl);
+        if let Some(session) = sessions.get_mut(token) {
+            if session.identity == identity {
+                session.joined_at = now;
+                return token.to_string();
+            }
+        }
+        let token = format!("{:032x}", rand::random::<u128>());
+        let session = Session {
+            identity: identity.to_string(),
+            joined_at: now,
+        };
+        sessions.insert(token.clone(), session);
+        token
+    }
+}
+
+/// Connects to `addr`, and authenticates with `credentials` before returning a bincode transport
+/// that decodes [responses](Decodes::Responses).
+///
+/// Fails with [`PermissionDenied`](io::ErrorKind::PermissionDenied) if the server rejects the
+/// credentials.
+pub async fn connect<Item, SinkItem, C>(
+    addr: &SocketAddr,
+    credentials: C,
+) -> io::Result<Transport<TcpStream, Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Credentials,
+{
+    await!(Connector::new().handshake(addr, credentials))
+}
+
+/// Like [`connect`], but also presents `token`, if any, to a server that issues [`Sessions`], to
+/// rejoin the session of `token`. The transport's
+/// [session token](rpc::Transport::session_token) is that of the session the server joined it
+/// to, which is a new one unless `token` was still valid.
+pub async fn connect_resuming<Item, SinkItem, C>(
+    addr: &SocketAddr,
+    credentials: C,
+    token: Option<String>,
+) -> io::Result<Transport<TcpStream, Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    C: Credentials,
+{
+    await!(Connector::new().handshake_resuming(addr, credentials, token))
+}
+
+impl Connector {
+    /// Like [`connect`], but connects with the options of this connector.
+    pub fn handshake<Item, SinkItem, C>(
+        &self,
+        addr: &SocketAddr,
+        credentials: C,
+    ) -> impl Future<Output = io::Result<Transport<TcpStream, Item, SinkItem>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+        C: Credentials,
+    {
+        self.authenticating(addr, credentials, None)
+    }
+
+    /// Like [`connect_resuming`], but connects with the options of this connector.
+    pub fn handshake_resuming<Item, SinkItem, C>(
+        &self,
+        addr: &SocketAddr,
+        credentials: C,
+        token: Option<String>,
+    ) -> impl Future<Output = io::Result<Transport<TcpStream, Item, SinkItem>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+        C: Credentials,
+    {
+        self.authenticating(addr, credentials, Some(token.unwrap_or_default()))
+    }
+
+    fn authenticating<Item, SinkItem, C>(
+        &self,
+        addr: &SocketAddr,
+        credentials: C,
+        token: Option<String>,
+    ) -> impl Future<Output = io::Result<Transport<TcpStream, Item, SinkItem>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+        C: Credentials,
+    {
+        let connector = self.clone();
+        let addr = *addr;
+        self.within(addr, async move {
+            let conn = await!(connector.tcp(addr))?;
+            let authentication = authenticate(conn, credentials, token);
+            let (conn, session) = await!(connector.handshaking(authentication))?;
+            let mut transport = connector.transport(conn);
+            transport.session = session;
+            Ok(transport)
+        })
+    }
+}
+
+/// Answers the server's challenge on `conn` with `credentials`, and reads the server's verdict,
+/// returning the token of the session the server issued, if any.
+///
+/// Given a `token`, presents it after the credentials, to a server that issues sessions; an empty
+/// token asks for a new session.
+async fn authenticate<C: Credentials>(
+    conn: TcpStream,
+    credentials: C,
+    token: Option<String>,
+) -> io::Result<(TcpStream, Option<String>)> {
+    let (conn, challenge) = await!(read_frame(conn))?;
+    let mut conn = await!(write_frame(conn, credentials.respond(&challenge)))?;
+    if let Some(token) = token {
+        conn = await!(write_frame(conn, token.into_bytes()))?;
+    }
+    let (conn, verdict) = await!(read_frame(conn))?;
+    match verdict.split_first() {
+        Some((&ACCEPTED, session)) if session.is_empty() => Ok((conn, None)),
+        Some((&ACCEPTED, session)) => match String::from_utf8(session.to_vec()) {
+            Ok(session) => Ok((conn, Some(session))),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Server sent an invalid session token.",
+            )),
+        },
+        Some((&REJECTED, reason)) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Server rejected the credentials: {}",
+                String::from_utf8_lossy(reason)
+            ),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Server sent an invalid handshake verdict.",
+        )),
+    }
+}
+
+/// Listens on `addr`, wrapping connections whose clients `authenticator` accepts in bincode
+/// transports that decode [requests](Decodes::Requests).
+pub fn listen<Item, SinkItem>(
+    addr: &SocketAddr,
+    authenticator: impl Authenticator,
+) -> io::Result<Incoming<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let incoming = listener.incoming().compat();
+    Ok(Incoming {
+        incoming,
+        local_addr,
+        ip_filter: IpFilter::default(),
+        authenticator: Arc::new(authenticator),
+        sessions: None,
+        handshakes: FuturesUnordered::new(),
+        max_handshakes: 64,
+        handshake_timeout: Duration::from_secs(10),
+        ghost: PhantomData,
+    })
+}
+
+type Handshake =
+    Pin<Box<dyn Future<Output = io::Result<(TcpStream, String, Option<String>)>> + Send>>;
+
+/// A [`TcpListener`] that authenticates clients before wrapping their connections in bincode
+/// transports.
+///
+/// Handshakes run concurrently, up to a [limit](Incoming::with_max_handshakes), beyond which no
+/// more connections are accepted until a handshake completes. A failed handshake, whether the
+/// client was rejected or didn't answer in time, yields an error for that connection only.
+pub struct Incoming<Item, SinkItem> {
+    incoming: Compat01As03<tokio_tcp::Incoming>,
+    local_addr: SocketAddr,
+    ip_filter: IpFilter,
+    authenticator: Arc<dyn Authenticator>,
+    sessions: Option<Sessions>,
+    handshakes: FuturesUnordered<Handshake>,
+    max_handshakes: usize,
+    handshake_timeout: Duration,
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+impl<Item, SinkItem> fmt::Debug for Incoming<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Incoming")
+            .field("local_addr", &self.local_addr)
+            .field("ip_filter", &self.ip_filter)
+            .field("sessions", &self.sessions)
+            .field("handshakes", &self.handshakes.len())
+            .field("max_handshakes", &self.max_handshakes)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .finish()
+    }
+}
+
+impl<Item, SinkItem> Incoming<Item, SinkItem> {
+    unsafe_pinned!(incoming: Compat01As03<tokio_tcp::Incoming>);
+    unsafe_unpinned!(handshakes: FuturesUnordered<Handshake>);
+
+    /// Returns the address being listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Closes connections from IPs that `ip_filter` doesn't allow as soon as they're accepted,
+    /// before the handshake. Allows every IP by default.
+    pub fn with_ip_filter(mut self, ip_filter: IpFilter) -> Self {
+        self.ip_filter = ip_filter;
+        self
+    }
+
+    /// Sets the maximum number of handshakes in progress at once. Defaults to 64.
+    pub fn with_max_handshakes(mut self, max_handshakes: usize) -> Self {
+        self.max_handshakes = max_handshakes;
+        self
+    }
+
+    /// Sets how long a client has to complete the handshake before its connection is closed.
+    /// Defaults to 10 seconds.
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    /// Issues each client it accepts a session from `sessions`, or lets a client that presents the
+    /// token of its session rejoin it. Clients must then connect with [`connect_resuming`].
+    pub fn with_sessions(mut self, sessions: Sessions) -> Self {
+        self.sessions = Some(sessions);
+        self
+    }
+}
+
+impl<Item, SinkItem> Stream for Incoming<Item, SinkItem>
+where
+    Item: for<'a> Deserialize<'a>,
+    SinkItem: Serialize,
+{
+    type Item = io::Result<Transport<TcpStream, Item, SinkItem>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while self.handshakes.len() < self.max_handshakes {
+            match self.as_mut().incoming().poll_next(cx) {
+                Poll::Ready(Some(Ok(conn))) => {
+                    if !crate::is_peer_allowed(&self.ip_filter, &conn) {
+                        continue;
+                    }
+                    let handshake = Timeout::new(
+                        accept(conn, self.authenticator.clone(), self.sessions.clone())
+                            .boxed()
+                            .compat(),
+                        self.handshake_timeout,
+                    )
+                    .compat()
+                    .map_err(|e| {
+                        if e.is_elapsed() {
+                            io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "Client didn't complete the handshake in time.",
+                            )
+                        } else if e.is_inner() {
+                            e.into_inner().expect("Checked by is_inner.")
+                        } else {
+                            io::Error::new(
+                                io::ErrorKind::Other,
+                                format!("Could not set the handshake timeout: {}", e),
+                            )
+                        }
+                    });
+                    self.as_mut().handshakes().push(handshake.boxed());
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) if self.handshakes.is_empty() => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+
+        match ready!(self.as_mut().handshakes().poll_next_unpin(cx)) {
+            Some(handshake) => {
+                let (conn, identity, session) = handshake?;
+                let codec = Codec::default().decoding(Decodes::Requests);
+                let mut transport = Transport::with_codec(conn, codec);
+                transport.identity = Some(identity);
+                transport.session = session;
+                Poll::Ready(Some(Ok(transport)))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Challenges the client of `conn`, and returns the identity it authenticated as, along with the
+/// token of the session it joined, if the server issues `sessions`.
+async fn accept(
+    conn: TcpStream,
+    authenticator: Arc<dyn Authenticator>,
+    sessions: Option<Sessions>,
+) -> io::Result<(TcpStream, String, Option<String>)> {
+    let challenge = authenticator.challenge();
+    let conn = await!(write_frame(conn, challenge.clone()))?;
+    let (mut conn, credentials) = await!(read_frame(conn))?;
+    let mut token = vec![];
+    if sessions.is_some() {
+        let (rest, presented) = await!(read_frame(conn))?;
+        conn = rest;
+        token = presented;
+    }
+    match authenticator.verify(&challenge, &credentials) {
+        Ok(identity) => {
+            let session =
+                sessions.map(|sessions| sessions.join(&identity, &String::from_utf8_lossy(&token)));
+            let mut verdict = vec![ACCEPTED];
+            verdict.extend(session.iter().flat_map(|session| session.bytes()));
+            let conn = await!(write_frame(conn, verdict))?;
+            Ok((conn, identity, session))
+        }
+        Err(e) => {
+            let mut verdict = vec![REJECTED];
+            verdict.extend_from_slice(e.to_string().as_bytes());
+            verdict.truncate(MAX_HANDSHAKE_FRAME_LEN);
+            // The client is told why it was rejected, if it's still listening.
+            let _ = await!(write_frame(conn, verdict));
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Rejected the client's credentials: {}", e),
+            ))
+        }
+    }
+}
+
+async fn read_frame<S: AsyncRead>(conn: S) -> io::Result<(S, Vec<u8>)> {
+    let (conn, len) = await!(read_exact(conn, [0; 4]).compat())?;
+    let len = u32::from_be_bytes(len);
+    if len as usize > MAX_HANDSHAKE_FRAME_LEN {
+        return Err(FrameTooLong {
+            len: u64::from(len),
+            max_frame_len: MAX_HANDSHAKE_FRAME_LEN,
+        }
+        .into());
+    }
+    await!(read_exact(conn, vec![0; len as usize]).compat())
+}
+
+async fn write_frame<S: AsyncWrite>(conn: S, frame: Vec<u8>) -> io::Result<S> {
+    if frame.len() > MAX_HANDSHAKE_FRAME_LEN {
+        return Err(FrameTooLong {
+            len: frame.len() as u64,
+            max_frame_len: MAX_HANDSHAKE_FRAME_LEN,
+        }
+        .into());
+    }
+    let (conn, _) = await!(write_all(conn, (frame.len() as u32).to_be_bytes()).compat())?;
+    let (conn, _) = await!(write_all(conn, frame).compat())?;
+    Ok(conn)
+}
@@ -4,95 +4,241 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
-//! A TCP [`Transport`] that serializes as bincode.
+//! A TCP [`Transport`] that serializes as bincode, or in any other serde [`Format`].
+//!
+//! The transport is behind the default `runtime` feature. Without it, only the [`frame`]s it
+//! writes and their [`Format`]s are built, over the wire types of `rpc` without its runtime, for
+//! tools that only read or write messages, e.g. to persist or replay request logs.
 
 #![feature(arbitrary_self_types, await_macro, async_await)]
 #![deny(missing_docs, missing_debug_implementations)]
 
-use async_bincode::{AsyncBincodeStream, AsyncDestination};
+#[cfg(feature = "runtime")]
+use crate::codec::{MethodLimits, PayloadMetrics};
+#[cfg(feature = "runtime")]
 use futures::{compat::*, prelude::*, ready};
-use pin_utils::unsafe_pinned;
+#[cfg(feature = "runtime")]
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+#[cfg(feature = "runtime")]
+use rpc::{
+    client::{
+        discovery::Resolver,
+        reconnect::{self, Balancer, Balancing},
+        registry::Registry,
+    },
+    context,
+    metrics::Metrics,
+    server::{limits::PayloadLimits, Handler, IpFilter, Server, Serving, ShutdownHandle},
+    ClientMessage, ServerMessage,
+};
+#[cfg(feature = "runtime")]
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "runtime")]
 use std::{
     error::Error,
-    io,
+    fmt, io,
     marker::PhantomData,
     net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
+#[cfg(feature = "runtime")]
+use tokio_codec::Framed;
+#[cfg(feature = "runtime")]
 use tokio_io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "runtime")]
+use tokio_reactor::Handle;
+#[cfg(feature = "runtime")]
 use tokio_tcp::{TcpListener, TcpStream};
+#[cfg(feature = "runtime")]
+use tokio_timer::{timeout, Delay};
+
+#[cfg(feature = "runtime")]
+pub mod codec;
+#[cfg(feature = "runtime")]
+pub mod compressed;
+#[cfg(feature = "runtime")]
+pub mod connector;
+pub mod frame;
+#[cfg(feature = "runtime")]
+pub mod handshake;
+#[cfg(feature = "runtime")]
+pub mod signed;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(all(unix, feature = "unix"))]
+pub mod unix;
+
+#[cfg(feature = "runtime")]
+pub use self::codec::{Codec, Decodes};
+#[cfg(feature = "runtime")]
+pub use self::connector::Connector;
+pub use self::frame::{Bincode, Format, FrameTooLong};
 
-/// A transport that serializes to, and deserializes from, a [`TcpStream`].
+/// A transport that serializes to, and deserializes from, a [`TcpStream`], in bincode unless
+/// another [`Format`] is given.
+#[cfg(feature = "runtime")]
 #[derive(Debug)]
-pub struct Transport<S, Item, SinkItem> {
-    inner: Compat01As03Sink<AsyncBincodeStream<S, Item, SinkItem, AsyncDestination>, SinkItem>,
+pub struct Transport<S, Item, SinkItem, F = Bincode> {
+    inner: Compat01As03Sink<Framed<S, Codec<Item, SinkItem, F>>, SinkItem>,
+    /// The identity the peer authenticated as, if it was authenticated by a [`handshake`].
+    identity: Option<String>,
+    /// The token of the session the connection belongs to, if a [`handshake`] issued one.
+    session: Option<String>,
+    read_timeout: Option<Duration>,
+    /// Set while a read is waiting on the peer; fires once the read has waited too long.
+    read_deadline: Option<Compat01As03<Delay>>,
+    write_timeout: Option<Duration>,
+    /// Set while a write is waiting on the peer; fires once the write has waited too long.
+    write_deadline: Option<Compat01As03<Delay>>,
 }
 
-impl<S, Item, SinkItem> Transport<S, Item, SinkItem> {
-    unsafe_pinned!(
-        inner: Compat01As03Sink<AsyncBincodeStream<S, Item, SinkItem, AsyncDestination>, SinkItem>
-    );
+#[cfg(feature = "runtime")]
+impl<S, Item, SinkItem, F> Transport<S, Item, SinkItem, F> {
+    unsafe_pinned!(inner: Compat01As03Sink<Framed<S, Codec<Item, SinkItem, F>>, SinkItem>);
+    unsafe_unpinned!(read_deadline: Option<Compat01As03<Delay>>);
+    unsafe_unpinned!(write_deadline: Option<Compat01As03<Delay>>);
+
+    /// Sets how long a read may wait for the peer to send the next message, after which the
+    /// transport fails with a [`TimedOut`](io::ErrorKind::TimedOut) error, so that the connection
+    /// is closed. Only suits peers that send messages regularly, e.g. servers that respond to a
+    /// steady stream of requests, as an idle peer times out as well. Defaults to no timeout.
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = Some(read_timeout);
+        self
+    }
+
+    /// Sets how long a write may wait on a peer that stops reading, after which the transport
+    /// fails with a [`TimedOut`](io::ErrorKind::TimedOut) error, so that the connection is closed.
+    /// Defaults to no timeout.
+    pub fn with_write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.write_timeout = Some(write_timeout);
+        self
+    }
+
+    /// Fails the read if it has been waiting longer than the read timeout, once the read returns
+    /// `poll`.
+    fn time_read<T>(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        poll: Poll<Option<io::Result<T>>>,
+    ) -> Poll<Option<io::Result<T>>> {
+        let read_timeout = match self.read_timeout {
+            Some(read_timeout) if poll.is_pending() => read_timeout,
+            _ => {
+                *self.as_mut().read_deadline() = None;
+                return poll;
+            }
+        };
+        poll_timeout(
+            self.as_mut().read_deadline(),
+            cx,
+            read_timeout,
+            "Reading from",
+        )
+        .map(|e| Some(Err(e)))
+    }
+
+    /// Fails the write if it has been waiting longer than the write timeout, once the write
+    /// returns `poll`.
+    fn time_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<()>>,
+    ) -> Poll<io::Result<()>> {
+        let write_timeout = match self.write_timeout {
+            Some(write_timeout) if poll.is_pending() => write_timeout,
+            _ => {
+                *self.as_mut().write_deadline() = None;
+                return poll;
+            }
+        };
+        poll_timeout(
+            self.as_mut().write_deadline(),
+            cx,
+            write_timeout,
+            "Writing to",
+        )
+        .map(Err)
+    }
+}
+
+/// Starts `deadline`, if it isn't already, then resolves to a [`TimedOut`](io::ErrorKind::TimedOut)
+/// error once `timeout` has passed. `doing` says what timed out, e.g. "Writing to".
+#[cfg(feature = "runtime")]
+fn poll_timeout(
+    deadline: &mut Option<Compat01As03<Delay>>,
+    cx: &mut Context<'_>,
+    timeout: Duration,
+    doing: &str,
+) -> Poll<io::Error> {
+    let result = ready!(deadline
+        .get_or_insert_with(|| Delay::new(Instant::now() + timeout).compat())
+        .poll_unpin(cx));
+    *deadline = None;
+    Poll::Ready(match result {
+        Ok(()) => io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} the peer timed out after {:?}.", doing, timeout),
+        ),
+        Err(e) => io::Error::new(
+            io::ErrorKind::Other,
+            format!("Could not set the timeout: {}", e),
+        ),
+    })
 }
 
-impl<S, Item, SinkItem> Stream for Transport<S, Item, SinkItem>
+#[cfg(feature = "runtime")]
+impl<S, Item, SinkItem, F> Stream for Transport<S, Item, SinkItem, F>
 where
     S: AsyncRead,
     Item: for<'a> Deserialize<'a>,
+    F: Format,
 {
     type Item = io::Result<Item>;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
-        match self.inner().poll_next(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Ready(Some(Ok(next))) => Poll::Ready(Some(Ok(next))),
-            Poll::Ready(Some(Err(e))) => {
-                Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::Other, e))))
-            }
-        }
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
+        let poll = self.as_mut().inner().poll_next(cx);
+        self.time_read(cx, poll)
     }
 }
 
-impl<S, Item, SinkItem> Sink<SinkItem> for Transport<S, Item, SinkItem>
+#[cfg(feature = "runtime")]
+impl<S, Item, SinkItem, F> Sink<SinkItem> for Transport<S, Item, SinkItem, F>
 where
     S: AsyncWrite,
     SinkItem: Serialize,
+    F: Format,
 {
     type SinkError = io::Error;
 
     fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
-        self.inner()
-            .start_send(item)
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        self.inner().start_send(item)
     }
 
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        convert(self.inner().poll_ready(cx))
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = self.as_mut().inner().poll_ready(cx);
+        self.time_write(cx, poll)
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        convert(self.inner().poll_flush(cx))
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = self.as_mut().inner().poll_flush(cx);
+        self.time_write(cx, poll)
     }
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        convert(self.inner().poll_close(cx))
-    }
-}
-
-fn convert<E: Into<Box<Error + Send + Sync>>>(poll: Poll<Result<(), E>>) -> Poll<io::Result<()>> {
-    match poll {
-        Poll::Pending => Poll::Pending,
-        Poll::Ready(Ok(())) => Poll::Ready(Ok(())),
-        Poll::Ready(Err(e)) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e))),
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = self.as_mut().inner().poll_close(cx);
+        self.time_write(cx, poll)
     }
 }
 
-impl<Item, SinkItem> rpc::Transport for Transport<TcpStream, Item, SinkItem>
+#[cfg(feature = "runtime")]
+impl<Item, SinkItem, F> rpc::Transport for Transport<TcpStream, Item, SinkItem, F>
 where
     Item: for<'de> Deserialize<'de>,
     SinkItem: Serialize,
+    F: Format,
 {
     type Item = Item;
     type SinkItem = SinkItem;
@@ -104,9 +250,18 @@ where
     fn local_addr(&self) -> io::Result<SocketAddr> {
         self.inner.get_ref().get_ref().local_addr()
     }
+
+    fn peer_identity(&self) -> Option<String> {
+        self.identity.clone()
+    }
+
+    fn session_token(&self) -> Option<String> {
+        self.session.clone()
+    }
 }
 
 /// Returns a new bincode transport that reads from and writes to `io`.
+#[cfg(feature = "runtime")]
 pub fn new<Item, SinkItem>(io: TcpStream) -> Transport<TcpStream, Item, SinkItem>
 where
     Item: for<'de> Deserialize<'de>,
@@ -115,15 +270,45 @@ where
     Transport::from(io)
 }
 
-impl<S, Item, SinkItem> From<S> for Transport<S, Item, SinkItem> {
-    fn from(inner: S) -> Self {
+#[cfg(feature = "runtime")]
+impl<S, Item, SinkItem, F> Transport<S, Item, SinkItem, F>
+where
+    S: AsyncRead + AsyncWrite,
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    F: Format,
+{
+    /// Returns a new transport that reads from and writes to `io`, framing messages with `codec`.
+    /// A codec [in another format](Codec::with_format) makes a transport in that format.
+    pub fn with_codec(io: S, codec: Codec<Item, SinkItem, F>) -> Self {
         Transport {
-            inner: Compat01As03Sink::new(AsyncBincodeStream::from(inner).for_async()),
+            inner: Compat01As03Sink::new(Framed::new(io, codec)),
+            identity: None,
+            session: None,
+            read_timeout: None,
+            read_deadline: None,
+            write_timeout: None,
+            write_deadline: None,
         }
     }
 }
 
-/// Connects to `addr`, wrapping the connection in a bincode transport.
+#[cfg(feature = "runtime")]
+impl<S, Item, SinkItem> From<S> for Transport<S, Item, SinkItem>
+where
+    S: AsyncRead + AsyncWrite,
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    fn from(inner: S) -> Self {
+        Transport::with_codec(inner, Codec::default())
+    }
+}
+
+/// Connects to `addr`, wrapping the connection in a bincode transport that decodes
+/// [responses](Decodes::Responses). Shorthand for [`Connector::connect`] with the default
+/// options.
+#[cfg(feature = "runtime")]
 pub async fn connect<Item, SinkItem>(
     addr: &SocketAddr,
 ) -> io::Result<Transport<TcpStream, Item, SinkItem>>
@@ -131,33 +316,237 @@ where
     Item: for<'de> Deserialize<'de>,
     SinkItem: Serialize,
 {
-    Ok(new(await!(TcpStream::connect(addr).compat())?))
+    await!(Connector::new().connect(addr))
 }
 
-/// Listens on `addr`, wrapping accepted connections in bincode transports.
+/// Like [`connect`], but the connection is registered with the reactor of `handle`, instead of the
+/// default reactor, for applications that run a reactor of their own.
+#[cfg(feature = "runtime")]
+#[deprecated(note = "Use `Connector::with_handle`, which combines with the other options.")]
+pub async fn connect_with_handle<Item, SinkItem>(
+    addr: &SocketAddr,
+    handle: &Handle,
+) -> io::Result<Transport<TcpStream, Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    await!(Connector::new().with_handle(handle.clone()).connect(addr))
+}
+
+/// Like [`connect`], but the transport rejects frames longer than `max_frame_len` bytes, in
+/// either direction, with a [`FrameTooLong`] error, instead of the
+/// [default](codec::DEFAULT_MAX_FRAME_LEN).
+#[cfg(feature = "runtime")]
+#[deprecated(note = "Use `Connector::with_max_frame_len`, which combines with the other options.")]
+pub async fn connect_with_max_frame_len<Item, SinkItem>(
+    addr: &SocketAddr,
+    max_frame_len: usize,
+) -> io::Result<Transport<TcpStream, Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    await!(Connector::new()
+        .with_max_frame_len(max_frame_len)
+        .connect(addr))
+}
+
+/// Like [`connect`], but fails with a [`ConnectTimeout`] if the connection isn't established
+/// within `timeout`, instead of waiting as long as the OS does, which can be minutes.
+#[cfg(feature = "runtime")]
+#[deprecated(
+    note = "Use `Connector::with_connect_timeout`, which combines with the other options."
+)]
+pub async fn connect_timeout<Item, SinkItem>(
+    addr: &SocketAddr,
+    timeout: Duration,
+) -> io::Result<Transport<TcpStream, Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    await!(Connector::new().with_connect_timeout(timeout).connect(addr))
+}
+
+/// The future of a connection made by a [`connect_balanced`] client or a [`registry`].
+#[cfg(feature = "runtime")]
+pub type Connecting<Req, Resp> = Pin<
+    Box<
+        dyn Future<
+                Output = io::Result<Transport<TcpStream, ServerMessage<Resp>, ClientMessage<Req>>>,
+            > + Send,
+    >,
+>;
+
+/// Returns a client that balances calls over bincode connections to each of `addrs`, e.g. every
+/// host that runs a service, reconnecting to each when its connection breaks.
+///
+/// Must only be called from on an executor.
+#[cfg(feature = "runtime")]
+pub fn connect_balanced<Req, Resp>(
+    config: reconnect::Config,
+    balancing: Balancing,
+    addrs: &[SocketAddr],
+) -> io::Result<Balancer<Req, Resp, impl Fn() -> Connecting<Req, Resp> + Send + Sync + 'static>>
+where
+    Req: Serialize + Send + 'static,
+    Resp: for<'de> Deserialize<'de> + Send + 'static,
+{
+    let connects = addrs
+        .iter()
+        .map(|&addr| move || async move { await!(connect(&addr)) }.boxed())
+        .collect();
+    Balancer::new(config, balancing, connects)
+}
+
+/// Returns a client that balances calls over bincode connections to the servers `resolver`
+/// resolves `name` to, following the servers as they come and go, once it resolves any.
+///
+/// Must only be called from on an executor.
+#[cfg(feature = "runtime")]
+pub fn connect_resolved<Req, Resp>(
+    config: reconnect::Config,
+    balancing: Balancing,
+    resolver: &dyn Resolver,
+    name: &str,
+) -> impl Future<
+    Output = io::Result<
+        Balancer<Req, Resp, impl Fn() -> Connecting<Req, Resp> + Send + Sync + 'static>,
+    >,
+>
+where
+    Req: Serialize + Send + 'static,
+    Resp: for<'de> Deserialize<'de> + Send + 'static,
+{
+    Balancer::resolve(config, balancing, resolver, name, |addr| {
+        move || async move { await!(connect(&addr)) }.boxed()
+    })
+}
+
+/// Returns a registry of clients that share bincode connections, one per server and config.
+#[cfg(feature = "runtime")]
+pub fn registry<Req, Resp>(
+) -> Registry<Req, Resp, impl Fn(SocketAddr) -> Connecting<Req, Resp> + Send + Sync + 'static>
+where
+    Req: Serialize + Send + 'static,
+    Resp: for<'de> Deserialize<'de> + Send + 'static,
+{
+    Registry::new(|addr| async move { await!(connect(&addr)) }.boxed())
+}
+
+#[cfg(feature = "runtime")]
+pub(crate) fn connect_error(
+    e: timeout::Error<io::Error>,
+    addr: SocketAddr,
+    timeout: Duration,
+) -> io::Error {
+    if e.is_elapsed() {
+        ConnectTimeout { addr, timeout }.into()
+    } else if e.is_inner() {
+        e.into_inner().expect("Checked by is_inner.")
+    } else {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("Could not set the connect timeout: {}", e),
+        )
+    }
+}
+
+/// A connection to a server that wasn't established in time, i.e. within a [`Connector`]'s
+/// connect timeout or by its deadline.
+///
+/// Returned as the inner error of a [`TimedOut`](io::ErrorKind::TimedOut) error, so that it can be
+/// told apart from requests that timed out, by downcasting the error with `io::Error::get_ref`.
+#[cfg(feature = "runtime")]
+#[derive(Debug)]
+pub struct ConnectTimeout {
+    /// The address being connected to.
+    pub addr: SocketAddr,
+    /// How long the connection was given to be established.
+    pub timeout: Duration,
+}
+
+#[cfg(feature = "runtime")]
+impl fmt::Display for ConnectTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Connecting to {} timed out after {:?}.",
+            self.addr, self.timeout
+        )
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl Error for ConnectTimeout {}
+
+#[cfg(feature = "runtime")]
+impl From<ConnectTimeout> for io::Error {
+    fn from(e: ConnectTimeout) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, e)
+    }
+}
+
+/// Listens on `addr`, wrapping accepted connections in bincode transports that decode
+/// [requests](Decodes::Requests).
+#[cfg(feature = "runtime")]
 pub fn listen<Item, SinkItem>(addr: &SocketAddr) -> io::Result<Incoming<Item, SinkItem>>
 where
     Item: for<'de> Deserialize<'de>,
     SinkItem: Serialize,
 {
-    let listener = TcpListener::bind(addr)?;
+    incoming(TcpListener::bind(addr)?)
+}
+
+/// Like [`listen`], but accepted connections are registered with the reactor of `handle`, instead
+/// of the default reactor, for applications that run a reactor of their own.
+#[cfg(feature = "runtime")]
+pub fn listen_with_handle<Item, SinkItem>(
+    addr: &SocketAddr,
+    handle: &Handle,
+) -> io::Result<Incoming<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    incoming(TcpListener::from_std(
+        std::net::TcpListener::bind(addr)?,
+        handle,
+    )?)
+}
+
+#[cfg(feature = "runtime")]
+fn incoming<Item, SinkItem>(listener: TcpListener) -> io::Result<Incoming<Item, SinkItem>> {
     let local_addr = listener.local_addr()?;
     let incoming = listener.incoming().compat();
     Ok(Incoming {
         incoming,
         local_addr,
+        ip_filter: IpFilter::default(),
+        max_frame_len: codec::DEFAULT_MAX_FRAME_LEN,
+        payload_limits: None,
+        payload_metrics: None,
+        write_timeout: None,
         ghost: PhantomData,
     })
 }
 
 /// A [`TcpListener`] that wraps connections in bincode transports.
+#[cfg(feature = "runtime")]
 #[derive(Debug)]
 pub struct Incoming<Item, SinkItem> {
     incoming: Compat01As03<tokio_tcp::Incoming>,
     local_addr: SocketAddr,
+    ip_filter: IpFilter,
+    max_frame_len: usize,
+    payload_limits: Option<MethodLimits>,
+    payload_metrics: Option<PayloadMetrics>,
+    write_timeout: Option<Duration>,
     ghost: PhantomData<(Item, SinkItem)>,
 }
 
+#[cfg(feature = "runtime")]
 impl<Item, SinkItem> Incoming<Item, SinkItem> {
     unsafe_pinned!(incoming: Compat01As03<tokio_tcp::Incoming>);
 
@@ -165,8 +554,54 @@ impl<Item, SinkItem> Incoming<Item, SinkItem> {
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
     }
+
+    /// Closes connections from IPs that `ip_filter` doesn't allow as soon as they're accepted.
+    /// Allows every IP by default.
+    pub fn with_ip_filter(mut self, ip_filter: IpFilter) -> Self {
+        self.ip_filter = ip_filter;
+        self
+    }
+
+    /// Sets the longest frame the transports of accepted connections read or write, beyond which
+    /// they fail with a [`FrameTooLong`] error, closing the connection. Defaults to
+    /// [`DEFAULT_MAX_FRAME_LEN`](codec::DEFAULT_MAX_FRAME_LEN).
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    /// Sets per-method [limits](Codec::with_payload_limits) on the size of the requests that
+    /// accepted connections read, beyond which requests are rejected with a
+    /// [`PayloadTooLarge`](rpc::ErrorCode::PayloadTooLarge) error, without closing the connection.
+    pub fn with_payload_limits(
+        mut self,
+        limits: PayloadLimits,
+        methods: &'static [&'static str],
+    ) -> Self {
+        self.payload_limits = Some(MethodLimits::new(limits, methods));
+        self
+    }
+
+    /// Reports the size of every request and response over accepted connections to `metrics`,
+    /// per method, as with [`Codec::with_payload_metrics`].
+    pub fn with_payload_metrics(
+        mut self,
+        metrics: Metrics,
+        methods: &'static [&'static str],
+    ) -> Self {
+        self.payload_metrics = Some(PayloadMetrics::new(metrics, methods));
+        self
+    }
+
+    /// Sets the [write timeout](Transport::with_write_timeout) of the transports of accepted
+    /// connections, so that clients that stop reading responses are disconnected.
+    pub fn with_write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.write_timeout = Some(write_timeout);
+        self
+    }
 }
 
+#[cfg(feature = "runtime")]
 impl<Item, SinkItem> Stream for Incoming<Item, SinkItem>
 where
     Item: for<'a> Deserialize<'a>,
@@ -174,8 +609,183 @@ where
 {
     type Item = io::Result<Transport<TcpStream, Item, SinkItem>>;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let next = ready!(self.incoming().poll_next(cx)?);
-        Poll::Ready(next.map(|conn| Ok(new(conn))))
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let conn = match ready!(self.as_mut().incoming().poll_next(cx)?) {
+                Some(conn) => conn,
+                None => return Poll::Ready(None),
+            };
+            if !is_peer_allowed(&self.ip_filter, &conn) {
+                continue;
+            }
+            let codec = Codec::new(self.max_frame_len)
+                .decoding(Decodes::Requests)
+                .with_method_limits(self.payload_limits.clone())
+                .with_method_metrics(self.payload_metrics.clone());
+            let mut transport = Transport::with_codec(conn, codec);
+            transport.write_timeout = self.write_timeout;
+            return Poll::Ready(Some(Ok(transport)));
+        }
+    }
+}
+
+/// Returns true if `ip_filter` allows the peer of `conn`. Connections whose peer address can't be
+/// read are let through, for the server to reject.
+#[cfg(feature = "runtime")]
+pub(crate) fn is_peer_allowed(ip_filter: &IpFilter, conn: &TcpStream) -> bool {
+    conn.peer_addr()
+        .map(|peer| ip_filter.allows(&peer.ip()))
+        .unwrap_or(true)
+}
+
+/// Listens on `addr` and spawns `server`, responding to requests with `request_handler`.
+/// Connections from IPs the server's config doesn't allow are closed as soon as they're accepted.
+/// Returns a handle to the server, to find the address it's listening on, e.g. when `addr` has
+/// port 0, to shut it down, or to wait for it to exit.
+#[cfg(feature = "runtime")]
+pub fn serve<Req, Resp, F, Fut>(
+    addr: &SocketAddr,
+    server: Server<Req, Resp>,
+    request_handler: F,
+) -> io::Result<Listening>
+where
+    Req: for<'de> Deserialize<'de> + Send + 'static,
+    Resp: Serialize + Send + 'static,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+{
+    let incoming = listen(addr)?.with_ip_filter(IpFilter::from(server.config()));
+    let local_addr = incoming.local_addr();
+    let serving = server
+        .incoming(incoming)
+        .respond_with(request_handler)
+        .spawn()?;
+    Ok(Listening {
+        local_addr,
+        serving,
+    })
+}
+
+/// A handle to a server spawned by [`serve`]. Resolves once the server exits: once it stops
+/// accepting connections, because it was shut down or its listener failed, and every
+/// connection has closed.
+///
+/// Dropping the handle leaves the server running.
+#[cfg(feature = "runtime")]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Listening {
+    local_addr: SocketAddr,
+    serving: Serving,
+}
+
+#[cfg(feature = "runtime")]
+impl Listening {
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns a handle that shuts the server down gracefully.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.serving.shutdown_handle()
+    }
+
+    /// Shuts the server down gracefully, resolving once every connection is closed. Connections
+    /// still open after `grace_period` are closed right away.
+    pub fn shutdown(&self, grace_period: Duration) -> impl Future<Output = ()> {
+        self.serving.shutdown(grace_period)
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl Future for Listening {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.serving.poll_unpin(cx)
+    }
+}
+
+#[cfg(all(test, feature = "runtime"))]
+mod tests {
+    use super::{connect_error, Codec, ConnectTimeout, Transport};
+    use futures::prelude::*;
+    use futures_legacy::{Async, Poll};
+    use std::{
+        io::{self, Read, Write},
+        time::Duration,
+    };
+    use tokio_io::{AsyncRead, AsyncWrite};
+    use tokio_timer::timeout;
+
+    /// A peer that stopped reading, so that writes to it wait forever.
+    struct Stuck;
+
+    impl Read for Stuck {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for Stuck {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for Stuck {}
+
+    impl AsyncWrite for Stuck {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn connect_timeout_is_distinct_from_connect_error() {
+        let addr = "10.0.0.1:80".parse().unwrap();
+        let timeout = Duration::from_secs(1);
+
+        let e = connect_error(timeout::Error::elapsed(), addr, timeout);
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        let e = e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<ConnectTimeout>()
+            .unwrap();
+        assert_eq!((e.addr, e.timeout), (addr, timeout));
+
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        let e = connect_error(timeout::Error::inner(refused), addr, timeout);
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn writes_to_a_peer_that_stops_reading_time_out() {
+        let mut transport = Transport::<_, String, String>::with_codec(Stuck, Codec::default())
+            .with_write_timeout(Duration::from_millis(10));
+        let sent = async move {
+            let e = await!(transport.send("hello".into())).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        };
+
+        tokio::run(sent.unit_error().boxed().compat());
+    }
+
+    #[test]
+    fn reads_from_a_quiet_peer_time_out() {
+        let mut transport = Transport::<_, String, String>::with_codec(Stuck, Codec::default())
+            .with_read_timeout(Duration::from_millis(10));
+        let read = async move {
+            let e = await!(transport.next()).unwrap().unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        };
+
+        tokio::run(read.unit_error().boxed().compat());
     }
 }
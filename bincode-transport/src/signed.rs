@@ -0,0 +1,822 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A bincode transport that signs every frame with an HMAC, for deployments that can't use TLS.
+//!
+//! Each frame carries the ID of the key that signed it, a per-connection sequence number, and the
+//! time it was sent. Frames are rejected with a [`SignatureError`] if the signature doesn't match,
+//! if the key is unknown, or if the frame is a replay: its sequence number is not greater than
+//! the last one seen on the connection, or it was sent outside the configured replay window.
+//!
+//! When a connection is established by [`connect`] or [`listen`], each end first sends the other a
+//! random nonce. Every frame's signature covers both nonces, along with the direction the frame
+//! travels in, so a frame recorded on one connection doesn't verify on any other, and a frame
+//! reflected back to the end that sent it doesn't verify either. Transports made with [`new`] take
+//! the [`Session`] of a [`handshake`] done by the caller.
+//!
+//! Signing provides integrity, not confidentiality; payloads are still sent in the clear.
+
+use crate::{Codec, Connector};
+use futures::{compat::*, prelude::*, ready, stream::FuturesUnordered};
+use hmac::{Hmac, Mac};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use rpc::server::IpFilter;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, io,
+    marker::PhantomData,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+use tokio_codec::Framed;
+use tokio_io::{
+    io::{read_exact, write_all},
+    AsyncRead, AsyncWrite,
+};
+use tokio_tcp::{TcpListener, TcpStream};
+use tokio_timer::Timeout;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Keys and replay-protection settings shared by both ends of a signed transport.
+#[derive(Clone)]
+pub struct Config {
+    /// The ID of the key used to sign outbound frames.
+    key_id: u32,
+    /// All keys accepted when verifying inbound frames, including the signing key.
+    keys: Arc<HashMap<u32, Vec<u8>>>,
+    /// How far a frame's timestamp may drift from the local clock before it is rejected.
+    replay_window: Duration,
+}
+
+impl Config {
+    /// Returns a config that signs and verifies frames with `key`, identified by `key_id`.
+    pub fn new(key_id: u32, key: impl Into<Vec<u8>>) -> Self {
+        let mut keys = HashMap::new();
+        keys.insert(key_id, key.into());
+        Config {
+            key_id,
+            keys: Arc::new(keys),
+            replay_window: Duration::from_secs(30),
+        }
+    }
+
+    /// Additionally accepts frames signed by `key`. Useful while rotating keys, when peers may
+    /// still be signing with the previous key.
+    pub fn with_verification_key(mut self, key_id: u32, key: impl Into<Vec<u8>>) -> Self {
+        Arc::make_mut(&mut self.keys).insert(key_id, key.into());
+        self
+    }
+
+    /// Sets how far a frame's timestamp may be from the local clock, in either direction, before
+    /// the frame is rejected as a replay. Defaults to 30 seconds.
+    pub fn with_replay_window(mut self, replay_window: Duration) -> Self {
+        self.replay_window = replay_window;
+        self
+    }
+
+    fn mac(&self, key_id: u32) -> Result<HmacSha256, SignatureError> {
+        let key = self
+            .keys
+            .get(&key_id)
+            .ok_or(SignatureError::UnknownKey(key_id))?;
+        Ok(HmacSha256::new_varkey(key).expect("HMAC accepts keys of any length"))
+    }
+}
+
+// Keys are deliberately left out so that they never end up in logs.
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut key_ids: Vec<_> = self.keys.keys().collect();
+        key_ids.sort();
+        f.debug_struct("Config")
+            .field("key_id", &self.key_id)
+            .field("key_ids", &key_ids)
+            .field("replay_window", &self.replay_window)
+            .finish()
+    }
+}
+
+/// The reason a signed frame was rejected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    /// The frame was signed with a key that isn't configured.
+    UnknownKey(u32),
+    /// The frame's signature doesn't match its contents.
+    InvalidSignature,
+    /// The frame's sequence number was not greater than the last one seen on the connection.
+    Replayed {
+        /// The sequence number of the rejected frame.
+        sequence: u64,
+    },
+    /// The frame was sent outside the replay window.
+    Expired,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::UnknownKey(key_id) => {
+                write!(f, "Frame signed with unknown key {}.", key_id)
+            }
+            SignatureError::InvalidSignature => write!(f, "Frame signature is invalid."),
+            SignatureError::Replayed { sequence } => {
+                write!(f, "Frame with sequence number {} was replayed.", sequence)
+            }
+            SignatureError::Expired => write!(f, "Frame was sent outside the replay window."),
+        }
+    }
+}
+
+impl Error for SignatureError {}
+
+impl From<SignatureError> for io::Error {
+    fn from(e: SignatureError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Which end of a connection a transport is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// The end that connected.
+    Client,
+    /// The end that accepted the connection.
+    Server,
+}
+
+impl Role {
+    fn peer(self) -> Role {
+        match self {
+            Role::Client => Role::Server,
+            Role::Server => Role::Client,
+        }
+    }
+
+    /// The byte that frames sent by this end are signed with, so that they don't verify when
+    /// reflected back.
+    fn direction(self) -> u8 {
+        match self {
+            Role::Client => 0,
+            Role::Server => 1,
+        }
+    }
+}
+
+const NONCE_LEN: usize = 16;
+
+type Nonce = [u8; NONCE_LEN];
+
+/// The nonces the two ends of a connection exchanged in their [`handshake`], which bind the
+/// connection's frames to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Session {
+    role: Role,
+    client_nonce: Nonce,
+    server_nonce: Nonce,
+}
+
+/// Sends the peer of `conn` a random nonce, and reads the peer's, returning the session they
+/// make up. Each end sends its nonce as 16 raw bytes, before any frames.
+pub async fn handshake<S>(conn: S, role: Role) -> io::Result<(S, Session)>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let nonce: Nonce = rand::random();
+    let (conn, _) = await!(write_all(conn, nonce).compat())?;
+    let (conn, peer_nonce) = await!(read_exact(conn, [0; NONCE_LEN]).compat())?;
+    let (client_nonce, server_nonce) = match role {
+        Role::Client => (nonce, peer_nonce),
+        Role::Server => (peer_nonce, nonce),
+    };
+    Ok((
+        conn,
+        Session {
+            role,
+            client_nonce,
+            server_nonce,
+        },
+    ))
+}
+
+const TAG_LEN: usize = 32;
+
+/// Confirms that both ends of `session` saw the same `transcript` of what they exchanged after
+/// their [`handshake`], e.g. the features they offered each other, so that an attacker who
+/// altered the exchange in transit, say to strip a feature from an offer, is caught before the
+/// connection is used.
+///
+/// Each end sends the ID of its signing key, as 4 big-endian bytes, followed by an HMAC of the
+/// transcript and the session's nonces, then verifies the peer's. Fails with
+/// [`InvalidData`](io::ErrorKind::InvalidData) if the peer's HMAC doesn't match.
+pub(crate) async fn confirm<S>(
+    conn: S,
+    config: Config,
+    session: Session,
+    transcript: Vec<u8>,
+) -> io::Result<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let mac = config
+        .mac(config.key_id)
+        .expect("The signing key is always configured.");
+    let mut confirmation = config.key_id.to_be_bytes().to_vec();
+    confirmation.extend(
+        transcript_mac(mac, &session, session.role, &transcript)
+            .result()
+            .code(),
+    );
+    let (conn, _) = await!(write_all(conn, confirmation).compat())?;
+    let (conn, peer) = await!(read_exact(conn, [0; 4 + TAG_LEN]).compat())?;
+    let mut key_id = [0; 4];
+    key_id.copy_from_slice(&peer[..4]);
+    let mac = config.mac(u32::from_be_bytes(key_id))?;
+    transcript_mac(mac, &session, session.role.peer(), &transcript)
+        .verify(&peer[4..])
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The peer saw a different handshake, which may have been tampered with.",
+            )
+        })?;
+    Ok(conn)
+}
+
+/// Returns `mac` over `transcript`, as confirmed by `sender` in `session`.
+fn transcript_mac(
+    mut mac: HmacSha256,
+    session: &Session,
+    sender: Role,
+    transcript: &[u8],
+) -> HmacSha256 {
+    // Keeps confirmations from ever verifying as frames, and vice versa.
+    mac.input(b"transcript");
+    mac.input(&[sender.direction()]);
+    mac.input(&session.client_nonce);
+    mac.input(&session.server_nonce);
+    mac.input(transcript);
+    mac
+}
+
+/// The frame written to the wire.
+#[derive(Debug, Serialize, Deserialize)]
+struct SignedFrame {
+    key_id: u32,
+    sequence: u64,
+    /// Milliseconds since the epoch.
+    timestamp: u64,
+    payload: Vec<u8>,
+    tag: Vec<u8>,
+}
+
+impl SignedFrame {
+    /// Returns `mac` over the frame, as sent by `sender` in `session`.
+    fn mac(&self, mut mac: HmacSha256, session: &Session, sender: Role) -> HmacSha256 {
+        mac.input(&[sender.direction()]);
+        mac.input(&session.client_nonce);
+        mac.input(&session.server_nonce);
+        mac.input(&self.key_id.to_be_bytes());
+        mac.input(&self.sequence.to_be_bytes());
+        mac.input(&self.timestamp.to_be_bytes());
+        mac.input(&self.payload);
+        mac
+    }
+}
+
+/// Signs outbound frames.
+#[derive(Debug)]
+struct Signer {
+    config: Config,
+    session: Session,
+    next_sequence: u64,
+}
+
+impl Signer {
+    fn sign(&mut self, payload: Vec<u8>, now: SystemTime) -> SignedFrame {
+        let mut frame = SignedFrame {
+            key_id: self.config.key_id,
+            sequence: self.next_sequence,
+            timestamp: epoch_millis(now),
+            payload,
+            tag: vec![],
+        };
+        self.next_sequence += 1;
+        let mac = self
+            .config
+            .mac(frame.key_id)
+            .expect("The signing key is always configured.");
+        frame.tag = frame
+            .mac(mac, &self.session, self.session.role)
+            .result()
+            .code()
+            .to_vec();
+        frame
+    }
+}
+
+/// Verifies inbound frames.
+#[derive(Debug)]
+struct Verifier {
+    config: Config,
+    session: Session,
+    last_sequence: Option<u64>,
+}
+
+impl Verifier {
+    fn verify(&mut self, frame: SignedFrame, now: SystemTime) -> Result<Vec<u8>, SignatureError> {
+        let mac = self.config.mac(frame.key_id)?;
+        frame
+            .mac(mac, &self.session, self.session.role.peer())
+            .verify(&frame.tag)
+            .map_err(|_| SignatureError::InvalidSignature)?;
+
+        if let Some(last_sequence) = self.last_sequence {
+            if frame.sequence <= last_sequence {
+                return Err(SignatureError::Replayed {
+                    sequence: frame.sequence,
+                });
+            }
+        }
+
+        let window = self.config.replay_window.as_millis() as u64;
+        let now = epoch_millis(now);
+        if frame.timestamp.saturating_add(window) < now
+            || frame.timestamp > now.saturating_add(window)
+        {
+            return Err(SignatureError::Expired);
+        }
+
+        self.last_sequence = Some(frame.sequence);
+        Ok(frame.payload)
+    }
+}
+
+fn epoch_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// A transport that signs frames written to, and verifies frames read from, a [`TcpStream`].
+#[derive(Debug)]
+pub struct Transport<S, Item, SinkItem> {
+    inner: Compat01As03Sink<Framed<S, Codec<SignedFrame, SignedFrame>>, SignedFrame>,
+    signer: Signer,
+    verifier: Verifier,
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+impl<S, Item, SinkItem> Transport<S, Item, SinkItem> {
+    unsafe_pinned!(
+        inner: Compat01As03Sink<Framed<S, Codec<SignedFrame, SignedFrame>>, SignedFrame>
+    );
+    unsafe_unpinned!(signer: Signer);
+    unsafe_unpinned!(verifier: Verifier);
+}
+
+impl<S, Item, SinkItem> Stream for Transport<S, Item, SinkItem>
+where
+    S: AsyncRead,
+    Item: for<'a> Deserialize<'a>,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
+        let frame = match ready!(self.as_mut().inner().poll_next(cx)?) {
+            Some(frame) => frame,
+            None => return Poll::Ready(None),
+        };
+        let payload = self.as_mut().verifier().verify(frame, SystemTime::now())?;
+        Poll::Ready(Some(
+            bincode::config()
+                .limit(payload.len() as u64)
+                .deserialize(&payload)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        ))
+    }
+}
+
+impl<S, Item, SinkItem> Sink<SinkItem> for Transport<S, Item, SinkItem>
+where
+    S: AsyncWrite,
+    SinkItem: Serialize,
+{
+    type SinkError = io::Error;
+
+    fn start_send(mut self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        let payload = bincode::serialize(&item)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let frame = self.as_mut().signer().sign(payload, SystemTime::now());
+        self.inner().start_send(frame)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_ready(cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_close(cx)
+    }
+}
+
+impl<Item, SinkItem> rpc::Transport for Transport<TcpStream, Item, SinkItem>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    type Item = Item;
+    type SinkItem = SinkItem;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.get_ref().get_ref().peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.get_ref().get_ref().local_addr()
+    }
+}
+
+/// Returns a new signed transport that reads from and writes to `io`, whose [`handshake`]
+/// established `session`.
+pub fn new<S, Item, SinkItem>(
+    io: S,
+    config: Config,
+    session: Session,
+) -> Transport<S, Item, SinkItem>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    framed(io, Codec::default(), config, session)
+}
+
+fn framed<S, Item, SinkItem>(
+    io: S,
+    codec: Codec<SignedFrame, SignedFrame>,
+    config: Config,
+    session: Session,
+) -> Transport<S, Item, SinkItem>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    Transport {
+        inner: Compat01As03Sink::new(Framed::new(io, codec)),
+        signer: Signer {
+            config: config.clone(),
+            session,
+            next_sequence: 0,
+        },
+        verifier: Verifier {
+            config,
+            session,
+            last_sequence: None,
+        },
+        ghost: PhantomData,
+    }
+}
+
+/// Connects to `addr`, and exchanges nonces with the server, before wrapping the connection in a
+/// signed transport.
+pub async fn connect<Item, SinkItem>(
+    addr: &SocketAddr,
+    config: Config,
+) -> io::Result<Transport<TcpStream, Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    await!(Connector::new().signed(addr, config))
+}
+
+impl Connector {
+    /// Like [`connect`](self::connect), but connects with the options of this connector. The
+    /// transport isn't timed, but otherwise takes the connector's max frame length.
+    pub fn signed<Item, SinkItem>(
+        &self,
+        addr: &SocketAddr,
+        config: Config,
+    ) -> impl Future<Output = io::Result<Transport<TcpStream, Item, SinkItem>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let connector = self.clone();
+        let addr = *addr;
+        self.within(addr, async move {
+            let conn = await!(connector.tcp(addr))?;
+            let (conn, session) = await!(connector.handshaking(handshake(conn, Role::Client)))?;
+            let codec = Codec::new(connector.max_frame_len());
+            Ok(framed(conn, codec, config, session))
+        })
+    }
+}
+
+/// Listens on `addr`, exchanging nonces with each client before wrapping its connection in a
+/// signed transport.
+pub fn listen<Item, SinkItem>(
+    addr: &SocketAddr,
+    config: Config,
+) -> io::Result<Incoming<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let incoming = listener.incoming().compat();
+    Ok(Incoming {
+        incoming,
+        local_addr,
+        ip_filter: IpFilter::default(),
+        config,
+        handshakes: FuturesUnordered::new(),
+        max_handshakes: 64,
+        handshake_timeout: Duration::from_secs(10),
+        ghost: PhantomData,
+    })
+}
+
+type Handshake = Pin<Box<dyn Future<Output = io::Result<(TcpStream, Session)>> + Send>>;
+
+/// A [`TcpListener`] that exchanges nonces with each client before wrapping its connection in a
+/// signed transport.
+///
+/// Handshakes run concurrently, up to a [limit](Incoming::with_max_handshakes), beyond which no
+/// more connections are accepted until a handshake completes. A failed handshake yields an error
+/// for that connection only.
+pub struct Incoming<Item, SinkItem> {
+    incoming: Compat01As03<tokio_tcp::Incoming>,
+    local_addr: SocketAddr,
+    ip_filter: IpFilter,
+    config: Config,
+    handshakes: FuturesUnordered<Handshake>,
+    max_handshakes: usize,
+    handshake_timeout: Duration,
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+impl<Item, SinkItem> fmt::Debug for Incoming<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Incoming")
+            .field("local_addr", &self.local_addr)
+            .field("ip_filter", &self.ip_filter)
+            .field("config", &self.config)
+            .field("handshakes", &self.handshakes.len())
+            .field("max_handshakes", &self.max_handshakes)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .finish()
+    }
+}
+
+impl<Item, SinkItem> Incoming<Item, SinkItem> {
+    unsafe_pinned!(incoming: Compat01As03<tokio_tcp::Incoming>);
+    unsafe_unpinned!(handshakes: FuturesUnordered<Handshake>);
+
+    /// Returns the address being listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Closes connections from IPs that `ip_filter` doesn't allow as soon as they're accepted,
+    /// before the handshake. Allows every IP by default.
+    pub fn with_ip_filter(mut self, ip_filter: IpFilter) -> Self {
+        self.ip_filter = ip_filter;
+        self
+    }
+
+    /// Sets the maximum number of handshakes in progress at once. Defaults to 64.
+    pub fn with_max_handshakes(mut self, max_handshakes: usize) -> Self {
+        self.max_handshakes = max_handshakes;
+        self
+    }
+
+    /// Sets how long a client has to complete the handshake before its connection is closed.
+    /// Defaults to 10 seconds.
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+}
+
+impl<Item, SinkItem> Stream for Incoming<Item, SinkItem>
+where
+    Item: for<'a> Deserialize<'a>,
+    SinkItem: Serialize,
+{
+    type Item = io::Result<Transport<TcpStream, Item, SinkItem>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while self.handshakes.len() < self.max_handshakes {
+            match self.as_mut().incoming().poll_next(cx) {
+                Poll::Ready(Some(Ok(conn))) => {
+                    if !crate::is_peer_allowed(&self.ip_filter, &conn) {
+                        continue;
+                    }
+                    let handshake = Timeout::new(
+                        handshake(conn, Role::Server).boxed().compat(),
+                        self.handshake_timeout,
+                    )
+                    .compat()
+                    .map_err(|e| {
+                        if e.is_elapsed() {
+                            io::Error::new(
+                                io::ErrorKind::TimedOut,
+                                "Client didn't complete the signing handshake in time.",
+                            )
+                        } else if e.is_inner() {
+                            e.into_inner().expect("Checked by is_inner.")
+                        } else {
+                            io::Error::new(
+                                io::ErrorKind::Other,
+                                format!("Could not set the handshake timeout: {}", e),
+                            )
+                        }
+                    });
+                    self.as_mut().handshakes().push(handshake.boxed());
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) if self.handshakes.is_empty() => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+
+        match ready!(self.as_mut().handshakes().poll_next_unpin(cx)) {
+            Some(handshake) => {
+                let (conn, session) = handshake?;
+                Poll::Ready(Some(Ok(new(conn, self.config.clone(), session))))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Role, Session, SignatureError, Signer, Verifier};
+    use std::time::{Duration, SystemTime};
+
+    fn session(role: Role, client_nonce: u8, server_nonce: u8) -> Session {
+        Session {
+            role,
+            client_nonce: [client_nonce; 16],
+            server_nonce: [server_nonce; 16],
+        }
+    }
+
+    /// Returns a client's signer and the verifier of the server it's connected to.
+    fn set_up(signing: Config, verifying: Config) -> (Signer, Verifier) {
+        (
+            Signer {
+                config: signing,
+                session: session(Role::Client, 1, 2),
+                next_sequence: 0,
+            },
+            Verifier {
+                config: verifying,
+                session: session(Role::Server, 1, 2),
+                last_sequence: None,
+            },
+        )
+    }
+
+    #[test]
+    fn verifies_signed_frame() {
+        let config = Config::new(1, "secret");
+        let (mut signer, mut verifier) = set_up(config.clone(), config);
+        let now = SystemTime::now();
+
+        let frame = signer.sign(b"hello".to_vec(), now);
+        assert_eq!(verifier.verify(frame, now), Ok(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn rejects_tampered_frame() {
+        let config = Config::new(1, "secret");
+        let (mut signer, mut verifier) = set_up(config.clone(), config);
+        let now = SystemTime::now();
+
+        let mut frame = signer.sign(b"hello".to_vec(), now);
+        frame.payload = b"jello".to_vec();
+        assert_eq!(
+            verifier.verify(frame, now),
+            Err(SignatureError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_key() {
+        let (mut signer, mut verifier) = set_up(Config::new(2, "new"), Config::new(1, "old"));
+        let now = SystemTime::now();
+
+        let frame = signer.sign(b"hello".to_vec(), now);
+        assert_eq!(
+            verifier.verify(frame, now),
+            Err(SignatureError::UnknownKey(2))
+        );
+    }
+
+    #[test]
+    fn accepts_rotated_key() {
+        let (mut signer, mut verifier) = set_up(
+            Config::new(1, "old"),
+            Config::new(2, "new").with_verification_key(1, "old"),
+        );
+        let now = SystemTime::now();
+
+        let frame = signer.sign(b"hello".to_vec(), now);
+        assert!(verifier.verify(frame, now).is_ok());
+    }
+
+    #[test]
+    fn rejects_replayed_frame() {
+        let config = Config::new(1, "secret");
+        let (mut signer, mut verifier) = set_up(config.clone(), config);
+        let now = SystemTime::now();
+
+        let first = signer.sign(b"hello".to_vec(), now);
+        let replay = super::SignedFrame {
+            key_id: first.key_id,
+            sequence: first.sequence,
+            timestamp: first.timestamp,
+            payload: first.payload.clone(),
+            tag: first.tag.clone(),
+        };
+        assert!(verifier.verify(first, now).is_ok());
+        assert_eq!(
+            verifier.verify(replay, now),
+            Err(SignatureError::Replayed { sequence: 0 })
+        );
+    }
+
+    #[test]
+    fn rejects_frame_replayed_on_another_connection() {
+        let config = Config::new(1, "secret");
+        let (mut signer, mut verifier) = set_up(config.clone(), config.clone());
+        let now = SystemTime::now();
+
+        let frame = signer.sign(b"hello".to_vec(), now);
+        let replay = super::SignedFrame {
+            key_id: frame.key_id,
+            sequence: frame.sequence,
+            timestamp: frame.timestamp,
+            payload: frame.payload.clone(),
+            tag: frame.tag.clone(),
+        };
+        assert!(verifier.verify(frame, now).is_ok());
+
+        // The second connection starts its sequence numbers over, but has a new server nonce.
+        let mut second = Verifier {
+            config,
+            session: session(Role::Server, 1, 3),
+            last_sequence: None,
+        };
+        assert_eq!(
+            second.verify(replay, now),
+            Err(SignatureError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_reflected_frame() {
+        let config = Config::new(1, "secret");
+        let (mut signer, _) = set_up(config.clone(), config.clone());
+        let now = SystemTime::now();
+
+        let frame = signer.sign(b"hello".to_vec(), now);
+        let mut client = Verifier {
+            config,
+            session: session(Role::Client, 1, 2),
+            last_sequence: None,
+        };
+        assert_eq!(
+            client.verify(frame, now),
+            Err(SignatureError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn rejects_expired_frame() {
+        let config = Config::new(1, "secret").with_replay_window(Duration::from_secs(1));
+        let (mut signer, mut verifier) = set_up(config.clone(), config);
+        let now = SystemTime::now();
+
+        let frame = signer.sign(b"hello".to_vec(), now);
+        assert_eq!(
+            verifier.verify(frame, now + Duration::from_secs(2)),
+            Err(SignatureError::Expired)
+        );
+    }
+}
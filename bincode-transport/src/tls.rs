@@ -0,0 +1,306 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A bincode transport encrypted with TLS, via [`native_tls`].
+//!
+//! Clients [`connect`] with a [`TlsConnector`], and servers [`listen`] with a [`TlsAcceptor`]; once
+//! the handshake completes, the transports behave like plain bincode transports.
+//!
+//! A listener's acceptor can be replaced while it listens through its [`Reload`] handle, e.g. to
+//! roll out a renewed certificate, either when told to or when
+//! [the identity's file changes](Reload::watch). Connections already accepted keep the
+//! certificate they were accepted with.
+//!
+//! Clients that reconnect often can [resume](resumption) their sessions instead, with the
+//! `tls-resumption` feature.
+
+use crate::{Codec, Connector, Decodes, Format, Transport};
+use futures::{compat::*, prelude::*, ready, stream::FuturesUnordered};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use rpc::server::IpFilter;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs, io,
+    marker::PhantomData,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio_tcp::{TcpListener, TcpStream};
+use tokio_timer::Delay;
+use tokio_tls::{Accept, TlsStream};
+
+#[cfg(feature = "tls-resumption")]
+pub mod resumption;
+
+pub use native_tls;
+pub use tokio_tls::{TlsAcceptor, TlsConnector};
+
+impl<Item, SinkItem, F> rpc::Transport for Transport<TlsStream<TcpStream>, Item, SinkItem, F>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    F: Format,
+{
+    type Item = Item;
+    type SinkItem = SinkItem;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp_stream().peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp_stream().local_addr()
+    }
+}
+
+impl<Item, SinkItem, F> Transport<TlsStream<TcpStream>, Item, SinkItem, F> {
+    fn tcp_stream(&self) -> &TcpStream {
+        self.inner.get_ref().get_ref().get_ref().get_ref()
+    }
+}
+
+/// Connects to `addr`, verifying that the server is `domain`, and wraps the connection in a
+/// bincode transport that decodes [responses](Decodes::Responses).
+pub fn connect<Item, SinkItem>(
+    addr: &SocketAddr,
+    domain: &str,
+    connector: TlsConnector,
+) -> impl Future<Output = io::Result<Transport<TlsStream<TcpStream>, Item, SinkItem>>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    Connector::new().tls(addr, domain, connector)
+}
+
+impl Connector {
+    /// Like [`connect`](self::connect), but connects with the options of this connector.
+    pub fn tls<Item, SinkItem>(
+        &self,
+        addr: &SocketAddr,
+        domain: &str,
+        tls: TlsConnector,
+    ) -> impl Future<Output = io::Result<Transport<TlsStream<TcpStream>, Item, SinkItem>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let connector = self.clone();
+        let addr = *addr;
+        let domain = domain.to_string();
+        self.within(addr, async move {
+            let conn = await!(connector.tcp(addr))?;
+            let handshake = tls.connect(&domain, conn).compat().map_err(tls_error);
+            let conn = await!(connector.handshaking(handshake))?;
+            Ok(connector.transport(conn))
+        })
+    }
+}
+
+/// Listens on `addr`, wrapping accepted connections in bincode transports that decode
+/// [requests](Decodes::Requests), once they complete a handshake with `acceptor`.
+pub fn listen<Item, SinkItem>(
+    addr: &SocketAddr,
+    acceptor: TlsAcceptor,
+) -> io::Result<Incoming<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let incoming = listener.incoming().compat();
+    Ok(Incoming {
+        incoming,
+        local_addr,
+        ip_filter: IpFilter::default(),
+        acceptor: Reload(Arc::new(RwLock::new(acceptor))),
+        handshakes: FuturesUnordered::new(),
+        max_handshakes: 64,
+        ghost: PhantomData,
+    })
+}
+
+/// A [`TcpListener`] that wraps connections in TLS-encrypted bincode transports.
+///
+/// Handshakes run concurrently, up to a [limit](Incoming::with_max_handshakes), beyond which no
+/// more connections are accepted until a handshake completes. A failed handshake yields an error
+/// for that connection only.
+pub struct Incoming<Item, SinkItem> {
+    incoming: Compat01As03<tokio_tcp::Incoming>,
+    local_addr: SocketAddr,
+    ip_filter: IpFilter,
+    acceptor: Reload,
+    handshakes: FuturesUnordered<Compat01As03<Accept<TcpStream>>>,
+    max_handshakes: usize,
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+impl<Item, SinkItem> fmt::Debug for Incoming<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Incoming")
+            .field("local_addr", &self.local_addr)
+            .field("ip_filter", &self.ip_filter)
+            .field("handshakes", &self.handshakes.len())
+            .field("max_handshakes", &self.max_handshakes)
+            .finish()
+    }
+}
+
+impl<Item, SinkItem> Incoming<Item, SinkItem> {
+    unsafe_pinned!(incoming: Compat01As03<tokio_tcp::Incoming>);
+    unsafe_unpinned!(handshakes: FuturesUnordered<Compat01As03<Accept<TcpStream>>>);
+
+    /// Returns the address being listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Closes connections from IPs that `ip_filter` doesn't allow as soon as they're accepted,
+    /// before the handshake. Allows every IP by default.
+    pub fn with_ip_filter(mut self, ip_filter: IpFilter) -> Self {
+        self.ip_filter = ip_filter;
+        self
+    }
+
+    /// Sets the maximum number of handshakes in progress at once. Defaults to 64.
+    pub fn with_max_handshakes(mut self, max_handshakes: usize) -> Self {
+        self.max_handshakes = max_handshakes;
+        self
+    }
+
+    /// Returns a handle that replaces the acceptor of this listener.
+    pub fn reload_handle(&self) -> Reload {
+        self.acceptor.clone()
+    }
+}
+
+/// Replaces the acceptor of a TLS [`Incoming`], for the handshakes of the connections it accepts
+/// from then on. Connections already accepted, including those still handshaking, are left as
+/// they are, so reloading drops none of them.
+#[derive(Clone)]
+pub struct Reload(Arc<RwLock<TlsAcceptor>>);
+
+impl fmt::Debug for Reload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Reload")
+    }
+}
+
+impl Reload {
+    /// Accepts connections with `acceptor` from now on.
+    pub fn reload(&self, acceptor: TlsAcceptor) {
+        *self.0.write().unwrap() = acceptor;
+    }
+
+    /// Accepts connections with an acceptor with the default settings, presenting `identity`, from
+    /// now on.
+    pub fn reload_identity(&self, identity: native_tls::Identity) -> io::Result<()> {
+        let acceptor = native_tls::TlsAcceptor::new(identity).map_err(tls_error)?;
+        self.reload(acceptor.into());
+        Ok(())
+    }
+
+    /// Returns a stream that checks the PKCS #12 archive at `path` every `interval`, and whenever
+    /// the file was modified, [reloads](Reload::reload_identity) the identity in it, decrypted
+    /// with `password`. The stream yields once per reload, or with the error of a failed check or
+    /// reload, which keeps the previous identity. The stream must be polled, e.g. on a task
+    /// spawned alongside the server, for the file to be checked.
+    pub fn watch(
+        &self,
+        path: impl Into<PathBuf>,
+        password: &str,
+        interval: Duration,
+    ) -> impl Stream<Item = io::Result<()>> {
+        let path = path.into();
+        let state = Watch {
+            reload: self.clone(),
+            modified: modified(&path).ok(),
+            path,
+            password: password.to_string(),
+        };
+        stream::unfold(state, move |mut state| async move {
+            loop {
+                if let Err(e) = await!(Delay::new(Instant::now() + interval).compat()) {
+                    return Some((Err(io::Error::new(io::ErrorKind::Other, e)), state));
+                }
+                let modified = match modified(&state.path) {
+                    Ok(modified) => modified,
+                    Err(e) => return Some((Err(e), state)),
+                };
+                if state.modified == Some(modified) {
+                    continue;
+                }
+                state.modified = Some(modified);
+                return Some((state.reload_identity(), state));
+            }
+        })
+    }
+}
+
+/// The state of a stream that watches an identity's file.
+struct Watch {
+    reload: Reload,
+    path: PathBuf,
+    password: String,
+    /// When the file was last modified, as of the last check.
+    modified: Option<SystemTime>,
+}
+
+impl Watch {
+    fn reload_identity(&self) -> io::Result<()> {
+        let archive = fs::read(&self.path)?;
+        let identity =
+            native_tls::Identity::from_pkcs12(&archive, &self.password).map_err(tls_error)?;
+        self.reload.reload_identity(identity)
+    }
+}
+
+fn modified(path: &Path) -> io::Result<SystemTime> {
+    fs::metadata(path)?.modified()
+}
+
+impl<Item, SinkItem> Stream for Incoming<Item, SinkItem>
+where
+    Item: for<'a> Deserialize<'a>,
+    SinkItem: Serialize,
+{
+    type Item = io::Result<Transport<TlsStream<TcpStream>, Item, SinkItem>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while self.handshakes.len() < self.max_handshakes {
+            match self.as_mut().incoming().poll_next(cx) {
+                Poll::Ready(Some(Ok(conn))) => {
+                    if !crate::is_peer_allowed(&self.ip_filter, &conn) {
+                        continue;
+                    }
+                    let handshake = self.acceptor.0.read().unwrap().accept(conn).compat();
+                    self.as_mut().handshakes().push(handshake);
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) if self.handshakes.is_empty() => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+
+        match ready!(self.as_mut().handshakes().poll_next_unpin(cx)) {
+            Some(conn) => {
+                let conn = conn.map_err(tls_error)?;
+                let codec = Codec::default().decoding(Decodes::Requests);
+                Poll::Ready(Some(Ok(Transport::with_codec(conn, codec))))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+fn tls_error(e: native_tls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
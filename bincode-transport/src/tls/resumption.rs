@@ -0,0 +1,174 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! TLS clients that resume sessions, via [`openssl`].
+//!
+//! [`native_tls`](super::native_tls) can't resume sessions, so every reconnect pays for a full
+//! handshake. A [`ResumingConnector`] instead remembers the last session it was given for each
+//! domain, and offers it when it next connects to that domain; if the server still has the
+//! session, the handshake skips the key exchange and certificate verification, which saves a
+//! round trip on TLS 1.2 and the server's key exchange on TLS 1.3. Servers listening with
+//! [`tls::listen`](super::listen) resume sessions by default.
+//!
+//! 0-RTT early data isn't sent: [`tokio_openssl`] has no way to write early data while
+//! connecting, and early data can be replayed by an attacker, so it would only suit requests that
+//! are safe to repeat.
+
+use crate::{Connector, Transport};
+use futures::{compat::*, prelude::*};
+use openssl::{
+    ex_data::Index,
+    ssl::{Ssl, SslConnector, SslConnectorBuilder, SslSession, SslSessionCacheMode},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio_openssl::{ConnectConfigurationExt, SslStream};
+use tokio_tcp::TcpStream;
+
+pub use openssl;
+
+/// Connects with TLS, resuming the session last established with the same domain, if there is
+/// one. Clones share their sessions.
+#[derive(Clone)]
+pub struct ResumingConnector {
+    connector: SslConnector,
+    sessions: Arc<Mutex<HashMap<String, SslSession>>>,
+    /// Where a connection stores the domain it connects to, for the new session callback.
+    domain: Index<Ssl, String>,
+}
+
+impl fmt::Debug for ResumingConnector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResumingConnector")
+            .field("sessions", &self.sessions.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl ResumingConnector {
+    /// Returns a connector with the settings of `builder`, e.g. its root certificates, that
+    /// caches the sessions of the connections it makes.
+    pub fn new(mut builder: SslConnectorBuilder) -> io::Result<Self> {
+        let domain = Ssl::new_ex_index::<String>().map_err(ssl_error)?;
+        let sessions = Arc::new(Mutex::new(HashMap::new()));
+        let cache = Arc::downgrade(&sessions);
+        builder.set_session_cache_mode(SslSessionCacheMode::CLIENT);
+        builder.set_new_session_callback(move |ssl, session| {
+            if let (Some(cache), Some(domain)) = (cache.upgrade(), ssl.ex_data(domain)) {
+                cache.lock().unwrap().insert(domain.clone(), session);
+            }
+        });
+        Ok(ResumingConnector {
+            connector: builder.build(),
+            sessions,
+            domain,
+        })
+    }
+
+    /// Forgets the session established with `domain`, so that the next connection to it does a
+    /// full handshake.
+    pub fn forget(&self, domain: &str) {
+        self.sessions.lock().unwrap().remove(domain);
+    }
+
+    /// Starts a handshake over `conn`, verifying that the server is `domain`, and offering the
+    /// session last established with it.
+    fn handshake(
+        &self,
+        domain: &str,
+        conn: TcpStream,
+    ) -> io::Result<impl Future<Output = io::Result<SslStream<TcpStream>>>> {
+        let mut config = self.connector.configure().map_err(ssl_error)?;
+        if let Some(session) = self.sessions.lock().unwrap().get(domain) {
+            // Safe because the session was established by a connection of the same context.
+            unsafe { config.set_session(session) }.map_err(ssl_error)?;
+        }
+        config.set_ex_data(self.domain, domain.to_string());
+        Ok(config
+            .connect_async(domain, conn)
+            .compat()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())))
+    }
+}
+
+impl<Item, SinkItem, F> rpc::Transport for Transport<SslStream<TcpStream>, Item, SinkItem, F>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    F: crate::Format,
+{
+    type Item = Item;
+    type SinkItem = SinkItem;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.ssl_stream().get_ref().peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.ssl_stream().get_ref().local_addr()
+    }
+}
+
+impl<Item, SinkItem, F> Transport<SslStream<TcpStream>, Item, SinkItem, F> {
+    /// Returns true if the handshake resumed a previous session.
+    pub fn session_reused(&self) -> bool {
+        self.ssl_stream().ssl().session_reused()
+    }
+
+    fn ssl_stream(&self) -> &openssl::ssl::SslStream<TcpStream> {
+        self.inner.get_ref().get_ref().get_ref()
+    }
+}
+
+/// Connects to `addr`, verifying that the server is `domain`, and offering the session
+/// `connector` last established with it, then wraps the connection in a bincode transport that
+/// decodes [responses](crate::Decodes::Responses).
+pub fn connect<Item, SinkItem>(
+    addr: &SocketAddr,
+    domain: &str,
+    connector: &ResumingConnector,
+) -> impl Future<Output = io::Result<Transport<SslStream<TcpStream>, Item, SinkItem>>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    Connector::new().tls_resuming(addr, domain, connector)
+}
+
+impl Connector {
+    /// Like [`resumption::connect`](self::connect), but connects with the options of this
+    /// connector.
+    pub fn tls_resuming<Item, SinkItem>(
+        &self,
+        addr: &SocketAddr,
+        domain: &str,
+        tls: &ResumingConnector,
+    ) -> impl Future<Output = io::Result<Transport<SslStream<TcpStream>, Item, SinkItem>>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let connector = self.clone();
+        let tls = tls.clone();
+        let addr = *addr;
+        let domain = domain.to_string();
+        self.within(addr, async move {
+            let conn = await!(connector.tcp(addr))?;
+            let handshake = tls.handshake(&domain, conn)?;
+            let conn = await!(connector.handshaking(handshake))?;
+            Ok(connector.transport(conn))
+        })
+    }
+}
+
+fn ssl_error(e: openssl::error::ErrorStack) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
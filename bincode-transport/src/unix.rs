@@ -0,0 +1,208 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A bincode transport over Unix domain sockets, for clients and servers on the same host, which
+//! authenticates the peer by the credentials the operating system reports for the socket.
+//!
+//! A server sees the [credentials](rpc::PeerCredentials) of each client's process — its user and
+//! group, and on Linux its process ID — as the
+//! [current credentials](rpc::server::identity::credentials) while handling the client's
+//! requests, and can close connections from processes it doesn't trust as soon as they're
+//! accepted, with [`Incoming::with_peer_filter`]. This gives local services authentication that
+//! needs no handshake or secrets.
+//!
+//! Unix sockets have no IP addresses, so the transports report `127.0.0.1:0` as both addresses,
+//! like the in-memory loopback, and a server's per-IP connection limit applies to all of its local
+//! clients together.
+
+use crate::{codec, Codec, Decodes, Format, Transport};
+use futures::{compat::*, prelude::*, ready};
+use pin_utils::unsafe_pinned;
+use rpc::PeerCredentials;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio_uds::{UnixListener, UnixStream};
+
+impl<Item, SinkItem, F> rpc::Transport for Transport<UnixStream, Item, SinkItem, F>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+    F: Format,
+{
+    type Item = Item;
+    type SinkItem = SinkItem;
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+    }
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        peer_credentials(self.inner.get_ref().get_ref()).ok()
+    }
+}
+
+/// Returns the credentials of the process on the other end of `stream`, as of when it connected.
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<PeerCredentials> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::{mem, os::unix::io::AsRawFd};
+
+        let mut ucred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // The kernel fills in at most `len` bytes of the `ucred` it points at.
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut ucred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PeerCredentials::new(
+            ucred.uid,
+            ucred.gid,
+            Some(ucred.pid as u32),
+        ))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let ucred = stream.peer_cred()?;
+        Ok(PeerCredentials::new(ucred.uid, ucred.gid, None))
+    }
+}
+
+/// Returns a [peer filter](Incoming::with_peer_filter) that only lets through processes running
+/// as the same user as this one, or as root.
+pub fn same_user() -> impl Fn(&PeerCredentials) -> bool + Send + Sync + 'static {
+    // Safe: geteuid can't fail, and touches no memory.
+    let uid = unsafe { libc::geteuid() };
+    move |credentials| credentials.uid == uid || credentials.uid == 0
+}
+
+/// Connects to the socket at `path`, wrapping the connection in a bincode transport that decodes
+/// [responses](Decodes::Responses).
+pub async fn connect<Item, SinkItem>(
+    path: &Path,
+) -> io::Result<Transport<UnixStream, Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let stream = await!(UnixStream::connect(path).compat())?;
+    let codec = Codec::default().decoding(Decodes::Responses);
+    Ok(Transport::with_codec(stream, codec))
+}
+
+/// Listens on a socket bound to `path`, wrapping accepted connections in bincode transports that
+/// decode [requests](Decodes::Requests). Fails if a file already exists at `path`; removing the
+/// socket once the server exits is up to the caller.
+pub fn listen<Item, SinkItem>(path: &Path) -> io::Result<Incoming<Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let listener = UnixListener::bind(path)?;
+    Ok(Incoming {
+        incoming: listener.incoming().compat(),
+        peer_filter: None,
+        max_frame_len: codec::DEFAULT_MAX_FRAME_LEN,
+        ghost: PhantomData,
+    })
+}
+
+/// A [`UnixListener`] that wraps connections in bincode transports.
+pub struct Incoming<Item, SinkItem> {
+    incoming: Compat01As03<tokio_uds::Incoming>,
+    peer_filter: Option<Arc<dyn Fn(&PeerCredentials) -> bool + Send + Sync>>,
+    max_frame_len: usize,
+    ghost: PhantomData<(Item, SinkItem)>,
+}
+
+impl<Item, SinkItem> fmt::Debug for Incoming<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Incoming")
+            .field("peer_filter", &self.peer_filter.is_some())
+            .field("max_frame_len", &self.max_frame_len)
+            .finish()
+    }
+}
+
+impl<Item, SinkItem> Incoming<Item, SinkItem> {
+    unsafe_pinned!(incoming: Compat01As03<tokio_uds::Incoming>);
+
+    /// Closes connections from processes whose credentials `peer_filter` doesn't allow as soon as
+    /// they're accepted, as well as connections whose peer's credentials can't be read. Allows
+    /// every process by default.
+    pub fn with_peer_filter<P>(mut self, peer_filter: P) -> Self
+    where
+        P: Fn(&PeerCredentials) -> bool + Send + Sync + 'static,
+    {
+        self.peer_filter = Some(Arc::new(peer_filter));
+        self
+    }
+
+    /// Sets the longest frame the transports of accepted connections read or write, beyond which
+    /// they fail with a [`FrameTooLong`](crate::FrameTooLong) error, closing the connection.
+    /// Defaults to [`DEFAULT_MAX_FRAME_LEN`](codec::DEFAULT_MAX_FRAME_LEN).
+    pub fn with_max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    /// Returns true if the peer filter, if any, allows the peer of `conn`.
+    fn is_peer_allowed(&self, conn: &UnixStream) -> bool {
+        let peer_filter = match &self.peer_filter {
+            Some(peer_filter) => peer_filter,
+            None => return true,
+        };
+        peer_credentials(conn)
+            .map(|credentials| peer_filter(&credentials))
+            .unwrap_or(false)
+    }
+}
+
+impl<Item, SinkItem> Stream for Incoming<Item, SinkItem>
+where
+    Item: for<'a> Deserialize<'a>,
+    SinkItem: Serialize,
+{
+    type Item = io::Result<Transport<UnixStream, Item, SinkItem>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let conn = match ready!(self.as_mut().incoming().poll_next(cx)?) {
+                Some(conn) => conn,
+                None => return Poll::Ready(None),
+            };
+            if !self.is_peer_allowed(&conn) {
+                continue;
+            }
+            let codec = Codec::new(self.max_frame_len).decoding(Decodes::Requests);
+            return Poll::Ready(Some(Ok(Transport::with_codec(conn, codec))));
+        }
+    }
+}
@@ -0,0 +1,68 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests that balanced clients spread calls over every server.
+
+#![feature(await_macro, async_await)]
+
+use futures::{
+    compat::{Executor01CompatExt, Future01CompatExt},
+    prelude::*,
+};
+use rpc::{
+    client::reconnect::{self, Balancing},
+    context,
+    server::{Handler, Server},
+    Client,
+};
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+
+fn spawn_server(name: &'static str) -> io::Result<std::net::SocketAddr> {
+    let listener = tarpc_bincode_transport::listen(&"0.0.0.0:0".parse().unwrap())?;
+    let addr = listener.local_addr();
+    let server = Server::<String, String>::default()
+        .incoming(listener)
+        .take(1)
+        .respond_with(move |_ctx, _request| future::ready(Ok(name.to_string())));
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+    Ok(addr)
+}
+
+async fn run() -> io::Result<()> {
+    let addrs = [spawn_server("a")?, spawn_server("b")?];
+    let mut client = tarpc_bincode_transport::connect_balanced::<String, String>(
+        reconnect::Config::default(),
+        Balancing::RoundRobin,
+        &addrs,
+    )?;
+    while client.states().iter().any(|state| match state {
+        reconnect::ConnectionState::Connected { .. } => false,
+        _ => true,
+    }) {
+        await!(Delay::new(Instant::now() + Duration::from_millis(1)).compat()).unwrap();
+    }
+
+    let mut responses = vec![];
+    for _ in 0..4 {
+        responses.push(await!(client.call(context::current(), "hi".into()))?);
+    }
+    responses.sort();
+    assert_eq!(responses, vec!["a", "a", "b", "b"]);
+    assert_eq!(client.in_flight(), vec![0, 0]);
+    Ok(())
+}
+
+#[test]
+fn round_robin_spreads_calls_over_servers() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(run().boxed().map_err(|e| panic!(e)).compat());
+}
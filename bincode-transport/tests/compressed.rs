@@ -0,0 +1,64 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests that compressed transports only compress with algorithms both ends have.
+
+#![feature(await_macro, async_await)]
+
+use futures::{
+    compat::{Executor01CompatExt, Future01CompatExt},
+    prelude::*,
+};
+use rpc::{
+    client, context,
+    server::{Handler, Server},
+};
+use std::io;
+use tarpc_bincode_transport::compressed::{self, Algorithm, Registry};
+
+/// Reverses bytes. Only the server has it.
+struct Reverse;
+
+impl Algorithm for Reverse {
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(data.iter().rev().cloned().collect())
+    }
+
+    fn decompress(&self, data: &[u8], _max_len: usize) -> io::Result<Vec<u8>> {
+        Ok(data.iter().rev().cloned().collect())
+    }
+}
+
+async fn run() -> io::Result<()> {
+    let registry = Registry::new()
+        .with_algorithm(1, Reverse)
+        .compressing_with(1)
+        .with_min_len(0);
+    let listener = compressed::listen(&"0.0.0.0:0".parse().unwrap(), registry)?;
+    let addr = listener.local_addr();
+    let server = Server::<String, String>::default()
+        .incoming(listener.take(1))
+        .respond_with(|_ctx, request: String| future::ready(Ok(request.repeat(100))));
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+
+    // The client doesn't have the server's algorithm, so the server doesn't compress with it.
+    let conn = await!(compressed::connect(&addr, Registry::new()))?;
+    let mut client = await!(client::new::<String, String, _>(
+        client::Config::default(),
+        conn
+    ))?;
+    let response = await!(client.call(context::current(), "hi".into()))?;
+    assert_eq!(response, "hi".repeat(100));
+    Ok(())
+}
+
+#[test]
+fn compresses_with_shared_algorithms() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(run().boxed().map_err(|e| panic!(e)).compat());
+}
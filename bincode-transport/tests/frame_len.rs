@@ -0,0 +1,52 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests that servers reject frames over their max frame length.
+
+#![feature(await_macro, async_await)]
+
+use futures::{
+    compat::{Executor01CompatExt, Future01CompatExt},
+    prelude::*,
+};
+use rpc::{
+    client, context,
+    server::{Handler, Server},
+};
+use std::io;
+
+async fn run() -> io::Result<()> {
+    let listener =
+        tarpc_bincode_transport::listen(&"0.0.0.0:0".parse().unwrap())?.with_max_frame_len(64);
+    let addr = listener.local_addr();
+    let server = Server::<String, String>::default()
+        .incoming(listener)
+        .take(1)
+        .respond_with(|_ctx, request| future::ready(Ok(request)));
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+
+    let conn = await!(tarpc_bincode_transport::connect(&addr))?;
+    let mut client = await!(client::new::<String, String, _>(
+        client::Config::default(),
+        conn
+    ))?;
+
+    let response = await!(client.call(context::current(), "hi".into()))?;
+    assert_eq!(response, "hi");
+
+    // The server closes the connection instead of reading the oversized request.
+    let response = await!(client.call(context::current(), "a".repeat(1024)));
+    assert!(response.is_err());
+    Ok(())
+}
+
+#[test]
+fn oversized_request_is_rejected() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(run().boxed().map_err(|e| panic!(e)).compat());
+}
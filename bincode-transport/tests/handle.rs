@@ -0,0 +1,47 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests that clients and servers can run on a reactor the application provides.
+
+#![feature(await_macro, async_await)]
+
+use futures::{compat::Executor01CompatExt, prelude::*};
+use rpc::{
+    client, context,
+    server::{Handler, Server},
+};
+use std::io;
+use tarpc_bincode_transport::Connector;
+use tokio_reactor::{Handle, Reactor};
+
+async fn run(handle: Handle) -> io::Result<()> {
+    let listener =
+        tarpc_bincode_transport::listen_with_handle(&"0.0.0.0:0".parse().unwrap(), &handle)?;
+    let addr = listener.local_addr();
+    let server = Server::<String, String>::default()
+        .incoming(listener)
+        .take(1)
+        .respond_with(|_ctx, request| future::ready(Ok(request)));
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+
+    let conn = await!(Connector::new().with_handle(handle.clone()).connect(&addr))?;
+    let mut client = await!(client::new::<String, String, _>(
+        client::Config::default(),
+        conn
+    ))?;
+    assert_eq!(await!(client.call(context::current(), "hi".into()))?, "hi");
+    Ok(())
+}
+
+#[test]
+fn clients_and_servers_run_on_the_given_reactor() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    let reactor = Reactor::new().unwrap().background().unwrap();
+    let handle = reactor.handle().clone();
+    tokio::run(run(handle).boxed().map_err(|e| panic!(e)).compat());
+}
@@ -0,0 +1,132 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests that servers authenticate clients during the connection handshake.
+
+#![feature(await_macro, async_await)]
+
+use futures::{
+    compat::{Executor01CompatExt, Future01CompatExt},
+    prelude::*,
+};
+use rpc::{
+    client::{self, reconnect, Client},
+    context,
+    server::{identity, Handler, Server},
+};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tarpc_bincode_transport::{
+    handshake::{self, Authenticator, Credentials, Sessions},
+    Connector,
+};
+
+struct Tokens;
+
+impl Authenticator for Tokens {
+    fn challenge(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn verify(&self, _: &[u8], credentials: &[u8]) -> io::Result<String> {
+        match credentials {
+            b"secret" => Ok("alice".into()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Unknown token.",
+            )),
+        }
+    }
+}
+
+struct Token(&'static [u8]);
+
+impl Credentials for Token {
+    fn respond(&self, _: &[u8]) -> Vec<u8> {
+        self.0.to_vec()
+    }
+}
+
+async fn run() -> io::Result<()> {
+    let listener = handshake::listen(&"0.0.0.0:0".parse().unwrap(), Tokens)?;
+    let addr = listener.local_addr();
+    let server = Server::<String, String>::default()
+        .incoming(listener.take(2))
+        .respond_with(|_ctx, request: String| {
+            let identity = identity::current().map(|identity| identity.to_string());
+            future::ready(Ok(format!("{} {}", request, identity.unwrap_or_default())))
+        });
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+
+    let e = await!(handshake::connect::<String, String, _>(
+        &addr,
+        Token(b"guess")
+    ))
+    .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+    let conn = await!(handshake::connect(&addr, Token(b"secret")))?;
+    let mut client = await!(client::new::<String, String, _>(
+        client::Config::default(),
+        conn
+    ))?;
+    let response = await!(client.call(context::current(), "hi".into()))?;
+    assert_eq!(response, "hi alice");
+    Ok(())
+}
+
+async fn resume() -> io::Result<()> {
+    let listener = handshake::listen(&"0.0.0.0:0".parse().unwrap(), Tokens)?
+        .with_sessions(Sessions::new(Duration::from_secs(60)));
+    let addr = listener.local_addr();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = connections.clone();
+    let server = Server::<String, String>::default()
+        .incoming(listener.take(2).inspect(move |_| {
+            accepted.fetch_add(1, Ordering::SeqCst);
+        }))
+        .respond_with(|_ctx, _request: String| {
+            let session = identity::session().map(|session| session.to_string());
+            future::ready(Ok(session.unwrap_or_default()))
+        });
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+
+    // Every call is made over a new connection.
+    let mut config = reconnect::Config::default();
+    config.max_lifetime = Some(Duration::from_secs(0));
+    let mut client = reconnect::resuming::<String, String, _, _, _>(config, move |token| {
+        Connector::new().handshake_resuming(&addr, Token(b"secret"), token)
+    })?;
+    let first = await!(client.call(context::current(), "hi".into()))?;
+    let second = await!(client.call(context::current(), "hi".into()))?;
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+    assert!(!first.is_empty());
+    assert_eq!(second, first);
+    assert_eq!(client.session_token(), Some(first));
+    Ok(())
+}
+
+#[test]
+fn server_authenticates_clients() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(run().boxed().map_err(|e| panic!(e)).compat());
+}
+
+#[test]
+fn reconnecting_clients_rejoin_their_sessions() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(resume().boxed().map_err(|e| panic!(e)).compat());
+}
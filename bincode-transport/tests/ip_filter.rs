@@ -0,0 +1,75 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests that listeners close connections from IPs that aren't allowed before handshaking.
+
+#![feature(await_macro, async_await)]
+
+use futures::{
+    compat::{Executor01CompatExt, Future01CompatExt},
+    prelude::*,
+};
+use rpc::{server::IpFilter, Transport};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tarpc_bincode_transport::handshake::{self, Authenticator, Credentials};
+
+/// Counts the challenges it sends, and accepts everyone.
+struct Counting(Arc<AtomicUsize>);
+
+impl Authenticator for Counting {
+    fn challenge(&self) -> Vec<u8> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        vec![]
+    }
+
+    fn verify(&self, _: &[u8], _: &[u8]) -> io::Result<String> {
+        Ok("anyone".into())
+    }
+}
+
+struct Empty;
+
+impl Credentials for Empty {
+    fn respond(&self, _: &[u8]) -> Vec<u8> {
+        vec![]
+    }
+}
+
+async fn run() -> io::Result<()> {
+    let challenges = Arc::new(AtomicUsize::new(0));
+    let denied = IpFilter::new(vec![], vec!["127.0.0.0/8".parse()?]);
+    let listener = handshake::listen(
+        &"127.0.0.1:0".parse().unwrap(),
+        Counting(challenges.clone()),
+    )?
+    .with_ip_filter(denied);
+    let addr = listener.local_addr();
+    let mut incoming = listener.map_ok(|transport| transport.peer_identity());
+
+    // The listener is polled alongside the client, and yields nothing.
+    let connect = handshake::connect::<String, String, _>(&addr, Empty).boxed();
+    let closed = match await!(future::select(incoming.next(), connect)) {
+        future::Either::Left((accepted, _)) => panic!("Accepted a denied IP: {:?}", accepted),
+        future::Either::Right((closed, _)) => closed,
+    };
+    assert!(closed.is_err());
+    assert_eq!(challenges.load(Ordering::SeqCst), 0);
+    Ok(())
+}
+
+#[test]
+fn denied_ips_are_closed_before_the_handshake() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(run().boxed().map_err(|e| panic!(e)).compat());
+}
@@ -0,0 +1,43 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests that served servers can be found, shut down, and waited on through their handles.
+
+#![feature(await_macro, async_await)]
+
+use futures::{compat::Executor01CompatExt, prelude::*};
+use rpc::{client, context, server::Server};
+use std::{io, time::Duration};
+
+async fn run() -> io::Result<()> {
+    let server = tarpc_bincode_transport::serve(
+        &"0.0.0.0:0".parse().unwrap(),
+        Server::<String, String>::default(),
+        |_ctx, request| future::ready(Ok(request)),
+    )?;
+    assert_ne!(server.local_addr().port(), 0);
+
+    let transport = await!(tarpc_bincode_transport::connect(&server.local_addr()))?;
+    let mut client = await!(client::new::<String, String, _>(
+        client::Config::default(),
+        transport
+    ))?;
+    assert_eq!(await!(client.call(context::current(), "hi".into()))?, "hi");
+
+    // Shutting down drains the client's connection, so the server can exit.
+    await!(server.shutdown(Duration::from_secs(10)));
+    await!(server);
+    assert!(await!(client.call(context::current(), "bye".into())).is_err());
+    Ok(())
+}
+
+#[test]
+fn served_servers_shut_down_through_their_handles() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(run().boxed().map_err(|e| panic!(e)).compat());
+}
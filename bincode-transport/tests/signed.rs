@@ -0,0 +1,47 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests that signed transports exchange nonces when connecting, and then carry calls.
+
+#![feature(await_macro, async_await)]
+
+use futures::{
+    compat::{Executor01CompatExt, Future01CompatExt},
+    prelude::*,
+};
+use rpc::{
+    client, context,
+    server::{Handler, Server},
+};
+use std::io;
+use tarpc_bincode_transport::signed::{self, Config};
+
+async fn run() -> io::Result<()> {
+    let config = Config::new(1, "secret");
+    let listener = signed::listen(&"0.0.0.0:0".parse().unwrap(), config.clone())?;
+    let addr = listener.local_addr();
+    let server = Server::<String, String>::default()
+        .incoming(listener.take(1))
+        .respond_with(|_ctx, request: String| future::ready(Ok(request.to_uppercase())));
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+
+    let conn = await!(signed::connect(&addr, config))?;
+    let mut client = await!(client::new::<String, String, _>(
+        client::Config::default(),
+        conn
+    ))?;
+    let response = await!(client.call(context::current(), "hi".into()))?;
+    assert_eq!(response, "HI");
+    Ok(())
+}
+
+#[test]
+fn signed_calls_round_trip() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(run().boxed().map_err(|e| panic!(e)).compat());
+}
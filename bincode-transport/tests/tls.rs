@@ -0,0 +1,172 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests clients and servers communicating over TLS.
+
+#![cfg(feature = "tls")]
+#![feature(await_macro, async_await)]
+
+use futures::{compat::Executor01CompatExt, prelude::*};
+use rpc::{
+    client, context,
+    server::{Handler, Server},
+};
+use std::{fs, io, net::SocketAddr, time::Duration};
+use tarpc_bincode_transport::tls::{self, native_tls, TlsAcceptor, TlsConnector};
+
+const IDENTITY: &[u8] = include_bytes!("tls/identity.p12");
+
+fn acceptor() -> TlsAcceptor {
+    let identity = native_tls::Identity::from_pkcs12(IDENTITY, "tarpc").unwrap();
+    TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap())
+}
+
+async fn connect(addr: SocketAddr) -> io::Result<client::Channel<String, String>> {
+    let certificate = native_tls::Certificate::from_pem(include_bytes!("tls/cert.pem")).unwrap();
+    let connector = native_tls::TlsConnector::builder()
+        .add_root_certificate(certificate)
+        .build()
+        .unwrap();
+    let conn = await!(tls::connect(
+        &addr,
+        "localhost",
+        TlsConnector::from(connector)
+    ))?;
+    await!(client::new(client::Config::default(), conn))
+}
+
+async fn run() -> io::Result<String> {
+    let listener = tls::listen(&"127.0.0.1:0".parse().unwrap(), acceptor())?;
+    let addr = listener.local_addr();
+    let server = Server::<String, String>::default()
+        .incoming(listener)
+        .take(1)
+        .respond_with(|_ctx, request| future::ready(Ok(request.to_uppercase())));
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+
+    let mut client = await!(connect(addr))?;
+    await!(client.call(context::current(), "hi".into()))
+}
+
+async fn reload() -> io::Result<Vec<String>> {
+    let path = std::env::temp_dir().join(format!("tarpc-identity-{}.p12", std::process::id()));
+    fs::write(&path, IDENTITY)?;
+    let listener = tls::listen(&"127.0.0.1:0".parse().unwrap(), acceptor())?;
+    let addr = listener.local_addr();
+    let reload = listener.reload_handle();
+    let mut reloads = reload
+        .watch(&path, "tarpc", Duration::from_millis(10))
+        .boxed();
+    let server = Server::<String, String>::default()
+        .incoming(listener)
+        .take(3)
+        .respond_with(|_ctx, request| future::ready(Ok(request.to_uppercase())));
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+
+    let mut before = await!(connect(addr))?;
+    let response = await!(before.call(context::current(), "before".into()))?;
+    let mut responses = vec![response];
+
+    reload.reload(acceptor());
+    let mut reloaded = await!(connect(addr))?;
+    let response = await!(reloaded.call(context::current(), "reloaded".into()))?;
+    responses.push(response);
+
+    // The file is written again, so that it's modified at a later time.
+    std::thread::sleep(Duration::from_millis(10));
+    fs::write(&path, IDENTITY)?;
+    await!(reloads.next()).unwrap()?;
+    let mut watched = await!(connect(addr))?;
+    let response = await!(watched.call(context::current(), "watched".into()))?;
+    responses.push(response);
+
+    // Connections from before the reloads are kept.
+    let response = await!(before.call(context::current(), "after".into()))?;
+    responses.push(response);
+    fs::remove_file(&path)?;
+    Ok(responses)
+}
+
+/// Returns whether each of two connections in turn resumed a session.
+#[cfg(feature = "tls-resumption")]
+async fn resume() -> io::Result<Vec<bool>> {
+    use tarpc_bincode_transport::tls::resumption::{
+        self,
+        openssl::{
+            ssl::{SslConnector, SslMethod},
+            x509::X509,
+        },
+        ResumingConnector,
+    };
+
+    let listener = tls::listen(&"127.0.0.1:0".parse().unwrap(), acceptor())?;
+    let addr = listener.local_addr();
+    let server = Server::<String, String>::default()
+        .incoming(listener)
+        .take(2)
+        .respond_with(|_ctx, request| future::ready(Ok(request.to_uppercase())));
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+
+    let certificate = X509::from_pem(include_bytes!("tls/cert.pem")).unwrap();
+    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+    builder.cert_store_mut().add_cert(certificate).unwrap();
+    let connector = ResumingConnector::new(builder)?;
+
+    let mut reused = vec![];
+    for _ in 0..2 {
+        let conn = await!(resumption::connect(&addr, "localhost", &connector))?;
+        reused.push(conn.session_reused());
+        let mut client = await!(client::new(client::Config::default(), conn))?;
+        // On TLS 1.3 the session arrives after the handshake, so it's only read with a response.
+        await!(client.call(context::current(), "hi".into()))?;
+    }
+    Ok(reused)
+}
+
+#[test]
+fn round_trip() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(
+        run()
+            .map_ok(|response| assert_eq!(response, "HI"))
+            .map_err(|e| panic!(e))
+            .boxed()
+            .compat(),
+    );
+}
+
+#[test]
+fn reloads_the_acceptor() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(
+        reload()
+            .map_ok(|responses| {
+                assert_eq!(responses, vec!["BEFORE", "RELOADED", "WATCHED", "AFTER"])
+            })
+            .map_err(|e| panic!(e))
+            .boxed()
+            .compat(),
+    );
+}
+
+#[cfg(feature = "tls-resumption")]
+#[test]
+fn reconnects_resume_the_session() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(
+        resume()
+            .map_ok(|reused| assert_eq!(reused, vec![false, true]))
+            .map_err(|e| panic!(e))
+            .boxed()
+            .compat(),
+    );
+}
@@ -0,0 +1,105 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tests clients and servers communicating over Unix sockets, and the peer credentials servers see.
+
+#![cfg(all(unix, feature = "unix"))]
+#![feature(await_macro, async_await)]
+
+use futures::{compat::Executor01CompatExt, prelude::*};
+use rpc::{
+    client, context,
+    server::{identity, Handler, Server},
+};
+use std::{
+    env, fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process,
+};
+use tarpc_bincode_transport::unix;
+
+/// Returns a path to bind a socket to that no other test uses.
+fn socket_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("tarpc-{}-{}.sock", name, process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+/// Spawns a server on `listener` that answers every request with the credentials of its client.
+fn serve(listener: unix::Incoming<rpc::ClientMessage<String>, rpc::ServerMessage<String>>) {
+    let server = Server::<String, String>::default()
+        .incoming(listener)
+        .take(1)
+        .respond_with(|_ctx, _request| {
+            future::lazy(|_| {
+                let credentials = identity::credentials().unwrap();
+                Ok(format!("{} {:?}", credentials.uid, credentials.pid))
+            })
+        });
+    tokio_executor::spawn(server.unit_error().boxed().compat());
+}
+
+async fn call(path: &Path) -> io::Result<String> {
+    let transport = await!(unix::connect(path))?;
+    let mut client = await!(client::new(client::Config::default(), transport))?;
+    await!(client.call(context::current(), "hi".into()))
+}
+
+async fn run() -> io::Result<(String, String)> {
+    let path = socket_path("credentials");
+    let listener = unix::listen(&path)?.with_peer_filter(unix::same_user());
+    // The socket is owned by the user this process runs as.
+    let uid = fs::metadata(&path)?.uid();
+    serve(listener);
+
+    let response = await!(call(&path));
+    fs::remove_file(&path)?;
+    let expected = if cfg!(target_os = "linux") {
+        format!("{} {:?}", uid, Some(process::id()))
+    } else {
+        format!("{} None", uid)
+    };
+    Ok((response?, expected))
+}
+
+async fn denied() -> io::Result<io::Result<String>> {
+    let path = socket_path("denied");
+    let listener = unix::listen(&path)?.with_peer_filter(|_: &rpc::PeerCredentials| false);
+    serve(listener);
+
+    let response = await!(call(&path));
+    fs::remove_file(&path)?;
+    Ok(response)
+}
+
+#[test]
+fn servers_see_the_peer_credentials() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(
+        run()
+            .map_ok(|(response, expected)| assert_eq!(response, expected))
+            .map_err(|e| panic!(e))
+            .boxed()
+            .compat(),
+    );
+}
+
+#[test]
+fn filtered_peers_are_closed_when_accepted() {
+    let _ = env_logger::try_init();
+    rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+    tokio::run(
+        denied()
+            .map_ok(|response| assert!(response.is_err(), "{:?}", response))
+            .map_err(|e| panic!(e))
+            .boxed()
+            .compat(),
+    );
+}
@@ -14,9 +14,9 @@ use proc_macro::TokenStream;
 
 use itertools::Itertools;
 use proc_macro2::Span;
-use quote::ToTokens;
+use quote::{quote, ToTokens};
 use std::str::FromStr;
-use syn::{parse, Ident, TraitItemType, TypePath};
+use syn::{parse, parse_quote, Attribute, Fields, Ident, ItemStruct, TraitItemType, TypePath};
 
 #[proc_macro]
 pub fn snake_to_camel(input: TokenStream) -> TokenStream {
@@ -24,34 +24,143 @@ pub fn snake_to_camel(input: TokenStream) -> TokenStream {
     let mut assoc_type = parse::<TraitItemType>(input)
         .unwrap_or_else(|_| panic!("Could not parse trait item from:\n{}", i));
 
-    let old_ident = convert(&mut assoc_type.ident);
+    let old_ident = convert(&mut assoc_type.ident, "Fut");
+    fill_docs(&mut assoc_type.attrs, &old_ident);
 
-    for mut attr in &mut assoc_type.attrs {
-        if let Some(pair) = attr.path.segments.first() {
-            if pair.value().ident == "doc" {
-                attr.tts = proc_macro2::TokenStream::from_str(
-                    &attr.tts.to_string().replace("{}", &old_ident),
-                )
-                .unwrap();
+    assoc_type.into_token_stream().into()
+}
+
+#[proc_macro]
+pub fn ty_snake_to_camel(input: TokenStream) -> TokenStream {
+    let mut path = parse::<TypePath>(input).unwrap();
+
+    // Only capitalize the final segment
+    convert(
+        &mut path.path.segments.last_mut().unwrap().into_value().ident,
+        "Fut",
+    );
+
+    path.into_token_stream().into()
+}
+
+/// Defines the request struct of an rpc, from a struct named after the rpc with a field per
+/// argument, marked with the argument's attributes. The struct is renamed to CamelCase with a
+/// `Request` suffix, e.g. `SearchRequest` for `search`. Its `new` fn takes the fields not marked
+/// `#[default]`, which start out with their `Default` values instead, and have a `with_` setter
+/// each. Fields marked `#[sensitive]` are redacted from its `Debug` output.
+#[proc_macro]
+pub fn request_struct(input: TokenStream) -> TokenStream {
+    let i = input.clone();
+    let mut item = parse::<ItemStruct>(input)
+        .unwrap_or_else(|_| panic!("Could not parse struct from:\n{}", i));
+
+    let old_ident = convert(&mut item.ident, "Request");
+    fill_docs(&mut item.attrs, &old_ident);
+    let ident = &item.ident;
+
+    let mut args = vec![];
+    let mut initializers = vec![];
+    let mut setters = vec![];
+    let mut debug_fields = vec![];
+    match &mut item.fields {
+        Fields::Named(fields) => {
+            for field in fields.named.iter_mut() {
+                let name = field.ident.clone().unwrap();
+                let ty = field.ty.clone();
+                if has_attr(&field.attrs, "default") {
+                    let setter = Ident::new(&format!("with_{}", name), Span::call_site());
+                    let doc = format!("Sets the `{}` argument.", name);
+                    initializers.push(quote!(#name: ::std::default::Default::default()));
+                    setters.push(quote! {
+                        #[doc = #doc]
+                        pub fn #setter(mut self, #name: #ty) -> Self {
+                            self.#name = #name;
+                            self
+                        }
+                    });
+                } else {
+                    args.push(quote!(#name: #ty));
+                    initializers.push(quote!(#name));
+                }
+                let label = name.to_string();
+                debug_fields.push(if has_attr(&field.attrs, "sensitive") {
+                    quote!(.field(#label, &format_args!("<redacted>")))
+                } else {
+                    quote!(.field(#label, &self.#name))
+                });
+                let doc = format!("The `{}` argument.", name);
+                field.attrs = vec![parse_quote!(#[doc = #doc])];
             }
         }
+        _ => panic!("Expected a struct with named fields, got:\n{}", i),
     }
 
-    assoc_type.into_token_stream().into()
+    let new_doc = format!(
+        "Returns the request of a call to `{}` with the given arguments, and the default values \
+         of the arguments marked `#[default]`.",
+        old_ident
+    );
+    let label = ident.to_string();
+    let expanded = quote! {
+        #item
+
+        impl #ident {
+            #[doc = #new_doc]
+            pub fn new(#(#args),*) -> Self {
+                #ident { #(#initializers),* }
+            }
+
+            #(#setters)*
+        }
+
+        impl ::std::fmt::Debug for #ident {
+            fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                fmt.debug_struct(#label) #(#debug_fields)* .finish()
+            }
+        }
+    };
+    expanded.into()
 }
 
+/// Names the request struct of an rpc, which [`request_struct!`] defines.
 #[proc_macro]
-pub fn ty_snake_to_camel(input: TokenStream) -> TokenStream {
+pub fn ty_snake_to_request(input: TokenStream) -> TokenStream {
     let mut path = parse::<TypePath>(input).unwrap();
 
-    // Only capitalize the final segment
-    convert(&mut path.path.segments.last_mut().unwrap().into_value().ident);
+    // Only convert the final segment
+    convert(
+        &mut path.path.segments.last_mut().unwrap().into_value().ident,
+        "Request",
+    );
 
     path.into_token_stream().into()
 }
 
-/// Converts an ident in-place to CamelCase and returns the previous ident.
-fn convert(ident: &mut Ident) -> String {
+/// Replaces `{}` in doc attributes with the original ident.
+fn fill_docs(attrs: &mut [Attribute], old_ident: &str) {
+    for attr in attrs {
+        if is_named(attr, "doc") {
+            attr.tts =
+                proc_macro2::TokenStream::from_str(&attr.tts.to_string().replace("{}", old_ident))
+                    .unwrap();
+        }
+    }
+}
+
+/// Returns true if any of `attrs` is named `name`.
+fn has_attr(attrs: &[Attribute], name: &str) -> bool {
+    attrs.iter().any(|attr| is_named(attr, name))
+}
+
+fn is_named(attr: &Attribute, name: &str) -> bool {
+    match attr.path.segments.first() {
+        Some(pair) => pair.value().ident == name,
+        None => false,
+    }
+}
+
+/// Converts an ident in-place to CamelCase, followed by `suffix`, and returns the previous ident.
+fn convert(ident: &mut Ident, suffix: &str) -> String {
     let ident_str = ident.to_string();
     let mut camel_ty = String::new();
 
@@ -82,8 +191,7 @@ fn convert(ident: &mut Ident) -> String {
         }
     }
 
-    // The Fut suffix is hardcoded right now; this macro isn't really meant to be general-purpose.
-    camel_ty.push_str("Fut");
+    camel_ty.push_str(suffix);
 
     *ident = Ident::new(&camel_ty, Span::call_site());
     ident_str
@@ -0,0 +1,406 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Caches responses for as long as the server says they stay fresh.
+//!
+//! A [`Cached`] client answers a request from its [`Cache`] while the server's response to the
+//! same request is fresh, per the response's [caching](Caching), without sending the request.
+//! Responses without caching, or without a [`max_age`](Caching::max_age), aren't cached. Once a
+//! cached response with a [`version`](Caching::version) goes stale, the request is sent again with
+//! the version as its [cached version](crate::server::caching::cached_version), and if the server
+//! answers [`NotModified`](ErrorCode::NotModified), the cached response is reused, fresh again for
+//! the answer's max age.
+//!
+//! Only send requests through a cache whose responses depend on nothing but the request, since
+//! the cache uses the request as the key.
+
+use crate::{
+    client::{delivery::SendsOnce, Channel, Client},
+    context,
+    metadata::Metadata,
+    server::caching::CACHED_VERSION,
+    Caching, ErrorCode,
+};
+use futures::{future, prelude::*};
+use log::trace;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    hash::Hash,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Up to a number of cached responses, the least recently used of which are evicted to make room
+/// for others. Clones share the same responses, so a cache can be shared by several clients.
+pub struct Cache<Req, Resp> {
+    entries: Arc<Mutex<Entries<Req, Resp>>>,
+}
+
+struct Entries<Req, Resp> {
+    capacity: usize,
+    responses: HashMap<Req, Entry<Resp>>,
+    /// The requests of the responses, by when they were last used.
+    used: BTreeMap<u64, Req>,
+    clock: u64,
+}
+
+struct Entry<Resp> {
+    response: Resp,
+    expires: Instant,
+    version: Option<String>,
+    used: u64,
+}
+
+/// What a cache holds for a request.
+enum Lookup<Resp> {
+    Fresh(Resp),
+    /// A stale response with a version, to revalidate.
+    Stale(Resp, String),
+    Missing,
+}
+
+impl<Req, Resp> Clone for Cache<Req, Resp> {
+    fn clone(&self) -> Self {
+        Cache {
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Cache<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entries = self.entries.lock().unwrap();
+        f.debug_struct("Cache")
+            .field("capacity", &entries.capacity)
+            .field("len", &entries.responses.len())
+            .finish()
+    }
+}
+
+impl<Req: Clone + Eq + Hash, Resp: Clone> Cache<Req, Resp> {
+    /// Returns a cache that holds up to `capacity` responses.
+    pub fn new(capacity: usize) -> Self {
+        Cache {
+            entries: Arc::new(Mutex::new(Entries {
+                capacity,
+                responses: HashMap::new(),
+                used: BTreeMap::new(),
+                clock: 0,
+            })),
+        }
+    }
+
+    /// Returns the number of responses cached, including stale ones.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().responses.len()
+    }
+
+    /// Returns true if no responses are cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets the response to `request`, e.g. after a call that changes it, so that the next
+    /// call sends the request.
+    pub fn invalidate(&self, request: &Req) {
+        self.entries.lock().unwrap().remove(request);
+    }
+
+    /// Forgets every response.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.responses.clear();
+        entries.used.clear();
+    }
+
+    fn lookup(&self, request: &Req) -> Lookup<Resp> {
+        let mut entries = self.entries.lock().unwrap();
+        let lookup = match entries.responses.get(request) {
+            Some(entry) if entry.expires > Instant::now() => Lookup::Fresh(entry.response.clone()),
+            Some(Entry {
+                response,
+                version: Some(version),
+                ..
+            }) => Lookup::Stale(response.clone(), version.clone()),
+            Some(_) => {
+                entries.remove(request);
+                return Lookup::Missing;
+            }
+            None => return Lookup::Missing,
+        };
+        entries.touch(request);
+        lookup
+    }
+
+    /// Caches `response` to `request` per `caching`, or forgets any cached response to `request`
+    /// if it may not be cached.
+    fn insert(&self, request: Req, response: Resp, caching: Option<Caching>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&request);
+        let caching = caching.unwrap_or_default();
+        let max_age = match caching.max_age {
+            Some(max_age) if max_age > Duration::from_secs(0) => max_age,
+            _ => return,
+        };
+        if entries.capacity == 0 {
+            return;
+        }
+        while entries.responses.len() >= entries.capacity {
+            let oldest = match entries.used.keys().next() {
+                Some(&oldest) => oldest,
+                None => break,
+            };
+            let oldest = entries.used.remove(&oldest).unwrap();
+            entries.responses.remove(&oldest);
+        }
+        let used = entries.clock;
+        entries.clock += 1;
+        entries.used.insert(used, request.clone());
+        entries.responses.insert(
+            request,
+            Entry {
+                response,
+                expires: Instant::now() + max_age,
+                version: caching.version,
+                used,
+            },
+        );
+    }
+}
+
+impl<Req: Clone + Eq + Hash, Resp> Entries<Req, Resp> {
+    fn touch(&mut self, request: &Req) {
+        let clock = self.clock;
+        self.clock += 1;
+        if let Some(entry) = self.responses.get_mut(request) {
+            self.used.remove(&entry.used);
+            entry.used = clock;
+            self.used.insert(clock, request.clone());
+        }
+    }
+
+    fn remove(&mut self, request: &Req) {
+        if let Some(entry) = self.responses.remove(request) {
+            self.used.remove(&entry.used);
+        }
+    }
+}
+
+/// A [`Client`] that answers requests from a [`Cache`] while their responses are fresh.
+pub struct Cached<Req, Resp> {
+    inner: Channel<Req, Resp>,
+    cache: Cache<Req, Resp>,
+}
+
+impl<Req, Resp> Cached<Req, Resp> {
+    /// Returns a client that sends requests over `channel`, unless their responses are fresh in
+    /// `cache`.
+    pub fn new(channel: Channel<Req, Resp>, cache: Cache<Req, Resp>) -> Self {
+        Cached {
+            inner: channel,
+            cache,
+        }
+    }
+
+    /// Returns the cache of the client's responses.
+    pub fn cache(&self) -> &Cache<Req, Resp> {
+        &self.cache
+    }
+}
+
+impl<Req, Resp> Clone for Cached<Req, Resp> {
+    fn clone(&self) -> Self {
+        Cached {
+            inner: self.inner.clone(),
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Cached<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cached")
+            .field("inner", &self.inner)
+            .field("cache", &self.cache)
+            .finish()
+    }
+}
+
+// A cached call isn't sent at all, and an uncached one is sent once.
+impl<Req, Resp> SendsOnce for Cached<Req, Resp> {}
+
+impl<'a, Req, Resp> Client<'a, Req> for Cached<Req, Resp>
+where
+    Req: Clone + Eq + Hash + Send + 'static,
+    Resp: Clone + Send + 'static,
+{
+    type Response = Resp;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + 'a>>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        let mut metadata = Metadata::new();
+        let (stale, version) = match self.cache.lookup(&request) {
+            Lookup::Fresh(response) => {
+                trace!("[{}] Answering from the cache.", ctx.trace_id());
+                return future::ready(Ok(response)).boxed();
+            }
+            Lookup::Stale(response, version) => {
+                metadata.insert(CACHED_VERSION.to_string(), version.clone());
+                (Some(response), Some(version))
+            }
+            Lookup::Missing => (None, None),
+        };
+        let cache = self.cache.clone();
+        let key = request.clone();
+        let call = self.inner.call_with_caching(ctx, metadata, request);
+        async move {
+            let (response, caching) = await!(call);
+            let e = match response {
+                Ok(response) => {
+                    cache.insert(key, response.clone(), caching);
+                    return Ok(response);
+                }
+                Err(e) => e,
+            };
+            match (stale, ErrorCode::of(&e)) {
+                (Some(stale), ErrorCode::NotModified) => {
+                    trace!("[{}] Cached response is still current.", ctx.trace_id());
+                    let caching = caching.map(|mut caching| {
+                        if caching.version.is_none() {
+                            caching.version = version;
+                        }
+                        caching
+                    });
+                    cache.insert(key, stale.clone(), caching);
+                    Ok(stale)
+                }
+                _ => Err(e),
+            }
+        }
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, Cached};
+    use crate::{
+        client::{self, Client},
+        context,
+        server::caching,
+        test_util, Caching, Server,
+    };
+    use futures::{compat::Future01CompatExt, future};
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+    use tokio_timer::Delay;
+
+    fn fresh_for(max_age: Duration, version: Option<&str>) -> Caching {
+        Caching {
+            max_age: Some(max_age),
+            version: version.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn fresh_responses_are_answered_from_the_cache() {
+        test_util::init();
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let (client_channel, server) = test_util::serve(Server::<String, String>::default(), {
+            let handled = handled.clone();
+            move |_ctx, request: String| {
+                handled.fetch_add(1, Ordering::SeqCst);
+                if request == "cached" {
+                    caching::set(fresh_for(Duration::from_secs(60), None));
+                }
+                future::ready(Ok::<_, io::Error>(request))
+            }
+        });
+
+        let responses = async {
+            let channel = await!(client::new(client::Config::default(), client_channel))?;
+            let mut client = Cached::new(channel, Cache::new(10));
+            let mut responses = vec![];
+            for request in &["cached", "cached", "uncached", "uncached"] {
+                responses.push(await!(
+                    client.call(context::current(), request.to_string())
+                )?);
+            }
+            io::Result::Ok((responses, client.cache().len()))
+        };
+
+        let (responses, cached) = test_util::run_future(future::join(server, responses))
+            .1
+            .unwrap();
+        assert_eq!(responses, vec!["cached", "cached", "uncached", "uncached"]);
+        assert_eq!(handled.load(Ordering::SeqCst), 3);
+        assert_eq!(cached, 1);
+    }
+
+    #[test]
+    fn stale_responses_are_revalidated_by_version() {
+        test_util::init();
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let (client_channel, server) = test_util::serve(Server::<String, String>::default(), {
+            let handled = handled.clone();
+            move |_ctx, request: String| {
+                let response = match handled.fetch_add(1, Ordering::SeqCst) {
+                    0 => Ok(format!("{} v1", request)),
+                    _ => {
+                        assert_eq!(caching::cached_version(), Some("v1".into()));
+                        Err(caching::not_modified())
+                    }
+                };
+                caching::set(fresh_for(Duration::from_millis(50), Some("v1")));
+                future::ready(response)
+            }
+        });
+
+        let responses = async {
+            let channel = await!(client::new(client::Config::default(), client_channel))?;
+            let mut client = Cached::new(channel, Cache::new(10));
+            let first = await!(client.call(context::current(), "hi".into()))?;
+            await!(Delay::new(Instant::now() + Duration::from_millis(100)).compat()).unwrap();
+            let revalidated = await!(client.call(context::current(), "hi".into()))?;
+            let refreshed = await!(client.call(context::current(), "hi".into()))?;
+            io::Result::Ok(vec![first, revalidated, refreshed])
+        };
+
+        let responses = test_util::run_future(future::join(server, responses))
+            .1
+            .unwrap();
+        assert_eq!(responses, vec!["hi v1", "hi v1", "hi v1"]);
+        // The stale response was revalidated once, then fresh again.
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn least_recently_used_responses_are_evicted() {
+        let cache = Cache::new(2);
+        let caching = || Some(fresh_for(Duration::from_secs(60), None));
+        cache.insert(1, "a", caching());
+        cache.insert(2, "b", caching());
+        cache.lookup(&1);
+        cache.insert(3, "c", caching());
+        assert_eq!(cache.len(), 2);
+        assert!(cache.entries.lock().unwrap().responses.get(&2).is_none());
+
+        // Responses that mustn't be cached replace the cached ones.
+        cache.insert(1, "a2", None);
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Causality tokens, the plumbing for applications that implement read-your-writes across
+//! replicas.
+//!
+//! A call's [causality token](crate::context::Context::causality) is sent with its request. The
+//! server echoes it in the response, and makes it part of the request's context, so that calls
+//! its handler makes with [`context::current`](crate::context::current) carry it on. What a token
+//! means is up to the application; e.g. a replica may hold a read back until it has applied the
+//! write with the token the read carries.
+//!
+//! A channel given a [`Clock`] with [`with_clock`](crate::client::Channel::with_clock) gives each
+//! call that has no token the clock's next tick, and acknowledges the tokens servers echo, so that
+//! the application can tell which of its writes a server has seen.
+
+use std::sync::{Arc, Mutex};
+
+/// A logical clock that counts a client's calls. Clones share the same clock, so one clock can
+/// be given to the channels to every replica of a service.
+#[derive(Clone, Debug, Default)]
+pub struct Clock {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The latest token ticked or acknowledged.
+    latest: u64,
+    /// The highest token a server has echoed.
+    acknowledged: Option<u64>,
+}
+
+impl Clock {
+    /// Returns a clock that hasn't ticked yet.
+    pub fn new() -> Self {
+        Clock::default()
+    }
+
+    /// Returns the next token, which is greater than every token the clock has ticked or
+    /// acknowledged so far.
+    pub fn tick(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.latest += 1;
+        state.latest
+    }
+
+    /// Records that a server echoed `token`. Later ticks are greater than `token`, even if it
+    /// came from another clock, e.g. the clock of another client of the same replicas.
+    pub fn acknowledge(&self, token: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.latest = state.latest.max(token);
+        state.acknowledged = Some(state.acknowledged.map_or(token, |acked| acked.max(token)));
+    }
+
+    /// Returns the highest token a server has echoed, if any has.
+    pub fn acknowledged(&self) -> Option<u64> {
+        self.state.lock().unwrap().acknowledged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Clock;
+    use crate::{client, context, test_util, Server};
+    use futures::{future, prelude::*};
+    use std::io;
+
+    #[test]
+    fn ticks_past_acknowledged_tokens() {
+        let clock = Clock::new();
+        assert_eq!((clock.tick(), clock.tick()), (1, 2));
+        assert_eq!(clock.acknowledged(), None);
+
+        clock.acknowledge(1);
+        assert_eq!(clock.acknowledged(), Some(1));
+        // Tokens echoed out of order don't move the clock back.
+        clock.acknowledge(10);
+        clock.acknowledge(2);
+        assert_eq!(clock.acknowledged(), Some(10));
+        assert_eq!(clock.tick(), 11);
+    }
+
+    #[test]
+    fn servers_echo_causality_tokens() {
+        test_util::init();
+
+        let (client_channel, server) =
+            test_util::serve(Server::<String, Option<u64>>::default(), |ctx, _request| {
+                future::ready(Ok(ctx.causality))
+            });
+        let clock = Clock::new();
+
+        let responses = {
+            let clock = clock.clone();
+            async move {
+                let mut client = await!(client::new(client::Config::default(), client_channel))?
+                    .with_clock(clock);
+                // Calls without a token are given the clock's next tick.
+                let ticked = await!(client.call(context::current(), "write".into()))?;
+                let ctx = context::Context {
+                    causality: Some(7),
+                    ..context::current()
+                };
+                let given = await!(client.call(ctx, "read".into()))?;
+                Ok::<_, io::Error>((ticked, given))
+            }
+        };
+
+        let tokens = test_util::run_future(future::join(
+            server,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .1;
+        assert_eq!(tokens, (Some(1), Some(7)));
+        assert_eq!(clock.acknowledged(), Some(7));
+        assert_eq!(clock.tick(), 8);
+    }
+}
@@ -5,13 +5,23 @@
 // https://opensource.org/licenses/MIT.
 
 use crate::{
+    client::causality::Clock,
     context,
-    util::{deadline_compat, AsDuration, Compact},
-    ClientMessage, ClientMessageKind, PollIo, Request, Response, Transport,
+    metadata::Metadata,
+    metrics::{Metrics, Recorder},
+    tracing::{self, Kind, Trace, Tracer},
+    util::{
+        concurrency::{AdaptiveLimit, INITIAL_LIMIT},
+        deadline_compat, AsDuration, Compact,
+    },
+    Caching, ClientMessage, ClientMessageKind, CloseReason, ConnectionClosed, PollIo, Request,
+    Response, ServerError, ServerMessage, Transport, UndecodableResponse,
 };
 use fnv::FnvHashMap;
 use futures::{
     channel::{mpsc, oneshot},
+    compat::*,
+    future,
     prelude::*,
     ready,
     stream::Fuse,
@@ -19,20 +29,21 @@ use futures::{
     Poll,
 };
 use humantime::format_rfc3339;
-use log::{debug, error, info, trace};
-use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use log::{debug, error, info, trace, warn};
+use pin_utils::{pin_mut, unsafe_pinned, unsafe_unpinned};
 use std::{
-    io,
+    fmt, io,
     marker::{self, Unpin},
     net::SocketAddr,
     pin::Pin,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
-    time::Instant,
+    time::{Duration, Instant, SystemTime},
 };
-use trace::SpanId;
+use tokio_timer::Delay;
+use trace::{ConnectionId, TraceId};
 
 use super::Config;
 
@@ -40,20 +51,147 @@ use super::Config;
 #[derive(Debug)]
 pub struct Channel<Req, Resp> {
     to_dispatch: mpsc::Sender<DispatchRequest<Req, Resp>>,
+    /// Creates the request queues of clones of this channel.
+    queues: RequestQueues<Req, Resp>,
     /// Channel to send a cancel message to the dispatcher.
     cancellation: RequestCancellation,
     /// The ID to use for the next request to stage.
     next_request_id: Arc<AtomicU64>,
     server_addr: SocketAddr,
+    /// Identifies the connection in logs. Shared by all clones.
+    connection_id: ConnectionId,
+    /// Calls made through this channel and its clones that haven't completed.
+    in_flight_calls: InFlightCalls,
+    /// Names the method of each call, for `in_flight_calls`.
+    call_names: Option<CallNames<Req>>,
+    /// The longest a call made through this channel can take before it times out.
+    timeout: Option<Duration>,
+    /// Exports the span of each call, if set.
+    tracer: Option<Tracer>,
+    /// Reports each call, if set.
+    metrics: Option<Metrics>,
+    /// Gives calls causality tokens, if set.
+    clock: Option<Clock>,
+    /// Whether the server is draining the connection, and why it closed it, once it says so.
+    server_close: ServerClose,
+    /// The number of items each streamed response buffers until they're read.
+    stream_item_buffer: usize,
+}
+
+/// Why the server closed the connection, once it said so in a [close](ServerMessage::Close)
+/// message, and whether it [drains](ServerMessage::Drain) the connection. Shared by a channel, its
+/// clones, and its request dispatch, so that calls failed by the connection closing can say why.
+#[derive(Clone, Debug, Default)]
+struct ServerClose(Arc<Mutex<Closing>>);
+
+#[derive(Debug, Default)]
+struct Closing {
+    draining: bool,
+    reason: Option<CloseReason>,
+}
+
+impl ServerClose {
+    fn set(&self, reason: CloseReason) {
+        self.0.lock().unwrap().reason = Some(reason);
+    }
+
+    fn drain(&self) {
+        self.0.lock().unwrap().draining = true;
+    }
+
+    fn reason(&self) -> Option<CloseReason> {
+        self.0.lock().unwrap().reason
+    }
+
+    /// Returns true once the server said it's draining or closing the connection.
+    fn is_draining(&self) -> bool {
+        let closing = self.0.lock().unwrap();
+        closing.draining || closing.reason.is_some()
+    }
+
+    /// Returns the error of a call that failed because the connection closed: a
+    /// [`ConnectionClosed`] error if the server said why, or else a plain connection reset.
+    fn connection_reset(&self) -> io::Error {
+        match self.reason() {
+            Some(reason) => ConnectionClosed::new(reason).into(),
+            None => connection_reset(),
+        }
+    }
+}
+
+/// A call that a [`Channel`] hasn't received the response to yet.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct InFlightCall {
+    /// The ID of the request.
+    pub request_id: u64,
+    /// The trace ID of the request, which identifies it in logs.
+    pub trace_id: TraceId,
+    /// The name of the method called, if the channel was given
+    /// [call names](Channel::with_call_names).
+    pub method: Option<&'static str>,
+    /// When the call was made.
+    pub started: Instant,
+    /// When the call times out.
+    pub deadline: SystemTime,
+}
+
+impl InFlightCall {
+    /// Returns how long ago the call was made.
+    pub fn age(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+type InFlightCalls = Arc<Mutex<FnvHashMap<u64, InFlightCall>>>;
+
+struct CallNames<Req>(fn(&Req) -> &'static str);
+
+impl<Req> Clone for CallNames<Req> {
+    fn clone(&self) -> Self {
+        CallNames(self.0)
+    }
+}
+
+impl<Req> fmt::Debug for CallNames<Req> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CallNames")
+    }
 }
 
 impl<Req, Resp> Clone for Channel<Req, Resp> {
     fn clone(&self) -> Self {
+        // Each clone gets its own queue, so that one clone can't fill the queue and starve the
+        // others.
+        self.with_queue(self.queues.new_queue())
+    }
+}
+
+impl<Req, Resp> Channel<Req, Resp> {
+    /// Returns a copy of the channel that shares its request queue, for wrappers that hand a copy
+    /// to each call they make. Unlike a clone, the copy doesn't register a queue of its own with
+    /// the dispatch task, so the requests buffered for the wrapper stay bounded by the one queue,
+    /// plus one request per copy waiting to be queued.
+    pub(crate) fn share(&self) -> Self {
+        self.with_queue(self.to_dispatch.clone())
+    }
+
+    fn with_queue(&self, to_dispatch: mpsc::Sender<DispatchRequest<Req, Resp>>) -> Self {
         Self {
-            to_dispatch: self.to_dispatch.clone(),
+            to_dispatch,
+            queues: self.queues.clone(),
             cancellation: self.cancellation.clone(),
             next_request_id: self.next_request_id.clone(),
             server_addr: self.server_addr,
+            connection_id: self.connection_id,
+            in_flight_calls: self.in_flight_calls.clone(),
+            call_names: self.call_names.clone(),
+            timeout: self.timeout,
+            tracer: self.tracer.clone(),
+            metrics: self.metrics.clone(),
+            clock: self.clock.clone(),
+            server_close: self.server_close.clone(),
+            stream_item_buffer: self.stream_item_buffer,
         }
     }
 }
@@ -111,14 +249,336 @@ impl<'a, Req, Resp> Future for Call<'a, Req, Resp> {
     }
 }
 
+/// A future returned by [`Channel::call_with_caching`] that resolves to a server response and its
+/// caching.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct CachingCall<'a, Req, Resp> {
+    fut: AndThenIdent<Send<'a, Req, Resp>, DispatchResponse<Resp>>,
+    caching: Arc<Mutex<Option<Caching>>>,
+}
+
+impl<'a, Req, Resp> CachingCall<'a, Req, Resp> {
+    unsafe_pinned!(fut: AndThenIdent<Send<'a, Req, Resp>, DispatchResponse<Resp>>);
+}
+
+impl<'a, Req, Resp> Future for CachingCall<'a, Req, Resp> {
+    type Output = (io::Result<Resp>, Option<Caching>);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let response = ready!(self.as_mut().fut().poll(cx));
+        Poll::Ready((response, self.caching.lock().unwrap().take()))
+    }
+}
+
+/// A future returned by [`Channel::notify`] that resolves once the notification is handed to
+/// request dispatch.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Notification<'a, Req, Resp> {
+    fut: SendMapErrConnectionReset<'a, Req, Resp>,
+}
+
+impl<'a, Req, Resp> Notification<'a, Req, Resp> {
+    unsafe_pinned!(fut: SendMapErrConnectionReset<'a, Req, Resp>);
+}
+
+impl<'a, Req, Resp> Future for Notification<'a, Req, Resp> {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.as_mut().fut().poll(cx)
+    }
+}
+
+/// A future returned by [`Channel::stream`] that resolves to a [`ResponseStream`] once the
+/// request is handed to request dispatch.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct StreamCall<'a, Req, Resp> {
+    fut: Send<'a, Req, Resp>,
+    items: Option<mpsc::Receiver<Resp>>,
+}
+
+impl<'a, Req, Resp> StreamCall<'a, Req, Resp> {
+    unsafe_pinned!(fut: Send<'a, Req, Resp>);
+    unsafe_unpinned!(items: Option<mpsc::Receiver<Resp>>);
+}
+
+impl<'a, Req, Resp> Future for StreamCall<'a, Req, Resp> {
+    type Output = io::Result<ResponseStream<Resp>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let response = ready!(self.as_mut().fut().poll(cx))?;
+        let items = self
+            .as_mut()
+            .items()
+            .take()
+            .expect("polled after completion");
+        Poll::Ready(Ok(ResponseStream {
+            items,
+            response: Some(response),
+        }))
+    }
+}
+
+/// Forwards the items of a request's body to request dispatch as it's polled, until the body
+/// ends, which drops the sender to tell request dispatch so, or until request dispatch drops the
+/// receiver because the request is over.
+struct BodyForward<S, Req> {
+    /// `None` once the body is done being forwarded.
+    body: Option<S>,
+    items: Option<mpsc::Sender<Req>>,
+    /// An item waiting for room to be forwarded.
+    pending: Option<Req>,
+}
+
+impl<S, Req> fmt::Debug for BodyForward<S, Req> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BodyForward")
+            .field("done", &self.body.is_none())
+            .finish()
+    }
+}
+
+impl<S: Stream<Item = Req>, Req> BodyForward<S, Req> {
+    unsafe_pinned!(body: Option<S>);
+    unsafe_unpinned!(items: Option<mpsc::Sender<Req>>);
+    unsafe_unpinned!(pending: Option<Req>);
+
+    fn new(body: S, items: mpsc::Sender<Req>) -> Self {
+        BodyForward {
+            body: Some(body),
+            items: Some(items),
+            pending: None,
+        }
+    }
+
+    /// Forwards as many items as there's room for.
+    fn poll_forward(mut self: Pin<&mut Self>, cx: &mut Context<'_>) {
+        loop {
+            if let Some(item) = self.as_mut().pending().take() {
+                let items = match self.as_mut().items() {
+                    Some(items) => items,
+                    None => return,
+                };
+                let sent = match items.poll_ready(cx) {
+                    Poll::Ready(Ok(())) => items.start_send(item).is_ok(),
+                    Poll::Ready(Err(_)) => false,
+                    Poll::Pending => {
+                        *self.as_mut().pending() = Some(item);
+                        return;
+                    }
+                };
+                if !sent {
+                    // The request is over.
+                    self.as_mut().stop();
+                    return;
+                }
+            }
+            let item = match self.as_mut().body().as_pin_mut() {
+                Some(body) => match body.poll_next(cx) {
+                    Poll::Ready(item) => item,
+                    Poll::Pending => return,
+                },
+                None => return,
+            };
+            match item {
+                Some(item) => *self.as_mut().pending() = Some(item),
+                None => {
+                    self.as_mut().stop();
+                    return;
+                }
+            }
+        }
+    }
+
+    fn stop(mut self: Pin<&mut Self>) {
+        self.as_mut().body().set(None);
+        *self.as_mut().items() = None;
+    }
+}
+
+/// A future returned by [`Channel::call_with_body`] that forwards the request's body while it
+/// resolves to the response.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct BodyCall<'a, Req, Resp, S> {
+    fut: AndThenIdent<Send<'a, Req, Resp>, DispatchResponse<Resp>>,
+    body: BodyForward<S, Req>,
+}
+
+impl<'a, Req, Resp, S: Stream<Item = Req>> BodyCall<'a, Req, Resp, S> {
+    unsafe_pinned!(fut: AndThenIdent<Send<'a, Req, Resp>, DispatchResponse<Resp>>);
+    unsafe_pinned!(body: BodyForward<S, Req>);
+}
+
+impl<'a, Req, Resp, S: Stream<Item = Req>> Future for BodyCall<'a, Req, Resp, S> {
+    type Output = io::Result<Resp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.as_mut().body().poll_forward(cx);
+        self.as_mut().fut().poll(cx)
+    }
+}
+
+/// A future returned by [`Channel::stream_with_body`] that resolves to the [`BodyStream`] of the
+/// response once request dispatch accepts the request.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct BodyStreamCall<'a, Req, Resp, S> {
+    fut: StreamCall<'a, Req, Resp>,
+    /// Moved into the stream once the future resolves. It's never pinned before then.
+    body: Option<BodyForward<S, Req>>,
+}
+
+impl<'a, Req, Resp, S> BodyStreamCall<'a, Req, Resp, S> {
+    unsafe_pinned!(fut: StreamCall<'a, Req, Resp>);
+    unsafe_unpinned!(body: Option<BodyForward<S, Req>>);
+}
+
+impl<'a, Req, Resp, S: Stream<Item = Req>> Future for BodyStreamCall<'a, Req, Resp, S> {
+    type Output = io::Result<BodyStream<Req, Resp, S>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let stream = ready!(self.as_mut().fut().poll(cx))?;
+        let body = self
+            .as_mut()
+            .body()
+            .take()
+            .expect("polled after completion");
+        Poll::Ready(Ok(BodyStream { stream, body }))
+    }
+}
+
+/// The [`ResponseStream`] of a request whose body is streamed to the server, which forwards the
+/// body while it's polled. Returned by [`Channel::stream_with_body`].
+///
+/// Dropping the stream before it ends cancels the request.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct BodyStream<Req, Resp, S> {
+    stream: ResponseStream<Resp>,
+    body: BodyForward<S, Req>,
+}
+
+impl<Req, Resp, S: Stream<Item = Req>> BodyStream<Req, Resp, S> {
+    unsafe_pinned!(stream: ResponseStream<Resp>);
+    unsafe_pinned!(body: BodyForward<S, Req>);
+
+    /// Like [`ResponseStream::items`]: returns a stream of the items converted by `f`, which
+    /// returns `None` for the final response.
+    pub fn items<T>(self, f: fn(Resp) -> Option<T>) -> Items<Resp, T, Self> {
+        Items {
+            stream: self,
+            f,
+            done: false,
+        }
+    }
+}
+
+impl<Req, Resp, S: Stream<Item = Req>> Stream for BodyStream<Req, Resp, S> {
+    type Item = io::Result<Resp>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.as_mut().body().poll_forward(cx);
+        self.as_mut().stream().poll_next(cx)
+    }
+}
+
 impl<Req, Resp> Channel<Req, Resp> {
+    /// Returns the ID of the connection this channel sends requests over.
+    pub fn connection_id(&self) -> &ConnectionId {
+        &self.connection_id
+    }
+
+    /// Names the method of each call with `name`, e.g. the `Request::name` generated by tarpc's
+    /// `service!`, in [`in_flight_calls`](Channel::in_flight_calls). Also applies to future
+    /// clones of this channel.
+    pub fn with_call_names(mut self, name: fn(&Req) -> &'static str) -> Self {
+        self.call_names = Some(CallNames(name));
+        self
+    }
+
+    /// Times out calls made through this channel that take longer than `timeout`, by bringing
+    /// their deadlines forward to at most `timeout` after the call is made. The deadline is sent
+    /// to the server, which abandons the request once it passes, and the call fails with
+    /// [`TimedOut`](io::ErrorKind::TimedOut). Also applies to future clones of this channel.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Records the span of each call made through this channel, named by its
+    /// [call name](Channel::with_call_names), if the channel has call names, and exports it with
+    /// `tracer` once the call completes. Also applies to future clones of this channel.
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Reports each call made through this channel to `metrics`, labeled by its
+    /// [call name](Channel::with_call_names), or by `unknown` if the channel has no call names.
+    /// Also applies to future clones of this channel.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Gives each call made through this channel that has no
+    /// [causality token](context::Context::causality) the next tick of `clock`, and acknowledges
+    /// to `clock` the tokens the server echoes. Also applies to future clones of this channel.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Returns the calls made through this channel or any of its clones that haven't completed
+    /// yet, oldest first, whether or not they have been written to the wire. Lets a watchdog find
+    /// calls that are stuck, even when they have deadlines too far off to time out.
+    pub fn in_flight_calls(&self) -> Vec<InFlightCall> {
+        let mut calls: Vec<_> = self
+            .in_flight_calls
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        calls.sort_by_key(|call| call.started);
+        calls
+    }
+
+    /// Returns true once the server said it's draining or closing the connection, e.g. because
+    /// it's shutting down. The server handles no new calls over the connection, so they should be
+    /// sent over another one, but it still answers the calls in flight before it closes the
+    /// connection.
+    pub fn is_draining(&self) -> bool {
+        self.server_close.is_draining()
+    }
+
+    /// Converts the context of a request to the context it's sent with.
+    fn prepare(&self, ctx: &mut context::Context) {
+        ctx.trace_context = tracing::child_of(ctx.trace_context);
+        if let Some(timeout) = self.timeout {
+            ctx.deadline = ctx.deadline.min(SystemTime::now() + timeout);
+        }
+        if let (None, Some(clock)) = (ctx.causality, &self.clock) {
+            ctx.causality = Some(clock.tick());
+        }
+    }
+
     /// Sends a request to the dispatch task to forward to the server, returning a [`Future`] that
     /// resolves when the request is sent (not when the response is received).
-    fn send(&mut self, mut ctx: context::Context, request: Req) -> Send<Req, Resp> {
-        // Convert the context to the call context.
-        ctx.trace_context.parent_id = Some(ctx.trace_context.span_id);
-        ctx.trace_context.span_id = SpanId::random(&mut rand::thread_rng());
-
+    fn send(
+        &mut self,
+        mut ctx: context::Context,
+        metadata: Metadata,
+        request: Req,
+        stream_items: Option<mpsc::Sender<Resp>>,
+        body: Option<mpsc::Receiver<Req>>,
+        caching: Option<Arc<Mutex<Option<Caching>>>>,
+    ) -> Send<Req, Resp> {
+        self.prepare(&mut ctx);
         let timeout = ctx.deadline.as_duration();
         let deadline = Instant::now() + timeout;
         trace!(
@@ -133,14 +593,40 @@ impl<Req, Resp> Channel<Req, Resp> {
         let cancellation = self.cancellation.clone();
         let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
         let server_addr = self.server_addr;
+        let method = self.call_names.as_ref().map(|names| (names.0)(&request));
+        let trace = self.tracer.as_ref().map(|tracer| {
+            let trace = tracer.start(ctx.trace_context, method.unwrap_or("call"), Kind::Client);
+            trace.tag("peer", server_addr.to_string());
+            trace
+        });
+        let recorder = self
+            .metrics
+            .as_ref()
+            .map(|metrics| Recorder::start(metrics.clone(), "client", method.unwrap_or("unknown")));
+        self.in_flight_calls.lock().unwrap().insert(
+            request_id,
+            InFlightCall {
+                request_id,
+                trace_id: *ctx.trace_id(),
+                method,
+                started: Instant::now(),
+                deadline: ctx.deadline,
+            },
+        );
         Send {
             fut: MapOkDispatchResponse::new(
-                MapErrConnectionReset::new(self.to_dispatch.send(DispatchRequest {
-                    ctx,
-                    request_id,
-                    request,
-                    response_completion,
-                })),
+                MapErrConnectionReset::new(
+                    self.to_dispatch.send(DispatchRequest {
+                        ctx,
+                        request_id,
+                        request,
+                        metadata,
+                        response_completion: Some(response_completion),
+                        stream_items,
+                        body,
+                    }),
+                    self.server_close.clone(),
+                ),
                 DispatchResponse {
                     response: deadline_compat::Deadline::new(response, deadline),
                     complete: false,
@@ -148,6 +634,12 @@ impl<Req, Resp> Channel<Req, Resp> {
                     cancellation,
                     ctx,
                     server_addr,
+                    in_flight_calls: self.in_flight_calls.clone(),
+                    trace,
+                    recorder,
+                    clock: self.clock.clone(),
+                    server_close: self.server_close.clone(),
+                    caching,
                 },
             ),
         }
@@ -156,12 +648,524 @@ impl<Req, Resp> Channel<Req, Resp> {
     /// Sends a request to the dispatch task to forward to the server, returning a [`Future`] that
     /// resolves to the response.
     pub fn call(&mut self, context: context::Context, request: Req) -> Call<Req, Resp> {
+        self.call_with_metadata(context, Metadata::new(), request)
+    }
+
+    /// Like [`call`](Channel::call), but sends `metadata` with the request, for the server's
+    /// handler to read with [`metadata::current`](crate::metadata::current).
+    pub fn call_with_metadata(
+        &mut self,
+        context: context::Context,
+        metadata: Metadata,
+        request: Req,
+    ) -> Call<Req, Resp> {
         Call {
-            fut: AndThenIdent::new(self.send(context, request)),
+            fut: AndThenIdent::new(self.send(context, metadata, request, None, None, None)),
+        }
+    }
+
+    /// Like [`call_with_metadata`](Channel::call_with_metadata), but also resolves to the
+    /// [caching](crate::Caching) the server sent with the response, whether the call succeeded or
+    /// failed, or None if it sent none or the response never arrived. Used by the client's
+    /// [cache](super::cache).
+    pub fn call_with_caching(
+        &mut self,
+        context: context::Context,
+        metadata: Metadata,
+        request: Req,
+    ) -> CachingCall<Req, Resp> {
+        let caching = Arc::new(Mutex::new(None));
+        CachingCall {
+            fut: AndThenIdent::new(self.send(
+                context,
+                metadata,
+                request,
+                None,
+                None,
+                Some(caching.clone()),
+            )),
+            caching,
+        }
+    }
+
+    /// Sends a request whose response the server [streams](crate::server::streaming) to the
+    /// dispatch task to forward to the server. Returns a [`Future`] that resolves, once the
+    /// dispatch task accepts the request, to a [`ResponseStream`] of the items the server streams,
+    /// followed by its final response.
+    ///
+    /// The request's deadline bounds the whole stream, and dropping the stream cancels the
+    /// request. Up to [`stream_item_buffer`](super::Config::stream_item_buffer) items are buffered
+    /// until they're read.
+    pub fn stream(&mut self, context: context::Context, request: Req) -> StreamCall<Req, Resp> {
+        let (stream_items, items) = mpsc::channel(self.stream_item_buffer);
+        StreamCall {
+            fut: self.send(
+                context,
+                Metadata::new(),
+                request,
+                Some(stream_items),
+                None,
+                None,
+            ),
+            items: Some(items),
+        }
+    }
+
+    /// Sends a request whose body is the items of `body`, which are streamed to the server after
+    /// the request, and read by its handler with
+    /// [`streaming::body`](crate::server::streaming::body). Returns a [`Future`] that resolves
+    /// to the response.
+    ///
+    /// The body is forwarded as the future is polled, with up to
+    /// [`stream_item_buffer`](super::Config::stream_item_buffer) items buffered until they're
+    /// written, and the server is told when it ends. If the response arrives first, the rest of
+    /// the body isn't sent.
+    pub fn call_with_body<S>(
+        &mut self,
+        context: context::Context,
+        request: Req,
+        body: S,
+    ) -> BodyCall<Req, Resp, S>
+    where
+        S: Stream<Item = Req>,
+    {
+        let (body_tx, body_rx) = mpsc::channel(self.stream_item_buffer);
+        BodyCall {
+            fut: AndThenIdent::new(self.send(
+                context,
+                Metadata::new(),
+                request,
+                None,
+                Some(body_rx),
+                None,
+            )),
+            body: BodyForward::new(body, body_tx),
+        }
+    }
+
+    /// Like [`stream`](Channel::stream), but streams the items of `body` to the server after the
+    /// request, like [`call_with_body`](Channel::call_with_body), making a bidirectional stream.
+    /// The body is forwarded while the stream the returned future resolves to is polled.
+    pub fn stream_with_body<S>(
+        &mut self,
+        context: context::Context,
+        request: Req,
+        body: S,
+    ) -> BodyStreamCall<Req, Resp, S>
+    where
+        S: Stream<Item = Req>,
+    {
+        let (stream_items, items) = mpsc::channel(self.stream_item_buffer);
+        let (body_tx, body_rx) = mpsc::channel(self.stream_item_buffer);
+        BodyStreamCall {
+            fut: StreamCall {
+                fut: self.send(
+                    context,
+                    Metadata::new(),
+                    request,
+                    Some(stream_items),
+                    Some(body_rx),
+                    None,
+                ),
+                items: Some(items),
+            },
+            body: Some(BodyForward::new(body, body_tx)),
+        }
+    }
+
+    /// Sends a one-way request, which the server handles without sending back a response, to the
+    /// dispatch task to forward to the server. Returns a [`Future`] that resolves once the
+    /// dispatch task accepts the request; nothing tells the client whether the server handled it.
+    /// Notifications take no in-flight request slot on the client and can't be canceled, and are
+    /// delivered [at most once](super::delivery::Delivery::AtMostOnce).
+    pub fn notify(
+        &mut self,
+        mut context: context::Context,
+        request: Req,
+    ) -> Notification<Req, Resp> {
+        self.prepare(&mut context);
+        trace!(
+            "[{}/{}] Queuing notification with deadline {}.",
+            context.trace_id(),
+            self.server_addr,
+            format_rfc3339(context.deadline),
+        );
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        Notification {
+            fut: MapErrConnectionReset::new(
+                self.to_dispatch.send(DispatchRequest {
+                    ctx: context,
+                    request_id,
+                    request,
+                    metadata: Metadata::new(),
+                    response_completion: None,
+                    stream_items: None,
+                    body: None,
+                }),
+                self.server_close.clone(),
+            ),
+        }
+    }
+
+    /// Returns a [`Future`] that resolves once request dispatch can accept a request from this
+    /// channel, or fails if the connection has shut down. Lets applications check that a server is
+    /// reachable, e.g. for readiness probes, without sending it a request.
+    pub fn ready(&mut self) -> impl Future<Output = io::Result<()>> + '_ {
+        future::poll_fn(move |cx| {
+            let server_close = &self.server_close;
+            self.to_dispatch
+                .poll_ready(cx)
+                .map_err(|_| server_close.connection_reset())
+        })
+    }
+}
+
+impl<Req, Resp> Channel<Req, Resp>
+where
+    Req: marker::Send + 'static,
+    Resp: marker::Send + 'static,
+{
+    /// Starts a call on the default executor, returning a handle to the response. Unlike
+    /// [`call`](Channel::call), the handle doesn't borrow the channel, and the response can be
+    /// collected without polling a future. Dropping the handle cancels the request.
+    pub fn start_call(
+        &mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> io::Result<CallHandle<Resp>> {
+        let (mut response_tx, response) = oneshot::channel();
+        let mut channel = self.clone();
+        spawn_or_err(
+            async move {
+                let response = {
+                    let call = channel.call(ctx, request);
+                    let canceled = response_tx.cancellation();
+                    pin_mut!(call);
+                    pin_mut!(canceled);
+                    match await!(future::select(call, canceled)) {
+                        future::Either::Left((response, _)) => response,
+                        // The handle was dropped. Dropping the call cancels the request.
+                        future::Either::Right(_) => return,
+                    }
+                };
+                let _ = response_tx.send(response);
+            },
+            "call",
+        )?;
+        Ok(CallHandle { response })
+    }
+}
+
+/// A handle to the response of a call started with [`Channel::start_call`].
+///
+/// The handle is a future, but it can also be checked for the response without blocking, have the
+/// response delivered to a callback, or be converted into a [`std::sync::mpsc::Receiver`], so that
+/// applications with their own event loops can make calls.
+#[derive(Debug)]
+#[must_use = "the request is canceled when the handle is dropped"]
+pub struct CallHandle<Resp> {
+    response: oneshot::Receiver<io::Result<Resp>>,
+}
+
+impl<Resp> CallHandle<Resp>
+where
+    Resp: marker::Send + 'static,
+{
+    /// Returns the response if it has arrived, or `None` if it hasn't yet.
+    pub fn try_recv(&mut self) -> Option<io::Result<Resp>> {
+        self.response
+            .try_recv()
+            .unwrap_or_else(|oneshot::Canceled| Some(Err(connection_reset())))
+    }
+
+    /// Calls `f` with the response on the default executor, once the response arrives.
+    pub fn on_complete<F>(self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(io::Result<Resp>) + marker::Send + 'static,
+    {
+        spawn_or_err(self.map(f), "call completion")
+    }
+
+    /// Returns a receiver that receives the response once it arrives.
+    pub fn into_receiver(self) -> io::Result<std::sync::mpsc::Receiver<io::Result<Resp>>> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(1);
+        self.on_complete(move |response| {
+            let _ = tx.send(response);
+        })?;
+        Ok(rx)
+    }
+}
+
+impl<Resp> Future for CallHandle<Resp> {
+    type Output = io::Result<Resp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
+        self.response
+            .poll_unpin(cx)
+            .map(|response| response.unwrap_or_else(|oneshot::Canceled| Err(connection_reset())))
+    }
+}
+
+/// The items a server [streams](crate::server::streaming) in response to a request, followed by
+/// the request's final response, or the error it failed with. Returned by [`Channel::stream`].
+///
+/// Dropping the stream before it ends cancels the request.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct ResponseStream<Resp> {
+    items: mpsc::Receiver<Resp>,
+    /// `None` once the final response is read.
+    response: Option<DispatchResponse<Resp>>,
+}
+
+impl<Resp> ResponseStream<Resp> {
+    unsafe_pinned!(items: mpsc::Receiver<Resp>);
+    unsafe_pinned!(response: Option<DispatchResponse<Resp>>);
+
+    /// Returns a stream of the items converted by `f`, which returns `None` for the final
+    /// response, e.g. for the response types of services whose items and final response are
+    /// variants of one type. An error still ends the stream after it's yielded.
+    pub fn items<T>(self, f: fn(Resp) -> Option<T>) -> Items<Resp, T> {
+        Items {
+            stream: self,
+            f,
+            done: false,
+        }
+    }
+
+    /// Returns an iterator that blocks the current thread until each item arrives, waiting at
+    /// most `item_timeout` for each. The stream is polled on the default executor.
+    pub fn blocking_iter(self, item_timeout: Duration) -> io::Result<BlockingIter<Resp>>
+    where
+        Resp: marker::Send + 'static,
+    {
+        BlockingIter::new(self, item_timeout)
+    }
+
+    /// Splits the stream into the items streamed ahead of the final response, e.g. a
+    /// long-running request's progress updates, and the final response.
+    ///
+    /// The progress must be read, or dropped, for the final response to arrive: while its buffer
+    /// is full, nothing more is read off the connection. Dropping the progress doesn't cancel the
+    /// request, but dropping the final response does.
+    ///
+    /// Panics if the stream already yielded the final response.
+    pub fn into_progress(self) -> (Progress<Resp>, FinalResponse<Resp>) {
+        let response = self
+            .response
+            .expect("The stream ended, so there's no final response to wait on.");
+        (Progress { items: self.items }, FinalResponse { response })
+    }
+}
+
+impl<Resp> Stream for ResponseStream<Resp> {
+    type Item = io::Result<Resp>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.response.is_none() {
+            return Poll::Ready(None);
+        }
+        // Request dispatch hands over every item before the final response, so the items are
+        // read first.
+        if let Poll::Ready(Some(item)) = self.as_mut().items().poll_next(cx) {
+            return Poll::Ready(Some(Ok(item)));
+        }
+        let response = ready!(self.as_mut().response().as_pin_mut().unwrap().poll(cx));
+        self.as_mut().response().set(None);
+        // Keeps request dispatch from waiting on room for items of a request that is over, e.g.
+        // one that timed out.
+        self.as_mut().items().close();
+        Poll::Ready(Some(response))
+    }
+}
+
+/// The items streamed ahead of a request's final response, e.g. progress updates. Returned by
+/// [`ResponseStream::into_progress`].
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Progress<Resp> {
+    items: mpsc::Receiver<Resp>,
+}
+
+impl<Resp> Progress<Resp> {
+    unsafe_pinned!(items: mpsc::Receiver<Resp>);
+}
+
+impl<Resp> Stream for Progress<Resp> {
+    type Item = Resp;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Resp>> {
+        self.items().poll_next(cx)
+    }
+}
+
+/// The final response to a request whose streamed items were split off into [`Progress`].
+/// Returned by [`ResponseStream::into_progress`].
+///
+/// Dropping the final response before it arrives cancels the request.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct FinalResponse<Resp> {
+    response: DispatchResponse<Resp>,
+}
+
+impl<Resp> FinalResponse<Resp> {
+    unsafe_pinned!(response: DispatchResponse<Resp>);
+}
+
+impl<Resp> Future for FinalResponse<Resp> {
+    type Output = io::Result<Resp>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
+        self.response().poll(cx)
+    }
+}
+
+/// The items of a [`ResponseStream`], or of a [`BodyStream`], converted by a function that
+/// returns `None` for the final response. Returned by [`ResponseStream::items`] and
+/// [`BodyStream::items`].
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Items<Resp, T, St = ResponseStream<Resp>> {
+    stream: St,
+    f: fn(Resp) -> Option<T>,
+    done: bool,
+}
+
+impl<Resp, T, St> Items<Resp, T, St> {
+    unsafe_pinned!(stream: St);
+    unsafe_unpinned!(done: bool);
+}
+
+impl<Resp, T, St> Items<Resp, T, St>
+where
+    St: Stream<Item = io::Result<Resp>> + marker::Send + 'static,
+    Resp: marker::Send + 'static,
+    T: marker::Send + 'static,
+{
+    /// Returns an iterator that blocks the current thread until each item arrives, waiting at
+    /// most `item_timeout` for each. The stream is polled on the default executor.
+    pub fn blocking_iter(self, item_timeout: Duration) -> io::Result<BlockingIter<T>> {
+        BlockingIter::new(self, item_timeout)
+    }
+}
+
+impl<Resp, T, St> Stream for Items<Resp, T, St>
+where
+    St: Stream<Item = io::Result<Resp>>,
+{
+    type Item = io::Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
         }
+        let item = match ready!(self.as_mut().stream().poll_next(cx)) {
+            Some(Ok(response)) => (self.f)(response).map(Ok),
+            Some(Err(e)) => {
+                *self.as_mut().done() = true;
+                Some(Err(e))
+            }
+            None => None,
+        };
+        if item.is_none() {
+            *self.as_mut().done() = true;
+        }
+        Poll::Ready(item)
+    }
+}
+
+/// An iterator over the items of a streamed response, for clients that can't poll the stream
+/// themselves, e.g. command-line tools without an async runtime of their own. Returned by
+/// [`ResponseStream::blocking_iter`] and [`Items::blocking_iter`].
+///
+/// Each item is only read off the stream once it's asked for, so a slow reader back-pressures
+/// the server. If an item doesn't arrive in time, the iterator yields an error of kind
+/// [`TimedOut`](io::ErrorKind::TimedOut) and ends. Ending or dropping the iterator before the
+/// stream ends cancels the request.
+#[derive(Debug)]
+pub struct BlockingIter<T> {
+    /// Asks the task polling the stream for the next item. `None` once the iterator ends.
+    next: Option<mpsc::Sender<()>>,
+    /// Receives the next item, or `None` at the end of the stream.
+    items: std::sync::mpsc::Receiver<Option<io::Result<T>>>,
+    item_timeout: Duration,
+}
+
+impl<T: marker::Send + 'static> BlockingIter<T> {
+    fn new<S>(stream: S, item_timeout: Duration) -> io::Result<Self>
+    where
+        S: Stream<Item = io::Result<T>> + marker::Send + 'static,
+    {
+        // At most one item is asked for at a time, which the sender's guaranteed slot holds.
+        let (next_tx, mut next_rx) = mpsc::channel(0);
+        let (items_tx, items) = std::sync::mpsc::channel();
+        spawn_or_err(
+            async move {
+                pin_mut!(stream);
+                while let Some(()) = await!(next_rx.next()) {
+                    let item = await!(stream.next());
+                    let end = item.is_none();
+                    if items_tx.send(item).is_err() || end {
+                        break;
+                    }
+                }
+            },
+            "blocking stream",
+        )?;
+        Ok(BlockingIter {
+            next: Some(next_tx),
+            items,
+            item_timeout,
+        })
     }
 }
 
+impl<T> Iterator for BlockingIter<T> {
+    type Item = io::Result<T>;
+
+    fn next(&mut self) -> Option<io::Result<T>> {
+        // Dropping the sender stops the task, which drops the stream.
+        let mut next = self.next.take()?;
+        if next.try_send(()).is_err() {
+            return Some(Err(connection_reset()));
+        }
+        match self.items.recv_timeout(self.item_timeout) {
+            Ok(Some(item)) => {
+                self.next = Some(next);
+                Some(item)
+            }
+            Ok(None) => None,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Some(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("No item arrived within {:?}.", self.item_timeout),
+            ))),
+            // The executor dropped the task.
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => Some(Err(connection_reset())),
+        }
+    }
+}
+
+fn connection_reset() -> io::Error {
+    io::Error::from(io::ErrorKind::ConnectionReset)
+}
+
+fn spawn_or_err(
+    future: impl Future<Output = ()> + marker::Send + 'static,
+    what: &str,
+) -> io::Result<()> {
+    crate::spawn(future).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Could not spawn {} task. Is shutdown: {}",
+                what,
+                e.is_shutdown()
+            ),
+        )
+    })
+}
+
 /// A server response that is completed by request dispatch when the corresponding response
 /// arrives off the wire.
 #[derive(Debug)]
@@ -172,6 +1176,13 @@ struct DispatchResponse<Resp> {
     cancellation: RequestCancellation,
     request_id: u64,
     server_addr: SocketAddr,
+    in_flight_calls: InFlightCalls,
+    trace: Option<Trace>,
+    recorder: Option<Recorder>,
+    clock: Option<Clock>,
+    server_close: ServerClose,
+    /// Receives the response's caching, for calls made with [`Channel::call_with_caching`].
+    caching: Option<Arc<Mutex<Option<Caching>>>>,
 }
 
 impl<Resp> DispatchResponse<Resp> {
@@ -186,9 +1197,21 @@ impl<Resp> Future for DispatchResponse<Resp> {
         let resp = ready!(self.response.poll_unpin(cx));
 
         self.complete = true;
-
-        Poll::Ready(match resp {
-            Ok(resp) => Ok(resp.message?),
+        self.in_flight_calls
+            .lock()
+            .unwrap()
+            .remove(&self.request_id);
+
+        let response = match resp {
+            Ok(resp) => {
+                if let (Some(token), Some(clock)) = (resp.causality, &self.clock) {
+                    clock.acknowledge(token);
+                }
+                if let Some(caching) = &self.caching {
+                    *caching.lock().unwrap() = resp.caching;
+                }
+                resp.message.map_err(io::Error::from)
+            }
             Err(e) => Err({
                 let trace_id = *self.as_mut().ctx().trace_id();
                 let server_addr = *self.as_mut().server_addr();
@@ -217,7 +1240,12 @@ impl<Resp> Future for DispatchResponse<Resp> {
                     }
                 } else if e.is_inner() {
                     // The oneshot is Canceled when the dispatch task ends.
-                    io::Error::from(io::ErrorKind::ConnectionReset)
+                    debug!(
+                        "[{}/{}] Request {} failed, because the connection closed before the \
+                         response arrived.",
+                        trace_id, server_addr, self.request_id
+                    );
+                    self.server_close.connection_reset()
                 } else {
                     panic!(
                         "[{}/{}] Unrecognized deadline error: {}",
@@ -225,7 +1253,14 @@ impl<Resp> Future for DispatchResponse<Resp> {
                     )
                 }
             }),
-        })
+        };
+        if let Some(trace) = &self.trace {
+            trace.end_with(&response);
+        }
+        if let Some(recorder) = &mut self.recorder {
+            recorder.complete(&response);
+        }
+        Poll::Ready(response)
     }
 }
 
@@ -245,6 +1280,14 @@ impl<Resp> Drop for DispatchResponse<Resp> {
             // receiver as closed.
             self.response.get_mut().close();
             self.cancellation.cancel(self.request_id);
+            self.in_flight_calls
+                .lock()
+                .unwrap()
+                .remove(&self.request_id);
+            if let Some(trace) = &self.trace {
+                trace.annotate("Canceled.");
+                trace.end(None);
+            }
         }
     }
 }
@@ -259,22 +1302,46 @@ pub async fn spawn<Req, Resp, C>(
 where
     Req: marker::Send + 'static,
     Resp: marker::Send + 'static,
-    C: Transport<Item = Response<Resp>, SinkItem = ClientMessage<Req>> + marker::Send + 'static,
+    C: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>>
+        + marker::Send
+        + 'static,
 {
-    let (to_dispatch, pending_requests) = mpsc::channel(config.pending_request_buffer);
+    let (queues, pending_requests) = request_queues(config.pending_request_buffer);
+    let to_dispatch = queues.new_queue();
     let (cancellation, canceled_requests) = cancellations();
     let canceled_requests = canceled_requests.fuse();
-
+    let connection_id = ConnectionId::random(&mut rand::thread_rng());
+    let server_close = ServerClose::default();
+    let stream_item_buffer = config.stream_item_buffer;
+    debug!("[{}] Opened connection {}.", server_addr, connection_id);
+
+    let concurrency_limit = if config.adaptive_concurrency {
+        Some(AdaptiveLimit::new(INITIAL_LIMIT, config.max_in_flight_requests))
+    } else {
+        None
+    };
     crate::spawn(
         RequestDispatch {
             config,
             server_addr,
+            connection_id,
             canceled_requests,
             transport: transport.fuse(),
             in_flight_requests: FnvHashMap::default(),
             pending_requests: pending_requests.fuse(),
+            pushback: None,
+            concurrency_limit,
+            server_close: server_close.clone(),
+            close_sent: false,
+            stream_item: None,
+            bodies: vec![],
         }
-        .unwrap_or_else(move |e| error!("[{}] Connection broken: {}", server_addr, e)),
+        .unwrap_or_else(move |e| {
+            error!(
+                "[{}] Connection {} broken: {}",
+                server_addr, connection_id, e
+            )
+        }),
     )
     .map_err(|e| {
         io::Error::new(
@@ -288,9 +1355,19 @@ where
 
     Ok(Channel {
         to_dispatch,
+        queues,
         cancellation,
         server_addr,
+        connection_id,
         next_request_id: Arc::new(AtomicU64::new(0)),
+        in_flight_calls: Arc::default(),
+        call_names: None,
+        timeout: None,
+        tracer: None,
+        metrics: None,
+        clock: None,
+        server_close,
+        stream_item_buffer,
     })
 }
 
@@ -300,7 +1377,7 @@ struct RequestDispatch<Req, Resp, C> {
     /// Writes requests to the wire and reads responses off the wire.
     transport: Fuse<C>,
     /// Requests waiting to be written to the wire.
-    pending_requests: Fuse<mpsc::Receiver<DispatchRequest<Req, Resp>>>,
+    pending_requests: Fuse<PendingRequests<Req, Resp>>,
     /// Requests that were dropped.
     canceled_requests: Fuse<CanceledRequests>,
     /// Requests already written to the wire that haven't yet received responses.
@@ -309,26 +1386,109 @@ struct RequestDispatch<Req, Resp, C> {
     config: Config,
     /// The address of the server connected to.
     server_addr: SocketAddr,
+    /// Identifies the connection in logs.
+    connection_id: ConnectionId,
+    /// When set, no new requests are written until it fires, because the server asked the client
+    /// to back off.
+    pushback: Option<Compat01As03<Delay>>,
+    /// If the client adapts its concurrency, the limit on in-flight requests, at most
+    /// `config.max_in_flight_requests`.
+    concurrency_limit: Option<AdaptiveLimit>,
+    /// Set when the server says it's closing the connection.
+    server_close: ServerClose,
+    /// Whether the client told the server it's closing the connection.
+    close_sent: bool,
+    /// An item of a streamed response read off the wire, waiting for room in the buffer of its
+    /// request's stream.
+    stream_item: Option<(u64, Resp)>,
+    /// The streamed bodies of in-flight requests, whose items are written in turns.
+    bodies: Vec<StreamingBody<Req>>,
 }
 
 impl<Req, Resp, C> RequestDispatch<Req, Resp, C>
 where
     Req: marker::Send,
     Resp: marker::Send,
-    C: Transport<Item = Response<Resp>, SinkItem = ClientMessage<Req>>,
+    C: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>>,
 {
     unsafe_pinned!(server_addr: SocketAddr);
     unsafe_pinned!(in_flight_requests: FnvHashMap<u64, InFlightData<Resp>>);
     unsafe_pinned!(canceled_requests: Fuse<CanceledRequests>);
-    unsafe_pinned!(pending_requests: Fuse<mpsc::Receiver<DispatchRequest<Req, Resp>>>);
+    unsafe_pinned!(pending_requests: Fuse<PendingRequests<Req, Resp>>);
     unsafe_pinned!(transport: Fuse<C>);
+    unsafe_unpinned!(pushback: Option<Compat01As03<Delay>>);
+    unsafe_unpinned!(concurrency_limit: Option<AdaptiveLimit>);
+    unsafe_unpinned!(close_sent: bool);
+    unsafe_unpinned!(stream_item: Option<(u64, Resp)>);
+    unsafe_unpinned!(bodies: Vec<StreamingBody<Req>>);
+
+    /// Returns the number of requests that can be in flight at once.
+    fn max_in_flight_requests(&self) -> usize {
+        match &self.concurrency_limit {
+            Some(limit) => limit.limit(),
+            None => self.config.max_in_flight_requests,
+        }
+    }
 
     fn pump_read(self: &mut Pin<&mut Self>, cx: &mut Context<'_>) -> PollIo<()> {
-        Poll::Ready(match ready!(self.as_mut().transport().poll_next(cx)?) {
-            Some(response) => {
+        ready!(self.poll_deliver_stream_item(cx));
+        Poll::Ready(match ready!(self.as_mut().transport().poll_next(cx)) {
+            Some(Err(e)) => {
+                let is_undecodable_response = e
+                    .get_ref()
+                    .map_or(false, |inner| inner.is::<UndecodableResponse>());
+                if !is_undecodable_response || !self.config.forgive_undecodable_responses {
+                    return Poll::Ready(Some(Err(e)));
+                }
+                let e = e
+                    .into_inner()
+                    .unwrap()
+                    .downcast::<UndecodableResponse>()
+                    .unwrap();
+                debug!(
+                    "[{}] Failing request {}: {}",
+                    self.as_mut().server_addr(),
+                    e.request_id,
+                    e
+                );
+                self.complete(Response {
+                    request_id: e.request_id,
+                    message: Err(ServerError::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Client could not decode the response: {}", e.detail),
+                    )),
+                    causality: None,
+                    caching: None,
+                });
+                Some(Ok(()))
+            }
+            Some(Ok(ServerMessage::Response(response))) => {
                 self.complete(response);
                 Some(Ok(()))
             }
+            Some(Ok(ServerMessage::StreamItem { request_id, item })) => {
+                *self.as_mut().stream_item() = Some((request_id, item));
+                Some(Ok(()))
+            }
+            Some(Ok(ServerMessage::Close { reason })) => {
+                info!(
+                    "[{}] Connection {} closed by the server: {}.",
+                    self.as_mut().server_addr(),
+                    self.connection_id,
+                    reason
+                );
+                self.server_close.set(reason);
+                None
+            }
+            Some(Ok(ServerMessage::Drain)) => {
+                info!(
+                    "[{}] Connection {} is being drained by the server.",
+                    self.as_mut().server_addr(),
+                    self.connection_id
+                );
+                self.server_close.drain();
+                Some(Ok(()))
+            }
             None => {
                 trace!("[{}] read half closed", self.as_mut().server_addr());
                 None
@@ -336,6 +1496,41 @@ where
         })
     }
 
+    /// Hands the streamed item read off the wire last to its request's stream, once the stream
+    /// has room for it. Until then, nothing more is read, which back-pressures the server, but
+    /// also holds up the connection's other responses.
+    fn poll_deliver_stream_item(self: &mut Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let (request_id, item) = match self.as_mut().stream_item().take() {
+            Some(stream_item) => stream_item,
+            None => return Poll::Ready(()),
+        };
+        let server_addr = *self.as_mut().server_addr();
+        let mut in_flight_requests = self.as_mut().in_flight_requests();
+        let stream_items = in_flight_requests
+            .get_mut(&request_id)
+            .and_then(|in_flight_data| in_flight_data.stream_items.as_mut());
+        let delivered = match stream_items {
+            Some(stream_items) => match stream_items.poll_ready(cx) {
+                Poll::Ready(Ok(())) => stream_items.start_send(item).is_ok(),
+                // The stream, or the progress split off of it, was dropped.
+                Poll::Ready(Err(_)) => false,
+                Poll::Pending => {
+                    *self.as_mut().stream_item() = Some((request_id, item));
+                    return Poll::Pending;
+                }
+            },
+            None => false,
+        };
+        if !delivered {
+            trace!(
+                "[{}] Dropping item streamed to request {}, which no stream is reading.",
+                server_addr,
+                request_id
+            );
+        }
+        Poll::Ready(())
+    }
+
     fn pump_write(self: &mut Pin<&mut Self>, cx: &mut Context<'_>) -> PollIo<()> {
         enum ReceiverStatus {
             NotReady,
@@ -360,6 +1555,11 @@ where
             Poll::Pending => ReceiverStatus::NotReady,
         };
 
+        if let Poll::Ready((ctx, request_id, item)) = self.poll_next_body_item(cx)? {
+            self.write_body_item(ctx, request_id, item)?;
+            return Poll::Ready(Some(Ok(())));
+        }
+
         match (pending_requests_status, canceled_requests_status) {
             (ReceiverStatus::Closed, ReceiverStatus::Closed) => {
                 ready!(self.as_mut().transport().poll_flush(cx)?);
@@ -381,11 +1581,12 @@ where
         self: &mut Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> PollIo<DispatchRequest<Req, Resp>> {
-        if self.as_mut().in_flight_requests().len() >= self.config.max_in_flight_requests {
+        let max_in_flight_requests = self.max_in_flight_requests();
+        if self.as_mut().in_flight_requests().len() >= max_in_flight_requests {
             info!(
                 "At in-flight request capacity ({}/{}).",
                 self.as_mut().in_flight_requests().len(),
-                self.config.max_in_flight_requests
+                max_in_flight_requests
             );
 
             // No need to schedule a wakeup, because timers and responses are responsible
@@ -393,6 +1594,25 @@ where
             return Poll::Pending;
         }
 
+        if let Some(pushback) = self.as_mut().pushback() {
+            match pushback.poll_unpin(cx) {
+                Poll::Pending => {
+                    trace!(
+                        "[{}] Holding requests until server pushback expires.",
+                        self.as_mut().server_addr()
+                    );
+                    return Poll::Pending;
+                }
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => warn!(
+                    "[{}] Ignoring server pushback, because the timer failed: {}",
+                    self.as_mut().server_addr(),
+                    e
+                ),
+            }
+            *self.as_mut().pushback() = None;
+        }
+
         while let Poll::Pending = self.as_mut().transport().poll_ready(cx)? {
             // We can't yield a request-to-be-sent before the transport is capable of buffering it.
             ready!(self.as_mut().transport().poll_flush(cx)?);
@@ -401,7 +1621,11 @@ where
         loop {
             match ready!(self.as_mut().pending_requests().poll_next_unpin(cx)) {
                 Some(request) => {
-                    if request.response_completion.is_canceled() {
+                    let canceled = request
+                        .response_completion
+                        .as_ref()
+                        .map_or(false, |completion| completion.is_canceled());
+                    if canceled {
                         trace!(
                             "[{}] Request canceled before being sent.",
                             request.ctx.trace_id()
@@ -436,6 +1660,7 @@ where
                         self.as_mut().in_flight_requests().remove(&request_id)
                     {
                         self.as_mut().in_flight_requests().compact(0.1);
+                        self.drop_body(request_id);
 
                         debug!(
                             "[{}/{}] Removed request.",
@@ -457,27 +1682,107 @@ where
         }
     }
 
+    /// Yields the next item of a streamed request body, or `None` once a body ends, along with its
+    /// request. Bodies take turns.
+    fn poll_next_body_item(
+        self: &mut Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(context::Context, u64, Option<Req>)>> {
+        if self.bodies.is_empty() {
+            return Poll::Pending;
+        }
+        while let Poll::Pending = self.as_mut().transport().poll_ready(cx)? {
+            ready!(self.as_mut().transport().poll_flush(cx)?);
+        }
+
+        let bodies = self.as_mut().bodies();
+        for i in 0..bodies.len() {
+            if let Poll::Ready(item) = bodies[i].items.poll_next_unpin(cx) {
+                let body = bodies.remove(i);
+                let next = (body.ctx, body.request_id, item);
+                // A body that yielded an item goes to the back of the line, and one that ended
+                // is done.
+                if next.2.is_some() {
+                    bodies.push(body);
+                }
+                return Poll::Ready(Ok(next));
+            }
+        }
+        Poll::Pending
+    }
+
+    fn write_body_item(
+        self: &mut Pin<&mut Self>,
+        ctx: context::Context,
+        request_id: u64,
+        item: Option<Req>,
+    ) -> io::Result<()> {
+        let message = match item {
+            Some(item) => ClientMessageKind::StreamItem { request_id, item },
+            None => {
+                trace!(
+                    "[{}/{}] Ending request body.",
+                    ctx.trace_id(),
+                    self.as_mut().server_addr()
+                );
+                ClientMessageKind::StreamEnd { request_id }
+            }
+        };
+        self.as_mut().transport().start_send(ClientMessage {
+            trace_context: ctx.trace_context,
+            message,
+        })
+    }
+
+    /// Stops writing the streamed body of request `request_id`, which is over, if it has one.
+    fn drop_body(self: &mut Pin<&mut Self>, request_id: u64) {
+        if !self.bodies.is_empty() {
+            self.as_mut()
+                .bodies()
+                .retain(|body| body.request_id != request_id);
+        }
+    }
+
     fn write_request(
         self: &mut Pin<&mut Self>,
         dispatch_request: DispatchRequest<Req, Resp>,
     ) -> io::Result<()> {
         let request_id = dispatch_request.request_id;
-        let request = ClientMessage {
-            trace_context: dispatch_request.ctx.trace_context,
-            message: ClientMessageKind::Request(Request {
-                id: request_id,
-                message: dispatch_request.request,
-                deadline: dispatch_request.ctx.deadline,
-            }),
+        let request = Request {
+            id: request_id,
+            message: dispatch_request.request,
+            deadline: dispatch_request.ctx.deadline,
+            metadata: dispatch_request.metadata,
+            causality: dispatch_request.ctx.causality,
         };
-        self.as_mut().transport().start_send(request)?;
-        self.as_mut().in_flight_requests().insert(
-            request_id,
-            InFlightData {
-                ctx: dispatch_request.ctx,
-                response_completion: dispatch_request.response_completion,
-            },
-        );
+        let message = match (&dispatch_request.response_completion, dispatch_request.body) {
+            (Some(_), Some(items)) => {
+                self.as_mut().bodies().push(StreamingBody {
+                    ctx: dispatch_request.ctx,
+                    request_id,
+                    items,
+                });
+                ClientMessageKind::StreamingRequest(request)
+            }
+            (Some(_), None) => ClientMessageKind::Request(request),
+            (None, _) => ClientMessageKind::Notification(request),
+        };
+        self.as_mut().transport().start_send(ClientMessage {
+            trace_context: dispatch_request.ctx.trace_context,
+            message,
+        })?;
+        // Notifications have no response to wait for.
+        if let Some(response_completion) = dispatch_request.response_completion {
+            self.as_mut().in_flight_requests().insert(
+                request_id,
+                InFlightData {
+                    ctx: dispatch_request.ctx,
+                    response_completion,
+                    stream_items: dispatch_request.stream_items,
+                    sent: Instant::now(),
+                },
+            );
+        }
         Ok(())
     }
 
@@ -500,6 +1805,44 @@ where
         Ok(())
     }
 
+    /// Tells the server that the client is closing the connection, because every channel using it
+    /// was dropped.
+    fn poll_close(self: &mut Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.close_sent {
+            ready!(self.as_mut().transport().poll_ready(cx)?);
+            self.as_mut().transport().start_send(ClientMessage {
+                trace_context: trace::Context::new_root(),
+                message: ClientMessageKind::Close {
+                    reason: CloseReason::Shutdown,
+                },
+            })?;
+            *self.as_mut().close_sent() = true;
+        }
+        self.as_mut().transport().poll_flush(cx)
+    }
+
+    /// Holds off on writing new requests for `retry_after`, at most `config.max_pushback`, unless
+    /// already holding off for longer.
+    fn push_back(self: &mut Pin<&mut Self>, retry_after: Duration) {
+        let retry_after = retry_after.min(self.config.max_pushback);
+        let until = match Instant::now().checked_add(retry_after) {
+            Some(until) => until,
+            None => {
+                warn!(
+                    "[{}] Ignoring server pushback of {:?}, which is too long to wait.",
+                    self.as_mut().server_addr(),
+                    retry_after
+                );
+                return;
+            }
+        };
+        let pushback = self.as_mut().pushback();
+        match pushback {
+            Some(delay) if delay.get_ref().deadline() >= until => {}
+            _ => *pushback = Some(Delay::new(until).compat()),
+        }
+    }
+
     /// Sends a server response to the client task that initiated the associated request.
     fn complete(self: &mut Pin<&mut Self>, response: Response<Resp>) -> bool {
         if let Some(in_flight_data) = self
@@ -508,13 +1851,40 @@ where
             .remove(&response.request_id)
         {
             self.as_mut().in_flight_requests().compact(0.1);
+            self.drop_body(response.request_id);
+            let in_flight = self.as_mut().in_flight_requests().len() + 1;
+            if let Some(limit) = self.as_mut().concurrency_limit() {
+                limit.sample(in_flight_data.sent.elapsed(), in_flight);
+            }
 
             trace!(
                 "[{}/{}] Received response.",
                 in_flight_data.ctx.trace_id(),
                 self.as_mut().server_addr()
             );
-            let _ = in_flight_data.response_completion.send(response);
+            if let Err(ServerError {
+                retry_after: Some(retry_after),
+                ..
+            }) = response.message
+            {
+                if self.config.honor_pushback {
+                    debug!(
+                        "[{}/{}] Server asked for pushback of {:?}.",
+                        in_flight_data.ctx.trace_id(),
+                        self.as_mut().server_addr(),
+                        retry_after
+                    );
+                    self.push_back(retry_after);
+                }
+            }
+            if let Err(response) = in_flight_data.response_completion.send(response) {
+                trace!(
+                    "[{}/{}] Dropping response to request {}, because the caller stopped waiting.",
+                    in_flight_data.ctx.trace_id(),
+                    self.as_mut().server_addr(),
+                    response.request_id
+                );
+            }
             return true;
         }
 
@@ -533,19 +1903,28 @@ impl<Req, Resp, C> Future for RequestDispatch<Req, Resp, C>
 where
     Req: marker::Send,
     Resp: marker::Send,
-    C: Transport<Item = Response<Resp>, SinkItem = ClientMessage<Req>>,
+    C: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>>,
 {
     type Output = io::Result<()>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         trace!("[{}] RequestDispatch::poll", self.as_mut().server_addr());
         loop {
-            match (self.pump_read(cx)?, self.pump_write(cx)?) {
+            let read = self.pump_read(cx)?;
+            if self.server_close.reason().is_some() {
+                // Dropping the dispatch fails the calls in flight and the calls still queued,
+                // which then report why the server closed the connection.
+                return Poll::Ready(Ok(()));
+            }
+            match (read, self.pump_write(cx)?) {
                 (read, write @ Poll::Ready(None)) => {
                     if self.as_mut().in_flight_requests().is_empty() {
+                        ready!(self.poll_close(cx)?);
                         info!(
-                            "[{}] Shutdown: write half closed, and no requests in flight.",
-                            self.as_mut().server_addr()
+                            "[{}] Connection {} shutdown: write half closed, and no requests in \
+                             flight.",
+                            self.as_mut().server_addr(),
+                            self.connection_id
                         );
                         return Poll::Ready(Ok(()));
                     }
@@ -591,12 +1970,126 @@ struct DispatchRequest<Req, Resp> {
     ctx: context::Context,
     request_id: u64,
     request: Req,
-    response_completion: oneshot::Sender<Response<Resp>>,
+    metadata: Metadata,
+    /// Completes the call with its response; `None` for notifications.
+    response_completion: Option<oneshot::Sender<Response<Resp>>>,
+    /// Hands the items of a streamed response to the caller; `None` for other calls.
+    stream_items: Option<mpsc::Sender<Resp>>,
+    /// The items of the request's streamed body; `None` for requests without one.
+    body: Option<mpsc::Receiver<Req>>,
 }
 
 struct InFlightData<Resp> {
     ctx: context::Context,
     response_completion: oneshot::Sender<Response<Resp>>,
+    /// Hands the items of a streamed response to the caller. Dropped with the rest of the data
+    /// when the final response arrives, which ends the items.
+    stream_items: Option<mpsc::Sender<Resp>>,
+    /// When the request was written to the wire.
+    sent: Instant,
+}
+
+/// The streamed body of a request, whose items are written after the request.
+struct StreamingBody<Req> {
+    ctx: context::Context,
+    request_id: u64,
+    items: mpsc::Receiver<Req>,
+}
+
+/// Creates a request queue for each [`Channel`].
+#[derive(Debug)]
+struct RequestQueues<Req, Resp> {
+    new_queues: mpsc::UnboundedSender<mpsc::Receiver<DispatchRequest<Req, Resp>>>,
+    buffer: usize,
+}
+
+impl<Req, Resp> Clone for RequestQueues<Req, Resp> {
+    fn clone(&self) -> Self {
+        RequestQueues {
+            new_queues: self.new_queues.clone(),
+            buffer: self.buffer,
+        }
+    }
+}
+
+/// The requests of all channels waiting to be written to the wire. Channels' queues take turns,
+/// so requests from different channels are interleaved.
+#[derive(Debug)]
+struct PendingRequests<Req, Resp> {
+    new_queues: mpsc::UnboundedReceiver<mpsc::Receiver<DispatchRequest<Req, Resp>>>,
+    new_queues_closed: bool,
+    queues: Vec<mpsc::Receiver<DispatchRequest<Req, Resp>>>,
+    /// The index of the queue to take the next request from.
+    next: usize,
+}
+
+/// Returns a factory of request queues, and a stream of the requests sent over all of them.
+fn request_queues<Req, Resp>(
+    buffer: usize,
+) -> (RequestQueues<Req, Resp>, PendingRequests<Req, Resp>) {
+    // Unbounded because a queue is registered for every clone of a channel, and clones can't be
+    // back-pressured. There is at most one queue per live clone.
+    let (new_queues, new_queues_rx) = mpsc::unbounded();
+    (
+        RequestQueues { new_queues, buffer },
+        PendingRequests {
+            new_queues: new_queues_rx,
+            new_queues_closed: false,
+            queues: vec![],
+            next: 0,
+        },
+    )
+}
+
+impl<Req, Resp> RequestQueues<Req, Resp> {
+    /// Returns the sender of a new request queue.
+    fn new_queue(&self) -> mpsc::Sender<DispatchRequest<Req, Resp>> {
+        let (tx, rx) = mpsc::channel(self.buffer);
+        // Unbounded, because a channel is cloned without waiting; the number of queues it holds
+        // is bounded by the number of clones, each of which sends one. If the dispatch task is
+        // gone, the receiver is dropped, and sending requests fails.
+        let _ = self.new_queues.unbounded_send(rx);
+        tx
+    }
+}
+
+impl<Req, Resp> Stream for PendingRequests<Req, Resp> {
+    type Item = DispatchRequest<Req, Resp>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<DispatchRequest<Req, Resp>>> {
+        while !self.new_queues_closed {
+            match self.new_queues.poll_next_unpin(cx) {
+                Poll::Ready(Some(queue)) => self.queues.push(queue),
+                Poll::Ready(None) => self.new_queues_closed = true,
+                Poll::Pending => break,
+            }
+        }
+
+        let mut polled = 0;
+        while polled < self.queues.len() {
+            let i = (self.next + polled) % self.queues.len();
+            match self.queues[i].poll_next_unpin(cx) {
+                Poll::Ready(Some(request)) => {
+                    self.next = i + 1;
+                    return Poll::Ready(Some(request));
+                }
+                // All senders of the queue were dropped.
+                Poll::Ready(None) => {
+                    self.queues.swap_remove(i);
+                }
+                Poll::Pending => polled += 1,
+            }
+        }
+
+        if self.new_queues_closed && self.queues.is_empty() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
 }
 
 /// Sends request cancellation signals.
@@ -636,17 +2129,18 @@ impl Stream for CanceledRequests {
 #[must_use = "futures do nothing unless polled"]
 struct MapErrConnectionReset<Fut> {
     future: Fut,
-    finished: Option<()>,
+    /// Taken once the future finishes.
+    server_close: Option<ServerClose>,
 }
 
 impl<Fut> MapErrConnectionReset<Fut> {
     unsafe_pinned!(future: Fut);
-    unsafe_unpinned!(finished: Option<()>);
+    unsafe_unpinned!(server_close: Option<ServerClose>);
 
-    fn new(future: Fut) -> MapErrConnectionReset<Fut> {
+    fn new(future: Fut, server_close: ServerClose) -> MapErrConnectionReset<Fut> {
         MapErrConnectionReset {
             future,
-            finished: Some(()),
+            server_close: Some(server_close),
         }
     }
 }
@@ -663,10 +2157,10 @@ where
         match self.as_mut().future().try_poll(cx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(result) => {
-                self.finished().take().expect(
+                let server_close = self.server_close().take().expect(
                     "MapErrConnectionReset must not be polled after it returned `Poll::Ready`",
                 );
-                Poll::Ready(result.map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset)))
+                Poll::Ready(result.map_err(|_| server_close.connection_reset()))
             }
         }
     }
@@ -820,23 +2314,40 @@ where
 mod tests {
     use super::{
         CanceledRequests, Channel, DispatchResponse, RequestCancellation, RequestDispatch,
+        ServerClose,
     };
     use crate::{
-        client::Config,
+        client::{self, Config},
         context,
+        metadata::Metadata,
+        server::{
+            self,
+            cancellation::{self, Canceled},
+        },
+        test_util,
         transport::{self, channel::UnboundedChannel},
-        ClientMessage, Response,
+        ClientMessage, ClientMessageKind, CloseReason, ConnectionClosed, Response, Server,
+        ServerError, ServerMessage,
     };
     use fnv::FnvHashMap;
-    use futures::{channel::mpsc, prelude::*, task::Context, Poll};
+    use futures::{
+        channel::{mpsc, oneshot},
+        compat::Executor01CompatExt,
+        future,
+        prelude::*,
+        task::Context,
+        Poll,
+    };
     use futures_test::task::noop_waker_ref;
     use std::{
-        marker,
+        io, marker,
         net::{IpAddr, Ipv4Addr, SocketAddr},
         pin::Pin,
         sync::atomic::AtomicU64,
-        sync::Arc,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant, SystemTime},
     };
+    use trace::ConnectionId;
 
     #[test]
     fn stage_request() {
@@ -855,6 +2366,44 @@ mod tests {
     }
 
     // Regression test for  https://github.com/google/tarpc/issues/220
+    #[test]
+    fn in_flight_calls_until_complete() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let mut channel =
+            channel.with_call_names(|request| if request == "hi" { "greet" } else { "other" });
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(&noop_waker_ref());
+
+        let hi = send_request(&mut channel, "hi");
+        let bye = send_request(&mut channel.clone(), "bye");
+        let calls = channel.in_flight_calls();
+        assert_eq!(
+            calls
+                .iter()
+                .map(|call| (call.request_id, call.method))
+                .collect::<Vec<_>>(),
+            vec![(0, Some("greet")), (1, Some("other"))]
+        );
+
+        drop(bye);
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        send_response(
+            &mut server_channel,
+            Response {
+                request_id: 0,
+                message: Ok("hello".into()),
+                causality: None,
+                caching: None,
+            },
+        );
+        assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+        assert_eq!(
+            tokio::runtime::current_thread::block_on_all(hi.boxed().compat()).unwrap(),
+            "hello"
+        );
+        assert!(channel.in_flight_calls().is_empty());
+    }
+
     #[test]
     fn stage_request_channel_dropped_doesnt_panic() {
         let (mut dispatch, mut channel, mut server_channel) = set_up();
@@ -870,6 +2419,8 @@ mod tests {
             Response {
                 request_id: 0,
                 message: Ok("hello".into()),
+                causality: None,
+                caching: None,
             },
         );
         tokio::runtime::current_thread::block_on_all(dispatch.boxed().compat()).unwrap();
@@ -912,6 +2463,202 @@ mod tests {
         assert!(dispatch.in_flight_requests().is_empty());
     }
 
+    #[test]
+    fn error_response_keeps_server_error_details() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(&noop_waker_ref());
+        let mut dispatch = Pin::new(&mut dispatch);
+
+        let mut resp = send_request(&mut channel, "hi");
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        send_response(
+            &mut server_channel,
+            Response {
+                request_id: 0,
+                message: Err(ServerError::new(io::ErrorKind::InvalidInput, "Bad name.")
+                    .with_detail("field", "name")
+                    .with_retry_after(Duration::from_secs(1))),
+                causality: None,
+                caching: None,
+            },
+        );
+        assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+
+        let e = match resp.poll_unpin(cx) {
+            Poll::Ready(Err(e)) => e,
+            _ => panic!("Expected an error response"),
+        };
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(e.to_string(), "Bad name.");
+        let e = e.get_ref().unwrap().downcast_ref::<ServerError>().unwrap();
+        assert_eq!(e.details["field"], "name");
+        assert_eq!(e.retry_after, Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn timeout_brings_deadline_forward() {
+        let (mut dispatch, channel, _server_channel) = set_up();
+        let mut channel = channel.with_timeout(Duration::from_secs(1));
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(&noop_waker_ref());
+
+        let _resp = send_request(&mut channel, "hi");
+        let request = dispatch.poll_next_request(cx).ready().unwrap();
+        assert!(request.ctx.deadline <= SystemTime::now() + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn pushback_holds_new_requests() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(&noop_waker_ref());
+        let mut dispatch = Pin::new(&mut dispatch);
+
+        let _resp = send_request(&mut channel, "hi");
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        send_response(
+            &mut server_channel,
+            Response {
+                request_id: 0,
+                message: Err(ServerError::new(io::ErrorKind::WouldBlock, "Throttled.")
+                    .with_retry_after(Duration::from_secs(60))),
+                causality: None,
+                caching: None,
+            },
+        );
+        assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+        assert!(dispatch.pushback.is_some());
+    }
+
+    #[test]
+    fn pushback_is_capped() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(&noop_waker_ref());
+        let mut dispatch = Pin::new(&mut dispatch);
+
+        let _resp = send_request(&mut channel, "hi");
+        assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+        send_response(
+            &mut server_channel,
+            Response {
+                request_id: 0,
+                message: Err(ServerError::new(io::ErrorKind::WouldBlock, "Throttled.")
+                    .with_retry_after(Duration::from_secs(u64::max_value()))),
+                causality: None,
+                caching: None,
+            },
+        );
+        assert!(dispatch.as_mut().pump_read(cx).ready().is_some());
+        let deadline = dispatch.pushback.as_ref().unwrap().get_ref().deadline();
+        assert!(deadline <= Instant::now() + Config::default().max_pushback);
+    }
+
+    #[test]
+    fn stage_requests_from_clones_round_robin() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(&noop_waker_ref());
+        let mut clone = channel.clone();
+
+        let _resp1 = send_request(&mut channel, "bulk1");
+        let _resp2 = send_request(&mut channel, "bulk2");
+        let _resp3 = send_request(&mut clone, "latency-sensitive");
+
+        let requests: Vec<_> = (0..3)
+            .map(|_| dispatch.poll_next_request(cx).ready().unwrap().request)
+            .collect();
+        assert_eq!(requests, vec!["bulk1", "latency-sensitive", "bulk2"]);
+    }
+
+    #[test]
+    fn shared_copies_take_turns_in_one_queue() {
+        let (mut dispatch, mut channel, _server_channel) = set_up();
+        let mut dispatch = Pin::new(&mut dispatch);
+        let cx = &mut Context::from_waker(&noop_waker_ref());
+        let mut shared = channel.share();
+        let mut clone = channel.clone();
+
+        let _resp1 = send_request(&mut channel, "bulk1");
+        let _resp2 = send_request(&mut shared, "shared");
+        let _resp3 = send_request(&mut channel, "bulk2");
+        let _resp4 = send_request(&mut clone, "clone");
+
+        let requests: Vec<_> = (0..4)
+            .map(|_| dispatch.poll_next_request(cx).ready().unwrap().request)
+            .collect();
+        assert_eq!(requests, vec!["bulk1", "clone", "shared", "bulk2"]);
+    }
+
+    #[test]
+    fn ready_until_dispatch_shuts_down() {
+        let (dispatch, mut channel, _server_channel) = set_up();
+        let cx = &mut Context::from_waker(&noop_waker_ref());
+
+        match channel.ready().poll_unpin(cx) {
+            Poll::Ready(result) => result.unwrap(),
+            Poll::Pending => panic!("Expected the channel to be ready."),
+        }
+
+        drop(dispatch);
+        match channel.ready().poll_unpin(cx) {
+            Poll::Ready(Err(e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+            other => panic!("Expected a connection reset, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn server_close_fails_calls_with_its_reason() {
+        let (mut dispatch, mut channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(&noop_waker_ref());
+
+        let resp = send_request(&mut channel, "hi");
+        {
+            let mut dispatch = Pin::new(&mut dispatch);
+            assert!(dispatch.as_mut().pump_write(cx).ready().is_some());
+            send_message(
+                &mut server_channel,
+                ServerMessage::Close {
+                    reason: CloseReason::Kicked,
+                },
+            );
+            match dispatch.poll(cx) {
+                Poll::Ready(result) => result.unwrap(),
+                Poll::Pending => panic!("Expected dispatch to end when the server closes."),
+            }
+        }
+        drop(dispatch);
+
+        let e = tokio::runtime::current_thread::block_on_all(resp.boxed().compat()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            ConnectionClosed::of(&e).unwrap().reason,
+            CloseReason::Kicked
+        );
+        let e = match channel.ready().poll_unpin(cx) {
+            Poll::Ready(Err(e)) => e,
+            other => panic!("Expected the channel to be closed, got {:?}", other),
+        };
+        assert_eq!(
+            ConnectionClosed::of(&e).unwrap().reason,
+            CloseReason::Kicked
+        );
+    }
+
+    #[test]
+    fn tells_server_when_closing() {
+        let (mut dispatch, channel, mut server_channel) = set_up();
+        let cx = &mut Context::from_waker(&noop_waker_ref());
+
+        drop(channel);
+        assert!(Pin::new(&mut dispatch).poll(cx).is_ready());
+        match server_channel.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(ClientMessage {
+                message: ClientMessageKind::Close { reason },
+                ..
+            }))) => assert_eq!(reason, CloseReason::Shutdown),
+            other => panic!("Expected a close message, got {:?}", other),
+        }
+    }
+
     #[test]
     fn stage_request_response_closed_skipped() {
         let (mut dispatch, mut channel, _server_channel) = set_up();
@@ -928,15 +2675,22 @@ mod tests {
     }
 
     fn set_up() -> (
-        RequestDispatch<String, String, UnboundedChannel<Response<String>, ClientMessage<String>>>,
+        RequestDispatch<
+            String,
+            String,
+            UnboundedChannel<ServerMessage<String>, ClientMessage<String>>,
+        >,
         Channel<String, String>,
-        UnboundedChannel<ClientMessage<String>, Response<String>>,
+        UnboundedChannel<ClientMessage<String>, ServerMessage<String>>,
     ) {
         let _ = env_logger::try_init();
 
-        let (to_dispatch, pending_requests) = mpsc::channel(1);
+        let (queues, pending_requests) = super::request_queues(1);
+        let to_dispatch = queues.new_queue();
         let (cancel_tx, canceled_requests) = mpsc::unbounded();
         let (client_channel, server_channel) = transport::channel::unbounded();
+        let connection_id = ConnectionId::random(&mut rand::thread_rng());
+        let server_close = ServerClose::default();
 
         let dispatch = RequestDispatch::<String, String, _> {
             transport: client_channel.fuse(),
@@ -945,14 +2699,31 @@ mod tests {
             in_flight_requests: FnvHashMap::default(),
             config: Config::default(),
             server_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0),
+            connection_id,
+            pushback: None,
+            concurrency_limit: None,
+            server_close: server_close.clone(),
+            close_sent: false,
+            stream_item: None,
+            bodies: vec![],
         };
 
         let cancellation = RequestCancellation(cancel_tx);
         let channel = Channel {
             to_dispatch,
+            queues,
             cancellation,
             next_request_id: Arc::new(AtomicU64::new(0)),
             server_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            connection_id,
+            in_flight_calls: Arc::default(),
+            call_names: None,
+            timeout: None,
+            tracer: None,
+            metrics: None,
+            clock: None,
+            server_close,
+            stream_item_buffer: 1,
         };
 
         (dispatch, channel, server_channel)
@@ -964,7 +2735,13 @@ mod tests {
     ) -> DispatchResponse<String> {
         tokio::runtime::current_thread::block_on_all(
             channel
-                .send(context::current(), request.to_string())
+                .send(
+                    context::current(),
+                    Metadata::new(),
+                    request.to_string(),
+                    None,
+                    None,
+                )
                 .boxed()
                 .compat(),
         )
@@ -972,10 +2749,17 @@ mod tests {
     }
 
     fn send_response(
-        channel: &mut UnboundedChannel<ClientMessage<String>, Response<String>>,
+        channel: &mut UnboundedChannel<ClientMessage<String>, ServerMessage<String>>,
         response: Response<String>,
     ) {
-        tokio::runtime::current_thread::block_on_all(channel.send(response).boxed().compat())
+        send_message(channel, ServerMessage::Response(response));
+    }
+
+    fn send_message(
+        channel: &mut UnboundedChannel<ClientMessage<String>, ServerMessage<String>>,
+        message: ServerMessage<String>,
+    ) {
+        tokio::runtime::current_thread::block_on_all(channel.send(message).boxed().compat())
             .unwrap();
     }
 
@@ -1009,4 +2793,140 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn call_handle() {
+        test_util::init();
+
+        let (client_channel, server) =
+            test_util::serve(Server::<String, String>::default(), |_ctx, request| {
+                future::ready(Ok(request.to_uppercase()))
+            });
+
+        let response = async {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            let mut handle = client.start_call(context::current(), "hi".into())?;
+            assert!(handle.try_recv().is_none());
+            await!(handle)
+        };
+
+        let response = test_util::run_future(future::join(server, response)).1;
+        assert_eq!(response.unwrap(), "HI");
+    }
+
+    #[test]
+    fn streamed_items_can_be_read_without_a_runtime() {
+        let _ = env_logger::try_init();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        crate::init_thread(runtime.executor().compat());
+
+        let (client_channel, server) = test_util::serve(
+            Server::<String, String>::default(),
+            |_ctx, request: String| async move {
+                let mut sender = server::streaming::sender::<String>().unwrap();
+                for item in request.split(' ') {
+                    await!(sender.send(item.to_string()))?;
+                }
+                if request.starts_with("stall") {
+                    await!(future::pending::<()>());
+                }
+                Ok("done".to_string())
+            },
+        );
+        crate::spawn(server).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        crate::spawn(async move {
+            let _ = tx.send(await!(client::new(
+                client::Config::default(),
+                client_channel
+            )));
+        })
+        .unwrap();
+        let mut client = rx.recv().unwrap().unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let call = |client: &mut client::Channel<String, String>, request: &str| {
+            let tx = tx.clone();
+            let stream = client.stream(context::current(), request.to_string());
+            crate::spawn(async move {
+                let _ = tx.send(await!(stream));
+            })
+            .unwrap();
+            rx.recv().unwrap().unwrap()
+        };
+
+        let items: Vec<_> = call(&mut client, "a b")
+            .blocking_iter(Duration::from_secs(5))
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(items, vec!["a", "b", "done"]);
+
+        let mut items = call(&mut client, "stall")
+            .blocking_iter(Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(items.next().unwrap().unwrap(), "stall");
+        assert_eq!(
+            items.next().unwrap().unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+        assert!(items.next().is_none());
+    }
+
+    #[test]
+    fn progress_is_read_apart_from_the_final_response() {
+        test_util::init();
+
+        let (canceled_tx, canceled_rx) = oneshot::channel::<Canceled>();
+        let canceled_tx = Arc::new(Mutex::new(Some(canceled_tx)));
+        let results = async move {
+            let (mut client, serving) = await!(transport::channel::loopback(
+                client::Config::default(),
+                Server::<String, String>::default(),
+                move |_ctx, request: String| {
+                    let canceled_tx = canceled_tx.clone();
+                    async move {
+                        let mut progress = server::streaming::sender::<String>().unwrap();
+                        if request == "forever" {
+                            let canceled = cancellation::canceled();
+                            let canceled_tx = canceled_tx.lock().unwrap().take().unwrap();
+                            let _ = canceled_tx.send(canceled.clone());
+                            await!(progress.send("started".into()))?;
+                            // Only the client can end this request.
+                            await!(canceled);
+                            return Ok(request);
+                        }
+                        for percent in &["50%", "100%"] {
+                            assert!(progress.try_send(percent.to_string())?);
+                        }
+                        Ok("built".to_string())
+                    }
+                },
+            ))?;
+
+            let stream = await!(client.stream(context::current(), "build".into()))?;
+            let (progress, response) = stream.into_progress();
+            let (progress, response) =
+                await!(future::join(progress.collect::<Vec<_>>(), response));
+
+            // Dropping the final response cancels the request, which the handler sees.
+            let stream = await!(client.stream(context::current(), "forever".into()))?;
+            let (mut started, forever) = stream.into_progress();
+            let started = await!(started.next());
+            let canceled = await!(canceled_rx).unwrap();
+            assert!(!canceled.is_canceled());
+            drop(forever);
+            await!(canceled);
+
+            drop(client);
+            await!(serving);
+            Ok::<_, io::Error>((progress, response?, started))
+        };
+
+        let (progress, response, started) =
+            test_util::run_future(results.unwrap_or_else(|e| panic!(e)));
+        assert_eq!(progress, vec!["50%", "100%"]);
+        assert_eq!(response, "built");
+        assert_eq!(started, Some("started".to_string()));
+    }
 }
@@ -0,0 +1,173 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Attaches credentials, e.g. auth tokens, to requests, and refreshes them when they expire.
+//!
+//! An [`Authenticated`] client attaches the current credentials to every request it sends. When
+//! the server rejects a request as unauthenticated, by failing it with
+//! [`PermissionDenied`](io::ErrorKind::PermissionDenied), the client asks its [`Credentials`] for
+//! new ones and retries the request once with them, so that callers don't each have to handle
+//! token expiry.
+
+use crate::{
+    client::{delivery::SendsOnce, Channel, Client},
+    context,
+};
+use futures::prelude::*;
+use log::debug;
+use std::{fmt, io, pin::Pin, sync::Arc};
+
+/// Provides the credentials attached to requests.
+pub trait Credentials<Req> {
+    /// Resolves once new credentials have been fetched.
+    type Refresh: Future<Output = io::Result<()>> + Send;
+
+    /// Returns `request` with the current credentials attached.
+    fn attach(&self, request: Req) -> Req;
+
+    /// Fetches new credentials, after the server rejected the current ones.
+    ///
+    /// Called once for every rejected request, so when many requests are rejected at once, it
+    /// is up to the implementation to fetch new credentials only once.
+    fn refresh(&self) -> Self::Refresh;
+}
+
+/// A [`Client`] that attaches [`Credentials`] to its requests.
+pub struct Authenticated<Req, Resp, P> {
+    inner: Channel<Req, Resp>,
+    credentials: Arc<P>,
+}
+
+impl<Req, Resp, P> Authenticated<Req, Resp, P> {
+    /// Returns a client that sends requests over `channel` with `credentials` attached.
+    pub fn new(channel: Channel<Req, Resp>, credentials: P) -> Self {
+        Authenticated {
+            inner: channel,
+            credentials: Arc::new(credentials),
+        }
+    }
+}
+
+impl<Req, Resp, P> Clone for Authenticated<Req, Resp, P> {
+    fn clone(&self) -> Self {
+        Authenticated {
+            inner: self.inner.clone(),
+            credentials: self.credentials.clone(),
+        }
+    }
+}
+
+impl<Req, Resp, P> fmt::Debug for Authenticated<Req, Resp, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Authenticated")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<'a, Req, Resp, P> Client<'a, Req> for Authenticated<Req, Resp, P>
+where
+    Req: Clone + Send + 'static,
+    Resp: Send + 'static,
+    P: Credentials<Req> + Send + Sync + 'static,
+{
+    type Response = Resp;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + 'a>>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        let channel = &mut self.inner;
+        let credentials = self.credentials.clone();
+        async move {
+            let attached = credentials.attach(request.clone());
+            match await!(channel.call(ctx, attached)) {
+                Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                    debug!(
+                        "[{}] Refreshing credentials after the request was rejected: {}",
+                        ctx.trace_id(),
+                        e
+                    );
+                    await!(credentials.refresh())?;
+                    await!(channel.call(ctx, credentials.attach(request)))
+                }
+                result => result,
+            }
+        }
+        .boxed()
+    }
+}
+
+// Calls are only resent after the server rejected them without handling them.
+impl<Req, Resp, P> SendsOnce for Authenticated<Req, Resp, P> {}
+
+#[cfg(test)]
+mod tests {
+    use super::{Authenticated, Credentials};
+    use crate::{
+        client::{self, Client},
+        context, test_util, Server,
+    };
+    use futures::future;
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    #[test]
+    fn rejected_requests_are_retried_with_refreshed_credentials() {
+        struct Token {
+            token: Mutex<String>,
+            refreshes: Arc<AtomicUsize>,
+        }
+
+        impl Credentials<String> for Token {
+            type Refresh = future::Ready<io::Result<()>>;
+
+            fn attach(&self, request: String) -> String {
+                format!("{} {}", self.token.lock().unwrap(), request)
+            }
+
+            fn refresh(&self) -> Self::Refresh {
+                self.refreshes.fetch_add(1, Ordering::SeqCst);
+                *self.token.lock().unwrap() = "fresh".into();
+                future::ready(Ok(()))
+            }
+        }
+
+        test_util::init();
+
+        let (client_channel, server) = test_util::serve(
+            Server::<String, String>::default(),
+            |_ctx, request: String| {
+                future::ready(if request.starts_with("fresh ") {
+                    Ok(request)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "Token expired.",
+                    ))
+                })
+            },
+        );
+
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let credentials = Token {
+            token: Mutex::new("expired".into()),
+            refreshes: refreshes.clone(),
+        };
+        let response = async {
+            let channel = await!(client::new(client::Config::default(), client_channel))?;
+            let mut client = Authenticated::new(channel, credentials);
+            await!(client.call(context::current(), "hi".into()))
+        };
+
+        let response = test_util::run_future(future::join(server, response)).1;
+        assert_eq!(response.unwrap(), "fresh hi");
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! The delivery semantics of a method: whether its requests may be sent more than once.
+//!
+//! A method that isn't idempotent, like charging a card, can be declared
+//! [at most once](Delivery::AtMostOnce), so that its requests are never resent: its client stub
+//! then only compiles over a client that [sends calls once](SendsOnce), which a
+//! [`Retry`](super::retry::Retry) client doesn't. A method declared
+//! [at least once](Delivery::AtLeastOnce) is idempotent, or deduplicated by the server, so its
+//! requests may be [resent](Resend) until one succeeds. Notifications are always at most once,
+//! since the client never learns whether one was handled.
+
+/// How many times a method's requests may be sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Delivery {
+    /// A request is sent at most once, so a call that fails may or may not have been handled.
+    AtMostOnce,
+    /// A request may be sent again after a call fails, so it may be handled more than once.
+    AtLeastOnce,
+}
+
+/// A [`Client`](super::Client) that sends each call at most once, never resending it after it
+/// fails, except when the server rejected it unhandled. Only such clients can call at-most-once
+/// methods.
+pub trait SendsOnce {}
+
+/// Implemented by clients that can call methods with the delivery semantics of `D`: any client
+/// for [`AtLeastOnce`] and [`Unspecified`] methods, but only those that [send calls once](SendsOnce)
+/// for [`AtMostOnce`] methods.
+pub trait Delivers<D> {}
+
+/// Marks methods declared [at most once](Delivery::AtMostOnce).
+#[derive(Debug)]
+pub enum AtMostOnce {}
+
+/// Marks methods declared [at least once](Delivery::AtLeastOnce).
+#[derive(Debug)]
+pub enum AtLeastOnce {}
+
+/// Marks methods without declared delivery semantics.
+#[derive(Debug)]
+pub enum Unspecified {}
+
+impl<C: SendsOnce> Delivers<AtMostOnce> for C {}
+impl<C> Delivers<AtLeastOnce> for C {}
+impl<C> Delivers<Unspecified> for C {}
+
+/// A request that may be sent again, like the requests of [at-least-once](Delivery::AtLeastOnce)
+/// methods, which [`Retry`](super::retry::Retry) clients resend.
+pub trait Resend: Sized {
+    /// Returns a copy of the request to send again, or None if it mustn't be resent.
+    fn resend(&self) -> Option<Self>;
+}
+
+impl<T: Clone> Resend for T {
+    fn resend(&self) -> Option<Self> {
+        Some(self.clone())
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Finds the servers of a service, as they come and go.
+//!
+//! A [`Resolver`] turns the name of a service into a stream of the addresses of its servers,
+//! which a [`Balancer`](crate::client::reconnect::Balancer) made with
+//! [`resolve`](crate::client::reconnect::Balancer::resolve) follows, or into the addresses to
+//! connect to once, with [`resolve_once`](Resolver::resolve_once). [`Dns`] resolves host names,
+//! caching their addresses for as long as their records' TTL, through a pluggable [`Lookup`], by
+//! default the [`System`] resolver; lookups through a DNS client library, e.g. trust-dns, can
+//! implement [`Lookup`] to report the TTLs, which the system's resolver doesn't. Resolvers backed
+//! by a service registry, e.g. Consul or etcd, can implement [`Resolver`] by watching the
+//! registry.
+
+use futures::{
+    channel::oneshot,
+    compat::Future01CompatExt,
+    future::{self, BoxFuture},
+    prelude::*,
+    stream::BoxStream,
+};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio_timer::Delay;
+
+/// Resolves the names of services to the addresses of their servers.
+pub trait Resolver: Send + Sync + 'static {
+    /// Returns a stream that yields every address `name` resolves to, first as soon as `name` is
+    /// resolved, and then whenever the addresses change, or an error when resolving fails.
+    fn resolve(&self, name: &str) -> BoxStream<'static, io::Result<Vec<SocketAddr>>>;
+
+    /// Returns every address `name` resolves to now, e.g. to connect to one of them.
+    fn resolve_once(&self, name: &str) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        let name = name.to_string();
+        self.resolve(&name)
+            .into_future()
+            .map(move |(addrs, _)| {
+                addrs.unwrap_or_else(|| {
+                    Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Could not resolve {}.", name),
+                    ))
+                })
+            })
+            .boxed()
+    }
+}
+
+/// Looks up the addresses of host names.
+pub trait Lookup: Send + Sync + 'static {
+    /// Returns the addresses `name` resolves to, and how long they may be cached for, if known.
+    fn lookup(
+        &self,
+        name: &str,
+    ) -> BoxFuture<'static, io::Result<(Vec<SocketAddr>, Option<Duration>)>>;
+}
+
+/// Looks up `host:port` names through the system's resolver, which doesn't report TTLs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct System;
+
+impl Lookup for System {
+    fn lookup(
+        &self,
+        name: &str,
+    ) -> BoxFuture<'static, io::Result<(Vec<SocketAddr>, Option<Duration>)>> {
+        lookup(name.to_string())
+            .map_ok(|addrs| (addrs, None))
+            .boxed()
+    }
+}
+
+/// Resolves host names through a [`Lookup`], caching their addresses until their TTL runs out,
+/// and looking them up again then. The clones of a resolver share a cache.
+#[non_exhaustive]
+#[derive(Clone)]
+pub struct Dns {
+    /// How long to cache the addresses of records with no known TTL, e.g. all those looked up
+    /// through the [`System`] resolver.
+    pub interval: Duration,
+    /// The shortest time to cache addresses for, however short their TTL, so that records with a
+    /// TTL of zero aren't looked up in a loop.
+    pub min_ttl: Duration,
+    lookup: Arc<dyn Lookup>,
+    /// The addresses of each name looked up, and when they expire.
+    cache: Arc<Mutex<HashMap<String, (Vec<SocketAddr>, Instant)>>>,
+}
+
+impl Default for Dns {
+    fn default() -> Self {
+        Dns::with_lookup(System)
+    }
+}
+
+impl fmt::Debug for Dns {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Dns")
+            .field("interval", &self.interval)
+            .field("min_ttl", &self.min_ttl)
+            .finish()
+    }
+}
+
+impl Dns {
+    /// Returns a resolver that looks up names through `lookup`, instead of the system's resolver.
+    pub fn with_lookup<L: Lookup>(lookup: L) -> Self {
+        Dns {
+            interval: Duration::from_secs(30),
+            min_ttl: Duration::from_secs(1),
+            lookup: Arc::new(lookup),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns the addresses of `name` and when they expire, from the cache if they haven't
+    /// expired yet, and otherwise looked up again.
+    fn cached(&self, name: &str) -> impl Future<Output = io::Result<(Vec<SocketAddr>, Instant)>> {
+        if let Some((addrs, expires)) = self.cache.lock().unwrap().get(name) {
+            if *expires > Instant::now() {
+                return future::Either::Left(future::ready(Ok((addrs.clone(), *expires))));
+            }
+        }
+        let lookup = self.lookup.lookup(name);
+        let (interval, min_ttl) = (self.interval, self.min_ttl);
+        let cache = self.cache.clone();
+        let name = name.to_string();
+        future::Either::Right(async move {
+            let (mut addrs, ttl) = await!(lookup)?;
+            addrs.sort();
+            addrs.dedup();
+            let expires = Instant::now() + ttl.map_or(interval, |ttl| ttl.max(min_ttl));
+            cache.lock().unwrap().insert(name, (addrs.clone(), expires));
+            Ok((addrs, expires))
+        })
+    }
+}
+
+impl Resolver for Dns {
+    fn resolve(&self, name: &str) -> BoxStream<'static, io::Result<Vec<SocketAddr>>> {
+        let state = Lookups {
+            dns: self.clone(),
+            name: name.to_string(),
+            last: None,
+            expires: None,
+        };
+        stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(expires) = state.expires {
+                    if let Err(e) = await!(Delay::new(expires).compat()) {
+                        return Some((Err(io::Error::new(io::ErrorKind::Other, e)), state));
+                    }
+                }
+                match await!(state.dns.cached(&state.name)) {
+                    Ok((addrs, expires)) => {
+                        state.expires = Some(expires);
+                        if state.last.as_ref() != Some(&addrs) {
+                            state.last = Some(addrs.clone());
+                            return Some((Ok(addrs), state));
+                        }
+                    }
+                    Err(e) => {
+                        // Looks up again after the usual interval.
+                        state.expires = Some(Instant::now() + state.dns.interval);
+                        return Some((Err(e), state));
+                    }
+                }
+            }
+        })
+        .boxed()
+    }
+
+    fn resolve_once(&self, name: &str) -> BoxFuture<'static, io::Result<Vec<SocketAddr>>> {
+        self.cached(name).map_ok(|(addrs, _)| addrs).boxed()
+    }
+}
+
+/// The state of a stream of DNS lookups.
+struct Lookups {
+    dns: Dns,
+    name: String,
+    /// The addresses last yielded.
+    last: Option<Vec<SocketAddr>>,
+    /// When the addresses last looked up expire, if any were.
+    expires: Option<Instant>,
+}
+
+/// Looks up `name` on another thread, since the system's resolver blocks.
+async fn lookup(name: String) -> io::Result<Vec<SocketAddr>> {
+    let (tx, rx) = oneshot::channel();
+    thread::Builder::new()
+        .name("tarpc-dns".into())
+        .spawn(move || {
+            let _ = tx.send(name.to_socket_addrs().map(Iterator::collect));
+        })?;
+    await!(rx).unwrap_or_else(|oneshot::Canceled| {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "DNS lookup thread panicked.",
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Dns, Lookup, Resolver};
+    use crate::{
+        client::{
+            reconnect::{self, Balancer, Balancing},
+            Client,
+        },
+        context,
+        server::Handler,
+        test_util, transport, Server,
+    };
+    use futures::{
+        channel::mpsc,
+        compat::Future01CompatExt,
+        executor::block_on,
+        future::{self, BoxFuture},
+        prelude::*,
+        stream::BoxStream,
+    };
+    use std::{
+        io,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+    use tokio_timer::Delay;
+
+    #[test]
+    fn dns_resolves_ip_addresses_to_themselves() {
+        let mut addrs = Dns::default().resolve("127.0.0.1:8080");
+        let addrs = block_on(addrs.next()).unwrap().unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:8080".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn dns_caches_addresses_for_their_ttl() {
+        /// Resolves every name to the same address, with a TTL of 50 milliseconds.
+        struct Counted(Arc<AtomicUsize>);
+
+        impl Lookup for Counted {
+            fn lookup(
+                &self,
+                _name: &str,
+            ) -> BoxFuture<'static, io::Result<(Vec<SocketAddr>, Option<Duration>)>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+                future::ready(Ok((vec![addr], Some(Duration::from_millis(50))))).boxed()
+            }
+        }
+
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let mut dns = Dns::with_lookup(Counted(lookups.clone()));
+        dns.min_ttl = Duration::from_millis(0);
+        let first = block_on(dns.resolve_once("service")).unwrap();
+        let cached = block_on(dns.clone().resolve_once("service")).unwrap();
+        let cached_lookups = lookups.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        let expired = block_on(dns.resolve_once("service")).unwrap();
+
+        assert_eq!(first, cached);
+        assert_eq!(first, expired);
+        assert_eq!(cached_lookups, 1);
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn resolved_balancer_follows_servers() {
+        test_util::init();
+
+        /// Resolves every name to the address sets sent on a channel.
+        struct Resolved(Mutex<Option<BoxStream<'static, io::Result<Vec<SocketAddr>>>>>);
+
+        impl Resolver for Resolved {
+            fn resolve(&self, _name: &str) -> BoxStream<'static, io::Result<Vec<SocketAddr>>> {
+                self.0.lock().unwrap().take().unwrap()
+            }
+        }
+
+        let (resolved_tx, resolved_rx) = mpsc::unbounded();
+        let consumed = Arc::new(AtomicUsize::new(0));
+        let resolver = Resolved(Mutex::new(Some(
+            resolved_rx
+                .inspect({
+                    let consumed = consumed.clone();
+                    move |_| {
+                        consumed.fetch_add(1, Ordering::SeqCst);
+                    }
+                })
+                .boxed(),
+        )));
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+
+        let (server_channels_tx, server_channels_rx) = mpsc::unbounded();
+        let server_channels_tx = Arc::new(Mutex::new(server_channels_tx));
+        let server = Server::<String, String>::default()
+            .incoming(server_channels_rx.map(Ok))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+        let connect = move |_addr| {
+            let server_channels_tx = server_channels_tx.clone();
+            move || {
+                let (client_channel, server_channel) = transport::channel::unbounded();
+                let _ = server_channels_tx
+                    .lock()
+                    .unwrap()
+                    .unbounded_send(server_channel);
+                future::ready(Ok(client_channel))
+            }
+        };
+
+        let response = async move {
+            resolved_tx.unbounded_send(Ok(vec![addr(1)])).unwrap();
+            let mut client = await!(Balancer::resolve(
+                reconnect::Config::default(),
+                Balancing::RoundRobin,
+                &resolver,
+                "service",
+                connect,
+            ))?;
+            assert_eq!(client.addrs(), vec![addr(1)]);
+
+            let until = |consumed_sets| {
+                let consumed = consumed.clone();
+                async move {
+                    while consumed.load(Ordering::SeqCst) < consumed_sets {
+                        await!(Delay::new(Instant::now() + Duration::from_millis(1)).compat())
+                            .unwrap();
+                    }
+                }
+            };
+
+            resolved_tx
+                .unbounded_send(Ok(vec![addr(1), addr(2)]))
+                .unwrap();
+            resolved_tx.unbounded_send(Ok(vec![])).unwrap();
+            resolved_tx
+                .unbounded_send(Err(io::Error::new(io::ErrorKind::Other, "Unreachable.")))
+                .unwrap();
+            // The error is consumed only after the empty set is handled.
+            await!(until(4));
+            assert_eq!(client.addrs(), vec![addr(1), addr(2)]);
+
+            resolved_tx.unbounded_send(Ok(vec![addr(2)])).unwrap();
+            while client.addrs() != vec![addr(2)] {
+                await!(Delay::new(Instant::now() + Duration::from_millis(1)).compat()).unwrap();
+            }
+            while !client.is_connected() {
+                await!(Delay::new(Instant::now() + Duration::from_millis(1)).compat()).unwrap();
+            }
+            await!(client.call(context::current(), "hi".into()))
+        };
+
+        let response =
+            test_util::run_future(future::join(server, response.unwrap_or_else(|e| panic!(e)))).1;
+        assert_eq!(response, "hi");
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Calls a request handler in the same process directly, without a transport or a server.
+//!
+//! A [`Local`] client hands each typed request straight to the handler, and polls the handler's
+//! future as the call's future, so nothing is spawned, queued, or encoded. Calls fail the same way
+//! as calls over a connection: with the handler's error, as a
+//! [`ServerError`](crate::ServerError), with [`TimedOut`](io::ErrorKind::TimedOut) past their
+//! deadline, and with an [internal](crate::ErrorCode::Internal) error if the handler panics.
+//! Dropping a call cancels it.
+//!
+//! Server-side limits and policies, like in-flight request limits and admission control, don't
+//! apply to local calls, since no server is involved.
+
+use crate::{
+    client::{delivery::SendsOnce, Client},
+    context,
+    server::{self, cancellation::Cancelable},
+    util::{deadline_compat::Deadline, AsDuration},
+};
+use futures::{future::BoxFuture, prelude::*};
+use log::error;
+use std::{fmt, io, panic::AssertUnwindSafe, time::Instant};
+
+/// A client that calls a request handler directly.
+#[derive(Clone)]
+pub struct Local<F> {
+    handler: F,
+}
+
+impl<F> fmt::Debug for Local<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Local").finish()
+    }
+}
+
+impl<F> Local<F> {
+    /// Returns a client that calls `handler`, e.g. one returned by a service's `serve` fn.
+    pub fn new(handler: F) -> Self {
+        Local { handler }
+    }
+}
+
+impl<F> SendsOnce for Local<F> {}
+
+impl<'a, Req, Resp, F, Fut> Client<'a, Req> for Local<F>
+where
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+{
+    type Response = Resp;
+    type Future = BoxFuture<'a, io::Result<Resp>>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        let response = self.handler.clone()(ctx, request);
+        let response = context::scope(ctx, Cancelable::new(response));
+        let deadline = Instant::now() + ctx.deadline.as_duration();
+        async move {
+            let response = AssertUnwindSafe(Deadline::new(response, deadline)).catch_unwind();
+            match await!(response) {
+                Ok(Ok(response)) => Ok(response),
+                Ok(Err(ref e)) if e.is_elapsed() => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Client dropped expired request.",
+                )),
+                Ok(Err(e)) => match e.into_inner() {
+                    Some(e) => Err(server::handler_error(e).into()),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "Cancelling request because an expiration could not be set.",
+                    )),
+                },
+                Err(panic) => {
+                    error!(
+                        "[{}] Request handler panicked: {}",
+                        ctx.trace_id(),
+                        server::panic_message(&*panic)
+                    );
+                    Err(server::panic_error().into())
+                }
+            }
+        }
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Local;
+    use crate::{context, Client, ErrorCode, ServerError};
+    use futures::{executor::block_on, future};
+    use std::io;
+
+    #[test]
+    fn calls_handler_directly() {
+        let mut client = Local::new(|_, request: String| {
+            future::ready(Ok::<_, io::Error>(format!("{}!", request)))
+        });
+        let response = block_on(client.call(context::current(), "hi".into()));
+        assert_eq!(response.unwrap(), "hi!");
+    }
+
+    #[test]
+    fn fails_like_a_remote_call() {
+        let mut client = Local::new(|_, request: &'static str| {
+            future::lazy(move |_| match request {
+                "panic" => panic!("Handler bug."),
+                "invalid" => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid.")),
+                _ => Ok(request),
+            })
+        });
+
+        let e = block_on(client.call(context::current(), "invalid")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(ServerError::of(&e).unwrap().code, ErrorCode::BadRequest);
+
+        let e = block_on(client.call(context::current(), "panic")).unwrap_err();
+        assert_eq!(ServerError::of(&e).unwrap().code, ErrorCode::Internal);
+    }
+}
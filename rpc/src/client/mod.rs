@@ -6,17 +6,35 @@
 
 //! Provides a client that connects to a server and sends multiplexed requests.
 
-use crate::{context, ClientMessage, Response, Transport};
-use futures::prelude::*;
-use log::warn;
+use crate::{context, ClientMessage, ServerMessage, Transport};
+use futures::{
+    prelude::*,
+    ready,
+    task::{Context, Poll},
+};
+use log::{debug, warn};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
 use std::{
     io,
     net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    time::Duration,
 };
 
+pub mod cache;
+pub mod causality;
 /// Provides a [`Client`] backed by a transport.
 pub mod channel;
-pub use self::channel::Channel;
+pub use self::channel::{
+    BlockingIter, BodyStream, Channel, FinalResponse, InFlightCall, Progress, ResponseStream,
+};
+pub mod credentials;
+pub mod delivery;
+pub mod discovery;
+pub mod local;
+pub mod reconnect;
+pub mod registry;
+pub mod retry;
 
 /// Sends multiplexed requests to, and receives responses from, a server.
 pub trait Client<'a, Req> {
@@ -43,6 +61,16 @@ pub trait Client<'a, Req> {
         MapResponse { inner: self, f }
     }
 
+    /// Returns a Client that applies a fallible post-processing function to the returned response,
+    /// e.g. to validate it. If the function returns an error, the call fails with that error.
+    fn try_map_response<F, R>(self, f: F) -> TryMapResponse<Self, F>
+    where
+        F: FnMut(Self::Response) -> io::Result<R>,
+        Self: Sized,
+    {
+        TryMapResponse { inner: self, f }
+    }
+
     /// Returns a Client that applies a pre-processing function to the request.
     fn with_request<F, Req2>(self, f: F) -> WithRequest<Self, F>
     where
@@ -51,6 +79,87 @@ pub trait Client<'a, Req> {
     {
         WithRequest { inner: self, f }
     }
+
+    /// Returns a Client that also sends a random `fraction` of its requests to `mirror`, e.g. to
+    /// try a new version of a service on live traffic. Responses from `mirror` are ignored, and
+    /// calls complete as soon as this client's response arrives.
+    fn mirror<Resp2>(self, mirror: Channel<Req, Resp2>, fraction: f64) -> Mirror<Self, Req, Resp2>
+    where
+        Self: Sized,
+    {
+        Mirror {
+            inner: self,
+            mirror,
+            fraction,
+        }
+    }
+}
+
+/// Sends one-way requests, which servers handle without responding, to a server. Notifications
+/// are delivered [at most once](delivery::Delivery::AtMostOnce), so a notifying client mustn't
+/// resend them, and services' notify methods need a client that [sends once](delivery::SendsOnce).
+pub trait Notify<'a, Req> {
+    /// The future returned by [`notify`](Notify::notify).
+    type Future: Future<Output = io::Result<()>> + 'a;
+
+    /// Initiates a one-way request, sending it to the dispatch task.
+    ///
+    /// Returns a [`Future`] that resolves once the request is successfully enqueued. No response
+    /// is ever received for it.
+    ///
+    /// [`Future`]: futures::Future
+    fn notify(&'a mut self, ctx: context::Context, request: Req) -> Self::Future;
+}
+
+/// Sends requests whose responses servers [stream](crate::server::streaming) back.
+pub trait Streaming<'a, Req> {
+    /// The type of the final response and of the items streamed ahead of it.
+    type Response;
+
+    /// The future returned by [`stream`](Streaming::stream).
+    type Future: Future<Output = io::Result<ResponseStream<Self::Response>>> + 'a;
+
+    /// Initiates a request whose response is streamed, sending it to the dispatch task.
+    ///
+    /// Returns a [`Future`] that resolves to the stream of the response once the request is
+    /// successfully enqueued.
+    ///
+    /// [`Future`]: futures::Future
+    fn stream(&'a mut self, ctx: context::Context, request: Req) -> Self::Future;
+}
+
+/// Sends requests whose bodies are streamed to servers after them, for handlers to read with
+/// [`streaming::body`](crate::server::streaming::body).
+pub trait Uploading<'a, Req, S> {
+    /// The type of the final response and of any items streamed ahead of it.
+    type Response;
+
+    /// The future returned by [`call_with_body`](Uploading::call_with_body).
+    type Future: Future<Output = io::Result<Self::Response>> + 'a;
+
+    /// The future returned by [`stream_with_body`](Uploading::stream_with_body).
+    type StreamFuture: Future<Output = io::Result<BodyStream<Req, Self::Response, S>>> + 'a;
+
+    /// Initiates a request whose body is the items of `body`, sending it to the dispatch task.
+    ///
+    /// Returns a [`Future`] that forwards the body while it resolves to the response.
+    ///
+    /// [`Future`]: futures::Future
+    fn call_with_body(&'a mut self, ctx: context::Context, request: Req, body: S) -> Self::Future;
+
+    /// Initiates a request whose body is the items of `body` and whose response is streamed,
+    /// sending it to the dispatch task.
+    ///
+    /// Returns a [`Future`] that resolves to the stream of the response once the request is
+    /// successfully enqueued. The body is forwarded while the stream is polled.
+    ///
+    /// [`Future`]: futures::Future
+    fn stream_with_body(
+        &'a mut self,
+        ctx: context::Context,
+        request: Req,
+        body: S,
+    ) -> Self::StreamFuture;
 }
 
 /// A Client that applies a function to the returned response.
@@ -73,6 +182,129 @@ where
     }
 }
 
+impl<'a, C, F, Req> Notify<'a, Req> for MapResponse<C, F>
+where
+    C: Notify<'a, Req>,
+{
+    type Future = C::Future;
+
+    fn notify(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        self.inner.notify(ctx, request)
+    }
+}
+
+impl<C: delivery::SendsOnce, F> delivery::SendsOnce for MapResponse<C, F> {}
+
+/// A Client that applies a fallible function to the returned response.
+#[derive(Clone, Debug)]
+pub struct TryMapResponse<C, F> {
+    inner: C,
+    f: F,
+}
+
+impl<'a, C, F, Req, Resp, Resp2> Client<'a, Req> for TryMapResponse<C, F>
+where
+    C: Client<'a, Req, Response = Resp>,
+    F: FnMut(Resp) -> io::Result<Resp2> + 'a,
+{
+    type Response = Resp2;
+    type Future = TryMapResponseFuture<'a, <C as Client<'a, Req>>::Future, F>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        TryMapResponseFuture {
+            fut: self.inner.call(ctx, request),
+            f: &mut self.f,
+        }
+    }
+}
+
+impl<'a, C, F, Req> Notify<'a, Req> for TryMapResponse<C, F>
+where
+    C: Notify<'a, Req>,
+{
+    type Future = C::Future;
+
+    fn notify(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        self.inner.notify(ctx, request)
+    }
+}
+
+impl<C: delivery::SendsOnce, F> delivery::SendsOnce for TryMapResponse<C, F> {}
+
+/// A future returned by [`TryMapResponse`] that resolves to the post-processed response.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct TryMapResponseFuture<'a, Fut, F> {
+    fut: Fut,
+    f: &'a mut F,
+}
+
+impl<'a, Fut, F> TryMapResponseFuture<'a, Fut, F> {
+    unsafe_pinned!(fut: Fut);
+    unsafe_unpinned!(f: &'a mut F);
+}
+
+impl<'a, Fut, F, Resp, Resp2> Future for TryMapResponseFuture<'a, Fut, F>
+where
+    Fut: Future<Output = io::Result<Resp>>,
+    F: FnMut(Resp) -> io::Result<Resp2>,
+{
+    type Output = io::Result<Resp2>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp2>> {
+        let response = ready!(self.as_mut().fut().poll(cx))?;
+        Poll::Ready((self.as_mut().f())(response))
+    }
+}
+
+/// A Client that sends a share of its requests to a mirror as well.
+#[derive(Debug)]
+pub struct Mirror<C, Req, Resp2> {
+    inner: C,
+    mirror: Channel<Req, Resp2>,
+    fraction: f64,
+}
+
+impl<C: Clone, Req, Resp2> Clone for Mirror<C, Req, Resp2> {
+    fn clone(&self) -> Self {
+        Mirror {
+            inner: self.inner.clone(),
+            mirror: self.mirror.clone(),
+            fraction: self.fraction,
+        }
+    }
+}
+
+impl<'a, C, Req, Resp2> Client<'a, Req> for Mirror<C, Req, Resp2>
+where
+    C: Client<'a, Req>,
+    Req: Clone + Send + 'static,
+    Resp2: Send + 'static,
+{
+    type Response = C::Response;
+    type Future = C::Future;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        if rand::random::<f64>() < self.fraction {
+            let mut mirror = self.mirror.clone();
+            let mirrored = request.clone();
+            let spawned = crate::spawn(async move {
+                if let Err(e) = await!(mirror.call(ctx, mirrored)) {
+                    debug!("[{}] Mirrored request failed: {}", ctx.trace_id(), e);
+                }
+            });
+            if let Err(e) = spawned {
+                warn!(
+                    "[{}] Could not spawn mirrored request: {:?}",
+                    ctx.trace_id(),
+                    e
+                );
+            }
+        }
+        self.inner.call(ctx, request)
+    }
+}
+
 /// A Client that applies a pre-processing function to the request.
 #[derive(Clone, Debug)]
 pub struct WithRequest<C, F> {
@@ -93,6 +325,35 @@ where
     }
 }
 
+impl<'a, C, F, Req, Req2> Notify<'a, Req2> for WithRequest<C, F>
+where
+    C: Notify<'a, Req>,
+    F: FnMut(Req2) -> Req,
+{
+    type Future = <C as Notify<'a, Req>>::Future;
+
+    fn notify(&'a mut self, ctx: context::Context, request: Req2) -> Self::Future {
+        self.inner.notify(ctx, (self.f)(request))
+    }
+}
+
+impl<'a, C, F, Req, Req2> Streaming<'a, Req2> for WithRequest<C, F>
+where
+    C: Streaming<'a, Req>,
+    F: FnMut(Req2) -> Req,
+{
+    type Response = C::Response;
+    type Future = <C as Streaming<'a, Req>>::Future;
+
+    fn stream(&'a mut self, ctx: context::Context, request: Req2) -> Self::Future {
+        self.inner.stream(ctx, (self.f)(request))
+    }
+}
+
+impl<C: delivery::SendsOnce, F> delivery::SendsOnce for WithRequest<C, F> {}
+
+impl<Req, Resp> delivery::SendsOnce for Channel<Req, Resp> {}
+
 impl<'a, Req, Resp> Client<'a, Req> for Channel<Req, Resp>
 where
     Req: 'a,
@@ -106,9 +367,71 @@ where
     }
 }
 
+impl<'a, Req, Resp> Notify<'a, Req> for Channel<Req, Resp>
+where
+    Req: 'a,
+    Resp: 'a,
+{
+    type Future = channel::Notification<'a, Req, Resp>;
+
+    fn notify(
+        &'a mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> channel::Notification<'a, Req, Resp> {
+        self.notify(ctx, request)
+    }
+}
+
+impl<'a, Req, Resp> Streaming<'a, Req> for Channel<Req, Resp>
+where
+    Req: 'a,
+    Resp: 'a,
+{
+    type Response = Resp;
+    type Future = channel::StreamCall<'a, Req, Resp>;
+
+    fn stream(
+        &'a mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> channel::StreamCall<'a, Req, Resp> {
+        self.stream(ctx, request)
+    }
+}
+
+impl<'a, Req, Resp, S> Uploading<'a, Req, S> for Channel<Req, Resp>
+where
+    Req: 'a,
+    Resp: 'a,
+    S: Stream<Item = Req> + 'a,
+{
+    type Response = Resp;
+    type Future = channel::BodyCall<'a, Req, Resp, S>;
+    type StreamFuture = channel::BodyStreamCall<'a, Req, Resp, S>;
+
+    fn call_with_body(
+        &'a mut self,
+        ctx: context::Context,
+        request: Req,
+        body: S,
+    ) -> channel::BodyCall<'a, Req, Resp, S> {
+        self.call_with_body(ctx, request, body)
+    }
+
+    fn stream_with_body(
+        &'a mut self,
+        ctx: context::Context,
+        request: Req,
+        body: S,
+    ) -> channel::BodyStreamCall<'a, Req, Resp, S> {
+        self.stream_with_body(ctx, request, body)
+    }
+}
+
 /// Settings that control the behavior of the client.
 #[non_exhaustive]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Config {
     /// The number of requests that can be in flight at once.
     /// `max_in_flight_requests` controls the size of the map used by the client
@@ -116,8 +439,35 @@ pub struct Config {
     pub max_in_flight_requests: usize,
     /// The number of requests that can be buffered client-side before being sent.
     /// `pending_requests_buffer` controls the size of the channel clients use
-    /// to communicate with the request dispatch task.
+    /// to communicate with the request dispatch task. Each clone of a client has its own channel,
+    /// and the dispatch task takes requests from the channels in turn.
+    ///
+    /// The client therefore buffers up to `pending_request_buffer` requests per live clone, plus
+    /// one per call waiting to be buffered. Clients that wrap a channel, like
+    /// [`Retry`](retry::Retry) and [`Reconnecting`](reconnect::Reconnecting), share one channel
+    /// across their calls, so however many calls they make, they count as a single clone.
     pub pending_request_buffer: usize,
+    /// Whether to stop sending new requests for as long as the server asks, when a response
+    /// carries a [`retry_after`](crate::ServerError::retry_after) hint. Requests are still
+    /// queued, up to `pending_request_buffer`, while held back.
+    pub honor_pushback: bool,
+    /// The longest the client holds back new requests for a single pushback hint. Longer hints,
+    /// e.g. from a misbehaving server, are cut down to this.
+    pub max_pushback: Duration,
+    /// Whether to limit requests in flight to a limit that adapts to response latency, at most
+    /// `max_in_flight_requests`. The limit grows while latency holds steady and shrinks when it
+    /// rises, so that requests queue on the client rather than on an overloaded server.
+    pub adaptive_concurrency: bool,
+    /// Whether a response the transport couldn't decode, but could tell the request ID of, fails
+    /// only that request, with the connection kept open. If false, the connection is closed, as it
+    /// is for any other read error.
+    pub forgive_undecodable_responses: bool,
+    /// The number of items of a [streamed](Channel::stream) response that are buffered until
+    /// they're read. While a stream's buffer is full, no more is read off the connection, which
+    /// holds up the server, but also the connection's other responses, so streams should be read
+    /// promptly, or dropped. Also the number of items of a request's
+    /// [streamed body](Channel::call_with_body) that are buffered until they're written.
+    pub stream_item_buffer: usize,
 }
 
 impl Default for Config {
@@ -125,6 +475,11 @@ impl Default for Config {
         Config {
             max_in_flight_requests: 1_000,
             pending_request_buffer: 100,
+            honor_pushback: true,
+            max_pushback: Duration::from_secs(60),
+            adaptive_concurrency: false,
+            forgive_undecodable_responses: true,
+            stream_item_buffer: 100,
         }
     }
 }
@@ -137,7 +492,7 @@ pub async fn new<Req, Resp, T>(config: Config, transport: T) -> io::Result<Chann
 where
     Req: Send + 'static,
     Resp: Send + 'static,
-    T: Transport<Item = Response<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
 {
     let server_addr = transport.peer_addr().unwrap_or_else(|e| {
         warn!(
@@ -149,3 +504,56 @@ where
 
     Ok(await!(channel::spawn(config, transport, server_addr))?)
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        client::{self, Client},
+        context, test_util, Server,
+    };
+    use futures::{future, prelude::*};
+    use std::io;
+
+    #[test]
+    fn mirror_requests() {
+        test_util::init();
+
+        let (client_channel, server) =
+            test_util::serve(Server::<String, String>::default(), |_ctx, request| {
+                future::ready(Ok(request))
+            });
+
+        let (mirrored_tx, mirrored_rx) = futures::channel::mpsc::unbounded();
+        let (mirror_client_channel, mirror_server) = test_util::serve(
+            Server::<String, String>::default(),
+            move |_ctx, request: String| {
+                mirrored_tx.unbounded_send(request.clone()).unwrap();
+                future::ready(Ok(request.to_uppercase()))
+            },
+        );
+
+        let responses = async {
+            let client = await!(client::new(client::Config::default(), client_channel))?;
+            let mirror = await!(client::new(
+                client::Config::default(),
+                mirror_client_channel
+            ))?;
+
+            let mut client = client.mirror(mirror, 1.0);
+            let response = await!(client.call(context::current(), "hi".into()))?;
+            drop(client);
+            let mirrored = await!(mirrored_rx.collect::<Vec<_>>());
+
+            Ok::<_, io::Error>((response, mirrored))
+        };
+
+        let (response, mirrored) = test_util::run_future(future::join3(
+            server,
+            mirror_server,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .2;
+        assert_eq!(response, "hi");
+        assert_eq!(mirrored, vec!["hi".to_string()]);
+    }
+}
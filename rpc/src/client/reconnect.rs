@@ -0,0 +1,1678 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Reconnects to a server when the connection breaks.
+//!
+//! A [`Reconnecting`] client connects in the background as soon as it's created, and again
+//! whenever its connection shuts down, backing off between failed attempts. Calls made while it
+//! isn't connected wait for the next attempt, and fail if that attempt fails, so that callers
+//! aren't held up for longer than one attempt. Calls that were in flight when the connection broke
+//! fail and are not retried, because the server may have handled them.
+//!
+//! If configured to, a client also checks its connection periodically, so that it notices a
+//! connection the server closed, and starts reconnecting, before the next call fails.
+//!
+//! Clients connect as soon as they're created, and a client's `ready` future resolves once it's
+//! connected, so that a client can warm up before it takes traffic, rather than its first calls
+//! waiting on connections.
+//!
+//! Once the server says it's draining the connection, e.g. because it's shutting down, the client
+//! reconnects before it makes its next call, while the calls in flight over the drained
+//! connection finish there. So does a connection that outlived the configured
+//! [`max_lifetime`](Config::max_lifetime), so that long-lived clients rebalance over servers that
+//! were added since they connected. A connection that goes unused for
+//! [`max_idle`](Config::max_idle) is closed, and the next call reconnects.
+//!
+//! A client made with [`resuming`] keeps its server-side session across reconnects: it presents
+//! the [session token](Transport::session_token) the server issued with its last connection when
+//! it next connects, so that a network blip doesn't end the session.
+//!
+//! A [`Pool`] spreads calls round-robin over several reconnecting connections to the same server,
+//! a [`Balancer`] spreads calls over connections to several servers of the same service, and
+//! [`Failover`] sends calls to the first of several pools that is connected.
+
+use crate::{
+    client::{self, delivery::SendsOnce, discovery, Channel, Client},
+    context, ClientMessage, ServerMessage, Transport,
+};
+use futures::{
+    channel::oneshot, compat::Future01CompatExt, future, prelude::*, stream::FuturesUnordered,
+};
+use log::{debug, info, warn};
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio_timer::Delay;
+use trace::ConnectionId;
+
+/// Settings that control how a [`Reconnecting`] client connects.
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The settings of the channel made for each connection.
+    pub client: client::Config,
+    /// How long to wait before reconnecting after the first failed attempt. The wait doubles
+    /// after every consecutive failed attempt, up to `max_backoff`.
+    pub initial_backoff: Duration,
+    /// The longest to wait between attempts.
+    pub max_backoff: Duration,
+    /// How often to check that the connection is still up, if at all. Otherwise, a closed
+    /// connection is only noticed when a call over it fails.
+    pub health_check_interval: Option<Duration>,
+    /// How long a connection may go without calls before it's closed, if ever. The next call
+    /// reconnects.
+    pub max_idle: Option<Duration>,
+    /// How long a connection may be used for, if not forever. Once it's that old, the client
+    /// reconnects before the next call, while the calls in flight over it finish there.
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            client: client::Config::default(),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            health_check_interval: None,
+            max_idle: None,
+            max_lifetime: None,
+        }
+    }
+}
+
+/// The state of a [`Reconnecting`] client's connection.
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connecting, after `failed_attempts` attempts failed in a row.
+    Connecting {
+        /// The number of consecutive attempts that failed.
+        failed_attempts: u32,
+    },
+    /// Connected.
+    Connected {
+        /// The ID of the connection.
+        connection_id: ConnectionId,
+    },
+    /// Connected, but the server is draining or closed the connection, so the next call
+    /// reconnects first.
+    Draining {
+        /// The ID of the connection.
+        connection_id: ConnectionId,
+    },
+    /// Not connected, because the connection went unused for
+    /// [`max_idle`](Config::max_idle). The next call reconnects.
+    Idle,
+}
+
+/// A [`Client`] that reconnects to its server whenever its connection shuts down.
+pub struct Reconnecting<Req, Resp, C> {
+    shared: Arc<Shared<Req, Resp, C>>,
+}
+
+struct Shared<Req, Resp, C> {
+    config: Config,
+    /// Connects a new transport to the server.
+    connect: C,
+    connection: Mutex<Connection<Req, Resp>>,
+    /// The session token the server issued with the last connection, if any.
+    session: Arc<Mutex<Option<String>>>,
+}
+
+struct Connection<Req, Resp> {
+    /// The channel over the current connection, or None while connecting.
+    channel: Option<Channel<Req, Resp>>,
+    state: ConnectionState,
+    /// Calls waiting for the next connection attempt.
+    waiters: Vec<oneshot::Sender<io::Result<Channel<Req, Resp>>>>,
+    /// When the current connection was made.
+    connected_at: Instant,
+    /// When a call last started or finished.
+    last_used: Instant,
+}
+
+impl<Req, Resp> Connection<Req, Resp> {
+    /// Returns the ID of the current connection if it shouldn't take new calls, because the server
+    /// is draining it, or it outlived `max_lifetime`.
+    fn retired(&self, config: &Config) -> Option<ConnectionId> {
+        let channel = self.channel.as_ref()?;
+        let expired = match config.max_lifetime {
+            Some(max_lifetime) => self.connected_at.elapsed() >= max_lifetime,
+            None => false,
+        };
+        if channel.is_draining() || expired {
+            Some(*channel.connection_id())
+        } else {
+            None
+        }
+    }
+}
+
+impl<Req, Resp, C> Clone for Reconnecting<Req, Resp, C> {
+    fn clone(&self) -> Self {
+        Reconnecting {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<Req, Resp, C> fmt::Debug for Reconnecting<Req, Resp, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Reconnecting")
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+impl<Req, Resp, C> Reconnecting<Req, Resp, C> {
+    /// Returns the state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        let connection = self.shared.connection.lock().unwrap();
+        match &connection.channel {
+            Some(channel) if channel.is_draining() => ConnectionState::Draining {
+                connection_id: *channel.connection_id(),
+            },
+            _ => connection.state.clone(),
+        }
+    }
+
+    /// Returns true if the client is connected, over a connection the server isn't draining and
+    /// that didn't outlive `max_lifetime`.
+    pub fn is_connected(&self) -> bool {
+        let connection = self.shared.connection.lock().unwrap();
+        connection.channel.is_some() && connection.retired(&self.shared.config).is_none()
+    }
+
+    /// Returns true if the client is connected, or idle, in which case the next call reconnects.
+    fn is_available(&self) -> bool {
+        self.is_connected() || self.state() == ConnectionState::Idle
+    }
+}
+
+impl<Req, Resp, C, Fut, T> Reconnecting<Req, Resp, C>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    /// Returns a client whose connections are made by `connect`, and starts connecting.
+    ///
+    /// Must only be called from on an executor.
+    pub fn new(config: Config, connect: C) -> io::Result<Self> {
+        Self::with_session(config, connect, Arc::default())
+    }
+
+    /// Like [`new`](Reconnecting::new), but records the session tokens the server issues in
+    /// `session`.
+    fn with_session(
+        config: Config,
+        connect: C,
+        session: Arc<Mutex<Option<String>>>,
+    ) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            config,
+            connect,
+            session,
+            connection: Mutex::new(Connection {
+                channel: None,
+                state: ConnectionState::Connecting { failed_attempts: 0 },
+                waiters: vec![],
+                connected_at: Instant::now(),
+                last_used: Instant::now(),
+            }),
+        });
+        reconnect(shared.clone())?;
+        if let Some(interval) = shared.config.health_check_interval {
+            check_health(shared.clone(), interval)?;
+        }
+        if let Some(max_idle) = shared.config.max_idle {
+            close_when_idle(shared.clone(), max_idle)?;
+        }
+        Ok(Reconnecting { shared })
+    }
+
+    /// Returns the session token the server issued with the last connection, if the transport
+    /// issues sessions.
+    pub fn session_token(&self) -> Option<String> {
+        self.shared.session.lock().unwrap().clone()
+    }
+
+    /// Starts reconnecting if the current connection is retired, leaving it to finish the calls in
+    /// flight over it.
+    fn reconnect_if_retired(&self) {
+        let retired = self
+            .shared
+            .connection
+            .lock()
+            .unwrap()
+            .retired(&self.shared.config);
+        if let Some(connection_id) = retired {
+            disconnected(&self.shared, connection_id);
+        }
+    }
+
+    /// Returns a future that resolves once the client is connected and its connection passes a
+    /// health check, or fails if the next connection attempt fails.
+    pub fn ready(&self) -> impl Future<Output = io::Result<()>> {
+        self.reconnect_if_retired();
+        let channel = self.channel();
+        async move {
+            let mut channel = await!(channel)?;
+            await!(channel.ready())
+        }
+    }
+
+    /// Returns the channel over the current connection, or else the channel over the next
+    /// connection attempt, if it succeeds, reconnecting first if the client is idle.
+    fn channel(&self) -> impl Future<Output = io::Result<Channel<Req, Resp>>> {
+        let (waiter, channel) = oneshot::channel();
+        let idle = {
+            let mut connection = self.shared.connection.lock().unwrap();
+            connection.last_used = Instant::now();
+            if let Some(channel) = &connection.channel {
+                return future::Either::Left(future::ready(Ok(channel.share())));
+            }
+            connection.waiters.push(waiter);
+            if connection.state == ConnectionState::Idle {
+                connection.state = ConnectionState::Connecting { failed_attempts: 0 };
+                true
+            } else {
+                false
+            }
+        };
+        if idle {
+            debug!("Reconnecting after the connection was idle.");
+            if let Err(e) = reconnect(self.shared.clone()) {
+                let mut connection = self.shared.connection.lock().unwrap();
+                connection.state = ConnectionState::Idle;
+                connection.waiters.clear();
+                return future::Either::Left(future::ready(Err(e)));
+            }
+        }
+        future::Either::Right(channel.map(|channel| {
+            channel.unwrap_or_else(|oneshot::Canceled| {
+                Err(io::Error::from(io::ErrorKind::NotConnected))
+            })
+        }))
+    }
+
+    /// Sends a request over the current connection, waiting for one if not connected.
+    fn send(
+        &self,
+        ctx: context::Context,
+        request: Req,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + 'static>> {
+        self.reconnect_if_retired();
+        let shared = self.shared.clone();
+        let channel = self.channel();
+        async move {
+            let mut channel = await!(channel)?;
+            let response = await!(channel.call(ctx, request));
+            shared.connection.lock().unwrap().last_used = Instant::now();
+            if let Err(ref e) = response {
+                // The channel fails calls with ConnectionReset once its connection shuts down, at
+                // which point it can't accept any more requests.
+                if e.kind() == io::ErrorKind::ConnectionReset && await!(channel.ready()).is_err() {
+                    disconnected(&shared, *channel.connection_id());
+                }
+            }
+            response
+        }
+        .boxed()
+    }
+}
+
+impl<Req, Resp, C> SendsOnce for Reconnecting<Req, Resp, C> {}
+
+impl<'a, Req, Resp, C, Fut, T> Client<'a, Req> for Reconnecting<Req, Resp, C>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    type Response = Resp;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + 'static>>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        self.send(ctx, request)
+    }
+}
+
+/// Returns a client whose connections are made by `connect`, like [`Reconnecting::new`], except
+/// that `connect` is given the session token the server issued with the last connection, if any,
+/// to present to the server, so that the new connection rejoins the session.
+///
+/// Must only be called from on an executor.
+pub fn resuming<Req, Resp, R, Fut, T>(
+    config: Config,
+    connect: R,
+) -> io::Result<Reconnecting<Req, Resp, impl Fn() -> Fut + Send + Sync + 'static>>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    R: Fn(Option<String>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    let session = Arc::new(Mutex::new(None));
+    let last_session = session.clone();
+    let connect = move || connect(last_session.lock().unwrap().clone());
+    Reconnecting::with_session(config, connect, session)
+}
+
+/// Forgets the channel over connection `connection_id`, if it's still the current one, and starts
+/// reconnecting.
+fn disconnected<Req, Resp, C, Fut, T>(
+    shared: &Arc<Shared<Req, Resp, C>>,
+    connection_id: ConnectionId,
+) where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    {
+        let mut connection = shared.connection.lock().unwrap();
+        match &connection.channel {
+            Some(channel) if *channel.connection_id() == connection_id => {}
+            // Another call already noticed.
+            _ => return,
+        }
+        info!("[{}] Connection shut down. Reconnecting.", connection_id);
+        connection.channel = None;
+        connection.state = ConnectionState::Connecting { failed_attempts: 0 };
+    }
+    if let Err(e) = reconnect(shared.clone()) {
+        warn!("[{}] Could not reconnect: {}", connection_id, e);
+    }
+}
+
+/// Spawns a task that connects, backing off between failed attempts, until an attempt succeeds.
+fn reconnect<Req, Resp, C, Fut, T>(shared: Arc<Shared<Req, Resp, C>>) -> io::Result<()>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    let config = shared.config.clone();
+    // Holds the client weakly, so that reconnecting stops once the client is dropped.
+    let shared = Arc::downgrade(&shared);
+    crate::spawn(async move {
+        let mut backoff = config.initial_backoff;
+        let mut failed_attempts = 0;
+        loop {
+            let connect = match shared.upgrade() {
+                Some(shared) => (shared.connect)(),
+                None => return,
+            };
+            let channel = match await!(connect) {
+                Ok(transport) => {
+                    if let (Some(token), Some(shared)) =
+                        (transport.session_token(), shared.upgrade())
+                    {
+                        *shared.session.lock().unwrap() = Some(token);
+                    }
+                    await!(client::new(config.client.clone(), transport))
+                }
+                Err(e) => Err(e),
+            };
+            {
+                let shared = match shared.upgrade() {
+                    Some(shared) => shared,
+                    None => return,
+                };
+                let mut connection = shared.connection.lock().unwrap();
+                match channel {
+                    Ok(channel) => {
+                        debug!("[{}] Connected.", channel.connection_id());
+                        connection.state = ConnectionState::Connected {
+                            connection_id: *channel.connection_id(),
+                        };
+                        connection.connected_at = Instant::now();
+                        connection.last_used = Instant::now();
+                        for waiter in connection.waiters.drain(..) {
+                            let _ = waiter.send(Ok(channel.share()));
+                        }
+                        connection.channel = Some(channel);
+                        return;
+                    }
+                    Err(e) => {
+                        failed_attempts += 1;
+                        warn!(
+                            "Connection attempt {} failed, retrying in {:?}: {}",
+                            failed_attempts, backoff, e
+                        );
+                        connection.state = ConnectionState::Connecting { failed_attempts };
+                        for waiter in connection.waiters.drain(..) {
+                            let _ = waiter.send(Err(io::Error::new(e.kind(), e.to_string())));
+                        }
+                    }
+                }
+            }
+            if let Err(e) = await!(Delay::new(Instant::now() + backoff).compat()) {
+                warn!("Could not back off before reconnecting: {}", e);
+            }
+            backoff = (backoff * 2).min(config.max_backoff);
+        }
+    })
+    .map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Could not spawn reconnect task. Is shutdown: {}",
+                e.is_shutdown()
+            ),
+        )
+    })
+}
+
+/// Spawns a task that checks the connection every `interval`, and starts reconnecting if it shut
+/// down.
+fn check_health<Req, Resp, C, Fut, T>(
+    shared: Arc<Shared<Req, Resp, C>>,
+    interval: Duration,
+) -> io::Result<()>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    // Holds the client weakly, so that checking stops once the client is dropped.
+    let shared = Arc::downgrade(&shared);
+    crate::spawn(async move {
+        loop {
+            if let Err(e) = await!(Delay::new(Instant::now() + interval).compat()) {
+                warn!("Could not wait between health checks: {}", e);
+            }
+            let (channel, retired) = match shared.upgrade() {
+                Some(shared) => {
+                    let connection = shared.connection.lock().unwrap();
+                    (
+                        connection.channel.as_ref().map(Channel::share),
+                        connection.retired(&shared.config).is_some(),
+                    )
+                }
+                None => return,
+            };
+            if let Some(mut channel) = channel {
+                if retired || await!(channel.ready()).is_err() {
+                    if let Some(shared) = shared.upgrade() {
+                        disconnected(&shared, *channel.connection_id());
+                    }
+                }
+            }
+        }
+    })
+    .map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Could not spawn health check task. Is shutdown: {}",
+                e.is_shutdown()
+            ),
+        )
+    })
+}
+
+/// Spawns a task that closes the connection once it goes `max_idle` without calls. The calls in
+/// flight over it, if any, finish first.
+fn close_when_idle<Req, Resp, C>(
+    shared: Arc<Shared<Req, Resp, C>>,
+    max_idle: Duration,
+) -> io::Result<()>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Send + Sync + 'static,
+{
+    // Holds the client weakly, so that checking stops once the client is dropped.
+    let shared = Arc::downgrade(&shared);
+    crate::spawn(async move {
+        let mut idle_at = Instant::now() + max_idle;
+        loop {
+            if let Err(e) = await!(Delay::new(idle_at).compat()) {
+                warn!("Could not wait for the connection to go idle: {}", e);
+            }
+            let shared = match shared.upgrade() {
+                Some(shared) => shared,
+                None => return,
+            };
+            let mut connection = shared.connection.lock().unwrap();
+            idle_at = connection.last_used + max_idle;
+            if idle_at > Instant::now() {
+                continue;
+            }
+            idle_at = Instant::now() + max_idle;
+            if let Some(channel) = connection.channel.take() {
+                info!(
+                    "[{}] Connection unused for {:?}. Closing it.",
+                    channel.connection_id(),
+                    max_idle
+                );
+                connection.state = ConnectionState::Idle;
+            }
+        }
+    })
+    .map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Could not spawn idle check task. Is shutdown: {}",
+                e.is_shutdown()
+            ),
+        )
+    })
+}
+
+/// A [`Client`] that sends calls round-robin over a fixed number of [`Reconnecting`] connections,
+/// skipping connections that are reconnecting or draining while any are connected or idle.
+pub struct Pool<Req, Resp, C> {
+    clients: Arc<Vec<Reconnecting<Req, Resp, C>>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl<Req, Resp, C> Clone for Pool<Req, Resp, C> {
+    fn clone(&self) -> Self {
+        Pool {
+            clients: self.clients.clone(),
+            next: self.next.clone(),
+        }
+    }
+}
+
+impl<Req, Resp, C> fmt::Debug for Pool<Req, Resp, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("clients", &self.clients)
+            .finish()
+    }
+}
+
+impl<Req, Resp, C> Pool<Req, Resp, C> {
+    /// Returns the state of each connection in the pool.
+    pub fn states(&self) -> Vec<ConnectionState> {
+        self.clients.iter().map(Reconnecting::state).collect()
+    }
+
+    /// Returns true if any connection in the pool is connected.
+    pub fn is_connected(&self) -> bool {
+        self.clients.iter().any(Reconnecting::is_connected)
+    }
+
+    /// Returns true if any connection in the pool is connected or idle.
+    fn is_available(&self) -> bool {
+        self.clients.iter().any(Reconnecting::is_available)
+    }
+}
+
+impl<Req, Resp, C, Fut, T> Pool<Req, Resp, C>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    /// Returns a pool of `size` connections made by `connect`, and starts connecting them.
+    ///
+    /// Must only be called from on an executor.
+    pub fn new(config: Config, size: usize, connect: C) -> io::Result<Self>
+    where
+        C: Clone,
+    {
+        assert!(size > 0, "A pool needs at least one connection.");
+        let clients = (0..size)
+            .map(|_| Reconnecting::new(config.clone(), connect.clone()))
+            .collect::<io::Result<_>>()?;
+        Ok(Pool {
+            clients: Arc::new(clients),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Returns a future that resolves once at least `min_connected` of the pool's connections are
+    /// [ready](Reconnecting::ready), or fails once too many of their next connection attempts fail
+    /// for that.
+    pub fn ready(&self, min_connected: usize) -> impl Future<Output = io::Result<()>> {
+        warm_up(
+            self.clients.iter().map(Reconnecting::ready).collect(),
+            min_connected,
+        )
+    }
+
+    /// Sends a request over the next connection in turn, skipping connections that are
+    /// reconnecting or draining while any are connected or idle.
+    fn send(
+        &self,
+        ctx: context::Context,
+        request: Req,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + 'static>> {
+        for client in self.clients.iter() {
+            client.reconnect_if_retired();
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.clients.len();
+        let client = (0..len)
+            .map(|i| &self.clients[(start + i) % len])
+            .find(|client| client.is_available())
+            .unwrap_or(&self.clients[start % len]);
+        client.send(ctx, request)
+    }
+}
+
+impl<Req, Resp, C> SendsOnce for Pool<Req, Resp, C> {}
+
+impl<'a, Req, Resp, C, Fut, T> Client<'a, Req> for Pool<Req, Resp, C>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    type Response = Resp;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + 'static>>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        self.send(ctx, request)
+    }
+}
+
+/// A [`Client`] that sends calls to the first of several tiers of servers with a live connection,
+/// e.g. to primary servers while any are up, and otherwise to secondaries in another region.
+///
+/// Each tier is a [`Pool`] that reconnects on its own, so calls fail back to a higher tier as soon
+/// as it reconnects. While no tier is connected, calls go to the first tier.
+pub struct Failover<Req, Resp, C> {
+    tiers: Arc<Vec<Pool<Req, Resp, C>>>,
+}
+
+impl<Req, Resp, C> Clone for Failover<Req, Resp, C> {
+    fn clone(&self) -> Self {
+        Failover {
+            tiers: self.tiers.clone(),
+        }
+    }
+}
+
+impl<Req, Resp, C> fmt::Debug for Failover<Req, Resp, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Failover")
+            .field("tiers", &self.tiers)
+            .finish()
+    }
+}
+
+impl<Req, Resp, C> Failover<Req, Resp, C> {
+    /// Returns a client that prefers earlier tiers in `tiers` to later ones.
+    pub fn new(tiers: Vec<Pool<Req, Resp, C>>) -> Self {
+        assert!(!tiers.is_empty(), "Failover needs at least one tier.");
+        Failover {
+            tiers: Arc::new(tiers),
+        }
+    }
+
+    /// Returns the index of the tier calls are sent to, or None if no tier is connected or idle.
+    pub fn active_tier(&self) -> Option<usize> {
+        self.tiers.iter().position(Pool::is_available)
+    }
+
+    /// Returns the tiers, in order of preference.
+    pub fn tiers(&self) -> &[Pool<Req, Resp, C>] {
+        &self.tiers
+    }
+}
+
+impl<Req, Resp, C> SendsOnce for Failover<Req, Resp, C> {}
+
+impl<'a, Req, Resp, C, Fut, T> Client<'a, Req> for Failover<Req, Resp, C>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    type Response = Resp;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + 'static>>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        let tier = self.active_tier().unwrap_or(0);
+        self.tiers[tier].send(ctx, request)
+    }
+}
+
+/// How a [`Balancer`] picks the server a call is sent to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Balancing {
+    /// Take turns between servers.
+    RoundRobin,
+    /// Send to the server with the fewest calls in flight, taking turns between ties.
+    LeastLoaded,
+}
+
+/// A [`Client`] that spreads calls over [`Reconnecting`] connections to several servers,
+/// e.g. every host that runs a service.
+///
+/// Calls are only sent to connected or idle servers while any are, so a server whose
+/// connection broke is out of rotation until it reconnects. So is a server draining its
+/// connection, e.g. because it's shutting down: it gets no new calls, but still answers the calls
+/// in flight to it, while the balancer reconnects to it. Setting a
+/// [`health_check_interval`](Config::health_check_interval) takes servers that closed their
+/// connections out of rotation without waiting for a call to fail.
+///
+/// The servers are either fixed, or, for a balancer made with [`resolve`](Balancer::resolve),
+/// kept up to date with a [`Resolver`](discovery::Resolver).
+///
+/// Fixed servers can be split into [`weighted`](Balancer::weighted) groups, e.g. the servers of
+/// the old and the new deployment of a service, which take shares of the calls in proportion to
+/// their weights. Changing the weights while the balancer is in use shifts calls from one group to
+/// another gradually.
+pub struct Balancer<Req, Resp, C> {
+    /// Never empty.
+    backends: Arc<RwLock<Vec<Backend<Req, Resp, C>>>>,
+    /// The weight of each group of servers.
+    weights: Arc<RwLock<Vec<u32>>>,
+    balancing: Balancing,
+    next: Arc<AtomicUsize>,
+    /// Takes turns between groups.
+    next_group: Arc<AtomicUsize>,
+}
+
+struct Backend<Req, Resp, C> {
+    /// The server's address, if the balancer resolves its servers.
+    addr: Option<SocketAddr>,
+    /// The index of the server's group.
+    group: usize,
+    client: Reconnecting<Req, Resp, C>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl<Req, Resp, C> Clone for Balancer<Req, Resp, C> {
+    fn clone(&self) -> Self {
+        Balancer {
+            backends: self.backends.clone(),
+            weights: self.weights.clone(),
+            balancing: self.balancing,
+            next: self.next.clone(),
+            next_group: self.next_group.clone(),
+        }
+    }
+}
+
+impl<Req, Resp, C> fmt::Debug for Balancer<Req, Resp, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Balancer")
+            .field("states", &self.states())
+            .field("weights", &self.weights())
+            .field("balancing", &self.balancing)
+            .finish()
+    }
+}
+
+impl<Req, Resp, C> Balancer<Req, Resp, C> {
+    /// Returns the state of the connection to each server, in the order the servers were given.
+    pub fn states(&self) -> Vec<ConnectionState> {
+        self.backends
+            .read()
+            .unwrap()
+            .iter()
+            .map(|backend| backend.client.state())
+            .collect()
+    }
+
+    /// Returns the number of calls in flight to each server, in the order the servers were given.
+    pub fn in_flight(&self) -> Vec<usize> {
+        self.backends
+            .read()
+            .unwrap()
+            .iter()
+            .map(|backend| backend.in_flight.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Returns the addresses of the servers, if the balancer resolves them, in the order of
+    /// [`states`](Balancer::states).
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.backends
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|backend| backend.addr)
+            .collect()
+    }
+
+    /// Returns the weight of each group of servers.
+    pub fn weights(&self) -> Vec<u32> {
+        self.weights.read().unwrap().clone()
+    }
+
+    /// Sets the weight of group `group`, which then takes that weight's share of the new calls.
+    /// A group with no weight only takes calls while no group with weight has servers that are
+    /// connected or idle.
+    ///
+    /// Panics if there's no such group.
+    pub fn set_weight(&self, group: usize, weight: u32) {
+        let mut weights = self.weights.write().unwrap();
+        assert!(group < weights.len(), "No group {} of servers.", group);
+        info!("Group {} of servers now has weight {}.", group, weight);
+        weights[group] = weight;
+    }
+
+    /// Returns true if any server is connected.
+    pub fn is_connected(&self) -> bool {
+        self.backends
+            .read()
+            .unwrap()
+            .iter()
+            .any(|backend| backend.client.is_connected())
+    }
+}
+
+impl<Req, Resp, C, Fut, T> Balancer<Req, Resp, C>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    /// Returns a client that balances calls over one connection per server, each made by one of
+    /// `connects`, and starts connecting them.
+    ///
+    /// Must only be called from on an executor.
+    pub fn new(config: Config, balancing: Balancing, connects: Vec<C>) -> io::Result<Self> {
+        Self::weighted(config, balancing, vec![(1, connects)])
+    }
+
+    /// Returns a client that balances calls over groups of servers, sending each group a share
+    /// of the calls in proportion to its weight, and starts connecting to the servers. Each group
+    /// is a weight and the `connects` of one connection per server in the group. Within a group,
+    /// calls are balanced like over the servers of a balancer made with
+    /// [`new`](Balancer::new).
+    ///
+    /// Must only be called from on an executor.
+    pub fn weighted(
+        config: Config,
+        balancing: Balancing,
+        groups: Vec<(u32, Vec<C>)>,
+    ) -> io::Result<Self> {
+        assert!(
+            groups.iter().any(|(_, connects)| !connects.is_empty()),
+            "A balancer needs at least one server."
+        );
+        let mut weights = vec![];
+        let mut backends = vec![];
+        for (group, (weight, connects)) in groups.into_iter().enumerate() {
+            weights.push(weight);
+            for connect in connects {
+                backends.push(Backend::new(&config, None, group, connect)?);
+            }
+        }
+        Ok(Balancer {
+            backends: Arc::new(RwLock::new(backends)),
+            weights: Arc::new(RwLock::new(weights)),
+            balancing,
+            next: Arc::new(AtomicUsize::new(0)),
+            next_group: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Returns a client that balances calls over the servers `resolver` resolves `name` to, once
+    /// it resolves any, connecting to each server with the transport made by `connect(addr)`.
+    ///
+    /// As the servers change, connections to new servers are added to the rotation, and
+    /// connections to servers that are gone are dropped once their calls complete. If `name`
+    /// resolves to no servers, or fails to resolve, the previous servers are kept, so that a
+    /// resolver outage doesn't take every server out of rotation.
+    ///
+    /// Must only be called from on an executor.
+    pub fn resolve<D>(
+        config: Config,
+        balancing: Balancing,
+        resolver: &dyn discovery::Resolver,
+        name: &str,
+        connect: D,
+    ) -> impl Future<Output = io::Result<Self>>
+    where
+        D: Fn(SocketAddr) -> C + Send + 'static,
+    {
+        let mut addrs = resolver.resolve(name);
+        let name = name.to_string();
+        async move {
+            let first = loop {
+                match await!(addrs.next()) {
+                    Some(Ok(ref first)) if first.is_empty() => {}
+                    Some(Ok(first)) => break first,
+                    Some(Err(e)) => return Err(e),
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("Could not resolve {}.", name),
+                        ))
+                    }
+                }
+            };
+            let backends = first
+                .into_iter()
+                .map(|addr| Backend::new(&config, Some(addr), 0, connect(addr)))
+                .collect::<io::Result<_>>()?;
+            let balancer = Balancer {
+                backends: Arc::new(RwLock::new(backends)),
+                weights: Arc::new(RwLock::new(vec![1])),
+                balancing,
+                next: Arc::new(AtomicUsize::new(0)),
+                next_group: Arc::new(AtomicUsize::new(0)),
+            };
+
+            // Holds the servers weakly, so that resolving stops once the client is dropped.
+            let backends = Arc::downgrade(&balancer.backends);
+            crate::spawn(async move {
+                while let Some(resolved) = await!(addrs.next()) {
+                    let backends = match backends.upgrade() {
+                        Some(backends) => backends,
+                        None => return,
+                    };
+                    let resolved = match resolved {
+                        Ok(ref resolved) if resolved.is_empty() => {
+                            warn!(
+                                "{} resolved to no servers. Keeping the previous ones.",
+                                name
+                            );
+                            continue;
+                        }
+                        Ok(resolved) => resolved,
+                        Err(e) => {
+                            warn!(
+                                "Could not resolve {}. Keeping the previous servers: {}",
+                                name, e
+                            );
+                            continue;
+                        }
+                    };
+                    let mut backends = backends.write().unwrap();
+                    let mut kept = vec![];
+                    for addr in resolved {
+                        let existing = backends
+                            .iter()
+                            .position(|backend| backend.addr == Some(addr));
+                        match existing {
+                            Some(i) => kept.push(backends.swap_remove(i)),
+                            None => match Backend::new(&config, Some(addr), 0, connect(addr)) {
+                                Ok(backend) => {
+                                    info!("Added server {} of {}.", addr, name);
+                                    kept.push(backend);
+                                }
+                                Err(e) => warn!("Could not add server {} of {}: {}", addr, name, e),
+                            },
+                        }
+                    }
+                    if !kept.is_empty() {
+                        for removed in backends.iter().filter_map(|backend| backend.addr) {
+                            info!("Removed server {} of {}.", removed, name);
+                        }
+                        *backends = kept;
+                    }
+                }
+            })
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "Could not spawn resolver task. Is shutdown: {}",
+                        e.is_shutdown()
+                    ),
+                )
+            })?;
+            Ok(balancer)
+        }
+    }
+
+    /// Returns a future that resolves once the connections to at least `min_connected` of the
+    /// servers are [ready](Reconnecting::ready), or fails once too many of their next connection
+    /// attempts fail for that.
+    pub fn ready(&self, min_connected: usize) -> impl Future<Output = io::Result<()>> {
+        let ready = self
+            .backends
+            .read()
+            .unwrap()
+            .iter()
+            .map(|backend| backend.client.ready())
+            .collect();
+        warm_up(ready, min_connected)
+    }
+
+    /// Picks the group the next call goes to, taking turns between the groups with weight that
+    /// have servers that are connected or idle, in proportion to their weights. Returns None if
+    /// there are no such groups.
+    fn next_group(&self, backends: &[Backend<Req, Resp, C>]) -> Option<usize> {
+        let weights: Vec<u64> = self
+            .weights
+            .read()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .map(|(group, &weight)| {
+                let available = backends
+                    .iter()
+                    .any(|backend| backend.group == group && backend.client.is_available());
+                if available {
+                    u64::from(weight)
+                } else {
+                    0
+                }
+            })
+            .collect();
+        let total: u64 = weights.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let mut turn = self.next_group.fetch_add(1, Ordering::Relaxed) as u64 % total;
+        weights.iter().position(|&weight| {
+            if turn < weight {
+                true
+            } else {
+                turn -= weight;
+                false
+            }
+        })
+    }
+
+    /// Sends a request to the server picked by the balancing policy, out of the connected ones
+    /// that aren't retired, and the idle ones, while there are any. If there are several groups,
+    /// the server is picked out of the next group in turn.
+    fn send(
+        &self,
+        ctx: context::Context,
+        request: Req,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + 'static>> {
+        /// Counts a call as in flight until dropped.
+        struct InFlight(Arc<AtomicUsize>);
+
+        impl Drop for InFlight {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+
+        let (in_flight, response) = {
+            let backends = self.backends.read().unwrap();
+            for backend in backends.iter() {
+                backend.client.reconnect_if_retired();
+            }
+            let group = self.next_group(&backends);
+            let start = self.next.fetch_add(1, Ordering::Relaxed);
+            let len = backends.len();
+            let mut connected = (0..len)
+                .map(|i| &backends[(start + i) % len])
+                .filter(|backend| group.map_or(true, |group| backend.group == group))
+                .filter(|backend| backend.client.is_available());
+            let backend = match self.balancing {
+                Balancing::RoundRobin => connected.next(),
+                // Ties go to the earliest in turn.
+                Balancing::LeastLoaded => {
+                    connected.min_by_key(|backend| backend.in_flight.load(Ordering::Relaxed))
+                }
+            }
+            .unwrap_or(&backends[start % len]);
+            backend.in_flight.fetch_add(1, Ordering::Relaxed);
+            (
+                InFlight(backend.in_flight.clone()),
+                backend.client.send(ctx, request),
+            )
+        };
+        async move {
+            let _in_flight = in_flight;
+            await!(response)
+        }
+        .boxed()
+    }
+}
+
+/// Resolves once `min_ready` of `ready` succeed, or fails with the error that leaves too few of
+/// them to.
+async fn warm_up<F>(ready: Vec<F>, min_ready: usize) -> io::Result<()>
+where
+    F: Future<Output = io::Result<()>>,
+{
+    let mut remaining = ready.len();
+    if remaining < min_ready {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Can't have {} connections ready out of {}.",
+                min_ready, remaining
+            ),
+        ));
+    }
+    let mut ready: FuturesUnordered<_> = ready.into_iter().map(Box::pin).collect();
+    let mut succeeded = 0;
+    while succeeded < min_ready {
+        let result = await!(ready.next()).expect("Fewer connections ready than required.");
+        remaining -= 1;
+        match result {
+            Ok(()) => succeeded += 1,
+            Err(e) if succeeded + remaining < min_ready => return Err(e),
+            Err(e) => debug!("A connection failed to warm up: {}", e),
+        }
+    }
+    Ok(())
+}
+
+impl<Req, Resp, C, Fut, T> Backend<Req, Resp, C>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    fn new(
+        config: &Config,
+        addr: Option<SocketAddr>,
+        group: usize,
+        connect: C,
+    ) -> io::Result<Self> {
+        Ok(Backend {
+            addr,
+            group,
+            client: Reconnecting::new(config.clone(), connect)?,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+}
+
+impl<Req, Resp, C> SendsOnce for Balancer<Req, Resp, C> {}
+
+impl<'a, Req, Resp, C, Fut, T> Client<'a, Req> for Balancer<Req, Resp, C>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    C: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    type Response = Resp;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + 'static>>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        self.send(ctx, request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Balancer, Balancing, Config, ConnectionState, Failover, Pool, Reconnecting};
+    use crate::{
+        client::Client,
+        context,
+        server::{self, Handler},
+        test_util, transport, Server,
+    };
+    use futures::{
+        channel::{mpsc, oneshot},
+        compat::Future01CompatExt,
+        future,
+        prelude::*,
+    };
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+    use tokio_timer::Delay;
+
+    #[test]
+    fn reconnecting_client_reconnects_after_the_connection_closes() {
+        test_util::init();
+
+        let (server_channels_tx, server_channels_rx) = mpsc::unbounded();
+        let server_channels_tx = Arc::new(Mutex::new(server_channels_tx));
+        let (handles_tx, mut handles_rx) = mpsc::unbounded();
+        let server = Server::<String, String>::default()
+            .incoming(server_channels_rx.map(Ok))
+            .map_ok(move |channel| {
+                handles_tx.unbounded_send(channel.handle()).unwrap();
+                channel
+            })
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+        let connect = move || {
+            let (client_channel, server_channel) = transport::channel::unbounded();
+            let _ = server_channels_tx
+                .lock()
+                .unwrap()
+                .unbounded_send(server_channel);
+            future::ready(Ok(client_channel))
+        };
+
+        let responses = async move {
+            let mut client = Reconnecting::new(Config::default(), connect)?;
+            let response1 = await!(client.call(context::current(), "hi".into()))?;
+            let first = client.state();
+
+            let handle: server::ConnectionHandle = await!(handles_rx.next()).unwrap();
+            handle.close();
+            // Calls fail until the client notices the connection closed, and then reconnects.
+            let mut failed = false;
+            let response2 = loop {
+                match await!(client.call(context::current(), "bye".into())) {
+                    Ok(response) if failed => break response,
+                    Ok(_) => {}
+                    Err(_) => failed = true,
+                }
+            };
+            Ok::<_, io::Error>((response1, response2, first, client.state()))
+        };
+
+        let (response1, response2, first, second) = test_util::run_future(future::join(
+            server,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .1;
+        assert_eq!(response1, "hi");
+        assert_eq!(response2, "bye");
+        match (first, second) {
+            (
+                ConnectionState::Connected { connection_id: a },
+                ConnectionState::Connected { connection_id: b },
+            ) => assert_ne!(a, b),
+            states => panic!("Unexpected connection states: {:?}", states),
+        }
+    }
+
+    #[test]
+    fn reconnecting_client_replaces_idle_and_old_connections() {
+        test_util::init();
+
+        let (server_channels_tx, server_channels_rx) = mpsc::unbounded();
+        let server_channels_tx = Arc::new(Mutex::new(server_channels_tx));
+        let server = Server::<String, String>::default()
+            .incoming(server_channels_rx.map(Ok))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+        let connect = move || {
+            let (client_channel, server_channel) = transport::channel::unbounded();
+            let _ = server_channels_tx
+                .lock()
+                .unwrap()
+                .unbounded_send(server_channel);
+            future::ready(Ok(client_channel))
+        };
+        let connect_idle = connect.clone();
+
+        let states = async move {
+            let connection_id = |state: ConnectionState| match state {
+                ConnectionState::Connected { connection_id } => connection_id,
+                state => panic!("Unexpected connection state: {:?}", state),
+            };
+
+            let mut config = Config::default();
+            config.max_idle = Some(Duration::from_millis(10));
+            let mut client = Reconnecting::new(config, connect_idle)?;
+            await!(client.call(context::current(), "hi".into()))?;
+            let first = connection_id(client.state());
+            while client.state() != ConnectionState::Idle {
+                await!(Delay::new(Instant::now() + Duration::from_millis(1)).compat()).unwrap();
+            }
+            await!(client.call(context::current(), "hi".into()))?;
+            let after_idle = connection_id(client.state());
+
+            let mut config = Config::default();
+            config.max_lifetime = Some(Duration::from_millis(10));
+            let mut client = Reconnecting::new(config, connect)?;
+            await!(client.call(context::current(), "hi".into()))?;
+            let young = connection_id(client.state());
+            await!(Delay::new(Instant::now() + Duration::from_millis(10)).compat()).unwrap();
+            // The old connection is still up, but isn't used for new calls.
+            let expired = client.is_connected();
+            await!(client.call(context::current(), "hi".into()))?;
+            let old = connection_id(client.state());
+            Ok::<_, io::Error>((first, after_idle, young, expired, old))
+        };
+
+        let (first, after_idle, young, expired, old) =
+            test_util::run_future(future::join(server, states.unwrap_or_else(|e| panic!(e)))).1;
+        assert_ne!(first, after_idle);
+        assert!(!expired);
+        assert_ne!(young, old);
+    }
+
+    #[test]
+    fn failover_uses_secondaries_until_primaries_are_back() {
+        test_util::init();
+
+        let (server_channels_tx, server_channels_rx) = mpsc::unbounded();
+        let server_channels_tx = Arc::new(Mutex::new(server_channels_tx));
+        let server = Server::<String, String>::default()
+            .incoming(server_channels_rx.map(Ok))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+        let connect = |up: Arc<AtomicBool>| {
+            let server_channels_tx = server_channels_tx.clone();
+            move || {
+                future::ready(if up.load(Ordering::SeqCst) {
+                    let (client_channel, server_channel) = transport::channel::unbounded();
+                    let _ = server_channels_tx
+                        .lock()
+                        .unwrap()
+                        .unbounded_send(server_channel);
+                    Ok(client_channel)
+                } else {
+                    Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+                })
+            }
+        };
+        let primary_up = Arc::new(AtomicBool::new(false));
+        let connect_primary = connect(primary_up.clone());
+        let connect_secondary = connect(Arc::new(AtomicBool::new(true)));
+        drop(server_channels_tx);
+
+        let responses = async move {
+            let mut config = Config::default();
+            config.initial_backoff = Duration::from_millis(1);
+            config.max_backoff = Duration::from_millis(10);
+            let primary = Pool::new(config.clone(), 1, connect_primary)?;
+            let mut secondary = Pool::new(config, 1, connect_secondary)?;
+            // Waits for the secondary to connect.
+            await!(secondary.call(context::current(), "hi".into()))?;
+
+            let mut client = Failover::new(vec![primary, secondary]);
+            let response1 = await!(client.call(context::current(), "hi".into()))?;
+            let tier1 = client.active_tier();
+
+            primary_up.store(true, Ordering::SeqCst);
+            while client.active_tier() != Some(0) {
+                await!(Delay::new(Instant::now() + Duration::from_millis(1)).compat()).unwrap();
+            }
+            let response2 = await!(client.call(context::current(), "bye".into()))?;
+            Ok::<_, io::Error>((response1, tier1, response2))
+        };
+
+        let (response1, tier1, response2) = test_util::run_future(future::join(
+            server,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .1;
+        assert_eq!(response1, "hi");
+        assert_eq!(tier1, Some(1));
+        assert_eq!(response2, "bye");
+    }
+
+    #[test]
+    fn balancer_is_ready_once_enough_servers_are_connected() {
+        test_util::init();
+
+        let (server_channels_tx, server_channels_rx) = mpsc::unbounded();
+        let server_channels_tx = Arc::new(Mutex::new(server_channels_tx));
+        let server = Server::<String, String>::default()
+            .incoming(server_channels_rx.map(Ok))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+        let connect = |up: bool| {
+            let server_channels_tx = server_channels_tx.clone();
+            move || {
+                future::ready(if up {
+                    let (client_channel, server_channel) = transport::channel::unbounded();
+                    let _ = server_channels_tx
+                        .lock()
+                        .unwrap()
+                        .unbounded_send(server_channel);
+                    Ok(client_channel)
+                } else {
+                    Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+                })
+            }
+        };
+        let connects = vec![connect(true), connect(false)];
+        drop(server_channels_tx);
+
+        let responses = async move {
+            let mut client = Balancer::new(Config::default(), Balancing::RoundRobin, connects)?;
+            // Only one of the servers is up.
+            let all_ready = await!(client.ready(2)).map_err(|e| e.kind());
+            await!(client.ready(1))?;
+            let connected = client.states()[0].clone();
+            let response = await!(client.call(context::current(), "hi".into()))?;
+            Ok::<_, io::Error>((all_ready, connected, response))
+        };
+
+        let (all_ready, connected, response) = test_util::run_future(future::join(
+            server,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .1;
+        assert_eq!(all_ready, Err(io::ErrorKind::ConnectionRefused));
+        match connected {
+            ConnectionState::Connected { .. } => {}
+            state => panic!("Unexpected connection state: {:?}", state),
+        }
+        assert_eq!(response, "hi");
+    }
+
+    #[test]
+    fn least_loaded_balancer_avoids_busy_servers() {
+        test_util::init();
+
+        let (server_channels_tx, server_channels_rx) = mpsc::unbounded();
+        let server_channels_tx = Arc::new(Mutex::new(server_channels_tx));
+        let server = Server::<String, String>::default()
+            .incoming(server_channels_rx.map(Ok))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+        let connect = || {
+            let server_channels_tx = server_channels_tx.clone();
+            move || {
+                let (client_channel, server_channel) = transport::channel::unbounded();
+                let _ = server_channels_tx
+                    .lock()
+                    .unwrap()
+                    .unbounded_send(server_channel);
+                future::ready(Ok(client_channel))
+            }
+        };
+        let connects = vec![connect(), connect()];
+        drop(server_channels_tx);
+
+        let in_flight = async move {
+            let mut client = Balancer::new(Config::default(), Balancing::LeastLoaded, connects)?;
+            while client.states().iter().any(|state| match state {
+                ConnectionState::Connected { .. } => false,
+                _ => true,
+            }) {
+                await!(Delay::new(Instant::now() + Duration::from_millis(1)).compat()).unwrap();
+            }
+
+            let first = client.call(context::current(), "hi".into());
+            let first_in_flight = client.in_flight();
+            let _ = await!(client.call(context::current(), "hi".into()))?;
+            // Round robin would send this call to the first server, which already has a call.
+            let second = client.call(context::current(), "hi".into());
+            let in_flight = client.in_flight();
+            drop((first, second));
+            Ok::<_, io::Error>((first_in_flight, in_flight))
+        };
+
+        let (first_in_flight, in_flight) = test_util::run_future(future::join(
+            server,
+            in_flight.unwrap_or_else(|e| panic!(e)),
+        ))
+        .1;
+        assert_eq!(first_in_flight, vec![1, 0]);
+        assert_eq!(in_flight, vec![1, 1]);
+    }
+
+    #[test]
+    fn weighted_balancer_shifts_calls_between_groups() {
+        test_util::init();
+
+        // Serves requests with the name of the group the server is in.
+        let serve = |name: &'static str| {
+            let (server_channels_tx, server_channels_rx) = mpsc::unbounded();
+            let server_channels_tx = Arc::new(Mutex::new(server_channels_tx));
+            let server = Server::<String, String>::default()
+                .incoming(server_channels_rx.map(Ok))
+                .respond_with(move |_ctx, _request| future::ready(Ok(name.to_string())));
+            let connect = move || {
+                let (client_channel, server_channel) = transport::channel::unbounded();
+                let _ = server_channels_tx
+                    .lock()
+                    .unwrap()
+                    .unbounded_send(server_channel);
+                future::ready(Ok(client_channel))
+            };
+            (server, connect)
+        };
+        let (blue, connect_blue) = serve("blue");
+        let (green, connect_green) = serve("green");
+        let groups = vec![(3, vec![connect_blue]), (1, vec![connect_green])];
+
+        let responses = async move {
+            let mut client = Balancer::weighted(Config::default(), Balancing::RoundRobin, groups)?;
+            await!(client.ready(2))?;
+            let mut weighted = vec![];
+            for _ in 0..8 {
+                weighted.push(await!(client.call(context::current(), "hi".into()))?);
+            }
+            client.set_weight(0, 0);
+            let mut shifted = vec![];
+            for _ in 0..2 {
+                shifted.push(await!(client.call(context::current(), "hi".into()))?);
+            }
+            Ok::<_, io::Error>((weighted, client.weights(), shifted))
+        };
+
+        let (weighted, weights, shifted) = test_util::run_future(future::join3(
+            blue,
+            green,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .2;
+        assert_eq!(
+            weighted,
+            vec!["blue", "blue", "blue", "green", "blue", "blue", "blue", "green"]
+        );
+        assert_eq!(weights, vec![0, 1]);
+        assert_eq!(shifted, vec!["green", "green"]);
+    }
+
+    #[test]
+    fn balancer_sends_no_new_calls_to_draining_servers() {
+        test_util::init();
+
+        let (started_tx, mut started_rx) = mpsc::unbounded();
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(Some(release_rx)));
+        // Serves requests, holding up the first "slow" one until it's released.
+        let serve = |name: &'static str| {
+            let (server_channels_tx, server_channels_rx) = mpsc::unbounded();
+            let (handles_tx, handles_rx) = mpsc::unbounded();
+            let (started_tx, release_rx) = (started_tx.clone(), release_rx.clone());
+            let server = Server::<String, String>::default()
+                .incoming(server_channels_rx.map(Ok))
+                .map_ok(move |channel| {
+                    handles_tx.unbounded_send(channel.handle()).unwrap();
+                    channel
+                })
+                .respond_with(move |_ctx, request: String| {
+                    let release = match &*request {
+                        "slow" => release_rx.lock().unwrap().take(),
+                        _ => None,
+                    };
+                    let started_tx = started_tx.clone();
+                    async move {
+                        if let Some(release) = release {
+                            started_tx.unbounded_send(()).unwrap();
+                            let _ = await!(release);
+                        }
+                        Ok(format!("{} {}", name, request))
+                    }
+                });
+            let up = Arc::new(AtomicBool::new(true));
+            let connect = {
+                let up = up.clone();
+                let server_channels_tx = Arc::new(Mutex::new(server_channels_tx));
+                move || {
+                    future::ready(if up.load(Ordering::SeqCst) {
+                        let (client_channel, server_channel) = transport::channel::unbounded();
+                        let _ = server_channels_tx
+                            .lock()
+                            .unwrap()
+                            .unbounded_send(server_channel);
+                        Ok(client_channel)
+                    } else {
+                        Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+                    })
+                }
+            };
+            (server, connect, handles_rx, up)
+        };
+        let (server_a, connect_a, mut handles_a, a_up) = serve("a");
+        let (server_b, connect_b, _handles_b, _) = serve("b");
+
+        let responses = async move {
+            let mut client = Balancer::new(
+                Config::default(),
+                Balancing::RoundRobin,
+                vec![connect_a, connect_b],
+            )?;
+            while client.states().iter().any(|state| match state {
+                ConnectionState::Connected { .. } => false,
+                _ => true,
+            }) {
+                await!(Delay::new(Instant::now() + Duration::from_millis(1)).compat()).unwrap();
+            }
+            let a = await!(handles_a.next()).unwrap();
+
+            // The first call goes to the first server, which holds it up.
+            let slow = client.call(context::current(), "slow".into());
+            let mut others = client.clone();
+            let drained = async move {
+                await!(started_rx.next()).unwrap();
+                // The first server doesn't accept the balancer reconnecting to it.
+                a_up.store(false, Ordering::SeqCst);
+                a.drain();
+                while match others.states()[0] {
+                    ConnectionState::Draining { .. } => false,
+                    _ => true,
+                } {
+                    await!(Delay::new(Instant::now() + Duration::from_millis(1)).compat())
+                        .unwrap();
+                }
+                let first = await!(others.call(context::current(), "hi".into()))?;
+                let second = await!(others.call(context::current(), "bye".into()))?;
+                release_tx.send(()).unwrap();
+                Ok::<_, io::Error>(vec![first, second])
+            };
+            let (slow, others) = await!(future::join(slow, drained));
+            Ok::<_, io::Error>((slow?, others?))
+        };
+
+        let (slow, others) = test_util::run_future(future::join3(
+            server_a,
+            server_b,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .2;
+        // The call in flight to the draining server still finished.
+        assert_eq!(slow, "a slow");
+        assert_eq!(others, vec!["b hi", "b bye"]);
+    }
+}
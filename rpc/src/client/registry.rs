@@ -0,0 +1,393 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Shares connections between the parts of a process that call the same servers.
+//!
+//! Components that each ask a [`Registry`] for a client to the same server, with the same
+//! [config](Config), share one connection, instead of each dialing their own. Components that ask
+//! while the connection is still being made wait for the same attempt, rather than making more.
+//! The connection closes once the last [`Registered`] client using it is dropped, and the next
+//! component to ask makes a new one.
+
+use crate::{
+    client::{
+        self, channel, delivery::SendsOnce, Channel, Client, Config, Notify, Streaming, Uploading,
+    },
+    context, ClientMessage, ServerMessage, Transport,
+};
+use fnv::FnvHashMap;
+use futures::{channel::oneshot, prelude::*};
+use log::debug;
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+};
+
+/// Identifies the clients that share a connection.
+type Key = (SocketAddr, Config);
+
+type Clients<Req, Resp> = Mutex<FnvHashMap<Key, Entry<Req, Resp>>>;
+
+enum Entry<Req, Resp> {
+    /// Being connected; the components waiting for the connection.
+    Connecting(Vec<oneshot::Sender<io::Result<Registered<Req, Resp>>>>),
+    /// Connected, for as long as any registered client uses the connection.
+    Connected(Weak<Registration<Req, Resp>>),
+}
+
+/// Hands out clients that share one connection per server and config. Clones share the same
+/// connections, so a single registry can be handed to every component of a process.
+pub struct Registry<Req, Resp, D> {
+    connect: Arc<D>,
+    clients: Arc<Clients<Req, Resp>>,
+}
+
+impl<Req, Resp, D> Clone for Registry<Req, Resp, D> {
+    fn clone(&self) -> Self {
+        Registry {
+            connect: self.connect.clone(),
+            clients: self.clients.clone(),
+        }
+    }
+}
+
+impl<Req, Resp, D> fmt::Debug for Registry<Req, Resp, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("clients", &self.clients.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl<Req, Resp, D, Fut, T> Registry<Req, Resp, D>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    D: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    /// Returns a registry that makes connections to servers with `connect`.
+    pub fn new(connect: D) -> Self {
+        Registry {
+            connect: Arc::new(connect),
+            clients: Arc::default(),
+        }
+    }
+
+    /// Returns a client to the server at `addr` with `config`, sharing the connection of any
+    /// other client to it with the same config, or connecting to it if there's none.
+    ///
+    /// Must only be called from on an executor.
+    pub fn client(
+        &self,
+        addr: SocketAddr,
+        config: Config,
+    ) -> impl Future<Output = io::Result<Registered<Req, Resp>>> {
+        let (registered_tx, registered) = oneshot::channel();
+        let key = (addr, config);
+        let connect = {
+            let mut clients = self.clients.lock().unwrap();
+            let registration = match clients.get(&key) {
+                Some(Entry::Connected(registration)) => registration.upgrade(),
+                _ => None,
+            };
+            if let Some(registration) = registration {
+                let _ = registered_tx.send(Ok(Registered::new(registration)));
+                false
+            } else if let Some(Entry::Connecting(waiting)) = clients.get_mut(&key) {
+                waiting.push(registered_tx);
+                false
+            } else {
+                clients.insert(key.clone(), Entry::Connecting(vec![registered_tx]));
+                true
+            }
+        };
+
+        let spawned = if connect {
+            debug!("[{}] Connecting a client for the registry.", addr);
+            let connecting = (self.connect)(addr);
+            crate::spawn(register(self.clients.clone(), key, connecting)).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "Could not spawn registry connect task. Is shutdown: {}",
+                        e.is_shutdown()
+                    ),
+                )
+            })
+        } else {
+            Ok(())
+        };
+
+        async move {
+            spawned?;
+            await!(registered).unwrap_or_else(|oneshot::Canceled| {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Registry connect task was dropped.",
+                ))
+            })
+        }
+    }
+}
+
+/// Connects the clients waiting on `key` with the transport `connecting` resolves to.
+async fn register<Req, Resp, Fut, T>(clients: Arc<Clients<Req, Resp>>, key: Key, connecting: Fut)
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Transport<Item = ServerMessage<Resp>, SinkItem = ClientMessage<Req>> + Send + 'static,
+{
+    let channel = match await!(connecting) {
+        Ok(transport) => await!(client::new(key.1.clone(), transport)),
+        Err(e) => Err(e),
+    };
+    let (registration, waiting) = {
+        let mut locked = clients.lock().unwrap();
+        let waiting = match locked.remove(&key) {
+            Some(Entry::Connecting(waiting)) => waiting,
+            _ => vec![],
+        };
+        let registration = channel.map(|channel| {
+            let registration = Arc::new(Registration {
+                key: key.clone(),
+                clients: Arc::downgrade(&clients),
+                channel: Mutex::new(channel),
+            });
+            locked.insert(key.clone(), Entry::Connected(Arc::downgrade(&registration)));
+            registration
+        });
+        (registration, waiting)
+    };
+    // Sent with the lock released, since dropping the clients of waiters that gave up takes it.
+    for registered in waiting {
+        let _ = registered.send(match &registration {
+            Ok(registration) => Ok(Registered::new(registration.clone())),
+            Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+        });
+    }
+}
+
+/// A connection shared by registered clients. Removed from the registry once the last of them is
+/// dropped.
+struct Registration<Req, Resp> {
+    key: Key,
+    clients: Weak<Clients<Req, Resp>>,
+    /// Cloned for each registered client, so that each gets its own queue.
+    channel: Mutex<Channel<Req, Resp>>,
+}
+
+impl<Req, Resp> Drop for Registration<Req, Resp> {
+    fn drop(&mut self) {
+        let clients = match self.clients.upgrade() {
+            Some(clients) => clients,
+            None => return,
+        };
+        let mut clients = clients.lock().unwrap();
+        // Another component may have started connecting again already.
+        let gone = match clients.get(&self.key) {
+            Some(Entry::Connected(registration)) => registration.upgrade().is_none(),
+            _ => false,
+        };
+        if gone {
+            debug!(
+                "[{}] Closing the registry's connection, which is no longer used.",
+                self.key.0
+            );
+            clients.remove(&self.key);
+        }
+    }
+}
+
+/// A client from a [`Registry`], sharing its connection with the other clients to the same
+/// server and config. Clones share the connection too.
+pub struct Registered<Req, Resp> {
+    channel: Channel<Req, Resp>,
+    registration: Arc<Registration<Req, Resp>>,
+}
+
+impl<Req, Resp> Registered<Req, Resp> {
+    fn new(registration: Arc<Registration<Req, Resp>>) -> Self {
+        Registered {
+            channel: registration.channel.lock().unwrap().clone(),
+            registration,
+        }
+    }
+
+    /// Returns the address of the server connected to.
+    pub fn server_addr(&self) -> SocketAddr {
+        self.registration.key.0
+    }
+}
+
+impl<Req, Resp> Clone for Registered<Req, Resp> {
+    fn clone(&self) -> Self {
+        Registered::new(self.registration.clone())
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Registered<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Registered")
+            .field("channel", &self.channel)
+            .finish()
+    }
+}
+
+impl<Req, Resp> SendsOnce for Registered<Req, Resp> {}
+
+impl<'a, Req, Resp> Client<'a, Req> for Registered<Req, Resp>
+where
+    Req: 'a,
+    Resp: 'a,
+{
+    type Response = Resp;
+    type Future = channel::Call<'a, Req, Resp>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> channel::Call<'a, Req, Resp> {
+        self.channel.call(ctx, request)
+    }
+}
+
+impl<'a, Req, Resp> Notify<'a, Req> for Registered<Req, Resp>
+where
+    Req: 'a,
+    Resp: 'a,
+{
+    type Future = channel::Notification<'a, Req, Resp>;
+
+    fn notify(
+        &'a mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> channel::Notification<'a, Req, Resp> {
+        self.channel.notify(ctx, request)
+    }
+}
+
+impl<'a, Req, Resp> Streaming<'a, Req> for Registered<Req, Resp>
+where
+    Req: 'a,
+    Resp: 'a,
+{
+    type Response = Resp;
+    type Future = channel::StreamCall<'a, Req, Resp>;
+
+    fn stream(
+        &'a mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> channel::StreamCall<'a, Req, Resp> {
+        self.channel.stream(ctx, request)
+    }
+}
+
+impl<'a, Req, Resp, S> Uploading<'a, Req, S> for Registered<Req, Resp>
+where
+    Req: 'a,
+    Resp: 'a,
+    S: Stream<Item = Req> + 'a,
+{
+    type Response = Resp;
+    type Future = channel::BodyCall<'a, Req, Resp, S>;
+    type StreamFuture = channel::BodyStreamCall<'a, Req, Resp, S>;
+
+    fn call_with_body(
+        &'a mut self,
+        ctx: context::Context,
+        request: Req,
+        body: S,
+    ) -> channel::BodyCall<'a, Req, Resp, S> {
+        self.channel.call_with_body(ctx, request, body)
+    }
+
+    fn stream_with_body(
+        &'a mut self,
+        ctx: context::Context,
+        request: Req,
+        body: S,
+    ) -> channel::BodyStreamCall<'a, Req, Resp, S> {
+        self.channel.stream_with_body(ctx, request, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Registry;
+    use crate::{
+        client::{self, Client},
+        context,
+        server::Handler,
+        test_util, transport, Server,
+    };
+    use futures::{channel::mpsc, future, prelude::*};
+    use std::{
+        io,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    #[test]
+    fn registry_shares_connections_until_their_clients_are_dropped() {
+        test_util::init();
+
+        let (server_channels_tx, server_channels_rx) = mpsc::unbounded();
+        let server_channels_tx = Arc::new(Mutex::new(server_channels_tx));
+        let server = Server::<String, String>::default()
+            .incoming(server_channels_rx.map(Ok))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+        let dials = Arc::new(AtomicUsize::new(0));
+        let registry: Registry<String, String, _> = Registry::new({
+            let dials = dials.clone();
+            move |_| {
+                dials.fetch_add(1, Ordering::SeqCst);
+                let (client_channel, server_channel) = transport::channel::unbounded();
+                let _ = server_channels_tx
+                    .lock()
+                    .unwrap()
+                    .unbounded_send(server_channel);
+                future::ready(Ok(client_channel))
+            }
+        });
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
+
+        let responses = async move {
+            // Asked for at once, so the second waits for the first's connection.
+            let (a, b) = await!(future::join(
+                registry.client(addr, client::Config::default()),
+                registry.client(addr, client::Config::default()),
+            ));
+            let (mut a, mut b) = (a?, b?);
+            let response_a = await!(a.call(context::current(), "a".into()))?;
+            let response_b = await!(b.call(context::current(), "b".into()))?;
+            let shared = dials.load(Ordering::SeqCst);
+
+            drop((a, b));
+            let mut c = await!(registry.client(addr, client::Config::default()))?;
+            let response_c = await!(c.call(context::current(), "c".into()))?;
+            Ok::<_, io::Error>((
+                vec![response_a, response_b, response_c],
+                shared,
+                dials.load(Ordering::SeqCst),
+            ))
+        };
+
+        let (responses, shared, redialed) = test_util::run_future(future::join(
+            server,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .1;
+        assert_eq!(responses, vec!["a", "b", "c"]);
+        assert_eq!(shared, 1);
+        assert_eq!(redialed, 2);
+    }
+}
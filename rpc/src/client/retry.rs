@@ -0,0 +1,270 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Retries failed calls, backing off between attempts.
+//!
+//! A [`Retry`] client resends a call that failed with an error its [`Policy`] deems retryable,
+//! until the call succeeds, runs out of attempts, or would be retried after its deadline. By
+//! default, only errors that mean the server didn't handle the request are retried, so that calls
+//! aren't handled twice; adding other errors is safe only for idempotent rpcs.
+//!
+//! Only requests that can be [resent](Resend) are retried. The requests of services' rpcs can only
+//! be resent if the rpcs are declared [at least once](crate::client::delivery::Delivery), and the
+//! client stubs of rpcs declared at most once don't compile over a [`Retry`] client at all.
+
+use crate::{
+    client::{delivery::Resend, Channel, Client},
+    context, ServerError,
+};
+use futures::{compat::Future01CompatExt, prelude::*};
+use log::debug;
+use rand::Rng;
+use std::{
+    fmt, io,
+    pin::Pin,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio_timer::Delay;
+
+/// Settings that control which calls a [`Retry`] client retries, and when.
+#[non_exhaustive]
+#[derive(Clone)]
+pub struct Policy {
+    /// The most times a call is sent, including the first.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. The wait doubles after every attempt, up to
+    /// `max_backoff`.
+    pub initial_backoff: Duration,
+    /// The longest to wait between attempts.
+    pub max_backoff: Duration,
+    /// Whether to wait a random amount between half the backoff and all of it, so that clients
+    /// that failed at the same time don't all retry at the same time.
+    pub jitter: bool,
+    /// Decides whether a call that failed with an error should be retried.
+    pub retryable: fn(&io::Error) -> bool,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(5),
+            jitter: true,
+            retryable: not_handled,
+        }
+    }
+}
+
+impl fmt::Debug for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Policy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+/// Returns true for errors that mean the server didn't handle the request: the server throttled
+/// it, or the client never connected. The default [`Policy::retryable`].
+pub fn not_handled(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::NotConnected => true,
+        _ => false,
+    }
+}
+
+/// A [`Client`] that retries failed calls according to a [`Policy`], if their requests can be
+/// [resent](Resend).
+pub struct Retry<Req, Resp> {
+    inner: Channel<Req, Resp>,
+    policy: Policy,
+}
+
+impl<Req, Resp> Retry<Req, Resp> {
+    /// Returns a client that sends requests over `channel`, retrying them according to `policy`.
+    pub fn new(channel: Channel<Req, Resp>, policy: Policy) -> Self {
+        Retry {
+            inner: channel,
+            policy,
+        }
+    }
+}
+
+impl<Req, Resp> Clone for Retry<Req, Resp> {
+    fn clone(&self) -> Self {
+        Retry {
+            inner: self.inner.clone(),
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Retry<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Retry")
+            .field("inner", &self.inner)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl<'a, Req, Resp> Client<'a, Req> for Retry<Req, Resp>
+where
+    Req: Resend + Send + 'static,
+    Resp: Send + 'static,
+{
+    type Response = Resp;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Resp>> + Send + 'a>>;
+
+    fn call(&'a mut self, ctx: context::Context, mut request: Req) -> Self::Future {
+        let channel = &mut self.inner;
+        let policy = self.policy.clone();
+        async move {
+            let mut backoff = policy.initial_backoff;
+            let mut attempt = 1;
+            loop {
+                let resend = request.resend();
+                let e = match await!(channel.call(ctx, request)) {
+                    Ok(response) => return Ok(response),
+                    Err(e) => e,
+                };
+                let resend = match resend {
+                    Some(resend) if attempt < policy.max_attempts && (policy.retryable)(&e) => {
+                        resend
+                    }
+                    _ => return Err(e),
+                };
+                // Waits at least as long as the server asked.
+                let retry_after = e
+                    .get_ref()
+                    .and_then(|e| e.downcast_ref::<ServerError>())
+                    .and_then(|e| e.retry_after);
+                let wait = match retry_after {
+                    Some(retry_after) => retry_after.max(backoff),
+                    None if policy.jitter => jittered(backoff),
+                    None => backoff,
+                };
+                let retry_at = match retry_at(wait, ctx.deadline) {
+                    Some(retry_at) => retry_at,
+                    None => return Err(e),
+                };
+                debug!(
+                    "[{}] Retrying in {:?}, after attempt {} failed: {}",
+                    ctx.trace_id(),
+                    wait,
+                    attempt,
+                    e
+                );
+                if let Err(e) = await!(Delay::new(retry_at).compat()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Could not back off before retrying: {}", e),
+                    ));
+                }
+                attempt += 1;
+                request = resend;
+                backoff = backoff
+                    .checked_mul(2)
+                    .map_or(policy.max_backoff, |backoff| {
+                        backoff.min(policy.max_backoff)
+                    });
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Returns when to retry after waiting `wait`, or None if that's at or past `deadline`. A wait
+/// too long to represent, e.g. a server's bogus retry-after hint, is always past the deadline.
+fn retry_at(wait: Duration, deadline: SystemTime) -> Option<Instant> {
+    match SystemTime::now().checked_add(wait) {
+        Some(retry_at) if retry_at < deadline => Instant::now().checked_add(wait),
+        _ => None,
+    }
+}
+
+/// Returns a random duration between half of `backoff` and all of it.
+fn jittered(backoff: Duration) -> Duration {
+    let nanos = backoff.as_nanos() as f64 * rand::thread_rng().gen_range(0.5, 1.);
+    Duration::from_nanos(nanos as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{jittered, not_handled, retry_at, Policy, Retry};
+    use crate::{
+        client::{self, Client},
+        context, test_util, Server,
+    };
+    use futures::future;
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, SystemTime},
+    };
+
+    #[test]
+    fn only_errors_from_unhandled_requests_are_retryable() {
+        assert!(not_handled(&io::Error::from(io::ErrorKind::WouldBlock)));
+        assert!(not_handled(&io::Error::from(io::ErrorKind::NotConnected)));
+        // The server may have handled a request in flight when the connection broke.
+        assert!(!not_handled(&io::Error::from(
+            io::ErrorKind::ConnectionReset
+        )));
+        assert!(!not_handled(&io::Error::from(io::ErrorKind::InvalidInput)));
+    }
+
+    #[test]
+    fn jitter_waits_at_least_half_the_backoff() {
+        let backoff = Duration::from_millis(100);
+        for _ in 0..100 {
+            let wait = jittered(backoff);
+            assert!(wait >= backoff / 2 && wait <= backoff, "{:?}", wait);
+        }
+    }
+
+    #[test]
+    fn waits_past_the_deadline_are_not_retried() {
+        let deadline = SystemTime::now() + Duration::from_secs(10);
+        assert!(retry_at(Duration::from_secs(1), deadline).is_some());
+        assert!(retry_at(Duration::from_secs(10), deadline).is_none());
+        assert!(retry_at(Duration::from_secs(u64::max_value()), deadline).is_none());
+    }
+
+    #[test]
+    fn retry_resends_throttled_requests() {
+        test_util::init();
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let (client_channel, server) = test_util::serve(Server::<String, String>::default(), {
+            let attempts = attempts.clone();
+            move |_ctx, request| {
+                future::ready(match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 | 1 => Err(io::Error::new(io::ErrorKind::WouldBlock, "Busy.")),
+                    _ => Ok(request),
+                })
+            }
+        });
+
+        let response = async {
+            let channel = await!(client::new(client::Config::default(), client_channel))?;
+            let mut policy = Policy::default();
+            policy.initial_backoff = Duration::from_millis(1);
+            let mut client = Retry::new(channel, policy);
+            await!(client.call(context::current(), "hi".into()))
+        };
+
+        let response = test_util::run_future(future::join(server, response)).1;
+        assert_eq!(response.unwrap(), "hi");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+}
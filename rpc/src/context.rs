@@ -7,7 +7,16 @@
 //! Provides a request context that carries a deadline and trace context. This context is sent from
 //! client to server and is used by the server to enforce response deadlines.
 
-use std::time::{Duration, SystemTime};
+use futures::{
+    task::{Context as TaskContext, Poll},
+    Future,
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    cell::Cell,
+    pin::Pin,
+    time::{Duration, SystemTime},
+};
 use trace::{self, TraceId};
 
 /// A request context that carries request-scoped information like deadlines and trace information.
@@ -26,14 +35,63 @@ pub struct Context {
     /// include the same `trace_id` as that included on the original request. This way,
     /// users can trace related actions across a distributed system.
     pub trace_context: trace::Context,
+    /// A token the client sends with the request, e.g. the sequence number of its last write, for
+    /// servers that implement read-your-writes across replicas. The server echoes it in the
+    /// response. See [`client::causality`](crate::client::causality).
+    pub causality: Option<u64>,
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<Context>> = Cell::new(None);
 }
 
 /// Returns the context for the current request, or a default Context if no request is active.
-// TODO: populate Context with request-scoped data, with default fallbacks.
+///
+/// The server makes a request's context current while polling the request's handler, so requests
+/// a handler makes with the current context carry on the incoming request's deadline and trace.
 pub fn current() -> Context {
-    Context {
+    CURRENT.with(Cell::get).unwrap_or_else(|| Context {
         deadline: SystemTime::now() + Duration::from_secs(10),
         trace_context: trace::Context::new_root(),
+        causality: None,
+    })
+}
+
+/// Returns a future that makes `ctx` the [current](current) context while polling `future`. Useful
+/// for carrying a request's context over to tasks spawned by its handler.
+pub fn scope<F: Future>(ctx: Context, future: F) -> Scoped<F> {
+    Scoped { ctx, future }
+}
+
+/// A future that makes a context current while it is polled. Returned by [`scope`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Scoped<F> {
+    ctx: Context,
+    future: F,
+}
+
+impl<F> Scoped<F> {
+    unsafe_unpinned!(ctx: Context);
+    unsafe_pinned!(future: F);
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<F::Output> {
+        /// Restores the previous context, even if the future panics.
+        struct Reset(Option<Context>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CURRENT.with(|current| current.set(self.0));
+            }
+        }
+
+        let ctx = *self.as_mut().ctx();
+        let _reset = Reset(CURRENT.with(|current| current.replace(Some(ctx))));
+        self.as_mut().future().poll(cx)
     }
 }
 
@@ -43,3 +101,127 @@ impl Context {
         &self.trace_context.trace_id
     }
 }
+
+/// Splits what's left of a request's deadline across the requests its handler makes downstream,
+/// one after another, so that they all complete by the request's own deadline.
+///
+/// Each downstream request is given its share of the time left when it's made, so time that
+/// earlier requests didn't use goes to later ones. Time [reserved](Budget::with_reserve) for the
+/// handler's own work after the downstream requests is never given out. E.g. a budget weighted
+/// `[1, 2]` gives the first request a third of the time left, and the second, all the rest.
+#[derive(Clone, Debug)]
+pub struct Budget {
+    ctx: Context,
+    reserve: Duration,
+    /// The weight of each planned request.
+    weights: Vec<u32>,
+    /// The index of the next request.
+    next: usize,
+}
+
+impl Budget {
+    /// Returns a budget that splits the time left before the deadline of `ctx` evenly across
+    /// `requests` requests.
+    pub fn even(ctx: Context, requests: usize) -> Self {
+        Budget::weighted(ctx, vec![1; requests])
+    }
+
+    /// Returns a budget that splits the time left before the deadline of `ctx` across requests in
+    /// proportion to their `weights`, in the order the requests are made.
+    pub fn weighted(ctx: Context, weights: Vec<u32>) -> Self {
+        Budget {
+            ctx,
+            reserve: Duration::from_secs(0),
+            weights,
+            next: 0,
+        }
+    }
+
+    /// Keeps `reserve` of the time left for work after the last downstream request.
+    pub fn with_reserve(mut self, reserve: Duration) -> Self {
+        self.reserve = reserve;
+        self
+    }
+
+    /// Returns the context of the next downstream request, which carries the trace of the budget's
+    /// request, and a deadline that gives the request its share of the time left. Past the
+    /// planned requests, the deadline gives out all of the time left.
+    pub fn next_context(&mut self) -> Context {
+        self.next_context_at(SystemTime::now())
+    }
+
+    fn next_context_at(&mut self, now: SystemTime) -> Context {
+        let left = self
+            .ctx
+            .deadline
+            .duration_since(now)
+            .unwrap_or_default()
+            .checked_sub(self.reserve)
+            .unwrap_or_default();
+        let remaining = &self.weights[self.next.min(self.weights.len())..];
+        let total: u64 = remaining.iter().map(|&weight| u64::from(weight)).sum();
+        let share = match remaining.first() {
+            Some(&weight) if total > 0 => {
+                let nanos = left.as_nanos() * u128::from(weight) / u128::from(total);
+                Duration::from_nanos(nanos as u64)
+            }
+            _ => left,
+        };
+        self.next += 1;
+        Context {
+            deadline: now + share,
+            ..self.ctx
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current, scope, Budget};
+    use futures::{executor::block_on, future};
+    use std::time::Duration;
+
+    #[test]
+    fn scope_makes_context_current() {
+        let ctx = current();
+        let nested = block_on(scope(ctx, future::lazy(|_| current())));
+        assert_eq!(nested.trace_context, ctx.trace_context);
+        assert_eq!(nested.deadline, ctx.deadline);
+
+        // Outside the scope, there is no current request.
+        assert_ne!(current().trace_context, ctx.trace_context);
+    }
+
+    #[test]
+    fn budget_splits_time_left_by_weight() {
+        let mut ctx = current();
+        let now = ctx.deadline - Duration::from_millis(1000);
+        ctx.deadline = now + Duration::from_millis(1000);
+        let mut budget = Budget::weighted(ctx, vec![1, 3]).with_reserve(Duration::from_millis(200));
+
+        let first = budget.next_context_at(now);
+        assert_eq!(first.deadline, now + Duration::from_millis(200));
+        assert_eq!(first.trace_context, ctx.trace_context);
+
+        // The first request took only 100ms, so the second gets the rest of its share.
+        let now = now + Duration::from_millis(100);
+        let second = budget.next_context_at(now);
+        assert_eq!(second.deadline, now + Duration::from_millis(700));
+
+        // Unplanned requests get all of the time left, but never the reserve.
+        let now = now + Duration::from_millis(750);
+        assert_eq!(budget.next_context_at(now).deadline, now);
+    }
+
+    #[test]
+    fn even_budget_shares_equally() {
+        let mut ctx = current();
+        let now = ctx.deadline - Duration::from_millis(900);
+        ctx.deadline = now + Duration::from_millis(900);
+        let mut budget = Budget::even(ctx, 3);
+        assert_eq!(
+            budget.next_context_at(now).deadline,
+            now + Duration::from_millis(300)
+        );
+    }
+}
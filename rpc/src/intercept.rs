@@ -0,0 +1,197 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Chains of interceptors that wrap every call, on the client or the server, e.g. for logging,
+//! metrics, or auth checks.
+//!
+//! An [`Interceptor`] is given each call's request along with the [`Next`] link in the chain,
+//! which it can run to continue the call, after changing the request if it likes, or not run, to
+//! fail the call itself. The last link sends the request, on the client, or handles it, on the
+//! server. Interceptors run in the order they're given, so the first one sees the call first and
+//! its response last.
+//!
+//! A chain wraps a request handler with [`handler`], and a client's channel with [`Intercepted`].
+//! Closures taking a context, a request, and a [`Next`] are interceptors.
+
+use crate::{
+    client::{delivery::SendsOnce, Channel, Client},
+    context,
+};
+use futures::{future::BoxFuture, prelude::*};
+use std::{fmt, io, sync::Arc};
+
+/// The future of a call's response.
+pub type Call<Resp> = BoxFuture<'static, io::Result<Resp>>;
+
+/// Wraps calls with a request of type `Req` and a response of type `Resp`.
+pub trait Interceptor<Req, Resp>: Send + Sync + 'static {
+    /// Intercepts a call, running `next` to continue it.
+    fn intercept(&self, ctx: context::Context, request: Req, next: Next<Req, Resp>) -> Call<Resp>;
+}
+
+impl<Req, Resp, F, Fut> Interceptor<Req, Resp> for F
+where
+    F: Fn(context::Context, Req, Next<Req, Resp>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+{
+    fn intercept(&self, ctx: context::Context, request: Req, next: Next<Req, Resp>) -> Call<Resp> {
+        self(ctx, request, next).boxed()
+    }
+}
+
+/// The interceptors of a chain, in order.
+pub type Interceptors<Req, Resp> = Arc<Vec<Arc<dyn Interceptor<Req, Resp>>>>;
+
+type Last<Req, Resp> = Box<dyn FnOnce(context::Context, Req) -> Call<Resp> + Send>;
+
+/// The rest of a call's chain, after the interceptor it's given to.
+pub struct Next<Req, Resp> {
+    interceptors: Interceptors<Req, Resp>,
+    index: usize,
+    last: Last<Req, Resp>,
+}
+
+impl<Req, Resp> fmt::Debug for Next<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Next")
+            .field("remaining", &(self.interceptors.len() - self.index))
+            .finish()
+    }
+}
+
+impl<Req, Resp> Next<Req, Resp> {
+    /// Continues the call with `request`, through the remaining interceptors.
+    pub fn run(self, ctx: context::Context, request: Req) -> Call<Resp> {
+        match self.interceptors.get(self.index).cloned() {
+            Some(interceptor) => {
+                let next = Next {
+                    index: self.index + 1,
+                    ..self
+                };
+                interceptor.intercept(ctx, request, next)
+            }
+            None => (self.last)(ctx, request),
+        }
+    }
+}
+
+/// Wraps request handler `f` in a chain of `interceptors`, whose last link runs `f`.
+pub fn handler<Req, Resp, F, Fut>(
+    interceptors: Interceptors<Req, Resp>,
+    f: F,
+) -> impl FnOnce(context::Context, Req) -> Call<Resp> + Send + 'static + Clone
+where
+    Req: 'static,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+{
+    move |ctx, request| {
+        let next = Next {
+            interceptors,
+            index: 0,
+            last: Box::new(move |ctx, request| f(ctx, request).boxed()),
+        };
+        next.run(ctx, request)
+    }
+}
+
+/// A [`Client`] that sends every call through a chain of interceptors, whose last link sends it
+/// over a channel.
+pub struct Intercepted<Req, Resp> {
+    inner: Channel<Req, Resp>,
+    interceptors: Interceptors<Req, Resp>,
+}
+
+impl<Req, Resp> Intercepted<Req, Resp> {
+    /// Returns a client that sends calls through `interceptors`, and then over `channel`.
+    pub fn new(channel: Channel<Req, Resp>, interceptors: Interceptors<Req, Resp>) -> Self {
+        Intercepted {
+            inner: channel,
+            interceptors,
+        }
+    }
+}
+
+impl<Req, Resp> Clone for Intercepted<Req, Resp> {
+    fn clone(&self) -> Self {
+        Intercepted {
+            inner: self.inner.clone(),
+            interceptors: self.interceptors.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Intercepted<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Intercepted")
+            .field("inner", &self.inner)
+            .field("interceptors", &self.interceptors.len())
+            .finish()
+    }
+}
+
+impl<'a, Req, Resp> Client<'a, Req> for Intercepted<Req, Resp>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    type Response = Resp;
+    type Future = BoxFuture<'a, io::Result<Resp>>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        let channel = self.inner.clone();
+        let next = Next {
+            interceptors: self.interceptors.clone(),
+            index: 0,
+            last: Box::new(move |ctx, request| {
+                let mut channel = channel;
+                async move { await!(channel.call(ctx, request)) }.boxed()
+            }),
+        };
+        next.run(ctx, request)
+    }
+}
+
+impl<Req, Resp> SendsOnce for Intercepted<Req, Resp> {}
+
+#[cfg(test)]
+mod tests {
+    use super::{handler, Interceptor, Next};
+    use futures::{executor::block_on, future, prelude::*};
+    use std::{io, sync::Arc};
+
+    #[test]
+    fn interceptors_run_in_order() {
+        let tag = |name: &'static str| {
+            move |ctx, request: String, next: Next<String, String>| {
+                next.run(ctx, format!("{} {}", request, name))
+                    .map_ok(move |response| format!("{} {}", response, name))
+            }
+        };
+        let interceptors: Vec<Arc<dyn Interceptor<String, String>>> =
+            vec![Arc::new(tag("outer")), Arc::new(tag("inner"))];
+        let handler = handler(Arc::new(interceptors), |_, request| {
+            future::ready(Ok(format!("({})", request)))
+        });
+
+        let response = block_on(handler(crate::context::current(), "hi".into())).unwrap();
+        assert_eq!(response, "(hi outer inner) inner outer");
+    }
+
+    #[test]
+    fn interceptor_can_fail_the_call() {
+        let deny = |_, _: String, _: Next<String, String>| {
+            future::ready(Err(io::Error::from(io::ErrorKind::PermissionDenied)))
+        };
+        let interceptors: Vec<Arc<dyn Interceptor<String, String>>> = vec![Arc::new(deny)];
+        let handler = handler(Arc::new(interceptors), |_, _| -> future::Ready<_> {
+            unreachable!("The interceptor didn't run the handler.")
+        });
+
+        let e = block_on(handler(crate::context::current(), "hi".into())).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
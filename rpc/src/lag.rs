@@ -0,0 +1,207 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Measures how long the executor takes to get around to ready tasks.
+//!
+//! When a task blocks its thread, e.g. on synchronous I/O or a long computation, other tasks on
+//! the executor can't run, and every client and server sharing it stalls. [`monitor`] spawns a
+//! probe that repeatedly sleeps for an interval and records how late it wakes up, in a
+//! [`Histogram`] with exponentially growing buckets, so that such stalls show up in metrics.
+
+use futures::compat::Future01CompatExt;
+use log::warn;
+use std::{
+    io,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+use tokio_timer::Delay;
+
+/// The number of buckets in a [`Histogram`]. The last bucket counts every sample of at least
+/// 2<sup>`BUCKETS` - 2</sup> microseconds, about 34 seconds.
+pub const BUCKETS: usize = 27;
+
+/// Counts durations in buckets whose bounds double, starting from one microsecond.
+///
+/// Bucket 0 counts durations under 1µs, and bucket `i` counts durations under 2<sup>i</sup>µs that
+/// don't fit in bucket `i - 1`. The last bucket has no upper bound.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+    sum: Duration,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: [0; BUCKETS],
+            sum: Duration::from_secs(0),
+            max: Duration::from_secs(0),
+        }
+    }
+}
+
+impl Histogram {
+    /// Counts `sample`.
+    pub fn record(&mut self, sample: Duration) {
+        self.counts[bucket(sample)] += 1;
+        self.sum += sample;
+        self.max = self.max.max(sample);
+    }
+
+    /// Returns the number of samples counted.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the sum of the samples counted.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// Returns the longest sample counted.
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns each bucket's upper bound and count, in order. The last bucket's bound is `None`.
+    pub fn buckets(&self) -> Vec<(Option<Duration>, u64)> {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, &count)| (upper_bound(i), count))
+            .collect()
+    }
+
+    /// Returns the upper bound of the bucket holding the `quantile`th sample, e.g. 0.99 for the
+    /// 99th percentile, or the longest sample if that's in the last bucket. Returns zero if no
+    /// samples were counted.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let rank = (quantile.max(0.).min(1.) * self.count() as f64).ceil() as u64;
+        let mut seen = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if count > 0 && seen >= rank {
+                return upper_bound(i).unwrap_or(self.max).min(self.max);
+            }
+        }
+        Duration::from_secs(0)
+    }
+}
+
+fn bucket(sample: Duration) -> usize {
+    let micros = sample.as_secs() * 1_000_000 + u64::from(sample.subsec_micros());
+    // The number of bits needed for `micros`: 0 for 0µs, 1 for 1µs, 2 for 2-3µs, etc.
+    let bits = 64 - micros.leading_zeros() as usize;
+    bits.min(BUCKETS - 1)
+}
+
+fn upper_bound(bucket: usize) -> Option<Duration> {
+    if bucket == BUCKETS - 1 {
+        None
+    } else {
+        Some(Duration::from_micros(1 << bucket))
+    }
+}
+
+/// A handle to an event-loop lag probe, started by [`monitor`]. The probe stops once every clone
+/// of the handle is dropped.
+#[derive(Clone, Debug)]
+pub struct LagMonitor {
+    histogram: Arc<Mutex<Histogram>>,
+}
+
+impl LagMonitor {
+    /// Returns the lag recorded so far.
+    pub fn histogram(&self) -> Histogram {
+        self.histogram.lock().unwrap().clone()
+    }
+
+    /// Returns the lag recorded so far, and starts recording anew, e.g. to export the lag of each
+    /// scrape interval.
+    pub fn take_histogram(&self) -> Histogram {
+        std::mem::replace(&mut *self.histogram.lock().unwrap(), Histogram::default())
+    }
+}
+
+/// Spawns a probe that wakes up every `interval` and records how long after its timer fired it
+/// got to run.
+///
+/// The probe runs on the spawn set by [`init`](crate::init) or [`init_thread`](crate::init_thread),
+/// so it measures the executor that runs the clients and servers. The timer has millisecond
+/// granularity, so lag under a millisecond is noise.
+pub fn monitor(interval: Duration) -> io::Result<LagMonitor> {
+    let histogram = Arc::new(Mutex::new(Histogram::default()));
+    crate::spawn(probe(interval, Arc::downgrade(&histogram))).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Could not spawn lag probe. Is shutdown: {}",
+                e.is_shutdown()
+            ),
+        )
+    })?;
+    Ok(LagMonitor { histogram })
+}
+
+async fn probe(interval: Duration, histogram: Weak<Mutex<Histogram>>) {
+    loop {
+        let deadline = Instant::now() + interval;
+        if let Err(e) = await!(Delay::new(deadline).compat()) {
+            warn!("Stopping lag probe, because the timer failed: {}", e);
+            return;
+        }
+        let now = Instant::now();
+        let lag = if now > deadline {
+            now - deadline
+        } else {
+            Duration::from_secs(0)
+        };
+        match histogram.upgrade() {
+            Some(histogram) => histogram.lock().unwrap().record(lag),
+            None => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Histogram, BUCKETS};
+    use std::time::Duration;
+
+    #[test]
+    fn samples_are_counted_in_exponential_buckets() {
+        let mut histogram = Histogram::default();
+        histogram.record(Duration::from_micros(0));
+        histogram.record(Duration::from_micros(3));
+        histogram.record(Duration::from_micros(4));
+        histogram.record(Duration::from_secs(3600));
+
+        let buckets = histogram.buckets();
+        assert_eq!(buckets.len(), BUCKETS);
+        assert_eq!(buckets[0], (Some(Duration::from_micros(1)), 1));
+        assert_eq!(buckets[2], (Some(Duration::from_micros(4)), 1));
+        assert_eq!(buckets[3], (Some(Duration::from_micros(8)), 1));
+        assert_eq!(buckets[BUCKETS - 1], (None, 1));
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.max(), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn quantiles_are_bucket_upper_bounds() {
+        let mut histogram = Histogram::default();
+        for _ in 0..99 {
+            histogram.record(Duration::from_micros(100));
+        }
+        histogram.record(Duration::from_millis(50));
+
+        assert_eq!(histogram.quantile(0.5), Duration::from_micros(128));
+        assert_eq!(histogram.quantile(0.99), Duration::from_micros(128));
+        assert_eq!(histogram.quantile(1.), Duration::from_millis(50));
+        assert_eq!(Histogram::default().quantile(0.99), Duration::from_secs(0));
+    }
+}
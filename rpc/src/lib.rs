@@ -18,33 +18,125 @@
 //!
 //! Features:
 //! * RPC deadlines, both client- and server-side.
+//! * Per-request [`metadata`], sent alongside the request payload.
+//! * Optional [causality tokens](client::causality), echoed by the server, for read-your-writes
+//!   across replicas.
 //! * Cascading cancellation (works with multiple hops).
+//! * Structured [errors](ServerError), with [codes](ErrorCode) clients can match on. Handlers that
+//!   panic fail their request with an internal error.
+//! * Responses are sent as soon as they're ready (cheapest first, if given a cost), or, if
+//!   configured to, in request order.
 //! * Configurable limits
 //!    * In-flight requests, both client and server-side.
-//!        * Server-side limit is per-connection.
-//!        * When the server reaches the in-flight request maximum, it returns a throttled error
-//!          to the client.
+//!        * Server-side limit is per-connection, and optionally also server-wide, as a limit on
+//!          running handlers with a bounded queue of requests waiting to run.
+//!        * When the server reaches the in-flight request maximum, it returns a throttled,
+//!          [overloaded](ErrorCode::Overloaded) error to the client, or, if configured to,
+//!          closes the connection.
+//!        * Errors can ask the client to back off for a while, which the client honors by holding
+//!          new requests until then.
 //!        * When the client reaches the in-flight request max, messages are buffered up to a
 //!          configurable maximum, beyond which the requests are back-pressured.
 //!    * Server connections.
 //!        * Total and per-IP limits.
 //!        * When an incoming connection is accepted, if already at maximum, the connection is
 //!          dropped.
-//! * Transport agnostic.
+//! * Pluggable admission control, to shed load on overload signals the server doesn't track.
+//! * An optional warm-up period after the server starts, over which it sheds less and less load.
+//! * An optional server-wide limit on running handlers, which connections take turns under.
+//! * Optional concurrency limits that adapt to latency, on the client and server.
+//! * Clients that reconnect, with backoff, when their connection breaks, alone, in pools, balanced
+//!   over several servers, or in failover tiers.
+//! * Balanced clients that follow the servers of a service as they come and go, as found by a
+//!   pluggable [`Resolver`](client::discovery::Resolver), e.g. one over DNS.
+//! * A [registry](client::registry) of clients that share one connection per server and config,
+//!   across the components of a process.
+//! * Clients that retry failed calls, with backoff and jitter, per a configurable policy.
+//! * Deadline [budgets](context::Budget) that split what's left of a request's deadline across
+//!   the requests its handler makes downstream.
+//! * [Batch](server::batch) calls, with a result per item and a cap on how many items are handled
+//!   at once.
+//! * Responses the client may [cache](client::cache) for as long as the server says, and
+//!   revalidate by version once stale.
+//! * Request [deduplication](server::dedup) by idempotency key, replaying stored responses to
+//!   resent requests, with an in-memory store or a pluggable one shared by several servers.
+//! * One-way [notifications](client::Channel::notify), which the server handles without
+//!   responding, so the client needn't wait on or track a response.
+//! * [Streamed responses](server::streaming), whose items the server sends as they're ready,
+//!   ahead of the final response, with back-pressure from the client that reads them, and
+//!   streamed request [bodies](server::streaming::body), which the client uploads after the
+//!   request, for client-streaming and bidirectional calls. Large payloads can be
+//!   [uploaded](upload) in checksummed chunks that resume where an interrupted upload left off.
+//! * [Topics](server::pubsub) that handlers publish to and stream to subscribers, with a
+//!   per-topic policy for subscribers that fall behind and a cap on subscriptions per connection.
+//! * Bounded memory use per connection. The queues of requests and responses, and of stream items,
+//!   have capacities set by the configs. The few queues without a capacity hold at most one
+//!   message per something else that's bounded: a client's queue of canceled requests holds one
+//!   per request in flight, its queue of new request queues one per clone of the channel, and a
+//!   server's queue of closed connections one per open connection. Bounding the transport's own
+//!   buffers is up to the transport.
+//! * Transport agnostic, with an in-memory [loopback](transport::channel::loopback) for tests.
+//!   Handlers see the [credentials](server::identity::credentials) of the peer's process when the
+//!   transport can tell them, e.g. over Unix sockets.
+//! * [Interceptors](intercept) that wrap every call, on the client or the server, e.g. for logging,
+//!   metrics, or auth checks.
+//! * Graceful server shutdown, which drains connections within a grace period.
+//! * Connections closed on purpose say why, e.g. shutdown or idle timeout, and calls they fail
+//!   carry the [reason](ConnectionClosed).
+//! * An owner for the background tasks tarpc spawns, to join or stop them all at once.
+//! * A probe that records event-loop lag in a histogram, to catch tasks that block the executor.
+//! * Built-in [metrics](metrics) of the requests of clients and servers, and of the bytes on the
+//!   wire, reported to a pluggable backend, e.g. Prometheus or StatsD.
+//! * Distributed [tracing](tracing), with the spans of calls and requests exported to a pluggable
+//!   backend, e.g. Zipkin or Jaeger.
+//! * Leveled logging through the [`log`](https://docs.rs/log) crate. Records about a request start
+//!   with `[trace_id/peer_addr]`, and records about a connection include its
+//!   [`ConnectionId`](trace::ConnectionId), so records can be filtered by trace or connection.
+//!
+//! The client and server are behind the default `runtime` feature. Without it, only the wire
+//! types, [`context`], and [`Transport`] are built, with no timer or runtime dependencies, for
+//! tools that only read or write messages, e.g. to persist or replay request logs.
 
+#[cfg(feature = "runtime")]
 pub mod client;
 pub mod context;
+#[cfg(feature = "runtime")]
+pub mod intercept;
+#[cfg(feature = "runtime")]
+pub mod lag;
+pub mod metadata;
+#[cfg(feature = "runtime")]
+pub mod metrics;
+#[cfg(feature = "runtime")]
+mod runtime;
+#[cfg(feature = "runtime")]
 pub mod server;
+#[cfg(all(test, feature = "runtime"))]
+mod test_util;
+#[cfg(feature = "runtime")]
+pub mod tracing;
 pub mod transport;
+#[cfg(feature = "runtime")]
+pub mod upload;
 pub(crate) mod util;
 
-pub use crate::{client::Client, server::Server, transport::Transport};
+#[cfg(feature = "runtime")]
+pub(crate) use crate::runtime::spawn;
+pub use crate::transport::{PeerCredentials, Transport};
+#[cfg(feature = "runtime")]
+pub use crate::{
+    client::Client,
+    runtime::{init, init_thread, Tasks},
+    server::Server,
+};
 
-use futures::{
-    task::{Poll, Spawn, SpawnError, SpawnExt},
-    Future,
+use futures::task::Poll;
+use std::{
+    collections::BTreeMap,
+    error::Error as StdError,
+    fmt, io,
+    time::{Duration, SystemTime},
 };
-use std::{cell::RefCell, io, sync::Once, time::SystemTime};
 
 /// A message from a client to a server.
 #[derive(Debug)]
@@ -72,12 +164,52 @@ pub enum ClientMessageKind<T> {
     ///
     /// When received, the server will immediately cancel the main task (top-level future) of the
     /// request handler for the associated request. Any tasks spawned by the request handler will
-    /// not be canceled, because the framework layer does not
-    /// know about them.
+    /// not be canceled, because the framework layer does not know about them, but they can wait on
+    /// the request's [`canceled`](crate::server::cancellation::canceled) future to stop on their
+    /// own.
     Cancel {
         /// The ID of the request to cancel.
         request_id: u64,
     },
+    /// A one-way request, sent by [`Channel::notify`](client::Channel::notify). The server
+    /// handles it like any other request, but doesn't send back the response, and the client
+    /// doesn't wait for one. Notifications are delivered
+    /// [at most once](client::delivery::Delivery::AtMostOnce): since the client never learns
+    /// whether one was handled, it never resends one.
+    Notification(Request<T>),
+    /// Sent right before the client closes the connection on purpose, saying why.
+    Close {
+        /// Why the client is closing the connection.
+        reason: CloseReason,
+    },
+    /// A request whose body the client streams after it, in
+    /// [`StreamItem`](ClientMessageKind::StreamItem) messages ended by a
+    /// [`StreamEnd`](ClientMessageKind::StreamEnd) message. The server handles it like any other
+    /// request, and the handler reads the items with [`streaming::body`](server::streaming::body).
+    StreamingRequest(Request<T>),
+    /// One of the items of a request's streamed body.
+    StreamItem {
+        /// The ID of the request whose body the item belongs to.
+        request_id: u64,
+        /// The item.
+        item: T,
+    },
+    /// Ends a request's streamed body.
+    StreamEnd {
+        /// The ID of the request whose body ended.
+        request_id: u64,
+    },
+}
+
+impl<T> ClientMessageKind<T> {
+    /// Returns the delivery semantics of the message's request, if they're fixed by the kind of
+    /// message: [at most once](client::delivery::Delivery::AtMostOnce) for notifications.
+    pub fn delivery(&self) -> Option<client::delivery::Delivery> {
+        match self {
+            ClientMessageKind::Notification(_) => Some(client::delivery::Delivery::AtMostOnce),
+            _ => None,
+        }
+    }
 }
 
 /// A request from a client to a server.
@@ -100,6 +232,115 @@ pub struct Request<T> {
         serde(deserialize_with = "util::serde::deserialize_epoch_secs")
     )]
     pub deadline: SystemTime,
+    /// Out-of-band data sent with the request, e.g. auth tokens or tenant IDs.
+    pub metadata: metadata::Metadata,
+    /// The [causality token](context::Context::causality) of the request, if it has one.
+    pub causality: Option<u64>,
+}
+
+/// A message from a server to a client.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum ServerMessage<T> {
+    /// The response to a request.
+    Response(Response<T>),
+    /// Sent right before the server closes the connection on purpose, saying why. The client
+    /// fails the requests still in flight with a [`ConnectionClosed`] error.
+    Close {
+        /// Why the server is closing the connection.
+        reason: CloseReason,
+    },
+    /// One of the items of a [streamed](server::streaming) response, sent ahead of the final
+    /// response to the request, which ends the stream.
+    StreamItem {
+        /// The ID of the request being responded to.
+        request_id: u64,
+        /// The item.
+        item: T,
+    },
+    /// Sent when the server starts draining the connection, e.g. because it's shutting down. The
+    /// server reads no more requests over the connection, but still answers the ones in flight
+    /// before it [closes](ServerMessage::Close) the connection, so the client should send new
+    /// requests elsewhere.
+    Drain,
+}
+
+/// Why one end of a connection closed it on purpose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum CloseReason {
+    /// The end that closed the connection is shutting down, or was asked to drain it.
+    Shutdown,
+    /// The connection sat idle for longer than the end that closed it allows.
+    IdleTimeout,
+    /// The other end broke the protocol, e.g. by sending a message that couldn't be decoded.
+    ProtocolError,
+    /// The connection was closed on its own, e.g. by an operator kicking a misbehaving client.
+    Kicked,
+    /// The server was at its in-flight request limit, and closes connections that go over it
+    /// rather than rejecting their requests.
+    Overloaded,
+}
+
+impl CloseReason {
+    /// Returns the reason's name, e.g. `idle_timeout`, to label metrics and logs with.
+    pub fn name(self) -> &'static str {
+        match self {
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::ProtocolError => "protocol_error",
+            CloseReason::Kicked => "kicked",
+            CloseReason::Overloaded => "overloaded",
+        }
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The error of a call that failed because the server closed the connection on purpose, saying
+/// why in a [close](ServerMessage::Close) message.
+///
+/// Returned as the inner error of a [`ConnectionReset`](io::ErrorKind::ConnectionReset) error,
+/// the same kind calls fail with when a connection breaks, so the reason can be recovered with
+/// [`ConnectionClosed::of`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ConnectionClosed {
+    /// Why the server closed the connection.
+    pub reason: CloseReason,
+}
+
+impl ConnectionClosed {
+    /// Returns a new error for a connection closed because of `reason`.
+    pub fn new(reason: CloseReason) -> Self {
+        ConnectionClosed { reason }
+    }
+
+    /// Returns the `ConnectionClosed` that failed a call, if `e` is the error of a call that
+    /// failed because the server closed the connection on purpose.
+    pub fn of(e: &io::Error) -> Option<&ConnectionClosed> {
+        e.get_ref()?.downcast_ref::<ConnectionClosed>()
+    }
+}
+
+impl fmt::Display for ConnectionClosed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The server closed the connection: {}.", self.reason)
+    }
+}
+
+impl StdError for ConnectionClosed {}
+
+impl From<ConnectionClosed> for io::Error {
+    fn from(e: ConnectionClosed) -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, e)
+    }
 }
 
 /// A response from a server to a client.
@@ -111,9 +352,42 @@ pub struct Response<T> {
     pub request_id: u64,
     /// The response body, or an error if the request failed.
     pub message: Result<T, ServerError>,
+    /// The causality token of the request, echoed back.
+    pub causality: Option<u64>,
+    /// Whether, and for how long, the client may cache the response, as set by the handler with
+    /// [`server::caching::set`]. Added after the other fields, so peers built before it can't
+    /// decode responses from peers built after.
+    pub caching: Option<Caching>,
+}
+
+/// How a client may cache a response, like HTTP's `Cache-Control` and `ETag` headers, so that the
+/// server decides how long its responses stay fresh instead of each caller hard-coding a TTL.
+/// Understood by the client's [cache](client::cache).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Caching {
+    /// How long the response may be reused for the same request, or None if it mustn't be.
+    pub max_age: Option<Duration>,
+    /// The version of the data the response holds, e.g. a hash or revision number, which the
+    /// client sends back once the response is stale, so that the server can answer with
+    /// [`NotModified`](ErrorCode::NotModified) instead of the whole response if it hasn't changed.
+    pub version: Option<String>,
 }
 
 /// An error response from a server to a client.
+///
+/// Its [`code`](ServerError::code) says what went wrong in a way clients can match on, e.g. to tell
+/// a bad request from a bug in the server, while the [`kind`](ServerError::kind) is what the
+/// client's `io::Error` has.
+///
+/// # Wire compatibility
+///
+/// The `code`, `details`, `payload`, and `retry_after` fields were added after rpc 0.5, and change
+/// how a `ServerError` is encoded: clients and servers built before them can't decode the
+/// errors of ones built after, so both ends of a connection must be upgraded together. Since then,
+/// new [`ErrorCode`]s are only ever appended, because encodings like bincode's identify a variant
+/// by its index; a peer still fails to decode a code newer than it knows.
 #[derive(Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
 #[non_exhaustive]
@@ -128,61 +402,315 @@ pub struct ServerError {
     )]
     /// The type of error that occurred to fail the request.
     pub kind: io::ErrorKind,
+    /// What went wrong.
+    pub code: ErrorCode,
     /// A message describing more detail about the error that occurred.
     pub detail: Option<String>,
+    /// Application-defined details about the error, e.g. which field of the request was invalid.
+    pub details: BTreeMap<String, String>,
+    /// An application-defined payload, e.g. a serialized error type of the service's own.
+    pub payload: Option<Vec<u8>>,
+    /// How long the client should wait before retrying the request, if the server has an opinion.
+    pub retry_after: Option<Duration>,
+}
+
+/// What went wrong to fail a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The request was invalid, e.g. it couldn't be decoded, or its arguments were out of range.
+    BadRequest,
+    /// The server doesn't implement the method the request calls.
+    Unimplemented,
+    /// The server failed to handle the request, e.g. because the handler panicked.
+    Internal,
+    /// The server couldn't handle the request right now, e.g. because it was overloaded. The
+    /// request may succeed if retried later.
+    Unavailable,
+    /// The request didn't complete before its deadline.
+    DeadlineExceeded,
+    /// An error defined by the application, identified by its own code.
+    Application(u32),
+    /// The server was handling as many requests as it could, and rejected the request without
+    /// handling it. Clients should back off before retrying, for at least the error's
+    /// [`retry_after`](ServerError::retry_after), if set.
+    Overloaded,
+    /// The client, or the identity it sent the request as, has used up its quota for now, and the
+    /// server rejected the request without handling it. Clients should wait for the error's
+    /// [`retry_after`](ServerError::retry_after), if set, before retrying.
+    QuotaExceeded,
+    /// The request was larger than the server allows for the method it calls, and the server
+    /// rejected it without decoding it.
+    PayloadTooLarge,
+    /// The data the request reads hasn't changed since the version the client
+    /// [has cached](server::caching::cached_version), so the client should reuse its cached
+    /// response, which the error's response [caching](Response::caching) refreshes.
+    NotModified,
+}
+
+impl ErrorCode {
+    /// Returns the code of `e`: the code the server gave it, if the server failed the request, or
+    /// else the code of errors of its kind.
+    #[cfg(feature = "runtime")]
+    pub(crate) fn of(e: &io::Error) -> Self {
+        ServerError::of(e)
+            .map(|e| e.code)
+            .unwrap_or_else(|| ErrorCode::for_kind(e.kind()))
+    }
+
+    /// Returns the code of errors of `kind` that weren't given one.
+    fn for_kind(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => ErrorCode::BadRequest,
+            io::ErrorKind::WouldBlock => ErrorCode::Unavailable,
+            io::ErrorKind::TimedOut => ErrorCode::DeadlineExceeded,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// Returns the code's name, e.g. `bad_request`, to label metrics and logs with. Application
+    /// codes are all named `application`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCode::BadRequest => "bad_request",
+            ErrorCode::Unimplemented => "unimplemented",
+            ErrorCode::Internal => "internal",
+            ErrorCode::Unavailable => "unavailable",
+            ErrorCode::DeadlineExceeded => "deadline_exceeded",
+            ErrorCode::Application(_) => "application",
+            ErrorCode::Overloaded => "overloaded",
+            ErrorCode::QuotaExceeded => "quota_exceeded",
+            ErrorCode::PayloadTooLarge => "payload_too_large",
+            ErrorCode::NotModified => "not_modified",
+        }
+    }
+}
+
+impl ServerError {
+    /// Returns a new error of type `kind`, described by `detail`.
+    ///
+    /// Its code is [`BadRequest`](ErrorCode::BadRequest) if `kind` is `InvalidInput` or
+    /// `InvalidData`, [`Unavailable`](ErrorCode::Unavailable) if `WouldBlock`,
+    /// [`DeadlineExceeded`](ErrorCode::DeadlineExceeded) if `TimedOut`, and otherwise
+    /// [`Internal`](ErrorCode::Internal); [`with_code`](ServerError::with_code) sets another.
+    pub fn new(kind: io::ErrorKind, detail: impl Into<String>) -> Self {
+        ServerError {
+            kind,
+            code: ErrorCode::for_kind(kind),
+            detail: Some(detail.into()),
+            details: BTreeMap::new(),
+            payload: None,
+            retry_after: None,
+        }
+    }
+
+    /// Returns a new [overloaded](ErrorCode::Overloaded) error, of type
+    /// [`WouldBlock`](io::ErrorKind::WouldBlock), described by `detail`.
+    pub fn overloaded(detail: impl Into<String>) -> Self {
+        ServerError::new(io::ErrorKind::WouldBlock, detail).with_code(ErrorCode::Overloaded)
+    }
+
+    /// Returns a new application-defined error with code `code`, described by `detail`.
+    pub fn application(code: u32, detail: impl Into<String>) -> Self {
+        ServerError::new(io::ErrorKind::Other, detail).with_code(ErrorCode::Application(code))
+    }
+
+    /// Sets what went wrong.
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// Adds a key/value detail to the error.
+    pub fn with_detail(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.details.insert(key.into(), value.into());
+        self
+    }
+
+    /// Attaches an application-defined payload to the error.
+    pub fn with_payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = Some(payload);
+        self
+    }
+
+    /// Asks the client to wait `retry_after` before retrying the request.
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    /// Returns the `ServerError` that failed a request, if `e` is the error of a request that
+    /// the server failed.
+    pub fn of(e: &io::Error) -> Option<&ServerError> {
+        e.get_ref()?.downcast_ref::<ServerError>()
+    }
+
+    /// Returns true if `e` is the error of a request that an overloaded server rejected, so the
+    /// caller can back off, rather than just retry.
+    pub fn is_overloaded(e: &io::Error) -> bool {
+        ServerError::of(e).map(|e| e.code) == Some(ErrorCode::Overloaded)
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl StdError for ServerError {
+    fn description(&self) -> &str {
+        self.detail.as_ref().map(String::as_str).unwrap_or("")
+    }
 }
 
+/// The [`ServerError`] is kept as the error's inner error, so its details can be recovered with
+/// `io::Error::get_ref` and `downcast_ref`. Request handlers that fail with an error converted
+/// this way send the `ServerError` to the client as is.
 impl From<ServerError> for io::Error {
     fn from(e: ServerError) -> io::Error {
-        io::Error::new(e.kind, e.detail.unwrap_or_default())
+        io::Error::new(e.kind, e)
     }
 }
 
-impl<T> Request<T> {
-    /// Returns the deadline for this request.
-    pub fn deadline(&self) -> &SystemTime {
-        &self.deadline
-    }
+/// A request that a transport read off the wire but couldn't decode, e.g. because it calls a
+/// method the server doesn't implement, as happens when a client is newer than the server.
+///
+/// A transport that can tell which request a message was, without decoding all of it, returns
+/// this as the inner error of an [`InvalidData`](io::ErrorKind::InvalidData) error. The server
+/// then responds to the request with an error, or drops it if it was a notification, rather
+/// than closing the connection.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct UndecodableRequest {
+    /// The trace context of the request.
+    pub trace_context: trace::Context,
+    /// The ID of the request.
+    pub request_id: u64,
+    /// Why the request couldn't be decoded.
+    pub detail: String,
+    /// The code of the error the server responds with. Defaults to
+    /// [`BadRequest`](ErrorCode::BadRequest); transports that can tell the request calls a method
+    /// the server doesn't know set [`Unimplemented`](ErrorCode::Unimplemented), and transports
+    /// that reject requests over a size limit set [`PayloadTooLarge`](ErrorCode::PayloadTooLarge).
+    pub code: ErrorCode,
+    /// Whether the request was a [notification](ClientMessageKind::Notification), which the
+    /// server drops instead of responding to.
+    pub notification: bool,
+    /// Whether this was an item of a request's [streamed body](ClientMessageKind::StreamItem)
+    /// rather than a request, in which case the server fails the body instead of responding.
+    pub stream_item: bool,
 }
 
-pub(crate) type PollIo<T> = Poll<Option<io::Result<T>>>;
+impl UndecodableRequest {
+    /// Returns a new error for request `request_id`, which couldn't be decoded because of
+    /// `detail`.
+    pub fn new(trace_context: trace::Context, request_id: u64, detail: impl Into<String>) -> Self {
+        UndecodableRequest {
+            trace_context,
+            request_id,
+            detail: detail.into(),
+            code: ErrorCode::BadRequest,
+            notification: false,
+            stream_item: false,
+        }
+    }
+
+    /// Sets the code of the error the server responds with.
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// Sets whether the request was a notification.
+    pub fn with_notification(mut self, notification: bool) -> Self {
+        self.notification = notification;
+        self
+    }
 
-static INIT: Once = Once::new();
-static mut SEED_SPAWN: Option<Box<dyn CloneSpawn>> = None;
-thread_local! {
-    static SPAWN: RefCell<Box<dyn CloneSpawn>> = {
-        unsafe {
-            // INIT must always be called before accessing SPAWN.
-            // Otherwise, accessing SPAWN can trigger undefined behavior due to race conditions.
-            INIT.call_once(|| {});
-            RefCell::new(SEED_SPAWN.as_ref().expect("init() must be called.").box_clone())
+    /// Sets whether this was an item of a request's streamed body.
+    pub fn with_stream_item(mut self, stream_item: bool) -> Self {
+        self.stream_item = stream_item;
+        self
+    }
+}
+
+impl fmt::Display for UndecodableRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.stream_item {
+            write!(
+                f,
+                "Could not decode an item of request {}: {}",
+                self.request_id, self.detail
+            )
+        } else {
+            write!(
+                f,
+                "Could not decode request {}: {}",
+                self.request_id, self.detail
+            )
         }
-    };
+    }
+}
+
+impl StdError for UndecodableRequest {}
+
+impl From<UndecodableRequest> for io::Error {
+    fn from(e: UndecodableRequest) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
 }
 
-/// Initializes the RPC library with a mechanism to spawn futures on the user's runtime.
-/// Client stubs and servers both use the initialized spawn.
+/// A response that a transport read off the wire but couldn't decode, e.g. because the server is
+/// newer than the client and answered with a kind of response the client doesn't know.
 ///
-/// Init only has an effect the first time it is called. If called previously, successive calls to
-/// init are noops.
-pub fn init(spawn: impl Spawn + Clone + 'static) {
-    unsafe {
-        INIT.call_once(|| {
-            SEED_SPAWN = Some(Box::new(spawn));
-        });
+/// Like [`UndecodableRequest`], a transport returns this as the inner error of an
+/// [`InvalidData`](io::ErrorKind::InvalidData) error. The client then fails the request it
+/// responds to, rather than closing the connection.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct UndecodableResponse {
+    /// The ID of the request being responded to.
+    pub request_id: u64,
+    /// Why the response couldn't be decoded.
+    pub detail: String,
+}
+
+impl UndecodableResponse {
+    /// Returns a new error for the response to request `request_id`, which couldn't be decoded
+    /// because of `detail`.
+    pub fn new(request_id: u64, detail: impl Into<String>) -> Self {
+        UndecodableResponse {
+            request_id,
+            detail: detail.into(),
+        }
     }
 }
 
-pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) -> Result<(), SpawnError> {
-    SPAWN.with(|spawn| spawn.borrow_mut().spawn(future))
+impl fmt::Display for UndecodableResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Could not decode the response to request {}: {}",
+            self.request_id, self.detail
+        )
+    }
 }
 
-trait CloneSpawn: Spawn {
-    fn box_clone(&self) -> Box<dyn CloneSpawn>;
+impl StdError for UndecodableResponse {}
+
+impl From<UndecodableResponse> for io::Error {
+    fn from(e: UndecodableResponse) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
 }
 
-impl<S: Spawn + Clone + 'static> CloneSpawn for S {
-    fn box_clone(&self) -> Box<dyn CloneSpawn> {
-        Box::new(self.clone())
+impl<T> Request<T> {
+    /// Returns the deadline for this request.
+    pub fn deadline(&self) -> &SystemTime {
+        &self.deadline
     }
 }
+
+pub(crate) type PollIo<T> = Poll<Option<io::Result<T>>>;
@@ -0,0 +1,96 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Out-of-band key/value data sent along with a request, such as auth tokens or tenant IDs, so
+//! that it needn't be added to every request type.
+//!
+//! The client sends a call's metadata with the request, and the server makes it
+//! [current](current) while polling the request's handler.
+
+use futures::{
+    task::{Context, Poll},
+    Future,
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{cell::RefCell, collections::BTreeMap, pin::Pin, sync::Arc};
+
+/// The metadata sent with a request.
+pub type Metadata = BTreeMap<String, String>;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Metadata>>> = RefCell::new(None);
+}
+
+/// Returns the metadata of the current request, or empty metadata if no request is active.
+pub fn current() -> Arc<Metadata> {
+    CURRENT.with(|current| current.borrow().clone().unwrap_or_default())
+}
+
+/// Returns the value of `key` in the metadata of the current request, if any.
+pub fn get(key: &str) -> Option<String> {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .and_then(|metadata| metadata.get(key).cloned())
+    })
+}
+
+/// Returns a future that makes `metadata` the [current](current) metadata while polling `future`.
+/// Useful for carrying a request's metadata over to tasks spawned by its handler.
+pub fn scope<F: Future>(metadata: Arc<Metadata>, future: F) -> Scoped<F> {
+    Scoped { metadata, future }
+}
+
+/// A future that makes metadata current while it is polled. Returned by [`scope`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Scoped<F> {
+    metadata: Arc<Metadata>,
+    future: F,
+}
+
+impl<F> Scoped<F> {
+    unsafe_unpinned!(metadata: Arc<Metadata>);
+    unsafe_pinned!(future: F);
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        /// Restores the previous metadata, even if the future panics.
+        struct Reset(Option<Arc<Metadata>>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+            }
+        }
+
+        let metadata = self.as_mut().metadata().clone();
+        let _reset = Reset(CURRENT.with(|current| current.replace(Some(metadata))));
+        self.as_mut().future().poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current, get, scope, Metadata};
+    use futures::{executor::block_on, future};
+    use std::sync::Arc;
+
+    #[test]
+    fn scope_makes_metadata_current() {
+        let mut metadata = Metadata::new();
+        metadata.insert("tenant".into(), "acme".into());
+        let tenant = block_on(scope(Arc::new(metadata), future::lazy(|_| get("tenant"))));
+        assert_eq!(tenant, Some("acme".into()));
+
+        // Outside the scope, there is no current request.
+        assert!(current().is_empty());
+    }
+}
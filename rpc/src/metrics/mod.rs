@@ -0,0 +1,453 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Reports how requests went to a metrics backend of choice.
+//!
+//! Instrumentation reports to a [`MetricsSink`], which forwards counters, gauges, and histograms
+//! to a metrics backend. Sinks for [Prometheus](prometheus) and [StatsD](statsd) are available
+//! behind the `prometheus` and `statsd` features; other backends can implement [`MetricsSink`].
+//!
+//! A client [channel](crate::client::Channel::with_metrics) and a
+//! [server](crate::server::Config::metrics) given a `Metrics` report every request, as does a
+//! request handler wrapped with [`instrument`]. Request metrics are labeled by `side`, `client` or
+//! `server`, and by `method`:
+//!
+//! * `tarpc_requests_started_total`, a counter of requests started.
+//! * `tarpc_requests_total`, a counter of requests responded to.
+//! * `tarpc_request_errors_total`, a counter of requests that failed, also labeled by the
+//!   [`code`](crate::ErrorCode::name) of the error.
+//! * `tarpc_requests_canceled_total`, a counter of requests dropped before they were responded
+//!   to, e.g. because the client canceled them.
+//! * `tarpc_request_duration_seconds`, a histogram of how long requests took to respond to.
+//! * `tarpc_requests_in_flight`, a gauge of the requests started but not yet responded to.
+//!
+//! Transports can also report the bytes they read and write, as `tarpc_bytes_read_total` and
+//! `tarpc_bytes_written_total`, with [`Metrics::read`] and [`Metrics::written`], and the size of
+//! each request and response, labeled by `side` and `method` like request metrics, as the
+//! histograms `tarpc_request_payload_bytes` and `tarpc_response_payload_bytes`, with
+//! [`Metrics::request_payload`] and [`Metrics::response_payload`].
+
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+
+use crate::{context, ErrorCode};
+use fnv::FnvHashMap;
+use futures::{
+    ready,
+    task::{Context, Poll},
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Labels that break a metric down, as `(key, value)` pairs.
+pub type Labels<'a> = &'a [(&'static str, &'static str)];
+
+/// Forwards metrics to a metrics backend.
+///
+/// Metrics are identified by their name and labels. A metric's labels always have the same keys,
+/// in the same order.
+pub trait MetricsSink: Send + Sync + 'static {
+    /// Adds `delta` to the counter `name`.
+    fn counter(&self, name: &'static str, labels: Labels, delta: u64);
+
+    /// Sets the gauge `name` to `value`.
+    fn gauge(&self, name: &'static str, labels: Labels, value: f64);
+
+    /// Records `value` in the histogram `name`.
+    fn histogram(&self, name: &'static str, labels: Labels, value: f64);
+}
+
+/// Instruments clients, servers, and request handlers, reporting to a [`MetricsSink`]. Clones
+/// share the same sink, so a single `Metrics` can be used across all connections of a server.
+#[derive(Clone)]
+pub struct Metrics {
+    sink: Arc<dyn MetricsSink>,
+    in_flight: Arc<Mutex<FnvHashMap<(&'static str, &'static str), u64>>>,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Metrics")
+            .field("in_flight", &self.in_flight)
+            .finish()
+    }
+}
+
+impl Metrics {
+    /// Returns instrumentation that reports to `sink`.
+    pub fn new(sink: impl MetricsSink) -> Self {
+        Metrics {
+            sink: Arc::new(sink),
+            in_flight: Arc::new(Mutex::new(FnvHashMap::default())),
+        }
+    }
+
+    /// Returns the sink metrics are reported to, e.g. to report application metrics alongside.
+    pub fn sink(&self) -> &dyn MetricsSink {
+        &*self.sink
+    }
+
+    /// Reports `bytes` read from the wire.
+    pub fn read(&self, bytes: u64) {
+        self.sink.counter(BYTES_READ, &[], bytes);
+    }
+
+    /// Reports `bytes` written to the wire.
+    pub fn written(&self, bytes: u64) {
+        self.sink.counter(BYTES_WRITTEN, &[], bytes);
+    }
+
+    /// Reports a request payload of `bytes` to `method`, read or written on `side`, `client` or
+    /// `server`.
+    pub fn request_payload(&self, side: &'static str, method: &'static str, bytes: u64) {
+        self.sink.histogram(
+            REQUEST_PAYLOAD,
+            &[("side", side), ("method", method)],
+            bytes as f64,
+        );
+    }
+
+    /// Reports a response payload of `bytes` from `method`, read or written on `side`, `client`
+    /// or `server`.
+    pub fn response_payload(&self, side: &'static str, method: &'static str, bytes: u64) {
+        self.sink.histogram(
+            RESPONSE_PAYLOAD,
+            &[("side", side), ("method", method)],
+            bytes as f64,
+        );
+    }
+
+    /// Counts a request to `method` as in flight, or as no longer in flight, and reports the new
+    /// number in flight.
+    fn set_in_flight(&self, side: &'static str, method: &'static str, in_flight: bool) {
+        let mut counts = self.in_flight.lock().unwrap();
+        let count = counts.entry((side, method)).or_insert(0);
+        if in_flight {
+            *count += 1;
+        } else {
+            *count -= 1;
+        }
+        self.sink.gauge(
+            IN_FLIGHT,
+            &[("side", side), ("method", method)],
+            *count as f64,
+        );
+    }
+}
+
+const STARTED: &str = "tarpc_requests_started_total";
+const REQUESTS: &str = "tarpc_requests_total";
+const ERRORS: &str = "tarpc_request_errors_total";
+const CANCELED: &str = "tarpc_requests_canceled_total";
+const DURATION: &str = "tarpc_request_duration_seconds";
+const IN_FLIGHT: &str = "tarpc_requests_in_flight";
+const BYTES_READ: &str = "tarpc_bytes_read_total";
+const BYTES_WRITTEN: &str = "tarpc_bytes_written_total";
+const REQUEST_PAYLOAD: &str = "tarpc_request_payload_bytes";
+const RESPONSE_PAYLOAD: &str = "tarpc_response_payload_bytes";
+
+/// Reports a single request, from when it started until it completed or was dropped.
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    metrics: Metrics,
+    side: &'static str,
+    method: &'static str,
+    start: Instant,
+    completed: bool,
+}
+
+impl Recorder {
+    /// Reports a request to `method` as started on `side`, `client` or `server`.
+    pub(crate) fn start(metrics: Metrics, side: &'static str, method: &'static str) -> Self {
+        metrics
+            .sink
+            .counter(STARTED, &[("side", side), ("method", method)], 1);
+        metrics.set_in_flight(side, method, true);
+        Recorder {
+            metrics,
+            side,
+            method,
+            start: Instant::now(),
+            completed: false,
+        }
+    }
+
+    /// Reports the request as completed with `result`.
+    pub(crate) fn complete<T>(&mut self, result: &io::Result<T>) {
+        self.complete_with(result.as_ref().err().map(ErrorCode::of));
+    }
+
+    /// Reports the request as completed, as failed with `error`, if it failed.
+    pub(crate) fn complete_with(&mut self, error: Option<ErrorCode>) {
+        if self.completed {
+            return;
+        }
+        self.completed = true;
+        let elapsed = self.start.elapsed();
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        let labels = [("side", self.side), ("method", self.method)];
+        let sink = &self.metrics.sink;
+        sink.counter(REQUESTS, &labels, 1);
+        sink.histogram(DURATION, &labels, elapsed);
+        if let Some(error) = error {
+            let code = error.name();
+            sink.counter(
+                ERRORS,
+                &[("side", self.side), ("method", self.method), ("code", code)],
+                1,
+            );
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.metrics
+                .sink
+                .counter(CANCELED, &[("side", self.side), ("method", self.method)], 1);
+        }
+        self.metrics.set_in_flight(self.side, self.method, false);
+    }
+}
+
+/// Wraps request handler `f` so that every request is reported to `metrics`, as on the `server`
+/// side. Servers [configured](crate::server::Config::metrics) with metrics already report every
+/// request; wrapping handlers is for reporting to another sink, or only for some services.
+///
+/// `method` returns the name of the method a request calls. Services defined with
+/// `tarpc::service!` can use `Request::name`.
+pub fn instrument<Req, Resp, M, F, Fut>(
+    metrics: Metrics,
+    method: M,
+    f: F,
+) -> impl FnOnce(context::Context, Req) -> Instrumented<Fut> + Send + 'static + Clone
+where
+    M: Fn(&Req) -> &'static str + Send + 'static + Clone,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    move |ctx, req| {
+        let recorder = Recorder::start(metrics, "server", method(&req));
+        Instrumented::new(recorder, f(ctx, req))
+    }
+}
+
+/// A future that reports the response of a request to its [`Metrics`], e.g. one returned by a
+/// request handler wrapped with [`instrument`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Instrumented<Fut> {
+    future: Fut,
+    recorder: Recorder,
+}
+
+impl<Fut> Instrumented<Fut> {
+    unsafe_pinned!(future: Fut);
+    unsafe_unpinned!(recorder: Recorder);
+
+    pub(crate) fn new(recorder: Recorder, future: Fut) -> Self {
+        Instrumented { future, recorder }
+    }
+}
+
+impl<Fut, Resp> Future for Instrumented<Fut>
+where
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    type Output = io::Result<Resp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
+        let result = ready!(self.as_mut().future().poll(cx));
+        self.as_mut().recorder().complete(&result);
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instrument, Labels, Metrics, MetricsSink};
+    use crate::{
+        client, context,
+        server::{self, Handler},
+        test_util, transport,
+    };
+    use futures::{executor::block_on, future, prelude::*, stream};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    /// Records every metric reported, without the histogram values, which are timings.
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Recorder {
+        fn record(&self, name: &str, labels: Labels, value: Option<f64>) {
+            let labels: Vec<_> = labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            let value = value.map(|v| format!(" {}", v)).unwrap_or_default();
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{}{{{}}}{}", name, labels.join(","), value));
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::replace(&mut *self.0.lock().unwrap(), vec![])
+        }
+    }
+
+    impl MetricsSink for Recorder {
+        fn counter(&self, name: &'static str, labels: Labels, delta: u64) {
+            self.record(name, labels, Some(delta as f64));
+        }
+
+        fn gauge(&self, name: &'static str, labels: Labels, value: f64) {
+            self.record(name, labels, Some(value));
+        }
+
+        fn histogram(&self, name: &'static str, labels: Labels, _value: f64) {
+            self.record(name, labels, None);
+        }
+    }
+
+    #[test]
+    fn reports_requests() {
+        let recorder = Recorder::default();
+        let handler = instrument(
+            Metrics::new(recorder.clone()),
+            |_: &&str| "search",
+            |_, request: &'static str| {
+                future::ready(match request {
+                    "invalid" => Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid.")),
+                    _ => Ok(request),
+                })
+            },
+        );
+
+        assert_eq!(
+            block_on(handler.clone()(crate::context::current(), "hi")).unwrap(),
+            "hi"
+        );
+        assert_eq!(
+            recorder.take(),
+            vec![
+                "tarpc_requests_started_total{side=server,method=search} 1",
+                "tarpc_requests_in_flight{side=server,method=search} 1",
+                "tarpc_requests_total{side=server,method=search} 1",
+                "tarpc_request_duration_seconds{side=server,method=search}",
+                "tarpc_requests_in_flight{side=server,method=search} 0",
+            ]
+        );
+
+        block_on(handler.clone()(crate::context::current(), "invalid")).unwrap_err();
+        assert_eq!(
+            recorder.take()[4],
+            "tarpc_request_errors_total{side=server,method=search,code=bad_request} 1"
+        );
+
+        drop(handler(crate::context::current(), "hi"));
+        assert_eq!(
+            recorder.take(),
+            vec![
+                "tarpc_requests_started_total{side=server,method=search} 1",
+                "tarpc_requests_in_flight{side=server,method=search} 1",
+                "tarpc_requests_canceled_total{side=server,method=search} 1",
+                "tarpc_requests_in_flight{side=server,method=search} 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_bytes_on_the_wire() {
+        let recorder = Recorder::default();
+        let metrics = Metrics::new(recorder.clone());
+        metrics.read(12);
+        metrics.written(34);
+        assert_eq!(
+            recorder.take(),
+            vec![
+                "tarpc_bytes_read_total{} 12",
+                "tarpc_bytes_written_total{} 34"
+            ]
+        );
+    }
+
+    #[test]
+    fn clients_and_servers_report_metrics() {
+        #[derive(Clone, Default)]
+        struct Counters(Arc<Mutex<Vec<String>>>);
+
+        impl MetricsSink for Counters {
+            fn counter(&self, name: &'static str, labels: Labels, _: u64) {
+                let labels: Vec<_> = labels.iter().map(|(_, v)| *v).collect();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", name, labels.join(",")));
+            }
+
+            fn gauge(&self, _: &'static str, _: Labels, _: f64) {}
+
+            fn histogram(&self, _: &'static str, _: Labels, _: f64) {}
+        }
+
+        test_util::init();
+
+        let counters = Counters::default();
+        let metrics = Metrics::new(counters.clone());
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let mut config = server::Config::default();
+        config.metrics = Some(metrics.clone());
+        let server = server::new::<String, String>(config)
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .map_ok(|channel| channel.with_call_names(|_| "echo"))
+            .respond_with(|_ctx, request: String| {
+                future::ready(match &*request {
+                    "bad" => Err(io::Error::new(io::ErrorKind::InvalidInput, "Bad.")),
+                    _ => Ok(request),
+                })
+            });
+
+        let response = async move {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?
+                .with_call_names(|_| "echo")
+                .with_metrics(metrics);
+            await!(client.call(context::current(), "hi".into()))?;
+            await!(client.call(context::current(), "bad".into())).unwrap_err();
+            io::Result::Ok(())
+        };
+
+        test_util::run_future(future::join(server, response))
+            .1
+            .unwrap();
+        let counters = counters.0.lock().unwrap();
+        for side in &["client", "server"] {
+            let count = |metric: &str| counters.iter().filter(|c| *c == metric).count();
+            assert_eq!(
+                count(&format!("tarpc_requests_started_total {},echo", side)),
+                2
+            );
+            assert_eq!(count(&format!("tarpc_requests_total {},echo", side)), 2);
+            assert_eq!(
+                count(&format!(
+                    "tarpc_request_errors_total {},echo,bad_request",
+                    side
+                )),
+                1
+            );
+        }
+    }
+}
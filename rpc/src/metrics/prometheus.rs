@@ -0,0 +1,153 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A [`MetricsSink`] that registers metrics in a Prometheus [`Registry`], to be served to a
+//! Prometheus server by the application, e.g. on an HTTP endpoint.
+
+use super::{Labels, MetricsSink};
+use ::prometheus::{
+    core::Collector, exponential_buckets, CounterVec, GaugeVec, HistogramOpts, HistogramVec, Opts,
+    Registry,
+};
+use fnv::FnvHashMap;
+use log::warn;
+use std::{fmt, sync::Mutex};
+
+/// Registers each metric in a registry the first time it's reported, with the label keys it's
+/// first reported with. Histograms of sizes, whose names end in `_bytes`, have exponential
+/// buckets from 64 bytes to 16 MiB; other histograms have Prometheus' default buckets, which suit
+/// latencies in seconds.
+pub struct Prometheus {
+    registry: Registry,
+    vecs: Mutex<Vecs>,
+}
+
+#[derive(Default)]
+struct Vecs {
+    counters: FnvHashMap<&'static str, CounterVec>,
+    gauges: FnvHashMap<&'static str, GaugeVec>,
+    histograms: FnvHashMap<&'static str, HistogramVec>,
+}
+
+impl fmt::Debug for Prometheus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let vecs = self.vecs.lock().unwrap();
+        f.debug_struct("Prometheus")
+            .field("counters", &vecs.counters.keys())
+            .field("gauges", &vecs.gauges.keys())
+            .field("histograms", &vecs.histograms.keys())
+            .finish()
+    }
+}
+
+impl Prometheus {
+    /// Returns a sink that registers metrics in `registry`.
+    pub fn new(registry: Registry) -> Self {
+        Prometheus {
+            registry,
+            vecs: Mutex::default(),
+        }
+    }
+
+    /// Returns the registry metrics are registered in.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    fn get_or_register<V>(
+        &self,
+        vecs: &mut FnvHashMap<&'static str, V>,
+        name: &'static str,
+        new: impl FnOnce() -> ::prometheus::Result<V>,
+    ) -> Option<V>
+    where
+        V: Collector + Clone + 'static,
+    {
+        if let Some(vec) = vecs.get(name) {
+            return Some(vec.clone());
+        }
+        let vec = match new() {
+            Ok(vec) => vec,
+            Err(e) => {
+                warn!("Could not create metric {}: {}", name, e);
+                return None;
+            }
+        };
+        // Kept even if it can't be registered, so that the failure is only logged once.
+        if let Err(e) = self.registry.register(Box::new(vec.clone())) {
+            warn!("Could not register metric {}: {}", name, e);
+        }
+        vecs.insert(name, vec.clone());
+        Some(vec)
+    }
+}
+
+fn split(labels: Labels) -> (Vec<&'static str>, Vec<&'static str>) {
+    labels.iter().cloned().unzip()
+}
+
+impl MetricsSink for Prometheus {
+    fn counter(&self, name: &'static str, labels: Labels, delta: u64) {
+        let (keys, values) = split(labels);
+        let mut vecs = self.vecs.lock().unwrap();
+        let vec = self.get_or_register(&mut vecs.counters, name, || {
+            CounterVec::new(Opts::new(name, name), &keys)
+        });
+        if let Some(Ok(counter)) = vec.map(|vec| vec.get_metric_with_label_values(&values)) {
+            counter.inc_by(delta as f64);
+        }
+    }
+
+    fn gauge(&self, name: &'static str, labels: Labels, value: f64) {
+        let (keys, values) = split(labels);
+        let mut vecs = self.vecs.lock().unwrap();
+        let vec = self.get_or_register(&mut vecs.gauges, name, || {
+            GaugeVec::new(Opts::new(name, name), &keys)
+        });
+        if let Some(Ok(gauge)) = vec.map(|vec| vec.get_metric_with_label_values(&values)) {
+            gauge.set(value);
+        }
+    }
+
+    fn histogram(&self, name: &'static str, labels: Labels, value: f64) {
+        let (keys, values) = split(labels);
+        let mut vecs = self.vecs.lock().unwrap();
+        let vec = self.get_or_register(&mut vecs.histograms, name, || {
+            let mut opts = HistogramOpts::new(name, name);
+            if name.ends_with("_bytes") {
+                opts = opts.buckets(exponential_buckets(64.0, 4.0, 10)?);
+            }
+            HistogramVec::new(opts, &keys)
+        });
+        if let Some(Ok(histogram)) = vec.map(|vec| vec.get_metric_with_label_values(&values)) {
+            histogram.observe(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Prometheus;
+    use crate::metrics::MetricsSink;
+    use ::prometheus::Registry;
+
+    #[test]
+    fn registers_metrics_on_first_use() {
+        let sink = Prometheus::new(Registry::new());
+        sink.counter("requests", &[("method", "search")], 2);
+        sink.counter("requests", &[("method", "search")], 1);
+        sink.gauge("in_flight", &[("method", "search")], 4.0);
+        // Ignored, rather than panicking, since the labels don't match the metric's.
+        sink.counter("requests", &[("method", "search"), ("code", "internal")], 1);
+
+        let families = sink.registry().gather();
+        let names: Vec<_> = families.iter().map(|family| family.get_name()).collect();
+        assert_eq!(names, vec!["in_flight", "requests"]);
+        let requests = &families[1].get_metric()[0];
+        assert_eq!(requests.get_label()[0].get_value(), "search");
+        assert_eq!(requests.get_counter().get_value(), 3.0);
+    }
+}
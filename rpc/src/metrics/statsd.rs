@@ -0,0 +1,109 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A [`MetricsSink`] that sends metrics to a StatsD server over UDP.
+//!
+//! Labels are sent as tags, and histograms as the `h` type, as in the DogStatsD dialect that most
+//! StatsD servers accept, e.g. `tarpc_requests_total:1|c|#method:search`.
+
+use super::{Labels, MetricsSink};
+use log::debug;
+use std::{
+    fmt, io,
+    net::{SocketAddr, UdpSocket},
+};
+
+/// Sends each metric to a StatsD server as it's reported, in its own datagram. Metrics that can't
+/// be sent right away, e.g. because the socket's buffer is full, are dropped, so that reporting
+/// never blocks.
+#[derive(Debug)]
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+}
+
+impl Statsd {
+    /// Returns a sink that sends metrics to the StatsD server at `addr`.
+    pub fn new(addr: SocketAddr) -> io::Result<Self> {
+        let local: SocketAddr = if addr.is_ipv4() {
+            ([0; 4], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Statsd {
+            socket,
+            prefix: String::new(),
+        })
+    }
+
+    /// Prefixes the names of metrics with `prefix` and a period, e.g. with the name of the
+    /// application.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into() + ".";
+        self
+    }
+
+    fn send(&self, name: &str, labels: Labels, value: impl fmt::Display, kind: &str) {
+        let line = self.line(name, labels, value, kind);
+        if let Err(e) = self.socket.send(line.as_bytes()) {
+            debug!("Could not send metric {}: {}", name, e);
+        }
+    }
+
+    fn line(&self, name: &str, labels: Labels, value: impl fmt::Display, kind: &str) -> String {
+        let mut line = format!("{}{}:{}|{}", self.prefix, name, value, kind);
+        for (i, (key, label)) in labels.iter().enumerate() {
+            line.push_str(if i == 0 { "|#" } else { "," });
+            line.push_str(key);
+            line.push(':');
+            line.push_str(label);
+        }
+        line
+    }
+}
+
+impl MetricsSink for Statsd {
+    fn counter(&self, name: &'static str, labels: Labels, delta: u64) {
+        self.send(name, labels, delta, "c");
+    }
+
+    fn gauge(&self, name: &'static str, labels: Labels, value: f64) {
+        self.send(name, labels, value, "g");
+    }
+
+    fn histogram(&self, name: &'static str, labels: Labels, value: f64) {
+        self.send(name, labels, value, "h");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Statsd;
+    use crate::metrics::MetricsSink;
+    use std::net::UdpSocket;
+
+    #[test]
+    fn sends_metrics_as_tagged_lines() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sink = Statsd::new(server.local_addr().unwrap())
+            .unwrap()
+            .with_prefix("app");
+        sink.counter("requests", &[("method", "search"), ("code", "internal")], 1);
+        sink.gauge("in_flight", &[], 2.5);
+
+        let mut buf = [0; 128];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &b"app.requests:1|c|#method:search,code:internal"[..]
+        );
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &b"app.in_flight:2.5|g"[..]);
+    }
+}
@@ -0,0 +1,360 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Spawns the tasks that drive clients and servers on the user's executor.
+
+use fnv::FnvHashMap;
+use futures::{
+    channel::oneshot,
+    future::{AbortHandle, Abortable},
+    prelude::*,
+    task::{Context, Poll, Spawn, SpawnError, SpawnExt},
+};
+use pin_utils::unsafe_pinned;
+use std::{
+    cell::RefCell,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, Once},
+};
+
+static INIT: Once = Once::new();
+static mut SEED_SPAWN: Option<Box<dyn CloneSpawn>> = None;
+thread_local! {
+    /// The spawn used by the current thread. Cloned from `SEED_SPAWN` the first time the thread
+    /// spawns a task, unless set by [`init_thread`].
+    static SPAWN: RefCell<Option<Box<dyn CloneSpawn>>> = RefCell::new(None);
+    /// The owner of the task being polled, which owns the tasks it spawns too.
+    static OWNER: RefCell<Option<Arc<Owner>>> = RefCell::new(None);
+}
+
+/// Initializes the RPC library with a mechanism to spawn futures on the user's runtime.
+/// Client stubs and servers both use the initialized spawn.
+///
+/// Init only has an effect the first time it is called. If called previously, successive calls to
+/// init are noops.
+pub fn init(spawn: impl Spawn + Clone + 'static) {
+    unsafe {
+        INIT.call_once(|| {
+            SEED_SPAWN = Some(Box::new(spawn));
+        });
+    }
+}
+
+/// Sets the spawn used by client stubs and servers on the current thread, in place of the one
+/// passed to [`init`]. Calling it again replaces the spawn.
+///
+/// Tasks spawned by tarpc use the spawn of whichever thread they run on. This is useful for
+/// embedders that run tarpc on a single-threaded executor, such as a deterministic one in tests,
+/// in which case `init` need not be called at all.
+pub fn init_thread(spawn: impl Spawn + Clone + 'static) {
+    SPAWN.with(|current| *current.borrow_mut() = Some(Box::new(spawn)));
+}
+
+pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) -> Result<(), SpawnError> {
+    match OWNER.with(|owner| owner.borrow().clone()) {
+        Some(owner) => spawn_unowned(Owner::own(&owner, future)?),
+        None => spawn_unowned(future),
+    }
+}
+
+fn spawn_unowned(future: impl Future<Output = ()> + Send + 'static) -> Result<(), SpawnError> {
+    SPAWN.with(|spawn| {
+        spawn
+            .borrow_mut()
+            .get_or_insert_with(|| unsafe {
+                // INIT must always be called before accessing SEED_SPAWN.
+                // Otherwise, accessing SEED_SPAWN can trigger undefined behavior due to race
+                // conditions.
+                INIT.call_once(|| {});
+                SEED_SPAWN
+                    .as_ref()
+                    .expect("init() must be called.")
+                    .box_clone()
+            })
+            .spawn(future)
+    })
+}
+
+trait CloneSpawn: Spawn {
+    fn box_clone(&self) -> Box<dyn CloneSpawn>;
+}
+
+impl<S: Spawn + Clone + 'static> CloneSpawn for S {
+    fn box_clone(&self) -> Box<dyn CloneSpawn> {
+        Box::new(self.clone())
+    }
+}
+
+/// Owns the background tasks tarpc spawns, e.g. client dispatch, server connections, and
+/// reconnect loops, so that they can be counted, joined, and stopped.
+///
+/// Tasks spawned by tarpc while polling a future in [`scope`](Tasks::scope), or a future spawned
+/// with [`spawn`](Tasks::spawn), are owned, as are the tasks those tasks spawn in turn. Dropping
+/// the owner aborts every owned task, e.g. so that a test or a program exiting doesn't wait on
+/// connections that are still open.
+#[derive(Debug)]
+pub struct Tasks {
+    owner: Arc<Owner>,
+}
+
+#[derive(Debug, Default)]
+struct Owner {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    stopped: bool,
+    next_id: u64,
+    running: FnvHashMap<u64, AbortHandle>,
+    /// Notified once no owned tasks are running.
+    idle: Vec<oneshot::Sender<()>>,
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Tasks::new()
+    }
+}
+
+impl Tasks {
+    /// Returns an owner without any tasks.
+    pub fn new() -> Self {
+        Tasks {
+            owner: Arc::new(Owner::default()),
+        }
+    }
+
+    /// Returns a future that polls `future` such that the tasks tarpc spawns meanwhile, e.g. when
+    /// creating a client, are owned. `future` itself is not.
+    pub fn scope<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        Scoped {
+            owner: self.owner.clone(),
+            future,
+        }
+    }
+
+    /// Spawns `future` as an owned task, e.g. a server, on the spawn that tarpc uses.
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) -> io::Result<()> {
+        Owner::own(&self.owner, future)
+            .and_then(spawn_unowned)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Could not spawn task. Is shutdown: {}", e.is_shutdown()),
+                )
+            })
+    }
+
+    /// Returns the number of owned tasks that haven't finished.
+    pub fn running(&self) -> usize {
+        self.owner.state.lock().unwrap().running.len()
+    }
+
+    /// Returns a future that resolves once no owned tasks are running.
+    pub fn join(&self) -> impl Future<Output = ()> + Send + 'static {
+        let (idle_tx, idle) = oneshot::channel();
+        let mut state = self.owner.state.lock().unwrap();
+        if state.running.is_empty() {
+            let _ = idle_tx.send(());
+        } else {
+            state.idle.push(idle_tx);
+        }
+        idle.map(|_| ())
+    }
+
+    /// Aborts every owned task. Returns a future that resolves once they have all stopped.
+    pub fn shutdown(self) -> impl Future<Output = ()> + Send + 'static {
+        let stopped = self.join();
+        drop(self);
+        stopped
+    }
+}
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        let running: Vec<_> = {
+            let mut state = self.owner.state.lock().unwrap();
+            state.stopped = true;
+            state.running.values().cloned().collect()
+        };
+        for task in running {
+            task.abort();
+        }
+    }
+}
+
+impl Owner {
+    /// Wraps `future` in a task owned by `owner`, unless `owner` was dropped.
+    fn own<F: Future<Output = ()>>(owner: &Arc<Owner>, future: F) -> Result<Owned<F>, SpawnError> {
+        let mut state = owner.state.lock().unwrap();
+        if state.stopped {
+            return Err(SpawnError::shutdown());
+        }
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.running.insert(id, abort_handle);
+        Ok(Owned {
+            scoped: Scoped {
+                owner: owner.clone(),
+                future: Abortable::new(future, abort_registration),
+            },
+            id,
+        })
+    }
+
+    fn finished(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.running.remove(&id);
+        if state.running.is_empty() {
+            for idle in state.idle.drain(..) {
+                let _ = idle.send(());
+            }
+        }
+    }
+}
+
+/// A future that makes an owner current while it is polled.
+#[must_use = "futures do nothing unless polled"]
+struct Scoped<F> {
+    owner: Arc<Owner>,
+    future: F,
+}
+
+impl<F> Scoped<F> {
+    unsafe_pinned!(future: F);
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        /// Restores the previous owner, even if the future panics.
+        struct Reset(Option<Arc<Owner>>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                OWNER.with(|owner| *owner.borrow_mut() = self.0.take());
+            }
+        }
+
+        let owner = self.owner.clone();
+        let _reset = Reset(OWNER.with(|current| current.replace(Some(owner))));
+        self.as_mut().future().poll(cx)
+    }
+}
+
+/// An owned task, which stops being counted as running when it finishes or is dropped.
+#[must_use = "futures do nothing unless polled"]
+struct Owned<F> {
+    scoped: Scoped<Abortable<F>>,
+    id: u64,
+}
+
+impl<F> Owned<F> {
+    unsafe_pinned!(scoped: Scoped<Abortable<F>>);
+}
+
+impl<F: Future<Output = ()>> Future for Owned<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Resolves with an error if aborted, which ends the task all the same.
+        self.scoped().poll(cx).map(|_| ())
+    }
+}
+
+impl<F> Drop for Owned<F> {
+    fn drop(&mut self) {
+        self.scoped.owner.finished(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tasks;
+    use crate::{client, context, test_util, Server};
+    use futures::{
+        compat::Executor01CompatExt,
+        future::{self, FutureObj},
+        prelude::*,
+        task::{Spawn, SpawnError},
+    };
+    use std::{cell::Cell, io, rc::Rc};
+
+    #[test]
+    fn init_thread_overrides_spawn() {
+        #[derive(Clone)]
+        struct CountingSpawn<S> {
+            inner: S,
+            spawned: Rc<Cell<usize>>,
+        }
+
+        impl<S: Spawn> Spawn for CountingSpawn<S> {
+            fn spawn_obj(&mut self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+                self.spawned.set(self.spawned.get() + 1);
+                self.inner.spawn_obj(future)
+            }
+        }
+
+        let _ = env_logger::try_init();
+        let spawned = Rc::new(Cell::new(0));
+        crate::init_thread(CountingSpawn {
+            inner: tokio::runtime::current_thread::TaskExecutor::current().compat(),
+            spawned: spawned.clone(),
+        });
+
+        let (client_channel, server) =
+            test_util::serve(Server::<String, String>::default(), |_ctx, request| {
+                future::ready(Ok(request))
+            });
+
+        let response = async {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            await!(client.call(context::current(), "hi".into()))
+        };
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let response = runtime
+            .block_on(future::join(server, response).boxed().unit_error().compat())
+            .unwrap()
+            .1;
+        assert_eq!(response.unwrap(), "hi");
+        assert!(spawned.get() > 0);
+    }
+
+    #[test]
+    fn shutting_down_tasks_stops_clients_and_servers() {
+        test_util::init();
+
+        let (client_channel, server) =
+            test_util::serve(Server::<String, String>::default(), |_ctx, request| {
+                future::ready(Ok(request))
+            });
+
+        let results = async {
+            let tasks = Tasks::new();
+            tasks.spawn(server)?;
+            let mut client =
+                await!(tasks.scope(client::new(client::Config::default(), client_channel)))?;
+            let response = await!(client.call(context::current(), "hi".into()))?;
+            // At least the connection and the client's dispatch task are still running.
+            let running = tasks.running();
+
+            await!(tasks.shutdown());
+            let after_shutdown = await!(client.call(context::current(), "bye".into()));
+            Ok::<_, io::Error>((response, running, after_shutdown))
+        };
+
+        let (response, running, after_shutdown) =
+            test_util::run_future(results.unwrap_or_else(|e| panic!(e)));
+        assert_eq!(response, "hi");
+        assert!(running >= 2, "{} tasks running", running);
+        assert!(after_shutdown.is_err());
+    }
+}
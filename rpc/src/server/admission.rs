@@ -0,0 +1,405 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Pluggable admission control, for detecting overload in ways the server doesn't know about.
+//!
+//! A channel [with an admission policy](crate::server::Channel::with_admission) asks the policy
+//! whether to handle each request it reads, passing along the connection's [`Load`], and tells it
+//! how each admitted request ended. The policy is shared by every channel it's given to, so it can
+//! also weigh signals the server doesn't track, such as memory use or event-loop lag, and keep
+//! its own state across connections, e.g. to adapt a concurrency limit to observed latencies.
+//!
+//! The in-flight request limit in [`Config`](crate::server::Config) still applies; the policy is
+//! only asked about requests under it. Policies can be combined with [`Both`].
+
+use crate::{
+    context,
+    util::concurrency::{AdaptiveLimit, INITIAL_LIMIT},
+    ServerError,
+};
+use futures::{
+    ready,
+    task::{Context, Poll},
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Decides which requests a server handles.
+pub trait Admission: fmt::Debug + Send + Sync + 'static {
+    /// Decides what to do with a request that was just read off a connection with `load`.
+    fn admit(&self, ctx: &context::Context, load: &Load) -> Decision;
+
+    /// Called once an admitted request ends, `latency` after it was admitted.
+    fn complete(&self, _ctx: &context::Context, _latency: Duration, _outcome: Outcome) {}
+}
+
+/// The load on a connection when a request is read off it.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Load {
+    /// The number of requests in flight on the connection, not counting the new one.
+    pub in_flight_requests: usize,
+    /// The connection's in-flight request limit.
+    pub max_in_flight_requests: usize,
+    /// The number of responses that are ready but waiting to be written, e.g. because they are
+    /// pipelined behind the response to an earlier request.
+    pub queued_responses: usize,
+}
+
+/// What to do with a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Handle the request.
+    Admit,
+    /// Respond to the request with a throttled error, the same as if the connection were at its
+    /// in-flight request limit.
+    Shed,
+    /// Respond to the request with this error.
+    Reject(ServerError),
+}
+
+/// How an admitted request ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The handler responded successfully.
+    Succeeded,
+    /// The handler responded with an error.
+    Failed,
+    /// The request was canceled, or its deadline passed, before the handler responded.
+    Abandoned,
+}
+
+/// Sheds requests beyond a concurrency limit that adapts to latency, after the gradient algorithm
+/// of Netflix's concurrency-limits. Used by servers [configured](crate::server::Config) with
+/// `adaptive_concurrency`.
+///
+/// The limit counts the requests in flight across all channels given the same policy. While
+/// latency holds near its long-term average, the limit grows, and when latency rises above it,
+/// the limit shrinks.
+#[derive(Debug)]
+pub struct AdaptiveConcurrency {
+    state: Mutex<Concurrency>,
+}
+
+#[derive(Debug)]
+struct Concurrency {
+    limit: AdaptiveLimit,
+    in_flight: usize,
+}
+
+impl AdaptiveConcurrency {
+    /// Returns a policy whose limit starts at `initial` requests and adapts up to `max_limit`.
+    pub fn new(initial: usize, max_limit: usize) -> Self {
+        AdaptiveConcurrency {
+            state: Mutex::new(Concurrency {
+                limit: AdaptiveLimit::new(initial, max_limit),
+                in_flight: 0,
+            }),
+        }
+    }
+
+    /// Returns the current limit.
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit.limit()
+    }
+}
+
+impl Default for AdaptiveConcurrency {
+    fn default() -> Self {
+        AdaptiveConcurrency::new(INITIAL_LIMIT, 10_000)
+    }
+}
+
+impl Admission for AdaptiveConcurrency {
+    fn admit(&self, _: &context::Context, _: &Load) -> Decision {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight >= state.limit.limit() {
+            return Decision::Shed;
+        }
+        state.in_flight += 1;
+        Decision::Admit
+    }
+
+    fn complete(&self, _: &context::Context, latency: Duration, outcome: Outcome) {
+        let mut state = self.state.lock().unwrap();
+        let in_flight = state.in_flight;
+        state.in_flight -= 1;
+        if outcome != Outcome::Abandoned {
+            state.limit.sample(latency, in_flight);
+        }
+    }
+}
+
+/// Sheds a share of requests that shrinks from all of them to none over a warm-up period, so
+/// that a server that just started, e.g. with cold caches, takes on load gradually. Used by
+/// servers [configured](crate::server::Config) with a `warm_up` period, which starts when the
+/// server does.
+///
+/// The period can be [restarted](WarmUp::restart), e.g. when the server registers with service
+/// discovery, if clients only find it then.
+#[derive(Debug)]
+pub struct WarmUp {
+    period: Duration,
+    start: Mutex<Instant>,
+}
+
+impl WarmUp {
+    /// Returns a policy whose warm-up period of `period` starts now.
+    pub fn new(period: Duration) -> Self {
+        WarmUp {
+            period,
+            start: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Starts the warm-up period over.
+    pub fn restart(&self) {
+        *self.start.lock().unwrap() = Instant::now();
+    }
+
+    /// Returns the share of requests currently shed, from 0 to 1.
+    pub fn shed_fraction(&self) -> f64 {
+        let elapsed = self.start.lock().unwrap().elapsed();
+        if elapsed >= self.period {
+            return 0.0;
+        }
+        1.0 - as_secs_f64(elapsed) / as_secs_f64(self.period)
+    }
+}
+
+fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+impl Admission for WarmUp {
+    fn admit(&self, _: &context::Context, _: &Load) -> Decision {
+        if rand::random::<f64>() < self.shed_fraction() {
+            Decision::Shed
+        } else {
+            Decision::Admit
+        }
+    }
+}
+
+/// Admits requests that both policies admit, asking the second only about requests the first
+/// admits.
+#[derive(Debug)]
+pub struct Both<A, B>(pub A, pub B);
+
+impl<A: Admission, B: Admission> Admission for Both<A, B> {
+    fn admit(&self, ctx: &context::Context, load: &Load) -> Decision {
+        match self.0.admit(ctx, load) {
+            Decision::Admit => {}
+            decision => return decision,
+        }
+        match self.1.admit(ctx, load) {
+            Decision::Admit => Decision::Admit,
+            decision => {
+                // The first policy is told the request it admitted won't be handled.
+                self.0
+                    .complete(ctx, Duration::from_secs(0), Outcome::Abandoned);
+                decision
+            }
+        }
+    }
+
+    fn complete(&self, ctx: &context::Context, latency: Duration, outcome: Outcome) {
+        self.0.complete(ctx, latency, outcome);
+        self.1.complete(ctx, latency, outcome);
+    }
+}
+
+/// A response future that reports to an [`Admission`] policy when it ends.
+pub(crate) struct Tracked<Fut> {
+    future: Fut,
+    admission: Arc<dyn Admission>,
+    ctx: context::Context,
+    admitted: Instant,
+    complete: bool,
+}
+
+impl<Fut> Tracked<Fut> {
+    unsafe_pinned!(future: Fut);
+    unsafe_unpinned!(complete: bool);
+
+    pub(crate) fn new(future: Fut, admission: Arc<dyn Admission>, ctx: context::Context) -> Self {
+        Tracked {
+            future,
+            admission,
+            ctx,
+            admitted: Instant::now(),
+            complete: false,
+        }
+    }
+
+    fn report(&self, outcome: Outcome) {
+        self.admission
+            .complete(&self.ctx, self.admitted.elapsed(), outcome);
+    }
+}
+
+impl<Fut, Resp> Future for Tracked<Fut>
+where
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    type Output = io::Result<Resp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
+        let result = ready!(self.as_mut().future().poll(cx));
+        *self.as_mut().complete() = true;
+        self.report(match result {
+            Ok(_) => Outcome::Succeeded,
+            Err(_) => Outcome::Failed,
+        });
+        Poll::Ready(result)
+    }
+}
+
+impl<Fut> Drop for Tracked<Fut> {
+    fn drop(&mut self) {
+        if !self.complete {
+            self.report(Outcome::Abandoned);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AdaptiveConcurrency, Admission, Both, Decision, Load, Outcome, WarmUp};
+    use crate::{client, context, server::Handler, test_util, transport, Server, ServerError};
+    use futures::{channel::oneshot, future, prelude::*, stream};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    const LOAD: Load = Load {
+        in_flight_requests: 0,
+        max_in_flight_requests: 10,
+        queued_responses: 0,
+    };
+
+    #[test]
+    fn adaptive_concurrency_sheds_beyond_limit() {
+        let admission = AdaptiveConcurrency::new(1, 10);
+        let ctx = context::current();
+        let load = Load {
+            in_flight_requests: 0,
+            max_in_flight_requests: 10,
+            queued_responses: 0,
+        };
+
+        assert_eq!(admission.admit(&ctx, &load), Decision::Admit);
+        assert_eq!(admission.admit(&ctx, &load), Decision::Shed);
+        admission.complete(&ctx, Duration::from_millis(10), Outcome::Succeeded);
+        assert_eq!(admission.admit(&ctx, &load), Decision::Admit);
+    }
+
+    #[test]
+    fn warm_up_sheds_less_over_time() {
+        let warm_up = WarmUp::new(Duration::from_secs(3600));
+        assert!(warm_up.shed_fraction() > 0.99);
+
+        let warmed_up = WarmUp::new(Duration::from_secs(0));
+        assert_eq!(warmed_up.shed_fraction(), 0.0);
+        assert_eq!(warmed_up.admit(&context::current(), &LOAD), Decision::Admit);
+    }
+
+    /// Admits every request, and records how they ended.
+    #[derive(Debug, Default)]
+    struct Outcomes(Mutex<Vec<Outcome>>);
+
+    impl Admission for Outcomes {
+        fn admit(&self, _: &context::Context, _: &Load) -> Decision {
+            Decision::Admit
+        }
+
+        fn complete(&self, _: &context::Context, _: Duration, outcome: Outcome) {
+            self.0.lock().unwrap().push(outcome);
+        }
+    }
+
+    #[test]
+    fn both_abandons_requests_the_second_policy_sheds() {
+        let admission = Both(Outcomes::default(), AdaptiveConcurrency::new(1, 10));
+        let ctx = context::current();
+        assert_eq!(admission.admit(&ctx, &LOAD), Decision::Admit);
+        assert_eq!(admission.admit(&ctx, &LOAD), Decision::Shed);
+        admission.complete(&ctx, Duration::from_millis(10), Outcome::Succeeded);
+        assert_eq!(
+            *(admission.0).0.lock().unwrap(),
+            vec![Outcome::Abandoned, Outcome::Succeeded]
+        );
+    }
+
+    #[test]
+    fn admission_policy_decides_which_requests_are_handled() {
+        #[derive(Debug, Default)]
+        struct Policy {
+            outcomes: Mutex<Vec<Outcome>>,
+        }
+
+        impl Admission for Policy {
+            fn admit(&self, _: &context::Context, load: &Load) -> Decision {
+                match load.in_flight_requests {
+                    0 => Decision::Admit,
+                    _ => Decision::Reject(ServerError::new(io::ErrorKind::Other, "Busy.")),
+                }
+            }
+
+            fn complete(&self, _: &context::Context, _: Duration, outcome: Outcome) {
+                self.outcomes.lock().unwrap().push(outcome);
+            }
+        }
+
+        test_util::init();
+
+        let policy = Arc::new(Policy::default());
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(Some(release_rx)));
+        let server = Server::<String, String>::default()
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .map_ok({
+                let policy = policy.clone();
+                move |channel| channel.with_admission(policy.clone())
+            })
+            .respond_with(move |_ctx, request| {
+                let release = release_rx.lock().unwrap().take();
+                async move {
+                    if let Some(release) = release {
+                        let _ = await!(release);
+                    }
+                    Ok(request)
+                }
+            });
+
+        let responses = async {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            let slow = client.start_call(context::current(), "slow".into())?;
+            let rejected = await!(client.call(context::current(), "rejected".into()));
+            release_tx.send(()).unwrap();
+            Ok::<_, io::Error>((await!(slow), rejected))
+        };
+
+        let (slow, rejected) = test_util::run_future(future::join(
+            server,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .1;
+        assert_eq!(slow.unwrap(), "slow");
+        assert_eq!(rejected.unwrap_err().to_string(), "Busy.");
+        assert_eq!(*policy.outcomes.lock().unwrap(), vec![Outcome::Succeeded]);
+    }
+}
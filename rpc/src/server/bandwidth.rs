@@ -0,0 +1,224 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Per-method byte counts, for capacity planning.
+//!
+//! A [`Bandwidth`] counts the serialized bytes of the requests and responses of each method.
+//! Wrapping a request handler with [`count_bytes`] counts every request it's passed and every
+//! response it returns. Counts accumulate until [taken](Bandwidth::take), so that an exporter can
+//! take them once per scrape to get the bytes of each interval.
+
+use crate::context;
+use fnv::FnvHashMap;
+use futures::{
+    ready,
+    task::{Context, Poll},
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+/// The requests and responses counted for a method.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bytes {
+    /// The number of requests counted.
+    pub requests: u64,
+    /// The serialized bytes of the requests counted.
+    pub request_bytes: u64,
+    /// The number of successful responses counted.
+    pub responses: u64,
+    /// The serialized bytes of the successful responses counted.
+    pub response_bytes: u64,
+}
+
+impl Bytes {
+    fn add(&mut self, other: &Bytes) {
+        self.requests += other.requests;
+        self.request_bytes += other.request_bytes;
+        self.responses += other.responses;
+        self.response_bytes += other.response_bytes;
+    }
+}
+
+/// Counts request and response bytes per method. Clones share the same counts, so a single
+/// `Bandwidth` can be used across all connections of a server.
+#[derive(Clone, Debug, Default)]
+pub struct Bandwidth {
+    methods: Arc<Mutex<FnvHashMap<&'static str, Bytes>>>,
+}
+
+impl Bandwidth {
+    /// Returns a counter that hasn't counted anything.
+    pub fn new() -> Self {
+        Bandwidth::default()
+    }
+
+    /// Returns the counts of `method`, if any of its requests were counted.
+    pub fn bytes(&self, method: &str) -> Option<Bytes> {
+        self.methods.lock().unwrap().get(method).cloned()
+    }
+
+    /// Returns the counts of every method with requests counted.
+    pub fn stats(&self) -> Vec<(&'static str, Bytes)> {
+        self.methods
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&method, &bytes)| (method, bytes))
+            .collect()
+    }
+
+    /// Returns the counts of all methods combined.
+    pub fn total(&self) -> Bytes {
+        let mut total = Bytes::default();
+        for bytes in self.methods.lock().unwrap().values() {
+            total.add(bytes);
+        }
+        total
+    }
+
+    /// Returns the counts of every method with requests counted, and starts counting anew.
+    pub fn take(&self) -> Vec<(&'static str, Bytes)> {
+        self.methods.lock().unwrap().drain().collect()
+    }
+
+    fn record_request(&self, method: &'static str, bytes: u64) {
+        let mut methods = self.methods.lock().unwrap();
+        let counts = methods.entry(method).or_default();
+        counts.requests += 1;
+        counts.request_bytes += bytes;
+    }
+
+    fn record_response(&self, method: &'static str, bytes: u64) {
+        let mut methods = self.methods.lock().unwrap();
+        let counts = methods.entry(method).or_default();
+        counts.responses += 1;
+        counts.response_bytes += bytes;
+    }
+}
+
+/// A future returned by a request handler wrapped with [`count_bytes`] that counts the response.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Counted<Fut, R> {
+    future: Fut,
+    bandwidth: Bandwidth,
+    method: &'static str,
+    response_bytes: R,
+}
+
+impl<Fut, R> Counted<Fut, R> {
+    unsafe_pinned!(future: Fut);
+    unsafe_unpinned!(response_bytes: R);
+}
+
+impl<Fut, R, Resp> Future for Counted<Fut, R>
+where
+    Fut: Future<Output = io::Result<Resp>>,
+    R: Fn(&Resp) -> u64,
+{
+    type Output = io::Result<Resp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
+        let result = ready!(self.as_mut().future().poll(cx));
+        if let Ok(response) = &result {
+            let bytes = (self.as_mut().response_bytes())(response);
+            self.bandwidth.record_response(self.method, bytes);
+        }
+        Poll::Ready(result)
+    }
+}
+
+/// Wraps request handler `f` so that the bytes of every request and successful response are
+/// counted against their method in `bandwidth`.
+///
+/// `measure` returns the name of the method a request calls, along with the request's serialized
+/// size, and `response_bytes` returns a response's serialized size; with bincode, both can use
+/// `bincode::serialized_size`. Error responses aren't counted, since their encoding is up to the
+/// transport.
+pub fn count_bytes<Req, Resp, M, R, F, Fut>(
+    bandwidth: Bandwidth,
+    measure: M,
+    response_bytes: R,
+    f: F,
+) -> impl FnOnce(context::Context, Req) -> Counted<Fut, R> + Send + 'static + Clone
+where
+    M: Fn(&Req) -> (&'static str, u64) + Send + 'static + Clone,
+    R: Fn(&Resp) -> u64 + Send + 'static + Clone,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    move |ctx, req| {
+        let (method, bytes) = measure(&req);
+        bandwidth.record_request(method, bytes);
+        Counted {
+            future: f(ctx, req),
+            bandwidth,
+            method,
+            response_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count_bytes, Bandwidth, Bytes};
+    use futures::{executor::block_on, future};
+    use std::io;
+
+    #[test]
+    fn counts_requests_and_responses_per_method() {
+        let bandwidth = Bandwidth::new();
+        let handler = count_bytes(
+            bandwidth.clone(),
+            |req: &String| {
+                let method = if req.is_empty() { "ping" } else { "echo" };
+                (method, req.len() as u64)
+            },
+            |resp: &String| resp.len() as u64 * 2,
+            |_, req: String| {
+                future::ready(if req == "fail" {
+                    Err(io::Error::from(io::ErrorKind::Other))
+                } else {
+                    Ok(req.repeat(2))
+                })
+            },
+        );
+        for req in &["hi", "hello", "fail", ""] {
+            let _ = block_on(handler.clone()(crate::context::current(), req.to_string()));
+        }
+
+        assert_eq!(
+            bandwidth.bytes("echo"),
+            Some(Bytes {
+                requests: 3,
+                request_bytes: 11,
+                responses: 2,
+                response_bytes: 28,
+            })
+        );
+        assert_eq!(bandwidth.bytes("ping").unwrap().responses, 1);
+        assert_eq!(bandwidth.total().requests, 4);
+    }
+
+    #[test]
+    fn take_starts_counting_anew() {
+        let bandwidth = Bandwidth::new();
+        bandwidth.record_request("echo", 10);
+        bandwidth.record_response("echo", 20);
+
+        let taken = bandwidth.take();
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].0, "echo");
+        assert_eq!(taken[0].1.request_bytes + taken[0].1.response_bytes, 30);
+        assert!(bandwidth.bytes("echo").is_none());
+        assert_eq!(bandwidth.total(), Bytes::default());
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Calls that carry a batch of items, each handled on its own.
+//!
+//! A batch method takes a `Vec` of items and responds with a [`Batch`]: a result per item, in the
+//! order the items were sent. An item that fails has a [`ServerError`] as its result, the same
+//! error it would have failed a call of its own with, while the rest of the batch succeeds.
+//! Wrapping a handler of single items with [`batch`] makes a handler of batches, which handles up
+//! to a given number of a batch's items at once.
+
+use crate::{context, server::handler_error, ServerError};
+use futures::{
+    prelude::*,
+    ready,
+    stream::FuturesOrdered,
+    task::{Context, Poll},
+};
+use pin_utils::unsafe_unpinned;
+use std::{fmt, io, mem, pin::Pin, vec};
+
+/// The results of a batch call, one per item, in the order the items were sent.
+pub type Batch<T> = Vec<Result<T, ServerError>>;
+
+/// Wraps handler `f` of single items so that it handles batches of them, calling `f` on each
+/// item of a batch with the batch's context. At most `parallelism` items of a batch are handled
+/// at once; a `parallelism` of 1 handles them one after another.
+///
+/// The batch call itself never fails because of its items; the server can still fail it, e.g.
+/// when it misses its deadline.
+pub fn batch<Item, Resp, F, Fut>(
+    parallelism: usize,
+    f: F,
+) -> impl FnOnce(context::Context, Vec<Item>) -> Batched<Item, Resp, F, Fut> + Send + 'static + Clone
+where
+    F: FnOnce(context::Context, Item) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    let parallelism = parallelism.max(1);
+    move |ctx, items| Batched {
+        ctx,
+        items: items.into_iter(),
+        f,
+        parallelism,
+        running: FuturesOrdered::new(),
+        results: vec![],
+    }
+}
+
+/// A future returned by a handler wrapped with [`batch`], which handles the items of a batch.
+#[must_use = "futures do nothing unless polled"]
+pub struct Batched<Item, Resp, F, Fut: Future> {
+    ctx: context::Context,
+    /// The items not handled yet.
+    items: vec::IntoIter<Item>,
+    f: F,
+    parallelism: usize,
+    running: FuturesOrdered<Fut>,
+    results: Batch<Resp>,
+}
+
+impl<Item, Resp, F, Fut: Future> Batched<Item, Resp, F, Fut> {
+    unsafe_unpinned!(items: vec::IntoIter<Item>);
+    unsafe_unpinned!(running: FuturesOrdered<Fut>);
+    unsafe_unpinned!(results: Batch<Resp>);
+}
+
+impl<Item, Resp, F, Fut: Future> fmt::Debug for Batched<Item, Resp, F, Fut> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Batched")
+            .field("ctx", &self.ctx)
+            .field("waiting", &self.items.len())
+            .field("running", &self.running.len())
+            .field("done", &self.results.len())
+            .finish()
+    }
+}
+
+impl<Item, Resp, F, Fut> Future for Batched<Item, Resp, F, Fut>
+where
+    F: FnOnce(context::Context, Item) -> Fut + Clone,
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    type Output = io::Result<Batch<Resp>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Batch<Resp>>> {
+        loop {
+            while self.running.len() < self.parallelism {
+                let item = match self.as_mut().items().next() {
+                    Some(item) => item,
+                    None => break,
+                };
+                let handled = self.f.clone()(self.ctx, item);
+                self.as_mut().running().push(handled);
+            }
+            match ready!(self.as_mut().running().poll_next_unpin(cx)) {
+                Some(result) => self.as_mut().results().push(result.map_err(handler_error)),
+                None => return Poll::Ready(Ok(mem::replace(self.as_mut().results(), vec![]))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::batch;
+    use crate::{context, ErrorCode, ServerError};
+    use futures::{executor::block_on, future};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    #[test]
+    fn failed_items_fail_only_their_own_result() {
+        let handler = batch(4, |_, item: u32| {
+            future::ready(if item % 2 == 0 {
+                Ok(item * 10)
+            } else {
+                Err(ServerError::application(item, "Odd.").into())
+            })
+        });
+
+        let results = block_on(handler(context::current(), vec![2, 3, 4])).unwrap();
+        assert_eq!(results[0], Ok(20));
+        assert_eq!(
+            results[1].as_ref().unwrap_err().code,
+            ErrorCode::Application(3)
+        );
+        assert_eq!(results[2], Ok(40));
+    }
+
+    #[test]
+    fn handles_at_most_parallelism_items_at_once() {
+        let max_running = |parallelism| {
+            // Items started and finished, and the most running at once.
+            let counts = Arc::new(Mutex::new((0, 0, 0)));
+            let handler = batch(parallelism, {
+                let counts = counts.clone();
+                move |_, item: u32| {
+                    {
+                        let (started, finished, max) = &mut *counts.lock().unwrap();
+                        *started += 1;
+                        *max = (*started - *finished).max(*max);
+                    }
+                    future::lazy(move |_| {
+                        counts.lock().unwrap().1 += 1;
+                        Ok::<_, io::Error>(item)
+                    })
+                }
+            });
+            let results = block_on(handler(context::current(), vec![1, 2, 3, 4, 5])).unwrap();
+            let max = counts.lock().unwrap().2;
+            assert_eq!(results, vec![Ok(1), Ok(2), Ok(3), Ok(4), Ok(5)]);
+            max
+        };
+
+        assert_eq!(max_running(2), 2);
+        assert_eq!(max_running(10), 5);
+    }
+}
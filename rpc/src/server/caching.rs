@@ -0,0 +1,100 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Lets a request's handler say whether, and for how long, the client may [cache](Caching) the
+//! response, so that the server controls client caching instead of each caller hard-coding a TTL.
+//!
+//! A handler [sets](set) the caching of its response, which is sent along with the response and
+//! honored by the client's [cache](crate::client::cache). Once a cached response with a version is
+//! stale, the cache sends the request again with the version, which the handler reads with
+//! [`cached_version`]: if its data hasn't changed, it can fail the request with [`not_modified`],
+//! optionally setting a new caching, and the client reuses its cached response.
+
+use crate::{metadata, Caching, ErrorCode, ServerError};
+use futures::{
+    task::{Context, Poll},
+    Future,
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    cell::RefCell,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+/// The [metadata](crate::metadata) key of the version of the response the client has cached.
+pub const CACHED_VERSION: &str = "cached-version";
+
+/// The caching of a request's response, set by its handler.
+pub(crate) type Slot = Arc<Mutex<Option<Caching>>>;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Slot>> = RefCell::new(None);
+}
+
+/// Sets the caching of the current request's response, replacing any set before. Returns false,
+/// doing nothing, outside of a request's handler.
+pub fn set(caching: Caching) -> bool {
+    CURRENT.with(|current| match &*current.borrow() {
+        Some(slot) => {
+            *slot.lock().unwrap() = Some(caching);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Returns the version of the response the client of the current request has cached, if it sent
+/// one.
+pub fn cached_version() -> Option<String> {
+    metadata::get(CACHED_VERSION)
+}
+
+/// Returns the error that tells the client its cached response is still current, to fail a request
+/// with when its [`cached_version`] is the version of the data it reads.
+pub fn not_modified() -> io::Error {
+    ServerError::new(io::ErrorKind::Other, "Not modified.")
+        .with_code(ErrorCode::NotModified)
+        .into()
+}
+
+/// Returns a future that makes `slot` hold the caching [set](set) while polling `future`.
+pub(crate) fn scope<F: Future>(slot: Slot, future: F) -> Scoped<F> {
+    Scoped { slot, future }
+}
+
+/// A future that makes a request's caching settable while it is polled.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub(crate) struct Scoped<F> {
+    slot: Slot,
+    future: F,
+}
+
+impl<F> Scoped<F> {
+    unsafe_unpinned!(slot: Slot);
+    unsafe_pinned!(future: F);
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        /// Restores the previous slot, even if the future panics.
+        struct Reset(Option<Slot>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+            }
+        }
+
+        let slot = self.as_mut().slot().clone();
+        let _reset = Reset(CURRENT.with(|current| current.replace(Some(slot))));
+        self.as_mut().future().poll(cx)
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Lets request handlers find out when their request is canceled.
+//!
+//! The server stops polling a request's handler as soon as the client cancels the request, its
+//! deadline passes, or its connection closes, but tasks the handler spawned keep running. A handler
+//! can take the request's [`canceled`] future along to such tasks, so they can stop as well.
+
+use futures::task::{Context, Poll, Waker};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+thread_local! {
+    static CURRENT: RefCell<Option<Signal>> = RefCell::new(None);
+}
+
+/// Returns a future that resolves once the current request is canceled, i.e. once the server
+/// drops the request's handler before it responds.
+///
+/// The server makes a request current while polling the request's handler. Outside of a handler,
+/// the future never resolves.
+pub fn canceled() -> Canceled {
+    Canceled {
+        signal: CURRENT.with(|current| current.borrow().clone()),
+    }
+}
+
+/// A future that resolves once a request is canceled. Returned by [`canceled`].
+#[derive(Clone, Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Canceled {
+    signal: Option<Signal>,
+}
+
+impl Canceled {
+    /// Returns true if the request has been canceled.
+    pub fn is_canceled(&self) -> bool {
+        self.signal
+            .as_ref()
+            .map(|signal| signal.0.lock().unwrap().canceled)
+            .unwrap_or(false)
+    }
+}
+
+impl Future for Canceled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let signal = match &self.signal {
+            Some(signal) => signal,
+            None => return Poll::Pending,
+        };
+        let mut state = signal.0.lock().unwrap();
+        if state.canceled {
+            return Poll::Ready(());
+        }
+        if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            state.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Signal(Arc<Mutex<State>>);
+
+#[derive(Debug, Default)]
+struct State {
+    canceled: bool,
+    wakers: Vec<Waker>,
+}
+
+impl Signal {
+    fn cancel(&self) {
+        let mut state = self.0.lock().unwrap();
+        state.canceled = true;
+        for waker in state.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// A request handler's future, which makes its request current while it is polled, and cancels
+/// the request if dropped before it completes.
+#[derive(Debug)]
+pub(crate) struct Cancelable<Fut> {
+    future: Fut,
+    signal: Signal,
+    complete: bool,
+}
+
+impl<Fut> Cancelable<Fut> {
+    unsafe_pinned!(future: Fut);
+    unsafe_unpinned!(complete: bool);
+
+    pub(crate) fn new(future: Fut) -> Self {
+        Cancelable {
+            future,
+            signal: Signal::default(),
+            complete: false,
+        }
+    }
+}
+
+impl<Fut: Future> Future for Cancelable<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Fut::Output> {
+        /// Restores the previous request, even if the future panics.
+        struct Reset(Option<Signal>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+            }
+        }
+
+        let signal = self.signal.clone();
+        let _reset = Reset(CURRENT.with(|current| current.replace(Some(signal))));
+        let output = self.as_mut().future().poll(cx);
+        if output.is_ready() {
+            *self.as_mut().complete() = true;
+        }
+        output
+    }
+}
+
+impl<Fut> Drop for Cancelable<Fut> {
+    fn drop(&mut self) {
+        if !self.complete {
+            self.signal.cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{canceled, Cancelable, Canceled};
+    use crate::{client, context, server::Handler, test_util, transport, Server};
+    use futures::{
+        channel::oneshot,
+        executor::block_on,
+        future,
+        prelude::*,
+        stream,
+        task::{Context, Poll},
+    };
+    use futures_test::task::noop_waker_ref;
+    use std::{
+        io,
+        pin::Pin,
+        sync::{Arc, Mutex},
+    };
+
+    #[test]
+    fn completed_handler_does_not_cancel_its_request() {
+        let canceled = block_on(Cancelable::new(future::lazy(|_| canceled())));
+        assert!(!canceled.is_canceled());
+    }
+
+    #[test]
+    fn dropping_an_incomplete_handler_cancels_its_request() {
+        let cx = &mut Context::from_waker(&noop_waker_ref());
+        let mut captured = None;
+        let mut handler = Box::pin(Cancelable::new(future::poll_fn(|_| {
+            captured = Some(canceled());
+            Poll::<()>::Pending
+        })));
+        assert_eq!(Pin::as_mut(&mut handler).poll(cx), Poll::Pending);
+        drop(handler);
+
+        let canceled = captured.unwrap();
+        assert!(canceled.is_canceled());
+        block_on(canceled);
+    }
+
+    #[test]
+    fn dropping_a_call_cancels_its_request_on_the_server() {
+        test_util::init();
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let (canceled_tx, canceled_rx) = oneshot::channel::<Canceled>();
+        let canceled_tx = Arc::new(Mutex::new(Some(canceled_tx)));
+        let server = Server::<String, String>::default()
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(move |_ctx, request| {
+                let canceled_tx = canceled_tx.lock().unwrap().take();
+                async move {
+                    let canceled = canceled();
+                    if let Some(canceled_tx) = canceled_tx {
+                        let _ = canceled_tx.send(canceled.clone());
+                    }
+                    // Only the client can end this request.
+                    await!(canceled);
+                    Ok(request)
+                }
+            });
+
+        let canceled = async {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            let call = client.start_call(context::current(), "hi".into())?;
+            let canceled = await!(canceled_rx).unwrap();
+            assert!(!canceled.is_canceled());
+            drop(call);
+            await!(canceled);
+            Ok::<_, io::Error>(())
+        };
+
+        test_util::run_future(future::join(server, canceled))
+            .1
+            .unwrap();
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Deduplicates requests by idempotency key, so that a request resent by a client, e.g. after its
+//! connection dropped before the response arrived, is answered with the response to the original
+//! instead of being handled again.
+//!
+//! Wrapping a request handler with [`deduplicate`] stores the response to each request with a key
+//! in a [`Store`], and replays it to later requests with the same key until it expires. Clients
+//! usually send the key in the request's [metadata](crate::metadata) under [`IDEMPOTENCY_KEY`],
+//! which [`idempotency_key`] reads; other key functions can derive the key from the request
+//! itself.
+//!
+//! [`MemoryStore`] keeps responses in the server's memory, which suffices for a single server.
+//! Servers behind a load balancer need a store shared by all of them, such as one backed by a
+//! database or cache server, which they provide by implementing [`Store`].
+//!
+//! A duplicate that arrives while the original is still being handled is handled again, since
+//! there is no response to replay yet.
+
+use crate::{context, metadata, server::identity, ErrorCode, ServerError};
+use fnv::FnvHashMap;
+use futures::{
+    future::{self, BoxFuture},
+    prelude::*,
+};
+use log::{debug, warn};
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The [metadata](crate::metadata) key of the idempotency key read by [`idempotency_key`].
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Stores the responses to requests by idempotency key. Implementations are shared by all
+/// connections of a server, so must synchronize their own state.
+pub trait Store<Resp>: Send + Sync + 'static {
+    /// Returns the response stored under `key`, if it hasn't expired.
+    fn get(&self, key: &str) -> BoxFuture<'static, io::Result<Option<Resp>>>;
+
+    /// Stores `response` under `key` for `ttl`, replacing any response already stored under it.
+    fn insert(
+        &self,
+        key: String,
+        response: Resp,
+        ttl: Duration,
+    ) -> BoxFuture<'static, io::Result<()>>;
+}
+
+/// Returns the [`IDEMPOTENCY_KEY`] in the metadata of the current request, if any. Keys sent by
+/// authenticated clients are qualified by the client's [identity](identity::current), so that one
+/// client can't replay another's responses.
+pub fn idempotency_key<Req>(_: &Req) -> Option<String> {
+    let key = metadata::get(IDEMPOTENCY_KEY)?;
+    Some(match identity::current() {
+        Some(identity) => format!("{}/{}", identity, key),
+        None => key,
+    })
+}
+
+/// Wraps request handler `f` so that the responses to requests with a key are stored in `store`
+/// for `ttl`, and replayed to later requests with the same key instead of handling them.
+///
+/// `key` returns the idempotency key of a request, or None if it shouldn't be deduplicated; it's
+/// called while the request is current, so it can read its [metadata](crate::metadata), like
+/// [`idempotency_key`] does. Only successful responses are stored, so a request that failed is
+/// handled again when resent. Requests are failed as [unavailable](ErrorCode::Unavailable),
+/// without being handled, if the store can't be read.
+pub fn deduplicate<Req, Resp, S, K, F, Fut>(
+    store: Arc<S>,
+    ttl: Duration,
+    key: K,
+    f: F,
+) -> impl FnOnce(context::Context, Req) -> BoxFuture<'static, io::Result<Resp>> + Send + 'static + Clone
+where
+    Req: Send + 'static,
+    Resp: Clone + Send + 'static,
+    S: Store<Resp> + ?Sized,
+    K: Fn(&Req) -> Option<String> + Send + Sync + 'static + Clone,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+{
+    move |ctx, req| {
+        async move {
+            let key = match key(&req) {
+                Some(key) => key,
+                None => return await!(f(ctx, req)),
+            };
+            match await!(store.get(&key)) {
+                Ok(Some(response)) => {
+                    debug!(
+                        "[{}] Replaying the response to duplicate request {}.",
+                        ctx.trace_id(),
+                        key
+                    );
+                    return Ok(response);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    return Err(ServerError::new(
+                        io::ErrorKind::Other,
+                        format!("Could not look up idempotency key {}: {}", key, e),
+                    )
+                    .with_code(ErrorCode::Unavailable)
+                    .into())
+                }
+            }
+            let response = await!(f(ctx, req))?;
+            if let Err(e) = await!(store.insert(key.clone(), response.clone(), ttl)) {
+                warn!(
+                    "[{}] Could not store the response to request {}: {}",
+                    ctx.trace_id(),
+                    key,
+                    e
+                );
+            }
+            Ok(response)
+        }
+            .boxed()
+    }
+}
+
+/// Keeps up to a number of responses in memory, evicting the least recently used when full.
+pub struct MemoryStore<Resp> {
+    lru: Mutex<Lru<Resp>>,
+}
+
+impl<Resp> fmt::Debug for MemoryStore<Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let lru = self.lru.lock().unwrap();
+        f.debug_struct("MemoryStore")
+            .field("capacity", &lru.capacity)
+            .field("len", &lru.entries.len())
+            .finish()
+    }
+}
+
+struct Lru<Resp> {
+    capacity: usize,
+    /// Each response, with when it expires and when it was last used.
+    entries: FnvHashMap<String, (Resp, Instant, u64)>,
+    /// The keys of the responses, by when they were last used.
+    used: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl<Resp> Lru<Resp> {
+    fn touch(&mut self, key: &str) {
+        let clock = self.clock;
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.used.remove(&entry.2);
+            entry.2 = clock;
+            self.used.insert(clock, key.to_string());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, _, used)) = self.entries.remove(key) {
+            self.used.remove(&used);
+        }
+    }
+}
+
+impl<Resp> MemoryStore<Resp> {
+    /// Returns a store that keeps up to `capacity` responses.
+    pub fn new(capacity: usize) -> Self {
+        MemoryStore {
+            lru: Mutex::new(Lru {
+                capacity,
+                entries: FnvHashMap::default(),
+                used: BTreeMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Returns the number of responses stored, including expired ones not yet evicted.
+    pub fn len(&self) -> usize {
+        self.lru.lock().unwrap().entries.len()
+    }
+
+    /// Returns true if no responses are stored.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<Resp: Clone + Send + 'static> Store<Resp> for MemoryStore<Resp> {
+    fn get(&self, key: &str) -> BoxFuture<'static, io::Result<Option<Resp>>> {
+        let mut lru = self.lru.lock().unwrap();
+        let response = match lru.entries.get(key) {
+            Some((response, expires, _)) if *expires > Instant::now() => response.clone(),
+            Some(_) => {
+                lru.remove(key);
+                return future::ready(Ok(None)).boxed();
+            }
+            None => return future::ready(Ok(None)).boxed(),
+        };
+        lru.touch(key);
+        future::ready(Ok(Some(response))).boxed()
+    }
+
+    fn insert(
+        &self,
+        key: String,
+        response: Resp,
+        ttl: Duration,
+    ) -> BoxFuture<'static, io::Result<()>> {
+        let mut lru = self.lru.lock().unwrap();
+        lru.remove(&key);
+        if lru.capacity > 0 {
+            while lru.entries.len() >= lru.capacity {
+                let oldest = match lru.used.keys().next() {
+                    Some(&oldest) => oldest,
+                    None => break,
+                };
+                let oldest = lru.used.remove(&oldest).unwrap();
+                lru.entries.remove(&oldest);
+            }
+            lru.entries
+                .insert(key.clone(), (response, Instant::now() + ttl, 0));
+            lru.touch(&key);
+        }
+        future::ready(Ok(())).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deduplicate, idempotency_key, MemoryStore, Store, IDEMPOTENCY_KEY};
+    use crate::{metadata, ErrorCode, ServerError};
+    use futures::{
+        executor::block_on,
+        future::{self, BoxFuture},
+        prelude::*,
+    };
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// Calls `handler` with `req`, sending `key` as the idempotency key.
+    fn call<H, Fut>(handler: &H, key: Option<&str>, req: u32) -> io::Result<u32>
+    where
+        H: FnOnce(crate::context::Context, u32) -> Fut + Clone,
+        Fut: Future<Output = io::Result<u32>>,
+    {
+        let mut md = metadata::Metadata::new();
+        if let Some(key) = key {
+            md.insert(IDEMPOTENCY_KEY.into(), key.into());
+        }
+        let response = handler.clone()(crate::context::current(), req);
+        block_on(metadata::scope(Arc::new(md), response))
+    }
+
+    #[test]
+    fn replays_responses_to_duplicates() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let handler = {
+            let handled = handled.clone();
+            move |_, req: u32| {
+                handled.fetch_add(1, Ordering::SeqCst);
+                future::ready(Ok(req * 2))
+            }
+        };
+        let store = Arc::new(MemoryStore::new(10));
+        let handler = deduplicate(
+            store.clone(),
+            Duration::from_secs(60),
+            idempotency_key,
+            handler,
+        );
+
+        assert_eq!(call(&handler, Some("a"), 1).unwrap(), 2);
+        // The duplicate gets the original's response, without being handled.
+        assert_eq!(call(&handler, Some("a"), 5).unwrap(), 2);
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+        assert_eq!(call(&handler, Some("b"), 5).unwrap(), 10);
+        // Requests without keys are always handled.
+        assert_eq!(call(&handler, None, 3).unwrap(), 6);
+        assert_eq!(call(&handler, None, 3).unwrap(), 6);
+        assert_eq!(handled.load(Ordering::SeqCst), 4);
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn failed_requests_are_handled_again() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let handler = {
+            let handled = handled.clone();
+            move |_, req: u32| {
+                if handled.fetch_add(1, Ordering::SeqCst) == 0 {
+                    future::ready(Err(io::Error::from(io::ErrorKind::Other)))
+                } else {
+                    future::ready(Ok(req))
+                }
+            }
+        };
+        let handler = deduplicate(
+            Arc::new(MemoryStore::new(10)),
+            Duration::from_secs(60),
+            idempotency_key,
+            handler,
+        );
+
+        assert!(call(&handler, Some("a"), 1).is_err());
+        assert_eq!(call(&handler, Some("a"), 1).unwrap(), 1);
+        assert_eq!(handled.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn unreadable_stores_fail_requests_unhandled() {
+        struct Broken;
+
+        impl Store<u32> for Broken {
+            fn get(&self, _: &str) -> BoxFuture<'static, io::Result<Option<u32>>> {
+                future::ready(Err(io::Error::from(io::ErrorKind::ConnectionRefused))).boxed()
+            }
+
+            fn insert(&self, _: String, _: u32, _: Duration) -> BoxFuture<'static, io::Result<()>> {
+                unreachable!()
+            }
+        }
+
+        let handler = deduplicate(
+            Arc::new(Broken),
+            Duration::from_secs(60),
+            idempotency_key,
+            |_, _: u32| -> future::Ready<io::Result<u32>> { unreachable!() },
+        );
+        let e = call(&handler, Some("a"), 1).unwrap_err();
+        let e = e.get_ref().unwrap().downcast_ref::<ServerError>().unwrap();
+        assert_eq!(e.code, ErrorCode::Unavailable);
+    }
+
+    #[test]
+    fn memory_store_evicts_least_recently_used() {
+        let store = MemoryStore::new(2);
+        let ttl = Duration::from_secs(60);
+        block_on(store.insert("a".into(), 1, ttl)).unwrap();
+        block_on(store.insert("b".into(), 2, ttl)).unwrap();
+        // Using a makes b the least recently used.
+        assert_eq!(block_on(store.get("a")).unwrap(), Some(1));
+        block_on(store.insert("c".into(), 3, ttl)).unwrap();
+        assert_eq!(block_on(store.get("b")).unwrap(), None);
+        assert_eq!(block_on(store.get("a")).unwrap(), Some(1));
+        assert_eq!(block_on(store.get("c")).unwrap(), Some(3));
+    }
+
+    #[test]
+    fn memory_store_expires_responses() {
+        let store = MemoryStore::new(2);
+        block_on(store.insert("a".into(), 1, Duration::from_secs(0))).unwrap();
+        assert_eq!(block_on(store.get("a")).unwrap(), None);
+        assert!(store.is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Injects failures and latency into a share of a method's requests, to check how clients cope.
+//!
+//! A [`Faults`] holds the [`Fault`] injected into each method, and can be changed while the
+//! server runs, e.g. by an admin service that calls [`Faults::set`] during a game day. Wrapping a
+//! request handler with [`inject_faults`] applies the current fault of each request's method.
+
+use crate::{context, server::quota::Enforced, ServerError};
+use fnv::FnvHashMap;
+use futures::{
+    compat::{Compat01As03, Future01CompatExt},
+    future::{self, Either},
+    prelude::*,
+    ready,
+    task::{Context, Poll},
+};
+use log::{debug, trace};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_timer::Delay;
+
+/// What to inject into a method's requests.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fault {
+    /// The fraction of requests failed without being handled, from 0 to 1.
+    pub error_fraction: f64,
+    /// The kind of error failed requests are failed with.
+    pub error_kind: io::ErrorKind,
+    /// The fraction of requests held back by `delay` before being handled or failed, from 0 to 1.
+    pub delay_fraction: f64,
+    /// How long delayed requests are held back.
+    pub delay: Duration,
+}
+
+impl Default for Fault {
+    fn default() -> Self {
+        Fault {
+            error_fraction: 0.0,
+            error_kind: io::ErrorKind::Other,
+            delay_fraction: 0.0,
+            delay: Duration::from_secs(0),
+        }
+    }
+}
+
+/// The faults injected into each method. Clones share the same faults, so a single `Faults` can
+/// be used across all connections of a server, and changed from anywhere.
+#[derive(Clone, Debug, Default)]
+pub struct Faults {
+    methods: Arc<Mutex<FnvHashMap<String, Fault>>>,
+}
+
+impl Faults {
+    /// Returns a set of faults that injects nothing.
+    pub fn new() -> Self {
+        Faults::default()
+    }
+
+    /// Injects `fault` into requests to `method` from now on, replacing its previous fault.
+    pub fn set(&self, method: impl Into<String>, fault: Fault) {
+        self.methods.lock().unwrap().insert(method.into(), fault);
+    }
+
+    /// Stops injecting faults into requests to `method`.
+    pub fn clear(&self, method: &str) {
+        self.methods.lock().unwrap().remove(method);
+    }
+
+    /// Stops injecting faults into requests to any method.
+    pub fn clear_all(&self) {
+        self.methods.lock().unwrap().clear();
+    }
+
+    /// Returns the fault injected into requests to `method`, if any.
+    pub fn get(&self, method: &str) -> Option<Fault> {
+        self.methods.lock().unwrap().get(method).cloned()
+    }
+}
+
+/// A future returned by a request handler wrapped with [`inject_faults`] that may be delayed
+/// before it runs.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Injected<Fut, Resp> {
+    delay: Option<Compat01As03<Delay>>,
+    future: Enforced<Fut, Resp>,
+}
+
+impl<Fut, Resp> Injected<Fut, Resp> {
+    unsafe_unpinned!(delay: Option<Compat01As03<Delay>>);
+    unsafe_pinned!(future: Enforced<Fut, Resp>);
+}
+
+impl<Fut, Resp> Future for Injected<Fut, Resp>
+where
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    type Output = io::Result<Resp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
+        if let Some(delay) = self.as_mut().delay() {
+            // If the timer fails, the request just isn't delayed.
+            let _ = ready!(delay.poll_unpin(cx));
+            *self.as_mut().delay() = None;
+        }
+        self.as_mut().future().poll(cx)
+    }
+}
+
+/// Wraps request handler `f` so that the current fault of each request's method, if any, is
+/// injected into the request.
+///
+/// `method` returns the name of the method a request calls. Services defined with
+/// `tarpc::service!` can use `Request::name`.
+pub fn inject_faults<Req, Resp, M, F, Fut>(
+    faults: Faults,
+    method: M,
+    f: F,
+) -> impl FnOnce(context::Context, Req) -> Injected<Fut, Resp> + Send + 'static + Clone
+where
+    M: Fn(&Req) -> &str + Send + 'static + Clone,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    move |ctx, req| {
+        let fault = match faults.get(method(&req)) {
+            Some(fault) => fault,
+            None => {
+                return Injected {
+                    delay: None,
+                    future: Either::Left(f(ctx, req)),
+                }
+            }
+        };
+        let delay = if rand::random::<f64>() < fault.delay_fraction {
+            trace!(
+                "[{}] Injecting {:?} of latency.",
+                ctx.trace_id(),
+                fault.delay
+            );
+            Some(Delay::new(Instant::now() + fault.delay).compat())
+        } else {
+            None
+        };
+        let future = if rand::random::<f64>() < fault.error_fraction {
+            debug!(
+                "[{}] Injecting a {:?} error.",
+                ctx.trace_id(),
+                fault.error_kind
+            );
+            let error = ServerError::new(fault.error_kind, "Injected fault.");
+            Either::Right(future::ready(Err(error.into())))
+        } else {
+            Either::Left(f(ctx, req))
+        };
+        Injected { delay, future }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inject_faults, Fault, Faults};
+    use crate::ServerError;
+    use futures::{executor::block_on, future};
+    use std::io;
+
+    #[test]
+    fn injects_errors_into_the_faulty_method() {
+        let faults = Faults::new();
+        faults.set(
+            "search",
+            Fault {
+                error_fraction: 1.0,
+                error_kind: io::ErrorKind::ConnectionRefused,
+                ..Fault::default()
+            },
+        );
+        let handler = inject_faults(
+            faults.clone(),
+            |req: &&'static str| *req,
+            |_, req| future::ready(Ok::<_, io::Error>(req)),
+        );
+
+        let e = block_on(handler.clone()(crate::context::current(), "search")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(e.get_ref().unwrap().downcast_ref::<ServerError>().is_some());
+        let response = block_on(handler.clone()(crate::context::current(), "lookup"));
+        assert_eq!(response.unwrap(), "lookup");
+
+        faults.clear("search");
+        let response = block_on(handler(crate::context::current(), "search"));
+        assert_eq!(response.unwrap(), "search");
+    }
+}
@@ -5,9 +5,13 @@
 // https://opensource.org/licenses/MIT.
 
 use crate::{
-    server::{Channel, Config},
+    server::{
+        admission::{AdaptiveConcurrency, Admission, Both, WarmUp},
+        scheduling::Scheduler,
+        Channel, Config,
+    },
     util::Compact,
-    ClientMessage, PollIo, Response, Transport,
+    ClientMessage, PollIo, ServerMessage, Transport,
 };
 use fnv::FnvHashMap;
 use futures::{
@@ -21,26 +25,34 @@ use log::{debug, error, info, trace, warn};
 use pin_utils::unsafe_pinned;
 use std::{
     collections::hash_map::Entry,
-    io,
+    fmt, io,
     marker::PhantomData,
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     ops::Try,
     option::NoneError,
     pin::Pin,
+    str::FromStr,
+    sync::Arc,
 };
+use trace::ConnectionId;
 
 /// Drops connections under configurable conditions:
 ///
-/// 1. If the max number of connections is reached.
-/// 2. If the max number of connections for a single IP is reached.
+/// 1. If the client's IP is denied, or not allowed.
+/// 2. If the max number of connections is reached.
+/// 3. If the max number of connections for a single IP is reached.
 #[derive(Debug)]
 pub struct ConnectionFilter<S, Req, Resp> {
     listener: Fuse<S>,
-    closed_connections: mpsc::UnboundedSender<SocketAddr>,
-    closed_connections_rx: mpsc::UnboundedReceiver<SocketAddr>,
+    closed_connections: mpsc::Sender<SocketAddr>,
+    closed_connections_rx: mpsc::Receiver<SocketAddr>,
     config: Config,
     connections_per_ip: FnvHashMap<IpAddr, usize>,
     open_connections: usize,
+    /// Given to every channel, if the server adapts its concurrency.
+    admission: Option<Arc<dyn Admission>>,
+    /// Given to every channel, if the server limits the handlers running at once.
+    scheduler: Option<Scheduler>,
     ghost: PhantomData<(Req, Resp)>,
 }
 
@@ -73,16 +85,32 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
     unsafe_pinned!(open_connections: usize);
     unsafe_pinned!(config: Config);
     unsafe_pinned!(connections_per_ip: FnvHashMap<IpAddr, usize>);
-    unsafe_pinned!(closed_connections_rx: mpsc::UnboundedReceiver<SocketAddr>);
+    unsafe_pinned!(closed_connections_rx: mpsc::Receiver<SocketAddr>);
     unsafe_pinned!(listener: Fuse<S>);
 
     /// Sheds new connections to stay under configured limits.
     pub fn filter<C>(listener: S, config: Config) -> Self
     where
         S: Stream<Item = Result<C, io::Error>>,
-        C: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+        C: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     {
-        let (closed_connections, closed_connections_rx) = mpsc::unbounded();
+        // Every channel sends on its own clone of the sender, once, which a bounded channel always
+        // has a slot for. Connections are counted open until their message is received, so at
+        // most `max_connections` messages are ever queued.
+        let (closed_connections, closed_connections_rx) = mpsc::channel(0);
+        let scheduler = config.max_concurrent_handlers.map(|max_running| {
+            Scheduler::new(max_running, config.max_waiting_handlers, config.scheduling)
+        });
+        let admission: Option<Arc<dyn Admission>> =
+            match (config.warm_up, config.adaptive_concurrency) {
+                (Some(period), true) => Some(Arc::new(Both(
+                    WarmUp::new(period),
+                    AdaptiveConcurrency::default(),
+                ))),
+                (Some(period), false) => Some(Arc::new(WarmUp::new(period))),
+                (None, true) => Some(Arc::new(AdaptiveConcurrency::default())),
+                (None, false) => None,
+            };
 
         ConnectionFilter {
             listener: listener.fuse(),
@@ -91,13 +119,15 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
             config,
             connections_per_ip: FnvHashMap::default(),
             open_connections: 0,
+            admission,
+            scheduler,
             ghost: PhantomData,
         }
     }
 
     fn handle_new_connection<C>(self: &mut Pin<&mut Self>, stream: C) -> NewConnection<Req, Resp, C>
     where
-        C: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+        C: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     {
         let peer = match stream.peer_addr() {
             Ok(peer) => peer,
@@ -107,6 +137,14 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
             }
         };
 
+        if !is_ip_allowed(&self.config, &peer.ip()) {
+            info!(
+                "[{}] Shedding connection because the IP is not allowed.",
+                peer
+            );
+            return NewConnection::Filtered;
+        }
+
         let open_connections = *self.as_mut().open_connections();
         if open_connections >= self.as_mut().config().max_connections {
             warn!(
@@ -123,19 +161,33 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
         let open_connections_for_ip = self.increment_connections_for_ip(&peer)?;
         *self.as_mut().open_connections() += 1;
 
+        let connection_id = ConnectionId::random(&mut rand::thread_rng());
         debug!(
-            "[{}] Opening channel ({}/{} connections for IP, {} total).",
+            "[{}] Opening channel for connection {} ({}/{} connections for IP, {} total).",
             peer,
+            connection_id,
             open_connections_for_ip,
             config.max_connections_per_ip,
             self.as_mut().open_connections(),
         );
 
+        let identity = stream.peer_identity().map(Arc::new);
+        let session = stream.session_token().map(Arc::new);
+        let credentials = stream.peer_credentials();
         NewConnection::Accepted(Channel {
             client_addr: peer,
+            connection_id,
+            identity,
+            session,
+            credentials,
             closed_connections: self.closed_connections.clone(),
             transport: stream.fuse(),
+            shutdown: Arc::default(),
             config,
+            response_cost: None,
+            admission: self.admission.clone(),
+            scheduler: self.scheduler.clone(),
+            call_names: None,
             ghost: PhantomData,
         })
     }
@@ -203,7 +255,7 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
     ) -> PollIo<NewConnection<Req, Resp, C>>
     where
         S: Stream<Item = Result<C, io::Error>>,
-        C: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+        C: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     {
         match ready!(self.as_mut().listener().poll_next_unpin(cx)?) {
             Some(codec) => Poll::Ready(Some(Ok(self.handle_new_connection(codec)))),
@@ -225,10 +277,165 @@ impl<S, Req, Resp> ConnectionFilter<S, Req, Resp> {
     }
 }
 
+fn is_ip_allowed(config: &Config, ip: &IpAddr) -> bool {
+    allows(&config.allowed_ips, &config.denied_ips, ip)
+}
+
+fn allows(allowed: &[Cidr], denied: &[Cidr], ip: &IpAddr) -> bool {
+    if denied.iter().any(|cidr| cidr.contains(ip)) {
+        return false;
+    }
+    allowed.is_empty() || allowed.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// The IP ranges that clients may connect from, as configured by
+/// [`allowed_ips`](Config::allowed_ips) and [`denied_ips`](Config::denied_ips).
+///
+/// The server checks a connection's IP when the listener yields its transport, which, for
+/// transports that handshake, is only after the handshake. Transports can check the filter as
+/// soon as they accept a connection instead, e.g. with bincode-transport's
+/// `Incoming::with_ip_filter`, so that clients that aren't allowed can't make the server handshake
+/// with them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    allowed: Vec<Cidr>,
+    denied: Vec<Cidr>,
+}
+
+impl IpFilter {
+    /// Returns a filter that allows only IPs in one of the `allowed` ranges, or any IP if there
+    /// are none, except IPs in one of the `denied` ranges.
+    pub fn new(allowed: Vec<Cidr>, denied: Vec<Cidr>) -> Self {
+        IpFilter { allowed, denied }
+    }
+
+    /// Returns true if clients can connect from `ip`.
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        allows(&self.allowed, &self.denied, ip)
+    }
+}
+
+impl<'a> From<&'a Config> for IpFilter {
+    fn from(config: &'a Config) -> Self {
+        IpFilter::new(config.allowed_ips.clone(), config.denied_ips.clone())
+    }
+}
+
+/// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or `fe80::/10`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns the range of addresses sharing the first `prefix_len` bits of `addr`.
+    ///
+    /// Fails if `prefix_len` is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> io::Result<Self> {
+        let max_prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Prefix length {} is longer than the address {}.",
+                    prefix_len, addr
+                ),
+            ));
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+
+    /// Returns true if `ip` is in this range. An IPv4-mapped IPv6 address, e.g. `::ffff:10.0.0.1`,
+    /// as a dual-stack listener reports IPv4 peers, is in the IPv4 ranges its IPv4 address is in.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                let mask = u32::max_value()
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(addr) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                let mask = u128::max_value()
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(addr) & mask == u128::from(*ip) & mask
+            }
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match to_ipv4_mapped(ip) {
+                Some(ip) => self.contains(&IpAddr::V4(ip)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+/// Returns the IPv4 address that `ip` maps, if it's an IPv4-mapped address (`::ffff:a.b.c.d`).
+/// Unlike `Ipv6Addr::to_ipv4`, doesn't take IPv4-compatible addresses, like `::1`, for IPv4.
+fn to_ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    if segments[..5] == [0; 5] && segments[5] == 0xffff {
+        ip.to_ipv4()
+    } else {
+        None
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    /// Returns the range containing only `addr`.
+    fn from(addr: IpAddr) -> Self {
+        let prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Cidr { addr, prefix_len }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = io::Error;
+
+    /// Parses a range like `192.168.0.0/16`. An address without a prefix length is parsed as a
+    /// range containing only that address.
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = |e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid CIDR {:?}: {}", s, e),
+            )
+        };
+        let mut parts = s.splitn(2, '/');
+        let addr = parts
+            .next()
+            .unwrap_or_default()
+            .parse::<IpAddr>()
+            .map_err(|e| invalid(e.to_string()))?;
+        match parts.next() {
+            Some(prefix_len) => Cidr::new(
+                addr,
+                prefix_len
+                    .parse::<u8>()
+                    .map_err(|e| invalid(e.to_string()))?,
+            ),
+            None => Ok(Cidr::from(addr)),
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
 impl<S, Req, Resp, T> Stream for ConnectionFilter<S, Req, Resp>
 where
     S: Stream<Item = Result<T, io::Error>>,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
 {
     type Item = io::Result<Channel<Req, Resp, T>>;
 
@@ -264,3 +471,78 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{is_ip_allowed, Cidr, IpFilter};
+    use crate::server::Config;
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains(&ip("10.1.2.3")));
+        assert!(!cidr.contains(&ip("10.2.0.0")));
+        assert!(!cidr.contains(&ip("::1")));
+
+        let cidr: Cidr = "fe80::/10".parse().unwrap();
+        assert!(cidr.contains(&ip("fe80::1")));
+        assert!(!cidr.contains(&ip("fec0::1")));
+
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&ip("1.2.3.4")));
+
+        let single: Cidr = "1.2.3.4".parse().unwrap();
+        assert!(single.contains(&ip("1.2.3.4")));
+        assert!(!single.contains(&ip("1.2.3.5")));
+    }
+
+    #[test]
+    fn cidr_rejects_invalid() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+        assert!("not an ip/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let mut config = Config::default();
+        assert!(is_ip_allowed(&config, &ip("10.0.0.1")));
+
+        config.allowed_ips = vec!["10.0.0.0/8".parse().unwrap()];
+        config.denied_ips = vec!["10.0.0.0/24".parse().unwrap()];
+        assert!(is_ip_allowed(&config, &ip("10.1.0.1")));
+        assert!(!is_ip_allowed(&config, &ip("10.0.0.1")));
+        assert!(!is_ip_allowed(&config, &ip("192.168.0.1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_match_ipv4_ranges() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&ip("::ffff:10.0.0.1")));
+        assert!(!cidr.contains(&ip("::ffff:192.168.0.1")));
+        // IPv4-compatible addresses aren't IPv4 peers.
+        assert!(!cidr.contains(&ip("::10.0.0.1")));
+
+        let mut config = Config::default();
+        config.denied_ips = vec!["10.0.0.0/24".parse().unwrap()];
+        assert!(!is_ip_allowed(&config, &ip("::ffff:10.0.0.1")));
+        assert!(is_ip_allowed(&config, &ip("::ffff:10.0.1.1")));
+    }
+
+    #[test]
+    fn ip_filter_matches_config() {
+        let mut config = Config::default();
+        config.allowed_ips = vec!["10.0.0.0/8".parse().unwrap()];
+        config.denied_ips = vec!["10.0.0.0/24".parse().unwrap()];
+        let filter = IpFilter::from(&config);
+        for addr in &["10.1.0.1", "10.0.0.1", "192.168.0.1"] {
+            assert_eq!(filter.allows(&ip(addr)), is_ip_allowed(&config, &ip(addr)));
+        }
+        assert!(IpFilter::default().allows(&ip("192.168.0.1")));
+    }
+}
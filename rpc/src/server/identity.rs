@@ -0,0 +1,132 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! The identity a client authenticated as, for transports that authenticate clients when the
+//! connection is established.
+//!
+//! The server makes the identity of a request's connection [current](current) while polling the
+//! request's handler, e.g. so that a [quota](crate::server::quota) can charge it. So too the
+//! connection's [session](session), for transports that issue sessions, so that handlers can keep
+//! per-client state that outlives a connection, under the session's token, and the
+//! [connection](connection) itself, e.g. so that [topics](crate::server::pubsub) can limit the
+//! subscriptions of each connection, and the [credentials](credentials) of the client's process,
+//! for transports over local sockets that report them.
+
+use crate::PeerCredentials;
+use futures::{
+    task::{Context, Poll},
+    Future,
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    cell::{Cell, RefCell},
+    pin::Pin,
+    sync::Arc,
+};
+use trace::ConnectionId;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<String>>> = RefCell::new(None);
+    static SESSION: RefCell<Option<Arc<String>>> = RefCell::new(None);
+    static CONNECTION: Cell<Option<ConnectionId>> = Cell::new(None);
+    static CREDENTIALS: Cell<Option<PeerCredentials>> = Cell::new(None);
+}
+
+/// Returns the identity the client of the current request authenticated as, if its transport
+/// authenticated it.
+pub fn current() -> Option<Arc<String>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Returns the token of the session the connection of the current request belongs to, if its
+/// transport issues sessions. A client that reconnects keeps its token.
+pub fn session() -> Option<Arc<String>> {
+    SESSION.with(|session| session.borrow().clone())
+}
+
+/// Returns the ID of the connection the current request was received on, or `None` outside of a
+/// request's handler.
+pub fn connection() -> Option<ConnectionId> {
+    CONNECTION.with(Cell::get)
+}
+
+/// Returns the credentials of the process that made the current request, if its transport is over
+/// a local socket that reports them, e.g. to authorize requests by the client's user.
+pub fn credentials() -> Option<PeerCredentials> {
+    CREDENTIALS.with(Cell::get)
+}
+
+/// Returns a future that makes `connection`, with its `identity`, `session` and peer
+/// `credentials`, current while polling `future`.
+pub(crate) fn scope<F: Future>(
+    connection: ConnectionId,
+    identity: Option<Arc<String>>,
+    session: Option<Arc<String>>,
+    credentials: Option<PeerCredentials>,
+    future: F,
+) -> Scoped<F> {
+    Scoped {
+        connection,
+        identity,
+        session,
+        credentials,
+        future,
+    }
+}
+
+/// A future that makes a connection, with its identity, session and peer credentials, current
+/// while it is polled.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub(crate) struct Scoped<F> {
+    connection: ConnectionId,
+    identity: Option<Arc<String>>,
+    session: Option<Arc<String>>,
+    credentials: Option<PeerCredentials>,
+    future: F,
+}
+
+impl<F> Scoped<F> {
+    unsafe_unpinned!(identity: Option<Arc<String>>);
+    unsafe_unpinned!(session: Option<Arc<String>>);
+    unsafe_pinned!(future: F);
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        /// Restores the previous identity, session, connection and credentials, even if the
+        /// future panics.
+        struct Reset(
+            Option<Arc<String>>,
+            Option<Arc<String>>,
+            Option<ConnectionId>,
+            Option<PeerCredentials>,
+        );
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+                SESSION.with(|session| *session.borrow_mut() = self.1.take());
+                CONNECTION.with(|connection| connection.set(self.2));
+                CREDENTIALS.with(|credentials| credentials.set(self.3));
+            }
+        }
+
+        let connection = self.connection;
+        let credentials = self.credentials;
+        let identity = self.as_mut().identity().clone();
+        let session = self.as_mut().session().clone();
+        let _reset = Reset(
+            CURRENT.with(|current| current.replace(identity)),
+            SESSION.with(|current| current.replace(session)),
+            CONNECTION.with(|current| current.replace(Some(connection))),
+            CREDENTIALS.with(|current| current.replace(credentials)),
+        );
+        self.as_mut().future().poll(cx)
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Per-method limits on the size of request payloads.
+//!
+//! Limits are enforced by transports, on the length of a request's frame, before the request is
+//! deserialized, so that a request too large for the method it calls costs the server no more
+//! than reading it. The transport responds with a
+//! [`PayloadTooLarge`](crate::ErrorCode::PayloadTooLarge) error by returning an
+//! [`UndecodableRequest`](crate::UndecodableRequest) with that code. For example, the bincode
+//! transport's codec takes limits along with its max frame length, which bounds every frame; the
+//! limits let most methods be held to a much smaller size than the largest one needs.
+
+use fnv::FnvHashMap;
+
+/// The maximum request payload size of each method.
+#[derive(Clone, Debug)]
+pub struct PayloadLimits {
+    default_max_bytes: u64,
+    methods: FnvHashMap<&'static str, u64>,
+}
+
+impl Default for PayloadLimits {
+    fn default() -> Self {
+        PayloadLimits::new(64 * 1024)
+    }
+}
+
+impl PayloadLimits {
+    /// Returns limits that allow payloads of up to `default_max_bytes` for every method.
+    pub fn new(default_max_bytes: u64) -> Self {
+        PayloadLimits {
+            default_max_bytes,
+            methods: FnvHashMap::default(),
+        }
+    }
+
+    /// Allows payloads of up to `max_bytes` for `method`, overriding the default limit.
+    pub fn with_method(mut self, method: &'static str, max_bytes: u64) -> Self {
+        self.methods.insert(method, max_bytes);
+        self
+    }
+
+    /// Returns the maximum payload size allowed for `method`.
+    pub fn max_bytes(&self, method: &str) -> u64 {
+        *self.methods.get(method).unwrap_or(&self.default_max_bytes)
+    }
+
+    /// Returns the maximum payload size allowed for methods without a limit of their own.
+    pub fn default_max_bytes(&self) -> u64 {
+        self.default_max_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PayloadLimits;
+
+    #[test]
+    fn per_method_limit_overrides_default() {
+        let limits = PayloadLimits::new(64).with_method("upload_chunk", 4096);
+        assert_eq!(limits.max_bytes("upload_chunk"), 4096);
+        assert_eq!(limits.max_bytes("hello"), 64);
+        assert_eq!(limits.default_max_bytes(), 64);
+    }
+}
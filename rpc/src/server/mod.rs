@@ -7,34 +7,68 @@
 //! Provides a server that concurrently handles many connections sending multiplexed requests.
 
 use crate::{
-    context, util::deadline_compat, util::AsDuration, util::Compact, ClientMessage,
-    ClientMessageKind, PollIo, Request, Response, ServerError, Transport,
+    context, metadata,
+    metrics::{Metrics, Recorder},
+    util::{deadline_compat, AsDuration, Compact},
+    ClientMessage, ClientMessageKind, CloseReason, ErrorCode, PeerCredentials, PollIo, Request,
+    Response, ServerError, ServerMessage, Transport, UndecodableRequest,
 };
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures::{
-    channel::mpsc,
-    future::{abortable, AbortHandle},
+    channel::{mpsc, oneshot},
+    compat::{Compat01As03, Future01CompatExt},
+    future::{self, abortable, AbortHandle},
     prelude::*,
     ready,
     stream::Fuse,
-    task::{Context, Poll},
+    task::{Context, Poll, Waker},
     try_ready,
 };
 use humantime::format_rfc3339;
 use log::{debug, error, info, trace, warn};
 use pin_utils::{unsafe_pinned, unsafe_unpinned};
 use std::{
+    any::Any,
+    collections::VecDeque,
     error::Error as StdError,
-    io,
+    fmt, io,
     marker::PhantomData,
     net::SocketAddr,
+    panic::AssertUnwindSafe,
     pin::Pin,
-    time::{Instant, SystemTime},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant, SystemTime},
 };
-use tokio_timer::timeout;
-use trace::{self, TraceId};
+use tokio_timer::{timeout, Delay};
+use trace::{self, ConnectionId, TraceId};
 
+use self::{
+    admission::{Admission, Decision, Load, Tracked},
+    cancellation::Cancelable,
+    scheduling::{Scheduled, Scheduler, Scheduling},
+    streaming::{Body, Sender},
+};
+
+pub mod admission;
+pub mod bandwidth;
+pub mod batch;
+pub mod caching;
+pub mod cancellation;
+pub mod dedup;
+pub mod faults;
 mod filter;
+pub mod identity;
+pub mod limits;
+pub mod partition;
+pub mod pubsub;
+pub mod quota;
+pub mod scheduling;
+mod shutdown;
+pub mod slo;
+pub mod streaming;
+
+pub use self::filter::{Cidr, IpFilter};
+pub use self::shutdown::ShutdownHandle;
 
 /// Manages clients, serving multiplexed requests over each connection.
 #[derive(Debug)]
@@ -62,12 +96,60 @@ pub struct Config {
     pub max_connections_per_ip: usize,
     /// The maximum number of requests that can be in flight for each client. When a client is at
     /// the in-flight request limit, existing requests are fulfilled and new requests are rejected.
-    /// Rejected requests are sent a response error.
+    /// What happens to rejected requests is controlled by `overload_policy`.
     pub max_in_flight_requests_per_connection: usize,
+    /// What a connection does with requests received while at the in-flight request limit.
+    pub overload_policy: OverloadPolicy,
+    /// Whether a request the transport couldn't decode, but could tell the ID of, is failed on its
+    /// own, with the connection kept open. If false, the connection is closed, as it is for any
+    /// other read error.
+    pub forgive_undecodable_requests: bool,
+    /// Whether to shed requests beyond a concurrency limit that adapts to observed latency,
+    /// shared by all connections. See [`AdaptiveConcurrency`](admission::AdaptiveConcurrency).
+    pub adaptive_concurrency: bool,
+    /// If set, the period after the server starts over which it goes from shedding all requests
+    /// to shedding none. See [`WarmUp`](admission::WarmUp).
+    pub warm_up: Option<Duration>,
+    /// If set, the most request handlers that run at once, across all connections. Requests over
+    /// the limit wait, still counting against their connection's in-flight request limit, until
+    /// `scheduling` picks them to run.
+    pub max_concurrent_handlers: Option<usize>,
+    /// If set, the most requests that wait to run when at `max_concurrent_handlers`, across all
+    /// connections. Requests that would wait behind as many are rejected right away with an
+    /// [overloaded](ErrorCode::Overloaded) error, so that a burst can't queue up unbounded work;
+    /// `Some(0)` rejects every request over the limit. Together with `max_concurrent_handlers`,
+    /// bounds the requests in flight across the server.
+    pub max_waiting_handlers: Option<usize>,
+    /// Which waiting request runs next when at `max_concurrent_handlers`.
+    pub scheduling: Scheduling,
+    /// The order in which each connection sends responses.
+    pub response_order: ResponseOrder,
+    /// If set, throttled errors ask the client to wait this long before sending more requests.
+    pub throttled_retry_after: Option<Duration>,
     /// The number of responses per client that can be buffered server-side before being sent.
     /// `pending_response_buffer` controls the buffer size of the channel that a server's
-    /// response tasks use to send responses to the client handler task.
+    /// response tasks use to send responses to the client handler task. The items of
+    /// [streamed](streaming) responses are buffered in the same channel.
     pub pending_response_buffer: usize,
+    /// The number of items of each [streamed request body](streaming::body) that can be buffered
+    /// until the request's handler reads them. While a body's buffer is full, nothing more is
+    /// read off its connection.
+    pub body_item_buffer: usize,
+    /// If non-empty, only clients with an IP address in one of these ranges can connect. Checked
+    /// when the listener yields a connection's transport, before the server reads any request
+    /// off of it. Transports that handshake before yielding one should be given the
+    /// [`IpFilter`] of the config too, to check at accept time, before the handshake.
+    pub allowed_ips: Vec<Cidr>,
+    /// Clients with an IP address in one of these ranges are disconnected as soon as their
+    /// connection is accepted. Takes precedence over `allowed_ips`.
+    pub denied_ips: Vec<Cidr>,
+    /// If set, reports every request, including those throttled or rejected, labeled by the
+    /// [call name](Channel::with_call_names) of its method, or by `unknown` if the channel has no
+    /// call names.
+    pub metrics: Option<Metrics>,
+    /// If set, connections with no requests in flight for this long are closed, telling the
+    /// client the connection timed out.
+    pub idle_timeout: Option<Duration>,
 }
 
 impl Default for Config {
@@ -76,11 +158,53 @@ impl Default for Config {
             max_connections: 1_000_000,
             max_connections_per_ip: 1_000,
             max_in_flight_requests_per_connection: 1_000,
+            overload_policy: OverloadPolicy::Shed,
+            forgive_undecodable_requests: true,
+            adaptive_concurrency: false,
+            warm_up: None,
+            max_concurrent_handlers: None,
+            max_waiting_handlers: None,
+            scheduling: Scheduling::RoundRobin,
+            response_order: ResponseOrder::Multiplexed,
+            throttled_retry_after: None,
             pending_response_buffer: 100,
+            body_item_buffer: 100,
+            allowed_ips: vec![],
+            denied_ips: vec![],
+            metrics: None,
+            idle_timeout: None,
         }
     }
 }
 
+/// What a connection does with a request received while it is at its in-flight request limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Respond to the request with a throttled, [overloaded](ErrorCode::Overloaded) error.
+    /// Writing the error can still be back-pressured by the transport, in which case no more
+    /// requests are read until it can be written.
+    Shed,
+    /// Close the connection, telling the client it was [overloaded](CloseReason::Overloaded).
+    /// Nothing is buffered for the client beyond the responses to its in-flight requests, so
+    /// memory use per connection is capped even when the client ignores throttled errors.
+    Disconnect,
+}
+
+/// The order in which a connection sends responses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseOrder {
+    /// Send each response as soon as it's ready, regardless of the order the requests arrived in.
+    /// If the channel has a [response cost](Channel::with_response_cost), responses that are ready
+    /// at the same time are sent cheapest first.
+    Multiplexed,
+    /// Send responses in the order their requests arrived in. A response that's ready before the
+    /// responses to earlier requests is held until they're sent. While responses are held, they
+    /// count against the in-flight request limit, and when at the limit, no more requests are read
+    /// rather than being handled according to `overload_policy`. The items of
+    /// [streamed](streaming) responses aren't held; only the final responses are in order.
+    Pipelined,
+}
+
 /// Returns a new server with configuration specified `config`.
 pub fn new<Req, Resp>(config: Config) -> Server<Req, Resp> {
     Server {
@@ -104,7 +228,7 @@ impl<Req, Resp> Server<Req, Resp> {
         Req: Send,
         Resp: Send,
         S: Stream<Item = io::Result<T>>,
-        T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+        T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     {
         self::filter::ConnectionFilter::filter(listener, self.config.clone())
     }
@@ -115,11 +239,86 @@ impl<Req, Resp> Server<Req, Resp> {
 pub struct Running<S, F> {
     incoming: S,
     request_handler: F,
+    shutdown: ShutdownHandle,
 }
 
 impl<S, F> Running<S, F> {
     unsafe_pinned!(incoming: S);
     unsafe_unpinned!(request_handler: F);
+
+    /// Returns a handle that shuts the server down gracefully. The server stops accepting
+    /// connections, and resolves, as soon as shutdown starts.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Spawns the server, returning a handle that resolves once the server exits, for
+    /// applications that run the server alongside other work rather than awaiting it.
+    pub fn spawn<T, Req, Resp, Fut>(self) -> io::Result<Serving>
+    where
+        S: Stream<Item = io::Result<Channel<Req, Resp, T>>> + Send + 'static,
+        Req: Send + 'static,
+        Resp: Send + 'static,
+        T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send + 'static,
+        F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+        Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+        let (exit_tx, exit) = oneshot::channel();
+        let running = {
+            let shutdown = shutdown.clone();
+            async move {
+                await!(self);
+                let _ = await!(shutdown.connections_closed());
+                let _ = exit_tx.send(());
+            }
+        };
+        crate::spawn(running).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Could not spawn server task. Is shutdown: {}",
+                    e.is_shutdown()
+                ),
+            )
+        })?;
+        Ok(Serving { shutdown, exit })
+    }
+}
+
+/// A handle to a [spawned](Running::spawn) server. Resolves once the server has stopped accepting
+/// connections, because it was shut down or its listener ended, and every open connection has
+/// closed.
+///
+/// Dropping the handle leaves the server running.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Serving {
+    shutdown: ShutdownHandle,
+    exit: oneshot::Receiver<()>,
+}
+
+impl Serving {
+    /// Returns a handle that shuts the server down gracefully.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Shuts the server down gracefully, as with [`ShutdownHandle::shutdown`], resolving once
+    /// every connection is closed.
+    pub fn shutdown(&self, grace_period: Duration) -> impl Future<Output = ()> {
+        self.shutdown.clone().shutdown(grace_period)
+    }
+}
+
+impl Future for Serving {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Canceled if the server task was dropped, e.g. because the executor shut down.
+        let _ = ready!(self.exit.poll_unpin(cx));
+        Poll::Ready(())
+    }
 }
 
 impl<S, T, Req, Resp, F, Fut> Future for Running<S, F>
@@ -127,21 +326,34 @@ where
     S: Sized + Stream<Item = io::Result<Channel<Req, Resp, T>>>,
     Req: Send + 'static,
     Resp: Send + 'static,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send + 'static,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send + 'static,
     F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
     Fut: Future<Output = io::Result<Resp>> + Send + 'static,
 {
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
-        while let Some(channel) = ready!(self.as_mut().incoming().poll_next(cx)) {
+        loop {
+            if self.shutdown.is_shutting_down(cx.waker()) {
+                info!("Server stopped accepting connections.");
+                return Poll::Ready(());
+            }
+            let channel = match ready!(self.as_mut().incoming().poll_next(cx)) {
+                Some(channel) => channel,
+                None => break,
+            };
             match channel {
                 Ok(channel) => {
                     let peer = channel.client_addr;
-                    if let Err(e) =
-                        crate::spawn(channel.respond_with(self.as_mut().request_handler().clone()))
-                    {
+                    let connection_id = channel.connection_id;
+                    let shutdown = self.shutdown.clone();
+                    shutdown.opened(channel.handle());
+                    let connection = channel
+                        .respond_with(self.as_mut().request_handler().clone())
+                        .map(move |()| shutdown.closed_connection(&connection_id));
+                    if let Err(e) = crate::spawn(connection) {
                         warn!("[{}] Failed to spawn connection handler: {:?}", peer, e);
+                        self.shutdown.closed_connection(&connection_id);
                     }
                 }
                 Err(e) => {
@@ -160,7 +372,7 @@ where
     Self: Sized + Stream<Item = io::Result<Channel<Req, Resp, T>>>,
     Req: Send,
     Resp: Send,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
 {
     /// Responds to all requests with `request_handler`.
     fn respond_with<F, Fut>(self, request_handler: F) -> Running<Self, F>
@@ -171,6 +383,7 @@ where
         Running {
             incoming: self,
             request_handler,
+            shutdown: ShutdownHandle::default(),
         }
     }
 }
@@ -180,7 +393,7 @@ where
     S: Sized + Stream<Item = io::Result<Channel<Req, Resp, T>>>,
     Req: Send,
     Resp: Send,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
 {
 }
 
@@ -191,27 +404,67 @@ pub struct Channel<Req, Resp, T> {
     /// Writes responses to the wire and reads requests off the wire.
     transport: Fuse<T>,
     /// Signals the connection is closed when `Channel` is dropped.
-    closed_connections: mpsc::UnboundedSender<SocketAddr>,
+    closed_connections: mpsc::Sender<SocketAddr>,
+    /// Where [`ConnectionHandle`]s ask the channel to shut down.
+    shutdown: Arc<ShutdownSignal>,
     /// Channel limits to prevent unlimited resource usage.
     config: Config,
     /// The address of the server connected to.
     client_addr: SocketAddr,
+    /// Identifies the connection in logs.
+    connection_id: ConnectionId,
+    /// The identity the client authenticated as, if the transport authenticates clients.
+    identity: Option<Arc<String>>,
+    /// The token of the session the connection belongs to, if the transport issues sessions.
+    session: Option<Arc<String>>,
+    /// The credentials of the client's process, if the transport is over a local socket.
+    credentials: Option<PeerCredentials>,
+    /// Orders multiplexed responses that are ready at the same time.
+    response_cost: Option<ResponseCost<Resp>>,
+    /// Decides which requests are handled.
+    admission: Option<Arc<dyn Admission>>,
+    /// Limits the handlers running at once across connections.
+    scheduler: Option<Scheduler>,
+    /// Names the method of each request, for metrics.
+    call_names: Option<CallNames<Req>>,
     /// Types the request and response.
     ghost: PhantomData<(Req, Resp)>,
 }
 
+struct ResponseCost<Resp>(fn(&Response<Resp>) -> u64);
+
+impl<Resp> Clone for ResponseCost<Resp> {
+    fn clone(&self) -> Self {
+        ResponseCost(self.0)
+    }
+}
+
+impl<Resp> Copy for ResponseCost<Resp> {}
+
+impl<Resp> fmt::Debug for ResponseCost<Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ResponseCost")
+    }
+}
+
+struct CallNames<Req>(fn(&Req) -> &'static str);
+
+impl<Req> fmt::Debug for CallNames<Req> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CallNames")
+    }
+}
+
 impl<Req, Resp, T> Drop for Channel<Req, Resp, T> {
     fn drop(&mut self) {
-        trace!("[{}] Closing channel.", self.client_addr);
-
-        // Even in a bounded channel, each connection would have a guaranteed slot, so using
-        // an unbounded sender is actually no different. And, the bound is on the maximum number
-        // of open connections.
-        if self
-            .closed_connections
-            .unbounded_send(self.client_addr)
-            .is_err()
-        {
+        trace!(
+            "[{}] Closing channel for connection {}.",
+            self.client_addr,
+            self.connection_id
+        );
+
+        // Each connection's sender has a guaranteed slot in the channel, and sends only this.
+        if self.closed_connections.try_send(self.client_addr).is_err() {
             warn!(
                 "[{}] Failed to send closed connection message.",
                 self.client_addr
@@ -224,14 +477,125 @@ impl<Req, Resp, T> Channel<Req, Resp, T> {
     unsafe_pinned!(transport: Fuse<T>);
 }
 
+/// A handle to a single connection that can drain or close it, e.g. to kick a misbehaving client
+/// without disturbing the rest of the server. Obtained from [`Channel::handle`].
+#[derive(Clone, Debug)]
+pub struct ConnectionHandle {
+    client_addr: SocketAddr,
+    connection_id: ConnectionId,
+    shutdown: Weak<ShutdownSignal>,
+}
+
+/// A message for the client from the task handling one of its requests.
+#[derive(Debug)]
+pub(crate) enum Reply<Resp> {
+    /// One of the items of a [streamed](streaming) response.
+    Item { request_id: u64, item: Resp },
+    /// The final response to a request.
+    Response(Response<Resp>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Shutdown {
+    Drain,
+    Close(CloseReason),
+}
+
+/// The latest request from a [`ConnectionHandle`] to shut its connection down. Holds one request
+/// at most, however many times handles ask, rather than queuing them: a close supersedes a drain,
+/// and the first close's reason is the one the client is told.
+#[derive(Debug, Default)]
+struct ShutdownSignal(Mutex<ShutdownState>);
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requested: Option<Shutdown>,
+    waker: Option<Waker>,
+}
+
+impl ShutdownSignal {
+    fn request(&self, shutdown: Shutdown) {
+        let mut state = self.0.lock().unwrap();
+        if let Some(Shutdown::Close(_)) = state.requested {
+            return;
+        }
+        state.requested = Some(shutdown);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Takes the request to shut down, if there is one, and wakes the current task on the next.
+    fn poll_requested(&self, cx: &mut Context<'_>) -> Option<Shutdown> {
+        let mut state = self.0.lock().unwrap();
+        state.waker = Some(cx.waker().clone());
+        state.requested.take()
+    }
+}
+
+impl ConnectionHandle {
+    /// Returns the address of the client connected to the channel.
+    pub fn client_addr(&self) -> &SocketAddr {
+        &self.client_addr
+    }
+
+    /// Returns the ID the server gave the connection.
+    pub fn connection_id(&self) -> &ConnectionId {
+        &self.connection_id
+    }
+
+    /// Stops reading requests off the connection. The connection closes once the requests already
+    /// in flight are responded to, telling the client the server is
+    /// [shutting down](CloseReason::Shutdown).
+    pub fn drain(&self) {
+        self.shutdown(Shutdown::Drain);
+    }
+
+    /// Closes the connection right away, canceling the requests in flight. The client is told it
+    /// was [kicked](CloseReason::Kicked).
+    pub fn close(&self) {
+        self.close_with(CloseReason::Kicked);
+    }
+
+    /// Closes the connection right away, telling the client why.
+    pub(crate) fn close_with(&self, reason: CloseReason) {
+        self.shutdown(Shutdown::Close(reason));
+    }
+
+    fn shutdown(&self, shutdown: Shutdown) {
+        match self.shutdown.upgrade() {
+            Some(signal) => signal.request(shutdown),
+            None => trace!("[{}] Connection already closed.", self.client_addr),
+        }
+    }
+}
+
 impl<Req, Resp, T> Channel<Req, Resp, T>
 where
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     Req: Send,
     Resp: Send,
 {
     pub(crate) fn start_send(mut self: Pin<&mut Self>, response: Response<Resp>) -> io::Result<()> {
-        self.as_mut().transport().start_send(response)
+        self.as_mut()
+            .transport()
+            .start_send(ServerMessage::Response(response))
+    }
+
+    fn start_send_item(mut self: Pin<&mut Self>, request_id: u64, item: Resp) -> io::Result<()> {
+        self.as_mut()
+            .transport()
+            .start_send(ServerMessage::StreamItem { request_id, item })
+    }
+
+    fn start_drain(mut self: Pin<&mut Self>) -> io::Result<()> {
+        self.as_mut().transport().start_send(ServerMessage::Drain)
+    }
+
+    fn start_close(mut self: Pin<&mut Self>, reason: CloseReason) -> io::Result<()> {
+        self.as_mut()
+            .transport()
+            .start_send(ServerMessage::Close { reason })
     }
 
     pub(crate) fn poll_ready(
@@ -260,6 +624,68 @@ where
         &self.client_addr
     }
 
+    /// Returns the ID the server gave the connection, which appears in the server's logs about it.
+    pub fn connection_id(&self) -> &ConnectionId {
+        &self.connection_id
+    }
+
+    /// Returns the identity the client authenticated as, if the transport authenticates clients.
+    pub fn peer_identity(&self) -> Option<&str> {
+        self.identity.as_ref().map(|identity| identity.as_str())
+    }
+
+    /// Returns the token of the session the connection belongs to, if the transport issues
+    /// sessions.
+    pub fn session_token(&self) -> Option<&str> {
+        self.session.as_ref().map(|session| session.as_str())
+    }
+
+    /// Returns the credentials of the client's process, if the transport is over a local socket
+    /// that reports them.
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.credentials
+    }
+
+    /// Returns the config for this channel, e.g. to check the order it sends responses in.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Sends multiplexed responses that are ready at the same time in order of `cost`, cheapest
+    /// first, e.g. by their size, so that small responses aren't stuck behind large ones. Ready
+    /// responses are taken in batches, so a costly response waits at most for the responses that
+    /// were ready along with it. Has no effect when responses are
+    /// [pipelined](ResponseOrder::Pipelined).
+    pub fn with_response_cost(mut self, cost: fn(&Response<Resp>) -> u64) -> Self {
+        self.response_cost = Some(ResponseCost(cost));
+        self
+    }
+
+    /// Asks `admission` whether to handle each request under the in-flight request limit, and
+    /// tells it how each handled request ended. The same policy can be given to every channel of
+    /// a server, to decide based on the load across all of them.
+    pub fn with_admission(mut self, admission: Arc<dyn Admission>) -> Self {
+        self.admission = Some(admission);
+        self
+    }
+
+    /// Names the method of each request with `call_names`, e.g. `Request::name` for services
+    /// defined with `tarpc::service!`, to label the [metrics](Config::metrics) of requests with.
+    pub fn with_call_names(mut self, call_names: fn(&Req) -> &'static str) -> Self {
+        self.call_names = Some(CallNames(call_names));
+        self
+    }
+
+    /// Returns a handle that can drain or close this connection after it's handed off to
+    /// [`respond_with`](Channel::respond_with).
+    pub fn handle(&self) -> ConnectionHandle {
+        ConnectionHandle {
+            client_addr: self.client_addr,
+            connection_id: self.connection_id,
+            shutdown: Arc::downgrade(&self.shutdown),
+        }
+    }
+
     /// Respond to requests coming over the channel with `f`. Returns a future that drives the
     /// responses and resolves when the connection is closed.
     pub fn respond_with<F, Fut>(self, f: F) -> impl Future<Output = ()>
@@ -272,6 +698,7 @@ where
         let (responses_tx, responses) = mpsc::channel(self.config.pending_response_buffer);
         let responses = responses.fuse();
         let peer = self.client_addr;
+        let connection_id = self.connection_id;
 
         ClientHandler {
             channel: self,
@@ -279,9 +706,24 @@ where
             pending_responses: responses,
             responses_tx,
             in_flight_requests: FnvHashMap::default(),
+            notifications: FnvHashSet::default(),
+            unsent_requests: VecDeque::new(),
+            held_responses: FnvHashMap::default(),
+            ready_responses: VecDeque::new(),
+            stream_items: VecDeque::new(),
+            bodies: FnvHashMap::default(),
+            body_item: None,
+            draining: false,
+            drain_sent: false,
+            closing: None,
+            close_sent: false,
+            idle: None,
         }
         .unwrap_or_else(move |e| {
-            info!("[{}] ClientHandler errored out: {}", peer, e);
+            info!(
+                "[{}] ClientHandler for connection {} errored out: {}",
+                peer, connection_id, e
+            );
         })
     }
 }
@@ -289,12 +731,41 @@ where
 #[derive(Debug)]
 struct ClientHandler<Req, Resp, T, F> {
     channel: Channel<Req, Resp, T>,
-    /// Responses waiting to be written to the wire.
-    pending_responses: Fuse<mpsc::Receiver<(context::Context, Response<Resp>)>>,
+    /// Responses, and items of streamed responses, waiting to be written to the wire.
+    pending_responses: Fuse<mpsc::Receiver<(context::Context, Reply<Resp>)>>,
     /// Handed out to request handlers to fan in responses.
-    responses_tx: mpsc::Sender<(context::Context, Response<Resp>)>,
+    responses_tx: mpsc::Sender<(context::Context, Reply<Resp>)>,
     /// Number of requests currently being responded to.
     in_flight_requests: FnvHashMap<u64, AbortHandle>,
+    /// The IDs of in-flight requests that are notifications, whose responses are dropped instead
+    /// of sent.
+    notifications: FnvHashSet<u64>,
+    /// When responses are pipelined, the IDs of requests whose responses are not yet sent, in the
+    /// order the requests arrived.
+    unsent_requests: VecDeque<u64>,
+    /// When responses are pipelined, responses waiting on the responses to earlier requests.
+    held_responses: FnvHashMap<u64, (context::Context, Response<Resp>)>,
+    /// When responses are multiplexed and have a cost, the batch of responses being sent, from
+    /// cheapest to costliest.
+    ready_responses: VecDeque<(context::Context, Response<Resp>)>,
+    /// When responses are pipelined or have a cost, the items of streamed responses taken from
+    /// `pending_responses` along with the responses, which are sent before any response.
+    stream_items: VecDeque<(context::Context, u64, Resp)>,
+    /// Hands the items of streamed request bodies to the requests' handlers, by request ID.
+    bodies: FnvHashMap<u64, mpsc::Sender<io::Result<Req>>>,
+    /// An item of a streamed request body read off the wire, waiting for room in the body.
+    body_item: Option<(u64, io::Result<Req>)>,
+    /// Whether the connection was asked to drain, in which case no more requests are read.
+    draining: bool,
+    /// Whether the client was told the connection is draining.
+    drain_sent: bool,
+    /// Set when the server decides to close the connection, which it does after telling the
+    /// client why.
+    closing: Option<CloseReason>,
+    /// Whether the client was told why the connection is closing.
+    close_sent: bool,
+    /// While nothing is in flight, fires after `config.idle_timeout`.
+    idle: Option<Compat01As03<Delay>>,
     /// Request handler.
     f: F,
 }
@@ -302,8 +773,20 @@ struct ClientHandler<Req, Resp, T, F> {
 impl<Req, Resp, T, F> ClientHandler<Req, Resp, T, F> {
     unsafe_pinned!(channel: Channel<Req, Resp, T>);
     unsafe_pinned!(in_flight_requests: FnvHashMap<u64, AbortHandle>);
-    unsafe_pinned!(pending_responses: Fuse<mpsc::Receiver<(context::Context, Response<Resp>)>>);
-    unsafe_pinned!(responses_tx: mpsc::Sender<(context::Context, Response<Resp>)>);
+    unsafe_pinned!(pending_responses: Fuse<mpsc::Receiver<(context::Context, Reply<Resp>)>>);
+    unsafe_pinned!(responses_tx: mpsc::Sender<(context::Context, Reply<Resp>)>);
+    unsafe_unpinned!(notifications: FnvHashSet<u64>);
+    unsafe_unpinned!(unsent_requests: VecDeque<u64>);
+    unsafe_unpinned!(held_responses: FnvHashMap<u64, (context::Context, Response<Resp>)>);
+    unsafe_unpinned!(ready_responses: VecDeque<(context::Context, Response<Resp>)>);
+    unsafe_unpinned!(stream_items: VecDeque<(context::Context, u64, Resp)>);
+    unsafe_unpinned!(bodies: FnvHashMap<u64, mpsc::Sender<io::Result<Req>>>);
+    unsafe_unpinned!(body_item: Option<(u64, io::Result<Req>)>);
+    unsafe_unpinned!(draining: bool);
+    unsafe_unpinned!(drain_sent: bool);
+    unsafe_unpinned!(closing: Option<CloseReason>);
+    unsafe_unpinned!(close_sent: bool);
+    unsafe_unpinned!(idle: Option<Compat01As03<Delay>>);
     // For this to be safe, field f must be private, and code in this module must never
     // construct PinMut<F>.
     unsafe_unpinned!(f: F);
@@ -313,18 +796,34 @@ impl<Req, Resp, T, F, Fut> ClientHandler<Req, Resp, T, F>
 where
     Req: Send + 'static,
     Resp: Send + 'static,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
     Fut: Future<Output = io::Result<Resp>> + Send + 'static,
 {
     /// If at max in-flight requests, check that there's room to immediately write a throttled
-    /// response.
+    /// response. If responses are pipelined, wait for responses to be sent instead.
     fn poll_ready_if_throttling(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<io::Result<()>> {
-        if self.in_flight_requests.len()
-            >= self.channel.config.max_in_flight_requests_per_connection
+        if self.channel.config.response_order == ResponseOrder::Pipelined {
+            let max_in_flight_requests = self.channel.config.max_in_flight_requests_per_connection;
+            if self.unsent_requests.len() >= max_in_flight_requests {
+                trace!(
+                    "[{}] In-flight requests at max ({}), waiting for responses to be sent.",
+                    self.channel.client_addr,
+                    max_in_flight_requests,
+                );
+                // Sending a response makes progress on the write half, after which the read half
+                // is polled again.
+                return Poll::Pending;
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        if self.channel.config.overload_policy == OverloadPolicy::Shed
+            && self.in_flight_requests.len()
+                >= self.channel.config.max_in_flight_requests_per_connection
         {
             let peer = self.as_mut().channel().client_addr;
 
@@ -340,18 +839,147 @@ where
         Poll::Ready(Ok(()))
     }
 
+    /// Records requests from [`ConnectionHandle`]s to drain the connection, to be handled by
+    /// [`pump_read`](ClientHandler::pump_read), or to close it right away.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) {
+        if let Some(shutdown) = self.channel.shutdown.poll_requested(cx) {
+            match shutdown {
+                Shutdown::Drain => {
+                    info!("[{}] Draining connection.", self.channel.client_addr);
+                    *self.as_mut().draining() = true;
+                }
+                Shutdown::Close(reason) => self.as_mut().close(reason),
+            }
+        }
+    }
+
+    /// Closes the connection right away, canceling the requests in flight. The client is told
+    /// `reason` before the connection closes.
+    fn close(mut self: Pin<&mut Self>, reason: CloseReason) {
+        if self.closing.is_some() {
+            return;
+        }
+        info!(
+            "[{}] Closing connection {}: {}.",
+            self.channel.client_addr, self.channel.connection_id, reason
+        );
+        for (_, abort_handle) in self.as_mut().in_flight_requests().drain() {
+            abort_handle.abort();
+        }
+        *self.as_mut().closing() = Some(reason);
+    }
+
+    /// Tells the client why the connection is closing, then resolves, closing it.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>, reason: CloseReason) -> Poll<()> {
+        if let Err(e) = ready!(self.as_mut().poll_send_close(cx, reason)) {
+            debug!(
+                "[{}] Could not tell the client why the connection closed: {}",
+                self.channel.client_addr, e
+            );
+        }
+        Poll::Ready(())
+    }
+
+    fn poll_send_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        reason: CloseReason,
+    ) -> Poll<io::Result<()>> {
+        if !self.close_sent {
+            ready!(self.as_mut().channel().poll_ready(cx)?);
+            self.as_mut().channel().start_close(reason)?;
+            *self.as_mut().close_sent() = true;
+        }
+        self.as_mut().channel().poll_flush(cx)
+    }
+
+    /// Returns true once the connection goes `config.idle_timeout` without requests in flight.
+    fn poll_idle(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> bool {
+        let idle_timeout = match self.channel.config.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return false,
+        };
+        if !self.in_flight_requests.is_empty() {
+            *self.as_mut().idle() = None;
+            return false;
+        }
+        if self.idle.is_none() {
+            // An idle timeout too long to represent never fires.
+            let deadline = match Instant::now().checked_add(idle_timeout) {
+                Some(deadline) => deadline,
+                None => return false,
+            };
+            *self.as_mut().idle() = Some(Delay::new(deadline).compat());
+        }
+        match self.as_mut().idle().as_mut().unwrap().poll_unpin(cx) {
+            Poll::Pending => false,
+            Poll::Ready(Ok(())) => true,
+            Poll::Ready(Err(e)) => {
+                warn!(
+                    "[{}] Not timing out the idle connection, because the timer failed: {}",
+                    self.channel.client_addr, e
+                );
+                false
+            }
+        }
+    }
+
     fn pump_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> PollIo<()> {
+        if self.draining {
+            return Poll::Ready(None);
+        }
+        ready!(self.as_mut().poll_deliver_body_item(cx));
         ready!(self.as_mut().poll_ready_if_throttling(cx)?);
 
-        Poll::Ready(match ready!(self.as_mut().channel().poll_next(cx)?) {
-            Some(message) => {
+        Poll::Ready(match ready!(self.as_mut().channel().poll_next(cx)) {
+            Some(Err(e)) => {
+                let is_undecodable_request = e
+                    .get_ref()
+                    .map_or(false, |inner| inner.is::<UndecodableRequest>());
+                if !is_undecodable_request || !self.channel.config.forgive_undecodable_requests {
+                    if e.kind() != io::ErrorKind::InvalidData {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    // The connection still works, so the client can be told why it's closed.
+                    debug!(
+                        "[{}] Client broke the protocol: {}",
+                        self.channel.client_addr, e
+                    );
+                    self.as_mut().close(CloseReason::ProtocolError);
+                    return Poll::Ready(Some(Ok(())));
+                }
+                let request = e.into_inner().unwrap().downcast().unwrap();
+                self.handle_undecodable_request(*request)?;
+                Some(Ok(()))
+            }
+            Some(Ok(message)) => {
+                *self.as_mut().idle() = None;
                 match message.message {
                     ClientMessageKind::Request(request) => {
-                        self.handle_request(message.trace_context, request)?;
+                        self.handle_request(message.trace_context, request, false)?;
                     }
                     ClientMessageKind::Cancel { request_id } => {
                         self.cancel_request(&message.trace_context, request_id);
                     }
+                    ClientMessageKind::Notification(request) => {
+                        self.as_mut().notifications().insert(request.id);
+                        self.handle_request(message.trace_context, request, false)?;
+                    }
+                    ClientMessageKind::Close { reason } => {
+                        info!(
+                            "[{}] Client is closing connection {}: {}.",
+                            self.channel.client_addr, self.channel.connection_id, reason
+                        );
+                    }
+                    ClientMessageKind::StreamingRequest(request) => {
+                        self.handle_request(message.trace_context, request, true)?;
+                    }
+                    ClientMessageKind::StreamItem { request_id, item } => {
+                        *self.as_mut().body_item() = Some((request_id, Ok(item)));
+                    }
+                    ClientMessageKind::StreamEnd { request_id } => {
+                        self.as_mut().end_body(request_id);
+                    }
                 }
                 Some(Ok(()))
             }
@@ -367,9 +995,30 @@ where
         cx: &mut Context<'_>,
         read_half_closed: bool,
     ) -> PollIo<()> {
+        if self.draining && !self.drain_sent {
+            // Sent ahead of the responses still owed, so the client stops sending requests that
+            // would go unread.
+            ready!(self.as_mut().channel().poll_ready(cx)?);
+            self.as_mut().channel().start_drain()?;
+            *self.as_mut().drain_sent() = true;
+            return Poll::Ready(Some(Ok(())));
+        }
         match self.as_mut().poll_next_response(cx)? {
-            Poll::Ready(Some((_, response))) => {
-                self.as_mut().channel().start_send(response)?;
+            Poll::Ready(Some((_, Reply::Item { request_id, item }))) => {
+                self.as_mut().channel().start_send_item(request_id, item)?;
+                Poll::Ready(Some(Ok(())))
+            }
+            Poll::Ready(Some((ctx, Reply::Response(response)))) => {
+                self.as_mut().end_body(response.request_id);
+                if self.as_mut().notifications().remove(&response.request_id) {
+                    trace!(
+                        "[{}/{}] Dropping response to notification.",
+                        ctx.trace_id(),
+                        self.channel.client_addr
+                    );
+                } else {
+                    self.as_mut().channel().start_send(response)?;
+                }
                 Poll::Ready(Some(Ok(())))
             }
             Poll::Ready(None) => {
@@ -396,52 +1045,249 @@ where
     fn poll_next_response(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-    ) -> PollIo<(context::Context, Response<Resp>)> {
+    ) -> PollIo<(context::Context, Reply<Resp>)> {
         // Ensure there's room to write a response.
         while let Poll::Pending = self.as_mut().channel().poll_ready(cx)? {
             ready!(self.as_mut().channel().poll_flush(cx)?);
         }
 
+        // Items were taken from `pending_responses` before any response taken along with them, so
+        // sending them first keeps each request's items ahead of its response.
+        if let Some((ctx, request_id, item)) = self.as_mut().stream_items().pop_front() {
+            return Poll::Ready(Some(Ok((ctx, Reply::Item { request_id, item }))));
+        }
+
+        if self.channel.config.response_order == ResponseOrder::Pipelined {
+            return self.poll_next_pipelined_response(cx);
+        }
+        if let Some(cost) = self.channel.response_cost {
+            return self.poll_next_costed_response(cx, cost);
+        }
+
         let peer = self.as_mut().channel().client_addr;
 
-        match ready!(self.as_mut().pending_responses().poll_next(cx)) {
-            Some((ctx, response)) => {
-                if self
-                    .as_mut()
-                    .in_flight_requests()
-                    .remove(&response.request_id)
-                    .is_some()
-                {
-                    self.as_mut().in_flight_requests().compact(0.1);
+        loop {
+            match ready!(self.as_mut().pending_responses().poll_next(cx)) {
+                Some((ctx, Reply::Item { request_id, item })) => {
+                    if self.is_streaming(&ctx, request_id) {
+                        return Poll::Ready(Some(Ok((ctx, Reply::Item { request_id, item }))));
+                    }
+                }
+                Some((ctx, Reply::Response(response))) => {
+                    if self
+                        .as_mut()
+                        .in_flight_requests()
+                        .remove(&response.request_id)
+                        .is_some()
+                    {
+                        self.as_mut().in_flight_requests().compact(0.1);
+                    }
+                    trace!(
+                        "[{}/{}] Staging response. In-flight requests = {}.",
+                        ctx.trace_id(),
+                        peer,
+                        self.as_mut().in_flight_requests().len(),
+                    );
+                    return Poll::Ready(Some(Ok((ctx, Reply::Response(response)))));
+                }
+                None => {
+                    // This branch likely won't happen, since the ClientHandler is holding a Sender.
+                    trace!("[{}] No new responses.", peer);
+                    return Poll::Ready(None);
                 }
+            }
+        }
+    }
+
+    /// Returns whether an item streamed in response to `request_id` should be sent, i.e. whether
+    /// the request is still in flight and isn't a notification.
+    fn is_streaming(&self, ctx: &context::Context, request_id: u64) -> bool {
+        if self.in_flight_requests.contains_key(&request_id)
+            && !self.notifications.contains(&request_id)
+        {
+            return true;
+        }
+        trace!(
+            "[{}/{}] Dropping streamed item, because its request is a notification or is no \
+             longer in flight.",
+            ctx.trace_id(),
+            self.channel.client_addr
+        );
+        false
+    }
+
+    /// Sets aside an item taken from `pending_responses` while looking for responses, to be sent
+    /// before them.
+    fn stash_stream_item(
+        mut self: Pin<&mut Self>,
+        ctx: context::Context,
+        request_id: u64,
+        item: Resp,
+    ) {
+        if self.is_streaming(&ctx, request_id) {
+            self.as_mut()
+                .stream_items()
+                .push_back((ctx, request_id, item));
+        }
+    }
+
+    /// Returns the cheapest response in the current batch of ready responses. When the batch runs
+    /// out, the responses ready by then become the next batch.
+    fn poll_next_costed_response(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        cost: ResponseCost<Resp>,
+    ) -> PollIo<(context::Context, Reply<Resp>)> {
+        let peer = self.as_mut().channel().client_addr;
+
+        if self.ready_responses.is_empty() {
+            let mut batch = vec![];
+            let mut responses_done = false;
+            loop {
+                match self.as_mut().pending_responses().poll_next(cx) {
+                    Poll::Ready(Some((ctx, Reply::Item { request_id, item }))) => {
+                        self.as_mut().stash_stream_item(ctx, request_id, item);
+                    }
+                    Poll::Ready(Some((ctx, Reply::Response(response)))) => {
+                        if self
+                            .as_mut()
+                            .in_flight_requests()
+                            .remove(&response.request_id)
+                            .is_some()
+                        {
+                            self.as_mut().in_flight_requests().compact(0.1);
+                        }
+                        batch.push((ctx, response));
+                    }
+                    Poll::Ready(None) => {
+                        responses_done = true;
+                        break;
+                    }
+                    Poll::Pending => break,
+                }
+            }
+            if batch.is_empty() && self.stream_items.is_empty() {
+                return if responses_done {
+                    trace!("[{}] No new responses.", peer);
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                };
+            }
+            // The sort is stable, so responses of equal cost are sent in the order they were ready.
+            batch.sort_by_key(|(_, response)| (cost.0)(response));
+            *self.as_mut().ready_responses() = batch.into();
+            // The items taken along with the batch are sent before it.
+            if let Some((ctx, request_id, item)) = self.as_mut().stream_items().pop_front() {
+                return Poll::Ready(Some(Ok((ctx, Reply::Item { request_id, item }))));
+            }
+        }
+
+        let (ctx, response) = self
+            .as_mut()
+            .ready_responses()
+            .pop_front()
+            .expect("The batch is not empty.");
+        trace!(
+            "[{}/{}] Staging response. In-flight requests = {}, ready responses = {}.",
+            ctx.trace_id(),
+            peer,
+            self.as_mut().in_flight_requests().len(),
+            self.ready_responses.len(),
+        );
+        Poll::Ready(Some(Ok((ctx, Reply::Response(response)))))
+    }
+
+    /// Returns the response to the earliest request whose response isn't sent yet, once it's
+    /// ready. Responses to later requests that are ready first are held until then.
+    fn poll_next_pipelined_response(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> PollIo<(context::Context, Reply<Resp>)> {
+        let peer = self.as_mut().channel().client_addr;
+
+        let mut responses_done = false;
+        loop {
+            match self.as_mut().pending_responses().poll_next(cx) {
+                Poll::Ready(Some((ctx, Reply::Item { request_id, item }))) => {
+                    self.as_mut().stash_stream_item(ctx, request_id, item);
+                }
+                Poll::Ready(Some((ctx, Reply::Response(response)))) => {
+                    // If the request is no longer in flight, it was canceled, and its place in
+                    // line may already be gone, so the response is dropped rather than held.
+                    if self
+                        .as_mut()
+                        .in_flight_requests()
+                        .remove(&response.request_id)
+                        .is_some()
+                    {
+                        self.as_mut().in_flight_requests().compact(0.1);
+                        self.as_mut()
+                            .held_responses()
+                            .insert(response.request_id, (ctx, response));
+                    }
+                }
+                Poll::Ready(None) => {
+                    responses_done = true;
+                    break;
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        if let Some((ctx, request_id, item)) = self.as_mut().stream_items().pop_front() {
+            return Poll::Ready(Some(Ok((ctx, Reply::Item { request_id, item }))));
+        }
+
+        while let Some(&request_id) = self.as_mut().unsent_requests().front() {
+            if let Some((ctx, response)) = self.as_mut().held_responses().remove(&request_id) {
+                self.as_mut().unsent_requests().pop_front();
+                self.as_mut().held_responses().compact(0.1);
                 trace!(
-                    "[{}/{}] Staging response. In-flight requests = {}.",
+                    "[{}/{}] Staging response. Unsent responses = {}.",
                     ctx.trace_id(),
                     peer,
-                    self.as_mut().in_flight_requests().len(),
+                    self.as_mut().unsent_requests().len(),
                 );
-                Poll::Ready(Some(Ok((ctx, response))))
+                return Poll::Ready(Some(Ok((ctx, Reply::Response(response)))));
             }
-            None => {
-                // This branch likely won't happen, since the ClientHandler is holding a Sender.
-                trace!("[{}] No new responses.", peer);
-                Poll::Ready(None)
+            if self.in_flight_requests.contains_key(&request_id) {
+                return Poll::Pending;
             }
+            // The request was canceled, so there's no response to wait for.
+            self.as_mut().unsent_requests().pop_front();
+        }
+
+        if responses_done {
+            trace!("[{}] No new responses.", peer);
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
         }
     }
 
+    /// Handles `request`, whose body the client streams after it if `streams_body` is set.
     fn handle_request(
         mut self: Pin<&mut Self>,
         trace_context: trace::Context,
         request: Request<Req>,
+        streams_body: bool,
     ) -> io::Result<()> {
         let request_id = request.id;
         let peer = self.as_mut().channel().client_addr;
         let ctx = context::Context {
             deadline: request.deadline,
             trace_context,
+            causality: request.causality,
         };
+        let metadata = Arc::new(request.metadata);
         let request = request.message;
+        let method = self
+            .channel
+            .call_names
+            .as_ref()
+            .map(|names| (names.0)(&request));
+        let mut recorder = self.start_recording(method.unwrap_or("unknown"));
 
         if self.as_mut().in_flight_requests().len()
             >= self
@@ -461,16 +1307,69 @@ where
                     .max_in_flight_requests_per_connection
             );
 
+            if self.as_mut().channel().config.overload_policy == OverloadPolicy::Disconnect {
+                self.close(CloseReason::Overloaded);
+                return Ok(());
+            }
+
+            let error = self.throttled_error();
+            if let Some(recorder) = &mut recorder {
+                recorder.complete_with(Some(error.code));
+            }
+            if self.as_mut().notifications().remove(&request_id) {
+                return Ok(());
+            }
             self.as_mut().channel().start_send(Response {
                 request_id,
-                message: Err(ServerError {
-                    kind: io::ErrorKind::WouldBlock,
-                    detail: Some("Server throttled the request.".into()),
-                }),
+                message: Err(error),
+                causality: ctx.causality,
+                caching: None,
             })?;
             return Ok(());
         }
 
+        // Takes a turn before the admission policy decides, so that every request it admits is
+        // handled, and reported to it.
+        let turn = match &self.channel.scheduler {
+            Some(scheduler) => match scheduler.wait(self.channel.connection_id) {
+                Some(turn) => Some(turn),
+                None => {
+                    debug!(
+                        "[{}/{}] Server has too many requests waiting to run.",
+                        ctx.trace_id(),
+                        peer
+                    );
+                    let response = future::ready(Err(io::Error::from(self.throttled_error())));
+                    return self.spawn_response(ctx, request_id, recorder, response);
+                }
+            },
+            None => None,
+        };
+
+        let admission = self.channel.admission.clone();
+        if let Some(admission) = &admission {
+            let load = Load {
+                in_flight_requests: self.in_flight_requests.len(),
+                max_in_flight_requests: self.channel.config.max_in_flight_requests_per_connection,
+                queued_responses: self.held_responses.len() + self.ready_responses.len(),
+            };
+            let error = match admission.admit(&ctx, &load) {
+                Decision::Admit => None,
+                Decision::Shed => Some(self.throttled_error()),
+                Decision::Reject(error) => Some(error),
+            };
+            if let Some(error) = error {
+                debug!(
+                    "[{}/{}] Admission policy rejected the request: {}",
+                    ctx.trace_id(),
+                    peer,
+                    error
+                );
+                let response = future::ready(Err(io::Error::from(error)));
+                return self.spawn_response(ctx, request_id, recorder, response);
+            }
+        }
+
         let deadline = ctx.deadline;
         let timeout = deadline.as_duration();
         trace!(
@@ -480,10 +1379,142 @@ where
             format_rfc3339(deadline),
             timeout,
         );
+        let response = self.as_mut().f().clone()(ctx, request);
+        let response = context::scope(ctx, Cancelable::new(response));
+        let response = metadata::scope(metadata, response);
+        let response = identity::scope(
+            self.channel.connection_id,
+            self.channel.identity.clone(),
+            self.channel.session.clone(),
+            self.channel.credentials,
+            response,
+        );
+        let sender = if self.notifications.contains(&request_id) {
+            None
+        } else {
+            Some(Sender::new(ctx, request_id, self.responses_tx.clone()))
+        };
+        let body = if streams_body {
+            let (items_tx, items) = mpsc::channel(self.channel.config.body_item_buffer);
+            self.as_mut().bodies().insert(request_id, items_tx);
+            Some(Body::new(items))
+        } else {
+            None
+        };
+        let response = streaming::scope(sender, body, response);
+        let response = Scheduled::new(turn, response);
+        match admission {
+            Some(admission) => self.spawn_response(
+                ctx,
+                request_id,
+                recorder,
+                Tracked::new(response, admission, ctx),
+            ),
+            None => self.spawn_response(ctx, request_id, recorder, response),
+        }
+    }
+
+    /// Reports a request to `method` as started, if the server has metrics.
+    fn start_recording(&self, method: &'static str) -> Option<Recorder> {
+        let metrics = self.channel.config.metrics.clone()?;
+        Some(Recorder::start(metrics, "server", method))
+    }
+
+    /// Returns the error sent in response to requests that are throttled.
+    fn throttled_error(&self) -> ServerError {
+        let mut error = ServerError::overloaded("Server throttled the request.");
+        error.retry_after = self.channel.config.throttled_retry_after;
+        error
+    }
+
+    /// Responds to a request that the transport couldn't decode with an error.
+    fn handle_undecodable_request(
+        mut self: Pin<&mut Self>,
+        request: UndecodableRequest,
+    ) -> io::Result<()> {
+        let peer = self.as_mut().channel().client_addr;
+        let ctx = context::Context {
+            trace_context: request.trace_context,
+            ..context::current()
+        };
+        if request.stream_item {
+            debug!(
+                "[{}/{}] Failing the body of request {}: {}",
+                ctx.trace_id(),
+                peer,
+                request.request_id,
+                request
+            );
+            let error = io::Error::new(io::ErrorKind::InvalidData, request.to_string());
+            *self.as_mut().body_item() = Some((request.request_id, Err(error)));
+            return Ok(());
+        }
+        if request.notification {
+            debug!(
+                "[{}/{}] Dropping undecodable notification: {}",
+                ctx.trace_id(),
+                peer,
+                request
+            );
+            return Ok(());
+        }
+        debug!(
+            "[{}/{}] Responding to undecodable request: {}",
+            ctx.trace_id(),
+            peer,
+            request
+        );
+
+        let detail = match request.code {
+            ErrorCode::PayloadTooLarge => {
+                format!("Server rejected the request: {}", request.detail)
+            }
+            _ => format!(
+                "Server could not decode the request. The method may not be implemented: {}",
+                request.detail
+            ),
+        };
+        let error = ServerError::new(io::ErrorKind::InvalidInput, detail).with_code(request.code);
+        let recorder = self.start_recording("unknown");
+        self.spawn_response(
+            ctx,
+            request.request_id,
+            recorder,
+            future::ready(Err(io::Error::from(error))),
+        )
+    }
+
+    /// Spawns a task that sends the response resolved by `response` to the client, once it's
+    /// ready, and tracks it as in flight until then. Reports the response to `recorder`, if set.
+    fn spawn_response(
+        mut self: Pin<&mut Self>,
+        ctx: context::Context,
+        request_id: u64,
+        mut recorder: Option<Recorder>,
+        response: impl Future<Output = io::Result<Resp>> + Send + 'static,
+    ) -> io::Result<()> {
+        let peer = self.as_mut().channel().client_addr;
+        let deadline = ctx.deadline;
+        let timeout = deadline.as_duration();
         let mut response_tx = self.as_mut().responses_tx().clone();
 
         let trace_id = *ctx.trace_id();
-        let response = self.as_mut().f().clone()(ctx, request);
+        let caching = caching::Slot::default();
+        let response = caching::scope(caching.clone(), response);
+        let response = AssertUnwindSafe(response)
+            .catch_unwind()
+            .map(move |result| match result {
+                Ok(result) => result,
+                Err(panic) => {
+                    error!(
+                        "[{}/{}] Request handler panicked: {}",
+                        trace_id,
+                        peer,
+                        panic_message(&*panic)
+                    );
+                    Err(panic_error().into())
+                }
+            });
         let response = deadline_compat::Deadline::new(response, Instant::now() + timeout).then(
             async move |result| {
                 let response = Response {
@@ -492,9 +1523,19 @@ where
                         Ok(message) => Ok(message),
                         Err(e) => Err(make_server_error(e, trace_id, peer, deadline)),
                     },
+                    causality: ctx.causality,
+                    caching: caching.lock().unwrap().take(),
                 };
+                if let Some(recorder) = &mut recorder {
+                    recorder.complete_with(response.message.as_ref().err().map(|e| e.code));
+                }
                 trace!("[{}/{}] Sending response.", trace_id, peer);
-                await!(response_tx.send((ctx, response)).unwrap_or_else(|_| ()));
+                if await!(response_tx.send((ctx, Reply::Response(response)))).is_err() {
+                    debug!(
+                        "[{}/{}] Dropping response to request {}, because the connection closed.",
+                        trace_id, peer, request_id
+                    );
+                }
             },
         );
         let (abortable_response, abort_handle) = abortable(response);
@@ -510,10 +1551,15 @@ where
         self.as_mut()
             .in_flight_requests()
             .insert(request_id, abort_handle);
+        if self.channel.config.response_order == ResponseOrder::Pipelined {
+            self.as_mut().unsent_requests().push_back(request_id);
+        }
         Ok(())
     }
 
     fn cancel_request(mut self: Pin<&mut Self>, trace_context: &trace::Context, request_id: u64) {
+        self.as_mut().notifications().remove(&request_id);
+        self.as_mut().end_body(request_id);
         // It's possible the request was already completed, so it's fine
         // if this is None.
         if let Some(cancel_handle) = self.as_mut().in_flight_requests().remove(&request_id) {
@@ -536,13 +1582,55 @@ where
             );
         }
     }
+
+    /// Hands the body item read off the wire last to its request's handler, once the body has
+    /// room for it. Until then, nothing more is read, which back-pressures the client. An error
+    /// ends the body.
+    fn poll_deliver_body_item(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let (request_id, item) = match self.as_mut().body_item().take() {
+            Some(body_item) => body_item,
+            None => return Poll::Ready(()),
+        };
+        let peer = self.channel.client_addr;
+        let is_err = item.is_err();
+        let delivered = match self.as_mut().bodies().get_mut(&request_id) {
+            Some(items) => match items.poll_ready(cx) {
+                Poll::Ready(Ok(())) => items.start_send(item).is_ok(),
+                // The handler dropped the body, or completed.
+                Poll::Ready(Err(_)) => false,
+                Poll::Pending => {
+                    *self.as_mut().body_item() = Some((request_id, item));
+                    return Poll::Pending;
+                }
+            },
+            None => false,
+        };
+        if !delivered {
+            trace!(
+                "[{}] Dropping an item of the body of request {}, which no handler is reading.",
+                peer,
+                request_id
+            );
+        }
+        if is_err {
+            self.end_body(request_id);
+        }
+        Poll::Ready(())
+    }
+
+    /// Ends the streamed body of request `request_id`, if it has one.
+    fn end_body(mut self: Pin<&mut Self>, request_id: u64) {
+        if self.as_mut().bodies().remove(&request_id).is_some() {
+            self.as_mut().bodies().compact(0.1);
+        }
+    }
 }
 
 impl<Req, Resp, T, F, Fut> Future for ClientHandler<Req, Resp, T, F>
 where
     Req: Send + 'static,
     Resp: Send + 'static,
-    T: Transport<Item = ClientMessage<Req>, SinkItem = Response<Resp>> + Send,
+    T: Transport<Item = ClientMessage<Req>, SinkItem = ServerMessage<Resp>> + Send,
     F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
     Fut: Future<Output = io::Result<Resp>> + Send + 'static,
 {
@@ -551,13 +1639,28 @@ where
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         trace!("[{}] ClientHandler::poll", self.channel.client_addr);
         loop {
+            self.as_mut().poll_shutdown(cx);
+            if let Some(reason) = self.closing {
+                ready!(self.as_mut().poll_close(cx, reason));
+                return Poll::Ready(Ok(()));
+            }
             let read = self.as_mut().pump_read(cx)?;
+            if self.closing.is_some() {
+                continue;
+            }
             match (
                 read,
                 self.as_mut().pump_write(cx, read == Poll::Ready(None))?,
             ) {
                 (Poll::Ready(None), Poll::Ready(None)) => {
-                    info!("[{}] Client disconnected.", self.channel.client_addr);
+                    if self.draining {
+                        self.as_mut().close(CloseReason::Shutdown);
+                        continue;
+                    }
+                    info!(
+                        "[{}] Client disconnected from connection {}.",
+                        self.channel.client_addr, self.channel.connection_id
+                    );
                     return Poll::Ready(Ok(()));
                 }
                 (read @ Poll::Ready(Some(())), write) | (read, write @ Poll::Ready(Some(()))) => {
@@ -569,6 +1672,10 @@ where
                     )
                 }
                 (read, write) => {
+                    if self.as_mut().poll_idle(cx) {
+                        self.as_mut().close(CloseReason::IdleTimeout);
+                        continue;
+                    }
                     trace!(
                         "[{}] read: {:?}, write: {:?} (not ready).",
                         self.channel.client_addr,
@@ -582,6 +1689,30 @@ where
     }
 }
 
+/// Returns the error sent to the client for a request handler that failed with `e`.
+pub(crate) fn handler_error(e: io::Error) -> ServerError {
+    if e.get_ref().map_or(false, |inner| inner.is::<ServerError>()) {
+        *e.into_inner().unwrap().downcast::<ServerError>().unwrap()
+    } else {
+        ServerError::new(e.kind(), e.description())
+    }
+}
+
+/// Returns the error sent to the client for a request handler that panicked.
+pub(crate) fn panic_error() -> ServerError {
+    ServerError::new(io::ErrorKind::Other, "Request handler panicked.")
+        .with_code(ErrorCode::Internal)
+}
+
+/// Returns the message a request handler panicked with, if it's a string.
+pub(crate) fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .cloned()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("")
+}
+
 fn make_server_error(
     e: timeout::Error<io::Error>,
     trace_id: TraceId,
@@ -596,35 +1727,483 @@ fn make_server_error(
             format_rfc3339(deadline)
         );
         // No point in responding, since the client will have dropped the request.
-        ServerError {
-            kind: io::ErrorKind::TimedOut,
-            detail: Some(format!(
+        ServerError::new(
+            io::ErrorKind::TimedOut,
+            format!(
                 "Response did not complete before deadline of {}s.",
                 format_rfc3339(deadline)
-            )),
-        }
+            ),
+        )
     } else if e.is_timer() {
         error!(
             "[{}/{}] Response failed because of an issue with a timer: {}",
             trace_id, peer, e
         );
 
-        ServerError {
-            kind: io::ErrorKind::Other,
-            detail: Some(format!("{}", e)),
-        }
+        ServerError::new(io::ErrorKind::Other, format!("{}", e))
     } else if e.is_inner() {
-        let e = e.into_inner().unwrap();
-        ServerError {
-            kind: e.kind(),
-            detail: Some(e.description().into()),
-        }
+        handler_error(e.into_inner().unwrap())
     } else {
         error!("[{}/{}] Unexpected response failure: {}", trace_id, peer, e);
 
-        ServerError {
-            kind: io::ErrorKind::Other,
-            detail: Some(format!("Server unexpectedly failed to respond: {}", e)),
+        ServerError::new(
+            io::ErrorKind::Other,
+            format!("Server unexpectedly failed to respond: {}", e),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        client, context,
+        metadata::{self, Metadata},
+        server::{self, Handler, Server},
+        test_util, transport, ClientMessage, ClientMessageKind, CloseReason, ConnectionClosed,
+        ErrorCode, Request, ServerError, ServerMessage, UndecodableRequest,
+    };
+    use futures::{channel::oneshot, compat::Executor01CompatExt, future, prelude::*, stream};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    #[test]
+    fn unforgiving_server_closes_connection_on_undecodable_request() {
+        test_util::init();
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let (sink, stream) = server_channel.split();
+        let stream = stream.map(|message: io::Result<ClientMessage<String>>| {
+            let message = message?;
+            match message.message {
+                ClientMessageKind::Request(ref request) if request.message == "bad" => {
+                    Err(UndecodableRequest::new(message.trace_context, request.id, "Bad.").into())
+                }
+                _ => Ok(message),
+            }
+        });
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let mut config = server::Config::default();
+        config.forgive_undecodable_requests = false;
+        let server = server::new::<String, String>(config)
+            .incoming(stream::once(future::ready(Ok(transport::join(
+                stream, sink, addr, addr,
+            )))))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+
+        let response = async {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            await!(client.call(context::current(), "bad".into()))
+        };
+
+        let response = test_util::run_future(future::join(server, response)).1;
+        let e = response.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            ConnectionClosed::of(&e).unwrap().reason,
+            CloseReason::ProtocolError
+        );
+    }
+
+    #[test]
+    fn handler_panic_fails_request_with_internal_error() {
+        test_util::init();
+
+        let (client_channel, server) = test_util::serve(
+            Server::<String, String>::default(),
+            |_ctx, request: String| {
+                future::lazy(move |_| {
+                    if request == "panic" {
+                        panic!("Handler bug.");
+                    }
+                    Ok(request)
+                })
+            },
+        );
+
+        let responses = async {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            let e = await!(client.call(context::current(), "panic".into())).unwrap_err();
+            assert_eq!(ServerError::of(&e).unwrap().code, ErrorCode::Internal);
+            await!(client.call(context::current(), "hi".into()))
+        };
+
+        let response = test_util::run_future(future::join(server, responses)).1;
+        assert_eq!(response.unwrap(), "hi");
+    }
+
+    #[test]
+    fn metadata_is_current_in_handler() {
+        test_util::init();
+
+        let (client_channel, server) =
+            test_util::serve(Server::<String, String>::default(), |_ctx, request| {
+                future::lazy(move |_| {
+                    Ok(format!("{} {}", request, metadata::get("tenant").unwrap()))
+                })
+            });
+
+        let response = async {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            let mut metadata = Metadata::new();
+            metadata.insert("tenant".into(), "acme".into());
+            await!(client.call_with_metadata(context::current(), metadata, "hi".into()))
+        };
+
+        let response = test_util::run_future(future::join(server, response)).1;
+        assert_eq!(response.unwrap(), "hi acme");
+    }
+
+    #[test]
+    fn pipelined_responses_keep_request_order() {
+        test_util::init();
+
+        // The first request isn't answered until the second one has been.
+        let (first_tx, first_rx) = oneshot::channel::<()>();
+        let first_tx = Arc::new(Mutex::new(Some(first_tx)));
+        let first_rx = Arc::new(Mutex::new(Some(first_rx)));
+
+        let mut config = server::Config::default();
+        config.response_order = server::ResponseOrder::Pipelined;
+        let (mut client_channel, server_channel) = transport::channel::unbounded();
+        let server = server::new::<String, String>(config)
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(move |_ctx, request: String| {
+                let (first_tx, first_rx) = (first_tx.clone(), first_rx.clone());
+                async move {
+                    if request == "first" {
+                        let first_rx = first_rx.lock().unwrap().take().unwrap();
+                        await!(first_rx).unwrap();
+                    } else {
+                        first_tx.lock().unwrap().take().unwrap().send(()).unwrap();
+                    }
+                    Ok(request)
+                }
+            });
+
+        let responses = async move {
+            for (id, message) in vec!["first", "second"].into_iter().enumerate() {
+                await!(client_channel.send(ClientMessage {
+                    trace_context: context::current().trace_context,
+                    message: ClientMessageKind::Request(Request {
+                        id: id as u64,
+                        message: message.to_string(),
+                        deadline: context::current().deadline,
+                        metadata: Metadata::new(),
+                        causality: None,
+                    }),
+                }))
+                .unwrap();
+            }
+            await!(client_channel.take(2).collect::<Vec<_>>())
+        };
+
+        let responses = test_util::run_future(future::join(server, responses)).1;
+        let responses: Vec<_> = responses
+            .into_iter()
+            .map(|message| {
+                let response = test_util::into_response(message);
+                (response.request_id, response.message.unwrap())
+            })
+            .collect();
+        assert_eq!(
+            responses,
+            vec![(0, "first".to_string()), (1, "second".to_string())]
+        );
+    }
+
+    #[test]
+    fn responses_ready_together_are_sent_cheapest_first() {
+        let _ = env_logger::try_init();
+        crate::init_thread(tokio::runtime::current_thread::TaskExecutor::current().compat());
+
+        let (mut client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, String>::default()
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .map_ok(|channel| {
+                channel.with_response_cost(|response| match &response.message {
+                    Ok(message) => message.len() as u64,
+                    Err(_) => 0,
+                })
+            })
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+
+        // All requests are read before any handler runs, so all responses are ready together.
+        let responses = async move {
+            for (id, message) in vec!["large response", "small", "go"]
+                .into_iter()
+                .enumerate()
+            {
+                await!(client_channel.send(ClientMessage {
+                    trace_context: context::current().trace_context,
+                    message: ClientMessageKind::Request(Request {
+                        id: id as u64,
+                        message: message.to_string(),
+                        deadline: context::current().deadline,
+                        metadata: Metadata::new(),
+                        causality: None,
+                    }),
+                }))
+                .unwrap();
+            }
+            await!(client_channel.take(3).collect::<Vec<_>>())
+        };
+
+        let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+        let responses = runtime
+            .block_on(
+                future::join(server, responses)
+                    .boxed()
+                    .unit_error()
+                    .compat(),
+            )
+            .unwrap()
+            .1;
+        let request_ids: Vec<_> = responses
+            .into_iter()
+            .map(|message| test_util::into_response(message).request_id)
+            .collect();
+        assert_eq!(request_ids, vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn drained_connection_stops_reading_requests() {
+        test_util::init();
+
+        let (handle_tx, handle_rx) = oneshot::channel();
+        let mut handle_tx = Some(handle_tx);
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, String>::default()
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .map_ok(move |channel| {
+                handle_tx.take().unwrap().send(channel.handle()).unwrap();
+                channel
+            })
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+
+        let responses = async {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            let handle: server::ConnectionHandle = await!(handle_rx).unwrap();
+
+            let response1 = await!(client.call(context::current(), "hi".into()));
+            handle.drain();
+            let response2 = await!(client.call(context::current(), "bye".into()));
+
+            Ok::<_, io::Error>((response1, response2))
+        };
+
+        let (response1, response2) = test_util::run_future(future::join(
+            server,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .1;
+
+        assert_eq!(response1.unwrap(), "hi");
+        let e = response2.unwrap_err();
+        assert_eq!(
+            ConnectionClosed::of(&e).unwrap().reason,
+            CloseReason::Shutdown
+        );
+    }
+
+    #[test]
+    fn closed_connection_tells_client_it_was_kicked() {
+        test_util::init();
+
+        let (handle_tx, handle_rx) = oneshot::channel();
+        let mut handle_tx = Some(handle_tx);
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, String>::default()
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .map_ok(move |channel| {
+                handle_tx.take().unwrap().send(channel.handle()).unwrap();
+                channel
+            })
+            .respond_with(|_ctx, _request: String| future::pending());
+
+        let response = async {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            let handle: server::ConnectionHandle = await!(handle_rx).unwrap();
+            let response = client.call(context::current(), "hi".into());
+            handle.close();
+            await!(response)
+        };
+
+        let e = test_util::run_future(future::join(server, response))
+            .1
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            ConnectionClosed::of(&e).unwrap().reason,
+            CloseReason::Kicked
+        );
+    }
+
+    #[test]
+    fn idle_connections_time_out() {
+        test_util::init();
+
+        let mut config = server::Config::default();
+        config.idle_timeout = Some(Duration::from_millis(10));
+        let (mut client_channel, server_channel) = transport::channel::unbounded();
+        let server = server::new::<String, String>(config)
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+
+        let messages = async move {
+            await!(client_channel.send(ClientMessage {
+                trace_context: context::current().trace_context,
+                message: ClientMessageKind::Request(Request {
+                    id: 0,
+                    message: "hi".to_string(),
+                    deadline: context::current().deadline,
+                    metadata: Metadata::new(),
+                    causality: None,
+                }),
+            }))
+            .unwrap();
+            // The connection stays open while the request is in flight, and times out after.
+            await!(client_channel.collect::<Vec<_>>())
+        };
+
+        let messages: Vec<_> = test_util::run_future(future::join(server, messages))
+            .1
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(messages.len(), 2);
+        match &messages[1] {
+            ServerMessage::Close { reason } => assert_eq!(*reason, CloseReason::IdleTimeout),
+            other => panic!("Expected the connection to time out, got {:?}", other),
         }
     }
+
+    #[test]
+    fn spawned_servers_exit_once_their_connections_close() {
+        test_util::init();
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let response = async {
+            let serving = Server::<String, String>::default()
+                .incoming(stream::once(future::ready(Ok(server_channel))))
+                .respond_with(|_ctx, request| future::ready(Ok(request)))
+                .spawn()?;
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            let response = await!(client.call(context::current(), "hi".into()))?;
+            // The listener has ended, but the server runs until its connection closes.
+            drop(client);
+            await!(serving);
+            Ok::<_, io::Error>(response)
+        };
+
+        assert_eq!(test_util::run_future(response).unwrap(), "hi");
+    }
+
+    #[test]
+    fn servers_handle_notifications_without_responding() {
+        test_util::init();
+
+        let handled = Arc::new(Mutex::new(vec![]));
+        let (mut client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, String>::default()
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with({
+                let handled = handled.clone();
+                move |_ctx, request: String| {
+                    handled.lock().unwrap().push(request.clone());
+                    future::ready(Ok(request))
+                }
+            });
+
+        let responses = async move {
+            let request = |id, message: &str| Request {
+                id,
+                message: message.to_string(),
+                deadline: context::current().deadline,
+                metadata: Metadata::new(),
+                causality: None,
+            };
+            for message in vec![
+                ClientMessageKind::Notification(request(0, "note")),
+                ClientMessageKind::Request(request(1, "hi")),
+            ] {
+                await!(client_channel.send(ClientMessage {
+                    trace_context: context::current().trace_context,
+                    message,
+                }))
+                .unwrap();
+            }
+            // The server closes the connection once it has handled both.
+            await!(client_channel.close()).unwrap();
+            await!(client_channel.collect::<Vec<_>>())
+        };
+
+        let responses: Vec<_> = test_util::run_future(future::join(server, responses))
+            .1
+            .into_iter()
+            .map(|message| {
+                let response = test_util::into_response(message);
+                (response.request_id, response.message.unwrap())
+            })
+            .collect();
+        assert_eq!(responses, vec![(1, "hi".to_string())]);
+        assert_eq!(*handled.lock().unwrap(), vec!["note", "hi"]);
+    }
+
+    #[test]
+    fn servers_drop_undecodable_notifications() {
+        test_util::init();
+
+        let (mut client_channel, server_channel) = transport::channel::unbounded();
+        let (sink, stream) = server_channel.split();
+        let stream = stream.map(|message: io::Result<ClientMessage<String>>| {
+            let message = message?;
+            match message.message {
+                ClientMessageKind::Notification(ref request) if request.message == "bad" => Err(
+                    UndecodableRequest::new(message.trace_context, request.id, "Bad.")
+                        .with_notification(true)
+                        .into(),
+                ),
+                _ => Ok(message),
+            }
+        });
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = Server::<String, String>::default()
+            .incoming(stream::once(future::ready(Ok(transport::join(
+                stream, sink, addr, addr,
+            )))))
+            .respond_with(|_ctx, request| future::ready(Ok(request)));
+
+        let responses = async move {
+            let request = |id, message: &str| Request {
+                id,
+                message: message.to_string(),
+                deadline: context::current().deadline,
+                metadata: Metadata::new(),
+                causality: None,
+            };
+            for message in vec![
+                ClientMessageKind::Notification(request(0, "bad")),
+                ClientMessageKind::Request(request(1, "hi")),
+            ] {
+                await!(client_channel.send(ClientMessage {
+                    trace_context: context::current().trace_context,
+                    message,
+                }))
+                .unwrap();
+            }
+            await!(client_channel.close()).unwrap();
+            await!(client_channel.collect::<Vec<_>>())
+        };
+
+        let responses: Vec<_> = test_util::run_future(future::join(server, responses))
+            .1
+            .into_iter()
+            .map(|message| test_util::into_response(message).request_id)
+            .collect();
+        // The connection stays open, and nothing is sent for the notification.
+        assert_eq!(responses, vec![1]);
+    }
 }
@@ -0,0 +1,403 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Routes requests to per-key worker queues.
+//!
+//! Wrapping a request handler with [`partition`] gives it a fixed number of workers, each of which
+//! handles one request at a time, in the order requests were read. Every request is routed to a
+//! worker by its key, so requests with the same key, from any connection, are handled one after
+//! another, and handlers don't need to lock per-key state.
+
+use crate::{
+    context,
+    server::{panic_error, panic_message},
+    util::{deadline_compat::Deadline, AsDuration},
+    ServerError,
+};
+use fnv::FnvHasher;
+use futures::{
+    channel::{mpsc, oneshot},
+    pin_mut,
+    prelude::*,
+    task::{Context, Poll},
+};
+use log::{debug, error, warn};
+use pin_utils::unsafe_pinned;
+use std::{
+    hash::{Hash, Hasher},
+    io,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// A worker's queue. Every clone of the handler sends on the same sender, because each sender
+/// is allowed a request beyond the channel's buffer.
+type Queue<Req, Resp> =
+    Mutex<mpsc::Sender<(context::Context, Req, oneshot::Sender<io::Result<Resp>>)>>;
+
+/// Settings that control the workers of a [`partition`]ed handler.
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The number of workers requests are partitioned over. Must be at least 1.
+    pub workers: usize,
+    /// The most requests that wait for each worker. Requests routed to a worker already this far
+    /// behind are rejected right away with an [overloaded](crate::ErrorCode::Overloaded) error.
+    /// Counts the request the worker is handling, and is at least 1.
+    pub max_queued_requests: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            workers: 8,
+            max_queued_requests: 100,
+        }
+    }
+}
+
+/// Wraps request handler `f` so that requests are handled by one of `config.workers` workers,
+/// picked by the hash of the key that `key` returns for each request. Fails with an
+/// [`InvalidInput`](io::ErrorKind::InvalidInput) error if there are no workers.
+///
+/// Each worker handles its requests in the order they were read, waiting for each response before
+/// starting on the next request. Requests whose caller stopped waiting, e.g. because their
+/// deadline passed, are skipped, and a request whose caller stops waiting while it's handled, or
+/// whose deadline passes, is dropped, so the worker moves on. A handler that panics fails its
+/// request with an [internal](crate::ErrorCode::Internal) error, and its worker carries on with
+/// the next one.
+///
+/// The workers are spawned right away, and stop once the returned handler and all its clones are
+/// dropped.
+pub fn partition<Req, Resp, K, KeyFn, F, Fut>(
+    config: Config,
+    key: KeyFn,
+    f: F,
+) -> io::Result<impl FnOnce(context::Context, Req) -> Queued<Resp> + Send + 'static + Clone>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    K: Hash,
+    KeyFn: Fn(&Req) -> K + Send + 'static + Clone,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+{
+    if config.workers == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "A partitioned handler needs at least one worker.",
+        ));
+    }
+    let mut queues: Vec<Queue<Req, Resp>> = Vec::with_capacity(config.workers);
+    for worker in 0..config.workers {
+        // The sender's own slot counts too, so the channel holds one more request than its buffer.
+        let (queue, mut requests) = mpsc::channel(config.max_queued_requests.saturating_sub(1));
+        let f = f.clone();
+        crate::spawn(async move {
+            while let Some((ctx, req, mut response_tx)) = await!(requests.next()) {
+                if response_tx.is_canceled() {
+                    debug!(
+                        "[{}] Skipping request, because the caller stopped waiting.",
+                        ctx.trace_id()
+                    );
+                    continue;
+                }
+                let response = {
+                    let response = handle(worker, ctx, f.clone()(ctx, req));
+                    let canceled = response_tx.cancellation();
+                    pin_mut!(response);
+                    pin_mut!(canceled);
+                    match await!(future::select(response, canceled)) {
+                        future::Either::Left((response, _)) => response,
+                        future::Either::Right(_) => {
+                            debug!(
+                                "[{}] Dropping request, because the caller stopped waiting.",
+                                ctx.trace_id()
+                            );
+                            continue;
+                        }
+                    }
+                };
+                let _ = response_tx.send(response);
+            }
+        })
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "Could not spawn partition worker. Is shutdown: {}",
+                    e.is_shutdown()
+                ),
+            )
+        })?;
+        queues.push(Mutex::new(queue));
+    }
+    let queues = Arc::new(queues);
+
+    Ok(move |ctx: context::Context, req| {
+        let mut hasher = FnvHasher::default();
+        key(&req).hash(&mut hasher);
+        let worker = (hasher.finish() % queues.len() as u64) as usize;
+
+        let (response_tx, response) = oneshot::channel();
+        let sent = queues[worker]
+            .lock()
+            .unwrap()
+            .try_send((ctx, req, response_tx));
+        if let Err(e) = sent {
+            let full = e.is_full();
+            let (_, _, response_tx) = e.into_inner();
+            let _ = response_tx.send(Err(if full {
+                ServerError::overloaded(format!("Partition worker {} is full.", worker)).into()
+            } else {
+                warn!("[{}] Partition worker {} stopped.", ctx.trace_id(), worker);
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Partition worker {} stopped.", worker),
+                )
+            }));
+        }
+        Queued { response }
+    })
+}
+
+/// Runs `response` on worker `worker` until its deadline, failing it with an internal error if it
+/// panics.
+async fn handle<Resp>(
+    worker: usize,
+    ctx: context::Context,
+    response: impl Future<Output = io::Result<Resp>>,
+) -> io::Result<Resp> {
+    let response = AssertUnwindSafe(context::scope(ctx, response)).catch_unwind();
+    let deadline = Instant::now() + ctx.deadline.as_duration();
+    match await!(Deadline::new(response.map(Ok::<_, io::Error>), deadline)) {
+        Ok(Ok(response)) => response,
+        Ok(Err(panic)) => {
+            error!(
+                "[{}] Request handler panicked on partition worker {}: {}",
+                ctx.trace_id(),
+                worker,
+                panic_message(&*panic)
+            );
+            Err(panic_error().into())
+        }
+        Err(ref e) if e.is_elapsed() => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "Partition worker {} dropped the request at its deadline.",
+                worker
+            ),
+        )),
+        Err(e) => Err(e.into_inner().unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "Dropping request because its deadline could not be set.",
+            )
+        })),
+    }
+}
+
+/// The future returned by a request handler wrapped with [`partition`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Queued<Resp> {
+    response: oneshot::Receiver<io::Result<Resp>>,
+}
+
+impl<Resp> Queued<Resp> {
+    unsafe_pinned!(response: oneshot::Receiver<io::Result<Resp>>);
+}
+
+impl<Resp> Future for Queued<Resp> {
+    type Output = io::Result<Resp>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
+        self.response().poll(cx).map(|response| {
+            response.unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "Partition worker stopped before responding.",
+                ))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{partition, Config};
+    use crate::{context, ErrorCode, ServerError};
+    use futures::{channel::oneshot, compat::*, prelude::*};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+    use tokio::{runtime::current_thread, timer::Delay};
+
+    #[test]
+    fn requests_with_the_same_key_are_handled_in_order() {
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        crate::init_thread(current_thread::TaskExecutor::current().compat());
+
+        let finished = Arc::new(Mutex::new(vec![]));
+        let responses = {
+            let finished = finished.clone();
+            async move {
+                let mut config = super::Config::default();
+                config.workers = 4;
+                let handler = partition(
+                    config,
+                    |req: &(&'static str, u64)| req.0,
+                    move |_, req| {
+                        let (_, delay_millis): (&'static str, u64) = req;
+                        let finished = finished.clone();
+                        async move {
+                            let delay = Instant::now() + Duration::from_millis(delay_millis);
+                            await!(Delay::new(delay).compat()).unwrap();
+                            finished.lock().unwrap().push(delay_millis);
+                            Ok::<_, io::Error>(delay_millis)
+                        }
+                    },
+                )
+                .unwrap();
+
+                let slow = handler.clone()(context::current(), ("a", 50));
+                let fast = handler(context::current(), ("a", 0));
+                await!(future::join(slow, fast))
+            }
+        };
+        let responses = runtime
+            .block_on(responses.unit_error().boxed().compat())
+            .unwrap();
+        assert_eq!((responses.0.unwrap(), responses.1.unwrap()), (50, 0));
+        assert_eq!(*finished.lock().unwrap(), vec![50, 0]);
+    }
+
+    #[test]
+    fn partitions_need_a_worker() {
+        let mut config = Config::default();
+        config.workers = 0;
+        let handler = partition(config, |_: &()| (), |_, ()| future::ready(Ok(())));
+        assert_eq!(handler.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn workers_outlive_handlers_that_panic() {
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        crate::init_thread(current_thread::TaskExecutor::current().compat());
+
+        let responses = async {
+            let mut config = Config::default();
+            config.workers = 1;
+            let handler = partition(
+                config,
+                |_: &bool| (),
+                |_, panic| async move {
+                    if panic {
+                        panic!("Boom.");
+                    }
+                    Ok::<_, io::Error>("ok")
+                },
+            )
+            .unwrap();
+
+            let panicked = await!(handler.clone()(context::current(), true));
+            let next = await!(handler(context::current(), false));
+            (panicked, next)
+        };
+        let (panicked, next) = runtime
+            .block_on(responses.unit_error().boxed().compat())
+            .unwrap();
+        let panicked = panicked.unwrap_err();
+        assert_eq!(
+            ServerError::of(&panicked).unwrap().code,
+            ErrorCode::Internal
+        );
+        assert_eq!(next.unwrap(), "ok");
+    }
+
+    #[test]
+    fn requests_beyond_the_queue_limit_are_overloaded() {
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        crate::init_thread(current_thread::TaskExecutor::current().compat());
+
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(Some(release_rx)));
+        let responses = async move {
+            let mut config = Config::default();
+            config.workers = 1;
+            config.max_queued_requests = 2;
+            let handler = partition(
+                config,
+                |_: &&'static str| (),
+                move |_, req| {
+                    let release = release_rx.lock().unwrap().take();
+                    async move {
+                        if let Some(release) = release {
+                            let _ = await!(release);
+                        }
+                        Ok::<_, io::Error>(req)
+                    }
+                },
+            )
+            .unwrap();
+
+            let first = handler.clone()(context::current(), "first");
+            let second = handler.clone()(context::current(), "second");
+            let rejected = await!(handler(context::current(), "rejected"));
+            release_tx.send(()).unwrap();
+            let (first, second) = await!(future::join(first, second));
+            (first, second, rejected)
+        };
+        let (first, second, rejected) = runtime
+            .block_on(responses.unit_error().boxed().compat())
+            .unwrap();
+        assert_eq!(first.unwrap(), "first");
+        assert_eq!(second.unwrap(), "second");
+        assert!(ServerError::is_overloaded(&rejected.unwrap_err()));
+    }
+
+    #[test]
+    fn worker_drops_requests_whose_caller_stops_waiting() {
+        let mut runtime = current_thread::Runtime::new().unwrap();
+        crate::init_thread(current_thread::TaskExecutor::current().compat());
+
+        let (started_tx, started_rx) = oneshot::channel::<()>();
+        let started_tx = Arc::new(Mutex::new(Some(started_tx)));
+        let response = async move {
+            let mut config = Config::default();
+            config.workers = 1;
+            let handler = partition(
+                config,
+                |_: &&'static str| (),
+                move |_, req| {
+                    let started_tx = started_tx.lock().unwrap().take();
+                    async move {
+                        if let Some(started_tx) = started_tx {
+                            started_tx.send(()).unwrap();
+                            // Only the caller can end this request.
+                            await!(future::pending::<()>());
+                        }
+                        Ok::<_, io::Error>(req)
+                    }
+                },
+            )
+            .unwrap();
+
+            let stuck = handler.clone()(context::current(), "stuck");
+            let stuck = await!(future::select(stuck, started_rx));
+            drop(stuck);
+            await!(handler(context::current(), "next"))
+        };
+        let response = runtime
+            .block_on(response.unit_error().boxed().compat())
+            .unwrap();
+        assert_eq!(response.unwrap(), "next");
+    }
+}
@@ -0,0 +1,554 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Topics that request handlers publish messages to, and the subscriptions that stream the
+//! messages to subscribers.
+//!
+//! A handler [subscribes](Topics::subscribe) the client of the current request to a topic, and
+//! returns the [`Subscription`], a stream of the topic's [events](Event), from a `stream` rpc, so
+//! that the client reads the messages published to the topic as the rpc's streamed response. The
+//! subscription ends when the client cancels the rpc or its connection closes, when the server
+//! [unsubscribes](Topics::unsubscribe) it or [closes](Topics::close) the topic, or when the
+//! subscriber falls too far behind.
+//!
+//! Messages published to a topic wait in each subscriber's buffer until the subscriber reads them.
+//! What happens when a buffer is full depends on the topic's [`Policy`], so that slow subscribers
+//! of high-rate topics don't force unbounded buffering: either the subscriber
+//! [lapses](Event::Lapsed), or its oldest messages are dropped, or only its latest message is
+//! kept.
+//!
+//! Each connection can hold up to
+//! [`max_subscriptions_per_connection`](Config::max_subscriptions_per_connection) subscriptions
+//! across all topics; further subscriptions are rejected with a
+//! [`QuotaExceeded`](ErrorCode::QuotaExceeded) error.
+
+use crate::{server::identity, ErrorCode, ServerError};
+use fnv::FnvHashMap;
+use futures::{
+    stream::Stream,
+    task::{Context, Poll, Waker},
+};
+use log::debug;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io, mem,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+use trace::ConnectionId;
+
+/// What a topic does with a message published while a subscriber's buffer is full.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Buffers up to this many messages per subscriber. A subscriber with a full buffer lapses:
+    /// its buffered messages are dropped, and its subscription ends with [`Event::Lapsed`].
+    Buffer(usize),
+    /// Buffers up to this many messages per subscriber, dropping the oldest to make room.
+    DropOldest(usize),
+    /// Keeps only the latest message per subscriber, for topics whose messages supersede each
+    /// other, e.g. the current value of a gauge.
+    CoalesceLatest,
+}
+
+/// Settings that control the behavior of [`Topics`].
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The policy of topics that weren't given their own.
+    pub default_policy: Policy,
+    /// The most subscriptions a connection can hold at once, across all topics.
+    pub max_subscriptions_per_connection: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            default_policy: Policy::Buffer(1_000),
+            max_subscriptions_per_connection: 100,
+        }
+    }
+}
+
+/// An item of a [`Subscription`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum Event<T> {
+    /// A message published to the topic.
+    Message(T),
+    /// The subscriber fell too far behind, so it was unsubscribed, and its buffered messages
+    /// dropped. Always the last event of a subscription.
+    Lapsed,
+}
+
+/// A subscriber of a topic, as enumerated by [`Topics::subscribers`].
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subscriber {
+    /// Identifies the subscription, e.g. to [unsubscribe](Topics::unsubscribe) it.
+    pub id: u64,
+    /// The connection the subscriber subscribed over, or `None` if it subscribed outside of a
+    /// request's handler.
+    pub connection: Option<ConnectionId>,
+    /// The identity the subscriber authenticated as, if its transport authenticated it.
+    pub identity: Option<Arc<String>>,
+    /// The number of messages waiting in the subscriber's buffer.
+    pub buffered: usize,
+    /// The number of messages dropped from the subscriber's buffer by the topic's policy.
+    pub dropped: u64,
+}
+
+/// Named topics and their subscribers. Clones share the same topics, so a single `Topics` can be
+/// used by all connections of a server.
+pub struct Topics<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> Clone for Topics<T> {
+    fn clone(&self) -> Self {
+        Topics {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Topics<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Topics")
+            .field("topics", &self.state.lock().unwrap().topics.len())
+            .finish()
+    }
+}
+
+struct State<T> {
+    config: Config,
+    policies: HashMap<String, Policy>,
+    /// The subscribers of each topic with any, by subscription ID.
+    topics: HashMap<String, FnvHashMap<u64, Entry<T>>>,
+    /// The number of subscriptions each connection holds, for connections that hold any.
+    connections: FnvHashMap<ConnectionId, usize>,
+    next_id: u64,
+}
+
+struct Entry<T> {
+    queue: Arc<Mutex<Queue<T>>>,
+    connection: Option<ConnectionId>,
+    identity: Option<Arc<String>>,
+}
+
+/// A subscriber's buffer, shared by the topic and the subscription.
+struct Queue<T> {
+    messages: VecDeque<T>,
+    status: Status,
+    dropped: u64,
+    waker: Option<Waker>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Subscribed,
+    /// The subscriber lapsed, but hasn't been told yet.
+    Lapsed,
+    Ended,
+}
+
+impl<T> Queue<T> {
+    fn end(&mut self, status: Status) {
+        self.messages.clear();
+        self.status = status;
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> State<T> {
+    /// Removes a subscriber, returning it if it was still subscribed.
+    fn remove(&mut self, topic: &str, id: u64) -> Option<Entry<T>> {
+        let subscribers = self.topics.get_mut(topic)?;
+        let entry = subscribers.remove(&id)?;
+        if subscribers.is_empty() {
+            self.topics.remove(topic);
+        }
+        if let Some(connection) = entry.connection {
+            if let Some(count) = self.connections.get_mut(&connection) {
+                *count -= 1;
+                if *count == 0 {
+                    self.connections.remove(&connection);
+                }
+            }
+        }
+        Some(entry)
+    }
+}
+
+impl<T> Topics<T> {
+    /// Returns an empty set of topics.
+    pub fn new(config: Config) -> Self {
+        Topics {
+            state: Arc::new(Mutex::new(State {
+                config,
+                policies: HashMap::new(),
+                topics: HashMap::new(),
+                connections: FnvHashMap::default(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    /// Sets the policy of `topic`. Takes effect for the messages published after.
+    pub fn set_policy(&self, topic: &str, policy: Policy) {
+        self.state
+            .lock()
+            .unwrap()
+            .policies
+            .insert(topic.to_string(), policy);
+    }
+
+    /// Subscribes to `topic` on behalf of the client of the current request, i.e. over its
+    /// connection, and as its identity. Fails if the connection already holds as many
+    /// subscriptions as it may.
+    pub fn subscribe(&self, topic: &str) -> io::Result<Subscription<T>> {
+        let connection = identity::connection();
+        let mut state = self.state.lock().unwrap();
+        if let Some(connection) = connection {
+            let max = state.config.max_subscriptions_per_connection;
+            let count = state.connections.entry(connection).or_insert(0);
+            if *count >= max {
+                return Err(ServerError::new(
+                    io::ErrorKind::WouldBlock,
+                    format!(
+                        "The connection already holds the most subscriptions it may, {}.",
+                        max
+                    ),
+                )
+                .with_code(ErrorCode::QuotaExceeded)
+                .into());
+            }
+            *count += 1;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        let queue = Arc::new(Mutex::new(Queue {
+            messages: VecDeque::new(),
+            status: Status::Subscribed,
+            dropped: 0,
+            waker: None,
+        }));
+        state.topics.entry(topic.to_string()).or_default().insert(
+            id,
+            Entry {
+                queue: queue.clone(),
+                connection,
+                identity: identity::current(),
+            },
+        );
+        Ok(Subscription {
+            id,
+            topic: topic.to_string(),
+            queue,
+            state: self.state.clone(),
+        })
+    }
+
+    /// Publishes `message` to the subscribers of `topic`, returning how many it was buffered for.
+    /// Subscribers that lapse because of it are unsubscribed.
+    pub fn publish(&self, topic: &str, message: T) -> usize
+    where
+        T: Clone,
+    {
+        let mut state = self.state.lock().unwrap();
+        let policy = state
+            .policies
+            .get(topic)
+            .cloned()
+            .unwrap_or(state.config.default_policy);
+        let subscribers = match state.topics.get(topic) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+        let mut buffered = 0;
+        let mut lapsed = vec![];
+        for (&id, entry) in subscribers {
+            let mut queue = entry.queue.lock().unwrap();
+            match policy {
+                Policy::Buffer(max) if queue.messages.len() >= max => {
+                    queue.dropped += queue.messages.len() as u64 + 1;
+                    queue.end(Status::Lapsed);
+                    lapsed.push(id);
+                    continue;
+                }
+                Policy::Buffer(_) => {}
+                Policy::DropOldest(max) => {
+                    while !queue.messages.is_empty() && queue.messages.len() >= max {
+                        queue.messages.pop_front();
+                        queue.dropped += 1;
+                    }
+                }
+                Policy::CoalesceLatest => {
+                    queue.dropped += queue.messages.len() as u64;
+                    queue.messages.clear();
+                }
+            }
+            queue.messages.push_back(message.clone());
+            queue.wake();
+            buffered += 1;
+        }
+        for id in lapsed {
+            debug!(
+                "Subscriber {} of topic {} fell behind, so it lapsed.",
+                id, topic
+            );
+            state.remove(topic, id);
+        }
+        buffered
+    }
+
+    /// Returns the subscribers of `topic`.
+    pub fn subscribers(&self, topic: &str) -> Vec<Subscriber> {
+        let state = self.state.lock().unwrap();
+        let subscribers = match state.topics.get(topic) {
+            Some(subscribers) => subscribers,
+            None => return vec![],
+        };
+        let mut subscribers: Vec<_> = subscribers
+            .iter()
+            .map(|(&id, entry)| {
+                let queue = entry.queue.lock().unwrap();
+                Subscriber {
+                    id,
+                    connection: entry.connection,
+                    identity: entry.identity.clone(),
+                    buffered: queue.messages.len(),
+                    dropped: queue.dropped,
+                }
+            })
+            .collect();
+        subscribers.sort_by_key(|subscriber| subscriber.id);
+        subscribers
+    }
+
+    /// Returns the topics with any subscribers.
+    pub fn topics(&self) -> Vec<String> {
+        let mut topics: Vec<_> = self.state.lock().unwrap().topics.keys().cloned().collect();
+        topics.sort();
+        topics
+    }
+
+    /// Ends subscription `id` to `topic`, once its subscriber has read the messages already
+    /// buffered for it. Returns false if it already ended.
+    pub fn unsubscribe(&self, topic: &str, id: u64) -> bool {
+        match self.state.lock().unwrap().remove(topic, id) {
+            Some(entry) => {
+                let mut queue = entry.queue.lock().unwrap();
+                queue.status = Status::Ended;
+                queue.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Ends every subscription to `topic`, once its subscriber has read the messages already
+    /// buffered for it, and forgets the topic's policy. Returns the number of subscriptions ended.
+    pub fn close(&self, topic: &str) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.policies.remove(topic);
+        let ids: Vec<_> = match state.topics.get(topic) {
+            Some(subscribers) => subscribers.keys().cloned().collect(),
+            None => return 0,
+        };
+        for &id in &ids {
+            if let Some(entry) = state.remove(topic, id) {
+                let mut queue = entry.queue.lock().unwrap();
+                queue.status = Status::Ended;
+                queue.wake();
+            }
+        }
+        ids.len()
+    }
+}
+
+/// A subscription to a topic: a stream of the messages published to the topic after it was made.
+/// Dropping it unsubscribes.
+pub struct Subscription<T> {
+    id: u64,
+    topic: String,
+    queue: Arc<Mutex<Queue<T>>>,
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T> fmt::Debug for Subscription<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("id", &self.id)
+            .field("topic", &self.topic)
+            .finish()
+    }
+}
+
+impl<T> Subscription<T> {
+    /// Returns the ID of the subscription, as enumerated by [`Topics::subscribers`].
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the topic subscribed to.
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+impl<T> Stream for Subscription<T> {
+    type Item = Event<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event<T>>> {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(message) = queue.messages.pop_front() {
+            return Poll::Ready(Some(Event::Message(message)));
+        }
+        match mem::replace(&mut queue.status, Status::Ended) {
+            Status::Subscribed => {
+                queue.status = Status::Subscribed;
+                queue.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Status::Lapsed => Poll::Ready(Some(Event::Lapsed)),
+            Status::Ended => Poll::Ready(None),
+        }
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().remove(&self.topic, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Event, Policy, Subscription, Topics};
+    use crate::{server::identity, ErrorCode, ServerError};
+    use futures::{executor::block_on, future, prelude::*, task::Poll};
+    use std::io;
+    use trace::ConnectionId;
+
+    /// Returns the events buffered for `subscription`, and whether it ended.
+    fn drain(subscription: &mut Subscription<u32>) -> (Vec<Event<u32>>, bool) {
+        let mut events = vec![];
+        loop {
+            let next = future::poll_fn(|cx| Poll::Ready(subscription.poll_next_unpin(cx)));
+            match block_on(next) {
+                Poll::Ready(Some(event)) => events.push(event),
+                Poll::Ready(None) => return (events, true),
+                Poll::Pending => return (events, false),
+            }
+        }
+    }
+
+    fn messages(range: std::ops::Range<u32>) -> Vec<Event<u32>> {
+        range.map(Event::Message).collect()
+    }
+
+    #[test]
+    fn buffered_subscribers_lapse_when_full() {
+        let topics = Topics::new(Config::default());
+        topics.set_policy("t", Policy::Buffer(2));
+        let mut slow = topics.subscribe("t").unwrap();
+        let mut fast = topics.subscribe("t").unwrap();
+        assert_eq!(topics.publish("t", 0), 2);
+        assert_eq!(topics.publish("t", 1), 2);
+        assert_eq!(drain(&mut fast), (messages(0..2), false));
+        assert_eq!(topics.publish("t", 2), 1);
+        assert_eq!(drain(&mut slow), (vec![Event::Lapsed], true));
+        assert_eq!(drain(&mut fast), (messages(2..3), false));
+        let subscribers = topics.subscribers("t");
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(subscribers[0].id, fast.id());
+    }
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_messages() {
+        let topics = Topics::new(Config::default());
+        topics.set_policy("t", Policy::DropOldest(2));
+        let mut subscription = topics.subscribe("t").unwrap();
+        for i in 0..5 {
+            topics.publish("t", i);
+        }
+        assert_eq!(topics.subscribers("t")[0].dropped, 3);
+        assert_eq!(drain(&mut subscription), (messages(3..5), false));
+    }
+
+    #[test]
+    fn coalesce_latest_keeps_the_latest_message() {
+        let topics = Topics::new(Config::default());
+        topics.set_policy("t", Policy::CoalesceLatest);
+        let mut subscription = topics.subscribe("t").unwrap();
+        for i in 0..5 {
+            topics.publish("t", i);
+        }
+        assert_eq!(drain(&mut subscription), (messages(4..5), false));
+    }
+
+    #[test]
+    fn dropped_subscriptions_unsubscribe() {
+        let topics = Topics::<u32>::new(Config::default());
+        let a = topics.subscribe("a").unwrap();
+        let b = topics.subscribe("b").unwrap();
+        assert_eq!(topics.topics(), vec!["a".to_string(), "b".to_string()]);
+        drop(a);
+        assert_eq!(topics.topics(), vec!["b".to_string()]);
+        assert!(topics.subscribers("a").is_empty());
+        assert_eq!(topics.publish("a", 0), 0);
+        assert!(topics.unsubscribe("b", b.id()));
+        assert!(!topics.unsubscribe("b", b.id()));
+    }
+
+    #[test]
+    fn closed_topics_end_subscriptions_after_buffered_messages() {
+        let topics = Topics::new(Config::default());
+        let mut subscription = topics.subscribe("t").unwrap();
+        topics.publish("t", 0);
+        assert_eq!(topics.close("t"), 1);
+        assert_eq!(drain(&mut subscription), (messages(0..1), true));
+        assert!(topics.topics().is_empty());
+    }
+
+    #[test]
+    fn subscriptions_are_limited_per_connection() {
+        let mut config = Config::default();
+        config.max_subscriptions_per_connection = 2;
+        let topics = Topics::<u32>::new(config);
+        let connection = ConnectionId::random(&mut rand::thread_rng());
+        let subscribe = |topic: &'static str| {
+            let topics = topics.clone();
+            let subscribe = future::lazy(move |_| topics.subscribe(topic));
+            block_on(identity::scope(connection, None, None, None, subscribe))
+        };
+        let a = subscribe("a").unwrap();
+        let _b = subscribe("b").unwrap();
+        let e = subscribe("c").unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        let code = e
+            .get_ref()
+            .unwrap()
+            .downcast_ref::<ServerError>()
+            .unwrap()
+            .code;
+        assert_eq!(code, ErrorCode::QuotaExceeded);
+        assert_eq!(topics.subscribers("a")[0].connection, Some(connection));
+
+        // Ending a subscription makes room for another.
+        drop(a);
+        subscribe("c").unwrap();
+        // Subscriptions outside of handlers aren't limited.
+        topics.subscribe("d").unwrap();
+    }
+}
@@ -0,0 +1,394 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Per-identity request quotas and usage accounting.
+//!
+//! A [`Quotas`] tracks how many requests, and how many request bytes, each identity has sent in the
+//! current accounting period. Wrapping a request handler with [`enforce`] charges every request to
+//! the identity that sent it, and rejects requests from identities that are over quota, with a
+//! [`QuotaExceeded`](ErrorCode::QuotaExceeded) error.
+//!
+//! An identity that sends no requests for a whole period of its quota has nothing left to charge
+//! in the current period, so its account is eventually dropped, along with its totals, to keep
+//! identities that come and go from piling up.
+
+use crate::{
+    context,
+    util::{elapsed_since, Compact},
+    ErrorCode, ServerError,
+};
+use fnv::FnvHashMap;
+use futures::future::{self, Either, Ready};
+use log::debug;
+use std::{
+    fmt,
+    future::Future,
+    hash::Hash,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Limits on the usage of a single identity within one accounting period.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    /// The maximum number of requests per period.
+    pub max_requests: u64,
+    /// The maximum number of request bytes per period.
+    pub max_bytes: u64,
+    /// The length of an accounting period. Usage is reset at the start of every period.
+    pub period: Duration,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Quota {
+            max_requests: u64::max_value(),
+            max_bytes: u64::max_value(),
+            period: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The usage of a single identity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// The number of requests accepted in the current period.
+    pub requests: u64,
+    /// The number of request bytes accepted in the current period.
+    pub bytes: u64,
+    /// The number of requests rejected in the current period for exceeding the quota.
+    pub rejected_requests: u64,
+    /// The total number of requests accepted across all periods.
+    pub total_requests: u64,
+    /// The total number of request bytes accepted across all periods.
+    pub total_bytes: u64,
+}
+
+/// Tracks usage and enforces quotas for a set of identities. Clones share the same state, so a
+/// single `Quotas` can be used across all connections of a server.
+pub struct Quotas<K> {
+    state: Arc<Mutex<State<K>>>,
+}
+
+impl<K> Clone for Quotas<K> {
+    fn clone(&self) -> Self {
+        Quotas {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<K> fmt::Debug for Quotas<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Quotas").finish()
+    }
+}
+
+struct State<K> {
+    default_quota: Quota,
+    quotas: FnvHashMap<K, Quota>,
+    accounts: FnvHashMap<K, Account>,
+    /// The number of accounts at which idle accounts are next dropped.
+    next_sweep: usize,
+}
+
+struct Account {
+    period_start: Instant,
+    /// When the account was last charged a request.
+    last_charged: Instant,
+    usage: Usage,
+}
+
+/// The fewest accounts at which idle accounts are dropped.
+const MIN_SWEEP: usize = 64;
+
+impl<K> Quotas<K>
+where
+    K: Hash + Eq + Clone,
+{
+    /// Returns a new tracker that applies `default_quota` to every identity without its own quota.
+    pub fn new(default_quota: Quota) -> Self {
+        Quotas {
+            state: Arc::new(Mutex::new(State {
+                default_quota,
+                quotas: FnvHashMap::default(),
+                accounts: FnvHashMap::default(),
+                next_sweep: MIN_SWEEP,
+            })),
+        }
+    }
+
+    /// Sets the quota of `identity`, overriding the default quota.
+    pub fn set_quota(&self, identity: K, quota: Quota) {
+        self.state.lock().unwrap().quotas.insert(identity, quota);
+    }
+
+    /// Charges a request of size `bytes` to `identity`. Returns an error, without charging the
+    /// request, if it would put `identity` over its quota. The error asks the client to retry
+    /// once the current period is over.
+    pub fn charge(&self, identity: &K, bytes: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        // Read the clock under the lock, so that no other charge sees a later time first.
+        state.charge(identity, bytes, Instant::now())
+    }
+
+    #[cfg(test)]
+    fn charge_at(&self, identity: &K, bytes: u64, now: Instant) -> io::Result<()> {
+        self.state.lock().unwrap().charge(identity, bytes, now)
+    }
+
+    /// Returns the number of identities with an account.
+    #[cfg(test)]
+    fn accounts(&self) -> usize {
+        self.state.lock().unwrap().accounts.len()
+    }
+
+    /// Returns the usage of `identity`, if it has sent any requests.
+    pub fn usage(&self, identity: &K) -> Option<Usage> {
+        self.state
+            .lock()
+            .unwrap()
+            .accounts
+            .get(identity)
+            .map(|account| account.usage)
+    }
+
+    /// Returns the usage of every identity that has sent requests.
+    pub fn stats(&self) -> Vec<(K, Usage)> {
+        self.state
+            .lock()
+            .unwrap()
+            .accounts
+            .iter()
+            .map(|(identity, account)| (identity.clone(), account.usage))
+            .collect()
+    }
+}
+
+impl<K> State<K>
+where
+    K: Hash + Eq + Clone,
+{
+    fn quota(&self, identity: &K) -> Quota {
+        *self.quotas.get(identity).unwrap_or(&self.default_quota)
+    }
+
+    fn charge(&mut self, identity: &K, bytes: u64, now: Instant) -> io::Result<()> {
+        if self.accounts.len() >= self.next_sweep {
+            self.drop_idle_accounts(now);
+        }
+
+        let quota = self.quota(identity);
+        let account = self
+            .accounts
+            .entry(identity.clone())
+            .or_insert_with(|| Account {
+                period_start: now,
+                last_charged: now,
+                usage: Usage::default(),
+            });
+
+        if elapsed_since(now, account.period_start) >= quota.period {
+            account.period_start = now;
+            account.usage.requests = 0;
+            account.usage.bytes = 0;
+            account.usage.rejected_requests = 0;
+        }
+        account.last_charged = now;
+
+        let period_remaining = quota
+            .period
+            .checked_sub(elapsed_since(now, account.period_start))
+            .unwrap_or_default();
+        let usage = &mut account.usage;
+        if usage.requests >= quota.max_requests
+            || usage.bytes.saturating_add(bytes) > quota.max_bytes
+        {
+            usage.rejected_requests += 1;
+            let error = ServerError::new(
+                io::ErrorKind::WouldBlock,
+                format!(
+                    "Quota exceeded ({}/{} requests, {}/{} bytes).",
+                    usage.requests, quota.max_requests, usage.bytes, quota.max_bytes
+                ),
+            )
+            .with_code(ErrorCode::QuotaExceeded);
+            return Err(error.with_retry_after(period_remaining).into());
+        }
+
+        usage.requests += 1;
+        usage.bytes += bytes;
+        usage.total_requests += 1;
+        usage.total_bytes += bytes;
+        Ok(())
+    }
+
+    /// Drops the accounts of identities that haven't been charged for a whole period of their
+    /// quota. Runs again once the number of accounts doubles, so that charges stay cheap.
+    fn drop_idle_accounts(&mut self, now: Instant) {
+        let idle: Vec<K> = self
+            .accounts
+            .iter()
+            .filter(|(identity, account)| {
+                elapsed_since(now, account.last_charged) >= self.quota(identity).period
+            })
+            .map(|(identity, _)| identity.clone())
+            .collect();
+        for identity in &idle {
+            self.accounts.remove(identity);
+        }
+        self.accounts.compact(0.1);
+        debug!(
+            "Dropped {} idle quota accounts, {} left.",
+            idle.len(),
+            self.accounts.len()
+        );
+        self.next_sweep = (self.accounts.len() * 2).max(MIN_SWEEP);
+    }
+}
+
+/// The future returned by a request handler wrapped with [`enforce`].
+pub type Enforced<Fut, Resp> = Either<Fut, Ready<io::Result<Resp>>>;
+
+/// Wraps request handler `f` so that every request is charged to an identity in `quotas`.
+///
+/// `identify` returns the identity that sent a request along with the request's size in bytes.
+/// Requests that would put their identity over quota are rejected with a
+/// [`QuotaExceeded`](ErrorCode::QuotaExceeded) error, of type
+/// [`WouldBlock`](io::ErrorKind::WouldBlock), without being passed to `f`.
+pub fn enforce<K, Req, Resp, I, F, Fut>(
+    quotas: Quotas<K>,
+    identify: I,
+    f: F,
+) -> impl FnOnce(context::Context, Req) -> Enforced<Fut, Resp> + Send + 'static + Clone
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    I: Fn(&context::Context, &Req) -> (K, u64) + Send + 'static + Clone,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    move |ctx, req| {
+        let (identity, bytes) = identify(&ctx, &req);
+        match quotas.charge(&identity, bytes) {
+            Ok(()) => Either::Left(f(ctx, req)),
+            Err(e) => {
+                debug!("[{}] Rejecting request: {}", ctx.trace_id(), e);
+                Either::Right(future::ready(Err(e)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quota, Quotas, MIN_SWEEP};
+    use crate::{ErrorCode, ServerError};
+    use std::{
+        io,
+        time::{Duration, Instant},
+    };
+
+    fn quota(max_requests: u64, max_bytes: u64) -> Quota {
+        Quota {
+            max_requests,
+            max_bytes,
+            period: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn charges_usage() {
+        let quotas = Quotas::new(Quota::default());
+        quotas.charge(&"alice", 10).unwrap();
+        quotas.charge(&"alice", 5).unwrap();
+
+        let usage = quotas.usage(&"alice").unwrap();
+        assert_eq!(usage.requests, 2);
+        assert_eq!(usage.bytes, 15);
+        assert!(quotas.usage(&"bob").is_none());
+    }
+
+    #[test]
+    fn rejects_over_quota() {
+        let quotas = Quotas::new(quota(1, 100));
+        quotas.charge(&"alice", 10).unwrap();
+
+        let e = quotas.charge(&"alice", 10).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        let e = e.get_ref().unwrap().downcast_ref::<ServerError>().unwrap();
+        assert_eq!(e.code, ErrorCode::QuotaExceeded);
+        assert!(e.retry_after.unwrap() <= Duration::from_secs(1));
+        assert_eq!(quotas.usage(&"alice").unwrap().rejected_requests, 1);
+
+        // Other identities have their own accounts.
+        quotas.charge(&"bob", 10).unwrap();
+    }
+
+    #[test]
+    fn rejects_over_byte_quota() {
+        let quotas = Quotas::new(quota(100, 10));
+        assert!(quotas.charge(&"alice", 11).is_err());
+        assert!(quotas.charge(&"alice", 10).is_ok());
+    }
+
+    #[test]
+    fn per_identity_quota_overrides_default() {
+        let quotas = Quotas::new(quota(1, 100));
+        quotas.set_quota("alice", quota(2, 100));
+        quotas.charge(&"alice", 0).unwrap();
+        quotas.charge(&"alice", 0).unwrap();
+        assert!(quotas.charge(&"alice", 0).is_err());
+    }
+
+    #[test]
+    fn resets_each_period() {
+        let quotas = Quotas::new(quota(1, 100));
+        let start = Instant::now();
+        quotas.charge_at(&"alice", 10, start).unwrap();
+        assert!(quotas.charge_at(&"alice", 10, start).is_err());
+        quotas
+            .charge_at(&"alice", 10, start + Duration::from_secs(1))
+            .unwrap();
+
+        let usage = quotas.usage(&"alice").unwrap();
+        assert_eq!(usage.requests, 1);
+        assert_eq!(usage.total_requests, 2);
+        assert_eq!(usage.total_bytes, 20);
+    }
+
+    #[test]
+    fn charges_out_of_order_times() {
+        let quotas = Quotas::new(quota(10, 100));
+        let start = Instant::now();
+        quotas
+            .charge_at(&"alice", 1, start + Duration::from_secs(2))
+            .unwrap();
+        // A charge that read the clock before the one above was recorded.
+        quotas.charge_at(&"alice", 1, start).unwrap();
+        assert_eq!(quotas.usage(&"alice").unwrap().requests, 2);
+    }
+
+    #[test]
+    fn drops_idle_accounts() {
+        let quotas = Quotas::new(quota(10, 100));
+        let start = Instant::now();
+        for identity in 1..MIN_SWEEP {
+            quotas.charge_at(&identity, 1, start).unwrap();
+        }
+        quotas
+            .charge_at(&0, 1, start + Duration::from_millis(500))
+            .unwrap();
+        // There are enough accounts to look for idle ones before this charge.
+        quotas
+            .charge_at(&0, 1, start + Duration::from_secs(1))
+            .unwrap();
+        // Only the account charged within the last period is left.
+        assert_eq!(quotas.accounts(), 1);
+        assert_eq!(quotas.usage(&0).unwrap().total_requests, 2);
+    }
+}
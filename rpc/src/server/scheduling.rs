@@ -0,0 +1,350 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Interleaves request handlers across connections.
+//!
+//! When the server limits how many handlers run at once, with
+//! [`max_concurrent_handlers`](crate::server::Config::max_concurrent_handlers), requests over the
+//! limit wait for a running handler to finish, and the [`Scheduling`] policy decides which waiting
+//! request runs next. The limit is shared by every connection of a server. With
+//! [`max_waiting_handlers`](crate::server::Config::max_waiting_handlers), requests that would
+//! wait behind too many others are rejected as [overloaded](crate::ErrorCode::Overloaded)
+//! instead.
+
+use fnv::FnvHashMap;
+use futures::{
+    channel::oneshot,
+    prelude::*,
+    ready,
+    task::{Context, Poll},
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+use trace::ConnectionId;
+
+/// Which waiting request a server runs next, once a handler finishes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheduling {
+    /// Run requests in the order they arrived in, regardless of connection.
+    Fifo,
+    /// Take turns between connections with waiting requests, so that a connection that sends
+    /// many requests at once doesn't hold up the others.
+    RoundRobin,
+}
+
+/// Runs handlers under a limit shared by all of a server's connections.
+#[derive(Clone, Debug)]
+pub(crate) struct Scheduler {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    scheduling: Scheduling,
+    max_running: usize,
+    max_waiting: Option<usize>,
+    running: usize,
+    /// The requests waiting to run, in arrival order, if scheduling first in, first out.
+    arrivals: VecDeque<oneshot::Sender<()>>,
+    /// The requests waiting to run on each connection, if round robin.
+    connections: FnvHashMap<ConnectionId, VecDeque<oneshot::Sender<()>>>,
+    /// The connections with waiting requests, in the order they're next in turn.
+    turns: VecDeque<ConnectionId>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(
+        max_running: usize,
+        max_waiting: Option<usize>,
+        scheduling: Scheduling,
+    ) -> Self {
+        Scheduler {
+            state: Arc::new(Mutex::new(State {
+                scheduling,
+                max_running,
+                max_waiting,
+                running: 0,
+                arrivals: VecDeque::new(),
+                connections: FnvHashMap::default(),
+                turns: VecDeque::new(),
+            })),
+        }
+    }
+
+    /// Returns the request's turn, which is sent a permit to run when it comes, or `None` if the
+    /// request would wait behind as many requests as may wait.
+    pub(crate) fn wait(&self, connection: ConnectionId) -> Option<Turn> {
+        let (turn_tx, turn) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+        if state.running < state.max_running {
+            state.running += 1;
+            let _ = turn_tx.send(());
+            return Some(Turn {
+                scheduler: self.clone(),
+                turn,
+            });
+        }
+        if let Some(max_waiting) = state.max_waiting {
+            if state.waiting() >= max_waiting {
+                state.prune();
+                if state.waiting() >= max_waiting {
+                    return None;
+                }
+            }
+        }
+        match state.scheduling {
+            Scheduling::Fifo => state.arrivals.push_back(turn_tx),
+            Scheduling::RoundRobin => {
+                let waiting = state.connections.entry(connection).or_default();
+                waiting.push_back(turn_tx);
+                if waiting.len() == 1 {
+                    state.turns.push_back(connection);
+                }
+            }
+        }
+        Some(Turn {
+            scheduler: self.clone(),
+            turn,
+        })
+    }
+
+    /// Hands the permit of a finished handler to the next waiting request.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(next) = state.next() {
+            // Requests dropped while waiting have closed their receivers.
+            if next.send(()).is_ok() {
+                return;
+            }
+        }
+        state.running -= 1;
+    }
+}
+
+impl State {
+    /// Returns the number of requests waiting to run, including any dropped while waiting that
+    /// haven't been pruned yet.
+    fn waiting(&self) -> usize {
+        self.arrivals.len() + self.connections.values().map(VecDeque::len).sum::<usize>()
+    }
+
+    /// Forgets the requests dropped while waiting.
+    fn prune(&mut self) {
+        self.arrivals.retain(|turn| !turn.is_canceled());
+        for waiting in self.connections.values_mut() {
+            waiting.retain(|turn| !turn.is_canceled());
+        }
+        self.connections.retain(|_, waiting| !waiting.is_empty());
+        let connections = &self.connections;
+        self.turns
+            .retain(|connection| connections.contains_key(connection));
+    }
+
+    fn next(&mut self) -> Option<oneshot::Sender<()>> {
+        match self.scheduling {
+            Scheduling::Fifo => self.arrivals.pop_front(),
+            Scheduling::RoundRobin => {
+                let connection = self.turns.pop_front()?;
+                let waiting = self
+                    .connections
+                    .get_mut(&connection)
+                    .expect("Connections in turn have waiting requests.");
+                let next = waiting.pop_front();
+                if waiting.is_empty() {
+                    self.connections.remove(&connection);
+                } else {
+                    self.turns.push_back(connection);
+                }
+                next
+            }
+        }
+    }
+}
+
+/// Releases a handler's permit to run when dropped.
+#[derive(Debug)]
+struct Permit(Scheduler);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+/// A request's turn to run. Gives up the turn when dropped, passing it on if it already came.
+#[derive(Debug)]
+pub(crate) struct Turn {
+    scheduler: Scheduler,
+    turn: oneshot::Receiver<()>,
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.turn.close();
+        // The turn came after the receiver was last polled.
+        if let Ok(Some(())) = self.turn.try_recv() {
+            self.scheduler.release();
+        }
+    }
+}
+
+/// A handler future that waits for its turn to run, if the server has a scheduler.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub(crate) struct Scheduled<Fut> {
+    /// Set while waiting for the request's turn.
+    turn: Option<Turn>,
+    permit: Option<Permit>,
+    future: Fut,
+}
+
+impl<Fut> Scheduled<Fut> {
+    unsafe_unpinned!(turn: Option<Turn>);
+    unsafe_unpinned!(permit: Option<Permit>);
+    unsafe_pinned!(future: Fut);
+
+    /// Returns a future that runs `future` once its `turn` comes, or right away if the server has
+    /// no scheduler.
+    pub(crate) fn new(turn: Option<Turn>, future: Fut) -> Self {
+        Scheduled {
+            turn,
+            permit: None,
+            future,
+        }
+    }
+}
+
+impl<Fut: Future> Future for Scheduled<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Fut::Output> {
+        if let Some(turn) = self.as_mut().turn() {
+            // The scheduler never drops a sender without sending.
+            let _ = ready!(turn.turn.poll_unpin(cx));
+            let scheduler = turn.scheduler.clone();
+            // The turn was taken, so dropping it doesn't pass it on.
+            *self.as_mut().turn() = None;
+            *self.as_mut().permit() = Some(Permit(scheduler));
+        }
+        self.as_mut().future().poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scheduler, Scheduling, Turn};
+    use crate::{
+        client, context,
+        server::{self, Handler},
+        test_util, transport, ServerError,
+    };
+    use futures::{channel::oneshot, future, prelude::*, stream};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+    use trace::ConnectionId;
+
+    fn turns_taken(scheduling: Scheduling) -> Vec<&'static str> {
+        let scheduler = Scheduler::new(1, None, scheduling);
+        let mut rng = rand::thread_rng();
+        let (a, b) = (
+            ConnectionId::random(&mut rng),
+            ConnectionId::random(&mut rng),
+        );
+        let mut running = scheduler.wait(a).unwrap();
+        assert_eq!(running.turn.try_recv(), Ok(Some(())));
+
+        let mut waiting: Vec<(&str, Turn)> = vec![
+            ("a2", scheduler.wait(a).unwrap()),
+            ("a3", scheduler.wait(a).unwrap()),
+            ("b1", scheduler.wait(b).unwrap()),
+        ];
+        let mut turns = vec![];
+        while !waiting.is_empty() {
+            scheduler.release();
+            let i = waiting
+                .iter_mut()
+                .position(|(_, turn)| turn.turn.try_recv() == Ok(Some(())))
+                .unwrap();
+            turns.push(waiting.remove(i).0);
+        }
+        turns
+    }
+
+    #[test]
+    fn fifo_runs_requests_in_arrival_order() {
+        assert_eq!(turns_taken(Scheduling::Fifo), vec!["a2", "a3", "b1"]);
+    }
+
+    #[test]
+    fn round_robin_takes_turns_between_connections() {
+        assert_eq!(turns_taken(Scheduling::RoundRobin), vec!["a2", "b1", "a3"]);
+    }
+
+    #[test]
+    fn rejects_requests_beyond_the_waiting_limit() {
+        let scheduler = Scheduler::new(1, Some(1), Scheduling::RoundRobin);
+        let connection = ConnectionId::random(&mut rand::thread_rng());
+        let _running = scheduler.wait(connection).unwrap();
+        let waiting = scheduler.wait(connection).unwrap();
+        assert!(scheduler.wait(connection).is_none());
+
+        // Requests dropped while waiting make room.
+        drop(waiting);
+        let _waiting = scheduler.wait(connection).unwrap();
+        assert!(scheduler.wait(connection).is_none());
+    }
+
+    #[test]
+    fn requests_beyond_the_waiting_limit_are_overloaded() {
+        test_util::init();
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(Some(release_rx)));
+        let mut config = server::Config::default();
+        config.max_concurrent_handlers = Some(1);
+        config.max_waiting_handlers = Some(0);
+        let server = server::new::<String, String>(config)
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(move |_ctx, request| {
+                let release = release_rx.lock().unwrap().take();
+                async move {
+                    if let Some(release) = release {
+                        let _ = await!(release);
+                    }
+                    Ok(request)
+                }
+            });
+
+        let responses = async {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            let slow = client.start_call(context::current(), "slow".into())?;
+            let rejected = await!(client.call(context::current(), "rejected".into()));
+            release_tx.send(()).unwrap();
+            let slow = await!(slow);
+            // The handler finished, so the next request runs.
+            let next = await!(client.call(context::current(), "next".into()));
+            Ok::<_, io::Error>((slow, rejected, next))
+        };
+
+        let (slow, rejected, next) = test_util::run_future(future::join(
+            server,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .1;
+        assert_eq!(slow.unwrap(), "slow");
+        let rejected = rejected.unwrap_err();
+        assert!(ServerError::is_overloaded(&rejected));
+        assert_eq!(rejected.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(next.unwrap(), "next");
+    }
+}
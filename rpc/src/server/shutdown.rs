@@ -0,0 +1,186 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{server::ConnectionHandle, CloseReason};
+use fnv::FnvHashMap;
+use futures::{
+    channel::oneshot,
+    compat::Future01CompatExt,
+    future::{self, Either},
+    pin_mut,
+    task::Waker,
+};
+use log::{info, warn};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio_timer::Delay;
+use trace::ConnectionId;
+
+/// A handle to a running server that can shut it down gracefully, e.g. before a restart. Obtained
+/// from [`Running::shutdown_handle`](crate::server::Running::shutdown_handle).
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownHandle {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    shutting_down: bool,
+    /// The open connections.
+    connections: FnvHashMap<ConnectionId, ConnectionHandle>,
+    /// Wakes the server to stop accepting connections.
+    server: Option<Waker>,
+    /// Notified once every connection is closed.
+    closed: Vec<oneshot::Sender<()>>,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting connections and drains the open ones: each stops reading requests and
+    /// closes after responding to the requests already in flight. Connections still open after
+    /// `grace_period` are closed right away, canceling their requests.
+    ///
+    /// Resolves once every connection is closed.
+    pub async fn shutdown(self, grace_period: Duration) {
+        let closed = {
+            let mut state = self.state.lock().unwrap();
+            state.shutting_down = true;
+            if let Some(server) = state.server.take() {
+                server.wake();
+            }
+            info!(
+                "Draining {} connections before shutting down.",
+                state.connections.len()
+            );
+            for connection in state.connections.values() {
+                connection.drain();
+            }
+            self.closed(&mut state)
+        };
+        let grace_period = Delay::new(Instant::now() + grace_period).compat();
+        pin_mut!(closed);
+        pin_mut!(grace_period);
+        if let Either::Right(_) = await!(future::select(closed, grace_period)) {
+            let closed = {
+                let mut state = self.state.lock().unwrap();
+                warn!(
+                    "Closing {} connections still open after the grace period.",
+                    state.connections.len()
+                );
+                for connection in state.connections.values() {
+                    connection.close_with(CloseReason::Shutdown);
+                }
+                self.closed(&mut state)
+            };
+            let _ = await!(closed);
+        }
+    }
+
+    /// Returns a future that resolves once every connection is closed.
+    fn closed(&self, state: &mut State) -> oneshot::Receiver<()> {
+        let (closed_tx, closed) = oneshot::channel();
+        if state.connections.is_empty() {
+            let _ = closed_tx.send(());
+        } else {
+            state.closed.push(closed_tx);
+        }
+        closed
+    }
+
+    /// Returns a future that resolves once every connection is closed, e.g. after the server
+    /// stopped accepting connections.
+    pub(crate) fn connections_closed(&self) -> oneshot::Receiver<()> {
+        let mut state = self.state.lock().unwrap();
+        self.closed(&mut state)
+    }
+
+    /// Returns true if the server should stop accepting connections. Otherwise, wakes the server
+    /// with `waker` once it should.
+    pub(crate) fn is_shutting_down(&self, waker: &Waker) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.shutting_down {
+            state.server = Some(waker.clone());
+        }
+        state.shutting_down
+    }
+
+    /// Tracks a newly accepted connection.
+    pub(crate) fn opened(&self, connection: ConnectionHandle) {
+        let mut state = self.state.lock().unwrap();
+        // Accepted just as the server started shutting down.
+        if state.shutting_down {
+            connection.drain();
+        }
+        state
+            .connections
+            .insert(*connection.connection_id(), connection);
+    }
+
+    /// Stops tracking a connection that closed.
+    pub(crate) fn closed_connection(&self, connection_id: &ConnectionId) {
+        let mut state = self.state.lock().unwrap();
+        state.connections.remove(connection_id);
+        if state.connections.is_empty() {
+            for closed in state.closed.drain(..) {
+                let _ = closed.send(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{client, context, server::Handler, test_util, transport, Server};
+    use futures::{channel::mpsc, compat::Future01CompatExt, future, prelude::*};
+    use std::{
+        io,
+        time::{Duration, Instant},
+    };
+    use tokio_timer::Delay;
+
+    #[test]
+    fn shutdown_drains_connections_before_resolving() {
+        test_util::init();
+
+        let (server_channels_tx, server_channels_rx) = mpsc::unbounded();
+        let (started_tx, mut started_rx) = mpsc::unbounded();
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        server_channels_tx.unbounded_send(server_channel).unwrap();
+        let server = Server::<String, String>::default()
+            .incoming(server_channels_rx.map(Ok))
+            .respond_with(move |_ctx, request| {
+                started_tx.unbounded_send(()).unwrap();
+                Delay::new(Instant::now() + Duration::from_millis(50))
+                    .compat()
+                    .map(|_| Ok(request))
+            });
+        let shutdown = server.shutdown_handle();
+
+        let responses = async move {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?;
+            let mut in_flight = client.clone();
+            let call = in_flight.call(context::current(), "hi".into());
+            let shutdown = async move {
+                await!(started_rx.next()).unwrap();
+                await!(shutdown.shutdown(Duration::from_secs(10)));
+            };
+            let (response1, ()) = await!(future::join(call, shutdown));
+            // The connection closed once the request in flight was answered.
+            let response2 = await!(client.call(context::current(), "bye".into()));
+            drop(server_channels_tx);
+            Ok::<_, io::Error>((response1, response2))
+        };
+
+        let (response1, response2) = test_util::run_future(future::join(
+            server,
+            responses.unwrap_or_else(|e| panic!(e)),
+        ))
+        .1;
+        assert_eq!(response1.unwrap(), "hi");
+        assert!(response2.is_err());
+    }
+}
@@ -0,0 +1,491 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Per-method service-level objectives.
+//!
+//! An [`Objective`] sets a latency target for a method, along with an error budget: the fraction
+//! of requests that can fail or miss the target. [`Slos`] tracks how fast each method is burning
+//! through its budget in the current window. Wrapping a request handler with [`enforce_slos`]
+//! records every response against its method's objective, and, when a method's budget is burning
+//! too fast, sheds a share of the method's requests until it recovers.
+
+use crate::{context, server::quota::Enforced, util::elapsed_since, ServerError};
+use fnv::FnvHashMap;
+use futures::{
+    future::{self, Either},
+    ready,
+    task::{Context, Poll},
+};
+use log::debug;
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The objective of a single method.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Objective {
+    /// Requests that take longer than this to respond to miss the objective, as do requests that
+    /// fail.
+    pub target_latency: Duration,
+    /// The fraction of requests that can miss the objective, e.g. `0.001` for an objective of
+    /// 99.9%.
+    pub error_budget: f64,
+    /// The length of a measurement window. Counts are reset at the start of every window.
+    pub window: Duration,
+    /// If set, requests are shed once the budget burns this many times faster than it allows.
+    /// The faster the burn, the larger the share of requests shed. To avoid reacting to a single
+    /// miss, nothing is shed until the window has counted enough requests for one miss to be
+    /// within budget.
+    pub max_burn_rate: Option<f64>,
+}
+
+impl Default for Objective {
+    fn default() -> Self {
+        Objective {
+            target_latency: Duration::from_secs(1),
+            error_budget: 0.01,
+            window: Duration::from_secs(60),
+            max_burn_rate: None,
+        }
+    }
+}
+
+/// The requests counted against a method's objective in the current window.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    /// The number of requests responded to.
+    pub requests: u64,
+    /// The number of requests that missed the objective.
+    pub misses: u64,
+    /// The number of requests shed because the budget was burning too fast.
+    pub shed: u64,
+}
+
+impl Counts {
+    /// Returns how many times faster than allowed the error budget is being spent: 1.0 means
+    /// misses are exactly on budget.
+    pub fn burn_rate(&self, objective: &Objective) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        self.misses as f64 / self.requests as f64 / objective.error_budget
+    }
+}
+
+/// Tracks requests against per-method objectives. Clones share the same state, so a single `Slos`
+/// can be used across all connections of a server.
+#[derive(Clone, Debug)]
+pub struct Slos {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    methods: FnvHashMap<&'static str, Method>,
+}
+
+#[derive(Debug)]
+struct Method {
+    objective: Objective,
+    /// Started when the method is first used.
+    window: Option<Window>,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    counts: Counts,
+    /// Requests admitted since shedding started.
+    admitted_while_shedding: u64,
+    /// Requests shed since shedding started.
+    shed_while_shedding: u64,
+}
+
+impl Default for Slos {
+    fn default() -> Self {
+        Slos::new()
+    }
+}
+
+impl Slos {
+    /// Returns a tracker without any objectives. Methods without an objective are not tracked.
+    pub fn new() -> Self {
+        Slos {
+            state: Arc::new(Mutex::new(State {
+                methods: FnvHashMap::default(),
+            })),
+        }
+    }
+
+    /// Sets the objective of `method`.
+    ///
+    /// # Panics
+    ///
+    /// If the objective's error budget isn't greater than zero.
+    pub fn with_objective(self, method: &'static str, objective: Objective) -> Self {
+        assert!(
+            objective.error_budget > 0.0,
+            "The error budget of {} must be greater than zero, not {}.",
+            method,
+            objective.error_budget
+        );
+        self.state.lock().unwrap().methods.insert(
+            method,
+            Method {
+                objective,
+                window: None,
+            },
+        );
+        self
+    }
+
+    // The clock is read under the lock, so that no thread sees a window that another opened
+    // after the time it read.
+
+    /// Returns the counts of `method` in the current window, if it has an objective.
+    pub fn counts(&self, method: &str) -> Option<Counts> {
+        let mut state = self.state.lock().unwrap();
+        state.counts(method, Instant::now())
+    }
+
+    /// Returns the burn rate of `method` in the current window, if it has an objective.
+    pub fn burn_rate(&self, method: &str) -> Option<f64> {
+        let mut state = self.state.lock().unwrap();
+        state.burn_rate(method, Instant::now())
+    }
+
+    fn admit(&self, method: &str) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.admit(method, Instant::now())
+    }
+
+    fn record(&self, method: &str, latency: Duration, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.record(method, latency, ok, Instant::now())
+    }
+
+    fn record_abandoned(&self, method: &str, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.record_abandoned(method, latency, Instant::now())
+    }
+
+    #[cfg(test)]
+    fn counts_at(&self, method: &str, now: Instant) -> Option<Counts> {
+        self.state.lock().unwrap().counts(method, now)
+    }
+
+    #[cfg(test)]
+    fn burn_rate_at(&self, method: &str, now: Instant) -> Option<f64> {
+        self.state.lock().unwrap().burn_rate(method, now)
+    }
+
+    #[cfg(test)]
+    fn admit_at(&self, method: &str, now: Instant) -> io::Result<()> {
+        self.state.lock().unwrap().admit(method, now)
+    }
+
+    #[cfg(test)]
+    fn record_at(&self, method: &str, latency: Duration, ok: bool, now: Instant) {
+        self.state.lock().unwrap().record(method, latency, ok, now)
+    }
+}
+
+impl State {
+    fn counts(&mut self, method: &str, now: Instant) -> Option<Counts> {
+        Some(self.methods.get_mut(method)?.window(now).counts)
+    }
+
+    fn burn_rate(&mut self, method: &str, now: Instant) -> Option<f64> {
+        let method = self.methods.get_mut(method)?;
+        let objective = method.objective;
+        Some(method.window(now).counts.burn_rate(&objective))
+    }
+
+    /// Returns an error if `method` is burning its budget too fast for the request to be admitted.
+    fn admit(&mut self, method: &str, now: Instant) -> io::Result<()> {
+        let (objective, window) = match self.methods.get_mut(method) {
+            Some(tracked) => (tracked.objective, tracked.window(now)),
+            None => return Ok(()),
+        };
+        let max_burn_rate = match objective.max_burn_rate {
+            Some(max_burn_rate) => max_burn_rate,
+            None => return Ok(()),
+        };
+        let burn_rate = window.counts.burn_rate(&objective);
+        let min_requests = (1.0 / objective.error_budget).ceil() as u64;
+        if window.counts.requests < min_requests || burn_rate <= max_burn_rate {
+            window.admitted_while_shedding = 0;
+            window.shed_while_shedding = 0;
+            return Ok(());
+        }
+
+        // Admit requests in the same ratio that the budget is over-burning, e.g. one in ten
+        // when burning ten times too fast.
+        let admitted_share = max_burn_rate / burn_rate;
+        let seen = window.admitted_while_shedding + window.shed_while_shedding + 1;
+        if (window.admitted_while_shedding as f64) < seen as f64 * admitted_share {
+            window.admitted_while_shedding += 1;
+            return Ok(());
+        }
+
+        window.shed_while_shedding += 1;
+        window.counts.shed += 1;
+        let window_remaining = objective
+            .window
+            .checked_sub(elapsed_since(now, window.start))
+            .unwrap_or_default();
+        let error = ServerError::new(
+            io::ErrorKind::WouldBlock,
+            format!(
+                "Shedding load: the error budget of {} is burning {:.1}x too fast.",
+                method,
+                burn_rate / max_burn_rate
+            ),
+        );
+        Err(error.with_retry_after(window_remaining).into())
+    }
+
+    /// Counts a response to `method` that took `latency` and succeeded if `ok`.
+    fn record(&mut self, method: &str, latency: Duration, ok: bool, now: Instant) {
+        let (objective, counts) = match self.methods.get_mut(method) {
+            Some(tracked) => (tracked.objective, &mut tracked.window(now).counts),
+            None => return,
+        };
+        counts.requests += 1;
+        if !ok || latency > objective.target_latency {
+            counts.misses += 1;
+        }
+    }
+
+    /// Counts a request to `method` that was dropped after `latency` without being responded to,
+    /// e.g. because its deadline passed. It only counts if it had already missed the latency
+    /// target; otherwise, it was likely canceled by the client.
+    fn record_abandoned(&mut self, method: &str, latency: Duration, now: Instant) {
+        if let Some(tracked) = self.methods.get_mut(method) {
+            if latency > tracked.objective.target_latency {
+                let counts = &mut tracked.window(now).counts;
+                counts.requests += 1;
+                counts.misses += 1;
+            }
+        }
+    }
+}
+
+impl Method {
+    /// Returns the current window, starting a new one if the last one is over.
+    fn window(&mut self, now: Instant) -> &mut Window {
+        let period = self.objective.window;
+        let window = self.window.get_or_insert_with(|| Window::new(now));
+        if elapsed_since(now, window.start) >= period {
+            *window = Window::new(now);
+        }
+        window
+    }
+}
+
+impl Window {
+    fn new(start: Instant) -> Self {
+        Window {
+            start,
+            counts: Counts::default(),
+            admitted_while_shedding: 0,
+            shed_while_shedding: 0,
+        }
+    }
+}
+
+/// A future returned by a request handler wrapped with [`enforce_slos`] that records the response
+/// against its method's objective.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Measured<Fut> {
+    future: Fut,
+    slos: Slos,
+    method: &'static str,
+    start: Instant,
+    recorded: bool,
+}
+
+impl<Fut> Measured<Fut> {
+    unsafe_pinned!(future: Fut);
+    unsafe_unpinned!(recorded: bool);
+}
+
+impl<Fut> Drop for Measured<Fut> {
+    fn drop(&mut self) {
+        if !self.recorded {
+            let latency = elapsed_since(Instant::now(), self.start);
+            self.slos.record_abandoned(self.method, latency);
+        }
+    }
+}
+
+impl<Fut, Resp> Future for Measured<Fut>
+where
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    type Output = io::Result<Resp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
+        let result = ready!(self.as_mut().future().poll(cx));
+        let latency = elapsed_since(Instant::now(), self.start);
+        self.slos.record(self.method, latency, result.is_ok());
+        *self.as_mut().recorded() = true;
+        Poll::Ready(result)
+    }
+}
+
+/// Wraps request handler `f` so that every response is counted against the objective of the
+/// method it answers.
+///
+/// `method` returns the name of the method a request calls. Services defined with
+/// `tarpc::service!` can use `Request::name`. Requests shed for burning their method's budget
+/// too fast are rejected with [`WouldBlock`](io::ErrorKind::WouldBlock) without being passed to
+/// `f`; the error asks the client to retry once the window is over.
+pub fn enforce_slos<Req, Resp, M, F, Fut>(
+    slos: Slos,
+    method: M,
+    f: F,
+) -> impl FnOnce(context::Context, Req) -> Enforced<Measured<Fut>, Resp> + Send + 'static + Clone
+where
+    M: Fn(&Req) -> &'static str + Send + 'static + Clone,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    move |ctx, req| {
+        let method = method(&req);
+        let start = Instant::now();
+        match slos.admit(method) {
+            Ok(()) => Either::Left(Measured {
+                future: f(ctx, req),
+                slos,
+                method,
+                start,
+                recorded: false,
+            }),
+            Err(e) => {
+                debug!("[{}] Rejecting request: {}", ctx.trace_id(), e);
+                Either::Right(future::ready(Err(e)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{enforce_slos, Counts, Objective, Slos};
+    use crate::ServerError;
+    use futures::{executor::block_on, future};
+    use std::{
+        io,
+        time::{Duration, Instant},
+    };
+
+    fn objective() -> Objective {
+        Objective {
+            target_latency: Duration::from_millis(100),
+            error_budget: 0.1,
+            window: Duration::from_secs(60),
+            max_burn_rate: Some(1.0),
+        }
+    }
+
+    #[test]
+    fn counts_misses_against_objective() {
+        let slos = Slos::new().with_objective("search", objective());
+        let now = Instant::now();
+        slos.record_at("search", Duration::from_millis(10), true, now);
+        slos.record_at("search", Duration::from_millis(200), true, now);
+        slos.record_at("search", Duration::from_millis(10), false, now);
+        slos.record_at("search", Duration::from_millis(10), true, now);
+        slos.record_at("untracked", Duration::from_millis(200), false, now);
+
+        let counts = slos.counts_at("search", now).unwrap();
+        assert_eq!(
+            counts,
+            Counts {
+                requests: 4,
+                misses: 2,
+                shed: 0
+            }
+        );
+        assert_eq!(slos.burn_rate_at("search", now), Some(5.0));
+        assert_eq!(slos.counts_at("untracked", now), None);
+
+        // Counts start over in the next window.
+        let later = now + Duration::from_secs(60);
+        assert_eq!(slos.counts_at("search", later), Some(Counts::default()));
+    }
+
+    #[test]
+    fn sheds_in_proportion_to_burn_rate() {
+        let slos = Slos::new().with_objective("search", objective());
+        let now = Instant::now();
+        // One miss in ten is on budget.
+        for i in 0..10 {
+            slos.record_at("search", Duration::from_millis(10), i != 0, now);
+        }
+        assert!(slos.admit_at("search", now).is_ok());
+
+        // Four misses in twenty burns the budget twice as fast as allowed, so half of the
+        // requests are shed.
+        for i in 0..10 {
+            slos.record_at("search", Duration::from_millis(10), i >= 3, now);
+        }
+        let results: Vec<_> = (0..10).map(|_| slos.admit_at("search", now)).collect();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 5);
+        assert_eq!(slos.counts_at("search", now).unwrap().shed, 5);
+
+        let e = results.into_iter().find_map(Result::err).unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        let e = e.into_inner().unwrap().downcast::<ServerError>().unwrap();
+        assert_eq!(e.retry_after, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn records_responses() {
+        let slos = Slos::new().with_objective("search", objective());
+        let handler = enforce_slos(
+            slos.clone(),
+            |_: &()| "search",
+            |_, _| future::ready(Err::<(), _>(io::Error::from(io::ErrorKind::Other))),
+        );
+
+        assert!(block_on(handler(crate::context::current(), ())).is_err());
+        let counts = slos.counts("search").unwrap();
+        assert_eq!((counts.requests, counts.misses), (1, 1));
+    }
+
+    #[test]
+    fn tolerates_times_read_before_the_window_opened() {
+        let slos = Slos::new().with_objective("search", objective());
+        let now = Instant::now();
+        let later = now + Duration::from_secs(61);
+        slos.record_at("search", Duration::from_millis(10), true, later);
+        // A thread that read the clock before the window above was opened.
+        slos.record_at("search", Duration::from_millis(10), true, now);
+        assert!(slos.admit_at("search", now).is_ok());
+        assert_eq!(slos.counts_at("search", now).unwrap().requests, 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_empty_error_budgets() {
+        let _ = Slos::new().with_objective(
+            "search",
+            Objective {
+                error_budget: 0.0,
+                ..objective()
+            },
+        );
+    }
+}
@@ -0,0 +1,660 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Lets request handlers stream items to the client ahead of their final response, and read the
+//! items the client streams after its request.
+//!
+//! The server makes a [`Sender`] for each request current while polling the request's handler.
+//! Items sent through it go out in [`StreamItem`](crate::ServerMessage::StreamItem) messages as
+//! soon as they're ready, whatever the server's [response order](super::ResponseOrder), and the
+//! handler's response, sent once it completes, ends the stream. Clients read the items with
+//! [`Channel::stream`](crate::client::Channel::stream).
+//!
+//! Items take room in the connection's buffer of pending responses, so a handler that streams
+//! faster than the client reads waits for room in [`send`](Sender::send). Items sent after the
+//! handler completes, e.g. by a task it spawned, or in response to a notification, are dropped.
+//!
+//! Long-running handlers can stream progress updates this way, which clients read apart from the
+//! final response by [splitting](crate::client::ResponseStream::into_progress) the stream.
+//! Updates sent with [`try_send`](Sender::try_send) are dropped rather than waited on while the
+//! buffer is full. Such handlers can also watch for their request being
+//! [canceled](super::cancellation::canceled), to stop the tasks they spawned.
+//!
+//! In the other direction, a client can stream the body of a request after it, with
+//! [`Channel::call_with_body`](crate::client::Channel::call_with_body), and the handler reads
+//! the items with [`body`]. Items the handler doesn't read yet fill a buffer of
+//! [`body_item_buffer`](super::Config::body_item_buffer) items, and while it's full, nothing more
+//! is read off the connection. Handlers that both read a body and stream their response make
+//! bidirectional streams.
+
+use crate::{context, server::Reply};
+use futures::{
+    channel::mpsc,
+    future, ready,
+    stream::{Stream, StreamExt},
+    task::{Context, Poll},
+    Future,
+};
+use log::trace;
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    any::Any,
+    cell::RefCell,
+    fmt, io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+thread_local! {
+    static CURRENT: RefCell<Option<Erased>> = RefCell::new(None);
+    static BODY: RefCell<Option<Erased>> = RefCell::new(None);
+}
+
+/// A [`Sender`], or a request's [`Body`] until the handler takes it, whose type is known only to
+/// the handler that asks for it.
+type Erased = Arc<Mutex<dyn Any + Send>>;
+
+/// Returns the sender of the items streamed in response to the current request, or `None` outside
+/// of a request's handler, if the request is a notification, or if `Resp` isn't the server's
+/// response type.
+pub fn sender<Resp: Send + 'static>() -> Option<Sender<Resp>> {
+    CURRENT.with(|current| {
+        let current = current.borrow();
+        let sender = current.as_ref()?.lock().unwrap();
+        sender.downcast_ref::<Sender<Resp>>().cloned()
+    })
+}
+
+/// Returns the streamed body of the current request the first time it's called while polling the
+/// request's handler. Returns `None` outside of a request's handler, if the request has no
+/// streamed body, if the body was already taken, or if `Req` isn't the server's request type.
+pub fn body<Req: Send + 'static>() -> Option<Body<Req>> {
+    BODY.with(|current| {
+        let current = current.borrow();
+        let mut body = current.as_ref()?.lock().unwrap();
+        body.downcast_mut::<Option<Body<Req>>>()?.take()
+    })
+}
+
+/// The items a client streams after a request, as the request's body. Returned by [`body`].
+///
+/// The stream ends once the client ends the body. An item the server couldn't decode is yielded
+/// as an error of kind [`InvalidData`](io::ErrorKind::InvalidData), which ends the body.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Body<Req> {
+    items: mpsc::Receiver<io::Result<Req>>,
+}
+
+impl<Req> Body<Req> {
+    pub(crate) fn new(items: mpsc::Receiver<io::Result<Req>>) -> Self {
+        Body { items }
+    }
+}
+
+impl<Req> Stream for Body<Req> {
+    type Item = io::Result<Req>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.items.poll_next_unpin(cx)
+    }
+}
+
+/// A stream of the items of the current request's [`body`], converted by a function that returns
+/// `None` for items that don't belong in it. Used by `tarpc::service!` to pass the streamed
+/// argument of a method to the service.
+///
+/// The body is looked up on the first poll, so the stream must first be polled by the request's
+/// handler. Outside of a handler, or if the request has no streamed body, the stream is empty.
+#[must_use = "streams do nothing unless polled"]
+pub struct BodyItems<Req, T> {
+    /// Whether the body was looked up.
+    started: bool,
+    body: Option<Body<Req>>,
+    f: fn(Req) -> Option<T>,
+}
+
+impl<Req, T> fmt::Debug for BodyItems<Req, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BodyItems")
+            .field("started", &self.started)
+            .finish()
+    }
+}
+
+impl<Req, T> BodyItems<Req, T> {
+    /// Returns a stream of the current request's body items, converted by `f`.
+    pub fn new(f: fn(Req) -> Option<T>) -> Self {
+        BodyItems {
+            started: false,
+            body: None,
+            f,
+        }
+    }
+}
+
+impl<Req: Send + 'static, T> Stream for BodyItems<Req, T> {
+    type Item = io::Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if !self.started {
+            self.started = true;
+            self.body = body();
+        }
+        let item = match &mut self.body {
+            Some(body) => ready!(body.poll_next_unpin(cx)),
+            None => return Poll::Ready(None),
+        };
+        let item = match item {
+            Some(Ok(item)) => match (self.f)(item) {
+                Some(item) => Ok(item),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The client streamed an item of another method's body.",
+                )),
+            },
+            Some(Err(e)) => Err(e),
+            None => return Poll::Ready(None),
+        };
+        if item.is_err() {
+            self.body = None;
+        }
+        Poll::Ready(Some(item))
+    }
+}
+
+/// Sends the items streamed in response to a request. Returned by [`sender`].
+pub struct Sender<Resp> {
+    ctx: context::Context,
+    request_id: u64,
+    replies: mpsc::Sender<(context::Context, Reply<Resp>)>,
+}
+
+impl<Resp> Clone for Sender<Resp> {
+    fn clone(&self) -> Self {
+        Sender {
+            ctx: self.ctx,
+            request_id: self.request_id,
+            replies: self.replies.clone(),
+        }
+    }
+}
+
+impl<Resp> fmt::Debug for Sender<Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("request_id", &self.request_id)
+            .finish()
+    }
+}
+
+impl<Resp> Sender<Resp> {
+    pub(crate) fn new(
+        ctx: context::Context,
+        request_id: u64,
+        replies: mpsc::Sender<(context::Context, Reply<Resp>)>,
+    ) -> Self {
+        Sender {
+            ctx,
+            request_id,
+            replies,
+        }
+    }
+
+    /// Returns the ID of the request the items are streamed in response to.
+    pub fn request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    /// Returns a future that resolves once `item` is queued to be sent, which waits while the
+    /// connection's buffer of pending responses is full. Fails if the connection closed.
+    pub fn send(&mut self, item: Resp) -> impl Future<Output = io::Result<()>> + '_ {
+        let mut item = Some(item);
+        future::poll_fn(move |cx| {
+            ready!(self.poll_ready(cx))?;
+            self.start_send(item.take().expect("polled after completion"))?;
+            Poll::Ready(Ok(()))
+        })
+    }
+
+    /// Resolves once an item can be [sent](Sender::start_send) without waiting.
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.replies.poll_ready(cx).map_err(|_| connection_closed())
+    }
+
+    /// Queues `item` to be sent if there's room for it, and returns whether there was. Suits
+    /// updates that are superseded by the next one, like progress, which needn't hold up the
+    /// handler. Fails if the connection closed.
+    pub fn try_send(&mut self, item: Resp) -> io::Result<bool> {
+        let reply = Reply::Item {
+            request_id: self.request_id,
+            item,
+        };
+        match self.replies.try_send((self.ctx, reply)) {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.is_full() => {
+                trace!(
+                    "[{}] Dropping item; no room to stream it.",
+                    self.ctx.trace_id()
+                );
+                Ok(false)
+            }
+            Err(_) => Err(connection_closed()),
+        }
+    }
+
+    /// Queues `item` to be sent. Must only be called after [`poll_ready`](Sender::poll_ready)
+    /// resolves.
+    pub fn start_send(&mut self, item: Resp) -> io::Result<()> {
+        trace!("[{}] Streaming item.", self.ctx.trace_id());
+        let reply = Reply::Item {
+            request_id: self.request_id,
+            item,
+        };
+        self.replies
+            .start_send((self.ctx, reply))
+            .map_err(|_| connection_closed())
+    }
+}
+
+fn connection_closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "The connection closed before the item could be sent.",
+    )
+}
+
+/// Returns a future that makes `sender`, and `body` until it's taken, current while polling
+/// `future`.
+pub(crate) fn scope<Req, Resp, F>(
+    sender: Option<Sender<Resp>>,
+    body: Option<Body<Req>>,
+    future: F,
+) -> Scoped<F>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    F: Future,
+{
+    Scoped {
+        sender: sender.map(|sender| Arc::new(Mutex::new(sender)) as Erased),
+        body: body.map(|body| Arc::new(Mutex::new(Some(body))) as Erased),
+        future,
+    }
+}
+
+/// A future that makes a sender and a body current while it is polled.
+#[must_use = "futures do nothing unless polled"]
+pub(crate) struct Scoped<F> {
+    sender: Option<Erased>,
+    body: Option<Erased>,
+    future: F,
+}
+
+impl<F> fmt::Debug for Scoped<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Scoped").finish()
+    }
+}
+
+impl<F> Scoped<F> {
+    unsafe_unpinned!(sender: Option<Erased>);
+    unsafe_unpinned!(body: Option<Erased>);
+    unsafe_pinned!(future: F);
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        /// Restores the previous sender and body, even if the future panics.
+        struct Reset(Option<Erased>, Option<Erased>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+                BODY.with(|current| *current.borrow_mut() = self.1.take());
+            }
+        }
+
+        let sender = self.as_mut().sender().clone();
+        let body = self.as_mut().body().clone();
+        let _reset = Reset(
+            CURRENT.with(|current| current.replace(sender)),
+            BODY.with(|current| current.replace(body)),
+        );
+        self.as_mut().future().poll(cx)
+    }
+}
+
+/// A future that sends the items of `stream` through the current request's [`sender`], then
+/// resolves to the response that ends the stream. Used by `tarpc::service!` to serve methods that
+/// stream their responses, whose items and final response are variants of one response type.
+///
+/// Outside of a request's handler, or for notifications, the items are dropped.
+#[must_use = "futures do nothing unless polled"]
+pub struct Forward<S: Stream, Resp> {
+    stream: S,
+    wrap: fn(S::Item) -> Resp,
+    end: Option<Resp>,
+    /// Whether the sender was looked up, which is done on the first poll, when the request is
+    /// current.
+    started: bool,
+    sender: Option<Sender<Resp>>,
+    /// An item waiting for room to be sent.
+    pending: Option<Resp>,
+}
+
+impl<S: Stream, Resp> fmt::Debug for Forward<S, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Forward").finish()
+    }
+}
+
+impl<S: Stream, Resp> Forward<S, Resp> {
+    unsafe_pinned!(stream: S);
+    unsafe_unpinned!(end: Option<Resp>);
+    unsafe_unpinned!(started: bool);
+    unsafe_unpinned!(sender: Option<Sender<Resp>>);
+    unsafe_unpinned!(pending: Option<Resp>);
+
+    /// Returns a future that sends each item of `stream`, converted into a response with `wrap`,
+    /// then resolves to `end`.
+    pub fn new(stream: S, wrap: fn(S::Item) -> Resp, end: Resp) -> Self {
+        Forward {
+            stream,
+            wrap,
+            end: Some(end),
+            started: false,
+            sender: None,
+            pending: None,
+        }
+    }
+}
+
+impl<S, Resp> Future for Forward<S, Resp>
+where
+    S: Stream,
+    Resp: Send + 'static,
+{
+    type Output = io::Result<Resp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
+        if !self.started {
+            *self.as_mut().started() = true;
+            *self.as_mut().sender() = sender();
+        }
+        loop {
+            if let Some(item) = self.as_mut().pending().take() {
+                let sender = self
+                    .as_mut()
+                    .sender()
+                    .as_mut()
+                    .expect("Items are only pending when there's a sender.");
+                match sender.poll_ready(cx)? {
+                    Poll::Ready(()) => sender.start_send(item)?,
+                    Poll::Pending => {
+                        *self.as_mut().pending() = Some(item);
+                        return Poll::Pending;
+                    }
+                }
+            }
+            match ready!(self.as_mut().stream().poll_next(cx)) {
+                Some(item) => {
+                    if self.sender.is_some() {
+                        let item = (self.wrap)(item);
+                        *self.as_mut().pending() = Some(item);
+                    }
+                }
+                None => {
+                    let end = self.as_mut().end().take().expect("polled after completion");
+                    return Poll::Ready(Ok(end));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{body, sender};
+    use crate::{
+        client, context,
+        metadata::Metadata,
+        server::{self, Handler},
+        test_util, transport, ClientMessage, ClientMessageKind, Request, Server, ServerMessage,
+    };
+    use futures::{future, prelude::*, stream};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    #[test]
+    fn streamed_items_arrive_ahead_of_the_final_response() {
+        test_util::init();
+
+        let items = async {
+            let (mut client, serving) = await!(transport::channel::loopback(
+                client::Config::default(),
+                Server::<String, String>::default(),
+                |_ctx, request: String| {
+                    async move {
+                        let mut sender = sender::<String>().unwrap();
+                        for item in request.split(' ') {
+                            await!(sender.send(item.to_string()))?;
+                        }
+                        Ok("done".to_string())
+                    }
+                },
+            ))?;
+            let stream = await!(client.stream(context::current(), "a b c".into()))?;
+            let items = await!(stream.collect::<Vec<_>>());
+            drop(client);
+            await!(serving);
+            items.into_iter().collect::<io::Result<Vec<_>>>()
+        };
+
+        assert_eq!(
+            test_util::run_future(items.unwrap_or_else(|e| panic!(e))),
+            vec!["a", "b", "c", "done"]
+        );
+    }
+
+    #[test]
+    fn pipelined_responses_keep_streamed_items_ahead() {
+        test_util::init();
+
+        let mut config = server::Config::default();
+        config.response_order = server::ResponseOrder::Pipelined;
+        let (mut client_channel, server_channel) = transport::channel::unbounded();
+        let server = server::new::<String, String>(config)
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with(|_ctx, request: String| async move {
+                if request == "stream" {
+                    let mut sender = sender::<String>().unwrap();
+                    await!(sender.send("item".to_string()))?;
+                }
+                Ok(request)
+            });
+
+        let messages = async move {
+            for (id, message) in vec!["plain", "stream"].into_iter().enumerate() {
+                await!(client_channel.send(ClientMessage {
+                    trace_context: context::current().trace_context,
+                    message: ClientMessageKind::Request(Request {
+                        id: id as u64,
+                        message: message.to_string(),
+                        deadline: context::current().deadline,
+                        metadata: Metadata::new(),
+                        causality: None,
+                    }),
+                }))
+                .unwrap();
+            }
+            await!(client_channel.take(3).collect::<Vec<_>>())
+        };
+
+        let messages: Vec<_> = test_util::run_future(future::join(server, messages))
+            .1
+            .into_iter()
+            .map(|message| match message.unwrap() {
+                ServerMessage::StreamItem { request_id, item } => (request_id, item, false),
+                ServerMessage::Response(response) => {
+                    (response.request_id, response.message.unwrap(), true)
+                }
+                other => panic!("Expected a response or an item, got {:?}", other),
+            })
+            .collect();
+        let stream: Vec<_> = messages.iter().filter(|(id, ..)| *id == 1).collect();
+        assert_eq!(
+            stream,
+            vec![
+                &(1, "item".to_string(), false),
+                &(1, "stream".to_string(), true)
+            ]
+        );
+        assert!(messages.contains(&(0, "plain".to_string(), true)));
+    }
+
+    #[test]
+    fn notifications_have_no_stream_sender() {
+        test_util::init();
+
+        let senders = Arc::new(Mutex::new(vec![]));
+        let (mut client_channel, server_channel) = transport::channel::unbounded();
+        let server = Server::<String, String>::default()
+            .incoming(stream::once(future::ready(Ok(server_channel))))
+            .respond_with({
+                let senders = senders.clone();
+                move |_ctx, request: String| {
+                    let senders = senders.clone();
+                    async move {
+                        // The sender is current while the handler's future is polled.
+                        let sender = sender::<String>();
+                        senders
+                            .lock()
+                            .unwrap()
+                            .push((request.clone(), sender.is_some()));
+                        Ok(request)
+                    }
+                }
+            });
+
+        let responses = async move {
+            let request = |id, message: &str| Request {
+                id,
+                message: message.to_string(),
+                deadline: context::current().deadline,
+                metadata: Metadata::new(),
+                causality: None,
+            };
+            for message in vec![
+                ClientMessageKind::Notification(request(0, "note")),
+                ClientMessageKind::Request(request(1, "hi")),
+            ] {
+                await!(client_channel.send(ClientMessage {
+                    trace_context: context::current().trace_context,
+                    message,
+                }))
+                .unwrap();
+            }
+            await!(client_channel.close()).unwrap();
+            await!(client_channel.collect::<Vec<_>>())
+        };
+
+        test_util::run_future(future::join(server, responses));
+        assert_eq!(
+            *senders.lock().unwrap(),
+            vec![("note".to_string(), false), ("hi".to_string(), true)]
+        );
+        // Outside of a handler, there is no sender.
+        assert!(sender::<String>().is_none());
+    }
+
+    #[test]
+    fn request_bodies_are_streamed_to_the_handler() {
+        test_util::init();
+
+        let responses = async {
+            let (mut client, serving) = await!(transport::channel::loopback(
+                client::Config::default(),
+                Server::<String, String>::default(),
+                |_ctx, request: String| {
+                    async move {
+                        let mut body = match body::<String>() {
+                            Some(body) => body,
+                            None => return Ok(request),
+                        };
+                        let mut items = vec![];
+                        while let Some(item) = await!(body.next()) {
+                            if request == "echo" {
+                                let mut sender = sender::<String>().unwrap();
+                                await!(sender.send(item?))?;
+                            } else {
+                                items.push(item?);
+                            }
+                        }
+                        Ok(if request == "echo" {
+                            "done".into()
+                        } else {
+                            items.join(" ")
+                        })
+                    }
+                },
+            ))?;
+            let body = || stream::iter(vec!["a".to_string(), "b".into()]);
+            let joined = await!(client.call_with_body(context::current(), "join".into(), body()))?;
+            let echoed =
+                await!(client.stream_with_body(context::current(), "echo".into(), body()))?;
+            let echoed = await!(echoed.collect::<Vec<_>>())
+                .into_iter()
+                .collect::<io::Result<Vec<_>>>()?;
+            // Requests without bodies have none to read.
+            let plain = await!(client.call(context::current(), "plain".into()))?;
+            drop(client);
+            await!(serving);
+            Ok::<_, io::Error>((joined, echoed, plain))
+        };
+
+        let (joined, echoed, plain) =
+            test_util::run_future(responses.unwrap_or_else(|e| panic!(e)));
+        assert_eq!(joined, "a b");
+        assert_eq!(echoed, vec!["a", "b", "done"]);
+        assert_eq!(plain, "plain");
+    }
+
+    #[test]
+    fn a_response_ahead_of_the_body_ends_the_request() {
+        test_util::init();
+
+        let responses = async {
+            let (mut client, serving) = await!(transport::channel::loopback(
+                client::Config::default(),
+                Server::<String, String>::default(),
+                |_ctx, request: String| {
+                    async move {
+                        if let Some(mut body) = body::<String>() {
+                            return await!(body.next()).unwrap();
+                        }
+                        Ok(request)
+                    }
+                },
+            ))?;
+            // The body never ends, but the handler only reads its first item.
+            let body = stream::repeat("first".to_string());
+            let first = await!(client.call_with_body(context::current(), "".into(), body))?;
+            let next = await!(client.call(context::current(), "next".into()))?;
+            drop(client);
+            await!(serving);
+            Ok::<_, io::Error>((first, next))
+        };
+
+        let (first, next) = test_util::run_future(responses.unwrap_or_else(|e| panic!(e)));
+        assert_eq!(first, "first");
+        assert_eq!(next, "next");
+    }
+}
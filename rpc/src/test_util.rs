@@ -0,0 +1,74 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Setup shared by the tests that run clients against servers.
+
+use crate::{
+    context,
+    server::{Handler, Server},
+    transport::{self, channel::UnboundedChannel},
+    ClientMessage, Response, ServerMessage,
+};
+use futures::{compat::Executor01CompatExt, future, prelude::*, stream};
+use std::io;
+
+/// Initializes logging, and spawns tarpc's tasks on the default tokio executor.
+pub(crate) fn init() {
+    let _ = env_logger::try_init();
+    crate::init(tokio::executor::DefaultExecutor::current().compat());
+}
+
+/// Returns one end of an in-memory connection, and `server` responding to the requests sent over
+/// it with `request_handler`. The server resolves once the connection closes.
+pub(crate) fn serve<Req, Resp, F, Fut>(
+    server: Server<Req, Resp>,
+    request_handler: F,
+) -> (
+    UnboundedChannel<ServerMessage<Resp>, ClientMessage<Req>>,
+    impl Future<Output = ()>,
+)
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+{
+    let (client_channel, server_channel) = transport::channel::unbounded();
+    let server = server
+        .incoming(stream::once(future::ready(Ok(server_channel))))
+        .respond_with(request_handler);
+    (client_channel, server)
+}
+
+/// Returns the response in `message`, panicking if it's anything else.
+pub(crate) fn into_response<T>(message: io::Result<ServerMessage<T>>) -> Response<T> {
+    match message.unwrap() {
+        ServerMessage::Response(response) => response,
+        ServerMessage::Close { reason } => panic!("Expected a response, got a close: {}", reason),
+        ServerMessage::StreamItem { request_id, .. } => panic!(
+            "Expected a response, got an item streamed to {}",
+            request_id
+        ),
+        ServerMessage::Drain => panic!("Expected a response, got a drain"),
+    }
+}
+
+/// Runs `f` to completion on a tokio runtime, along with the tasks it spawns, and returns its
+/// output.
+pub(crate) fn run_future<F>(f: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (tx, rx) = futures::channel::oneshot::channel();
+    tokio::run(
+        f.map(|result| tx.send(result).unwrap_or_else(|_| unreachable!()))
+            .boxed()
+            .unit_error()
+            .compat(),
+    );
+    futures::executor::block_on(rx).unwrap()
+}
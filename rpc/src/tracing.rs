@@ -0,0 +1,414 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Records the spans of distributed traces, and exports them to a tracing backend of choice.
+//!
+//! Every request carries the [trace context](trace::Context) of its [`Context`](context::Context)
+//! over the wire. Each call a client makes is a span of its own, a child of the span of the
+//! context the call is made with, and the server makes a request's context current while its
+//! handler runs, so calls the handler makes with [`context::current`] are children of the
+//! request's span.
+//!
+//! A [`Tracer`] exports spans to an [`Exporter`], e.g. one that ships them to Zipkin or Jaeger. A
+//! channel given a tracer with [`with_tracer`](crate::client::Channel::with_tracer) exports the
+//! span of every call it makes, and a request handler wrapped with [`trace`] exports the span of
+//! every request it handles, as a child of the client's span. While handling a request, the handler
+//! can get a [`Trace`] of the request's span with [`current`], to tag it, annotate it with events,
+//! or start spans of its own work.
+
+use crate::{context, ErrorCode};
+use futures::{
+    ready,
+    task::{Context, Poll},
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    cell::RefCell,
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use trace::SpanId;
+
+/// What a span measured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// A call made by a client, from when it was made until its response arrived.
+    Client,
+    /// A request handled by a server, from when its handler was called until it responded.
+    Server,
+    /// Work done by a handler, or by any other code, that isn't a call.
+    Local,
+}
+
+/// A span that has ended.
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub struct Span {
+    /// The span's trace and ID, and the ID of its parent.
+    pub context: trace::Context,
+    /// The name of the span, e.g. the name of the method called.
+    pub name: &'static str,
+    /// What the span measured.
+    pub kind: Kind,
+    /// When the span started.
+    pub start: SystemTime,
+    /// How long the span lasted.
+    pub duration: Duration,
+    /// The code of the error the call or request failed with, if it failed.
+    pub error: Option<ErrorCode>,
+    /// Key/value pairs describing the span, e.g. the address of the server called.
+    pub tags: Vec<(&'static str, String)>,
+    /// Events that happened during the span, and when they happened.
+    pub annotations: Vec<(SystemTime, String)>,
+}
+
+/// Ships spans to a tracing backend.
+pub trait Exporter: Send + Sync + 'static {
+    /// Exports a span that has ended. Called on the task that ended the span, so it shouldn't
+    /// block, e.g. on network I/O; exporters usually buffer spans and send them in batches.
+    fn export(&self, span: Span);
+}
+
+impl<F> Exporter for F
+where
+    F: Fn(Span) + Send + Sync + 'static,
+{
+    fn export(&self, span: Span) {
+        self(span)
+    }
+}
+
+/// Exports spans. Clones share the same exporter.
+#[derive(Clone)]
+pub struct Tracer {
+    exporter: Arc<dyn Exporter>,
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Tracer").finish()
+    }
+}
+
+impl Tracer {
+    /// Returns a tracer that exports spans to `exporter`.
+    pub fn new(exporter: impl Exporter) -> Self {
+        Tracer {
+            exporter: Arc::new(exporter),
+        }
+    }
+
+    /// Starts a span of `kind`, identified by `context`.
+    pub(crate) fn start(&self, context: trace::Context, name: &'static str, kind: Kind) -> Trace {
+        Trace {
+            context,
+            recording: Arc::new(Mutex::new(Recording {
+                tracer: self.clone(),
+                span: Span {
+                    context,
+                    name,
+                    kind,
+                    start: SystemTime::now(),
+                    duration: Duration::from_secs(0),
+                    error: None,
+                    tags: vec![],
+                    annotations: vec![],
+                },
+                started: Instant::now(),
+                ended: false,
+            })),
+        }
+    }
+}
+
+/// Returns a context for a new span, a child of the span of `parent`.
+pub(crate) fn child_of(parent: trace::Context) -> trace::Context {
+    trace::Context {
+        trace_id: parent.trace_id,
+        span_id: SpanId::random(&mut rand::thread_rng()),
+        parent_id: Some(parent.span_id),
+    }
+}
+
+/// A handle to a span that is being recorded. Clones are handles to the same span.
+///
+/// A span ends when [`end`](Trace::end) is called, or when its last handle is dropped, and is
+/// exported when it ends.
+#[derive(Clone)]
+pub struct Trace {
+    context: trace::Context,
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Trace")
+            .field("context", &self.context)
+            .finish()
+    }
+}
+
+struct Recording {
+    tracer: Tracer,
+    span: Span,
+    started: Instant,
+    ended: bool,
+}
+
+impl Recording {
+    fn end(&mut self, error: Option<ErrorCode>) {
+        if self.ended {
+            return;
+        }
+        self.ended = true;
+        self.span.duration = self.started.elapsed();
+        self.span.error = error;
+        self.tracer.exporter.export(self.span.clone());
+    }
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        self.end(None);
+    }
+}
+
+impl Trace {
+    /// Returns the trace context of the span, for calls that should be its children, e.g.
+    /// `Context { trace_context: child.trace_context(), ..ctx }`.
+    pub fn trace_context(&self) -> trace::Context {
+        self.context
+    }
+
+    /// Tags the span with `key` and `value`.
+    pub fn tag(&self, key: &'static str, value: impl Into<String>) {
+        self.recording
+            .lock()
+            .unwrap()
+            .span
+            .tags
+            .push((key, value.into()));
+    }
+
+    /// Annotates the span with an event that happened just now.
+    pub fn annotate(&self, annotation: impl Into<String>) {
+        self.recording
+            .lock()
+            .unwrap()
+            .span
+            .annotations
+            .push((SystemTime::now(), annotation.into()));
+    }
+
+    /// Starts a span of local work, a child of this one, exported by the same tracer.
+    pub fn child(&self, name: &'static str) -> Trace {
+        let tracer = self.recording.lock().unwrap().tracer.clone();
+        tracer.start(child_of(self.context), name, Kind::Local)
+    }
+
+    /// Ends the span, as failed with `error`, if it failed, and exports it. Spans can only end
+    /// once; later calls do nothing.
+    pub fn end(&self, error: Option<ErrorCode>) {
+        self.recording.lock().unwrap().end(error);
+    }
+
+    /// Ends the span with the outcome of `result`.
+    pub(crate) fn end_with<T>(&self, result: &io::Result<T>) {
+        self.end(result.as_ref().err().map(ErrorCode::of));
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Trace>> = RefCell::new(None);
+}
+
+/// Returns the span of the current request, if a handler wrapped with [`trace`] is handling one.
+pub fn current() -> Option<Trace> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Wraps request handler `f` so that it records and exports a span for every request it handles,
+/// with a context for the span current while `f` handles the request.
+///
+/// `method` returns the name of the method a request calls, which names its span. Services
+/// defined with `tarpc::service!` can use `Request::name`.
+pub fn trace<Req, Resp, M, F, Fut>(
+    tracer: Tracer,
+    method: M,
+    f: F,
+) -> impl FnOnce(context::Context, Req) -> Traced<context::Scoped<Fut>> + Send + 'static + Clone
+where
+    M: Fn(&Req) -> &'static str + Send + 'static + Clone,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    move |mut ctx, req| {
+        let trace = tracer.start(child_of(ctx.trace_context), method(&req), Kind::Server);
+        ctx.trace_context = trace.trace_context();
+        Traced {
+            future: context::scope(ctx, f(ctx, req)),
+            trace,
+            ended: false,
+        }
+    }
+}
+
+/// A future returned by a request handler wrapped with [`trace`] that makes the request's span
+/// [current] while it is polled, and ends it with the response.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Traced<Fut> {
+    future: Fut,
+    trace: Trace,
+    ended: bool,
+}
+
+impl<Fut> Traced<Fut> {
+    unsafe_pinned!(future: Fut);
+    unsafe_unpinned!(ended: bool);
+}
+
+impl<Fut> Drop for Traced<Fut> {
+    fn drop(&mut self) {
+        if !self.ended {
+            self.trace.annotate("Canceled.");
+            self.trace.end(None);
+        }
+    }
+}
+
+impl<Fut, Resp> Future for Traced<Fut>
+where
+    Fut: Future<Output = io::Result<Resp>>,
+{
+    type Output = io::Result<Resp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<Resp>> {
+        /// Restores the previous span, even if the future panics.
+        struct Reset(Option<Trace>);
+
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+            }
+        }
+
+        let trace = self.trace.clone();
+        let result = {
+            let _reset = Reset(CURRENT.with(|current| current.replace(Some(trace))));
+            ready!(self.as_mut().future().poll(cx))
+        };
+        self.trace.end_with(&result);
+        *self.as_mut().ended() = true;
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{current, trace, Kind, Span, Tracer};
+    use crate::{client, context, test_util, ErrorCode, Server};
+    use futures::{executor::block_on, future};
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    fn tracer() -> (Tracer, Arc<Mutex<Vec<Span>>>) {
+        let spans = Arc::new(Mutex::new(vec![]));
+        let tracer = Tracer::new({
+            let spans = spans.clone();
+            move |span| spans.lock().unwrap().push(span)
+        });
+        (tracer, spans)
+    }
+
+    #[test]
+    fn exports_request_spans_as_children() {
+        let (tracer, spans) = tracer();
+        let handler = trace(
+            tracer,
+            |_: &&str| "search",
+            |_, request: &'static str| {
+                future::lazy(move |_| {
+                    let trace = current().unwrap();
+                    assert_eq!(context::current().trace_context, trace.trace_context());
+                    trace.tag("query", request);
+                    trace.child("lookup").end(None);
+                    Err::<(), _>(io::Error::new(io::ErrorKind::InvalidInput, "Invalid."))
+                })
+            },
+        );
+
+        let ctx = context::current();
+        block_on(handler(ctx, "rust")).unwrap_err();
+        assert!(current().is_none());
+
+        let spans = spans.lock().unwrap();
+        let (lookup, request) = (&spans[0], &spans[1]);
+        assert_eq!(request.name, "search");
+        assert_eq!(request.kind, Kind::Server);
+        assert_eq!(request.error, Some(ErrorCode::BadRequest));
+        assert_eq!(request.tags, vec![("query", "rust".to_string())]);
+        assert_eq!(request.context.trace_id, ctx.trace_context.trace_id);
+        assert_eq!(request.context.parent_id, Some(ctx.trace_context.span_id));
+        assert_eq!(lookup.kind, Kind::Local);
+        assert_eq!(lookup.context.parent_id, Some(request.context.span_id));
+    }
+
+    #[test]
+    fn canceled_requests_end_their_spans() {
+        let (tracer, spans) = tracer();
+        let handler = trace(
+            tracer,
+            |_: &()| "wait",
+            |_, ()| future::pending::<io::Result<()>>(),
+        );
+
+        drop(handler(context::current(), ()));
+        let spans = spans.lock().unwrap();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].annotations[0].1, "Canceled.");
+    }
+
+    #[test]
+    fn server_spans_are_children_of_client_spans() {
+        test_util::init();
+
+        let (tracer, spans) = tracer();
+        let (client_channel, server) = test_util::serve(
+            Server::<String, String>::default(),
+            trace(
+                tracer.clone(),
+                |_: &String| "echo",
+                |_ctx, request| future::ready(Ok(request)),
+            ),
+        );
+
+        let ctx = context::current();
+        let response = async move {
+            let mut client = await!(client::new(client::Config::default(), client_channel))?
+                .with_call_names(|_| "echo")
+                .with_tracer(tracer);
+            await!(client.call(ctx, "hi".into()))
+        };
+
+        let response = test_util::run_future(future::join(server, response)).1;
+        assert_eq!(response.unwrap(), "hi");
+        let spans = spans.lock().unwrap();
+        let client = spans.iter().find(|span| span.kind == Kind::Client).unwrap();
+        let server = spans.iter().find(|span| span.kind == Kind::Server).unwrap();
+        assert_eq!(client.name, "echo");
+        assert_eq!(client.context.trace_id, ctx.trace_context.trace_id);
+        assert_eq!(client.context.parent_id, Some(ctx.trace_context.span_id));
+        assert_eq!(server.context.trace_id, ctx.trace_context.trace_id);
+        assert_eq!(server.context.parent_id, Some(client.context.span_id));
+    }
+}
@@ -6,8 +6,15 @@
 
 //! Transports backed by in-memory channels.
 
+#[cfg(feature = "runtime")]
+use crate::{
+    client, context,
+    server::{Handler, Server, Serving},
+};
 use crate::{PollIo, Transport};
 use futures::{channel::mpsc, task::Context, Poll, Sink, Stream};
+#[cfg(feature = "runtime")]
+use futures::{future, stream, Future};
 use pin_utils::unsafe_pinned;
 use std::pin::Pin;
 use std::{
@@ -29,6 +36,33 @@ pub fn unbounded<SinkItem, Item>() -> (
     )
 }
 
+/// Spawns `server` with a single connection, over an in-memory channel, that it responds to with
+/// `request_handler`, and returns a client of that connection, along with a handle to the server.
+/// No sockets are involved, so tests can wire a client to a server without binding a port. The
+/// server exits once the client and all its clones are dropped.
+///
+/// Must only be called from on an executor.
+#[cfg(feature = "runtime")]
+pub async fn loopback<Req, Resp, F, Fut>(
+    config: client::Config,
+    server: Server<Req, Resp>,
+    request_handler: F,
+) -> io::Result<(client::Channel<Req, Resp>, Serving)>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    F: FnOnce(context::Context, Req) -> Fut + Send + 'static + Clone,
+    Fut: Future<Output = io::Result<Resp>> + Send + 'static,
+{
+    let (client_channel, server_channel) = unbounded();
+    let serving = server
+        .incoming(stream::once(future::ready(Ok(server_channel))))
+        .respond_with(request_handler)
+        .spawn()?;
+    let client = await!(client::new(config, client_channel))?;
+    Ok((client, serving))
+}
+
 /// A bi-directional channel backed by an [`UnboundedSender`](mpsc::UnboundedSender)
 /// and [`UnboundedReceiver`](mpsc::UnboundedReceiver).
 #[derive(Debug)]
@@ -91,27 +125,19 @@ impl<Item, SinkItem> Transport for UnboundedChannel<Item, SinkItem> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "runtime"))]
 mod tests {
-    use crate::{
-        client, context,
-        server::{Handler, Server},
-        transport,
-    };
-    use futures::compat::Executor01CompatExt;
-    use futures::{prelude::*, stream};
+    use crate::{client, context, server::Server, test_util, transport};
+    use futures::prelude::*;
     use log::trace;
     use std::io;
 
     #[test]
     fn integration() {
-        let _ = env_logger::try_init();
-        crate::init(tokio::executor::DefaultExecutor::current().compat());
+        test_util::init();
 
-        let (client_channel, server_channel) = transport::channel::unbounded();
-        let server = Server::<String, u64>::default()
-            .incoming(stream::once(future::ready(Ok(server_channel))))
-            .respond_with(|_ctx, request| {
+        let (client_channel, server) =
+            test_util::serve(Server::<String, u64>::default(), |_ctx, request| {
                 future::ready(request.parse::<u64>().map_err(|_| {
                     io::Error::new(
                         io::ErrorKind::InvalidInput,
@@ -129,7 +155,7 @@ mod tests {
             Ok::<_, io::Error>((response1, response2))
         };
 
-        let (response1, response2) = run_future(future::join(
+        let (response1, response2) = test_util::run_future(future::join(
             server,
             responses.unwrap_or_else(|e| panic!(e)),
         ))
@@ -144,18 +170,26 @@ mod tests {
         assert_eq!(response2.err().unwrap().kind(), io::ErrorKind::InvalidInput);
     }
 
-    fn run_future<F>(f: F) -> F::Output
-    where
-        F: Future + Send + 'static,
-        F::Output: Send + 'static,
-    {
-        let (tx, rx) = futures::channel::oneshot::channel();
-        tokio::run(
-            f.map(|result| tx.send(result).unwrap_or_else(|_| unreachable!()))
-                .boxed()
-                .unit_error()
-                .compat(),
+    #[test]
+    fn loopback_connects_a_client_to_a_server_without_sockets() {
+        test_util::init();
+
+        let response = async {
+            let (mut client, serving) = await!(transport::channel::loopback(
+                client::Config::default(),
+                Server::<String, String>::default(),
+                |_ctx, request| future::ready(Ok(request)),
+            ))?;
+            let response = await!(client.call(context::current(), "hi".into()))?;
+            // The server exits once its only client is gone.
+            drop(client);
+            await!(serving);
+            Ok::<_, io::Error>(response)
+        };
+
+        assert_eq!(
+            test_util::run_future(response.unwrap_or_else(|e| panic!(e))),
+            "hi"
         );
-        futures::executor::block_on(rx).unwrap()
     }
 }
@@ -35,6 +35,46 @@ where
     fn peer_addr(&self) -> io::Result<SocketAddr>;
     /// The address of the local half of this transport.
     fn local_addr(&self) -> io::Result<SocketAddr>;
+    /// The identity the remote peer authenticated as when the connection was established, if the
+    /// transport authenticates peers. Servers make it [current](crate::server::identity::current)
+    /// while handling the peer's requests.
+    fn peer_identity(&self) -> Option<String> {
+        None
+    }
+    /// The token of the session the connection belongs to, if the transport issues sessions. The
+    /// server issues the token when the connection is established, and a client that reconnects
+    /// presents it again, so that its new connection rejoins the session. Servers make it
+    /// [current](crate::server::identity::session) while handling the peer's requests.
+    fn session_token(&self) -> Option<String> {
+        None
+    }
+    /// The credentials of the process on the other end of the connection, as vouched for by the
+    /// operating system, if the transport is over a local socket that reports them. Servers make
+    /// them [current](crate::server::identity::credentials) while handling the peer's requests.
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        None
+    }
+}
+
+/// The credentials of the process on the other end of a local socket, e.g. a Unix socket's
+/// `SO_PEERCRED`, as of when the connection was established. Since the operating system vouches
+/// for them, they authenticate the peer without a handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct PeerCredentials {
+    /// The effective user ID of the peer.
+    pub uid: u32,
+    /// The effective group ID of the peer.
+    pub gid: u32,
+    /// The process ID of the peer, if the operating system reports it.
+    pub pid: Option<u32>,
+}
+
+impl PeerCredentials {
+    /// Returns the credentials of a peer running as `uid` and `gid`, in process `pid`.
+    pub fn new(uid: u32, gid: u32, pid: Option<u32>) -> Self {
+        PeerCredentials { uid, gid, pid }
+    }
 }
 
 /// Returns a new Transport backed by the given Stream + Sink and connecting addresses.
@@ -55,6 +95,70 @@ where
     }
 }
 
+/// Returns a new Transport that reads from `stream` and writes to `sink`.
+///
+/// Together with [`StreamExt::split`](futures::StreamExt::split), this lets each half of a
+/// transport be wrapped on its own before the transport is handed to a client or server, e.g. to
+/// answer heartbeats on the read half or to prioritize messages on the write half.
+pub fn join<St, Si, SinkItem, Item>(
+    stream: St,
+    sink: Si,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+) -> impl Transport<Item = Item, SinkItem = SinkItem>
+where
+    St: Stream<Item = io::Result<Item>>,
+    Si: Sink<SinkItem, SinkError = io::Error>,
+{
+    new(Joined { stream, sink }, peer_addr, local_addr)
+}
+
+/// A Stream + Sink made of a separate Stream and Sink.
+#[derive(Debug)]
+struct Joined<St, Si> {
+    stream: St,
+    sink: Si,
+}
+
+impl<St, Si> Joined<St, Si> {
+    pin_utils::unsafe_pinned!(stream: St);
+    pin_utils::unsafe_pinned!(sink: Si);
+}
+
+impl<St, Si> Stream for Joined<St, Si>
+where
+    St: Stream,
+{
+    type Item = St::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<St::Item>> {
+        self.stream().poll_next(cx)
+    }
+}
+
+impl<St, Si, Item> Sink<Item> for Joined<St, Si>
+where
+    Si: Sink<Item>,
+{
+    type SinkError = Si::SinkError;
+
+    fn start_send(self: Pin<&mut Self>, item: Item) -> Result<(), Si::SinkError> {
+        self.sink().start_send(item)
+    }
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::SinkError>> {
+        self.sink().poll_ready(cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::SinkError>> {
+        self.sink().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::SinkError>> {
+        self.sink().poll_close(cx)
+    }
+}
+
 /// A transport created by adding peers to a Stream + Sink.
 #[derive(Debug)]
 struct TransportShim<S, SinkItem> {
@@ -121,3 +225,41 @@ where
         Ok(self.local_addr)
     }
 }
+
+#[cfg(all(test, feature = "runtime"))]
+mod tests {
+    use crate::{client, context, test_util, transport, Server};
+    use futures::{future, prelude::*};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn joined_halves() {
+        test_util::init();
+
+        let (client_channel, server) =
+            test_util::serve(Server::<String, String>::default(), |_ctx, request| {
+                future::ready(Ok(request))
+            });
+
+        let (sink, stream) = client_channel.split();
+        let responses_read = Arc::new(AtomicUsize::new(0));
+        let counter = responses_read.clone();
+        let stream = stream.inspect(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let client_transport = transport::join(stream, sink, addr, addr);
+
+        let response = async {
+            let mut client = await!(client::new(client::Config::default(), client_transport))?;
+            await!(client.call(context::current(), "hi".into()))
+        };
+
+        let response = test_util::run_future(future::join(server, response)).1;
+        assert_eq!(response.unwrap(), "hi");
+        assert_eq!(responses_read.load(Ordering::SeqCst), 1);
+    }
+}
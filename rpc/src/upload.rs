@@ -0,0 +1,552 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Resumable uploads of large payloads, in checksummed chunks streamed as a request's
+//! [body](crate::server::streaming::body).
+//!
+//! The server [starts](Uploads::start) an upload, handing the client a [`ResumeToken`] naming it.
+//! The client splits the payload into [`Chunks`], each carrying its index and a CRC-32 of its
+//! data, and streams them after a request holding the token. The handler reads them through
+//! [`Uploads::receive`], which fails on a chunk whose checksum doesn't match, and acknowledges
+//! each chunk once the handler asks for the next one, meaning it's done with the chunk, e.g.
+//! wrote it to disk. Acknowledgements can be [streamed](Receive::with_acks) back to the client
+//! as updated tokens.
+//!
+//! If the upload is interrupted, the client calls again with the last token it got, streaming the
+//! payload from the token's [`offset`](ResumeToken::offset) on; the chunks the server already
+//! acknowledged are skipped, so resuming from an older token is fine too. A request that resumes
+//! an upload takes it over from any earlier request still reading it, which then fails.
+
+use fnv::FnvHashMap;
+use futures::{
+    ready,
+    stream::Stream,
+    task::{Context, Poll},
+};
+use log::trace;
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+/// Names an upload, and how much of it the server has acknowledged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResumeToken {
+    /// The ID the server gave the upload.
+    pub upload_id: u64,
+    /// The index of the first chunk the server hasn't acknowledged.
+    pub next_chunk: u64,
+    /// The number of bytes of the payload in the chunks the server acknowledged, which is where
+    /// the first chunk that isn't starts.
+    pub offset: u64,
+}
+
+/// A piece of an upload's payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Chunk {
+    /// The chunk's position in the upload.
+    pub index: u64,
+    /// The chunk's piece of the payload.
+    pub data: Vec<u8>,
+    /// The CRC-32 of `data`, as computed by the client.
+    pub checksum: u32,
+}
+
+impl Chunk {
+    /// Returns chunk `index` of an upload, holding `data`.
+    pub fn new(index: u64, data: Vec<u8>) -> Self {
+        let checksum = crc32(&data);
+        Chunk {
+            index,
+            data,
+            checksum,
+        }
+    }
+
+    /// Whether the chunk's data matches its checksum.
+    pub fn is_intact(&self) -> bool {
+        crc32(&self.data) == self.checksum
+    }
+}
+
+/// The CRC-32 (IEEE) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            // Xors in the polynomial if the low bit is set.
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+/// The chunks of an upload's payload, numbered from where a [`ResumeToken`] resumes the upload.
+/// Each piece of the payload the wrapped stream yields is sent as one chunk.
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Chunks<S> {
+    data: S,
+    next_chunk: u64,
+}
+
+impl<S> Chunks<S> {
+    unsafe_pinned!(data: S);
+    unsafe_unpinned!(next_chunk: u64);
+
+    /// Returns the chunks of the pieces of the payload `data` yields, which must start at
+    /// `token`'s [`offset`](ResumeToken::offset).
+    pub fn new(token: &ResumeToken, data: S) -> Self {
+        Chunks {
+            data,
+            next_chunk: token.next_chunk,
+        }
+    }
+}
+
+impl<S: Stream<Item = Vec<u8>>> Stream for Chunks<S> {
+    type Item = Chunk;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Chunk>> {
+        let data = match ready!(self.as_mut().data().poll_next(cx)) {
+            Some(data) => data,
+            None => return Poll::Ready(None),
+        };
+        let index = self.next_chunk;
+        *self.as_mut().next_chunk() = index + 1;
+        Poll::Ready(Some(Chunk::new(index, data)))
+    }
+}
+
+/// The uploads a server is receiving, which outlive the requests that carry them, so that an
+/// interrupted upload can be resumed by a later request. Clones share the same uploads.
+#[derive(Clone, Debug, Default)]
+pub struct Uploads {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: u64,
+    uploads: FnvHashMap<u64, Upload>,
+}
+
+#[derive(Debug)]
+struct Upload {
+    /// How much of the upload was acknowledged.
+    token: ResumeToken,
+    /// Counts the requests that read the upload, so that only the latest acknowledges chunks.
+    attempt: u64,
+}
+
+impl Uploads {
+    /// Returns an empty set of uploads.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a new upload, returning the token the client sends its first chunks with.
+    pub fn start(&self) -> ResumeToken {
+        let mut inner = self.inner.lock().unwrap();
+        let upload_id = inner.next_id;
+        inner.next_id += 1;
+        let token = ResumeToken {
+            upload_id,
+            next_chunk: 0,
+            offset: 0,
+        };
+        inner
+            .uploads
+            .insert(upload_id, Upload { token, attempt: 0 });
+        token
+    }
+
+    /// Returns the token of upload `upload_id`, as of its last acknowledged chunk, or `None` if
+    /// there's no such upload.
+    pub fn token(&self, upload_id: u64) -> Option<ResumeToken> {
+        let inner = self.inner.lock().unwrap();
+        inner.uploads.get(&upload_id).map(|upload| upload.token)
+    }
+
+    /// Forgets upload `upload_id`, e.g. once its payload is complete, or abandoned, returning its
+    /// last token. Uploads that are never finished are kept until the `Uploads` are dropped.
+    pub fn finish(&self, upload_id: u64) -> Option<ResumeToken> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.uploads.remove(&upload_id).map(|upload| upload.token)
+    }
+
+    /// Returns a stream of the intact chunks of the upload `token` names that weren't already
+    /// acknowledged, read from `chunks`, e.g. the current request's body. Fails with
+    /// [`NotFound`](io::ErrorKind::NotFound) if there's no such upload.
+    ///
+    /// The stream fails with [`InvalidData`](io::ErrorKind::InvalidData) on a chunk that doesn't
+    /// match its checksum, or that arrives ahead of a chunk before it, and ends with an error of
+    /// kind [`Other`](io::ErrorKind::Other) if another request resumes the upload. An error ends
+    /// the stream, and the client resumes from the chunk after the last one acknowledged.
+    pub fn receive<S>(&self, token: &ResumeToken, chunks: S) -> io::Result<Receive<S>> {
+        let mut inner = self.inner.lock().unwrap();
+        let upload = match inner.uploads.get_mut(&token.upload_id) {
+            Some(upload) => upload,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("There's no upload {}.", token.upload_id),
+                ))
+            }
+        };
+        upload.attempt += 1;
+        Ok(Receive {
+            chunks,
+            uploads: self.clone(),
+            token: upload.token,
+            attempt: upload.attempt,
+            unacked: None,
+            acks: None,
+            done: false,
+        })
+    }
+}
+
+/// The intact, unacknowledged chunks of an upload. Returned by [`Uploads::receive`].
+///
+/// Each chunk is acknowledged once the next one is asked for, or the end of the upload.
+#[must_use = "streams do nothing unless polled"]
+pub struct Receive<S> {
+    chunks: S,
+    uploads: Uploads,
+    /// How much of the upload was acknowledged.
+    token: ResumeToken,
+    attempt: u64,
+    /// The length of the chunk last yielded, which is acknowledged once the next is asked for.
+    unacked: Option<u64>,
+    acks: Option<Box<dyn FnMut(ResumeToken) + Send>>,
+    done: bool,
+}
+
+impl<S> fmt::Debug for Receive<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receive")
+            .field("token", &self.token)
+            .field("attempt", &self.attempt)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl<S> Receive<S> {
+    unsafe_pinned!(chunks: S);
+    unsafe_unpinned!(token: ResumeToken);
+    unsafe_unpinned!(unacked: Option<u64>);
+    unsafe_unpinned!(acks: Option<Box<dyn FnMut(ResumeToken) + Send>>);
+    unsafe_unpinned!(done: bool);
+
+    /// Calls `acks` with the upload's updated token each time a chunk is acknowledged, e.g. to
+    /// [send](crate::server::streaming::Sender::try_send) it to the client ahead of the final
+    /// response, so that the client knows where to resume from.
+    pub fn with_acks<F>(mut self, acks: F) -> Self
+    where
+        F: FnMut(ResumeToken) + Send + 'static,
+    {
+        self.acks = Some(Box::new(acks));
+        self
+    }
+
+    /// Returns the upload's token, as of the last chunk acknowledged.
+    pub fn token(&self) -> ResumeToken {
+        self.token
+    }
+
+    /// Acknowledges the chunk last yielded, if there is one.
+    fn ack(mut self: Pin<&mut Self>) -> io::Result<()> {
+        let len = match self.as_mut().unacked().take() {
+            Some(len) => len,
+            None => return Ok(()),
+        };
+        let token = {
+            let mut inner = self.uploads.inner.lock().unwrap();
+            let upload = match inner.uploads.get_mut(&self.token.upload_id) {
+                Some(upload) if upload.attempt == self.attempt => upload,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!(
+                            "Upload {} was resumed by another request, or finished.",
+                            self.token.upload_id
+                        ),
+                    ))
+                }
+            };
+            upload.token.next_chunk += 1;
+            upload.token.offset += len;
+            upload.token
+        };
+        *self.as_mut().token() = token;
+        if let Some(acks) = self.as_mut().acks() {
+            acks(token);
+        }
+        Ok(())
+    }
+
+    fn poll_chunk(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Chunk>>>
+    where
+        S: Stream<Item = io::Result<Chunk>>,
+    {
+        self.as_mut().ack()?;
+        loop {
+            let chunk = match ready!(self.as_mut().chunks().poll_next(cx)) {
+                Some(chunk) => chunk?,
+                None => return Poll::Ready(None),
+            };
+            let upload_id = self.token.upload_id;
+            if !chunk.is_intact() {
+                return Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Chunk {} of upload {} doesn't match its checksum.",
+                        chunk.index, upload_id
+                    ),
+                ))));
+            }
+            let next_chunk = self.token.next_chunk;
+            if chunk.index < next_chunk {
+                trace!(
+                    "Skipping chunk {} of upload {}, which was already acknowledged.",
+                    chunk.index,
+                    upload_id
+                );
+                continue;
+            }
+            if chunk.index > next_chunk {
+                return Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Chunk {} of upload {} arrived ahead of chunk {}.",
+                        chunk.index, upload_id, next_chunk
+                    ),
+                ))));
+            }
+            *self.as_mut().unacked() = Some(chunk.data.len() as u64);
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+    }
+}
+
+impl<S> Stream for Receive<S>
+where
+    S: Stream<Item = io::Result<Chunk>>,
+{
+    type Item = io::Result<Chunk>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let chunk = ready!(self.as_mut().poll_chunk(cx));
+        if let None | Some(Err(_)) = chunk {
+            *self.as_mut().done() = true;
+        }
+        Poll::Ready(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, Chunk, Chunks, ResumeToken, Uploads};
+    use crate::{
+        client, context,
+        server::{
+            streaming::{self, BodyItems},
+            Server,
+        },
+        transport,
+    };
+    use futures::{compat::Executor01CompatExt, executor::block_on, future, prelude::*, stream};
+    use std::io;
+    use tokio::runtime::current_thread;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn chunks_are_numbered_from_the_token() {
+        let token = ResumeToken {
+            upload_id: 3,
+            next_chunk: 2,
+            offset: 10,
+        };
+        let chunks = Chunks::new(&token, stream::iter(vec![vec![1], vec![2, 3]]));
+        let chunks: Vec<_> = block_on(chunks.collect());
+        assert_eq!(
+            chunks,
+            vec![Chunk::new(2, vec![1]), Chunk::new(3, vec![2, 3])]
+        );
+        assert!(chunks.iter().all(Chunk::is_intact));
+    }
+
+    #[test]
+    fn corrupt_chunks_fail_the_upload() {
+        let uploads = Uploads::new();
+        let token = uploads.start();
+        let mut corrupt = Chunk::new(1, vec![4, 5]);
+        corrupt.data[0] = 6;
+        let chunks = stream::iter(vec![Ok(Chunk::new(0, vec![1, 2, 3])), Ok(corrupt)]);
+        let received: Vec<_> = block_on(uploads.receive(&token, chunks).unwrap().collect());
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].as_ref().unwrap().data, vec![1, 2, 3]);
+        assert_eq!(
+            received[1].as_ref().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        // The chunk before the corrupt one was acknowledged, but not the corrupt one.
+        let token = uploads.token(token.upload_id).unwrap();
+        assert_eq!((token.next_chunk, token.offset), (1, 3));
+    }
+
+    #[test]
+    fn interrupted_uploads_resume_from_the_last_acknowledged_chunk() {
+        let uploads = Uploads::new();
+        let start = uploads.start();
+        let payload: Vec<Vec<u8>> = vec![vec![1, 2], vec![3], vec![4, 5, 6], vec![7]];
+        let chunk = |index: usize| Ok(Chunk::new(index as u64, payload[index].clone()));
+
+        // The connection drops after the second chunk.
+        let chunks = stream::iter(vec![chunk(0), chunk(1)]).chain(stream::once(future::ready(
+            Err(io::ErrorKind::ConnectionReset.into()),
+        )));
+        let (acks_tx, acks) = std::sync::mpsc::channel();
+        let receive = uploads
+            .receive(&start, chunks)
+            .unwrap()
+            .with_acks(move |token| acks_tx.send(token).unwrap());
+        let received: Vec<_> = block_on(receive.collect());
+        assert_eq!(received.len(), 3);
+        assert!(received[2].is_err());
+        let acked: Vec<_> = acks.try_iter().collect();
+        let last = *acked.last().unwrap();
+        assert_eq!((last.next_chunk, last.offset), (2, 3));
+
+        // The client resumes from an older token, so its first chunk is skipped.
+        let resent = stream::iter(vec![chunk(1), chunk(2), chunk(3)]);
+        let received: Vec<_> = block_on(uploads.receive(&acked[0], resent).unwrap().collect());
+        let received: Vec<_> = received
+            .into_iter()
+            .map(|chunk| chunk.unwrap().data)
+            .collect();
+        assert_eq!(received, vec![vec![4, 5, 6], vec![7]]);
+        let done = uploads.finish(start.upload_id).unwrap();
+        assert_eq!((done.next_chunk, done.offset), (4, 7));
+    }
+
+    #[derive(Debug)]
+    enum Upload {
+        Resume(ResumeToken),
+        Chunk(Chunk),
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Progress {
+        Acked(ResumeToken),
+        Done(u64),
+    }
+
+    #[test]
+    fn uploads_stream_acknowledgements_to_the_client() {
+        let _ = env_logger::try_init();
+        crate::init(tokio::executor::DefaultExecutor::current().compat());
+
+        let uploads = Uploads::new();
+        let token = uploads.start();
+        let test = async move {
+            let (mut client, serving) = await!(transport::channel::loopback(
+                client::Config::default(),
+                Server::<Upload, Progress>::default(),
+                move |_ctx, request: Upload| {
+                    async move {
+                        let token = match request {
+                            Upload::Resume(token) => token,
+                            Upload::Chunk(_) => unreachable!(),
+                        };
+                        let chunks = BodyItems::new(|item| match item {
+                            Upload::Chunk(chunk) => Some(chunk),
+                            Upload::Resume(_) => None,
+                        });
+                        let mut sender = streaming::sender::<Progress>().unwrap();
+                        let mut chunks = uploads.receive(&token, chunks)?.with_acks(move |token| {
+                            let _ = sender.try_send(Progress::Acked(token));
+                        });
+                        let mut len = 0;
+                        while let Some(chunk) = await!(chunks.next()) {
+                            len += chunk?.data.len() as u64;
+                        }
+                        uploads.finish(token.upload_id);
+                        Ok(Progress::Done(len))
+                    }
+                },
+            ))?;
+            let payload = stream::iter(vec![vec![1, 2], vec![3], vec![4, 5, 6]]);
+            let chunks = Chunks::new(&token, payload).map(Upload::Chunk);
+            let progress = await!(client.stream_with_body(
+                context::current(),
+                Upload::Resume(token),
+                chunks
+            ))?;
+            let progress = await!(progress.collect::<Vec<_>>())
+                .into_iter()
+                .collect::<io::Result<Vec<_>>>()?;
+            drop(client);
+            await!(serving);
+            Ok::<_, io::Error>(progress)
+        };
+
+        let progress = current_thread::block_on_all(test.boxed().compat()).unwrap();
+        let acked = |next_chunk, offset| {
+            Progress::Acked(ResumeToken {
+                upload_id: token.upload_id,
+                next_chunk,
+                offset,
+            })
+        };
+        assert_eq!(
+            progress,
+            vec![acked(1, 2), acked(2, 3), acked(3, 6), Progress::Done(6)]
+        );
+    }
+
+    #[test]
+    fn resuming_takes_over_the_upload() {
+        let uploads = Uploads::new();
+        let token = uploads.start();
+        let mut first = uploads
+            .receive(&token, stream::iter(vec![Ok(Chunk::new(0, vec![1]))]))
+            .unwrap();
+        assert!(block_on(first.next()).unwrap().is_ok());
+        let _second = uploads.receive(&token, stream::empty::<io::Result<Chunk>>());
+        let e = block_on(first.next()).unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert_eq!(uploads.token(token.upload_id).unwrap().next_chunk, 0);
+    }
+
+    #[test]
+    fn unknown_uploads_are_not_found() {
+        let token = ResumeToken {
+            upload_id: 7,
+            next_chunk: 0,
+            offset: 0,
+        };
+        let e = Uploads::new()
+            .receive(&token, stream::empty::<io::Result<Chunk>>())
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use std::time::Duration;
+
+/// A concurrency limit that adapts to latency samples.
+///
+/// Each sample is compared against a long-term average. Within tolerance, the limit grows by about
+/// its square root, probing for more capacity; above it, requests are queueing somewhere, and the
+/// limit shrinks in proportion, by up to half. Changes are smoothed across samples.
+#[derive(Clone, Debug)]
+pub struct AdaptiveLimit {
+    limit: f64,
+    min_limit: f64,
+    max_limit: f64,
+    /// The long-term average latency, in seconds.
+    long_latency: Option<f64>,
+}
+
+/// Where limits start, unless told otherwise.
+pub const INITIAL_LIMIT: usize = 20;
+/// The number of samples averaged over by the long-term latency.
+const LONG_WINDOW: f64 = 100.;
+/// How much higher than the long-term average latency can be before the limit shrinks.
+const TOLERANCE: f64 = 1.5;
+/// How much weight each sample's limit gets in the smoothed limit.
+const SMOOTHING: f64 = 0.2;
+
+impl AdaptiveLimit {
+    /// Returns a limit that starts at `initial` and stays between 1 and `max_limit`.
+    pub fn new(initial: usize, max_limit: usize) -> Self {
+        AdaptiveLimit {
+            limit: initial.min(max_limit).max(1) as f64,
+            min_limit: 1.,
+            max_limit: max_limit.max(1) as f64,
+            long_latency: None,
+        }
+    }
+
+    /// Returns the current limit.
+    pub fn limit(&self) -> usize {
+        self.limit as usize
+    }
+
+    /// Updates the limit with the latency of a request that completed while `in_flight` requests
+    /// were in flight.
+    pub fn sample(&mut self, latency: Duration, in_flight: usize) {
+        let latency = latency.as_secs() as f64 + f64::from(latency.subsec_nanos()) * 1e-9;
+        let long_latency = match self.long_latency {
+            Some(long_latency) => long_latency + (latency - long_latency) / LONG_WINDOW,
+            None => latency,
+        };
+        self.long_latency = Some(long_latency);
+        if latency <= 0. {
+            return;
+        }
+
+        let gradient = (TOLERANCE * long_latency / latency).max(0.5).min(1.);
+        let new_limit = self.limit * gradient + self.limit.sqrt();
+        // When far from the limit, latency says nothing about whether more requests would fit.
+        if new_limit > self.limit && (in_flight as f64) < self.limit / 2. {
+            return;
+        }
+        self.limit = (self.limit * (1. - SMOOTHING) + new_limit * SMOOTHING)
+            .max(self.min_limit)
+            .min(self.max_limit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AdaptiveLimit;
+    use std::time::Duration;
+
+    #[test]
+    fn grows_while_latency_holds_and_shrinks_when_it_rises() {
+        let mut limit = AdaptiveLimit::new(10, 1000);
+        for _ in 0..20 {
+            let in_flight = limit.limit();
+            limit.sample(Duration::from_millis(10), in_flight);
+        }
+        let grown = limit.limit();
+        assert!(grown > 10, "{}", grown);
+
+        for _ in 0..20 {
+            let in_flight = limit.limit();
+            limit.sample(Duration::from_millis(100), in_flight);
+        }
+        assert!(limit.limit() < grown, "{} !< {}", limit.limit(), grown);
+    }
+
+    #[test]
+    fn does_not_grow_when_far_from_the_limit() {
+        let mut limit = AdaptiveLimit::new(10, 1000);
+        for _ in 0..20 {
+            limit.sample(Duration::from_millis(10), 1);
+        }
+        assert_eq!(limit.limit(), 10);
+    }
+}
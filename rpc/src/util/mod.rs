@@ -4,21 +4,27 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
+#[cfg(feature = "runtime")]
 use std::{
     collections::HashMap,
     hash::{BuildHasher, Hash},
     time::{Duration, SystemTime},
 };
 
+#[cfg(feature = "runtime")]
+pub mod concurrency;
+#[cfg(feature = "runtime")]
 pub mod deadline_compat;
 #[cfg(feature = "serde")]
 pub mod serde;
 
+#[cfg(feature = "runtime")]
 /// Types that can be represented by a [`Duration`].
 pub trait AsDuration {
     fn as_duration(&self) -> Duration;
 }
 
+#[cfg(feature = "runtime")]
 impl AsDuration for SystemTime {
     /// Duration of 0 if self is earlier than [`SystemTime::now`].
     fn as_duration(&self) -> Duration {
@@ -26,12 +32,26 @@ impl AsDuration for SystemTime {
     }
 }
 
+/// Returns how long after `earlier` `now` is, or zero if it isn't after, where
+/// `Instant::duration_since` would panic, e.g. when another thread read the clock first but
+/// recorded its reading later.
+#[cfg(feature = "runtime")]
+pub(crate) fn elapsed_since(now: std::time::Instant, earlier: std::time::Instant) -> Duration {
+    if now > earlier {
+        now - earlier
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+#[cfg(feature = "runtime")]
 /// Collection compaction; configurable `shrink_to_fit`.
 pub trait Compact {
     /// Compacts space if the ratio of length : capacity is less than `usage_ratio_threshold`.
     fn compact(&mut self, usage_ratio_threshold: f64);
 }
 
+#[cfg(feature = "runtime")]
 impl<K, V, H> Compact for HashMap<K, V, H>
 where
     K: Eq + Hash,
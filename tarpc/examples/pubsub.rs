@@ -60,19 +60,12 @@ impl subscriber::Service for Subscriber {
 }
 
 impl Subscriber {
-    async fn listen(id: u32, config: server::Config) -> io::Result<SocketAddr> {
-        let incoming = bincode_transport::listen(&"0.0.0.0:0".parse().unwrap())?;
-        let addr = incoming.local_addr();
-        tokio_executor::spawn(
-            server::new(config)
-                .incoming(incoming)
-                .take(1)
-                .respond_with(subscriber::serve(Subscriber { id }))
-                .unit_error()
-                .boxed()
-                .compat(),
-        );
-        Ok(addr)
+    fn listen(id: u32, config: server::Config) -> io::Result<bincode_transport::Listening> {
+        bincode_transport::serve(
+            &"0.0.0.0:0".parse().unwrap(),
+            server::new(config),
+            subscriber::serve(Subscriber { id }),
+        )
     }
 }
 
@@ -153,8 +146,8 @@ async fn run() -> io::Result<()> {
             .compat(),
     );
 
-    let subscriber1 = await!(Subscriber::listen(0, server::Config::default()))?;
-    let subscriber2 = await!(Subscriber::listen(1, server::Config::default()))?;
+    let subscriber1 = Subscriber::listen(0, server::Config::default())?;
+    let subscriber2 = Subscriber::listen(1, server::Config::default())?;
 
     let publisher_conn = bincode_transport::connect(&publisher_addr);
     let publisher_conn = await!(publisher_conn)?;
@@ -163,10 +156,10 @@ async fn run() -> io::Result<()> {
         publisher_conn
     ))?;
 
-    if let Err(e) = await!(publisher.subscribe(context::current(), 0, subscriber1))? {
+    if let Err(e) = await!(publisher.subscribe(context::current(), 0, subscriber1.local_addr()))? {
         eprintln!("Couldn't subscribe subscriber 0: {}", e);
     }
-    if let Err(e) = await!(publisher.subscribe(context::current(), 1, subscriber2))? {
+    if let Err(e) = await!(publisher.subscribe(context::current(), 1, subscriber2.local_addr()))? {
         eprintln!("Couldn't subscribe subscriber 1: {}", e);
     }
 
@@ -174,6 +167,10 @@ async fn run() -> io::Result<()> {
     await!(publisher.broadcast(context::current(), "hello to all".to_string()))?;
     await!(publisher.unsubscribe(context::current(), 1))?;
     await!(publisher.broadcast(context::current(), "hi again".to_string()))?;
+
+    let grace_period = Duration::from_secs(1);
+    await!(subscriber1.shutdown(grace_period));
+    await!(subscriber2.shutdown(grace_period));
     Ok(())
 }
 
@@ -0,0 +1,63 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use std::fmt;
+
+/// The `Request` field holding an rpc argument marked `#[default]`.
+///
+/// It's encoded as an `Option`, so self-describing codecs, e.g. JSON, decode a request that lacks
+/// the field, sent by a client that predates the argument, with the argument's default.
+#[doc(hidden)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Defaulted<T>(pub T);
+
+impl<T: fmt::Debug> fmt::Debug for Defaulted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use super::Defaulted;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    impl<T: Serialize> Serialize for Defaulted<T> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_some(&self.0)
+        }
+    }
+
+    impl<'de, T: Deserialize<'de> + Default> Deserialize<'de> for Defaulted<T> {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            // A missing field is decoded as `None`.
+            let value = Option::<T>::deserialize(deserializer)?;
+            Ok(Defaulted(value.unwrap_or_default()))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::Defaulted;
+    use serde::{de::IntoDeserializer, Deserialize};
+
+    #[test]
+    fn missing_value_is_defaulted() {
+        let missing: serde::de::value::UnitDeserializer<serde::de::value::Error> =
+            ().into_deserializer();
+        assert_eq!(Defaulted::<u32>::deserialize(missing), Ok(Defaulted(0)));
+    }
+
+    #[test]
+    fn roundtrips() {
+        let encoded = bincode::serialize(&Defaulted(7u32)).unwrap();
+        assert_eq!(
+            bincode::deserialize::<Defaulted<u32>>(&encoded).unwrap(),
+            Defaulted(7)
+        );
+    }
+}
@@ -21,6 +21,14 @@ pub use serde;
 #[doc(hidden)]
 pub use tarpc_plugins::*;
 
+mod defaulted;
+#[doc(hidden)]
+pub use crate::defaulted::Defaulted;
+mod request;
+pub use crate::request::MethodRequest;
+
 /// Provides the macro used for constructing rpc services and client stubs.
 #[macro_use]
 mod macros;
+#[cfg(feature = "serde1")]
+pub mod types;
@@ -25,6 +25,498 @@ macro_rules! add_serde_if_enabled {
     }
 }
 
+#[doc(hidden)]
+#[macro_export]
+macro_rules! rpc_arg {
+    // The type of the Request field holding the arg. A streamed arg is sent in items after the
+    // request instead.
+    (@ty #[stream] $(#[$attr:ident])* $ty:ty) => {
+        ()
+    };
+    (@ty #[boxed] $(#[$attr:ident])* $ty:ty) => {
+        Box<$crate::rpc_arg!(@ty $(#[$attr])* $ty)>
+    };
+    (@ty #[default] $(#[$attr:ident])* $ty:ty) => {
+        $crate::Defaulted<$crate::rpc_arg!(@ty $(#[$attr])* $ty)>
+    };
+    (@ty #[$other:ident] $(#[$attr:ident])* $ty:ty) => {
+        $crate::rpc_arg!(@ty $(#[$attr])* $ty)
+    };
+    (@ty $ty:ty) => {
+        $ty
+    };
+    // Converts the arg into its Request field.
+    (@wrap #[stream] $(#[$attr:ident])* $arg:ident) => {
+        ()
+    };
+    (@wrap #[boxed] $(#[$attr:ident])* $arg:ident) => {
+        Box::new($crate::rpc_arg!(@wrap $(#[$attr])* $arg))
+    };
+    (@wrap #[default] $(#[$attr:ident])* $arg:ident) => {
+        $crate::Defaulted($crate::rpc_arg!(@wrap $(#[$attr])* $arg))
+    };
+    (@wrap #[$other:ident] $(#[$attr:ident])* $arg:ident) => {
+        $crate::rpc_arg!(@wrap $(#[$attr])* $arg)
+    };
+    (@wrap $arg:ident) => {
+        $arg
+    };
+    // Converts the Request field of an arg of rpc `$fn_name` back into the arg. The first
+    // attribute wrapped the field last, so it's unwrapped first. A streamed arg is read from the
+    // items of the request's body instead.
+    (@unwrap $fn_name:ident $(#[$attr:ident])* $arg:ident) => {
+        $crate::rpc_body! {
+            @if_body [[$(#[$attr])*]]
+            {{
+                let _ = $arg;
+                $crate::server::streaming::BodyItems::new(|item__| match item__ {
+                    Request::__Item(RequestItem::$fn_name(item__)) => {
+                        ::std::option::Option::Some(item__)
+                    }
+                    _ => ::std::option::Option::None,
+                })
+            }}
+            { $crate::rpc_arg!(@unwrap_field ($arg) $(#[$attr])*) }
+        }
+    };
+    (@unwrap_field ($field:expr) #[boxed] $(#[$attr:ident])*) => {
+        $crate::rpc_arg!(@unwrap_field (*$field) $(#[$attr])*)
+    };
+    (@unwrap_field ($field:expr) #[default] $(#[$attr:ident])*) => {
+        $crate::rpc_arg!(@unwrap_field ($field.0) $(#[$attr])*)
+    };
+    (@unwrap_field ($field:expr) #[$other:ident] $(#[$attr:ident])*) => {
+        $crate::rpc_arg!(@unwrap_field ($field) $(#[$attr])*)
+    };
+    (@unwrap_field ($field:expr)) => {
+        $field
+    };
+    // The type the service fn takes the arg as.
+    (@service_ty #[stream] $(#[$attr:ident])* $ty:ty) => {
+        $crate::server::streaming::BodyItems<Request, $ty>
+    };
+    (@service_ty #[$other:ident] $(#[$attr:ident])* $ty:ty) => {
+        $crate::rpc_arg!(@service_ty $(#[$attr])* $ty)
+    };
+    (@service_ty $ty:ty) => {
+        $ty
+    };
+    // The type the client stub takes the arg as.
+    (@stub_ty #[stream] $(#[$attr:ident])* $ty:ty) => {
+        impl $crate::futures::Stream<Item = $ty> + Send + 'static
+    };
+    (@stub_ty #[$other:ident] $(#[$attr:ident])* $ty:ty) => {
+        $crate::rpc_arg!(@stub_ty $(#[$attr])* $ty)
+    };
+    (@stub_ty $ty:ty) => {
+        $ty
+    };
+    // The Debug representation of a reference to the Request field.
+    (@debug #[stream] $(#[$attr:ident])* $arg:ident) => {
+        &format_args!("<streamed>")
+    };
+    (@debug #[sensitive] $(#[$attr:ident])* $arg:ident) => {
+        &format_args!("<redacted>")
+    };
+    (@debug #[boxed] $(#[$attr:ident])* $arg:ident) => {
+        $crate::rpc_arg!(@debug $(#[$attr])* $arg)
+    };
+    (@debug #[default] $(#[$attr:ident])* $arg:ident) => {
+        $crate::rpc_arg!(@debug $(#[$attr])* $arg)
+    };
+    (@debug #[$other:ident] $(#[$attr:ident])* $arg:ident) => {
+        compile_error!(concat!(
+            "Unknown rpc argument attribute `",
+            stringify!($other),
+            "`; expected `sensitive`, `boxed`, `default`, or `stream`."
+        ))
+    };
+    (@debug $arg:ident) => {
+        $arg
+    };
+}
+
+/// The streamed body of an rpc, given its args, each as its attributes in brackets followed by
+/// its type or name.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! rpc_body {
+    // The type of the items of the rpc's body: its `#[stream]` arg's type, or `()` if it has none.
+    (@ty) => {
+        ()
+    };
+    (@ty [#[stream] $(#[$attr:ident])*] $ty:ty $(, $($rest:tt)*)?) => {
+        $ty
+    };
+    (@ty [#[$other:ident] $(#[$attr:ident])*] $ty:ty $(, $($rest:tt)*)?) => {
+        $crate::rpc_body!(@ty [$(#[$attr])*] $ty $(, $($rest)*)?)
+    };
+    (@ty [] $ty:ty $(, $($rest:tt)*)?) => {
+        $crate::rpc_body!(@ty $($($rest)*)?)
+    };
+    // The rpc's `#[stream]` arg.
+    (@arg [#[stream] $(#[$attr:ident])*] $arg:ident $(, $($rest:tt)*)?) => {
+        $arg
+    };
+    (@arg [#[$other:ident] $(#[$attr:ident])*] $arg:ident $(, $($rest:tt)*)?) => {
+        $crate::rpc_body!(@arg [$(#[$attr])*] $arg $(, $($rest)*)?)
+    };
+    (@arg [] $arg:ident $(, $($rest:tt)*)?) => {
+        $crate::rpc_body!(@arg $($($rest)*)?)
+    };
+    // Expands to the tokens of `$then` if the rpc has a `#[stream]` arg, else to `$else`'s. Takes
+    // only the args' attributes.
+    (@if_body [] { $($then:tt)* } { $($else:tt)* }) => {
+        $($else)*
+    };
+    (@if_body [[#[stream] $(#[$attr:ident])*] $($rest:tt)*] { $($then:tt)* } { $($else:tt)* }) => {
+        $($then)*
+    };
+    (@if_body [[#[$other:ident] $(#[$attr:ident])*] $($rest:tt)*] $then:tt $else:tt) => {
+        $crate::rpc_body!(@if_body [[$(#[$attr])*] $($rest)*] $then $else)
+    };
+    (@if_body [[] $($rest:tt)*] $then:tt $else:tt) => {
+        $crate::rpc_body!(@if_body [$($rest)*] $then $else)
+    };
+}
+
+/// The delivery semantics of an rpc, by its kind and its `at_most_once` or `at_least_once`
+/// declaration, if any. Notifications are always at most once.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! rpc_delivery {
+    // The marker type of the rpc's delivery semantics, which its client stub's client must deliver.
+    (@ty notify $fn_name:ident [at_least_once]) => {
+        compile_error!(concat!(
+            "Notify method `",
+            stringify!($fn_name),
+            "` can't be `at_least_once`; notifications are delivered at most once."
+        ))
+    };
+    (@ty notify $fn_name:ident []) => {
+        $crate::client::delivery::AtMostOnce
+    };
+    (@ty $kind:ident $fn_name:ident [at_most_once]) => {
+        $crate::client::delivery::AtMostOnce
+    };
+    (@ty $kind:ident $fn_name:ident [at_least_once]) => {
+        $crate::client::delivery::AtLeastOnce
+    };
+    (@ty $kind:ident $fn_name:ident []) => {
+        $crate::client::delivery::Unspecified
+    };
+    (@ty $kind:ident $fn_name:ident [$other:ident]) => {
+        compile_error!(concat!(
+            "Unknown delivery `",
+            stringify!($other),
+            "` of method `",
+            stringify!($fn_name),
+            "`; expected `at_most_once` or `at_least_once`."
+        ))
+    };
+    // The rpc's `Delivery`, if declared. Unknown deliveries fail to compile in `@ty`.
+    (@value notify $($rest:tt)*) => {
+        ::std::option::Option::Some($crate::client::delivery::Delivery::AtMostOnce)
+    };
+    (@value $kind:ident [at_most_once]) => {
+        ::std::option::Option::Some($crate::client::delivery::Delivery::AtMostOnce)
+    };
+    (@value $kind:ident [at_least_once]) => {
+        ::std::option::Option::Some($crate::client::delivery::Delivery::AtLeastOnce)
+    };
+    (@value $($rest:tt)*) => {
+        ::std::option::Option::None
+    };
+    // The copy of a request to resend: `$resend`, for at-least-once rpcs, else None.
+    (@resend $kind:ident [at_least_once] $resend:expr) => {
+        ::std::option::Option::Some($resend)
+    };
+    (@resend $kind:ident [$($delivery:ident)?] $resend:expr) => {
+        ::std::option::Option::None
+    };
+}
+
+/// The client stub's fn for an rpc, by the rpc's kind, and by whether it has a `#[stream]` arg.
+/// Its client must deliver the rpc's delivery semantics, `$delivery`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! rpc_stub {
+    (
+        @plain rpc [$delivery:ty]
+        $(#[$attr:meta])*
+        $fn_name:ident( $( $(#[$arg_attr:ident])* $arg:ident : $in_:ty ),* ) -> $out:ty
+    ) => {
+        #[allow(unused)]
+        $(#[$attr])*
+        pub fn $fn_name(&mut self, ctx: $crate::context::Context, $($arg: $in_),*)
+            -> impl ::std::future::Future<Output = ::std::io::Result<$out>> + '_
+        where
+            C: $crate::client::delivery::Delivers<$delivery>
+        {
+            let request__ = Request::$fn_name {
+                $($arg: $crate::rpc_arg!(@wrap $(#[$arg_attr])* $arg),)*
+            };
+            let resp = $crate::Client::call(&mut self.0, ctx, request__);
+            async move {
+                match await!(resp)? {
+                    Response::$fn_name(msg__) => ::std::result::Result::Ok(msg__),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    };
+    // A notification's response is dropped by the server, so its stub doesn't wait for one.
+    (
+        @plain notify [$delivery:ty]
+        $(#[$attr:meta])*
+        $fn_name:ident( $( $(#[$arg_attr:ident])* $arg:ident : $in_:ty ),* ) -> $out:ty
+    ) => {
+        #[allow(unused)]
+        $(#[$attr])*
+        pub fn $fn_name(&mut self, ctx: $crate::context::Context, $($arg: $in_),*)
+            -> impl ::std::future::Future<Output = ::std::io::Result<()>> + '_
+        where
+            for<'a> C: $crate::client::Notify<'a, Request>,
+            C: $crate::client::delivery::Delivers<$delivery>,
+        {
+            let request__ = Request::$fn_name {
+                $($arg: $crate::rpc_arg!(@wrap $(#[$arg_attr])* $arg),)*
+            };
+            $crate::client::Notify::notify(&mut self.0, ctx, request__)
+        }
+    };
+    // A streamed rpc's stub resolves to a stream of its items once the request is queued.
+    (
+        @plain stream [$delivery:ty]
+        $(#[$attr:meta])*
+        $fn_name:ident( $( $(#[$arg_attr:ident])* $arg:ident : $in_:ty ),* ) -> $out:ty
+    ) => {
+        #[allow(unused)]
+        $(#[$attr])*
+        pub fn $fn_name(&mut self, ctx: $crate::context::Context, $($arg: $in_),*)
+            -> impl ::std::future::Future<
+                Output = ::std::io::Result<$crate::client::channel::Items<Response, $out>>
+            > + '_
+        where
+            for<'a> C: $crate::client::Streaming<'a, Request, Response = Response>,
+            C: $crate::client::delivery::Delivers<$delivery>,
+        {
+            let request__ = Request::$fn_name {
+                $($arg: $crate::rpc_arg!(@wrap $(#[$arg_attr])* $arg),)*
+            };
+            let stream = $crate::client::Streaming::stream(&mut self.0, ctx, request__);
+            async move {
+                let stream = await!(stream)?;
+                ::std::result::Result::Ok(stream.items(|msg__| match msg__ {
+                    Response::$fn_name(msg__) => msg__,
+                    _ => unreachable!(),
+                }))
+            }
+        }
+    };
+    // The stub of an rpc with a streamed arg boxes the arg's stream, converted into the
+    // Request's items, for the client to send after the request.
+    (
+        @body rpc [$delivery:ty]
+        $(#[$attr:meta])*
+        $fn_name:ident( $( $(#[$arg_attr:ident])* $arg:ident : $in_:ty ),* ) -> $out:ty
+    ) => {
+        #[allow(unused)]
+        $(#[$attr])*
+        pub fn $fn_name(
+            &mut self,
+            ctx: $crate::context::Context,
+            $($arg: $crate::rpc_arg!(@stub_ty $(#[$arg_attr])* $in_)),*
+        ) -> impl ::std::future::Future<Output = ::std::io::Result<$out>> + '_
+        where
+            for<'a> C: $crate::client::Uploading<
+                'a,
+                Request,
+                ::std::pin::Pin<Box<dyn $crate::futures::Stream<Item = Request> + Send>>,
+                Response = Response,
+            >,
+            C: $crate::client::delivery::Delivers<$delivery>,
+        {
+            let body__ = $crate::rpc_stub!(
+                @body_items $fn_name $crate::rpc_body!(@arg $( [$(#[$arg_attr])*] $arg ),*)
+            );
+            let request__ = Request::$fn_name {
+                $($arg: $crate::rpc_arg!(@wrap $(#[$arg_attr])* $arg),)*
+            };
+            let resp = $crate::client::Uploading::call_with_body(&mut self.0, ctx, request__, body__);
+            async move {
+                match await!(resp)? {
+                    Response::$fn_name(msg__) => ::std::result::Result::Ok(msg__),
+                    _ => unreachable!(),
+                }
+            }
+        }
+    };
+    (
+        @body stream [$delivery:ty]
+        $(#[$attr:meta])*
+        $fn_name:ident( $( $(#[$arg_attr:ident])* $arg:ident : $in_:ty ),* ) -> $out:ty
+    ) => {
+        #[allow(unused)]
+        $(#[$attr])*
+        pub fn $fn_name(
+            &mut self,
+            ctx: $crate::context::Context,
+            $($arg: $crate::rpc_arg!(@stub_ty $(#[$arg_attr])* $in_)),*
+        ) -> impl ::std::future::Future<
+            Output = ::std::io::Result<$crate::client::channel::Items<
+                Response,
+                $out,
+                $crate::client::channel::BodyStream<
+                    Request,
+                    Response,
+                    ::std::pin::Pin<Box<dyn $crate::futures::Stream<Item = Request> + Send>>,
+                >,
+            >>
+        > + '_
+        where
+            for<'a> C: $crate::client::Uploading<
+                'a,
+                Request,
+                ::std::pin::Pin<Box<dyn $crate::futures::Stream<Item = Request> + Send>>,
+                Response = Response,
+            >,
+            C: $crate::client::delivery::Delivers<$delivery>,
+        {
+            let body__ = $crate::rpc_stub!(
+                @body_items $fn_name $crate::rpc_body!(@arg $( [$(#[$arg_attr])*] $arg ),*)
+            );
+            let request__ = Request::$fn_name {
+                $($arg: $crate::rpc_arg!(@wrap $(#[$arg_attr])* $arg),)*
+            };
+            let stream =
+                $crate::client::Uploading::stream_with_body(&mut self.0, ctx, request__, body__);
+            async move {
+                let stream = await!(stream)?;
+                ::std::result::Result::Ok(stream.items(|msg__| match msg__ {
+                    Response::$fn_name(msg__) => msg__,
+                    _ => unreachable!(),
+                }))
+            }
+        }
+    };
+    (
+        @body notify [$delivery:ty]
+        $(#[$attr:meta])*
+        $fn_name:ident( $($args:tt)* ) -> $out:ty
+    ) => {
+        compile_error!(concat!(
+            "Notify method `",
+            stringify!($fn_name),
+            "` can't have a `#[stream]` argument; notifications can't be streamed."
+        ));
+    };
+    // Converts the items of a stub's streamed arg into the Request's items.
+    (@body_items $fn_name:ident $body:expr) => {
+        ::std::boxed::Box::pin($crate::futures::StreamExt::map($body, |item__| {
+            Request::__Item(RequestItem::$fn_name(item__))
+        })) as ::std::pin::Pin<Box<dyn $crate::futures::Stream<Item = Request> + Send>>
+    };
+    (@$mode:ident $other:ident $($rest:tt)*) => {
+        compile_error!(concat!(
+            "Unknown rpc kind `",
+            stringify!($other),
+            "`; expected `rpc`, `notify`, or `stream`."
+        ));
+    };
+    (
+        $kind:ident [$delivery:ty]
+        $(#[$attr:meta])*
+        $fn_name:ident( $( $(#[$arg_attr:ident])* $arg:ident : $in_:ty ),* ) -> $out:ty
+    ) => {
+        $crate::rpc_body! {
+            @if_body [$( [$(#[$arg_attr])*] )*]
+            {
+                $crate::rpc_stub! {
+                    @body $kind [$delivery]
+                    $(#[$attr])*
+                    $fn_name( $( $(#[$arg_attr])* $arg : $in_ ),* ) -> $out
+                }
+            }
+            {
+                $crate::rpc_stub! {
+                    @plain $kind [$delivery]
+                    $(#[$attr])*
+                    $fn_name( $( $(#[$arg_attr])* $arg : $in_ ),* ) -> $out
+                }
+            }
+        }
+    };
+}
+
+/// The server side of an rpc, by the rpc's kind. A streamed rpc's service fn returns a stream,
+/// whose items are sent in its `Response` variant, followed by `None` to end the stream.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! rpc_kind {
+    // The type held by the rpc's Response variant.
+    (@response stream $out:ty) => {
+        ::std::option::Option<$out>
+    };
+    (@response $kind:ident $out:ty) => {
+        $out
+    };
+    // The Service trait's associated type for what the rpc's fn returns.
+    (@service_ty stream $fn_name:ident $out:ty) => {
+        $crate::snake_to_camel! {
+            /// The type of stream returned by `{}`.
+            type $fn_name: Stream__<Item = $out> + Send;
+        }
+    };
+    (@service_ty $kind:ident $fn_name:ident $out:ty) => {
+        $crate::snake_to_camel! {
+            /// The type of future returned by `{}`.
+            type $fn_name: Future__<Output = $out> + Send;
+        }
+    };
+    // The type of the rpc's ResponseFut variant, given the type the service fn returns.
+    (@response_fut stream $ret:ty) => {
+        $crate::server::streaming::Forward<$ret, Response>
+    };
+    (@response_fut $kind:ident $ret:ty) => {
+        $ret
+    };
+    // Makes the rpc's ResponseFut from what the service fn returned.
+    (@serve stream $fn_name:ident $ret:expr) => {
+        ResponseFut::$fn_name($crate::server::streaming::Forward::new(
+            $ret,
+            |item__| Response::$fn_name(::std::option::Option::Some(item__)),
+            Response::$fn_name(::std::option::Option::None),
+        ))
+    };
+    (@serve $kind:ident $fn_name:ident $ret:expr) => {
+        ResponseFut::$fn_name($ret)
+    };
+    // Converts the output of the rpc's ResponseFut into the response.
+    (@poll stream $fn_name:ident $poll:expr) => {
+        $poll
+    };
+    (@poll $kind:ident $fn_name:ident $poll:expr) => {
+        $poll.map(Response::$fn_name).map(Ok)
+    };
+    // Lets a stub's `call` fn send the request struct of an `rpc` method.
+    (@method_request rpc $fn_name:ident $out:ty [$delivery:ty]) => {
+        impl $crate::MethodRequest<Request, Response> for $crate::ty_snake_to_request!($fn_name) {
+            type Output = $out;
+            type Delivery = $delivery;
+
+            #[allow(unused)]
+            fn output(response: Response) -> $out {
+                match response {
+                    Response::$fn_name(msg__) => msg__,
+                    _ => unreachable!(),
+                }
+            }
+        }
+    };
+    (@method_request $kind:ident $($rest:tt)*) => {};
+}
+
 /// The main macro that creates RPC services.
 ///
 /// Rpc methods are specified, mirroring trait syntax:
@@ -42,12 +534,187 @@ macro_rules! add_serde_if_enabled {
 /// will then be attached to the generated service traits'
 /// corresponding `fn`s, as well as to the client stubs' RPCs.
 ///
+/// Arguments holding secrets, like passwords or tokens, can be marked `#[sensitive]`. Their
+/// values are redacted from the `Debug` output of the generated `Request`, so they don't leak
+/// into logs:
+///
+/// ```
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// rpc login(user: String, #[sensitive] password: String) -> bool;
+/// # }
+/// ```
+///
+/// Large arguments can be marked `#[boxed]`, so that the generated `Request` holds them on the
+/// heap; otherwise every `Request` is as large as the largest rpc's arguments. The service and
+/// client still take the argument by value. To do the same for a large return type, return a
+/// `Box`:
+///
+/// ```
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # type Image = [u64; 32];
+/// # tarpc::service! {
+/// rpc rotate(#[boxed] image: Image) -> Box<Image>;
+/// # }
+/// ```
+///
+/// Each rpc's arguments are sent as the fields of its `Request` variant, by name, so an rpc can
+/// take any number of arguments, and codecs that encode field names, like JSON, show them. An
+/// argument added to an existing rpc can be marked `#[default]`, so that, with such a codec,
+/// requests from clients that predate it are handled with the argument's `Default` value. Codecs
+/// that don't encode field names, like bincode, still need every client to send every argument:
+///
+/// ```
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// rpc search(query: String, #[default] max_results: u32) -> Vec<String>;
+/// # }
+/// ```
+///
+/// Each method without a `#[stream]` argument also gets a request struct, named after it in
+/// CamelCase with a `Request` suffix, which holds its arguments. Its `new` fn takes the arguments
+/// not marked `#[default]`, which can be set with `with_` setters instead, so callers that build
+/// requests this way keep compiling when defaulted arguments are added. The request struct of an
+/// `rpc` method can be sent with the client stub's `call` fn, and any request struct converts
+/// into a `Request`:
+///
+/// ```
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// # rpc search(query: String, #[default] max_results: u32) -> Vec<String>;
+/// # }
+/// async fn search(client: &mut Client) -> std::io::Result<Vec<String>> {
+///     let request = SearchRequest::new("tarpc".into()).with_max_results(10);
+///     await!(client.call(tarpc::context::current(), request))
+/// }
+/// ```
+///
+/// Static labels, like the team that owns an rpc, can be listed in brackets after its return type.
+/// They are available from the generated `Request::labels`, so metrics can be broken down by
+/// them:
+///
+/// ```
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// rpc search(query: String) -> Vec<String> [team = "search", tier = "1"];
+/// rpc report(bug: String) [team = "support"];
+/// # }
+/// ```
+///
+/// Methods declared with `notify` instead of `rpc` are one-way: the client stub sends the request
+/// and resolves once it's queued, without waiting for a response, and the server drops the
+/// handler's response instead of sending it back. A client doesn't know whether, or when, a
+/// notification is handled, so notify methods can't declare a return type. Their stub fns need a
+/// client that implements `rpc::client::Notify`, like `client::Channel`:
+///
+/// ```
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// notify log(line: String);
+/// # }
+/// ```
+///
+/// A notify method that declares a return type doesn't compile:
+///
+/// ```compile_fail
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// notify log(line: String) -> bool;
+/// # }
+/// ```
+///
+/// Methods declared with `stream` stream their responses: the service fn returns a stream
+/// instead of a future, and the client stub resolves, once the request is queued, to a stream of
+/// the items the server sends as they're ready, ending with the service's stream. Their stub fns
+/// need a client that implements `rpc::client::Streaming`, like `client::Channel`:
+///
+/// ```
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// stream tail(file: String) -> String;
+/// # }
+/// ```
+///
+/// An argument marked `#[stream]` is streamed to the server as the request's body: the client
+/// stub takes a stream of the argument's type, whose items are sent after the request, and the
+/// service fn takes an `rpc::server::streaming::BodyItems` stream of them instead, which yields
+/// them as they arrive. It must be polled by the future, or stream, the service fn returns. An rpc
+/// can have one streamed argument, and a `stream` rpc with one makes a bidirectional stream. Their
+/// stub fns need a client that implements `rpc::client::Uploading`, like `client::Channel`:
+///
+/// ```
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// rpc upload(file: String, #[stream] chunk: Vec<u8>) -> u64;
+/// stream chat(#[stream] line: String) -> String;
+/// # }
+/// ```
+///
+/// Notifications can't have streamed arguments:
+///
+/// ```compile_fail
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// notify log(#[stream] line: String);
+/// # }
+/// ```
+///
+/// An rpc's delivery semantics can be declared in parentheses after its kind. A method that isn't
+/// idempotent, like charging a card, can be declared `at_most_once`, so that its requests are never
+/// resent; its stub fn then needs a client that implements `rpc::client::delivery::SendsOnce`,
+/// like `client::Channel`, but unlike a `client::retry::Retry` client, which resends failed calls.
+/// A method that's safe to repeat can be declared `at_least_once`, so that a `Retry` client
+/// resends its requests; its arguments must then be `Clone`. Notify methods are always at most
+/// once. The generated `Request::delivery` returns the delivery a request's rpc was declared with:
+///
+/// ```
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// rpc(at_least_once) balance(account: String) -> u64;
+/// rpc(at_most_once) charge(account: String, cents: u64);
+/// # }
+///
+/// fn balance(client: &mut Client<tarpc::client::retry::Retry<Request, Response>>) {
+///     let _ = client.balance(tarpc::context::current(), "alice".into());
+/// }
+/// ```
+///
+/// An at-most-once method can't be called through a client that retries its calls:
+///
+/// ```compile_fail
+/// # #![feature(await_macro, arbitrary_self_types, async_await, proc_macro_hygiene)]
+/// # fn main() {}
+/// # tarpc::service! {
+/// # rpc(at_most_once) charge(account: String, cents: u64);
+/// # }
+/// fn charge(client: &mut Client<tarpc::client::retry::Retry<Request, Response>>) {
+///     let _ = client.charge(tarpc::context::current(), "alice".into(), 100);
+/// }
+/// ```
+///
 /// The following items are expanded in the enclosing module:
 ///
 /// * `trait Service` -- defines the RPC service.
 ///   * `fn serve` -- turns a service impl into a request handler.
+/// * `<Method>Request` -- the request struct of each RPC without a `#[stream]` argument.
 /// * `Client` -- a client stub with a fn for each RPC.
+///   * `fn call` -- calls an RPC with its request struct.
 ///   * `fn new_stub` -- creates a new Client stub.
+///   * `fn new_loopback_stub` -- creates a new Client stub of a service served in-process.
+///   * `From<C>` -- creates a Client stub from any `rpc::Client`, e.g. a `client::Channel` with a
+///     hook from `rpc::Client::try_map_response` that checks every response before it reaches
+///     the caller.
 ///
 #[macro_export]
 macro_rules! service {
@@ -58,13 +725,16 @@ macro_rules! service {
     (
         $(
             $(#[$attr:meta])*
-            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) $(-> $out:ty)*;
+            $kind:ident $(($delivery:ident))?
+                $fn_name:ident( $( $(#[$arg_attr:ident])* $arg:ident : $in_:ty ),* ) $(-> $out:ty)*
+                $([$($label:ident = $value:literal),*])?;
         )*
     ) => {
         $crate::service! {{
             $(
                 $(#[$attr])*
-                rpc $fn_name( $( $arg : $in_ ),* ) $(-> $out)*;
+                $kind $(($delivery))? $fn_name( $( $(#[$arg_attr])* $arg : $in_ ),* ) $(-> $out)*
+                    $([$($label = $value),*])?;
             )*
         }}
     };
@@ -72,7 +742,9 @@ macro_rules! service {
     (
         {
             $(#[$attr:meta])*
-            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* );
+            $kind:ident $(($delivery:ident))?
+                $fn_name:ident( $( $(#[$arg_attr:ident])* $arg:ident : $in_:ty ),* )
+                $([$($label:ident = $value:literal),*])?;
 
             $( $unexpanded:tt )*
         }
@@ -84,14 +756,36 @@ macro_rules! service {
             $( $expanded )*
 
             $(#[$attr])*
-            rpc $fn_name( $( $arg : $in_ ),* ) -> ();
+            $kind $(($delivery))?
+                $fn_name( $( $(#[$arg_attr])* $arg : $in_ ),* ) -> () $([$($label = $value),*])?;
         }
     };
+// Pattern for when the next rpc is a notification with a return type, which the client never
+// receives.
+    (
+        {
+            $(#[$attr:meta])*
+            notify $(($delivery:ident))?
+                $fn_name:ident( $( $(#[$arg_attr:ident])* $arg:ident : $in_:ty ),* ) -> $out:ty
+                $([$($label:ident = $value:literal),*])?;
+
+            $( $unexpanded:tt )*
+        }
+        $( $expanded:tt )*
+    ) => {
+        compile_error!(concat!(
+            "Notify method `",
+            stringify!($fn_name),
+            "` can't have a return type; the client never receives the response."
+        ));
+    };
 // Pattern for when the next rpc has an explicit return type.
     (
         {
             $(#[$attr:meta])*
-            rpc $fn_name:ident( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
+            $kind:ident $(($delivery:ident))?
+                $fn_name:ident( $( $(#[$arg_attr:ident])* $arg:ident : $in_:ty ),* ) -> $out:ty
+                $([$($label:ident = $value:literal),*])?;
 
             $( $unexpanded:tt )*
         }
@@ -103,7 +797,8 @@ macro_rules! service {
             $( $expanded )*
 
             $(#[$attr])*
-            rpc $fn_name( $( $arg : $in_ ),* ) -> $out;
+            $kind $(($delivery))?
+                $fn_name( $( $(#[$arg_attr])* $arg : $in_ ),* ) -> $out $([$($label = $value),*])?;
         }
     };
 // Pattern for when all return types have been expanded
@@ -111,19 +806,149 @@ macro_rules! service {
         { } // none left to expand
         $(
             $(#[$attr:meta])*
-            rpc $fn_name:ident ( $( $arg:ident : $in_:ty ),* ) -> $out:ty;
+            $kind:ident $(($delivery:ident))?
+                $fn_name:ident ( $( $(#[$arg_attr:ident])* $arg:ident : $in_:ty ),* ) -> $out:ty
+                $([$($label:ident = $value:literal),*])?;
         )*
     ) => {
         $crate::add_serde_if_enabled! {
             /// The request sent over the wire from the client to the server.
-            #[derive(Debug)]
             #[allow(non_camel_case_types, unused)]
             --
             pub enum Request {
                 $(
                     $(#[$attr])*
-                    $fn_name{ $($arg: $in_,)* }
-                ),*
+                    $fn_name{ $($arg: $crate::rpc_arg!(@ty $(#[$arg_attr])* $in_),)* },
+                )*
+                /// An item of the streamed body of a request, which is sent after the request.
+                #[doc(hidden)]
+                __Item(RequestItem),
+            }
+        }
+
+        $crate::add_serde_if_enabled! {
+            /// The items of the streamed bodies of requests, by rpc. Rpcs without a `#[stream]`
+            /// argument have no items, so theirs hold `()`.
+            #[doc(hidden)]
+            #[allow(non_camel_case_types, unused)]
+            --
+            pub enum RequestItem {
+                $(
+                    $fn_name($crate::rpc_body!(@ty $( [$(#[$arg_attr])*] $in_ ),*)),
+                )*
+            }
+        }
+
+        impl Request {
+            /// The names of the rpcs, in the order they're declared. On the wire, a request names
+            /// the rpc it calls by its index in this list.
+            pub const METHODS: &'static [&'static str] = &[$(stringify!($fn_name)),*];
+
+            /// Returns the name of the rpc this request calls.
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(
+                        Request::$fn_name{ .. } => stringify!($fn_name),
+                    )*
+                    Request::__Item(_) => "unknown",
+                }
+            }
+
+            /// Returns the labels of the rpc this request calls, as `(key, value)` pairs.
+            pub fn labels(&self) -> &'static [(&'static str, &'static str)] {
+                match self {
+                    $(
+                        Request::$fn_name{ .. } => &[$($( (stringify!($label), $value), )*)?],
+                    )*
+                    Request::__Item(_) => &[],
+                }
+            }
+
+            /// Returns the delivery semantics the rpc this request calls was declared with, if
+            /// any.
+            pub fn delivery(&self) -> ::std::option::Option<$crate::client::delivery::Delivery> {
+                match self {
+                    $(
+                        Request::$fn_name{ .. } => {
+                            $crate::rpc_delivery!(@value $kind [$($delivery)?])
+                        }
+                    )*
+                    Request::__Item(_) => ::std::option::Option::None,
+                }
+            }
+        }
+
+        /// Only requests of at-least-once rpcs without a `#[stream]` argument are resent, since a
+        /// request's streamed body can't be.
+        impl $crate::client::delivery::Resend for Request {
+            fn resend(&self) -> ::std::option::Option<Self> {
+                match self {
+                    $(
+                        #[allow(unused_variables)]
+                        Request::$fn_name{ $($arg,)* } => $crate::rpc_body! {
+                            @if_body [$( [$(#[$arg_attr])*] )*]
+                            { ::std::option::Option::None }
+                            {
+                                $crate::rpc_delivery!(@resend $kind [$($delivery)?] Request::$fn_name {
+                                    $($arg: ::std::clone::Clone::clone($arg),)*
+                                })
+                            }
+                        },
+                    )*
+                    Request::__Item(_) => ::std::option::Option::None,
+                }
+            }
+        }
+
+        $(
+            $crate::rpc_body! {
+                @if_body [$( [$(#[$arg_attr])*] )*]
+                {}
+                {
+                    $crate::request_struct! {
+                        /// The arguments of a call to `{}`. Building it with `new` and the setters
+                        /// of the arguments marked `#[default]` keeps compiling when defaulted
+                        /// arguments are added.
+                        pub struct $fn_name {
+                            $( $(#[$arg_attr])* pub $arg: $in_, )*
+                        }
+                    }
+
+                    impl ::std::convert::From<$crate::ty_snake_to_request!($fn_name)> for Request {
+                        #[allow(unused_variables)]
+                        fn from(request: $crate::ty_snake_to_request!($fn_name)) -> Self {
+                            $( let $arg = request.$arg; )*
+                            Request::$fn_name {
+                                $($arg: $crate::rpc_arg!(@wrap $(#[$arg_attr])* $arg),)*
+                            }
+                        }
+                    }
+
+                    $crate::rpc_kind! {
+                        @method_request $kind $fn_name $out
+                        [$crate::rpc_delivery!(@ty $kind $fn_name [$($delivery)?])]
+                    }
+                }
+            }
+        )*
+
+        impl ::std::fmt::Debug for Request {
+            fn fmt(&self, fmt: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                match self {
+                    $(
+                        Request::$fn_name{ $($arg,)* } => {
+                            fmt.debug_struct(stringify!($fn_name))
+                                $(
+                                    .field(
+                                        stringify!($arg),
+                                        $crate::rpc_arg!(@debug $(#[$arg_attr])* $arg),
+                                    )
+                                )*
+                                .finish()
+                        }
+                    )*
+                    Request::__Item(_) => fmt.write_str("<streamed item>"),
+                }
             }
         }
 
@@ -135,26 +960,30 @@ macro_rules! service {
             pub enum Response {
                 $(
                     $(#[$attr])*
-                    $fn_name($out)
+                    $fn_name($crate::rpc_kind!(@response $kind $out))
                 ),*
             }
         }
 
-        // TODO: proc_macro can't currently parse $crate, so this needs to be imported for the
+        // TODO: proc_macro can't currently parse $crate, so these need to be imported for the
         // usage of snake_to_camel! to work.
+        #[allow(unused_imports)]
         use $crate::futures::Future as Future__;
+        #[allow(unused_imports)]
+        use $crate::futures::Stream as Stream__;
 
         /// Defines the RPC service. The additional trait bounds are required so that services can
         /// multiplex requests across multiple tasks, potentially on multiple threads.
         pub trait Service: Clone + Send + 'static {
             $(
-                $crate::snake_to_camel! {
-                    /// The type of future returned by `{}`.
-                    type $fn_name: Future__<Output = $out> + Send;
-                }
+                $crate::rpc_kind! { @service_ty $kind $fn_name $out }
 
                 $(#[$attr])*
-                fn $fn_name(self, ctx: $crate::context::Context, $($arg:$in_),*) -> $crate::ty_snake_to_camel!(Self::$fn_name);
+                fn $fn_name(
+                    self,
+                    ctx: $crate::context::Context,
+                    $($arg: $crate::rpc_arg!(@service_ty $(#[$arg_attr])* $in_)),*
+                ) -> $crate::ty_snake_to_camel!(Self::$fn_name);
             )*
         }
 
@@ -164,8 +993,13 @@ macro_rules! service {
         pub enum ResponseFut<S: Service> {
             $(
                 $(#[$attr])*
-                $fn_name($crate::ty_snake_to_camel!(<S as Service>::$fn_name)),
+                $fn_name($crate::rpc_kind!(
+                    @response_fut $kind $crate::ty_snake_to_camel!(<S as Service>::$fn_name)
+                )),
             )*
+            /// The error an item of a streamed body sent as a request fails with.
+            #[doc(hidden)]
+            __Item,
         }
 
         impl<S: Service> ::std::fmt::Debug for ResponseFut<S> {
@@ -183,12 +1017,16 @@ macro_rules! service {
                 unsafe {
                     match ::std::pin::Pin::get_unchecked_mut(self) {
                         $(
-                            ResponseFut::$fn_name(resp) =>
-                                ::std::pin::Pin::new_unchecked(resp)
-                                    .poll(cx)
-                                    .map(Response::$fn_name)
-                                    .map(Ok),
+                            ResponseFut::$fn_name(resp) => $crate::rpc_kind!(
+                                @poll $kind $fn_name ::std::pin::Pin::new_unchecked(resp).poll(cx)
+                            ),
                         )*
+                        ResponseFut::__Item => ::std::task::Poll::Ready(::std::result::Result::Err(
+                            ::std::io::Error::new(
+                                ::std::io::ErrorKind::InvalidInput,
+                                "The client sent an item of a streamed body as a request.",
+                            ),
+                        )),
                     }
                 }
             }
@@ -201,10 +1039,15 @@ macro_rules! service {
                     match req {
                         $(
                             Request::$fn_name{ $($arg,)* } => {
-                                let resp = Service::$fn_name(service.clone(), ctx, $($arg),*);
-                                ResponseFut::$fn_name(resp)
+                                let resp = Service::$fn_name(
+                                    service.clone(),
+                                    ctx,
+                                    $($crate::rpc_arg!(@unwrap $fn_name $(#[$arg_attr])* $arg)),*
+                                );
+                                $crate::rpc_kind!(@serve $kind $fn_name resp)
                             }
                         )*
+                        Request::__Item(_) => ResponseFut::__Item,
                     }
                 }
             }
@@ -219,12 +1062,26 @@ macro_rules! service {
             -> ::std::io::Result<Client>
         where
             T: $crate::Transport<
-                    Item = $crate::Response<Response>,
+                    Item = $crate::ServerMessage<Response>,
                     SinkItem = $crate::ClientMessage<Request>> + Send + 'static,
         {
             Ok(Client(await!($crate::client::new(config, transport))?))
         }
 
+        /// Returns a new client stub connected, over an in-memory channel, to a server spawned to
+        /// serve `service`, along with a handle to the server. For tests, which needn't bind a
+        /// port to call a service.
+        pub async fn new_loopback_stub<S: Service>(config: $crate::client::Config, service: S)
+            -> ::std::io::Result<(Client, $crate::server::Serving)>
+        {
+            let (channel, serving) = await!($crate::transport::channel::loopback(
+                config,
+                $crate::server::Server::default(),
+                serve(service),
+            ))?;
+            Ok((Client(channel), serving))
+        }
+
         impl<C> From<C> for Client<C>
             where for <'a> C: $crate::Client<'a, Request, Response = Response>
         {
@@ -236,19 +1093,24 @@ macro_rules! service {
         impl<C> Client<C>
             where for<'a> C: $crate::Client<'a, Request, Response = Response>
         {
+            /// Calls the rpc whose request struct `request` is, e.g. a `SearchRequest` for
+            /// `rpc search`, like the rpc's stub fn does.
+            #[allow(unused)]
+            pub fn call<R>(&mut self, ctx: $crate::context::Context, request: R)
+                -> impl ::std::future::Future<Output = ::std::io::Result<R::Output>> + '_
+            where
+                R: $crate::MethodRequest<Request, Response> + 'static,
+                C: $crate::client::delivery::Delivers<R::Delivery>,
+            {
+                let resp = $crate::Client::call(&mut self.0, ctx, request.into());
+                async move { ::std::result::Result::Ok(R::output(await!(resp)?)) }
+            }
+
             $(
-                #[allow(unused)]
-                $(#[$attr])*
-                pub fn $fn_name(&mut self, ctx: $crate::context::Context, $($arg: $in_),*)
-                    -> impl ::std::future::Future<Output = ::std::io::Result<$out>> + '_ {
-                    let request__ = Request::$fn_name { $($arg,)* };
-                    let resp = $crate::Client::call(&mut self.0, ctx, request__);
-                    async move {
-                        match await!(resp)? {
-                            Response::$fn_name(msg__) => ::std::result::Result::Ok(msg__),
-                            _ => unreachable!(),
-                        }
-                    }
+                $crate::rpc_stub! {
+                    $kind [$crate::rpc_delivery!(@ty $kind $fn_name [$($delivery)?])]
+                    $(#[$attr])*
+                    $fn_name( $( $(#[$arg_attr])* $arg : $in_ ),* ) -> $out
                 }
             )*
         }
@@ -276,6 +1138,212 @@ mod syntax_test {
         rpc no_arg_implicit_return_error();
         #[doc="attr"]
         rpc one_arg_implicit_return_error(foo: String);
+        rpc sensitive_arg(#[sensitive] password: String);
+        rpc sensitive_args(user: String, #[sensitive] password: String, #[sensitive] pin: u32);
+        rpc boxed_arg(#[boxed] big: [u64; 32]) -> Box<[u64; 32]>;
+        rpc labeled() -> String [team = "rpc", tier = "1"];
+        rpc labeled_implicit_return() [team = "rpc"];
+        rpc labeled_empty() [];
+        rpc boxed_sensitive_args(#[boxed] #[sensitive] a: [u64; 32], #[sensitive] #[boxed] b: u8);
+        rpc default_arg(#[default] limit: u32) -> u32;
+        rpc boxed_default_args(#[boxed] #[default] a: [u64; 32], #[default] #[boxed] b: u8);
+        notify notify_no_args();
+        #[doc="attr"]
+        notify notify_args(bar: String, #[sensitive] #[boxed] baz: u64) [team = "rpc"];
+        stream stream_no_args();
+        #[doc="attr"]
+        stream stream_args(bar: String, #[boxed] baz: u64) -> String [team = "rpc"];
+        rpc body_only(#[stream] chunk: u8);
+        #[doc="attr"]
+        rpc body_args(bar: String, #[stream] chunk: Vec<u8>, baz: u64) -> u64 [team = "rpc"];
+        stream body_stream(#[sensitive] #[stream] line: String) -> String;
+        rpc(at_most_once) at_most_once() -> String;
+        #[doc="attr"]
+        rpc(at_least_once) at_least_once(#[boxed] #[default] a: u64) [team = "rpc"];
+        notify(at_most_once) notify_at_most_once(bar: String);
+        stream(at_least_once) stream_at_least_once(bar: String) -> String;
+        rpc(at_least_once) body_at_least_once(#[stream] chunk: u8) -> u64;
+    }
+}
+
+#[cfg(test)]
+mod delivery_test {
+    use rpc::client::delivery::{Delivery, Resend};
+
+    service! {
+        rpc(at_least_once) balance(account: String) -> u64;
+        rpc(at_most_once) charge(account: String, cents: u64);
+        rpc ping();
+        notify log(line: String);
+        rpc(at_least_once) upload(#[stream] chunk: u8);
+    }
+
+    #[test]
+    fn declared_deliveries() {
+        let balance = Request::balance {
+            account: "alice".into(),
+        };
+        assert_eq!(balance.delivery(), Some(Delivery::AtLeastOnce));
+        let charge = Request::charge {
+            account: "alice".into(),
+            cents: 100,
+        };
+        assert_eq!(charge.delivery(), Some(Delivery::AtMostOnce));
+        assert_eq!(Request::ping {}.delivery(), None);
+        let log = Request::log { line: "".into() };
+        assert_eq!(log.delivery(), Some(Delivery::AtMostOnce));
+    }
+
+    #[test]
+    fn only_at_least_once_requests_are_resent() {
+        let balance = Request::balance {
+            account: "alice".into(),
+        };
+        match balance.resend() {
+            Some(Request::balance { account }) => assert_eq!(account, "alice"),
+            resent => panic!("Unexpected resent request: {:?}", resent),
+        }
+        let charge = Request::charge {
+            account: "alice".into(),
+            cents: 100,
+        };
+        assert!(charge.resend().is_none());
+        assert!(Request::ping {}.resend().is_none());
+        // A streamed body can't be resent.
+        assert!(Request::upload { chunk: () }.resend().is_none());
+    }
+}
+
+#[cfg(test)]
+mod debug_test {
+    service! {
+        rpc login(user: String, #[sensitive] password: String) -> bool;
+    }
+
+    #[test]
+    fn sensitive_args_redacted() {
+        let request = Request::login {
+            user: "tim".into(),
+            password: "hunter2".into(),
+        };
+        assert_eq!(
+            format!("{:?}", request),
+            r#"login { user: "tim", password: <redacted> }"#
+        );
+    }
+
+    #[test]
+    fn request_name() {
+        let request = Request::login {
+            user: "tim".into(),
+            password: "hunter2".into(),
+        };
+        assert_eq!(request.name(), "login");
+        assert_eq!(Request::METHODS, &["login"]);
+    }
+}
+
+#[cfg(test)]
+mod labels_test {
+    service! {
+        rpc search(query: String) -> Vec<String> [team = "search", tier = "1"];
+        rpc report(bug: String) [team = "support"];
+        rpc ping();
+    }
+
+    #[test]
+    fn labels() {
+        let search = Request::search { query: "".into() };
+        assert_eq!(search.labels(), &[("team", "search"), ("tier", "1")]);
+        let report = Request::report { bug: "".into() };
+        assert_eq!(report.labels(), &[("team", "support")]);
+        assert!(Request::ping {}.labels().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod request_struct_test {
+    service! {
+        rpc search(query: String, #[default] max_results: u32) -> Vec<String>;
+        rpc login(user: String, #[sensitive] password: String) -> bool;
+        notify log(line: String, #[default] level: u8);
+        rpc ping();
+    }
+
+    #[test]
+    fn defaulted_args_have_setters() {
+        let request = SearchRequest::new("tarpc".into());
+        assert_eq!(request.query, "tarpc");
+        assert_eq!(request.max_results, 0);
+        let request = request.with_max_results(10);
+        assert_eq!(request.max_results, 10);
+        assert_eq!(LogRequest::new("hi".into()).with_level(2).level, 2);
+    }
+
+    #[test]
+    fn converts_into_request() {
+        let request = SearchRequest::new("tarpc".into()).with_max_results(10);
+        match Request::from(request) {
+            Request::search { query, max_results } => {
+                assert_eq!(query, "tarpc");
+                assert_eq!(max_results.0, 10);
+            }
+            request => panic!("Unexpected request: {:?}", request),
+        }
+        assert_eq!(Request::from(PingRequest::new()).name(), "ping");
+    }
+
+    #[test]
+    fn sensitive_args_redacted() {
+        let request = LoginRequest::new("tim".into(), "hunter2".into());
+        assert_eq!(
+            format!("{:?}", request),
+            r#"LoginRequest { user: "tim", password: <redacted> }"#
+        );
+    }
+}
+
+#[cfg(test)]
+mod boxed_test {
+    service! {
+        rpc big(#[boxed] a: [u64; 32], #[boxed] #[sensitive] b: [u64; 32]);
+    }
+
+    #[test]
+    fn boxed_args_kept_small() {
+        assert_eq!(
+            ::std::mem::size_of::<Request>(),
+            2 * ::std::mem::size_of::<Box<[u64; 32]>>()
+        );
+        let request = Request::big {
+            a: Box::new([1; 32]),
+            b: Box::new([2; 32]),
+        };
+        assert!(format!("{:?}", request).ends_with("b: <redacted> }"));
+    }
+}
+
+#[cfg(all(test, feature = "serde1"))]
+mod default_test {
+    service! {
+        rpc search(query: String, #[default] #[boxed] max_results: u32) -> Vec<String>;
+    }
+
+    #[test]
+    fn default_args_roundtrip() {
+        let request = Request::search {
+            query: "tarpc".into(),
+            max_results: crate::Defaulted(Box::new(10)),
+        };
+        assert_eq!(
+            format!("{:?}", request),
+            r#"search { query: "tarpc", max_results: 10 }"#
+        );
+        let encoded = bincode::serialize(&request).unwrap();
+        match bincode::deserialize(&encoded).unwrap() {
+            Request::search { max_results, .. } => assert_eq!(*max_results.0, 10),
+            request => panic!("Unexpected request: {:?}", request),
+        }
     }
 }
 
@@ -286,7 +1354,7 @@ mod functional_test {
         future::{ready, Ready},
         prelude::*,
     };
-    use rpc::{client, context, server::Handler, transport::channel};
+    use rpc::{client, context, server::Handler, transport::channel, Client as _};
     use std::io;
     use tokio::runtime::current_thread;
 
@@ -374,4 +1442,288 @@ mod functional_test {
 
         current_thread::block_on_all(test.boxed().compat()).unwrap();
     }
+
+    #[test]
+    fn loopback() {
+        let _ = env_logger::try_init();
+        rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+        let test = async {
+            let (mut client, serving) = await!(new_loopback_stub(client::Config::default(), Server))?;
+            assert_eq!(3, await!(client.add(context::current(), 1, 2))?);
+            drop(client);
+            await!(serving);
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!("test failed: {}", e));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
+
+    #[test]
+    fn request_struct() {
+        let _ = env_logger::try_init();
+        rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+        let test = async {
+            let (mut client, serving) =
+                await!(new_loopback_stub(client::Config::default(), Server))?;
+            assert_eq!(
+                3,
+                await!(client.call(context::current(), AddRequest::new(1, 2)))?
+            );
+            let request = HeyRequest::new("Tim".to_string());
+            assert_eq!(
+                "Hey, Tim.",
+                await!(client.call(context::current(), request))?
+            );
+            drop(client);
+            await!(serving);
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!("test failed: {}", e));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
+
+    #[test]
+    fn response_hook() {
+        let _ = env_logger::try_init();
+        rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+        let test = async {
+            let (tx, rx) = channel::unbounded();
+            tokio_executor::spawn(
+                rpc::Server::default()
+                    .incoming(stream::once(ready(Ok(rx))))
+                    .respond_with(serve(Server))
+                    .unit_error()
+                    .boxed()
+                    .compat(),
+            );
+
+            let channel = await!(client::new(client::Config::default(), tx))?;
+            let mut client = Client::from(channel.try_map_response(|response| match response {
+                Response::add(sum) if sum < 0 => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Sums must be positive.",
+                )),
+                response => Ok(response),
+            }));
+            assert_eq!(3, await!(client.add(context::current(), 1, 2))?);
+            let e = await!(client.add(context::current(), 1, -2)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(
+                "Hey, Tim.",
+                await!(client.hey(context::current(), "Tim".to_string()))?
+            );
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!("test failed: {}", e));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod notify_test {
+    use futures::{
+        channel::mpsc,
+        compat::Executor01CompatExt,
+        future::{ready, Ready},
+        prelude::*,
+    };
+    use rpc::{client, context};
+    use std::io;
+    use tokio::runtime::current_thread;
+
+    service! {
+        notify record(x: i32);
+        rpc add(x: i32, y: i32) -> i32;
+    }
+
+    #[derive(Clone)]
+    struct Server(mpsc::UnboundedSender<i32>);
+
+    impl Service for Server {
+        type RecordFut = Ready<()>;
+
+        fn record(self, _: context::Context, x: i32) -> Self::RecordFut {
+            self.0.unbounded_send(x).unwrap();
+            ready(())
+        }
+
+        type AddFut = Ready<i32>;
+
+        fn add(self, _: context::Context, x: i32, y: i32) -> Self::AddFut {
+            ready(x + y)
+        }
+    }
+
+    #[test]
+    fn notifications_are_handled() {
+        let _ = env_logger::try_init();
+        rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+        let test = async {
+            let (recorded_tx, mut recorded) = mpsc::unbounded();
+            let (mut client, serving) = await!(new_loopback_stub(
+                client::Config::default(),
+                Server(recorded_tx)
+            ))?;
+            await!(client.record(context::current(), 7))?;
+            assert_eq!(await!(recorded.next()), Some(7));
+            // The dropped response doesn't confuse the calls that follow.
+            assert_eq!(3, await!(client.add(context::current(), 1, 2))?);
+            drop(client);
+            await!(serving);
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!("test failed: {}", e));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod stream_test {
+    use futures::{
+        compat::Executor01CompatExt,
+        future::{ready, Ready},
+        prelude::*,
+        stream::Iter,
+    };
+    use rpc::{client, context};
+    use std::{io, ops::Range};
+    use tokio::runtime::current_thread;
+
+    service! {
+        stream count(to: u32) -> u32;
+        rpc add(x: i32, y: i32) -> i32;
+    }
+
+    #[derive(Clone)]
+    struct Server;
+
+    impl Service for Server {
+        type CountFut = Iter<Range<u32>>;
+
+        fn count(self, _: context::Context, to: u32) -> Self::CountFut {
+            stream::iter(0..to)
+        }
+
+        type AddFut = Ready<i32>;
+
+        fn add(self, _: context::Context, x: i32, y: i32) -> Self::AddFut {
+            ready(x + y)
+        }
+    }
+
+    #[test]
+    fn items_are_streamed() {
+        let _ = env_logger::try_init();
+        rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+        let test = async {
+            let (mut client, serving) =
+                await!(new_loopback_stub(client::Config::default(), Server))?;
+            let items = await!(client.count(context::current(), 3))?;
+            let items: Vec<_> = await!(items.map(Result::unwrap).collect());
+            assert_eq!(items, vec![0, 1, 2]);
+            let items = await!(client.count(context::current(), 0))?;
+            let items: Vec<_> = await!(items.map(Result::unwrap).collect());
+            assert!(items.is_empty());
+            // The stream's end doesn't confuse the calls that follow.
+            assert_eq!(3, await!(client.add(context::current(), 1, 2))?);
+            drop(client);
+            await!(serving);
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!("test failed: {}", e));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod body_test {
+    use futures::{
+        compat::Executor01CompatExt,
+        future::{ready, Ready},
+        prelude::*,
+        stream::Map,
+    };
+    use rpc::{client, context, server::streaming::BodyItems};
+    use std::{io, pin::Pin};
+    use tokio::runtime::current_thread;
+
+    service! {
+        rpc upload(file: String, #[stream] chunk: Vec<u8>) -> u64;
+        stream shout(#[stream] line: String) -> String;
+        rpc add(x: i32, y: i32) -> i32;
+    }
+
+    #[derive(Clone)]
+    struct Server;
+
+    fn shout(line: io::Result<String>) -> String {
+        line.unwrap().to_uppercase()
+    }
+
+    impl Service for Server {
+        type UploadFut = Pin<Box<dyn Future<Output = u64> + Send>>;
+
+        fn upload(
+            self,
+            _: context::Context,
+            _: String,
+            chunk: BodyItems<Request, Vec<u8>>,
+        ) -> Self::UploadFut {
+            Box::pin(
+                chunk
+                    .map(|chunk| chunk.unwrap().len() as u64)
+                    .fold(0, |len, chunk| ready(len + chunk)),
+            )
+        }
+
+        type ShoutFut = Map<BodyItems<Request, String>, fn(io::Result<String>) -> String>;
+
+        fn shout(self, _: context::Context, line: BodyItems<Request, String>) -> Self::ShoutFut {
+            line.map(shout)
+        }
+
+        type AddFut = Ready<i32>;
+
+        fn add(self, _: context::Context, x: i32, y: i32) -> Self::AddFut {
+            ready(x + y)
+        }
+    }
+
+    #[test]
+    fn bodies_are_streamed() {
+        let _ = env_logger::try_init();
+        rpc::init(tokio::executor::DefaultExecutor::current().compat());
+
+        let test = async {
+            let (mut client, serving) =
+                await!(new_loopback_stub(client::Config::default(), Server))?;
+            let chunks = stream::iter(vec![vec![0; 3], vec![], vec![0; 4]]);
+            let len = await!(client.upload(context::current(), "f".into(), chunks))?;
+            assert_eq!(len, 7);
+
+            let lines = stream::iter(vec!["hi".to_string(), "bye".into()]);
+            let lines = await!(client.shout(context::current(), lines))?;
+            let lines: Vec<_> = await!(lines.map(Result::unwrap).collect());
+            assert_eq!(lines, vec!["HI", "BYE"]);
+
+            // The bodies' ends don't confuse the calls that follow.
+            assert_eq!(3, await!(client.add(context::current(), 1, 2))?);
+            drop(client);
+            await!(serving);
+            Ok::<_, io::Error>(())
+        }
+            .map_err(|e| panic!("test failed: {}", e));
+
+        current_thread::block_on_all(test.boxed().compat()).unwrap();
+    }
 }
@@ -0,0 +1,22 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+/// The request struct of an rpc, e.g. `SearchRequest` for `rpc search(...)`, which holds the
+/// rpc's arguments for a client stub's `call` fn to send as a `Req`.
+///
+/// [`service!`](crate::service) implements it for the request structs of `rpc` methods without a
+/// `#[stream]` argument.
+pub trait MethodRequest<Req, Resp>: Into<Req> {
+    /// The type the rpc's client stub fn resolves to.
+    type Output;
+
+    /// The marker type of the rpc's [delivery semantics](crate::client::delivery), which the client
+    /// sending the request must deliver.
+    type Delivery;
+
+    /// Returns the output of the rpc from its response.
+    fn output(response: Resp) -> Self::Output;
+}
@@ -0,0 +1,351 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Wire-safe wrappers for types that services commonly send.
+//!
+//! Each type serializes the same way regardless of whether the codec is human-readable, using
+//! only fixed-width integers and byte arrays, so services using different codecs agree on what
+//! goes over the wire. The types convert to and from their `std` counterparts and are meant to be
+//! used by path, e.g. `types::Duration`, to avoid confusion with those counterparts.
+//!
+//! Rpcs can return [`types::Error`](Error) as their error type, so that their handlers can use `?`
+//! on any error, without converting each one by hand.
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{error::Error as StdError, fmt, io, net, time};
+
+const NANOS_PER_SEC: u32 = 1_000_000_000;
+
+/// A span of time, encoded as a `(u64, u32)` of seconds and nanoseconds.
+///
+/// Durations are always sent relative to nothing, so unlike an [`Instant`](time::Instant), they
+/// mean the same thing on both ends of a connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Duration(pub time::Duration);
+
+impl From<time::Duration> for Duration {
+    fn from(duration: time::Duration) -> Self {
+        Duration(duration)
+    }
+}
+
+impl From<Duration> for time::Duration {
+    fn from(duration: Duration) -> Self {
+        duration.0
+    }
+}
+
+impl Serialize for Duration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.0.as_secs(), self.0.subsec_nanos()).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Duration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (secs, nanos) = <(u64, u32)>::deserialize(deserializer)?;
+        if nanos >= NANOS_PER_SEC {
+            return Err(de::Error::custom(format!(
+                "Duration nanoseconds out of range: {}",
+                nanos
+            )));
+        }
+        Ok(Duration(time::Duration::new(secs, nanos)))
+    }
+}
+
+/// A point in wall-clock time, encoded as an `(i64, u32)` of seconds and nanoseconds since the
+/// Unix epoch. Times before the epoch have negative seconds; the nanoseconds always count forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Timestamp(pub time::SystemTime);
+
+impl From<time::SystemTime> for Timestamp {
+    fn from(time: time::SystemTime) -> Self {
+        Timestamp(time)
+    }
+}
+
+impl From<Timestamp> for time::SystemTime {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.0
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let out_of_range = || <S::Error as serde::ser::Error>::custom("Timestamp out of range");
+        let (secs, nanos) = match self.0.duration_since(time::UNIX_EPOCH) {
+            Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+            Err(e) => {
+                let before = e.duration();
+                let secs = (before.as_secs() as i64).wrapping_neg();
+                match before.subsec_nanos() {
+                    0 => (secs, 0),
+                    nanos => (
+                        secs.checked_sub(1).ok_or_else(out_of_range)?,
+                        NANOS_PER_SEC - nanos,
+                    ),
+                }
+            }
+        };
+        if (secs >= 0) != (self.0 >= time::UNIX_EPOCH) {
+            // The seconds overflowed an i64.
+            return Err(out_of_range());
+        }
+        (secs, nanos).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (secs, nanos) = <(i64, u32)>::deserialize(deserializer)?;
+        if nanos >= NANOS_PER_SEC {
+            return Err(de::Error::custom(format!(
+                "Timestamp nanoseconds out of range: {}",
+                nanos
+            )));
+        }
+        let time = if secs >= 0 {
+            time::UNIX_EPOCH.checked_add(time::Duration::new(secs as u64, nanos))
+        } else {
+            time::UNIX_EPOCH
+                .checked_sub(time::Duration::from_secs(secs.wrapping_neg() as u64))
+                .and_then(|time| time.checked_add(time::Duration::new(0, nanos)))
+        };
+        time.map(Timestamp)
+            .ok_or_else(|| de::Error::custom("Timestamp out of range"))
+    }
+}
+
+/// A 128-bit universally unique identifier, encoded as 16 bytes.
+///
+/// This type only carries UUIDs; generating them is left to a dedicated crate. It displays in the
+/// usual hyphenated form, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub struct Uuid([u8; 16]);
+
+impl Uuid {
+    /// Returns the UUID with the given bytes, most significant first.
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Uuid(bytes)
+    }
+
+    /// Returns the bytes of the UUID, most significant first.
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+}
+
+impl From<u128> for Uuid {
+    fn from(uuid: u128) -> Self {
+        Uuid(uuid.to_be_bytes())
+    }
+}
+
+impl From<Uuid> for u128 {
+    fn from(uuid: Uuid) -> Self {
+        u128::from_be_bytes(uuid.0)
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if let 4 | 6 | 8 | 10 = i {
+                f.write_str("-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// An IP address, encoded as its octets, tagged with the address version.
+///
+/// Unlike `std::net::IpAddr`, which is sent as a string by human-readable codecs, the encoding is
+/// the same for every codec.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct IpAddr(pub net::IpAddr);
+
+#[derive(Serialize, Deserialize)]
+enum IpAddrRepr {
+    V4([u8; 4]),
+    V6([u8; 16]),
+}
+
+impl From<net::IpAddr> for IpAddr {
+    fn from(addr: net::IpAddr) -> Self {
+        IpAddr(addr)
+    }
+}
+
+impl From<IpAddr> for net::IpAddr {
+    fn from(addr: IpAddr) -> Self {
+        addr.0
+    }
+}
+
+impl Serialize for IpAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            net::IpAddr::V4(addr) => IpAddrRepr::V4(addr.octets()),
+            net::IpAddr::V6(addr) => IpAddrRepr::V6(addr.octets()),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(IpAddr(match IpAddrRepr::deserialize(deserializer)? {
+            IpAddrRepr::V4(octets) => net::Ipv4Addr::from(octets).into(),
+            IpAddrRepr::V6(octets) => net::Ipv6Addr::from(octets).into(),
+        }))
+    }
+}
+
+/// An error message, along with the messages of the errors that caused it.
+///
+/// Every error converts into this type, so a handler for an rpc returning `Result<T,
+/// types::Error>` can use `?` on its own errors. For the same reason, this type doesn't
+/// implement `std::error::Error` itself; it does convert into an `io::Error`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Error {
+    message: String,
+    causes: Vec<String>,
+}
+
+impl Error {
+    /// Returns an error with the given message and no causes.
+    pub fn new(message: impl Into<String>) -> Self {
+        Error {
+            message: message.into(),
+            causes: vec![],
+        }
+    }
+
+    /// Returns the message of the error.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Returns the messages of the errors that caused this one, starting with the direct cause.
+    pub fn causes(&self) -> &[String] {
+        &self.causes
+    }
+}
+
+impl<E: StdError> From<E> for Error {
+    fn from(e: E) -> Self {
+        let mut causes = vec![];
+        let mut source = e.source();
+        while let Some(cause) = source {
+            causes.push(cause.to_string());
+            source = cause.source();
+        }
+        Error {
+            message: e.to_string(),
+            causes,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)?;
+        for cause in &self.causes {
+            write!(f, ": {}", cause)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Duration, Error, IpAddr, Timestamp, Uuid};
+    use serde::{de::DeserializeOwned, Serialize};
+    use std::{error::Error as StdError, fmt, fmt::Debug, net, time};
+
+    fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: T) -> Vec<u8> {
+        let bytes = bincode::serialize(&value).unwrap();
+        assert_eq!(bincode::deserialize::<T>(&bytes).unwrap(), value);
+        bytes
+    }
+
+    #[test]
+    fn duration_round_trip() {
+        let bytes = round_trip(Duration(time::Duration::new(3, 500)));
+        assert_eq!(bytes.len(), 12);
+    }
+
+    #[test]
+    fn duration_rejects_out_of_range_nanos() {
+        let bytes = bincode::serialize(&(1u64, 1_000_000_000u32)).unwrap();
+        assert!(bincode::deserialize::<Duration>(&bytes).is_err());
+    }
+
+    #[test]
+    fn timestamp_round_trip() {
+        let epoch = time::UNIX_EPOCH;
+        round_trip(Timestamp(epoch));
+        round_trip(Timestamp(epoch + time::Duration::new(1_553_807_709, 123)));
+
+        let bytes = round_trip(Timestamp(epoch - time::Duration::new(1, 250_000_000)));
+        let (secs, nanos): (i64, u32) = bincode::deserialize(&bytes).unwrap();
+        assert_eq!((secs, nanos), (-2, 750_000_000));
+    }
+
+    #[test]
+    fn uuid_display() {
+        let uuid = Uuid::from(0x67e5_5044_10b1_426f_9247_bb68_0e5f_e0c8_u128);
+        assert_eq!(uuid.to_string(), "67e55044-10b1-426f-9247-bb680e5fe0c8");
+        assert_eq!(round_trip(uuid).len(), 16);
+    }
+
+    #[test]
+    fn ip_addr_round_trip() {
+        round_trip(IpAddr(net::Ipv4Addr::LOCALHOST.into()));
+        round_trip(IpAddr(net::Ipv6Addr::LOCALHOST.into()));
+    }
+
+    #[test]
+    fn error_from_question_mark() {
+        #[derive(Debug)]
+        struct Lookup(std::num::ParseIntError);
+
+        impl fmt::Display for Lookup {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("lookup failed")
+            }
+        }
+
+        impl StdError for Lookup {
+            fn source(&self) -> Option<&(dyn StdError + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        fn lookup(id: &str) -> Result<u64, Error> {
+            Ok(id.parse().map_err(Lookup)?)
+        }
+
+        let e = round_trip(lookup("x").unwrap_err());
+        assert_eq!(e.message(), "lookup failed");
+        assert_eq!(e.causes(), ["invalid digit found in string"]);
+        assert_eq!(
+            e.to_string(),
+            "lookup failed: invalid digit found in string"
+        );
+    }
+}
@@ -18,8 +18,9 @@
 
 use rand::Rng;
 use std::{
-    fmt::{self, Formatter},
+    fmt::{self, Formatter, Write},
     mem,
+    time::SystemTime,
 };
 
 /// A context for tracing the execution of processes, distributed or otherwise.
@@ -55,6 +56,12 @@ pub struct TraceId(u128);
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SpanId(u64);
 
+/// A 128-bit [ULID](https://github.com/ulid/spec) identifying a connection. Connection IDs
+/// sort by the millisecond they were created in.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionId(u128);
+
 impl Context {
     /// Constructs a new root context. A root context is one with no parent span.
     pub fn new_root() -> Self {
@@ -82,6 +89,19 @@ impl SpanId {
     }
 }
 
+impl ConnectionId {
+    /// Returns a connection ID made of the current time and 80 random bits, which can be assumed
+    /// to be globally unique if `rng` generates actually-random numbers.
+    pub fn random<R: Rng>(rng: &mut R) -> Self {
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since| since.as_millis())
+            .unwrap_or(0);
+        let random = u128::from(rng.next_u64()) << 16 | u128::from(rng.next_u32() & 0xffff);
+        ConnectionId((millis & ((1 << 48) - 1)) << 80 | random)
+    }
+}
+
 impl fmt::Display for TraceId {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
         write!(f, "{:02x}", self.0)?;
@@ -95,3 +115,14 @@ impl fmt::Display for SpanId {
         Ok(())
     }
 }
+
+impl fmt::Display for ConnectionId {
+    /// Writes the ID as 26 characters of Crockford's base32, as ULIDs are usually written.
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+        for i in (0..26).rev() {
+            f.write_char(char::from(ALPHABET[(self.0 >> (5 * i)) as usize & 0x1f]))?;
+        }
+        Ok(())
+    }
+}